@@ -0,0 +1,281 @@
+use std::ops::Range;
+
+use cfa_mrp::{CancelToken, CsvWriter, Environment, MrpError, formats};
+
+use crate::extend::RunInfo;
+use crate::output::RenewalOutput;
+use crate::parameters::{Aggregate, Parameters};
+use crate::renewal::{Simulation, Streams};
+
+/// Simulate `chunk_steps` steps at a time, calling `emit` after each chunk
+/// with the simulation and the steps the chunk added.
+///
+/// Each chunk advances one [`Simulation`], whose generators carry over from
+/// chunk to chunk, so the trajectory is identical to an unchunked run. The
+/// output is not copied between chunks: `emit` reads the new steps from
+/// [`Simulation::output`]. `cancel` is checked before each chunk; once
+/// tripped, the output so far is returned.
+pub fn simulate_chunks(
+    parameters: &Parameters,
+    streams: Streams,
+    chunk_steps: usize,
+    cancel: &CancelToken,
+    mut emit: impl FnMut(&Simulation, Range<usize>) -> Result<(), MrpError>,
+) -> Result<RenewalOutput, MrpError> {
+    if chunk_steps == 0 {
+        return Err(MrpError::Input("chunk_steps must be positive".to_string()));
+    }
+    let mut simulation = Simulation::new(parameters, streams);
+    let mut done = 0;
+    while done < parameters.sim_length && !cancel.is_cancelled() {
+        let end = usize::min(done + chunk_steps, parameters.sim_length);
+        simulation.advance_to(end);
+        emit(&simulation, done..end)?;
+        done = end;
+    }
+    Ok(simulation.into_prefix())
+}
+
+/// Simulate in chunks, writing `renewal_output.csv` progressively.
+pub fn write_chunked(
+    ctx: &Environment<Parameters>,
+    parameters: &Parameters,
+    streams: Streams,
+) -> Result<RenewalOutput, MrpError> {
+    let mut writer = ChunkWriter::new(ctx, parameters)?;
+    let chunk_steps = parameters.chunk_steps.unwrap_or(parameters.sim_length);
+    let cancel = ctx.cancel_token();
+    simulate_chunks(
+        parameters,
+        streams,
+        chunk_steps,
+        &cancel,
+        |simulation, steps| writer.write(simulation, steps),
+    )
+}
+
+/// Writes each chunk's rows as it completes.
+///
+/// After each chunk the CSV is flushed, then `checkpoint.json` (the run info
+/// of the rows written so far) is replaced, so a run killed between chunks
+/// can be continued with `extend`. Metrics and progress are updated too.
+/// Only the chunk's own rows are rendered, so each chunk costs the same
+/// however long the run has gone.
+pub struct ChunkWriter<'a> {
+    ctx: &'a Environment<Parameters>,
+    parameters: &'a Parameters,
+    csv: Option<CsvWriter>,
+    cumulative_infections: u64,
+}
+
+impl<'a> ChunkWriter<'a> {
+    pub fn new(
+        ctx: &'a Environment<Parameters>,
+        parameters: &'a Parameters,
+    ) -> Result<Self, MrpError> {
+        if parameters.aggregate != Aggregate::Daily
+            || parameters.dt.is_some()
+            || parameters.replicates.is_some()
+            || parameters.extend.is_some()
+        {
+            return Err(MrpError::Input(
+                "chunk_steps requires daily output without dt, replicates or extend".to_string(),
+            ));
+        }
+        Ok(ChunkWriter {
+            ctx,
+            parameters,
+            csv: None,
+            cumulative_infections: 0,
+        })
+    }
+
+    pub fn write(&mut self, simulation: &Simulation, steps: Range<usize>) -> Result<(), MrpError> {
+        let ctx = self.ctx;
+        let output = simulation.output();
+        let so_far = Parameters {
+            sim_length: steps.end,
+            ..self.parameters.clone()
+        };
+        let (headers, rows) = output.to_rows_in(&so_far, steps.clone())?;
+        let csv = self
+            .csv
+            .get_or_insert_with(|| ctx.csv_writer("renewal_output.csv", &headers));
+        for row in &rows {
+            let refs: Vec<&str> = row.iter().map(|s| s.as_str()).collect();
+            csv.try_write_row(&refs)?;
+        }
+        csv.flush();
+        if ctx.output_dir().is_some() {
+            let info = RunInfo::new(&so_far).with_pending_onsets(Some(simulation.pending_onsets()));
+            let info = serde_json::to_vec_pretty(&formats::RunInfo::new(info))
+                .map_err(|e| MrpError::Serialization(e.to_string()))?;
+            ctx.write("checkpoint.json", &info);
+        }
+        self.cumulative_infections += output.infection_incidence[steps.clone()]
+            .iter()
+            .sum::<u64>();
+        ctx.update_metric("steps_completed", steps.end as f64);
+        ctx.update_metric("cumulative_infections", self.cumulative_infections as f64);
+        ctx.progress(steps.end as u64, self.parameters.sim_length as u64);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::*;
+    use crate::extend;
+    use crate::renewal::RenewalModel;
+
+    fn payload(dir: &std::path::Path, chunk_steps: Option<usize>) -> serde_json::Value {
+        serde_json::json!({
+            "input": {
+                "r0": 1.4,
+                "generation_interval_pmf": [0., 0.25, 0.5, 0.25],
+                "symptom_onset_pmf": [0.1, 0.3, 0.4, 0.2],
+                "initial_infections": [30],
+                "sim_length": 100,
+                "population": 50_000,
+                "seed": 77,
+                "chunk_steps": chunk_steps,
+            },
+            "output": { "spec": "filesystem", "dir": dir.to_str().unwrap() },
+            "progress_interval_ms": 0,
+        })
+    }
+
+    #[test]
+    fn test_chunked_matches_unchunked() {
+        let parameters = Parameters {
+            r0: 1.4,
+            generation_interval_pmf: vec![0., 0.25, 0.5, 0.25],
+            symptom_onset_pmf: vec![0.1, 0.3, 0.4, 0.2],
+            initial_infections: vec![30],
+            sim_length: 100,
+            population: Some(50_000),
+            seed: 77,
+            ..Default::default()
+        };
+        let streams = Streams::from_seed(parameters.seed);
+        let whole = RenewalModel::simulate_with(&parameters, streams);
+        for chunk_steps in [1, 7, 30, 100, 500] {
+            let mut seen = Vec::new();
+            let cancel = CancelToken::new();
+            let chunked = simulate_chunks(
+                &parameters,
+                streams,
+                chunk_steps,
+                &cancel,
+                |simulation, steps| {
+                    assert_eq!(simulation.step(), steps.end);
+                    seen.push(steps);
+                    Ok(())
+                },
+            )
+            .unwrap();
+            assert_eq!(chunked.infection_incidence, whole.infection_incidence);
+            assert_eq!(chunked.symptomatic_incidence, whole.symptomatic_incidence);
+            assert_eq!(seen.first().unwrap().start, 0);
+            assert_eq!(seen.last().unwrap().end, 100);
+        }
+
+        let a = tempfile::tempdir().unwrap();
+        let b = tempfile::tempdir().unwrap();
+        for (dir, chunk_steps) in [(a.path(), Some(9)), (b.path(), None)] {
+            let env = Environment::<Parameters>::from_json_typed(payload(dir, chunk_steps));
+            write_chunked(&env, env.input.as_ref().unwrap(), Streams::from_env(&env)).unwrap();
+        }
+        assert_eq!(
+            fs::read(a.path().join("renewal_output.csv")).unwrap(),
+            fs::read(b.path().join("renewal_output.csv")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_interrupted_run_leaves_usable_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let env = Environment::<Parameters>::from_json_typed(payload(dir.path(), Some(25)));
+        let parameters = env.input.as_ref().unwrap();
+        let streams = Streams::from_env(&env);
+        let mut writer = ChunkWriter::new(&env, parameters).unwrap();
+        // Stop before the fourth chunk is written, as if the process died
+        let result = simulate_chunks(
+            parameters,
+            streams,
+            25,
+            &CancelToken::new(),
+            |simulation, steps| {
+                if steps.start == 75 {
+                    return Err(MrpError::Runtime("killed".to_string()));
+                }
+                writer.write(simulation, steps)
+            },
+        );
+        assert!(result.is_err());
+
+        let csv = fs::read_to_string(dir.path().join("renewal_output.csv")).unwrap();
+        let info: RunInfo =
+            serde_json::from_slice(&fs::read(dir.path().join("checkpoint.json")).unwrap()).unwrap();
+        assert_eq!(info.sim_length, 75);
+        let prefix = extend::load_prior(&csv, &info).unwrap();
+        extend::check_prior(&info, parameters).unwrap();
+
+        // The checkpoint holds exactly the first 75 steps, which continue
+        let whole = RenewalModel::simulate_with(parameters, streams);
+        assert_eq!(prefix.infection_incidence, whole.infection_incidence[..75]);
+        assert_eq!(
+            prefix.symptomatic_incidence,
+            whole.symptomatic_incidence[..75]
+        );
+        let resumed = RenewalModel::extend_with(parameters, &prefix, streams);
+        assert_eq!(
+            resumed.infection_incidence[..75],
+            prefix.infection_incidence
+        );
+        assert_eq!(resumed.infection_incidence.len(), 100);
+    }
+
+    #[test]
+    fn test_cancel_stops_after_current_chunk() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut data = payload(dir.path(), Some(25));
+        data["manifest"] = true.into();
+        let mut env = Environment::<Parameters>::from_json_typed(data);
+        let parameters = env.input.clone().unwrap();
+        let cancel = env.cancel_token();
+        let mut writer = ChunkWriter::new(&env, &parameters).unwrap();
+        let output = simulate_chunks(
+            &parameters,
+            Streams::from_env(&env),
+            25,
+            &cancel,
+            |simulation, steps| {
+                writer.write(simulation, steps.clone())?;
+                if steps.end == 50 {
+                    let remote = cancel.clone();
+                    std::thread::spawn(move || remote.cancel("SIGTERM"))
+                        .join()
+                        .unwrap();
+                }
+                Ok(())
+            },
+        )
+        .unwrap();
+        drop(writer);
+        assert_eq!(output.infection_incidence.len(), 50);
+        env.finalize().unwrap();
+
+        let csv = fs::read_to_string(dir.path().join("renewal_output.csv")).unwrap();
+        assert_eq!(csv.lines().count(), 51);
+        let info: RunInfo =
+            serde_json::from_slice(&fs::read(dir.path().join("checkpoint.json")).unwrap()).unwrap();
+        assert_eq!(info.sim_length, 50);
+        let complete: serde_json::Value =
+            serde_json::from_slice(&fs::read(dir.path().join("complete.json")).unwrap()).unwrap();
+        assert_eq!(complete["status"], "cancelled");
+        assert_eq!(complete["reason"], "SIGTERM");
+    }
+}
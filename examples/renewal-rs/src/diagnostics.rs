@@ -0,0 +1,329 @@
+use cfa_mrp::{ReportSection, report};
+use serde::Serialize;
+
+use crate::output::RenewalOutput;
+use crate::parameters::Parameters;
+
+/// The outcome of one self-check on a finished run.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Diagnostic {
+    pub check: String,
+    pub passed: bool,
+    pub detail: String,
+}
+
+/// The diagnostics section of the run report.
+pub struct DiagnosticsSection(pub Vec<Diagnostic>);
+
+impl ReportSection for DiagnosticsSection {
+    fn title(&self) -> String {
+        "Diagnostics".to_string()
+    }
+
+    fn body(&self) -> String {
+        report::table(
+            &["check", "result", "detail"],
+            self.0.iter().map(|d| {
+                vec![
+                    d.check.clone(),
+                    if d.passed { "passed" } else { "failed" }.to_string(),
+                    d.detail.clone(),
+                ]
+            }),
+        )
+    }
+}
+
+/// A named self-check; `Err` carries what was wrong.
+pub struct Check {
+    pub name: &'static str,
+    pub run: fn(&RenewalOutput, &Parameters) -> Result<(), String>,
+}
+
+/// The checks run after every simulation.
+pub const CHECKS: &[Check] = &[
+    Check {
+        name: "conservation",
+        run: conservation,
+    },
+    Check {
+        name: "nonzero_output",
+        run: nonzero_output,
+    },
+    Check {
+        name: "monotonic_cumulative",
+        run: monotonic_cumulative,
+    },
+    Check {
+        name: "finite_values",
+        run: finite_values,
+    },
+    Check {
+        name: "incidence_within_population",
+        run: incidence_within_population,
+    },
+    Check {
+        name: "onsets_within_infections",
+        run: onsets_within_infections,
+    },
+    Check {
+        name: "nonnegative",
+        run: nonnegative,
+    },
+];
+
+/// Run [`CHECKS`], skipping any named in `parameters.disabled_checks`.
+pub fn diagnostics(output: &RenewalOutput, parameters: &Parameters) -> Vec<Diagnostic> {
+    run_checks(CHECKS, output, parameters)
+}
+
+pub fn run_checks(
+    checks: &[Check],
+    output: &RenewalOutput,
+    parameters: &Parameters,
+) -> Vec<Diagnostic> {
+    checks
+        .iter()
+        .filter(|c| !parameters.disabled_checks.iter().any(|d| d == c.name))
+        .map(|c| {
+            let result = (c.run)(output, parameters);
+            Diagnostic {
+                check: c.name.to_string(),
+                passed: result.is_ok(),
+                detail: result.err().unwrap_or_default(),
+            }
+        })
+        .collect()
+}
+
+/// Seeded steps carry exactly the requested initial infections.
+fn conservation(output: &RenewalOutput, parameters: &Parameters) -> Result<(), String> {
+    for (step, (&got, &seeded)) in output
+        .infection_incidence
+        .iter()
+        .zip(&parameters.initial_infections)
+        .enumerate()
+    {
+        if got != seeded {
+            return Err(format!(
+                "step {step} has {got} infections but {seeded} were seeded"
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn nonzero_output(output: &RenewalOutput, parameters: &Parameters) -> Result<(), String> {
+    let seeded: u64 = parameters.initial_infections.iter().sum();
+    if seeded > 0 && output.infection_incidence.iter().all(|&x| x == 0) {
+        return Err(format!(
+            "{seeded} infections were seeded but output is all zero"
+        ));
+    }
+    Ok(())
+}
+
+/// Cumulative counts must not overflow (and so never decrease).
+fn monotonic_cumulative(output: &RenewalOutput, _: &Parameters) -> Result<(), String> {
+    for (name, series) in [
+        ("infections", &output.infection_incidence),
+        ("symptom_onsets", &output.symptomatic_incidence),
+    ] {
+        let mut total: u64 = 0;
+        for (step, &x) in series.iter().enumerate() {
+            total = total
+                .checked_add(x)
+                .ok_or_else(|| format!("cumulative {name} overflows at step {step}"))?;
+        }
+    }
+    Ok(())
+}
+
+/// Every float that feeds the simulation is finite.
+fn finite_values(_: &RenewalOutput, parameters: &Parameters) -> Result<(), String> {
+    if !parameters.r0.is_finite() {
+        return Err(format!("r0 is {}", parameters.r0));
+    }
+    for (name, pmf) in [
+        (
+            "generation_interval_pmf",
+            &parameters.generation_interval_pmf,
+        ),
+        ("symptom_onset_pmf", &parameters.symptom_onset_pmf),
+    ] {
+        if let Some(i) = pmf.iter().position(|x| !x.is_finite()) {
+            return Err(format!("{name}[{i}] is {}", pmf[i]));
+        }
+    }
+    Ok(())
+}
+
+fn incidence_within_population(
+    output: &RenewalOutput,
+    parameters: &Parameters,
+) -> Result<(), String> {
+    let Some(population) = parameters.population else {
+        return Ok(());
+    };
+    let total = output
+        .infection_incidence
+        .iter()
+        .fold(0u64, |acc, &x| acc.saturating_add(x));
+    if total > population {
+        return Err(format!(
+            "{total} cumulative infections exceed population {population}"
+        ));
+    }
+    Ok(())
+}
+
+/// Onsets through each step never exceed infections before it.
+fn onsets_within_infections(output: &RenewalOutput, _: &Parameters) -> Result<(), String> {
+    let (mut infections, mut onsets) = (0u64, 0u64);
+    for (step, (&i, &o)) in output
+        .infection_incidence
+        .iter()
+        .zip(&output.symptomatic_incidence)
+        .enumerate()
+    {
+        onsets = onsets.saturating_add(o);
+        if onsets > infections {
+            return Err(format!(
+                "{onsets} cumulative onsets by step {step} exceed {infections} earlier infections"
+            ));
+        }
+        infections = infections.saturating_add(i);
+    }
+    Ok(())
+}
+
+/// Counts at or above 2^63 are almost certainly a wrapped negative number.
+fn nonnegative(output: &RenewalOutput, _: &Parameters) -> Result<(), String> {
+    for (name, series) in [
+        ("infections", &output.infection_incidence),
+        ("symptom_onsets", &output.symptomatic_incidence),
+    ] {
+        if let Some(step) = series.iter().position(|&x| x > i64::MAX as u64) {
+            return Err(format!(
+                "{name} at step {step} is {} (wrapped negative?)",
+                series[step]
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::renewal::RenewalModel;
+
+    fn parameters() -> Parameters {
+        Parameters {
+            r0: 1.5,
+            generation_interval_pmf: vec![0.5, 0.5],
+            symptom_onset_pmf: vec![1.],
+            initial_infections: vec![3],
+            sim_length: 5,
+            population: Some(100),
+            ..Default::default()
+        }
+    }
+
+    fn output(infections: Vec<u64>, onsets: Vec<u64>) -> RenewalOutput {
+        RenewalOutput {
+            infection_incidence: infections,
+            symptomatic_incidence: onsets,
+            ..Default::default()
+        }
+    }
+
+    fn failed(output: &RenewalOutput, parameters: &Parameters) -> Vec<String> {
+        diagnostics(output, parameters)
+            .into_iter()
+            .filter(|d| !d.passed)
+            .map(|d| d.check)
+            .collect()
+    }
+
+    #[test]
+    fn test_simulated_run_passes() {
+        let parameters = parameters();
+        let output = RenewalModel::simulate(&parameters);
+        assert_eq!(failed(&output, &parameters), Vec::<String>::new());
+        assert_eq!(diagnostics(&output, &parameters).len(), CHECKS.len());
+    }
+
+    #[test]
+    fn test_each_check_fails() {
+        let p = parameters();
+        let cases = [
+            (output(vec![2, 1], vec![0, 1]), p.clone(), "conservation"),
+            (
+                output(vec![0, 0], vec![0, 0]),
+                Parameters {
+                    initial_infections: vec![0, 0, 3],
+                    ..p.clone()
+                },
+                "nonzero_output",
+            ),
+            (
+                output(vec![3, u64::MAX / 2 + 1, u64::MAX / 2 + 1], vec![0, 0, 0]),
+                Parameters {
+                    population: None,
+                    ..p.clone()
+                },
+                "monotonic_cumulative",
+            ),
+            (
+                output(vec![3, 1], vec![0, 1]),
+                Parameters {
+                    r0: f64::NAN,
+                    ..p.clone()
+                },
+                "finite_values",
+            ),
+            (
+                output(vec![3, 200], vec![0, 3]),
+                p.clone(),
+                "incidence_within_population",
+            ),
+            (
+                output(vec![3, 1], vec![2, 5]),
+                p.clone(),
+                "onsets_within_infections",
+            ),
+            (
+                output(vec![3, 0u64.wrapping_sub(2)], vec![0, 0]),
+                Parameters {
+                    population: None,
+                    ..p.clone()
+                },
+                "nonnegative",
+            ),
+        ];
+        for (output, parameters, check) in cases {
+            let failures = failed(&output, &parameters);
+            assert!(
+                failures.contains(&check.to_string()),
+                "{check}: {failures:?}"
+            );
+            let record = diagnostics(&output, &parameters)
+                .into_iter()
+                .find(|d| d.check == check)
+                .unwrap();
+            assert!(!record.detail.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_disabled_checks_are_skipped() {
+        let parameters = Parameters {
+            disabled_checks: vec!["conservation".to_string()],
+            ..parameters()
+        };
+        let records = diagnostics(&output(vec![2, 1], vec![0, 1]), &parameters);
+        assert!(records.iter().all(|d| d.check != "conservation"));
+        assert!(records.iter().all(|d| d.passed));
+    }
+}
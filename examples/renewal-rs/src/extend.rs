@@ -0,0 +1,361 @@
+use std::collections::BTreeMap;
+
+use cfa_mrp::provenance::sha256_hex;
+use cfa_mrp::{IoPoolStats, MrpError};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::output::RenewalOutput;
+use crate::parameters::{Aggregate, Parameters};
+use crate::presets::Applied;
+
+/// What a run records about itself so it can later be extended.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunInfo {
+    pub seed: u64,
+    /// Hash of every parameter except `sim_length`, the extend settings,
+    /// disabled checks, `chunk_steps` and `trace`.
+    pub parameters_sha256: String,
+    pub sim_length: usize,
+    /// The preset the input was based on, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>,
+    /// Preset values the input replaced, keyed by input field.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub preset_overrides: BTreeMap<String, Value>,
+    /// Warnings raised per code, including suppressed duplicates.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub warning_counts: BTreeMap<String, u64>,
+    /// Background IO pool counters when run_info was written.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub io_pool: Option<IoPoolStats>,
+    /// Infections of the last cohorts whose symptom onsets fall past
+    /// `sim_length`, by infection step; see [`RenewalOutput::pending_onsets`].
+    /// Required to extend the run.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pending_onsets: Option<BTreeMap<usize, u64>>,
+}
+
+impl RunInfo {
+    pub fn new(parameters: &Parameters) -> Self {
+        RunInfo {
+            seed: parameters.seed,
+            parameters_sha256: parameters_hash(parameters),
+            sim_length: parameters.sim_length,
+            preset: None,
+            preset_overrides: BTreeMap::new(),
+            warning_counts: BTreeMap::new(),
+            io_pool: None,
+            pending_onsets: None,
+        }
+    }
+
+    pub fn with_preset(mut self, preset: Option<&Applied>) -> Self {
+        if let Some(applied) = preset {
+            self.preset = Some(applied.name.clone());
+            self.preset_overrides = applied.overrides.clone();
+        }
+        self
+    }
+
+    pub fn with_warning_counts(mut self, counts: BTreeMap<String, u64>) -> Self {
+        self.warning_counts = counts;
+        self
+    }
+
+    pub fn with_io_pool(mut self, stats: IoPoolStats) -> Self {
+        self.io_pool = Some(stats);
+        self
+    }
+
+    pub fn with_pending_onsets(mut self, pending: Option<BTreeMap<usize, u64>>) -> Self {
+        self.pending_onsets = pending;
+        self
+    }
+}
+
+fn parameters_hash(parameters: &Parameters) -> String {
+    let identity = Parameters {
+        sim_length: 0,
+        extend: None,
+        force_extend: false,
+        disabled_checks: Vec::new(),
+        chunk_steps: None,
+        trace: None,
+        emit_tree: None,
+        ..parameters.clone()
+    };
+    sha256_hex(&serde_json::to_vec(&identity).expect("failed to serialize parameters"))
+}
+
+/// Check that `prior` describes a run these parameters can extend.
+///
+/// A seed or parameter mismatch is an error unless `force_extend` is set.
+pub fn check_prior(prior: &RunInfo, parameters: &Parameters) -> Result<(), MrpError> {
+    if parameters.aggregate != Aggregate::Daily {
+        return Err(MrpError::Input(
+            "extend requires daily output (aggregate = \"daily\")".to_string(),
+        ));
+    }
+    if parameters.outputs.is_some() {
+        return Err(MrpError::Input(
+            "extend requires the default outputs (infections and symptom_onsets)".to_string(),
+        ));
+    }
+    if parameters.replicates.is_some() || parameters.dt.is_some() {
+        return Err(MrpError::Input(
+            "extend does not support in-process replicates or dt".to_string(),
+        ));
+    }
+    if prior.pending_onsets.is_none() {
+        return Err(MrpError::Input(
+            "prior run info does not record its pending symptom onsets, so the run cannot be \
+             extended"
+                .to_string(),
+        ));
+    }
+    if prior.sim_length > parameters.sim_length {
+        return Err(MrpError::Input(format!(
+            "cannot extend a {}-step run to {} steps",
+            prior.sim_length, parameters.sim_length
+        )));
+    }
+    let current = RunInfo::new(parameters);
+    if !parameters.force_extend {
+        if prior.seed != current.seed {
+            return Err(MrpError::Input(format!(
+                "prior run used seed {} but this run has seed {} (set force_extend to override)",
+                prior.seed, current.seed
+            )));
+        }
+        if prior.parameters_sha256 != current.parameters_sha256 {
+            return Err(MrpError::Input(
+                "prior run's parameters differ from this run's (set force_extend to override)"
+                    .to_string(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Parse a prior daily `renewal_output.csv` back into a trajectory, with
+/// the pending onsets `prior` records.
+pub fn load_prior(csv: &str, prior: &RunInfo) -> Result<RenewalOutput, MrpError> {
+    let mut output = load_trajectory(csv, prior.sim_length)?;
+    let pending = prior.pending_onsets.clone().unwrap_or_default();
+    for (&step, &count) in &pending {
+        if output
+            .infection_incidence
+            .get(step)
+            .is_none_or(|&n| count > n)
+        {
+            return Err(MrpError::Input(format!(
+                "prior run info records {count} pending onsets for step {step}, which has \
+                 fewer infections"
+            )));
+        }
+    }
+    output.pending_onsets = pending;
+    Ok(output)
+}
+
+/// Parse a prior daily `renewal_output.csv` back into a trajectory.
+pub fn load_trajectory(csv: &str, expected_len: usize) -> Result<RenewalOutput, MrpError> {
+    let mut lines = csv.lines();
+    let headers: Vec<&str> = lines.next().unwrap_or_default().split(',').collect();
+    let column = |name: &str| {
+        headers
+            .iter()
+            .position(|h| *h == name)
+            .ok_or_else(|| MrpError::Input(format!("prior trajectory has no '{name}' column")))
+    };
+    let (step_col, inf_col, onset_col) = (
+        column("step")?,
+        column("infections")?,
+        column("symptom_onsets")?,
+    );
+    let mut output = RenewalOutput::default();
+    for (i, line) in lines.filter(|l| !l.is_empty()).enumerate() {
+        let cells: Vec<&str> = line.split(',').collect();
+        let cell = |col: usize| -> Result<u64, MrpError> {
+            cells
+                .get(col)
+                .and_then(|c| c.parse().ok())
+                .ok_or_else(|| MrpError::Input(format!("bad prior trajectory row {i}: {line}")))
+        };
+        if cell(step_col)? != i as u64 {
+            return Err(MrpError::Input(format!(
+                "prior trajectory steps are not contiguous from 0 at row {i}"
+            )));
+        }
+        output.infection_incidence.push(cell(inf_col)?);
+        output.symptomatic_incidence.push(cell(onset_col)?);
+    }
+    if output.infection_incidence.len() != expected_len {
+        return Err(MrpError::Input(format!(
+            "prior trajectory has {} rows but its run info records {expected_len} steps",
+            output.infection_incidence.len()
+        )));
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::renewal::RenewalModel;
+
+    fn parameters(sim_length: usize, population: Option<u64>) -> Parameters {
+        Parameters {
+            r0: 1.8,
+            generation_interval_pmf: vec![0., 0.25, 0.5, 0.25],
+            symptom_onset_pmf: vec![0.1, 0.3, 0.4, 0.2],
+            initial_infections: vec![20],
+            sim_length,
+            population,
+            seed: 2024,
+            ..Default::default()
+        }
+    }
+
+    fn to_csv(output: &RenewalOutput, parameters: &Parameters) -> String {
+        let (headers, rows) = output.to_rows(parameters).unwrap();
+        let mut csv = headers.join(",") + "\n";
+        for row in rows {
+            csv += &(row.join(",") + "\n");
+        }
+        csv
+    }
+
+    #[test]
+    fn test_extend_continues_prior_run() {
+        for population in [None, Some(50_000)] {
+            let short = parameters(12 * 7, population);
+            let prior = RenewalModel::simulate(&short);
+            let info = RunInfo::new(&short).with_pending_onsets(Some(prior.pending_onsets.clone()));
+
+            let long = parameters(16 * 7, population);
+            check_prior(&info, &long).unwrap();
+            let loaded = load_prior(&to_csv(&prior, &short), &info).unwrap();
+            let extended = RenewalModel::extend(&long, &loaded);
+            assert_eq!(extended.infection_incidence.len(), 16 * 7);
+            assert!(to_csv(&extended, &long).starts_with(&to_csv(&prior, &short)));
+            let again = RenewalModel::extend(&long, &loaded);
+            assert_eq!(again.infection_incidence, extended.infection_incidence);
+            assert_eq!(again.symptomatic_incidence, extended.symptomatic_incidence);
+        }
+    }
+
+    #[test]
+    fn test_extend_matches_straight_run_in_distribution() {
+        // A straight run's first 12 weeks of infections are the prior run's,
+        // so each seed's 4 new weeks should agree with the straight run's up
+        // to the noise of those weeks alone
+        let (mut extended_total, mut straight_total) = ([0; 2], [0; 2]);
+        for seed in 0..100 {
+            let short = Parameters {
+                seed,
+                ..parameters(12 * 7, None)
+            };
+            let long = Parameters {
+                sim_length: 16 * 7,
+                ..short.clone()
+            };
+            let prior = RenewalModel::simulate(&short);
+            let straight = RenewalModel::simulate(&long);
+            assert_eq!(
+                straight.infection_incidence[..12 * 7],
+                prior.infection_incidence
+            );
+            let extended = RenewalModel::extend(&long, &prior);
+            for (totals, output) in [
+                (&mut extended_total, &extended),
+                (&mut straight_total, &straight),
+            ] {
+                totals[0] += output.infection_incidence[12 * 7..].iter().sum::<u64>();
+                totals[1] += output.symptomatic_incidence[12 * 7..].iter().sum::<u64>();
+            }
+        }
+        for (extended, straight) in extended_total.iter().zip(&straight_total) {
+            let ratio = *extended as f64 / *straight as f64;
+            assert!((ratio - 1.0).abs() < 0.01, "{ratio}");
+        }
+    }
+
+    #[test]
+    fn test_extension_conserves_each_cohort() {
+        // One infected cohort, whose onsets straddle the prior run's end
+        for (initial_infections, infections) in [(vec![400], 400), (vec![0, 0, 250], 250)] {
+            for seed in 0..50 {
+                let short = Parameters {
+                    r0: 0.,
+                    generation_interval_pmf: vec![1.],
+                    symptom_onset_pmf: vec![0.1, 0.2, 0.3, 0.25, 0.15],
+                    initial_infections: initial_infections.clone(),
+                    sim_length: 5,
+                    seed,
+                    ..Default::default()
+                };
+                let long = Parameters {
+                    sim_length: 12,
+                    ..short.clone()
+                };
+                let prior = RenewalModel::simulate(&short);
+                let placed: u64 = prior.symptomatic_incidence.iter().sum();
+                let pending: u64 = prior.pending_onsets.values().sum();
+                assert_eq!(placed + pending, infections);
+
+                let extended = RenewalModel::extend(&long, &prior);
+                assert_eq!(
+                    extended.symptomatic_incidence[..5],
+                    prior.symptomatic_incidence
+                );
+                assert_eq!(
+                    extended.symptomatic_incidence.iter().sum::<u64>(),
+                    infections
+                );
+                assert!(extended.pending_onsets.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn test_tampered_prior_rejected() {
+        let long = parameters(20, None);
+        let recorded = |parameters: &Parameters| {
+            RunInfo::new(parameters).with_pending_onsets(Some(BTreeMap::new()))
+        };
+        let mut info = recorded(&parameters(10, None));
+        info.parameters_sha256 = RunInfo::new(&Parameters {
+            r0: 2.5,
+            ..parameters(10, None)
+        })
+        .parameters_sha256;
+        let err = check_prior(&info, &long).unwrap_err();
+        assert!(err.to_string().contains("parameters differ"));
+
+        let mut reseeded = recorded(&parameters(10, None));
+        reseeded.seed += 1;
+        assert!(check_prior(&reseeded, &long).is_err());
+
+        let unrecorded = RunInfo::new(&parameters(10, None));
+        let err = check_prior(&unrecorded, &long).unwrap_err();
+        assert!(err.to_string().contains("pending symptom onsets"), "{err}");
+
+        let forced = Parameters {
+            force_extend: true,
+            ..long
+        };
+        check_prior(&info, &forced).unwrap();
+    }
+
+    #[test]
+    fn test_load_trajectory_checks_rows() {
+        let csv = "step,infections,symptom_onsets\n0,5,0\n2,3,1\n";
+        assert!(load_trajectory(csv, 2).is_err());
+        let csv = "step,infections,symptom_onsets\n0,5,0\n1,3,1\n";
+        assert!(load_trajectory(csv, 3).is_err());
+        let output = load_trajectory(csv, 2).unwrap();
+        assert_eq!(output.infection_incidence, vec![5, 3]);
+    }
+}
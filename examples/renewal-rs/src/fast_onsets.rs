@@ -0,0 +1,204 @@
+use cfa_mrp::seed::derive_seed;
+use rand::SeedableRng;
+use rand::distr::Distribution;
+use rand::rngs::StdRng;
+use rand_distr::{Normal, Poisson};
+
+use crate::trace::Draw;
+
+/// Bins expecting fewer onsets than this are drawn from a Poisson rather
+/// than a normal.
+const NORMAL_ABOVE: f64 = 30.0;
+
+/// Approximately split `infections` over onset delays with probabilities
+/// `masses`, conserving the total.
+///
+/// Each bin, plus one for the mass beyond the last, is drawn on its own
+/// from a normal with the binomial's mean and variance, or a Poisson for
+/// small means, using a generator seeded from `(seed, bin)`. Bins are
+/// therefore independent of one another and of the order they are drawn
+/// in. The drawn total is then corrected to exactly `infections`, adding
+/// any shortfall to the bin with the largest mean and taking any excess
+/// from bins in order of decreasing mean.
+///
+/// `seed` should already identify the stream and step. The draws' values
+/// are after the correction.
+pub fn allocate(infections: u64, masses: &[f64], seed: u64) -> Vec<Draw> {
+    let beyond = f64::max(1.0 - masses.iter().sum::<f64>(), 0.0);
+    let mut draws: Vec<Draw> = masses
+        .iter()
+        .chain([&beyond])
+        .enumerate()
+        .map(|(bin, &p)| draw_bin(infections, p, derive_seed(seed, bin as u64)))
+        .collect();
+
+    let mut order: Vec<usize> = (0..draws.len()).collect();
+    order.sort_by(|&a, &b| f64::total_cmp(&mean(&draws[b]), &mean(&draws[a])));
+    let total: u64 = draws.iter().map(Draw::value).sum();
+    if total < infections {
+        *value_mut(&mut draws[order[0]]) += infections - total;
+    } else {
+        let mut excess = total - infections;
+        for &bin in &order {
+            let value = value_mut(&mut draws[bin]);
+            let taken = u64::min(*value, excess);
+            *value -= taken;
+            excess -= taken;
+        }
+    }
+    draws.truncate(masses.len());
+    draws
+}
+
+fn draw_bin(n: u64, p: f64, seed: u64) -> Draw {
+    let mean = n as f64 * p;
+    if mean <= 0.0 {
+        return Draw::Poisson {
+            rate: 0.0,
+            value: 0,
+        };
+    }
+    let mut rng = StdRng::seed_from_u64(seed);
+    if mean < NORMAL_ABOVE {
+        let value = Poisson::new(mean).unwrap().sample(&mut rng) as u64;
+        return Draw::Poisson {
+            rate: mean,
+            value: value.min(n),
+        };
+    }
+    let sd = f64::sqrt(mean * (1.0 - p).max(0.0));
+    let value = Normal::new(mean, sd).unwrap().sample(&mut rng).round();
+    Draw::Normal {
+        mean,
+        sd,
+        value: value.clamp(0.0, n as f64) as u64,
+    }
+}
+
+fn mean(draw: &Draw) -> f64 {
+    match draw {
+        Draw::Binomial { n, p, .. } => *n as f64 * p,
+        Draw::Poisson { rate, .. } => *rate,
+        Draw::Normal { mean, .. } => *mean,
+    }
+}
+
+fn value_mut(draw: &mut Draw) -> &mut u64 {
+    match draw {
+        Draw::Binomial { value, .. } | Draw::Poisson { value, .. } | Draw::Normal { value, .. } => {
+            value
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Instant;
+
+    use rand::Rng;
+
+    use super::*;
+    use crate::parameters::{FastOnsets, Parameters};
+    use crate::renewal::RenewalModel;
+
+    #[test]
+    fn test_total_is_conserved() {
+        let mut rng = StdRng::seed_from_u64(3);
+        for case in 0..500 {
+            let bins = rng.random_range(1..12);
+            let weights: Vec<f64> = (0..bins).map(|_| rng.random::<f64>()).collect();
+            let sum: f64 = weights.iter().sum();
+            let masses: Vec<f64> = weights.iter().map(|w| w / sum).collect();
+            let infections = match case % 3 {
+                0 => rng.random_range(0..50),
+                1 => rng.random_range(0..100_000),
+                _ => rng.random_range(0..100_000_000),
+            };
+            let draws = allocate(infections, &masses, case);
+            assert_eq!(draws.len(), bins);
+            let total: u64 = draws.iter().map(Draw::value).sum();
+            // All but rounding-level mass falls within the bins
+            assert!(total <= infections && infections - total <= 1, "{case}");
+
+            // Dropping trailing bins only loses their onsets
+            let truncated = allocate(infections, &masses[..bins.div_ceil(2)], case);
+            assert!(truncated.iter().map(Draw::value).sum::<u64>() <= infections);
+            assert_eq!(allocate(infections, &masses, case), draws);
+        }
+    }
+
+    #[test]
+    fn test_symptom_onset_recovered() {
+        let initial_infections = 1_000_000;
+        let symptom_onset_pmf = vec![0., 0., 0.25, 0.5, 0.25];
+        let parameters = Parameters {
+            population: None,
+            r0: 0.,
+            generation_interval_pmf: vec![1.],
+            symptom_onset_pmf: symptom_onset_pmf.clone(),
+            initial_infections: vec![initial_infections],
+            sim_length: symptom_onset_pmf.len() + 1,
+            seed: 8675310,
+            fast_onsets: Some(FastOnsets {
+                threshold: 1000,
+                force_exact: false,
+            }),
+            ..Default::default()
+        };
+        let output = RenewalModel::simulate(&parameters);
+        let total: u64 = output.symptomatic_incidence.iter().sum();
+        assert_eq!(total, initial_infections);
+        for (step, mass) in symptom_onset_pmf.iter().enumerate() {
+            let fraction = output.symptomatic_incidence[step + 1] as f64 / total as f64;
+            assert!(f64::abs(fraction - mass) < 1e-3);
+        }
+
+        let exact = Parameters {
+            fast_onsets: Some(FastOnsets {
+                threshold: 1000,
+                force_exact: true,
+            }),
+            ..parameters.clone()
+        };
+        assert_eq!(
+            RenewalModel::simulate(&exact).symptomatic_incidence,
+            RenewalModel::simulate(&Parameters {
+                fast_onsets: None,
+                ..parameters
+            })
+            .symptomatic_incidence
+        );
+    }
+
+    /// `cargo test --release -p renewal bench_onsets -- --ignored --nocapture`
+    ///
+    /// Binomial sampling takes constant time in `n`, so the exact chain
+    /// costs the same at 10^7 infections as at 10^3; seeding one generator
+    /// per bin made the approximation about 2x slower when last measured.
+    #[test]
+    #[ignore]
+    fn bench_onsets_at_ten_million() {
+        let masses = [0.05, 0.1, 0.2, 0.25, 0.15, 0.1, 0.06, 0.04, 0.03, 0.02];
+        let parameters = Parameters {
+            symptom_onset_pmf: masses.to_vec(),
+            sim_length: 1000,
+            ..Default::default()
+        };
+        let steps = 2000;
+        let mut rng = StdRng::seed_from_u64(1);
+        let start = Instant::now();
+        for step in 0..steps {
+            RenewalModel::onset_draws(&parameters, &mut rng, step % 900, 10_000_000);
+        }
+        let exact = start.elapsed();
+        let start = Instant::now();
+        for step in 0..steps {
+            allocate(10_000_000, &masses, step as u64);
+        }
+        let fast = start.elapsed();
+        println!(
+            "10^7 infections x {steps} steps: exact {exact:?}, approximate {fast:?} ({:.1}x)",
+            exact.as_secs_f64() / fast.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,228 @@
+use cfa_mrp::MrpError;
+use serde::Serialize;
+
+use crate::parameters::{OutputStream, Parameters};
+
+/// Simulated counts of zero are scored as this, so one day the model
+/// missed does not make a likelihood infinite.
+const ZERO_FLOOR: f64 = 0.5;
+
+/// One row of the `observed_cases` file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Observation {
+    pub day: usize,
+    pub count: u64,
+}
+
+/// How well simulated reported cases match the observations.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Fit {
+    pub observations: usize,
+    pub poisson_loglik: f64,
+    /// Present when `dispersion` is set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub negbin_loglik: Option<f64>,
+    pub rmse: f64,
+    /// Share of observations inside the replicates' min–max envelope;
+    /// present for in-process replicate runs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub coverage: Option<f64>,
+}
+
+impl Fit {
+    /// The fit as `fit_*` metrics.
+    pub fn metrics(&self) -> Vec<(&'static str, f64)> {
+        let mut metrics = vec![
+            ("fit_poisson_loglik", self.poisson_loglik),
+            ("fit_rmse", self.rmse),
+        ];
+        if let Some(ll) = self.negbin_loglik {
+            metrics.push(("fit_negbin_loglik", ll));
+        }
+        if let Some(coverage) = self.coverage {
+            metrics.push(("fit_coverage", coverage));
+        }
+        metrics
+    }
+}
+
+/// Parse an observed-cases CSV with `day` and `count` columns, checking it
+/// against the parameters it will be compared with.
+pub fn parse_observed(csv: &str, parameters: &Parameters) -> Result<Vec<Observation>, MrpError> {
+    if !parameters.wants(OutputStream::ReportedCases) {
+        return Err(MrpError::Input(
+            "observed_cases requires reported_cases in outputs".to_string(),
+        ));
+    }
+    if parameters
+        .dispersion
+        .is_some_and(|k| k.is_nan() || k <= 0.0)
+    {
+        return Err(MrpError::Input("dispersion must be positive".to_string()));
+    }
+    if parameters.dt.is_some() {
+        return Err(MrpError::Input(
+            "observed_cases is not supported with dt".to_string(),
+        ));
+    }
+    let mut lines = csv.lines();
+    let headers: Vec<&str> = lines.next().unwrap_or_default().split(',').collect();
+    let column = |name: &str| {
+        headers
+            .iter()
+            .position(|h| h.trim() == name)
+            .ok_or_else(|| MrpError::Input(format!("observed_cases has no '{name}' column")))
+    };
+    let (day_col, count_col) = (column("day")?, column("count")?);
+    let mut observed = Vec::new();
+    for (i, line) in lines.filter(|l| !l.trim().is_empty()).enumerate() {
+        let cells: Vec<&str> = line.split(',').collect();
+        let cell = |col: usize| -> Result<u64, MrpError> {
+            cells
+                .get(col)
+                .and_then(|c| c.trim().parse().ok())
+                .ok_or_else(|| MrpError::Input(format!("bad observed_cases row {i}: {line}")))
+        };
+        let day = cell(day_col)? as usize;
+        if day >= parameters.sim_length {
+            return Err(MrpError::Input(format!(
+                "observed_cases row {i}: day {day} is beyond sim_length {}",
+                parameters.sim_length
+            )));
+        }
+        observed.push(Observation {
+            day,
+            count: cell(count_col)?,
+        });
+    }
+    Ok(observed)
+}
+
+/// Score `observed` against the reported-case series of one or more runs.
+///
+/// Likelihoods and RMSE use the mean across `runs`; coverage is computed
+/// when there is more than one run.
+pub fn fit(observed: &[Observation], runs: &[&[u64]], dispersion: Option<f64>) -> Fit {
+    let mut poisson = 0.0;
+    let mut negbin = 0.0;
+    let mut squared = 0.0;
+    let mut covered = 0;
+    for obs in observed {
+        let values = runs.iter().map(|run| run[obs.day]);
+        let mean = values.clone().sum::<u64>() as f64 / runs.len() as f64;
+        let (lo, hi) = (values.clone().min(), values.max());
+        covered += (lo <= Some(obs.count) && Some(obs.count) <= hi) as usize;
+        let y = obs.count;
+        let mu = if mean > 0.0 { mean } else { ZERO_FLOOR };
+        poisson += y as f64 * mu.ln() - mu - ln_factorial(y);
+        if let Some(k) = dispersion {
+            negbin += ln_rising(k, y) - ln_factorial(y)
+                + k * (k / (k + mu)).ln()
+                + y as f64 * (mu / (k + mu)).ln();
+        }
+        squared += (y as f64 - mean).powi(2);
+    }
+    let n = observed.len();
+    Fit {
+        observations: n,
+        poisson_loglik: poisson,
+        negbin_loglik: dispersion.map(|_| negbin),
+        rmse: if n > 0 {
+            (squared / n as f64).sqrt()
+        } else {
+            0.0
+        },
+        coverage: (runs.len() > 1 && n > 0).then(|| covered as f64 / n as f64),
+    }
+}
+
+/// `ln(y!)`.
+fn ln_factorial(y: u64) -> f64 {
+    (2..=y).map(|i| (i as f64).ln()).sum()
+}
+
+/// `ln(Γ(k + y) / Γ(k))`, the log rising factorial.
+fn ln_rising(k: f64, y: u64) -> f64 {
+    (0..y).map(|i| (k + i as f64).ln()).sum()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::renewal::RenewalModel;
+
+    fn parameters(r0: f64) -> Parameters {
+        Parameters {
+            r0,
+            generation_interval_pmf: vec![0.2, 0.5, 0.3],
+            symptom_onset_pmf: vec![0.1, 0.6, 0.3],
+            initial_infections: vec![20],
+            sim_length: 50,
+            population: Some(100_000),
+            seed: 321,
+            outputs: Some(vec![OutputStream::ReportedCases]),
+            ascertainment: Some(0.4),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_self_fit_beats_perturbed() {
+        let truth = RenewalModel::simulate(&parameters(1.6));
+        let csv: String = std::iter::once("day,count".to_string())
+            .chain(
+                (0..50)
+                    .step_by(3)
+                    .map(|day| format!("{day},{}", truth.reported_incidence[day])),
+            )
+            .collect::<Vec<_>>()
+            .join("\n");
+        let observed = parse_observed(&csv, &parameters(1.6)).unwrap();
+        assert_eq!(observed.len(), 17);
+
+        let same = fit(&observed, &[&truth.reported_incidence], Some(10.0));
+        let other = RenewalModel::simulate(&parameters(1.3));
+        let perturbed = fit(&observed, &[&other.reported_incidence], Some(10.0));
+        assert_eq!(same.rmse, 0.0);
+        assert!(same.poisson_loglik > perturbed.poisson_loglik);
+        assert!(same.negbin_loglik.unwrap() > perturbed.negbin_loglik.unwrap());
+        assert!(perturbed.rmse > 0.0);
+        assert_eq!(same.coverage, None);
+    }
+
+    #[test]
+    fn test_likelihood_values() {
+        let observed = [Observation { day: 0, count: 3 }];
+        let f = fit(&observed, &[&[2]], Some(4.0));
+        // Poisson(3; 2) and NB(3; mean 2, size 4)
+        assert!((f.poisson_loglik - (0.180_447_044_315_483_6f64).ln()).abs() < 1e-12);
+        let nb = 20.0 * (4.0f64 / 6.0).powi(4) * (2.0f64 / 6.0).powi(3);
+        assert!((f.negbin_loglik.unwrap() - nb.ln()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_replicate_coverage() {
+        let observed = [
+            Observation { day: 0, count: 5 },
+            Observation { day: 1, count: 9 },
+        ];
+        let f = fit(&observed, &[&[4, 6], &[6, 8]], None);
+        assert_eq!(f.coverage, Some(0.5));
+        assert_eq!(f.negbin_loglik, None);
+        assert!((f.rmse - (0.5f64 * 4.0).sqrt()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_days_beyond_sim_length_rejected() {
+        let err = parse_observed("day,count\n10,1\n50,2\n", &parameters(1.6)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "input error: observed_cases row 1: day 50 is beyond sim_length 50"
+        );
+        let default_outputs = Parameters {
+            outputs: None,
+            ..parameters(1.6)
+        };
+        assert!(parse_observed("day,count\n", &default_outputs).is_err());
+    }
+}
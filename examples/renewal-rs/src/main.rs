@@ -1,29 +1,199 @@
+pub mod chunk;
+pub mod diagnostics;
+pub mod extend;
+pub mod fast_onsets;
+pub mod fit;
 pub mod output;
 pub mod parameters;
+pub mod presets;
 pub mod renewal;
+pub mod replicates;
+pub mod timestep;
+pub mod trace;
+pub mod tree;
 
-use parameters::Parameters;
-use renewal::RenewalModel;
+use cfa_mrp::{Environment, MrpError, cli, formats};
+use diagnostics::DiagnosticsSection;
+use extend::RunInfo;
+use output::OutcomeSection;
+use parameters::{OutputStream, Parameters};
+use presets::Applied;
+use renewal::{RenewalModel, Streams};
 
 fn main() {
-    let ctx = cfa_mrp::Environment::<Parameters>::from_stdin_typed();
-    let params = ctx.input.as_ref().expect("missing input");
+    // MRP_MODE=schema prints the input's JSON Schema instead of running
+    cfa_mrp::exit_with_schema_if_requested::<Parameters>().unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(1);
+    });
+    let (ctx, preset) = load().unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(1);
+    });
+    let mut ctx = ctx
+        .try_with_input_type_validated::<Parameters>()
+        .unwrap_or_else(|e| {
+            eprintln!("{e}");
+            std::process::exit(1);
+        });
+    cfa_mrp::install_panic_hook(&ctx);
+    // With "mrp": {"verify_reproducibility": true}, run twice more first
+    let result = ctx.run_model(|ctx| {
+        let params = ctx.input.as_ref().expect("missing input");
+        run(ctx, params, preset.as_ref())
+    });
+    if let Err(e) = result.and_then(|_| ctx.finalize()) {
+        ctx.fail(e.code(), &e.to_string());
+    }
+}
+
+/// Read the payload, apply `--set key=value` arguments, discretize a
+/// `generation_interval` spec, then apply any preset, which leaves the
+/// values already set alone.
+fn load() -> Result<(Environment, Option<Applied>), MrpError> {
+    let mut env = Environment::try_from_stdin()?;
+    env.apply_overrides(&cli::parse_set_args()?)?;
+    presets::apply(parameters::resolve_generation_interval(env)?)
+}
 
-    let result = RenewalModel::simulate(&params);
+fn run(
+    ctx: &Environment<Parameters>,
+    params: &Parameters,
+    preset: Option<&Applied>,
+) -> Result<(), MrpError> {
+    params.validate_outputs()?;
+    ctx.validate_files()?;
+    let observed = if ctx.files.contains_key("observed_cases") {
+        let csv = ctx.read_file_to_string("observed_cases")?;
+        Some(fit::parse_observed(&csv, params)?)
+    } else {
+        None
+    };
+    let mut completed = params.clone();
+    let mut tree_truncated = None;
+    let result = match &params.extend {
+        None if ctx.replicate_count().is_some() => {
+            if params.replicates.is_some()
+                || params.chunk_steps.is_some()
+                || params.emit_tree.is_some()
+            {
+                return Err(MrpError::Input(
+                    "payload replicates cannot be combined with input replicates, chunk_steps \
+                     or emit_tree"
+                        .to_string(),
+                ));
+            }
+            replicates::write_in_process(ctx)?;
+            None
+        }
+        None if params.replicates.is_some() => {
+            let (headers, rows) = replicates::to_rows(ctx, params)?;
+            let headers: Vec<&str> = headers.iter().map(|s| s.as_str()).collect();
+            ctx.write_csv("renewal_output.csv", &headers, &rows);
+            if let Some(observed) = &observed {
+                let series = replicates::reported_series(ctx, params)?;
+                let runs: Vec<&[u64]> = series.iter().map(|s| s.as_slice()).collect();
+                record_fit(ctx, &fit::fit(observed, &runs, params.dispersion));
+            }
+            None
+        }
+        None if params.chunk_steps.is_some() => {
+            let result = chunk::write_chunked(ctx, params, Streams::from_env(ctx))?;
+            // Cancellation stops the run at a chunk boundary
+            completed.sim_length = result.infection_incidence.len();
+            Some((result, completed.clone()))
+        }
+        None => {
+            let streams = Streams::from_env(ctx);
+            let run = timestep::simulate(params, streams)?;
+            ctx.write_csv("renewal_output.csv", &run.headers, &run.rows);
+            if let Some(emit) = &params.emit_tree {
+                let tree = tree::build(params, &run.output, streams, emit.max_cases);
+                tree::write(ctx, &tree, emit.max_cases);
+                tree_truncated = Some(tree.truncated_at.is_some());
+            }
+            Some((run.output, run.parameters))
+        }
+        Some(extend) => {
+            let prior_info: RunInfo = formats::parse_run_info(&ctx.read_file(&extend.run_info)?)
+                .map_err(|e| MrpError::Input(format!("invalid prior run info: {e}")))?
+                .info;
+            extend::check_prior(&prior_info, params)?;
+            let prior_csv = ctx.read_file_to_string(&extend.trajectory)?;
+            let prior = extend::load_prior(&prior_csv, &prior_info)?;
 
-    let rows: Vec<Vec<String>> = (0..params.sim_length)
-        .map(|i| {
-            vec![
-                i.to_string(),
-                result.infection_incidence[i].to_string(),
-                result.symptomatic_incidence[i].to_string(),
-            ]
-        })
-        .collect();
+            let result = RenewalModel::extend_with(params, &prior, Streams::from_env(ctx));
+            let (headers, rows) = result.to_rows(params)?;
+            let mut writer =
+                ctx.append_csv("renewal_output.csv", &headers, prior_csv.as_bytes())?;
+            for row in &rows[prior_info.sim_length..] {
+                let refs: Vec<&str> = row.iter().map(|s| s.as_str()).collect();
+                writer.write_row(&refs);
+            }
+            writer.flush();
+            Some((result, params.clone()))
+        }
+    };
+    // On the step grid, which only a run without dt shares with its days
+    let pending_onsets = result
+        .as_ref()
+        .filter(|_| params.dt.is_none())
+        .map(|(result, _)| result.pending_onsets.clone());
+    if let Some((result, grid)) = result {
+        let mut summary = result.summary(params);
+        summary.tree_truncated = tree_truncated;
+        if let Some(observed) = &observed {
+            let fit = fit::fit(observed, &[&result.reported_incidence], params.dispersion);
+            record_fit(ctx, &fit);
+            summary.fit = Some(fit);
+        }
+        if params.wants(OutputStream::Summary) {
+            let json = serde_json::to_vec_pretty(&summary)
+                .map_err(|e| MrpError::Serialization(e.to_string()))?;
+            ctx.write("summary.json", &json);
+        }
+        if params.trace.is_some() {
+            let mut writer = ctx.jsonl_writer("trace.jsonl");
+            for record in &result.trace {
+                writer.try_write_record(record)?;
+            }
+            writer.flush();
+        }
+        let records = diagnostics::diagnostics(&result, &grid);
+        for failed in records.iter().filter(|d| !d.passed) {
+            ctx.warn(
+                "diagnostic_failed",
+                &format!("{}: {}", failed.check, failed.detail),
+            );
+        }
+        if ctx.output_dir().is_some() {
+            let json = serde_json::to_vec_pretty(&records)
+                .map_err(|e| MrpError::Serialization(e.to_string()))?;
+            ctx.write("diagnostics.json", &json);
+        }
+        ctx.add_report_section(OutcomeSection {
+            summary,
+            population: params.population,
+        });
+        ctx.add_report_section(DiagnosticsSection(records));
+    }
+    // Recorded alongside file output so the run can be extended later
+    if ctx.output_dir().is_some() {
+        let info = serde_json::to_vec_pretty(&formats::RunInfo::new(
+            RunInfo::new(&completed)
+                .with_preset(preset)
+                .with_warning_counts(ctx.warning_counts())
+                .with_io_pool(ctx.io_pool_stats())
+                .with_pending_onsets(pending_onsets),
+        ))
+        .map_err(|e| MrpError::Serialization(e.to_string()))?;
+        ctx.write("run_info.json", &info);
+    }
+    Ok(())
+}
 
-    ctx.write_csv(
-        "renewal_output.csv",
-        &["step", "infections", "symptom_onsets"],
-        &rows,
-    );
+fn record_fit(ctx: &Environment<Parameters>, fit: &fit::Fit) {
+    for (name, value) in fit.metrics() {
+        ctx.record_metric(name, value);
+    }
 }
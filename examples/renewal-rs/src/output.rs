@@ -1,7 +1,96 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use cfa_mrp::calendar::{Calendar, Weekday};
+use cfa_mrp::{MrpError, ReportSection, report};
+#[cfg(feature = "polars")]
+use polars::prelude::{Column, DataFrame};
+use serde::Serialize;
+
+use crate::fit::Fit;
+use crate::parameters::{Aggregate, OutputStream, Parameters};
+use crate::trace::StepTrace;
+
 #[derive(Default)]
 pub struct RenewalOutput {
     pub infection_incidence: Vec<u64>,
     pub symptomatic_incidence: Vec<u64>,
+    /// Empty unless `reported_cases` was requested.
+    pub reported_incidence: Vec<u64>,
+    pub draws: Draws,
+    /// Records of the steps selected by `trace`.
+    pub trace: Vec<StepTrace>,
+    /// Infections of the last cohorts whose symptom onsets fall past the
+    /// end of the run, by infection step; empty without onsets.
+    pub pending_onsets: BTreeMap<usize, u64>,
+}
+
+/// Random draws made by each stage of a simulation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Draws {
+    pub transmission: u64,
+    pub observation: u64,
+    pub reporting: u64,
+}
+
+/// Run totals and peaks, written to `summary.json` when requested.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Summary {
+    pub total_infections: u64,
+    pub peak_infections: u64,
+    pub peak_step: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_symptom_onsets: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_reported_cases: Option<u64>,
+    /// Goodness of fit to `observed_cases`, when given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fit: Option<Fit>,
+    /// Whether `tree.csv` stopped at `emit_tree.max_cases`; absent without
+    /// a tree.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tree_truncated: Option<bool>,
+}
+
+/// The final size and peak section of the run report.
+pub struct OutcomeSection {
+    pub summary: Summary,
+    pub population: Option<u64>,
+}
+
+impl ReportSection for OutcomeSection {
+    fn title(&self) -> String {
+        "Final size and peak".to_string()
+    }
+
+    fn body(&self) -> String {
+        let summary = &self.summary;
+        let mut rows = vec![vec![
+            "total infections".to_string(),
+            summary.total_infections.to_string(),
+        ]];
+        if let Some(population) = self.population {
+            rows.push(vec![
+                "attack rate".to_string(),
+                format!(
+                    "{:.1}%",
+                    100.0 * summary.total_infections as f64 / population as f64
+                ),
+            ]);
+        }
+        rows.push(vec![
+            "peak infections".to_string(),
+            summary.peak_infections.to_string(),
+        ]);
+        rows.push(vec!["peak step".to_string(), summary.peak_step.to_string()]);
+        if let Some(total) = summary.total_symptom_onsets {
+            rows.push(vec!["total symptom onsets".to_string(), total.to_string()]);
+        }
+        if let Some(total) = summary.total_reported_cases {
+            rows.push(vec!["total reported cases".to_string(), total.to_string()]);
+        }
+        report::table(&["measure", "value"], rows)
+    }
 }
 
 impl RenewalOutput {
@@ -9,6 +98,293 @@ impl RenewalOutput {
         RenewalOutput {
             infection_incidence: vec![0; len],
             symptomatic_incidence: vec![0; len],
+            ..Default::default()
+        }
+    }
+
+    pub fn summary(&self, parameters: &Parameters) -> Summary {
+        let (peak_step, peak_infections) = self
+            .infection_incidence
+            .iter()
+            .copied()
+            .enumerate()
+            .max_by_key(|&(step, x)| (x, std::cmp::Reverse(step)))
+            .unwrap_or_default();
+        Summary {
+            total_infections: self.infection_incidence.iter().sum(),
+            peak_infections,
+            peak_step,
+            total_symptom_onsets: parameters
+                .wants(OutputStream::SymptomOnsets)
+                .then(|| self.symptomatic_incidence.iter().sum()),
+            total_reported_cases: parameters
+                .wants(OutputStream::ReportedCases)
+                .then(|| self.reported_incidence.iter().sum()),
+            fit: None,
+            tree_truncated: None,
+        }
+    }
+
+    /// The requested per-step series, in column order.
+    fn measures(&self, parameters: &Parameters) -> Vec<(&'static str, &[u64])> {
+        OutputStream::COLUMNS
+            .into_iter()
+            .filter(|stream| parameters.wants(*stream))
+            .map(|stream| {
+                let series = match stream {
+                    OutputStream::Infections => &self.infection_incidence,
+                    OutputStream::SymptomOnsets => &self.symptomatic_incidence,
+                    _ => &self.reported_incidence,
+                };
+                (stream.column(), series.as_slice())
+            })
+            .collect()
+    }
+
+    /// CSV headers and rows, keyed and aggregated as requested by `parameters`.
+    ///
+    /// Key columns come first, then one column per requested stream.
+    pub fn to_rows(
+        &self,
+        parameters: &Parameters,
+    ) -> Result<(Vec<&'static str>, Vec<Vec<String>>), MrpError> {
+        self.to_rows_in(parameters, 0..self.infection_incidence.len())
+    }
+
+    /// [`RenewalOutput::to_rows`] for only the steps in `steps`, e.g. those
+    /// a chunk of a run added; weeks are summed within `steps`.
+    pub fn to_rows_in(
+        &self,
+        parameters: &Parameters,
+        steps: Range<usize>,
+    ) -> Result<(Vec<&'static str>, Vec<Vec<String>>), MrpError> {
+        let calendar = parameters.start_date.map(|d| Calendar::new(d, 1));
+        let measures = self.measures(parameters);
+        let (keys, rows): (Vec<&'static str>, _) = match (parameters.aggregate, calendar) {
+            (Aggregate::Daily, None) => (
+                vec!["step"],
+                self.rows_by(&measures, steps, false, |i| vec![i.to_string()]),
+            ),
+            (Aggregate::Daily, Some(calendar)) => (
+                vec!["step", "date"],
+                self.rows_by(&measures, steps, false, |i| {
+                    vec![i.to_string(), calendar.date_for(i).to_string()]
+                }),
+            ),
+            (Aggregate::Weekly, Some(calendar)) => {
+                let week_start = parameters.week_start.unwrap_or(Weekday::Sunday);
+                (
+                    vec!["week_ending"],
+                    self.rows_by(&measures, steps, true, |i| {
+                        vec![calendar.week_ending(i, week_start).to_string()]
+                    }),
+                )
+            }
+            (Aggregate::MmwrWeek, Some(calendar)) => (
+                vec!["mmwr_year", "mmwr_week", "week_ending"],
+                self.rows_by(&measures, steps, true, |i| {
+                    let week = calendar.mmwr_week(i);
+                    vec![
+                        week.year.to_string(),
+                        week.week.to_string(),
+                        calendar.week_ending(i, Weekday::Sunday).to_string(),
+                    ]
+                }),
+            ),
+            (aggregate, None) => {
+                return Err(MrpError::Input(format!(
+                    "aggregate = {aggregate:?} requires start_date"
+                )));
+            }
+        };
+        let headers = keys
+            .into_iter()
+            .chain(measures.iter().map(|(name, _)| *name))
+            .collect();
+        Ok((headers, rows))
+    }
+
+    /// [`RenewalOutput::to_rows`] as a frame, with whole-number columns
+    /// (steps, MMWR weeks and counts) as `u64` and dates as strings.
+    #[cfg(feature = "polars")]
+    pub fn to_dataframe(&self, parameters: &Parameters) -> Result<DataFrame, MrpError> {
+        let (headers, rows) = self.to_rows(parameters)?;
+        let columns = headers
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let cells: Vec<&str> = rows.iter().map(|row| row[i].as_str()).collect();
+                match cells.iter().map(|c| c.parse::<u64>()).collect::<Result<Vec<_>, _>>() {
+                    Ok(values) => Column::new((*name).into(), values),
+                    Err(_) => Column::new((*name).into(), cells),
+                }
+            })
+            .collect();
+        DataFrame::new(columns).map_err(|e| MrpError::Serialization(e.to_string()))
+    }
+
+    /// One row per step of `steps`, or with `sum`, per run of consecutive
+    /// steps sharing the same key columns.
+    fn rows_by(
+        &self,
+        measures: &[(&'static str, &[u64])],
+        steps: Range<usize>,
+        sum: bool,
+        key: impl Fn(usize) -> Vec<String>,
+    ) -> Vec<Vec<String>> {
+        let mut rows: Vec<Vec<String>> = Vec::new();
+        let mut current: Option<Vec<String>> = None;
+        let mut totals = vec![0u64; measures.len()];
+        let row = |key: Vec<String>, totals: &[u64]| -> Vec<String> {
+            [key, totals.iter().map(|t| t.to_string()).collect()].concat()
+        };
+        for i in steps {
+            let k = key(i);
+            if let Some(prev) = current.take_if(|prev| !sum || *prev != k) {
+                rows.push(row(prev, &totals));
+                totals.fill(0);
+            }
+            current = Some(k);
+            for (total, (_, series)) in totals.iter_mut().zip(measures) {
+                *total += series[i];
+            }
+        }
+        if let Some(prev) = current {
+            rows.push(row(prev, &totals));
         }
+        rows
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cfa_mrp::calendar::{Date, Weekday};
+
+    use crate::{
+        output::RenewalOutput,
+        parameters::{Aggregate, Parameters},
+    };
+
+    #[test]
+    fn test_weekly_aggregation() {
+        let output = RenewalOutput {
+            infection_incidence: (1..=10).collect(),
+            symptomatic_incidence: vec![1; 10],
+            ..Default::default()
+        };
+        // 2024-01-03 is a Wednesday: 4 days to the first Saturday, then 6 more
+        let parameters = Parameters {
+            start_date: Some(Date::parse("2024-01-03").unwrap()),
+            aggregate: Aggregate::Weekly,
+            week_start: Some(Weekday::Sunday),
+            ..Default::default()
+        };
+        let (headers, rows) = output.to_rows(&parameters).unwrap();
+        assert_eq!(headers, vec!["week_ending", "infections", "symptom_onsets"]);
+        assert_eq!(rows[0], vec!["2024-01-06", "10", "4"]);
+        assert_eq!(rows[1], vec!["2024-01-13", "45", "6"]);
+        let total: u64 = rows.iter().map(|r| r[1].parse::<u64>().unwrap()).sum();
+        assert_eq!(total, 55);
+    }
+
+    #[cfg(feature = "polars")]
+    #[test]
+    fn test_to_dataframe_matches_rows() {
+        use polars::prelude::DataType;
+
+        let output = RenewalOutput {
+            infection_incidence: vec![3, 5, 8],
+            symptomatic_incidence: vec![0, 1, 2],
+            ..Default::default()
+        };
+        let parameters = Parameters {
+            start_date: Some(Date::parse("2024-01-01").unwrap()),
+            ..Default::default()
+        };
+        let frame = output.to_dataframe(&parameters).unwrap();
+        let (headers, rows) = output.to_rows(&parameters).unwrap();
+        assert_eq!(frame.get_column_names(), headers);
+        assert_eq!(frame.height(), rows.len());
+        assert_eq!(frame.column("step").unwrap().dtype(), &DataType::UInt64);
+        assert_eq!(frame.column("date").unwrap().dtype(), &DataType::String);
+        let infections = frame.column("infections").unwrap().u64().unwrap();
+        assert_eq!(infections.into_no_null_iter().collect::<Vec<_>>(), [3, 5, 8]);
+    }
+
+    #[test]
+    fn test_weekly_requires_start_date() {
+        let output = RenewalOutput::new(3);
+        let parameters = Parameters {
+            aggregate: Aggregate::Weekly,
+            ..Default::default()
+        };
+        assert!(output.to_rows(&parameters).is_err());
+    }
+
+    #[test]
+    fn test_mmwr_week_aggregation() {
+        // Daily steps spanning the 2020/2021 boundary (2020 has 53 MMWR weeks)
+        let len = 21;
+        let output = RenewalOutput {
+            infection_incidence: (0..len as u64).collect(),
+            symptomatic_incidence: vec![2; len],
+            ..Default::default()
+        };
+        let parameters = Parameters {
+            start_date: Some(Date::parse("2020-12-24").unwrap()),
+            aggregate: Aggregate::MmwrWeek,
+            ..Default::default()
+        };
+        let (headers, rows) = output.to_rows(&parameters).unwrap();
+        assert_eq!(headers[..3], ["mmwr_year", "mmwr_week", "week_ending"]);
+        let keys: Vec<(&str, &str, &str)> = rows
+            .iter()
+            .map(|r| (r[0].as_str(), r[1].as_str(), r[2].as_str()))
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                ("2020", "52", "2020-12-26"),
+                ("2020", "53", "2021-01-02"),
+                ("2021", "1", "2021-01-09"),
+                ("2021", "2", "2021-01-16"),
+            ]
+        );
+        // Totals are conserved
+        let infections: u64 = rows.iter().map(|r| r[3].parse::<u64>().unwrap()).sum();
+        let onsets: u64 = rows.iter().map(|r| r[4].parse::<u64>().unwrap()).sum();
+        assert_eq!(infections, output.infection_incidence.iter().sum::<u64>());
+        assert_eq!(onsets, 2 * len as u64);
+        assert_eq!(rows[0][4], "6");
+    }
+
+    #[test]
+    fn test_outcome_section() {
+        use cfa_mrp::ReportSection;
+
+        use crate::output::{OutcomeSection, Summary};
+
+        let section = OutcomeSection {
+            summary: Summary {
+                total_infections: 250,
+                peak_infections: 40,
+                peak_step: 12,
+                total_symptom_onsets: Some(240),
+                total_reported_cases: None,
+                fit: None,
+                tree_truncated: None,
+            },
+            population: Some(1000),
+        };
+        assert_eq!(
+            section.body(),
+            "| measure | value |\n\
+             | --- | --- |\n\
+             | total infections | 250 |\n\
+             | attack rate | 25.0% |\n\
+             | peak infections | 40 |\n\
+             | peak step | 12 |\n\
+             | total symptom onsets | 240 |\n"
+        );
     }
 }
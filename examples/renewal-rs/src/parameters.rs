@@ -1,12 +1,322 @@
-use serde::Deserialize;
+use cfa_mrp::calendar::{Date, Weekday};
+use cfa_mrp::{DistributionSpec, Environment, MrpError, PmfOptions, Validate, ValidationError};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 
-#[derive(Debug, Clone, Deserialize)]
+/// Days a `generation_interval` spec is discretized over; later mass is
+/// dropped.
+pub const GENERATION_INTERVAL_DAYS: usize = 30;
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
 pub struct Parameters {
     pub r0: f64,
     pub generation_interval_pmf: Vec<f64>,
+    /// The distribution `generation_interval_pmf` was discretized from, if
+    /// it was given that way.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generation_interval: Option<DistributionSpec>,
     pub symptom_onset_pmf: Vec<f64>,
     pub initial_infections: Vec<u64>,
     pub sim_length: usize,
     pub population: Option<u64>,
     pub seed: u64,
+    /// Calendar date of step 0; required for any date-keyed output.
+    #[serde(default)]
+    pub start_date: Option<Date>,
+    #[serde(default)]
+    pub aggregate: Aggregate,
+    /// First day of the week for weekly aggregation (defaults to Sunday).
+    #[serde(default)]
+    pub week_start: Option<Weekday>,
+    /// Continue a prior run's output instead of simulating from step 0.
+    #[serde(default)]
+    pub extend: Option<Extend>,
+    /// Extend even if the prior run's seed or parameters differ.
+    #[serde(default)]
+    pub force_extend: bool,
+    /// Run this many replicates in-process through
+    /// `Environment::run_replicates`, so replicate `r` draws what a
+    /// standalone run with `"replicate": r` does.
+    #[serde(default)]
+    pub replicates: Option<usize>,
+    #[serde(default)]
+    pub output_layout: OutputLayout,
+    /// Largest replicates × rows table `wide_by_replicate` will buffer.
+    #[serde(default)]
+    pub max_wide_cells: Option<usize>,
+    /// Names of diagnostics checks to skip.
+    #[serde(default)]
+    pub disabled_checks: Vec<String>,
+    /// Step length as a fraction of a day; PMFs and `sim_length` stay in days.
+    #[serde(default)]
+    pub dt: Option<f64>,
+    /// With `dt`, sum steps back into daily rows.
+    #[serde(default)]
+    pub daily_totals: bool,
+    /// Simulate and write output this many steps at a time, checkpointing
+    /// after each chunk. Defaults to the whole run in one chunk.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk_steps: Option<usize>,
+    /// Streams to compute and write; unrequested streams are not simulated.
+    /// Defaults to infections and symptom onsets.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outputs: Option<Vec<OutputStream>>,
+    /// Probability that a symptom onset is reported; needed for
+    /// `reported_cases`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ascertainment: Option<f64>,
+    /// Steps to record in `trace.jsonl`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace: Option<Trace>,
+    /// Negative binomial size for the `observed_cases` likelihood; the
+    /// Poisson likelihood is always reported.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dispersion: Option<f64>,
+    /// Write who infected whom to `tree.csv` while the outbreak is small.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub emit_tree: Option<EmitTree>,
+    /// Approximate symptom onsets for steps with many infections.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fast_onsets: Option<FastOnsets>,
+}
+
+impl Parameters {
+    /// Whether `stream` was requested in `outputs`.
+    pub fn wants(&self, stream: OutputStream) -> bool {
+        match &self.outputs {
+            Some(outputs) => outputs.contains(&stream),
+            None => OutputStream::DEFAULT.contains(&stream),
+        }
+    }
+
+    /// Check that every requested stream has the inputs it needs.
+    pub fn validate_outputs(&self) -> Result<(), MrpError> {
+        if !OutputStream::COLUMNS.iter().any(|s| self.wants(*s)) {
+            return Err(MrpError::Input(
+                "outputs must include at least one of infections, symptom_onsets, reported_cases"
+                    .to_string(),
+            ));
+        }
+        if self.trace.is_some() && self.replicates.is_some() {
+            return Err(MrpError::Input(
+                "trace is not available with in-process replicates".to_string(),
+            ));
+        }
+        if self.emit_tree.is_some()
+            && (self.replicates.is_some()
+                || self.extend.is_some()
+                || self.chunk_steps.is_some()
+                || self.dt.is_some())
+        {
+            return Err(MrpError::Input(
+                "emit_tree requires a single daily run without replicates, extend or chunk_steps"
+                    .to_string(),
+            ));
+        }
+        if self.wants(OutputStream::Summary) && self.replicates.is_some() {
+            return Err(MrpError::Input(
+                "the summary output is not available with in-process replicates".to_string(),
+            ));
+        }
+        let mut missing = Vec::new();
+        if self.wants(OutputStream::ReportedCases)
+            && !self.ascertainment.is_some_and(|a| (0.0..=1.0).contains(&a))
+        {
+            missing.push("reported_cases needs ascertainment in [0, 1]");
+        }
+        if !missing.is_empty() {
+            return Err(MrpError::Input(format!(
+                "requested outputs are missing inputs: {}",
+                missing.join("; ")
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Validate for Parameters {
+    /// `r0` must be a non-negative rate and each PMF a distribution, as
+    /// [`cfa_mrp::Environment::get_pmf`] checks them.
+    fn validate(&self) -> Result<(), Vec<ValidationError>> {
+        let mut errors = Vec::new();
+        if !(self.r0.is_finite() && self.r0 >= 0.0) {
+            errors.push(ValidationError::new(
+                "r0",
+                format!("must be non-negative, got {}", self.r0),
+            ));
+        }
+        for (field, pmf) in [
+            ("generation_interval_pmf", &self.generation_interval_pmf),
+            ("symptom_onset_pmf", &self.symptom_onset_pmf),
+        ] {
+            if let Err(message) = PmfOptions::default().check(pmf) {
+                errors.push(ValidationError::new(field, message));
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// Fill `generation_interval_pmf` from a `generation_interval` spec, if
+/// one is given instead; giving both is an error.
+pub fn resolve_generation_interval(env: Environment<()>) -> Result<Environment<()>, MrpError> {
+    if env.input_json().get("generation_interval").is_none() {
+        return Ok(env);
+    }
+    if env.input_json().get("generation_interval_pmf").is_some() {
+        return Err(MrpError::Input(
+            "give generation_interval or generation_interval_pmf, not both".to_string(),
+        ));
+    }
+    let spec = env.get_distribution("generation_interval")?;
+    env.try_with_defaults(json!({
+        "generation_interval_pmf": spec.discretize(GENERATION_INTERVAL_DAYS),
+    }))
+}
+
+/// An optional result stream of the model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputStream {
+    Infections,
+    SymptomOnsets,
+    /// Symptom onsets thinned by `ascertainment`.
+    ReportedCases,
+    /// Totals and peaks, written to `summary.json`.
+    Summary,
+}
+
+impl OutputStream {
+    pub const DEFAULT: [OutputStream; 2] = [OutputStream::Infections, OutputStream::SymptomOnsets];
+
+    /// Streams written as columns of `renewal_output.csv`, in column order.
+    pub const COLUMNS: [OutputStream; 3] = [
+        OutputStream::Infections,
+        OutputStream::SymptomOnsets,
+        OutputStream::ReportedCases,
+    ];
+
+    pub fn column(self) -> &'static str {
+        match self {
+            OutputStream::Infections => "infections",
+            OutputStream::SymptomOnsets => "symptom_onsets",
+            OutputStream::ReportedCases => "reported_cases",
+            OutputStream::Summary => "summary",
+        }
+    }
+}
+
+/// Which simulation steps to trace.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+pub struct Trace {
+    #[serde(default)]
+    pub steps: Vec<usize>,
+    /// Trace every step, up to `max_rows` records.
+    #[serde(default)]
+    pub all: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_rows: Option<usize>,
+}
+
+impl Trace {
+    pub const DEFAULT_MAX_ROWS: usize = 10_000;
+
+    /// Whether to trace `step`, given `recorded` records so far.
+    pub fn includes(&self, step: usize, recorded: usize) -> bool {
+        recorded < self.max_rows.unwrap_or(Self::DEFAULT_MAX_ROWS)
+            && (self.all || self.steps.contains(&step))
+    }
+}
+
+/// Options for `tree.csv`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+pub struct EmitTree {
+    /// Stop the tree before cumulative infections exceed this.
+    #[serde(default = "EmitTree::default_max_cases")]
+    pub max_cases: u64,
+}
+
+impl EmitTree {
+    pub const DEFAULT_MAX_CASES: u64 = 5000;
+
+    fn default_max_cases() -> u64 {
+        Self::DEFAULT_MAX_CASES
+    }
+}
+
+/// Options for approximate symptom onsets; see [`crate::fast_onsets`].
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+pub struct FastOnsets {
+    /// Steps with more infections than this are approximated.
+    #[serde(default = "FastOnsets::default_threshold")]
+    pub threshold: u64,
+    /// Use the exact draws anyway, to validate against them.
+    #[serde(default)]
+    pub force_exact: bool,
+}
+
+impl FastOnsets {
+    pub const DEFAULT_THRESHOLD: u64 = 1_000_000;
+
+    fn default_threshold() -> u64 {
+        Self::DEFAULT_THRESHOLD
+    }
+
+    /// Whether `infections` are approximated.
+    pub fn applies(&self, infections: u64) -> bool {
+        !self.force_exact && infections > self.threshold
+    }
+}
+
+/// How replicate trajectories are laid out in `renewal_output.csv`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputLayout {
+    /// One row per replicate and step, with a leading `replicate` column.
+    #[default]
+    Long,
+    /// One row per step with the first requested stream (infections by
+    /// default) in a `rep_{r}` column per replicate.
+    WideByReplicate,
+}
+
+/// File keys for the prior run being extended.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct Extend {
+    /// The prior `renewal_output.csv` (daily rows).
+    pub trajectory: String,
+    /// The prior `run_info.json`.
+    pub run_info: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Aggregate {
+    #[default]
+    Daily,
+    Weekly,
+    /// CDC MMWR epidemiological weeks (Sunday start, labelled by MMWR year and week).
+    MmwrWeek,
+}
+
+#[cfg(test)]
+mod test {
+    use super::Parameters;
+
+    #[test]
+    fn test_input_schema() {
+        let schema = cfa_mrp::emit_input_schema::<Parameters>();
+        let properties = &schema["properties"];
+        assert_eq!(properties["r0"]["type"], "number");
+        let seed = properties["seed"]["description"].as_str().unwrap();
+        assert!(seed.starts_with("Reserved"), "{seed}");
+        assert_eq!(schema["$defs"]["Date"]["format"], "date");
+        let aggregate = schema["$defs"]["Aggregate"].to_string();
+        assert!(aggregate.contains("\"mmwr_week\""), "{aggregate}");
+    }
 }
@@ -0,0 +1,140 @@
+use std::collections::BTreeMap;
+
+use cfa_mrp::{Environment, MrpError};
+use serde_json::{Map, Value};
+
+/// Curated parameter bundles, selected with `"preset": "<name>"`.
+const PRESETS: &str = include_str!("presets.json");
+
+/// A preset applied to a run's input.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Applied {
+    pub name: String,
+    /// Preset values the input replaced, as given in the input.
+    pub overrides: BTreeMap<String, Value>,
+}
+
+fn presets() -> Map<String, Value> {
+    serde_json::from_str(PRESETS).expect("presets.json is not a JSON object")
+}
+
+/// The values of the preset called `name`.
+pub fn preset(name: &str) -> Result<Value, MrpError> {
+    let mut presets = presets();
+    presets.remove(name).ok_or_else(|| {
+        let available: Vec<&str> = presets.keys().map(|k| k.as_str()).collect();
+        MrpError::Input(format!(
+            "unknown preset '{name}' (available: {})",
+            available.join(", ")
+        ))
+    })
+}
+
+/// Fill the input from the preset it names, if any. Inputs set explicitly
+/// keep their values.
+pub fn apply(env: Environment<()>) -> Result<(Environment<()>, Option<Applied>), MrpError> {
+    let Some(name) = env.get_or::<Option<String>>("preset", None)? else {
+        return Ok((env, None));
+    };
+    let values = preset(&name)?;
+    let overrides = values
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter_map(|(key, value)| {
+            let given = env.input_json().get(key)?;
+            (given != value).then(|| (key.clone(), given.clone()))
+        })
+        .collect();
+    let env = env.try_with_defaults(values)?;
+    Ok((env, Some(Applied { name, overrides })))
+}
+
+#[cfg(test)]
+mod test {
+    use cfa_mrp::provenance::sha256_hex;
+    use serde_json::json;
+
+    use super::*;
+    use crate::parameters::Parameters;
+    use crate::renewal::{RenewalModel, Streams};
+
+    fn resolve(input: Value) -> (Environment<()>, Option<Applied>) {
+        apply(Environment::from_json(json!({ "input": input }))).unwrap()
+    }
+
+    #[test]
+    fn test_presets_run_alone() {
+        let expected = [
+            (
+                "covid_like",
+                "bc94bcd9e8c9b22d88f2e4e99917190485108aa2a8e74eb1c232fd54c1c07e94",
+            ),
+            (
+                "flu_like",
+                "a612628bd52dd37d9840f8d9717b396d4ed7d81f681ff59bf4d87c8ef7f8ace2",
+            ),
+            (
+                "measles_like",
+                "12787a91076f0f6482d7cb26342c683e62d9e37353bb27471d313046249552a4",
+            ),
+        ];
+        for (name, hash) in expected {
+            let (env, applied) = resolve(json!({ "preset": name }));
+            assert!(applied.unwrap().overrides.is_empty());
+            let env = env.with_input_type::<Parameters>();
+            let params = env.input.as_ref().unwrap();
+            params.validate_outputs().unwrap();
+            let output = RenewalModel::simulate_with(params, Streams::from_seed(params.seed));
+            let summary = output.summary(params);
+            assert!(summary.total_infections > 0, "{name}");
+            assert!(
+                summary.total_infections <= params.population.unwrap(),
+                "{name}"
+            );
+            assert!(summary.peak_step > 0, "{name}");
+
+            assert_eq!(
+                sha256_hex(&serde_json::to_vec(&summary).unwrap()),
+                hash,
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_override_changes_only_that_field() {
+        let (base, _) = resolve(json!({ "preset": "covid_like" }));
+        let (env, applied) = resolve(json!({ "preset": "covid_like", "r0": 3.1 }));
+        let changed: Vec<&String> = env
+            .input_json()
+            .as_object()
+            .unwrap()
+            .iter()
+            .filter(|(key, value)| base.input_json().get(*key) != Some(*value))
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(changed, ["r0"]);
+        assert_eq!(env.input_json()["r0"], 3.1);
+        assert_eq!(
+            applied.unwrap(),
+            Applied {
+                name: "covid_like".to_string(),
+                overrides: BTreeMap::from([("r0".to_string(), json!(3.1))]),
+            }
+        );
+    }
+
+    #[test]
+    fn test_unknown_preset_lists_available() {
+        let Err(err) = apply(Environment::from_json(
+            json!({ "input": { "preset": "ebola" } }),
+        )) else {
+            panic!("expected an unknown preset error");
+        };
+        assert_eq!(
+            err.to_string(),
+            "input error: unknown preset 'ebola' (available: covid_like, flu_like, measles_like)"
+        );
+    }
+}
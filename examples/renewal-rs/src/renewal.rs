@@ -1,17 +1,189 @@
-use rand::{SeedableRng, distr::Distribution, rngs::StdRng};
+use std::collections::BTreeMap;
+
+use cfa_mrp::seed::{derive_seed, named_stream_seed};
+use cfa_mrp::{Environment, ReplicateContext};
+use rand::{Rng, SeedableRng, distr::Distribution, rngs::StdRng};
 use rand_distr::{Binomial, Poisson};
 
-use crate::{output::RenewalOutput, parameters::Parameters};
+use crate::fast_onsets;
+use crate::output::RenewalOutput;
+use crate::parameters::{OutputStream, Parameters};
+use crate::trace::{Cohort, Draw, StepTrace};
 
 pub struct RenewalModel {}
 
-impl RenewalModel {
-    pub fn simulate(parameters: &Parameters) -> RenewalOutput {
-        let mut output = RenewalOutput::new(parameters.sim_length);
-        let mut rt = vec![parameters.r0; parameters.sim_length];
-        let mut cum_infected = 0;
-        let mut rng = StdRng::seed_from_u64(parameters.seed);
-        for step in 0..parameters.sim_length {
+/// Seeds of the model's named random streams: `transmission` for new
+/// infections, `observation` for symptom onsets, `reporting` for which
+/// onsets are reported and `tree` for who infected whom in `tree.csv`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Streams {
+    pub transmission: u64,
+    pub observation: u64,
+    pub reporting: u64,
+    pub tree: u64,
+}
+
+impl Streams {
+    pub const NAMES: [&str; 4] = ["transmission", "observation", "reporting", "tree"];
+
+    /// Streams for `seed` as an unconfigured Environment at replicate 0 gives.
+    pub fn from_seed(seed: u64) -> Self {
+        Streams {
+            transmission: named_stream_seed(seed, 0, "transmission"),
+            observation: named_stream_seed(seed, 0, "observation"),
+            reporting: named_stream_seed(seed, 0, "reporting"),
+            tree: named_stream_seed(seed, 0, "tree"),
+        }
+    }
+
+    /// Streams as configured by the payload's `"rng_streams"`.
+    pub fn from_env<I>(env: &Environment<I>) -> Self {
+        env.declare_rng_streams(&Self::NAMES);
+        Streams {
+            transmission: env.stream_seed("transmission"),
+            observation: env.stream_seed("observation"),
+            reporting: env.stream_seed("reporting"),
+            tree: env.stream_seed("tree"),
+        }
+    }
+
+    /// Streams for one replicate of an in-process run, the same as a
+    /// standalone run of that replicate gets from [`Streams::from_env`].
+    pub fn from_replicate<I>(context: &ReplicateContext<'_, I>) -> Self {
+        Streams {
+            transmission: context.stream_seed("transmission"),
+            observation: context.stream_seed("observation"),
+            reporting: context.stream_seed("reporting"),
+            tree: context.stream_seed("tree"),
+        }
+    }
+}
+
+/// Seed of the generator an extension of a `prior_steps`-step run draws
+/// `stream` from. A run's trajectory does not record where its generators
+/// stopped, so an extension cannot continue them; it draws from a stream of
+/// its own instead, and matches a straight run in distribution only.
+pub fn extension_seed(stream: u64, prior_steps: usize) -> u64 {
+    derive_seed(stream, prior_steps as u64)
+}
+
+/// A run in progress. Each stream has one generator, drawn from in step
+/// order, so advancing a run in several calls draws exactly what one call
+/// would.
+pub struct Simulation<'a> {
+    parameters: &'a Parameters,
+    output: RenewalOutput,
+    rt: Vec<f64>,
+    cum_infected: u64,
+    step: usize,
+    /// Onsets of the cohorts some of whose onsets may fall at or past
+    /// `step`, for [`Simulation::pending_onsets`].
+    recent: Vec<CohortOnsets>,
+    transmission: StdRng,
+    observation: StdRng,
+    reporting: StdRng,
+}
+
+/// Where one cohort's symptom onsets were placed.
+struct CohortOnsets {
+    step: usize,
+    /// Infections whose onsets were not placed before `from`.
+    unplaced: u64,
+    /// The step of `onsets[0]`.
+    from: usize,
+    onsets: Vec<u64>,
+}
+
+impl<'a> Simulation<'a> {
+    pub fn new(parameters: &'a Parameters, streams: Streams) -> Self {
+        Simulation {
+            parameters,
+            output: RenewalOutput::new(parameters.sim_length),
+            rt: vec![parameters.r0; parameters.sim_length],
+            cum_infected: 0,
+            step: 0,
+            recent: Vec::new(),
+            transmission: StdRng::seed_from_u64(streams.transmission),
+            observation: StdRng::seed_from_u64(streams.observation),
+            reporting: StdRng::seed_from_u64(streams.reporting),
+        }
+    }
+
+    /// Continue `prior`, the output of a run with the same parameters but a
+    /// shorter `sim_length`, drawing from each stream's [`extension_seed`].
+    ///
+    /// Infection history and the susceptible count are rebuilt from `prior`.
+    /// The infections in its `pending_onsets` are split over the onset
+    /// delays past its end, conditional on the delays already passed, so
+    /// each cohort's onsets are distributed as in a straight run.
+    pub fn extend(parameters: &'a Parameters, prior: &RenewalOutput, streams: Streams) -> Self {
+        let start = prior.infection_incidence.len();
+        let extension = |stream| StdRng::seed_from_u64(extension_seed(stream, start));
+        let mut simulation = Simulation {
+            transmission: extension(streams.transmission),
+            observation: extension(streams.observation),
+            reporting: extension(streams.reporting),
+            ..Simulation::new(parameters, streams)
+        };
+        let output = &mut simulation.output;
+        output.infection_incidence[..start].copy_from_slice(&prior.infection_incidence);
+        output.symptomatic_incidence[..start].copy_from_slice(&prior.symptomatic_incidence);
+        output.reported_incidence = prior.reported_incidence.clone();
+        output.trace = prior.trace.clone();
+        simulation.cum_infected = prior.infection_incidence.iter().sum();
+        simulation.step = start;
+        if let Some(population) = parameters.population
+            && start > 0
+            && start < parameters.sim_length
+        {
+            simulation.rt[start] =
+                parameters.r0 * (population - simulation.cum_infected) as f64 / population as f64
+        }
+        if simulation.wants_onsets() {
+            for (&step, &unplaced) in &prior.pending_onsets {
+                simulation.distribute_onsets(step, unplaced, start - step - 1, false);
+            }
+        }
+        simulation
+    }
+
+    fn wants_onsets(&self) -> bool {
+        self.parameters.wants(OutputStream::SymptomOnsets)
+            || self.parameters.wants(OutputStream::ReportedCases)
+    }
+
+    /// Simulate up to (not including) step `end`, then report the steps
+    /// whose onsets are now complete.
+    ///
+    /// Each stream is a stage run only when `parameters` requests it (or a
+    /// stream that depends on it): transmission always, then symptom onsets,
+    /// then reporting.
+    pub fn advance_to(&mut self, end: usize) {
+        let parameters = self.parameters;
+        let end = usize::min(end, parameters.sim_length);
+        let onsets = self.wants_onsets();
+        for step in self.step..end {
+            let rt = self.rt[step];
+            let cum_infected = self.cum_infected;
+            let output = &mut self.output;
+            let rng = &mut self.transmission;
+            // Trace records only copy values the step computes anyway
+            let mut record = parameters
+                .trace
+                .as_ref()
+                .is_some_and(|trace| trace.includes(step, output.trace.len()))
+                .then(|| StepTrace {
+                    step,
+                    cohorts: Vec::new(),
+                    infectious: 0.,
+                    base_rate: 0.,
+                    rt,
+                    transmission_rate: 0.,
+                    susceptible: parameters.population.map(|p| p - cum_infected),
+                    infection_draw: None,
+                    infections: 0,
+                    onset_draws: Vec::new(),
+                });
             // Set infections
             // Determine infections
             let infections: u64;
@@ -22,29 +194,55 @@ impl RenewalModel {
                 // Use renewal equation calculation
                 let mut current_infectious = 0.0;
                 for lag in 0..usize::min(step, parameters.generation_interval_pmf.len()) {
-                    current_infectious += output.infection_incidence[step - lag - 1] as f64
-                        * parameters.generation_interval_pmf[lag];
+                    let cohort = step - lag - 1;
+                    let weight = parameters.generation_interval_pmf[lag];
+                    current_infectious += output.infection_incidence[cohort] as f64 * weight;
+                    if let Some(record) = &mut record {
+                        record.cohorts.push(Cohort {
+                            step: cohort,
+                            infections: output.infection_incidence[cohort],
+                            weight,
+                        });
+                    }
+                }
+                let transmission_rate = rt * current_infectious;
+                if let Some(record) = &mut record {
+                    record.infectious = current_infectious;
+                    record.base_rate = parameters.r0 * current_infectious;
+                    record.transmission_rate = transmission_rate;
                 }
-                let transmission_rate = rt[step] * current_infectious;
 
                 match parameters.population {
                     Some(population) => {
                         let susceptible = population - cum_infected;
                         infections = if susceptible > 0 {
-                            Binomial::new(
-                                susceptible,
-                                f64::min(transmission_rate / susceptible as f64, 1.0),
-                            )
-                            .unwrap()
-                            .sample(&mut rng)
+                            output.draws.transmission += 1;
+                            let p = f64::min(transmission_rate / susceptible as f64, 1.0);
+                            let value = Binomial::new(susceptible, p).unwrap().sample(rng);
+                            if let Some(record) = &mut record {
+                                record.infection_draw = Some(Draw::Binomial {
+                                    n: susceptible,
+                                    p,
+                                    value,
+                                });
+                            }
+                            value
                         } else {
                             0
                         };
                     }
                     None => {
                         infections = if transmission_rate > 0. {
+                            output.draws.transmission += 1;
                             // Poisson requires non-zero rate
-                            Poisson::new(transmission_rate).unwrap().sample(&mut rng) as u64
+                            let value = Poisson::new(transmission_rate).unwrap().sample(rng) as u64;
+                            if let Some(record) = &mut record {
+                                record.infection_draw = Some(Draw::Poisson {
+                                    rate: transmission_rate,
+                                    value,
+                                });
+                            }
+                            value
                         } else {
                             0
                         }
@@ -52,40 +250,223 @@ impl RenewalModel {
                 }
             }
             output.infection_incidence[step] = infections;
-            cum_infected += infections;
+
+            self.cum_infected += infections;
             // Update rt if needed
             if let Some(population) = parameters.population
                 && step < parameters.sim_length - 1
             {
-                rt[step + 1] =
-                    parameters.r0 * (population - cum_infected) as f64 / population as f64
+                self.rt[step + 1] =
+                    parameters.r0 * (population - self.cum_infected) as f64 / population as f64
             }
 
-            // Distribute symptom onset times
-            if infections > 0 {
-                let mut residual_mass = 1.;
-                let mut cum_onsets = 0;
-                for (mass, output_onsets) in parameters
-                    .symptom_onset_pmf
-                    .iter()
-                    .zip(output.symptomatic_incidence.iter_mut().skip(step + 1))
-                {
-                    let onsets = Binomial::new(infections - cum_onsets, *mass / residual_mass)
-                        .unwrap()
-                        .sample(&mut rng);
-                    *output_onsets += onsets;
-                    cum_onsets += onsets;
-                    residual_mass -= *mass;
+            if onsets {
+                let draws = self.distribute_onsets(step, infections, 0, record.is_some());
+                if let Some(record) = &mut record {
+                    record.onset_draws = draws;
                 }
             }
+            if let Some(mut record) = record {
+                record.infections = infections;
+                self.output.trace.push(record);
+            }
+        }
+        self.step = self.step.max(end);
+        let (pmf_len, step) = (parameters.symptom_onset_pmf.len(), self.step);
+        self.recent.retain(|cohort| cohort.step + pmf_len >= step);
+        if parameters.wants(OutputStream::ReportedCases) {
+            self.report(end);
+        }
+    }
+
+    /// Simulate the remaining steps and return the output.
+    pub fn finish(mut self) -> RenewalOutput {
+        self.advance_to(self.parameters.sim_length);
+        self.output.pending_onsets = self.pending_onsets();
+        self.output
+    }
+
+    /// The infections of each cohort whose onsets have not been placed
+    /// before the current step, by infection step.
+    pub fn pending_onsets(&self) -> BTreeMap<usize, u64> {
+        self.recent
+            .iter()
+            .filter_map(|cohort| {
+                let placed_bins = self.step.saturating_sub(cohort.from);
+                let placed: u64 = cohort.onsets.iter().take(placed_bins).sum();
+                let pending = cohort.unplaced - placed;
+                (pending > 0).then_some((cohort.step, pending))
+            })
+            .collect()
+    }
+
+    /// The output being simulated, with a slot in each series for every
+    /// step of the run; only steps before [`Simulation::step`] are final.
+    pub fn output(&self) -> &RenewalOutput {
+        &self.output
+    }
+
+    /// The number of steps simulated so far.
+    pub fn step(&self) -> usize {
+        self.step
+    }
+
+    /// The output of the steps simulated so far, as a run of that many
+    /// steps would give it.
+    pub fn into_prefix(mut self) -> RenewalOutput {
+        let steps = self.step;
+        self.output.infection_incidence.truncate(steps);
+        self.output.symptomatic_incidence.truncate(steps);
+        self.output.pending_onsets = self.pending_onsets();
+        self.output
+    }
+
+    /// Thin the symptom onsets of steps before `end` not yet reported by
+    /// `ascertainment` into reported cases.
+    fn report(&mut self, end: usize) {
+        let ascertainment = self.parameters.ascertainment.unwrap_or(1.0);
+        for step in self.output.reported_incidence.len()..end {
+            let onsets = self.output.symptomatic_incidence[step];
+            let reported = Binomial::new(onsets, ascertainment)
+                .unwrap()
+                .sample(&mut self.reporting);
+            self.output.reported_incidence.push(reported);
+            self.output.draws.reporting += 1;
+        }
+    }
+
+    /// Distribute symptom onset times for `step`'s `infections` over the
+    /// onset delays after the first `passed`. Returns the draws if `keep` is
+    /// set, for a trace record.
+    fn distribute_onsets(
+        &mut self,
+        step: usize,
+        infections: u64,
+        passed: usize,
+        keep: bool,
+    ) -> Vec<Draw> {
+        let draws = RenewalModel::remaining_onset_draws(
+            self.parameters,
+            &mut self.observation,
+            step,
+            infections,
+            passed,
+        );
+        let from = step + 1 + passed;
+        for (draw, onset_step) in draws.iter().zip(from..) {
+            self.output.symptomatic_incidence[onset_step] += draw.value();
         }
-        output
+        let onsets: Vec<u64> = draws.iter().map(Draw::value).collect();
+        assert!(
+            onsets.iter().sum::<u64>() <= infections,
+            "step {step}'s onsets exceed its {infections} infections"
+        );
+        if infections > 0 {
+            self.recent.push(CohortOnsets {
+                step,
+                unplaced: infections,
+                from,
+                onsets,
+            });
+        }
+        self.output.draws.observation += draws.len() as u64;
+        if keep { draws } else { Vec::new() }
+    }
+}
+
+impl RenewalModel {
+    pub fn simulate(parameters: &Parameters) -> RenewalOutput {
+        Self::simulate_with(parameters, Streams::from_seed(parameters.seed))
+    }
+
+    pub fn simulate_with(parameters: &Parameters, streams: Streams) -> RenewalOutput {
+        Simulation::new(parameters, streams).finish()
+    }
+
+    /// Continue `prior` to `parameters.sim_length` steps, simulating only
+    /// the additional steps; see [`Simulation::extend`].
+    pub fn extend(parameters: &Parameters, prior: &RenewalOutput) -> RenewalOutput {
+        Self::extend_with(parameters, prior, Streams::from_seed(parameters.seed))
+    }
+
+    pub fn extend_with(
+        parameters: &Parameters,
+        prior: &RenewalOutput,
+        streams: Streams,
+    ) -> RenewalOutput {
+        Simulation::extend(parameters, prior, streams).finish()
+    }
+
+    /// The binomial draws splitting `step`'s infections over onset steps
+    /// `step + 1`, `step + 2`, ..., stopping at `sim_length`, or their
+    /// approximation above the `fast_onsets` threshold.
+    pub(crate) fn onset_draws(
+        parameters: &Parameters,
+        rng: &mut StdRng,
+        step: usize,
+        infections: u64,
+    ) -> Vec<Draw> {
+        Self::remaining_onset_draws(parameters, rng, step, infections, 0)
+    }
+
+    /// As [`RenewalModel::onset_draws`], for `infections` known to have
+    /// onsets after the first `passed` delays: the draws for onset steps
+    /// `step + passed + 1`, ..., with each delay's probability conditional
+    /// on the passed ones.
+    pub(crate) fn remaining_onset_draws(
+        parameters: &Parameters,
+        rng: &mut StdRng,
+        step: usize,
+        infections: u64,
+        passed: usize,
+    ) -> Vec<Draw> {
+        if infections == 0 {
+            return Vec::new();
+        }
+        let pmf = &parameters.symptom_onset_pmf;
+        let passed = passed.min(pmf.len());
+        let steps = parameters
+            .sim_length
+            .saturating_sub(step + 1)
+            .saturating_sub(passed);
+        let mut residual_mass = 1.;
+        for mass in &pmf[..passed] {
+            residual_mass -= *mass;
+        }
+        if let Some(fast) = &parameters.fast_onsets
+            && fast.applies(infections)
+        {
+            let masses: Vec<f64> = pmf[passed..]
+                .iter()
+                .take(steps)
+                .map(|mass| mass / residual_mass)
+                .collect();
+            return fast_onsets::allocate(infections, &masses, rng.random());
+        }
+        let mut cum_onsets = 0;
+        let mut draws = Vec::new();
+        for mass in pmf[passed..].iter().take(steps) {
+            // Rounding can leave the last ratio just above 1
+            let p = f64::min(*mass / residual_mass, 1.0);
+            let n = infections - cum_onsets;
+            let value = Binomial::new(n, p).unwrap().sample(rng);
+            draws.push(Draw::Binomial { n, p, value });
+            cum_onsets += value;
+            residual_mass -= *mass;
+        }
+        draws
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::{parameters::Parameters, renewal::RenewalModel};
+    use cfa_mrp::Validate;
+
+    use crate::{
+        parameters::{OutputStream, Parameters, Trace},
+        renewal::{RenewalModel, Streams},
+        trace::Draw,
+    };
 
     #[test]
     fn test_final_size() {
@@ -98,6 +479,7 @@ mod test {
             initial_infections: vec![1],
             sim_length: 200,
             seed: 8675308,
+            ..Default::default()
         };
         let output = RenewalModel::simulate(&parameters);
         let cum_infected: u64 = output.infection_incidence.iter().sum();
@@ -106,6 +488,76 @@ mod test {
         assert!(f64::abs(fraction_infected - 0.796811) < 0.1);
     }
 
+    #[test]
+    fn test_validate_parameters() {
+        let parameters = Parameters {
+            r0: 2.0,
+            generation_interval_pmf: vec![0., 0.25, 0.5, 0.25],
+            symptom_onset_pmf: vec![1.],
+            ..Default::default()
+        };
+        parameters.validate().unwrap();
+        let errors = Parameters {
+            r0: -0.5,
+            generation_interval_pmf: vec![0.5, 0.5, 0.5],
+            symptom_onset_pmf: vec![1.5, -0.5],
+            ..parameters
+        }
+        .validate()
+        .unwrap_err();
+        let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            errors,
+            vec![
+                "r0: must be non-negative, got -0.5",
+                "generation_interval_pmf: sums to 1.5, not 1 (tolerance 0.000001)",
+                "symptom_onset_pmf: entry 1 is -0.5, must be non-negative",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_generation_interval_from_spec() {
+        use cfa_mrp::Environment;
+        use serde_json::json;
+
+        use crate::parameters::{GENERATION_INTERVAL_DAYS, resolve_generation_interval};
+
+        // Exponential(mean 1): day i holds e^-i - e^-(i+1)
+        let env = resolve_generation_interval(Environment::from_json(json!({
+            "input": { "generation_interval": { "distribution": "exponential", "mean": 1 } },
+        })))
+        .unwrap();
+        let pmf: Vec<f64> = env.get("generation_interval_pmf").unwrap();
+        assert_eq!(pmf.len(), GENERATION_INTERVAL_DAYS);
+        for (day, expected) in [0.632_120_558_8, 0.232_544_157_9, 0.085_548_214_9]
+            .into_iter()
+            .enumerate()
+        {
+            assert!(
+                (pmf[day] - expected).abs() < 1e-9,
+                "day {day}: {}",
+                pmf[day]
+            );
+        }
+
+        let given = json!({ "generation_interval_pmf": [0.5, 0.5] });
+        let env =
+            resolve_generation_interval(Environment::from_json(json!({ "input": given }))).unwrap();
+        assert_eq!(env.input_json(), &given);
+
+        let both = Environment::from_json(json!({ "input": {
+            "generation_interval": { "distribution": "fixed", "value": 2 },
+            "generation_interval_pmf": [1.0],
+        }}));
+        assert!(resolve_generation_interval(both).is_err());
+        let invalid = Environment::from_json(json!({ "input": {
+            "generation_interval": { "distribution": "gamma", "mean": 4.2, "sd": 0 },
+        }}));
+        let err = resolve_generation_interval(invalid).err().unwrap();
+        assert!(err.to_string().contains("sd must be positive"), "{err}");
+    }
+
     #[test]
     fn test_generation_interval() {
         let n_samples = 10000;
@@ -123,6 +575,7 @@ mod test {
                 initial_infections: vec![initial_infections],
                 sim_length: generation_interval_pmf.len() + 1,
                 seed,
+                ..Default::default()
             };
             let output = RenewalModel::simulate(&parameters);
             for (i, entry) in cumulative_output.iter_mut().enumerate() {
@@ -152,6 +605,7 @@ mod test {
             initial_infections: vec![initial_infections],
             sim_length: symptom_onset_pmf.len() + 1,
             seed: 8675309,
+            ..Default::default()
         };
         let output = RenewalModel::simulate(&parameters);
         let total: u64 = output.symptomatic_incidence.iter().skip(1).sum();
@@ -160,4 +614,211 @@ mod test {
             assert!(f64::abs(fraction - mass) < 1e-3);
         }
     }
+
+    #[test]
+    fn test_negative_control_r0() {
+        let env = cfa_mrp::Environment::<Parameters>::from_json_typed(serde_json::json!({
+            "input": {
+                "r0": 2.0,
+                "generation_interval_pmf": [0., 0., 0.25, 0.5, 0.25],
+                "symptom_onset_pmf": [1.],
+                "initial_infections": [10],
+                "sim_length": 100,
+                "population": 100000,
+                "seed": 42
+            },
+            "negative_control": { "parameter": "r0", "value": 0.0 }
+        }));
+        assert!(env.is_negative_control());
+        assert_eq!(env.tags()["negative_control"], "true");
+        let parameters = env.input.as_ref().unwrap();
+        assert_eq!(parameters.r0, 0.0);
+        let output = RenewalModel::simulate(parameters);
+        let cum_infected: u64 = output.infection_incidence.iter().sum();
+        assert_eq!(cum_infected, 10);
+    }
+
+    #[test]
+    fn test_frozen_transmission_stream() {
+        let run = |replicate: u64| {
+            let env = cfa_mrp::Environment::<Parameters>::from_json_typed(serde_json::json!({
+                "input": {
+                    "r0": 1.5,
+                    "generation_interval_pmf": [0., 0.5, 0.5],
+                    "symptom_onset_pmf": [0.2, 0.3, 0.5],
+                    "initial_infections": [50],
+                    "sim_length": 20,
+                    "seed": 3,
+                    "replicate": replicate
+                },
+                "rng_streams": { "freeze": ["transmission"] }
+            }));
+            let parameters = env.input.as_ref().unwrap();
+            RenewalModel::simulate_with(parameters, Streams::from_env(&env))
+        };
+        let (a, b) = (run(0), run(1));
+        assert_eq!(a.infection_incidence, b.infection_incidence);
+        assert_ne!(a.symptomatic_incidence, b.symptomatic_incidence);
+    }
+
+    #[test]
+    fn test_output_streams() {
+        let base = Parameters {
+            r0: 1.5,
+            generation_interval_pmf: vec![0., 0.5, 0.5],
+            symptom_onset_pmf: vec![0.2, 0.3, 0.5],
+            initial_infections: vec![50],
+            sim_length: 20,
+            seed: 4,
+            ..Default::default()
+        };
+        let full = RenewalModel::simulate(&base);
+
+        let infections_only = Parameters {
+            outputs: Some(vec![OutputStream::Infections]),
+            ..base.clone()
+        };
+        let reduced = RenewalModel::simulate(&infections_only);
+        assert_eq!(reduced.infection_incidence, full.infection_incidence);
+        assert_eq!(reduced.draws.transmission, full.draws.transmission);
+        assert!(full.draws.observation > 0);
+        assert_eq!(reduced.draws.observation, 0);
+        let (headers, rows) = reduced.to_rows(&infections_only).unwrap();
+        assert_eq!(headers, vec!["step", "infections"]);
+        assert_eq!(rows[0].len(), 2);
+
+        let reported = Parameters {
+            outputs: Some(vec![OutputStream::ReportedCases, OutputStream::Summary]),
+            ..base.clone()
+        };
+        let err = reported.validate_outputs().unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("reported_cases needs ascertainment")
+        );
+        let reported = Parameters {
+            ascertainment: Some(0.5),
+            ..reported
+        };
+        reported.validate_outputs().unwrap();
+        let output = RenewalModel::simulate(&reported);
+        assert_eq!(output.symptomatic_incidence, full.symptomatic_incidence);
+        assert_eq!(output.draws.reporting, 20);
+        let (headers, _) = output.to_rows(&reported).unwrap();
+        assert_eq!(headers, vec!["step", "reported_cases"]);
+        let summary = output.summary(&reported);
+        assert!(summary.total_reported_cases.unwrap() < summary.total_infections);
+        assert_eq!(summary.total_symptom_onsets, None);
+        assert_eq!(
+            summary.total_infections,
+            full.infection_incidence.iter().sum::<u64>()
+        );
+    }
+
+    #[test]
+    fn test_trace_records_match_recomputation() {
+        let population = 20_000;
+        let parameters = Parameters {
+            population: Some(population),
+            r0: 1.8,
+            generation_interval_pmf: vec![0.2, 0.5, 0.3],
+            symptom_onset_pmf: vec![0.1, 0.6, 0.3],
+            initial_infections: vec![50],
+            sim_length: 60,
+            seed: 1234,
+            ..Default::default()
+        };
+        let untraced = RenewalModel::simulate(&parameters);
+        let traced = RenewalModel::simulate(&Parameters {
+            trace: Some(Trace {
+                steps: vec![10, 11, 12],
+                ..Default::default()
+            }),
+            ..parameters.clone()
+        });
+        assert!(untraced.trace.is_empty());
+        assert_eq!(traced.infection_incidence, untraced.infection_incidence);
+        assert_eq!(traced.symptomatic_incidence, untraced.symptomatic_incidence);
+        assert_eq!(traced.draws, untraced.draws);
+
+        let incidence = &untraced.infection_incidence;
+        let steps: Vec<usize> = traced.trace.iter().map(|r| r.step).collect();
+        assert_eq!(steps, [10, 11, 12]);
+        for record in &traced.trace {
+            let step = record.step;
+            let cum_infected: u64 = incidence[..step].iter().sum();
+            let susceptible = population - cum_infected;
+            let rt = parameters.r0 * susceptible as f64 / population as f64;
+            let cohorts: Vec<(usize, u64, f64)> = record
+                .cohorts
+                .iter()
+                .map(|c| (c.step, c.infections, c.weight))
+                .collect();
+            assert_eq!(
+                cohorts,
+                [
+                    (step - 1, incidence[step - 1], 0.2),
+                    (step - 2, incidence[step - 2], 0.5),
+                    (step - 3, incidence[step - 3], 0.3),
+                ]
+            );
+            let infectious = cohorts.iter().map(|(_, n, w)| *n as f64 * w).sum::<f64>();
+            assert!((record.infectious - infectious).abs() < 1e-9);
+            assert!((record.base_rate - parameters.r0 * infectious).abs() < 1e-9);
+            assert!((record.rt - rt).abs() < 1e-12);
+            assert!((record.transmission_rate - rt * infectious).abs() < 1e-9);
+            assert_eq!(record.susceptible, Some(susceptible));
+            let Some(Draw::Binomial { n, p, value }) = record.infection_draw else {
+                panic!("expected a binomial infection draw");
+            };
+            assert_eq!(n, susceptible);
+            assert!((p - rt * infectious / susceptible as f64).abs() < 1e-12);
+            assert_eq!(value, incidence[step]);
+            assert_eq!(record.infections, incidence[step]);
+            assert!(record.infections > 0);
+
+            // Each onset draw takes the infections not yet placed, with the
+            // onset probability conditional on the earlier delays
+            let mut remaining = record.infections;
+            let mut residual = 1.0;
+            assert_eq!(record.onset_draws.len(), 3);
+            for (draw, mass) in record.onset_draws.iter().zip(&parameters.symptom_onset_pmf) {
+                let Draw::Binomial { n, p, value } = *draw else {
+                    panic!("expected binomial onset draws");
+                };
+                assert_eq!(n, remaining);
+                assert!((p - f64::min(mass / residual, 1.0)).abs() < 1e-12);
+                remaining -= value;
+                residual -= mass;
+            }
+            assert_eq!(remaining, 0);
+        }
+    }
+
+    #[test]
+    fn test_trace_all_is_capped() {
+        let parameters = Parameters {
+            population: None,
+            r0: 1.1,
+            generation_interval_pmf: vec![0.5, 0.5],
+            symptom_onset_pmf: vec![1.],
+            initial_infections: vec![10],
+            sim_length: 30,
+            seed: 5,
+            trace: Some(Trace {
+                all: true,
+                max_rows: Some(12),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let output = RenewalModel::simulate(&parameters);
+        let steps: Vec<usize> = output.trace.iter().map(|r| r.step).collect();
+        assert_eq!(steps, (0..12).collect::<Vec<_>>());
+        assert_eq!(output.trace[0].infection_draw, None);
+        assert!(matches!(
+            output.trace[5].infection_draw,
+            Some(Draw::Poisson { .. })
+        ));
+    }
 }
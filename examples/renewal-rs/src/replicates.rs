@@ -0,0 +1,255 @@
+use cfa_mrp::{Environment, MrpError};
+
+use crate::parameters::{OutputLayout, OutputStream, Parameters};
+use crate::renewal::Streams;
+use crate::timestep::{self, Run};
+
+/// Default cap on cells buffered for `wide_by_replicate`: about 10 million
+/// short strings, a few hundred MB at most.
+pub const DEFAULT_MAX_WIDE_CELLS: usize = 10_000_000;
+
+/// Simulate `n` replicates with [`Environment::run_replicates`], each with
+/// the streams of a standalone run of that replicate.
+fn simulate_replicates(ctx: &Environment<Parameters>, n: u64) -> Result<Vec<(u64, Run)>, MrpError> {
+    ctx.declare_rng_streams(&Streams::NAMES);
+    let mut runs = Vec::new();
+    ctx.run_replicates(n, |replicate| {
+        if runs.last().is_some_and(|run: &Result<_, _>| run.is_err()) {
+            return;
+        }
+        runs.push(
+            timestep::simulate(replicate.input, Streams::from_replicate(&replicate))
+                .map(|run| (replicate.replicate, run)),
+        );
+    })?;
+    runs.into_iter().collect()
+}
+
+/// Run the payload's `"replicates": {"count": n}` in this process, writing
+/// each replicate's `renewal_output.csv` under `replicate_{k}/` exactly as
+/// a standalone run with `"replicate": k` writes it.
+pub fn write_in_process(ctx: &Environment<Parameters>) -> Result<(), MrpError> {
+    ctx.declare_rng_streams(&Streams::NAMES);
+    let mut result = Ok(());
+    ctx.run_payload_replicates(|replicate| {
+        if result.is_err() {
+            return;
+        }
+        result = timestep::simulate(replicate.input, Streams::from_replicate(&replicate))
+            .and_then(|run| replicate.try_write_csv("renewal_output.csv", &run.headers, &run.rows));
+    })?;
+    result
+}
+
+/// Each replicate's reported cases, for scoring against observations.
+pub fn reported_series(
+    ctx: &Environment<Parameters>,
+    parameters: &Parameters,
+) -> Result<Vec<Vec<u64>>, MrpError> {
+    let n = parameters.replicates.unwrap_or(1) as u64;
+    Ok(simulate_replicates(ctx, n)?
+        .into_iter()
+        .map(|(_, run)| run.output.reported_incidence)
+        .collect())
+}
+
+/// Simulate the input's `"replicates"` and lay the results out as requested.
+///
+/// The wide layout buffers one column per replicate before writing, so it
+/// holds `replicates × sim_length` cells at once; runs over `max_wide_cells`
+/// are rejected before simulating.
+pub fn to_rows(
+    ctx: &Environment<Parameters>,
+    parameters: &Parameters,
+) -> Result<(Vec<String>, Vec<Vec<String>>), MrpError> {
+    let n = parameters.replicates.unwrap_or(1);
+    match parameters.output_layout {
+        OutputLayout::Long => {
+            let mut headers = vec!["replicate".to_string()];
+            let mut rows = Vec::new();
+            for (i, (r, run)) in simulate_replicates(ctx, n as u64)?.into_iter().enumerate() {
+                let (h, rep_rows) = (run.headers, run.rows);
+                if i == 0 {
+                    headers.extend(h.iter().map(|s| s.to_string()));
+                }
+                rows.extend(
+                    rep_rows
+                        .into_iter()
+                        .map(|row| [vec![r.to_string()], row].concat()),
+                );
+            }
+            Ok((headers, rows))
+        }
+        OutputLayout::WideByReplicate => {
+            let limit = parameters.max_wide_cells.unwrap_or(DEFAULT_MAX_WIDE_CELLS);
+            let cells = n.saturating_mul(parameters.sim_length);
+            if cells > limit {
+                return Err(MrpError::Input(format!(
+                    "wide_by_replicate would buffer {n} replicates × {} steps = {cells} cells, \
+                     over max_wide_cells = {limit}",
+                    parameters.sim_length
+                )));
+            }
+            let measures = OutputStream::COLUMNS
+                .iter()
+                .filter(|stream| parameters.wants(**stream))
+                .count();
+            let mut headers = Vec::new();
+            let mut keys: Vec<Vec<String>> = Vec::new();
+            let mut columns: Vec<Vec<String>> = Vec::with_capacity(n);
+            let mut replicates = Vec::with_capacity(n);
+            for (i, (r, run)) in simulate_replicates(ctx, n as u64)?.into_iter().enumerate() {
+                let (h, rep_rows) = (run.headers, run.rows);
+                // Every column after the keys is a requested stream; the first fills `rep_{r}`
+                let n_keys = h.len() - measures;
+                replicates.push(r);
+                if i == 0 {
+                    headers = h[..n_keys].iter().map(|s| s.to_string()).collect();
+                    keys = rep_rows.iter().map(|row| row[..n_keys].to_vec()).collect();
+                }
+                columns.push(
+                    rep_rows
+                        .into_iter()
+                        .map(|row| row[n_keys].clone())
+                        .collect(),
+                );
+            }
+            headers.extend(replicates.iter().map(|r| format!("rep_{r}")));
+            let rows = keys
+                .into_iter()
+                .enumerate()
+                .map(|(i, mut row)| {
+                    row.extend(columns.iter().map(|column| column[i].clone()));
+                    row
+                })
+                .collect();
+            Ok((headers, rows))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use serde_json::json;
+
+    use super::*;
+
+    fn env(extra: serde_json::Value) -> Environment<Parameters> {
+        let mut input = json!({
+            "r0": 1.5,
+            "generation_interval_pmf": [0., 0.5, 0.5],
+            "symptom_onset_pmf": [0.5, 0.5],
+            "initial_infections": [5],
+            "sim_length": 30,
+            "seed": 11,
+            "replicates": 5,
+        });
+        input
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        Environment::from_json_typed(json!({ "input": input }))
+    }
+
+    fn to_rows_for(extra: serde_json::Value) -> Result<(Vec<String>, Vec<Vec<String>>), MrpError> {
+        let ctx = env(extra);
+        to_rows(&ctx, ctx.input.as_ref().unwrap())
+    }
+
+    #[test]
+    fn test_wide_is_pivot_of_long() {
+        let (long_headers, long_rows) = to_rows_for(json!({})).unwrap();
+        assert_eq!(
+            long_headers,
+            vec!["replicate", "step", "infections", "symptom_onsets"]
+        );
+        // Pivot long → step × replicate
+        let mut pivot: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+        for row in &long_rows {
+            let step: usize = row[1].parse().unwrap();
+            let rep: usize = row[0].parse().unwrap();
+            let cells = pivot.entry(step).or_insert_with(|| vec![String::new(); 5]);
+            cells[rep] = row[2].clone();
+        }
+
+        let (headers, rows) = to_rows_for(json!({ "output_layout": "wide_by_replicate" })).unwrap();
+        assert_eq!(
+            headers,
+            vec!["step", "rep_0", "rep_1", "rep_2", "rep_3", "rep_4"]
+        );
+        assert_eq!(rows.len(), 30);
+        for (row, (step, cells)) in rows.iter().zip(pivot) {
+            assert_eq!(row[0], step.to_string());
+            assert_eq!(row[1..], cells[..]);
+        }
+    }
+
+    #[test]
+    fn test_wide_cell_limit() {
+        let err = to_rows_for(json!({
+            "output_layout": "wide_by_replicate",
+            "max_wide_cells": 100,
+        }))
+        .unwrap_err();
+        assert!(err.to_string().contains("max_wide_cells = 100"));
+    }
+
+    #[test]
+    fn test_in_process_replicates_match_standalone() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = json!({
+            "r0": 1.5,
+            "generation_interval_pmf": [0., 0.5, 0.5],
+            "symptom_onset_pmf": [0.5, 0.5],
+            "initial_infections": [5],
+            "sim_length": 30,
+            "seed": 11,
+        });
+        let in_process = dir.path().join("in_process");
+        let ctx = Environment::<Parameters>::from_json_typed(json!({
+            "input": input,
+            "output": { "spec": "filesystem", "dir": in_process },
+            "replicates": { "count": 3 },
+        }));
+        write_in_process(&ctx).unwrap();
+
+        // The input's "replicates" runs the same replicates
+        let (_, long_rows) = to_rows_for(json!({ "replicates": 3 })).unwrap();
+        let mut outputs = Vec::new();
+        for k in 0..3u64 {
+            let standalone = dir.path().join(format!("standalone_{k}"));
+            let env = Environment::builder()
+                .seed(11)
+                .replicate(k)
+                .param("r0", 1.5)
+                .param("generation_interval_pmf", [0., 0.5, 0.5])
+                .param("symptom_onset_pmf", [0.5, 0.5])
+                .param("initial_infections", [5])
+                .param("sim_length", 30)
+                .output_dir(&standalone)
+                .build_typed::<Parameters>();
+            let params = env.input.as_ref().unwrap();
+            let run = timestep::simulate(params, Streams::from_env(&env)).unwrap();
+            env.write_csv("renewal_output.csv", &run.headers, &run.rows);
+
+            let expected = std::fs::read(standalone.join("renewal_output.csv")).unwrap();
+            let actual =
+                std::fs::read(in_process.join(format!("replicate_{k}/renewal_output.csv")))
+                    .unwrap();
+            assert_eq!(actual, expected, "replicate {k}");
+            let long: Vec<&[String]> = long_rows
+                .iter()
+                .filter(|row| row[0] == k.to_string())
+                .map(|row| &row[1..])
+                .collect();
+            assert_eq!(
+                long,
+                run.rows.iter().map(|row| &row[..]).collect::<Vec<_>>()
+            );
+            outputs.push(actual);
+        }
+        assert_ne!(outputs[0], outputs[1]);
+    }
+}
@@ -0,0 +1,221 @@
+use cfa_mrp::{MrpError, format};
+
+use crate::output::RenewalOutput;
+use crate::parameters::{Aggregate, Parameters};
+use crate::renewal::{RenewalModel, Streams};
+
+/// A simulated run on its own step grid, with the rows to write.
+pub struct Run {
+    /// Parameters on the step grid (PMFs and `sim_length` in steps).
+    pub parameters: Parameters,
+    pub output: RenewalOutput,
+    pub headers: Vec<&'static str>,
+    pub rows: Vec<Vec<String>>,
+}
+
+/// Simulate `parameters`, on a `dt`-day grid when `dt` is set.
+///
+/// With `dt`, PMFs and `sim_length` given in days are converted to steps and
+/// rows carry `step` and `time_days`, unless `daily_totals` sums steps back
+/// into days (which also enables date-keyed aggregation).
+pub fn simulate(parameters: &Parameters, streams: Streams) -> Result<Run, MrpError> {
+    let Some(dt) = parameters.dt else {
+        let output = RenewalModel::simulate_with(parameters, streams);
+        let (headers, rows) = output.to_rows(parameters)?;
+        return Ok(Run {
+            parameters: parameters.clone(),
+            output,
+            headers,
+            rows,
+        });
+    };
+    let steps_per_day = steps_per_day(dt)?;
+    if parameters.daily_totals && steps_per_day.is_none() {
+        return Err(MrpError::Input(format!(
+            "daily_totals requires dt to divide one day evenly, got dt = {dt}"
+        )));
+    }
+    if !parameters.daily_totals && parameters.aggregate != Aggregate::Daily {
+        return Err(MrpError::Input(
+            "aggregate with fractional dt requires daily_totals".to_string(),
+        ));
+    }
+    let grid = Parameters {
+        generation_interval_pmf: rediscretize(&parameters.generation_interval_pmf, dt),
+        symptom_onset_pmf: rediscretize(&parameters.symptom_onset_pmf, dt),
+        sim_length: (parameters.sim_length as f64 / dt).round() as usize,
+        dt: None,
+        ..parameters.clone()
+    };
+    let output = RenewalModel::simulate_with(&grid, streams);
+    let (headers, rows) = match steps_per_day {
+        Some(k) if parameters.daily_totals => to_daily(&output, k).to_rows(parameters)?,
+        _ => {
+            let steps = Parameters {
+                start_date: None,
+                aggregate: Aggregate::Daily,
+                ..grid.clone()
+            };
+            let (mut headers, mut rows) = output.to_rows(&steps)?;
+            headers.insert(1, "time_days");
+            for (i, row) in rows.iter_mut().enumerate() {
+                row.insert(1, format::float(i as f64 * dt));
+            }
+            (headers, rows)
+        }
+    };
+    Ok(Run {
+        parameters: grid,
+        output,
+        headers,
+        rows,
+    })
+}
+
+/// Steps per day if `dt` divides one day evenly.
+fn steps_per_day(dt: f64) -> Result<Option<usize>, MrpError> {
+    if !(dt > 0.0 && dt <= 1.0) {
+        return Err(MrpError::Input(format!(
+            "dt must be in (0, 1] days, got {dt}"
+        )));
+    }
+    let k = (1.0 / dt).round();
+    Ok(((1.0 / dt - k).abs() < 1e-9).then_some(k as usize))
+}
+
+/// Convert a PMF over whole-day delays onto a `dt`-day grid, conserving mass.
+///
+/// Entry `d` of a daily PMF is a delay of `d + 1` days, taken as uniform over
+/// `[d + 0.5, d + 1.5)` days; entry `j` of the result is the mass falling in
+/// `[(j + 0.5) dt, (j + 1.5) dt)`, i.e. a delay of `j + 1` steps. With
+/// `dt = 1` this is the identity, and the mean delay is preserved.
+pub fn rediscretize(pmf_days: &[f64], dt: f64) -> Vec<f64> {
+    let end = pmf_days.len() as f64 + 0.5;
+    let n = ((end / dt) - 0.5).ceil().max(0.0) as usize;
+    (0..n)
+        .map(|j| {
+            let (lo, hi) = ((j as f64 + 0.5) * dt, (j as f64 + 1.5) * dt);
+            pmf_days
+                .iter()
+                .enumerate()
+                .map(|(d, mass)| {
+                    let overlap = hi.min(d as f64 + 1.5) - lo.max(d as f64 + 0.5);
+                    mass * overlap.max(0.0)
+                })
+                .sum()
+        })
+        .collect()
+}
+
+/// Sum each day's `steps_per_day` steps into one.
+pub fn to_daily(output: &RenewalOutput, steps_per_day: usize) -> RenewalOutput {
+    let sum = |series: &[u64]| -> Vec<u64> {
+        series
+            .chunks(steps_per_day)
+            .map(|day| day.iter().sum())
+            .collect()
+    };
+    RenewalOutput {
+        infection_incidence: sum(&output.infection_incidence),
+        symptomatic_incidence: sum(&output.symptomatic_incidence),
+        reported_incidence: sum(&output.reported_incidence),
+        draws: output.draws,
+        trace: output.trace.clone(),
+        // Pending cohorts are sub-day steps, which a daily run cannot extend
+        pending_onsets: Default::default(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rediscretize_conserves_mass() {
+        let pmf = vec![0.1, 0.2, 0.4, 0.3];
+        let mean = |p: &[f64], dt: f64| -> f64 {
+            p.iter()
+                .enumerate()
+                .map(|(j, m)| (j + 1) as f64 * dt * m)
+                .sum()
+        };
+        for dt in [1.0, 0.5, 0.25, 0.3] {
+            let fine = rediscretize(&pmf, dt);
+            let total: f64 = fine.iter().sum();
+            assert!((total - 1.0).abs() < 1e-12, "dt = {dt}: {total}");
+            assert!(
+                (mean(&fine, dt) - mean(&pmf, 1.0)).abs() < 0.01,
+                "dt = {dt}"
+            );
+        }
+        let same = rediscretize(&pmf, 1.0);
+        assert_eq!(same.len(), pmf.len());
+        for (a, b) in same.iter().zip(&pmf) {
+            assert!((a - b).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_half_day_steps_match_daily_in_expectation() {
+        let base = Parameters {
+            r0: 1.6,
+            generation_interval_pmf: vec![0.1, 0.3, 0.4, 0.2],
+            symptom_onset_pmf: vec![0.5, 0.5],
+            initial_infections: vec![20],
+            sim_length: 80,
+            population: Some(20_000),
+            ..Default::default()
+        };
+        let half = Parameters {
+            dt: Some(0.5),
+            daily_totals: true,
+            ..base.clone()
+        };
+        let n = 100;
+        let (mut daily, mut fine) = (0.0, 0.0);
+        for seed in 0..n {
+            let total = |p: &Parameters| -> f64 {
+                let run =
+                    simulate(&Parameters { seed, ..p.clone() }, Streams::from_seed(seed)).unwrap();
+                assert_eq!(run.rows.len(), 80);
+                run.rows.iter().map(|r| r[1].parse::<f64>().unwrap()).sum()
+            };
+            daily += total(&base) / n as f64;
+            fine += total(&half) / n as f64;
+        }
+        // Final size is set by r0 alone, whatever the step
+        assert!((fine - daily).abs() / daily < 0.05, "{fine} vs {daily}");
+    }
+
+    #[test]
+    fn test_fine_rows_and_validation() {
+        let parameters = Parameters {
+            r0: 1.0,
+            generation_interval_pmf: vec![1.0],
+            symptom_onset_pmf: vec![1.0],
+            initial_infections: vec![1],
+            sim_length: 2,
+            dt: Some(0.25),
+            ..Default::default()
+        };
+        let run = simulate(&parameters, Streams::from_seed(0)).unwrap();
+        assert_eq!(
+            run.headers,
+            vec!["step", "time_days", "infections", "symptom_onsets"]
+        );
+        assert_eq!(run.rows.len(), 8);
+        assert_eq!(run.rows[3][1], "0.75");
+
+        let uneven = Parameters {
+            dt: Some(0.3),
+            daily_totals: true,
+            ..parameters.clone()
+        };
+        assert!(simulate(&uneven, Streams::from_seed(0)).is_err());
+        let zero = Parameters {
+            dt: Some(0.0),
+            ..parameters
+        };
+        assert!(simulate(&zero, Streams::from_seed(0)).is_err());
+    }
+}
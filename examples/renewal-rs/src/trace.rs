@@ -0,0 +1,65 @@
+use serde::Serialize;
+
+/// What one traced step of the renewal loop computed and drew.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StepTrace {
+    pub step: usize,
+    /// Earlier steps whose infections contribute to this step's force of
+    /// infection, most recent first.
+    pub cohorts: Vec<Cohort>,
+    /// Sum of cohort infections weighted by the generation interval.
+    pub infectious: f64,
+    /// `r0 * infectious`, before the susceptible-depletion multiplier.
+    pub base_rate: f64,
+    /// The reproduction number after depletion.
+    pub rt: f64,
+    /// `rt * infectious`.
+    pub transmission_rate: f64,
+    /// Susceptibles before this step's infections; absent without a
+    /// population.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub susceptible: Option<u64>,
+    /// The infection draw; absent for seeded steps or a zero rate.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub infection_draw: Option<Draw>,
+    pub infections: u64,
+    /// Symptom onset draws for this step's infections, one per onset delay.
+    pub onset_draws: Vec<Draw>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Cohort {
+    pub step: usize,
+    pub infections: u64,
+    pub weight: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "distribution", rename_all = "snake_case")]
+pub enum Draw {
+    Binomial {
+        n: u64,
+        p: f64,
+        value: u64,
+    },
+    Poisson {
+        rate: f64,
+        value: u64,
+    },
+    /// A rounded normal approximation, from `fast_onsets`.
+    Normal {
+        mean: f64,
+        sd: f64,
+        value: u64,
+    },
+}
+
+impl Draw {
+    pub fn value(&self) -> u64 {
+        match self {
+            Draw::Binomial { value, .. }
+            | Draw::Poisson { value, .. }
+            | Draw::Normal { value, .. } => *value,
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use cfa_mrp::Environment;
+use rand::distr::Distribution;
+use rand::distr::weighted::WeightedIndex;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+
+use crate::output::RenewalOutput;
+use crate::parameters::{OutputStream, Parameters};
+use crate::renewal::{RenewalModel, Streams};
+
+/// One infection in `tree.csv`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Case {
+    pub child_id: u64,
+    /// Absent for seeded infections.
+    pub parent_id: Option<u64>,
+    pub infection_day: usize,
+    /// Absent if onsets were not simulated or fall after the run.
+    pub onset_day: Option<usize>,
+}
+
+/// A transmission tree consistent with a run's counts.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Tree {
+    pub cases: Vec<Case>,
+    /// The first day left out because it would take cumulative infections
+    /// past `max_cases`.
+    pub truncated_at: Option<usize>,
+}
+
+/// Assign every infection in `output` an id and a parent, day by day, until
+/// cumulative infections would exceed `max_cases`.
+///
+/// A parent's cohort is drawn with the weight it contributes to the day's
+/// force of infection (generation interval mass times infections, as in the
+/// renewal loop), then a case uniformly within it. Onset days are the run's
+/// own onset draws, replayed from the `observation` stream in the same step
+/// order, and shuffled among the day's cases. Only the `tree` stream is
+/// drawn from otherwise, so the counts do not depend on whether a tree is
+/// built.
+pub fn build(
+    parameters: &Parameters,
+    output: &RenewalOutput,
+    streams: Streams,
+    max_cases: u64,
+) -> Tree {
+    let onsets = parameters.wants(OutputStream::SymptomOnsets)
+        || parameters.wants(OutputStream::ReportedCases);
+    let incidence = &output.infection_incidence;
+    let mut tree = Tree::default();
+    // Id of the first case infected on each day
+    let mut first_id = Vec::with_capacity(incidence.len());
+    let mut rng = StdRng::seed_from_u64(streams.tree);
+    let mut observation = StdRng::seed_from_u64(streams.observation);
+    for (day, &infections) in incidence.iter().enumerate() {
+        let next_id = tree.cases.len() as u64;
+        if next_id + infections > max_cases {
+            tree.truncated_at = Some(day);
+            break;
+        }
+        first_id.push(next_id);
+        let cohorts: Vec<(usize, f64)> = if day < parameters.initial_infections.len() {
+            Vec::new()
+        } else {
+            (0..usize::min(day, parameters.generation_interval_pmf.len()))
+                .map(|lag| {
+                    let cohort = day - lag - 1;
+                    let weight = parameters.generation_interval_pmf[lag];
+                    (cohort, weight * incidence[cohort] as f64)
+                })
+                .collect()
+        };
+        let pick = WeightedIndex::new(cohorts.iter().map(|(_, w)| *w)).ok();
+        let mut onset_days: Vec<Option<usize>> = Vec::new();
+        if onsets {
+            let draws = RenewalModel::onset_draws(parameters, &mut observation, day, infections);
+            for (draw, onset_day) in draws.iter().zip(day + 1..) {
+                onset_days.extend((0..draw.value()).map(|_| Some(onset_day)));
+            }
+        }
+        onset_days.resize(infections as usize, None);
+        onset_days.shuffle(&mut rng);
+        for onset_day in onset_days {
+            let parent_id = pick.as_ref().map(|pick| {
+                let cohort = cohorts[pick.sample(&mut rng)].0;
+                first_id[cohort] + rng.random_range(0..incidence[cohort])
+            });
+            tree.cases.push(Case {
+                child_id: tree.cases.len() as u64,
+                parent_id,
+                infection_day: day,
+                onset_day,
+            });
+        }
+    }
+    tree
+}
+
+/// Write `tree.csv`, warning if the tree stopped at `max_cases`.
+pub fn write<I>(ctx: &Environment<I>, tree: &Tree, max_cases: u64) {
+    let optional = |value: Option<String>| value.unwrap_or_default();
+    let rows: Vec<Vec<String>> = tree
+        .cases
+        .iter()
+        .map(|case| {
+            vec![
+                case.child_id.to_string(),
+                optional(case.parent_id.map(|id| id.to_string())),
+                case.infection_day.to_string(),
+                optional(case.onset_day.map(|day| day.to_string())),
+            ]
+        })
+        .collect();
+    ctx.write_csv(
+        "tree.csv",
+        &["child_id", "parent_id", "infection_day", "onset_day"],
+        &rows,
+    );
+    if let Some(day) = tree.truncated_at {
+        ctx.warn(
+            "tree_truncated",
+            &format!(
+                "tree.csv stops before day {day}, where cumulative infections exceed \
+                 max_cases = {max_cases}"
+            ),
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parameters::EmitTree;
+
+    fn parameters() -> Parameters {
+        Parameters {
+            r0: 1.8,
+            generation_interval_pmf: vec![0.1, 0.4, 0.3, 0.2],
+            symptom_onset_pmf: vec![0.2, 0.5, 0.3],
+            initial_infections: vec![3, 1],
+            sim_length: 25,
+            population: Some(2_000),
+            seed: 11,
+            outputs: Some(vec![OutputStream::Infections, OutputStream::SymptomOnsets]),
+            emit_tree: Some(EmitTree { max_cases: 5000 }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_every_case_has_an_earlier_parent() {
+        let parameters = parameters();
+        let streams = Streams::from_seed(parameters.seed);
+        let output = RenewalModel::simulate_with(&parameters, streams);
+        let tree = build(&parameters, &output, streams, 5000);
+        let total: u64 = output.infection_incidence.iter().sum();
+        assert!(total > 20, "{total}");
+        assert_eq!(tree.cases.len() as u64, total);
+        assert_eq!(tree.truncated_at, None);
+        for case in &tree.cases {
+            match case.parent_id {
+                None => assert!(case.infection_day < 2, "{case:?}"),
+                Some(parent) => {
+                    let parent = &tree.cases[parent as usize];
+                    assert!(parent.infection_day < case.infection_day);
+                    assert!(case.infection_day - parent.infection_day <= 4);
+                }
+            }
+        }
+        // Onsets match the run's counts
+        for (day, &onsets) in output.symptomatic_incidence.iter().enumerate() {
+            let in_tree = tree.cases.iter().filter(|c| c.onset_day == Some(day));
+            assert_eq!(in_tree.count() as u64, onsets, "day {day}");
+        }
+    }
+
+    #[test]
+    fn test_tree_leaves_trajectories_unchanged() {
+        let with_tree = parameters();
+        let without = Parameters {
+            emit_tree: None,
+            ..with_tree.clone()
+        };
+        let streams = Streams::from_seed(with_tree.seed);
+        let a = RenewalModel::simulate_with(&with_tree, streams);
+        let b = RenewalModel::simulate_with(&without, streams);
+        assert_eq!(a.infection_incidence, b.infection_incidence);
+        assert_eq!(a.symptomatic_incidence, b.symptomatic_incidence);
+    }
+
+    #[test]
+    fn test_truncated_at_max_cases() {
+        let parameters = parameters();
+        let streams = Streams::from_seed(parameters.seed);
+        let output = RenewalModel::simulate_with(&parameters, streams);
+        let tree = build(&parameters, &output, streams, 10);
+        let day = tree.truncated_at.unwrap();
+        let before: u64 = output.infection_incidence[..day].iter().sum();
+        assert_eq!(tree.cases.len() as u64, before);
+        assert!(before + output.infection_incidence[day] > 10);
+    }
+}
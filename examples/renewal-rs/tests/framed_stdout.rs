@@ -0,0 +1,51 @@
+//! The renewal model as the child of a parent that folds over its framed
+//! stdout as it runs.
+
+use cfa_mrp::framing::CompletionStatus;
+use cfa_mrp::{Frame, SubprocessRuntime};
+
+#[test]
+fn test_fold_child_rows_matches_its_summary() {
+    let runtime = SubprocessRuntime::new(vec![env!("CARGO_BIN_EXE_renewal").to_string()]);
+    let mut child = runtime
+        .spawn(&serde_json::json!({
+            "input": {
+                "seed": 42,
+                "r0": 2.0,
+                "population": 100000,
+                "generation_interval_pmf": [0.0, 0.0, 0.25, 0.5, 0.25],
+                "symptom_onset_pmf": [1.0],
+                "initial_infections": [1],
+                "sim_length": 200,
+                "outputs": ["infections", "summary"]
+            },
+            "output": { "spec": "stdout", "framed": true }
+        }))
+        .unwrap();
+
+    let mut column = None;
+    let mut infections = 0;
+    let mut summary = None;
+    let mut completion = None;
+    for frame in child.stream() {
+        match frame.unwrap() {
+            Frame::CsvRow { file, record } if file == "renewal_output.csv" => match column {
+                None => column = record.iter().position(|name| name == "infections"),
+                Some(i) => infections += record[i].parse::<u64>().unwrap(),
+            },
+            Frame::Data { file, data } if file == "summary.json" => {
+                summary = Some(serde_json::from_slice::<serde_json::Value>(&data).unwrap());
+            }
+            Frame::Completion(done) => completion = Some(done),
+            _ => {}
+        }
+    }
+    let result = child.wait().unwrap();
+    assert!(result.ok(), "{}", String::from_utf8_lossy(&result.stderr));
+    assert!(result.stdout.is_empty());
+
+    let summary = summary.expect("summary.json");
+    assert!(infections > 1);
+    assert_eq!(summary["total_infections"], infections);
+    assert_eq!(completion.unwrap().status, CompletionStatus::Complete);
+}
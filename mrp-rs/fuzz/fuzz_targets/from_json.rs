@@ -0,0 +1,40 @@
+//! Arbitrary payloads must never panic `Environment` construction.
+//!
+//! `Environment::from_json`, `from_stdin` and the other infallible
+//! constructors exit exactly when their `try_` form returns an error, so a
+//! payload that the `try_` form accepts must also build through the
+//! infallible one without panicking. Raw bytes go through `try_from_reader`, as a stdin
+//! payload does, and parsed JSON through `try_from_json` and `from_json`.
+//!
+//! Run with `cargo +nightly fuzz run from_json` from `mrp-rs/`.
+#![no_main]
+
+use cfa_mrp::Environment;
+use libfuzzer_sys::fuzz_target;
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+#[allow(dead_code)]
+struct Known {
+    r0: f64,
+    sim_length: usize,
+    pmf: Vec<f64>,
+    label: Option<String>,
+}
+
+fn exercise(env: Environment) {
+    let _ = env.output_dir();
+    let _ = env.try_with_input_type::<Known>();
+}
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(env) = Environment::try_from_reader(data) {
+        exercise(env);
+    }
+    let Ok(payload) = serde_json::from_slice::<serde_json::Value>(data) else {
+        return;
+    };
+    if Environment::try_from_json(payload.clone()).is_ok() {
+        exercise(Environment::from_json(payload));
+    }
+});
@@ -0,0 +1,588 @@
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use flate2::Compression;
+use flate2::write::{DeflateEncoder, GzEncoder};
+
+use crate::MrpError;
+use crate::atomic;
+use crate::calendar::Date;
+use crate::environment::Warning;
+use crate::scratch::Scratch;
+
+/// Outputs of the `"archive"` output spec, streamed as entries into one
+/// `.tar.gz` or `.zip` file named by the filenames the model passed.
+///
+/// Whole files are added as they are written. Streamed files (CSV and
+/// JSON-lines writers) are buffered and added when their writer is closed.
+/// The archive is written to a file in the run's [`Scratch`] directory and
+/// moved into place by [`ArchiveOutput::finish`], which finalize calls after closing managed
+/// CSV writers; a writer still open then is left out of the archive with
+/// an `archive_entry_open` warning, and later writes to it fail.
+pub(crate) struct ArchiveOutput {
+    path: PathBuf,
+    tmp: PathBuf,
+    encoder: RefCell<Option<Encoder>>,
+    /// Entries added or being written, so none is added twice.
+    names: RefCell<BTreeSet<String>>,
+    /// Entries whose writer has not been closed.
+    open: RefCell<BTreeSet<String>>,
+    warnings: Rc<RefCell<Vec<Warning>>>,
+}
+
+impl ArchiveOutput {
+    /// Start the archive at `path`, a `.tar.gz`, `.tgz` or `.zip` file,
+    /// staging it in `scratch`.
+    pub(crate) fn create(
+        path: &Path,
+        scratch: &Scratch,
+        warnings: Rc<RefCell<Vec<Warning>>>,
+    ) -> Result<Rc<Self>, MrpError> {
+        let name = path.to_string_lossy();
+        let zip = if name.ends_with(".zip") {
+            true
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            false
+        } else {
+            return Err(MrpError::Config(format!(
+                "archive path '{name}' must end in .tar.gz, .tgz or .zip"
+            )));
+        };
+        let tmp = scratch.file(&path.file_name().unwrap_or_default().to_string_lossy())?;
+        let file = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| File::create(&tmp))
+            .map_err(|e| {
+                MrpError::Output(format!("failed to create archive {}: {e}", path.display()))
+            })?;
+        let out = BufWriter::new(file);
+        let encoder = if zip {
+            Encoder::Zip(ZipEncoder {
+                out,
+                offset: 0,
+                entries: Vec::new(),
+            })
+        } else {
+            Encoder::TarGz(GzEncoder::new(out, Compression::default()))
+        };
+        Ok(Rc::new(ArchiveOutput {
+            path: path.to_path_buf(),
+            tmp,
+            encoder: RefCell::new(Some(encoder)),
+            names: RefCell::new(BTreeSet::new()),
+            open: RefCell::new(BTreeSet::new()),
+            warnings,
+        }))
+    }
+
+    fn reserve(&self, filename: &str) -> Result<(), MrpError> {
+        if self.encoder.borrow().is_none() {
+            return Err(MrpError::Output(format!(
+                "cannot add '{filename}' to {}: finalize has closed the archive",
+                self.path.display()
+            )));
+        }
+        if !self.names.borrow_mut().insert(filename.to_string()) {
+            return Err(MrpError::Output(format!(
+                "'{filename}' is already in archive {}; archive entries cannot be replaced",
+                self.path.display()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Add `filename` holding `data`.
+    pub(crate) fn put(&self, filename: &str, data: &[u8]) -> Result<(), MrpError> {
+        self.reserve(filename)?;
+        self.add(filename, data)
+    }
+
+    fn add(&self, filename: &str, data: &[u8]) -> Result<(), MrpError> {
+        let mut encoder = self.encoder.borrow_mut();
+        let encoder = encoder.as_mut().ok_or_else(|| {
+            MrpError::Output(format!(
+                "cannot add '{filename}' to {}: finalize has closed the archive",
+                self.path.display()
+            ))
+        })?;
+        encoder.add(filename, data).map_err(|e| {
+            MrpError::Output(format!(
+                "failed to add '{filename}' to archive {}: {e}",
+                self.path.display()
+            ))
+        })
+    }
+
+    /// A writer buffering `filename` until it is dropped, then adding it.
+    pub(crate) fn writer(self: &Rc<Self>, filename: &str) -> Result<ArchiveWriter, MrpError> {
+        self.reserve(filename)?;
+        self.open.borrow_mut().insert(filename.to_string());
+        Ok(ArchiveWriter {
+            archive: self.clone(),
+            filename: filename.to_string(),
+            data: Vec::new(),
+        })
+    }
+
+    /// Stop without writing the archive, removing what was started.
+    pub(crate) fn abandon(&self) {
+        if self.encoder.borrow_mut().take().is_some() {
+            let _ = fs::remove_file(&self.tmp);
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        self.encoder.borrow().is_none()
+    }
+
+    fn warn(&self, code: &str, message: String) {
+        let warning = Warning::output_failure(code, message);
+        eprintln!("warning [{}]: {}", warning.code, warning.message);
+        self.warnings.borrow_mut().push(warning);
+    }
+
+    /// Write the archive's trailer and move it into place. Entries still
+    /// being written are left out. Later calls do nothing.
+    pub(crate) fn finish(&self) -> Result<(), MrpError> {
+        let Some(encoder) = self.encoder.borrow_mut().take() else {
+            return Ok(());
+        };
+        for filename in std::mem::take(&mut *self.open.borrow_mut()) {
+            self.warn(
+                "archive_entry_open",
+                format!(
+                    "'{filename}' was still being written when finalize closed archive {}, \
+                     so it is left out; close its writer before finalizing",
+                    self.path.display()
+                ),
+            );
+        }
+        encoder
+            .finish()
+            .and_then(|_| atomic::move_into_place(&self.tmp, &self.path))
+            .map_err(|e| {
+                MrpError::Output(format!(
+                    "failed to finish archive {}: {e}",
+                    self.path.display()
+                ))
+            })
+    }
+}
+
+impl Drop for ArchiveOutput {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            return;
+        }
+        if let Err(e) = self.finish() {
+            self.warn("archive_failed", e.to_string());
+        }
+    }
+}
+
+/// Buffers one entry of an [`ArchiveOutput`] and adds it when dropped, or
+/// fails once the archive is closed.
+pub(crate) struct ArchiveWriter {
+    archive: Rc<ArchiveOutput>,
+    filename: String,
+    data: Vec<u8>,
+}
+
+impl ArchiveWriter {
+    fn check_open(&self) -> io::Result<()> {
+        if self.archive.is_finished() {
+            return Err(io::Error::other(format!(
+                "'{}' was left out of archive {}, which finalize has closed",
+                self.filename,
+                self.archive.path.display()
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Write for ArchiveWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check_open()?;
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.check_open()
+    }
+}
+
+impl Drop for ArchiveWriter {
+    fn drop(&mut self) {
+        if std::thread::panicking() || self.archive.is_finished() {
+            return;
+        }
+        self.archive.open.borrow_mut().remove(&self.filename);
+        if let Err(e) = self.archive.add(&self.filename, &self.data) {
+            self.archive.warn("archive_entry_failed", e.to_string());
+        }
+    }
+}
+
+enum Encoder {
+    TarGz(GzEncoder<BufWriter<File>>),
+    Zip(ZipEncoder),
+}
+
+impl Encoder {
+    fn add(&mut self, name: &str, data: &[u8]) -> io::Result<()> {
+        let mtime = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        match self {
+            Encoder::TarGz(out) => {
+                out.write_all(&tar_header(name, data.len() as u64, mtime)?)?;
+                out.write_all(data)?;
+                let padding = (512 - data.len() % 512) % 512;
+                out.write_all(&[0; 512][..padding])
+            }
+            Encoder::Zip(zip) => zip.add(name, data, mtime),
+        }
+    }
+
+    fn finish(self) -> io::Result<()> {
+        let mut out = match self {
+            Encoder::TarGz(mut out) => {
+                // Two empty blocks end a tar file
+                out.write_all(&[0; 1024])?;
+                out.finish()?
+            }
+            Encoder::Zip(zip) => zip.finish()?,
+        };
+        out.flush()?;
+        out.get_ref().sync_all()
+    }
+}
+
+/// A ustar header for a regular file. Names over 100 bytes are split into
+/// the header's prefix and name at a `/`.
+fn tar_header(name: &str, size: u64, mtime: u64) -> io::Result<[u8; 512]> {
+    let too_long = || io::Error::other(format!("'{name}' is too long for a tar entry name"));
+    let (prefix, name) = if name.len() <= 100 {
+        ("", name)
+    } else {
+        name.char_indices()
+            .filter(|&(i, c)| c == '/' && i <= 155 && name.len() - i - 1 <= 100)
+            .map(|(i, _)| (&name[..i], &name[i + 1..]))
+            .next()
+            .ok_or_else(too_long)?
+    };
+    if size >= 8 << 30 {
+        return Err(io::Error::other(format!(
+            "'{name}' is 8 GiB or more, too large for a tar entry"
+        )));
+    }
+    let mut header = [0u8; 512];
+    let mut field = |at: usize, value: &[u8]| header[at..at + value.len()].copy_from_slice(value);
+    field(0, name.as_bytes());
+    field(100, b"0000644\0");
+    field(108, b"0000000\0");
+    field(116, b"0000000\0");
+    field(124, format!("{size:011o}\0").as_bytes());
+    field(
+        136,
+        format!("{:011o}\0", mtime.min(0o77777777777)).as_bytes(),
+    );
+    field(156, b"0");
+    field(257, b"ustar\0");
+    field(263, b"00");
+    field(345, prefix.as_bytes());
+    // The checksum is taken with its own field as spaces
+    header[148..156].fill(b' ');
+    let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+    header[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+    Ok(header)
+}
+
+/// A zip file written entry by entry. Each entry is deflated whole, so its
+/// sizes and CRC go in its local header; there is no zip64, so an archive
+/// is limited to 65,535 entries and 4 GiB.
+struct ZipEncoder {
+    out: BufWriter<File>,
+    offset: u64,
+    entries: Vec<ZipEntry>,
+}
+
+struct ZipEntry {
+    name: String,
+    crc: u32,
+    compressed: u32,
+    size: u32,
+    offset: u32,
+    time: u16,
+    date: u16,
+}
+
+const ZIP_VERSION: u16 = 20;
+/// Names are UTF-8.
+const ZIP_FLAGS: u16 = 0x0800;
+const ZIP_DEFLATE: u16 = 8;
+
+impl ZipEncoder {
+    fn add(&mut self, name: &str, data: &[u8], mtime: u64) -> io::Result<()> {
+        let too_large = || {
+            io::Error::other(format!(
+                "'{name}' would take the zip past 4 GiB or 65,535 entries; use .tar.gz"
+            ))
+        };
+        let mut deflate = DeflateEncoder::new(Vec::new(), Compression::default());
+        deflate.write_all(data)?;
+        let compressed = deflate.finish()?;
+        let mut crc = flate2::Crc::new();
+        crc.update(data);
+        let (time, date) = dos_time(mtime);
+        let entry = ZipEntry {
+            name: name.to_string(),
+            crc: crc.sum(),
+            compressed: compressed.len().try_into().map_err(|_| too_large())?,
+            size: data.len().try_into().map_err(|_| too_large())?,
+            offset: self.offset.try_into().map_err(|_| too_large())?,
+            time,
+            date,
+        };
+        if self.entries.len() == u16::MAX as usize {
+            return Err(too_large());
+        }
+        let mut header = Vec::with_capacity(30 + name.len());
+        header.extend(0x04034b50u32.to_le_bytes());
+        for field in [ZIP_VERSION, ZIP_FLAGS, ZIP_DEFLATE, entry.time, entry.date] {
+            header.extend(field.to_le_bytes());
+        }
+        for field in [entry.crc, entry.compressed, entry.size] {
+            header.extend(field.to_le_bytes());
+        }
+        header.extend((name.len() as u16).to_le_bytes());
+        header.extend(0u16.to_le_bytes());
+        header.extend(name.as_bytes());
+        self.out.write_all(&header)?;
+        self.out.write_all(&compressed)?;
+        self.offset += (header.len() + compressed.len()) as u64;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Write the central directory.
+    fn finish(mut self) -> io::Result<BufWriter<File>> {
+        let too_large = || io::Error::other("the zip's central directory is past 4 GiB");
+        let start: u32 = self.offset.try_into().map_err(|_| too_large())?;
+        let mut directory = Vec::new();
+        for entry in &self.entries {
+            directory.extend(0x02014b50u32.to_le_bytes());
+            for field in [
+                ZIP_VERSION,
+                ZIP_VERSION,
+                ZIP_FLAGS,
+                ZIP_DEFLATE,
+                entry.time,
+                entry.date,
+            ] {
+                directory.extend(field.to_le_bytes());
+            }
+            for field in [entry.crc, entry.compressed, entry.size] {
+                directory.extend(field.to_le_bytes());
+            }
+            // Name length, then no extra field, comment, disk or attributes
+            directory.extend((entry.name.len() as u16).to_le_bytes());
+            directory.extend([0; 8]);
+            directory.extend(0u32.to_le_bytes());
+            directory.extend(entry.offset.to_le_bytes());
+            directory.extend(entry.name.as_bytes());
+        }
+        let count = self.entries.len() as u16;
+        let size: u32 = directory.len().try_into().map_err(|_| too_large())?;
+        directory.extend(0x06054b50u32.to_le_bytes());
+        directory.extend([0; 4]);
+        directory.extend(count.to_le_bytes());
+        directory.extend(count.to_le_bytes());
+        directory.extend(size.to_le_bytes());
+        directory.extend(start.to_le_bytes());
+        directory.extend(0u16.to_le_bytes());
+        self.out.write_all(&directory)?;
+        Ok(self.out)
+    }
+}
+
+/// `secs` since the Unix epoch as an MS-DOS time and date, in UTC and
+/// clamped to the 1980–2107 range they can hold.
+fn dos_time(secs: u64) -> (u16, u16) {
+    let days = (secs / 86400) as i64;
+    let date = Date::from_days(days);
+    if date.year() < 1980 {
+        return (0, 1 << 5 | 1);
+    }
+    if date.year() > 2107 {
+        return (23 << 11 | 59 << 5 | 29, 127 << 9 | 12 << 5 | 31);
+    }
+    let sod = secs % 86400;
+    let time = (sod / 3600) << 11 | (sod % 3600 / 60) << 5 | (sod % 60 / 2);
+    let date = ((date.year() - 1980) as u16) << 9 | (date.month() as u16) << 5 | date.day() as u16;
+    (time as u16, date)
+}
+
+/// The entries of a `.tar.gz` or `.zip` written by [`ArchiveOutput`], by
+/// name, for tests to compare with what the model wrote. Where `tar` or
+/// `unzip` is installed, they must extract the same entries.
+#[cfg(test)]
+pub(crate) fn read_entries(path: &Path) -> std::collections::BTreeMap<String, Vec<u8>> {
+    use flate2::read::{DeflateDecoder, GzDecoder};
+    use std::io::Read;
+
+    let data = fs::read(path).unwrap();
+    let mut entries = std::collections::BTreeMap::new();
+    let field = |bytes: &[u8], at: usize, len: usize| {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap()) as usize & ((1 << (8 * len)) - 1)
+    };
+    if path.to_string_lossy().ends_with(".zip") {
+        let mut at = 0;
+        while data[at..at + 4] == 0x04034b50u32.to_le_bytes() {
+            let compressed = field(&data, at + 18, 4);
+            let name_len = field(&data, at + 26, 2);
+            let extra_len = field(&data, at + 28, 2);
+            let name = String::from_utf8(data[at + 30..at + 30 + name_len].to_vec()).unwrap();
+            let start = at + 30 + name_len + extra_len;
+            let mut contents = Vec::new();
+            DeflateDecoder::new(&data[start..start + compressed])
+                .read_to_end(&mut contents)
+                .unwrap();
+            let mut crc = flate2::Crc::new();
+            crc.update(&contents);
+            assert_eq!(crc.sum() as usize, field(&data, at + 14, 4), "{name}");
+            entries.insert(name, contents);
+            at = start + compressed;
+        }
+        assert_eq!(data[at..at + 4], 0x02014b50u32.to_le_bytes());
+    } else {
+        let mut tar = Vec::new();
+        GzDecoder::new(&data[..]).read_to_end(&mut tar).unwrap();
+        let mut at = 0;
+        while tar[at..at + 512].iter().any(|&b| b != 0) {
+            let header = &tar[at..at + 512];
+            let text = |from: usize, len: usize| {
+                let bytes = &header[from..from + len];
+                let end = bytes.iter().position(|&b| b == 0).unwrap_or(len);
+                String::from_utf8(bytes[..end].to_vec()).unwrap()
+            };
+            let size = usize::from_str_radix(text(124, 12).trim(), 8).unwrap();
+            let name = match text(345, 155) {
+                prefix if prefix.is_empty() => text(0, 100),
+                prefix => format!("{prefix}/{}", text(0, 100)),
+            };
+            entries.insert(name, tar[at + 512..at + 512 + size].to_vec());
+            at += 512 + size.div_ceil(512) * 512;
+        }
+    }
+    if let Some(extracted) = extract_with_tools(path) {
+        assert_eq!(extracted, entries, "{}", path.display());
+    }
+    entries
+}
+
+/// The files `tar` or `unzip` extracts from `path`, by name; `None` when
+/// the tool is not installed.
+#[cfg(test)]
+fn extract_with_tools(path: &Path) -> Option<std::collections::BTreeMap<String, Vec<u8>>> {
+    use std::process::Command;
+
+    let dir = tempfile::tempdir().unwrap();
+    let mut command = if path.to_string_lossy().ends_with(".zip") {
+        let mut unzip = Command::new("unzip");
+        unzip.arg("-q").arg(path).arg("-d").arg(dir.path());
+        unzip
+    } else {
+        let mut tar = Command::new("tar");
+        tar.arg("-xzf").arg(path).arg("-C").arg(dir.path());
+        tar
+    };
+    let status = match command.status() {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+        status => status.unwrap(),
+    };
+    assert!(status.success(), "{command:?}: {status}");
+    let files = crate::compare::list_files(dir.path()).unwrap();
+    Some(
+        files
+            .into_iter()
+            .map(|name| {
+                let data = fs::read(dir.path().join(&name)).unwrap();
+                (name, data)
+            })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_archive_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let long = format!("{}/{}.csv", "d".repeat(80), "f".repeat(60));
+        let scratch = Scratch::new(Some(dir.path().join("scratch")), false);
+        for name in ["out.tar.gz", "out.zip"] {
+            let path = dir.path().join(name);
+            let warnings = Rc::new(RefCell::new(Vec::new()));
+            let archive = ArchiveOutput::create(&path, &scratch, warnings.clone()).unwrap();
+            archive.put("a.json", b"{}").unwrap();
+            archive.put(&long, &[b'x'; 700]).unwrap();
+            let mut writer = archive.writer("rows.csv").unwrap();
+            writer.write_all(b"step\n0\n").unwrap();
+            assert!(archive.put("rows.csv", b"").is_err());
+            drop(writer);
+            archive.put("empty.txt", b"").unwrap();
+            assert!(!path.exists());
+            assert_eq!(fs::read_dir(scratch.dir().unwrap()).unwrap().count(), 1);
+            archive.finish().unwrap();
+            assert!(path.is_file());
+            assert_eq!(fs::read_dir(scratch.dir().unwrap()).unwrap().count(), 0);
+            assert!(warnings.borrow().is_empty());
+
+            let entries = read_entries(&path);
+            assert_eq!(entries.len(), 4, "{name}");
+            assert_eq!(entries["a.json"], b"{}");
+            assert_eq!(entries[&long], [b'x'; 700]);
+            assert_eq!(entries["rows.csv"], b"step\n0\n");
+            assert_eq!(entries["empty.txt"], b"");
+            let err = archive.put("late.json", b"{}").unwrap_err();
+            assert!(err.to_string().contains("finalize has closed the archive"));
+        }
+        let err = ArchiveOutput::create(&dir.path().join("out.rar"), &scratch, Rc::default()).err();
+        assert!(err.unwrap().to_string().contains(".tar.gz, .tgz or .zip"));
+    }
+
+    #[test]
+    fn test_zip_past_4_gib_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut zip = ZipEncoder {
+            out: BufWriter::new(File::create(dir.path().join("big.zip")).unwrap()),
+            offset: u32::MAX as u64 + 1,
+            entries: Vec::new(),
+        };
+        let err = zip.add("late.csv", b"x", 0).unwrap_err();
+        assert!(err.to_string().contains("past 4 GiB"), "{err}");
+        assert!(zip.entries.is_empty());
+    }
+
+    #[test]
+    fn test_dos_time() {
+        // 2024-03-05T06:07:08Z
+        assert_eq!(
+            dos_time(1709618828),
+            (6 << 11 | 7 << 5 | 4, 44 << 9 | 3 << 5 | 5)
+        );
+        assert_eq!(dos_time(0), (0, 1 << 5 | 1));
+    }
+}
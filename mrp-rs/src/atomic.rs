@@ -0,0 +1,201 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::rc::{Rc, Weak};
+use std::time::SystemTime;
+
+use crate::compare::list_files;
+use crate::environment::Warning;
+
+const TEMP_MARKER: &str = ".tmp-";
+
+/// Where `path` is written before it is renamed into place:
+/// `<filename>.tmp-<pid>` in the same directory.
+pub(crate) fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!("{TEMP_MARKER}{}", std::process::id()));
+    path.with_file_name(name)
+}
+
+/// Write `data` to `path` through its [`temp_path`], so readers never see
+/// part of it.
+pub(crate) fn write(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = temp_path(path);
+    let result = fs::write(&tmp, data).and_then(|_| fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// Move `from` to `path`: a rename on the same filesystem, else a copy
+/// through `path`'s [`temp_path`], so readers never see part of it.
+pub(crate) fn move_into_place(from: &Path, path: &Path) -> io::Result<()> {
+    if fs::rename(from, path).is_ok() {
+        return Ok(());
+    }
+    let tmp = temp_path(path);
+    let result = fs::copy(from, &tmp).and_then(|_| fs::rename(&tmp, path));
+    match &result {
+        Ok(()) => {
+            let _ = fs::remove_file(from);
+        }
+        Err(_) => {
+            let _ = fs::remove_file(&tmp);
+        }
+    }
+    result
+}
+
+/// A file written to its [`temp_path`] and renamed into place by
+/// [`AtomicFile::commit`], or when dropped if it never was.
+///
+/// A drop while the thread is panicking leaves the temp file where it is
+/// rather than publishing what may be half a file. A failed rename on drop
+/// is recorded as an `atomic_rename_failed` warning; a failed commit is
+/// returned instead.
+pub(crate) struct AtomicFile {
+    pending: Rc<RefCell<Pending>>,
+    warnings: Rc<RefCell<Vec<Warning>>>,
+}
+
+struct Pending {
+    /// `None` once committed.
+    file: Option<File>,
+    tmp: PathBuf,
+    path: PathBuf,
+}
+
+impl Pending {
+    fn commit(&mut self) -> io::Result<()> {
+        let Some(file) = self.file.take() else {
+            return Ok(());
+        };
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&self.tmp, &self.path)
+    }
+}
+
+impl AtomicFile {
+    pub(crate) fn create(path: &Path, warnings: Rc<RefCell<Vec<Warning>>>) -> io::Result<Self> {
+        let tmp = temp_path(path);
+        Ok(AtomicFile {
+            pending: Rc::new(RefCell::new(Pending {
+                file: Some(File::create(&tmp)?),
+                tmp,
+                path: path.to_path_buf(),
+            })),
+            warnings,
+        })
+    }
+
+    /// Sync the temp file and rename it into place. Later writes fail and
+    /// later commits do nothing.
+    pub(crate) fn commit(&mut self) -> io::Result<()> {
+        self.pending.borrow_mut().commit()
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.pending.borrow_mut().file {
+            Some(file) => file.write(buf),
+            None => Err(io::Error::other("write after the file was committed")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.pending.borrow_mut().file {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if self.pending.borrow().file.is_none() || std::thread::panicking() {
+            return;
+        }
+        if let Err(e) = self.commit() {
+            let pending = self.pending.borrow();
+            let warning = Warning::output_failure(
+                "atomic_rename_failed",
+                format!(
+                    "failed to move {} into place, leaving it at {}: {e}",
+                    pending.path.display(),
+                    pending.tmp.display()
+                ),
+            );
+            eprintln!("warning [{}]: {}", warning.code, warning.message);
+            self.warnings.borrow_mut().push(warning);
+        }
+    }
+}
+
+/// The atomic files still open, by output name, so one buried in a writer
+/// chain can be committed when its output is closed.
+#[derive(Default)]
+pub(crate) struct OpenFiles(RefCell<HashMap<String, Vec<Weak<RefCell<Pending>>>>>);
+
+impl OpenFiles {
+    pub(crate) fn register(&self, name: &str, file: &AtomicFile) {
+        let mut open = self.0.borrow_mut();
+        let files = open.entry(name.to_string()).or_default();
+        files.retain(|f| f.strong_count() > 0);
+        files.push(Rc::downgrade(&file.pending));
+    }
+
+    /// [`AtomicFile::commit`] every file open for `name`, returning the
+    /// first failure. Files already dropped were renamed then.
+    pub(crate) fn commit(&self, name: &str) -> io::Result<()> {
+        let files = self.0.borrow_mut().remove(name).unwrap_or_default();
+        let mut result = Ok(());
+        for pending in files.iter().filter_map(Weak::upgrade) {
+            let committed = pending.borrow_mut().commit();
+            if result.is_ok() {
+                result = committed;
+            }
+        }
+        result
+    }
+}
+
+/// Remove temp files under `dir` left by atomic writes that never
+/// finished: those modified before `since` whose writer is no longer
+/// running, where that can be told. Returns the paths removed.
+pub(crate) fn remove_stale(dir: &Path, since: SystemTime) -> Vec<PathBuf> {
+    let Ok(files) = list_files(dir) else {
+        return Vec::new();
+    };
+    let mut removed = Vec::new();
+    for file in files {
+        let Some(pid) = file
+            .rsplit_once(TEMP_MARKER)
+            .and_then(|(_, pid)| pid.parse::<u32>().ok())
+        else {
+            continue;
+        };
+        let path = dir.join(&file);
+        let old = fs::metadata(&path)
+            .and_then(|meta| meta.modified())
+            .is_ok_and(|modified| modified < since);
+        if old && !is_running(pid) && fs::remove_file(&path).is_ok() {
+            removed.push(path);
+        }
+    }
+    removed
+}
+
+#[cfg(target_os = "linux")]
+fn is_running(pid: u32) -> bool {
+    pid == std::process::id() || Path::new("/proc").join(pid.to_string()).exists()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_running(pid: u32) -> bool {
+    pid == std::process::id()
+}
@@ -0,0 +1,109 @@
+use std::path::Path;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value, json};
+
+use crate::MrpError;
+use crate::environment::Environment;
+use crate::rng::RngKind;
+
+/// Builds an [`Environment`] without writing out a payload, e.g. for unit
+/// tests of model code. From [`Environment::builder`].
+///
+/// The setters fill in a payload, which is then read exactly as one from
+/// stdin would be, so a built environment matches a parsed one.
+#[derive(Debug, Clone, Default)]
+pub struct EnvironmentBuilder {
+    input: Map<String, Value>,
+    files: Map<String, Value>,
+    output: Option<Value>,
+    rng: Option<RngKind>,
+}
+
+impl EnvironmentBuilder {
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.input.insert("seed".to_string(), seed.into());
+        self
+    }
+
+    pub fn replicate(mut self, replicate: u64) -> Self {
+        self.input.insert("replicate".to_string(), replicate.into());
+        self
+    }
+
+    /// Set input key `key`. Panics if `value` cannot be written as JSON,
+    /// such as a map with non-string keys.
+    pub fn param(mut self, key: &str, value: impl Serialize) -> Self {
+        let value = serde_json::to_value(value)
+            .unwrap_or_else(|e| panic!("parameter '{key}' is not valid JSON: {e}"));
+        self.input.insert(key.to_string(), value);
+        self
+    }
+
+    /// Add `path` to the model's files as `key`.
+    pub fn file(mut self, key: &str, path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref().to_string_lossy().into_owned();
+        self.files.insert(key.to_string(), path.into());
+        self
+    }
+
+    /// Write outputs to `dir`, replacing any earlier output setting.
+    pub fn output_dir(mut self, dir: impl AsRef<Path>) -> Self {
+        let dir = dir.as_ref().to_string_lossy().into_owned();
+        self.output = Some(json!({ "spec": "filesystem", "dir": dir }));
+        self
+    }
+
+    /// Write outputs to stdout, replacing any earlier output setting.
+    pub fn output_stdout(mut self) -> Self {
+        self.output = Some(json!({ "spec": "stdout" }));
+        self
+    }
+
+    /// Keep outputs in memory, for [`Environment::memory_outputs`],
+    /// replacing any earlier output setting.
+    pub fn output_memory(mut self) -> Self {
+        self.output = Some(json!({ "spec": "memory" }));
+        self
+    }
+
+    /// The algorithm of [`Environment::rng`], as the payload's `"rng"` key.
+    pub fn rng(mut self, kind: RngKind) -> Self {
+        self.rng = Some(kind);
+        self
+    }
+
+    /// The payload the environment is built from.
+    pub fn payload(&self) -> Value {
+        let mut payload = Map::new();
+        payload.insert("input".to_string(), Value::Object(self.input.clone()));
+        if !self.files.is_empty() {
+            payload.insert("model".to_string(), json!({ "files": self.files }));
+        }
+        if let Some(output) = &self.output {
+            payload.insert("output".to_string(), output.clone());
+        }
+        if let Some(kind) = self.rng {
+            payload.insert("rng".to_string(), kind.name().into());
+        }
+        Value::Object(payload)
+    }
+
+    pub fn build(self) -> Environment {
+        self.try_build().unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    pub fn try_build(self) -> Result<Environment, MrpError> {
+        Environment::try_from_json(self.payload())
+    }
+
+    /// Build and deserialize the input as `I`.
+    pub fn build_typed<I: DeserializeOwned>(self) -> Environment<I> {
+        self.try_build_typed().unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    pub fn try_build_typed<I: DeserializeOwned>(self) -> Result<Environment<I>, MrpError> {
+        Ok(self.try_build()?.try_with_input_type()?)
+    }
+}
@@ -0,0 +1,314 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::MrpError;
+use crate::formats::{self, RunStatus};
+use crate::provenance::sha256_hex;
+use crate::runtime::{RunResult, Runtime};
+
+const MANIFEST: &str = "manifest.json";
+const COMPLETE: &str = "complete.json";
+
+/// The run's `"cache"` section.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CacheSpec {
+    dir: PathBuf,
+    #[serde(default = "enabled_by_default")]
+    enabled: bool,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+/// Reuses the outputs of an identical earlier run instead of running the
+/// model again.
+///
+/// Entries live in `<dir>/<key>`, where the key hashes the resolved run
+/// (input, seed, replicate and output options, but not the output dir), the
+/// runtime's [`Runtime::build_info`] and the contents of every `model.files`
+/// input. An entry only counts if its `manifest.json` lists files that are
+/// all present with matching hashes, and its `complete.json` says
+/// `complete`; anything else is a miss. A run without a literal seed (none,
+/// `null` or `"random"`) draws a fresh one each time, so it always runs the
+/// model and is never cached.
+pub struct RunCache {
+    dir: PathBuf,
+    output_dir: PathBuf,
+}
+
+impl RunCache {
+    /// The cache for `run_json`, or `None` if it has no enabled `"cache"`.
+    pub fn from_run(run_json: &Value) -> Result<Option<Self>, MrpError> {
+        let Some(spec) = run_json.get("cache") else {
+            return Ok(None);
+        };
+        let spec: CacheSpec = serde_json::from_value(spec.clone())
+            .map_err(|e| MrpError::Config(format!("invalid cache: {e}")))?;
+        if !spec.enabled {
+            return Ok(None);
+        }
+        let output = run_json.get("output");
+        let output_dir = output
+            .filter(|o| o.get("spec").and_then(|v| v.as_str()) == Some("filesystem"))
+            .and_then(|o| o.get("dir"))
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| {
+                MrpError::Config("cache requires a filesystem output with a dir".to_string())
+            })?;
+        Ok(Some(RunCache {
+            dir: spec.dir,
+            output_dir: PathBuf::from(output_dir),
+        }))
+    }
+
+    /// Hash identifying the run's outputs.
+    pub fn key(&self, run_json: &Value, runtime: &dyn Runtime) -> Result<String, MrpError> {
+        let mut run = run_json.clone();
+        let files = run
+            .get_mut("model")
+            .and_then(|m| m.as_object_mut())
+            .and_then(|m| m.remove("files"));
+        if let Some(obj) = run.as_object_mut() {
+            // Where the run writes, and how it is cached, do not change it
+            obj.remove("cache");
+            obj.remove("mrp");
+            if let Some(output) = obj.get_mut("output").and_then(|o| o.as_object_mut()) {
+                output.remove("dir");
+            }
+        }
+        let mut file_hashes = serde_json::Map::new();
+        for (name, path) in files
+            .as_ref()
+            .and_then(|f| f.as_object())
+            .into_iter()
+            .flatten()
+        {
+            let path = path.as_str().unwrap_or_default();
+            let data = fs::read(path)
+                .map_err(|e| MrpError::FileNotFound(format!("'{name}' ({path}): {e}")))?;
+            file_hashes.insert(name.clone(), sha256_hex(&data).into());
+        }
+        let keyed = serde_json::json!({
+            "run": run,
+            "build": runtime.build_info(),
+            "files": file_hashes,
+        });
+        let canonical =
+            serde_json::to_vec(&keyed).map_err(|e| MrpError::Serialization(e.to_string()))?;
+        Ok(sha256_hex(&canonical))
+    }
+
+    /// Copy a cached run into the output dir, or run the model and cache
+    /// its outputs. Caching needs a manifest, so the model is run with
+    /// `"manifest": true`.
+    pub fn run(&self, run_json: &Value, runtime: &dyn Runtime) -> Result<RunResult, MrpError> {
+        if !has_literal_seed(run_json) {
+            return runtime.run(run_json);
+        }
+        let key = self.key(run_json, runtime)?;
+        let entry = self.dir.join(&key);
+        if is_valid(&entry) {
+            restore(&entry, &self.output_dir)?;
+            return Ok(RunResult {
+                exit_code: 0,
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+            });
+        }
+        let mut run_json = run_json.clone();
+        run_json["manifest"] = Value::Bool(true);
+        let result = runtime.run(&run_json)?;
+        if result.ok() && is_valid(&self.output_dir) {
+            self.store(&key, &entry)?;
+        }
+        Ok(result)
+    }
+
+    /// Copy the output dir into the cache under a temporary name, then
+    /// rename it into place so readers never see a partial entry.
+    fn store(&self, key: &str, entry: &Path) -> Result<(), MrpError> {
+        let staging = self.dir.join(format!(".{key}.{}.tmp", std::process::id()));
+        let _ = fs::remove_dir_all(&staging);
+        copy_dir(&self.output_dir, &staging)?;
+        if entry.exists() {
+            // A stale or corrupt entry under the same key
+            let _ = fs::remove_dir_all(entry);
+        }
+        if fs::rename(&staging, entry).is_err() {
+            // Another run cached the same key first
+            let _ = fs::remove_dir_all(&staging);
+        }
+        Ok(())
+    }
+}
+
+/// Whether the run's input gives its seed, rather than leaving the
+/// Environment to draw one from OS entropy.
+fn has_literal_seed(run_json: &Value) -> bool {
+    run_json
+        .get("input")
+        .and_then(|input| input.get("seed"))
+        .is_some_and(|seed| !seed.is_null() && seed.as_str() != Some("random"))
+}
+
+/// Whether `dir` holds a complete run whose files match its manifest.
+fn is_valid(dir: &Path) -> bool {
+    let (Ok(manifest), Ok(complete)) = (
+        formats::read_manifest(&dir.join(MANIFEST)),
+        formats::read_complete(&dir.join(COMPLETE)),
+    ) else {
+        return false;
+    };
+    complete.status == RunStatus::Complete
+        && manifest.files.iter().all(|file| {
+            fs::read(dir.join(&file.name)).is_ok_and(|data| sha256_hex(&data) == file.sha256)
+        })
+}
+
+/// Copy a cache entry to the output dir and mark it `"cache_hit": true`.
+fn restore(entry: &Path, output_dir: &Path) -> Result<(), MrpError> {
+    copy_dir(entry, output_dir)?;
+    let path = output_dir.join(COMPLETE);
+    let mut complete = formats::read_complete(&path)?;
+    complete.cache_hit = Some(true);
+    let json =
+        serde_json::to_vec_pretty(&complete).map_err(|e| MrpError::Serialization(e.to_string()))?;
+    fs::write(&path, json).map_err(|e| MrpError::Output(format!("failed to write {COMPLETE}: {e}")))
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<(), MrpError> {
+    let err = |e: std::io::Error| {
+        MrpError::Output(format!(
+            "failed to copy '{}' to '{}': {e}",
+            from.display(),
+            to.display()
+        ))
+    };
+    fs::create_dir_all(to).map_err(err)?;
+    for item in fs::read_dir(from).map_err(err)? {
+        let item = item.map_err(err)?;
+        let target = to.join(item.file_name());
+        if item.file_type().map_err(err)?.is_dir() {
+            copy_dir(&item.path(), &target)?;
+        } else {
+            fs::copy(item.path(), target).map_err(err)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::Environment;
+
+    /// Runs a tiny model in-process, counting how often it runs.
+    struct CountingRuntime {
+        runs: Cell<u32>,
+    }
+
+    impl Runtime for CountingRuntime {
+        fn run(&self, run_json: &Value) -> Result<RunResult, MrpError> {
+            self.runs.set(self.runs.get() + 1);
+            let mut env = Environment::try_from_json(run_json.clone())?;
+            let r0 = run_json["input"]["r0"].as_f64().unwrap();
+            env.write_csv("out.csv", &["r0"], &[vec![r0.to_string()]]);
+            env.finalize()?;
+            Ok(RunResult {
+                exit_code: 0,
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+            })
+        }
+    }
+
+    fn run_json(cache: &Path, out: &Path, r0: f64) -> Value {
+        serde_json::json!({
+            "input": { "r0": r0, "seed": 1 },
+            "output": { "spec": "filesystem", "dir": out.to_str().unwrap() },
+            "cache": { "dir": cache.to_str().unwrap(), "enabled": true },
+        })
+    }
+
+    fn run(runtime: &CountingRuntime, run_json: &Value) -> Value {
+        let cache = RunCache::from_run(run_json).unwrap().unwrap();
+        assert!(cache.run(run_json, runtime).unwrap().ok());
+        let out = Path::new(run_json["output"]["dir"].as_str().unwrap());
+        serde_json::from_slice(&fs::read(out.join(COMPLETE)).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_identical_runs_hit() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir.path().join("cache");
+        let runtime = CountingRuntime { runs: Cell::new(0) };
+
+        let first = run(&runtime, &run_json(&cache, &dir.path().join("a"), 2.0));
+        assert_eq!(first.get("cache_hit"), None);
+        let second = run(&runtime, &run_json(&cache, &dir.path().join("b"), 2.0));
+        assert_eq!(second["cache_hit"], true);
+        assert_eq!(runtime.runs.get(), 1);
+        assert_eq!(
+            fs::read_to_string(dir.path().join("b/out.csv")).unwrap(),
+            fs::read_to_string(dir.path().join("a/out.csv")).unwrap()
+        );
+
+        let perturbed = run(&runtime, &run_json(&cache, &dir.path().join("c"), 2.5));
+        assert_eq!(perturbed.get("cache_hit"), None);
+        assert_eq!(runtime.runs.get(), 2);
+        assert_eq!(
+            fs::read_to_string(dir.path().join("c/out.csv")).unwrap(),
+            "r0\n2.5\n"
+        );
+    }
+
+    #[test]
+    fn test_seedless_runs_are_not_cached() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir.path().join("cache");
+        let runtime = CountingRuntime { runs: Cell::new(0) };
+        for (i, seed) in [None, None, Some(Value::Null), Some("random".into())]
+            .into_iter()
+            .enumerate()
+        {
+            let mut payload = run_json(&cache, &dir.path().join(i.to_string()), 2.0);
+            let input = payload["input"].as_object_mut().unwrap();
+            match seed {
+                Some(seed) => input.insert("seed".to_string(), seed),
+                None => input.remove("seed"),
+            };
+            let cache = RunCache::from_run(&payload).unwrap().unwrap();
+            assert!(cache.run(&payload, &runtime).unwrap().ok());
+            assert_eq!(runtime.runs.get(), i as u32 + 1);
+        }
+        assert!(!cache.exists());
+    }
+
+    #[test]
+    fn test_partial_entry_is_a_miss() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir.path().join("cache");
+        let runtime = CountingRuntime { runs: Cell::new(0) };
+        let payload = run_json(&cache, &dir.path().join("a"), 2.0);
+        run(&runtime, &payload);
+
+        let key = RunCache::from_run(&payload)
+            .unwrap()
+            .unwrap()
+            .key(&payload, &runtime)
+            .unwrap();
+        fs::remove_file(cache.join(&key).join(MANIFEST)).unwrap();
+        let again = run(&runtime, &run_json(&cache, &dir.path().join("b"), 2.0));
+        assert_eq!(again.get("cache_hit"), None);
+        assert_eq!(runtime.runs.get(), 2);
+        // The rerun replaced the partial entry
+        assert!(is_valid(&cache.join(&key)));
+    }
+}
@@ -0,0 +1,538 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::MrpError;
+
+/// A proleptic Gregorian calendar date.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Date {
+    year: i32,
+    month: u8,
+    day: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum Weekday {
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+    Sunday,
+}
+
+impl Weekday {
+    /// Days since Monday (Monday = 0, Sunday = 6).
+    pub fn days_from_monday(self) -> i64 {
+        self as i64
+    }
+
+    fn from_days_from_monday(n: i64) -> Self {
+        match n.rem_euclid(7) {
+            0 => Weekday::Monday,
+            1 => Weekday::Tuesday,
+            2 => Weekday::Wednesday,
+            3 => Weekday::Thursday,
+            4 => Weekday::Friday,
+            5 => Weekday::Saturday,
+            _ => Weekday::Sunday,
+        }
+    }
+}
+
+impl Date {
+    pub fn new(year: i32, month: u8, day: u8) -> Result<Self, MrpError> {
+        if !(1..=12).contains(&month) || day == 0 || day > days_in_month(year, month) {
+            return Err(MrpError::Input(format!(
+                "invalid date: {year:04}-{month:02}-{day:02}"
+            )));
+        }
+        Ok(Date { year, month, day })
+    }
+
+    /// Parse an ISO-8601 calendar date (`YYYY-MM-DD`).
+    pub fn parse(s: &str) -> Result<Self, MrpError> {
+        let invalid = || MrpError::Input(format!("invalid ISO date: {s:?}"));
+        let mut parts = s.trim().splitn(3, '-');
+        let (y, m, d) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(y), Some(m), Some(d)) => (y, m, d),
+            _ => return Err(invalid()),
+        };
+        if y.len() != 4 || m.len() != 2 || d.len() != 2 {
+            return Err(invalid());
+        }
+        let year = y.parse().map_err(|_| invalid())?;
+        let month = m.parse().map_err(|_| invalid())?;
+        let day = d.parse().map_err(|_| invalid())?;
+        Date::new(year, month, day)
+    }
+
+    pub fn year(&self) -> i32 {
+        self.year
+    }
+
+    pub fn month(&self) -> u8 {
+        self.month
+    }
+
+    pub fn day(&self) -> u8 {
+        self.day
+    }
+
+    /// Days since 1970-01-01.
+    pub fn to_days(&self) -> i64 {
+        // Howard Hinnant's days_from_civil
+        let y = self.year as i64 - if self.month <= 2 { 1 } else { 0 };
+        let era = y.div_euclid(400);
+        let yoe = y - era * 400;
+        let m = self.month as i64;
+        let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + self.day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        era * 146097 + doe - 719468
+    }
+
+    /// Inverse of [`Date::to_days`].
+    pub fn from_days(days: i64) -> Self {
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+        let year = (yoe + era * 400 + if month <= 2 { 1 } else { 0 }) as i32;
+        Date { year, month, day }
+    }
+
+    pub fn add_days(&self, n: i64) -> Self {
+        Date::from_days(self.to_days() + n)
+    }
+
+    pub fn weekday(&self) -> Weekday {
+        // 1970-01-01 was a Thursday
+        Weekday::from_days_from_monday(self.to_days() + 3)
+    }
+
+    /// The MMWR epidemiological week containing this date.
+    ///
+    /// MMWR weeks run Sunday through Saturday, and week 1 of a year is the
+    /// first such week with at least four days in that year, so a few days
+    /// around January 1 may belong to week 52/53 of the previous year or week
+    /// 1 of the next.
+    pub fn mmwr_week(&self) -> MmwrWeek {
+        let days = self.to_days();
+        let mut year = self.year;
+        if days >= mmwr_year_start(year + 1) {
+            year += 1;
+        } else if days < mmwr_year_start(year) {
+            year -= 1;
+        }
+        let week = ((days - mmwr_year_start(year)) / 7 + 1) as u8;
+        MmwrWeek { year, week }
+    }
+}
+
+/// An MMWR (CDC epidemiological) year and week number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MmwrWeek {
+    pub year: i32,
+    pub week: u8,
+}
+
+/// Days since the epoch of the Sunday starting MMWR week 1 of `year`.
+fn mmwr_year_start(year: i32) -> i64 {
+    // Week 1 is the Sunday-start week containing January 4
+    let jan4 = Date {
+        year,
+        month: 1,
+        day: 4,
+    };
+    let since_sunday = (jan4.weekday().days_from_monday() + 1) % 7;
+    jan4.to_days() - since_sunday
+}
+
+pub fn is_leap_year(year: i32) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+fn days_in_month(year: i32, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+impl FromStr for Date {
+    type Err = MrpError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Date::parse(s)
+    }
+}
+
+impl Serialize for Date {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Date {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Date::parse(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+/// A whole number of days, such as a forecast horizon. Deserializes from
+/// an integer or a string like `"28 days"` or `"4 weeks"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Days(pub u32);
+
+impl Days {
+    /// Parse `<n> day(s)` or `<n> week(s)`, or a bare number of days.
+    pub fn parse(s: &str) -> Result<Self, MrpError> {
+        let invalid = || {
+            MrpError::Input(format!(
+                "invalid duration: {s:?} (expected e.g. \"28 days\" or \"4 weeks\")"
+            ))
+        };
+        let mut parts = s.split_whitespace();
+        let n: u32 = parts
+            .next()
+            .and_then(|n| n.parse().ok())
+            .ok_or_else(invalid)?;
+        let per_unit = match parts.next() {
+            None | Some("day" | "days") => 1,
+            Some("week" | "weeks") => 7,
+            Some(_) => return Err(invalid()),
+        };
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+        n.checked_mul(per_unit).map(Days).ok_or_else(invalid)
+    }
+}
+
+impl fmt::Display for Days {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} days", self.0)
+    }
+}
+
+impl FromStr for Days {
+    type Err = MrpError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Days::parse(s)
+    }
+}
+
+impl Serialize for Days {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_u32(self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Days {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Count(u32),
+            Text(String),
+        }
+        match Raw::deserialize(deserializer) {
+            Ok(Raw::Count(n)) => Ok(Days(n)),
+            Ok(Raw::Text(s)) => Days::parse(&s).map_err(serde::de::Error::custom),
+            Err(_) => Err(serde::de::Error::custom(
+                "expected a number of days or a string like \"28 days\"",
+            )),
+        }
+    }
+}
+
+/// Format a timestamp as RFC 3339 in UTC with millisecond precision,
+/// e.g. `2024-01-02T03:04:05.678Z`.
+pub fn rfc3339_utc(t: SystemTime) -> String {
+    let (secs, millis) = match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => (d.as_secs() as i64, d.subsec_millis() as i64),
+        Err(e) => {
+            let d = e.duration();
+            let total = -(d.as_millis() as i64);
+            (total.div_euclid(1000), total.rem_euclid(1000))
+        }
+    };
+    let date = Date::from_days(secs.div_euclid(86400));
+    let sod = secs.rem_euclid(86400);
+    format!(
+        "{date}T{:02}:{:02}:{:02}.{millis:03}Z",
+        sod / 3600,
+        sod % 3600 / 60,
+        sod % 60
+    )
+}
+
+/// An inclusive range of dates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DateRange {
+    pub start: Date,
+    pub end: Date,
+}
+
+impl DateRange {
+    pub fn new(start: Date, end: Date) -> Self {
+        DateRange { start, end }
+    }
+
+    pub fn contains(&self, date: Date) -> bool {
+        self.start <= date && date <= self.end
+    }
+}
+
+/// Maps simulation steps onto calendar dates.
+///
+/// Step `0` falls on `start`, and each step advances `step_days` days.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Calendar {
+    start: Date,
+    step_days: u32,
+}
+
+impl Calendar {
+    /// # Panics
+    ///
+    /// If `step_days` is 0; [`Calendar::try_new`] returns an error instead.
+    pub fn new(start: Date, step_days: u32) -> Self {
+        Self::try_new(start, step_days).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    pub fn try_new(start: Date, step_days: u32) -> Result<Self, MrpError> {
+        if step_days == 0 {
+            return Err(MrpError::Input(
+                "calendar step_days must be positive".to_string(),
+            ));
+        }
+        Ok(Calendar { start, step_days })
+    }
+
+    pub fn start(&self) -> Date {
+        self.start
+    }
+
+    pub fn step_days(&self) -> u32 {
+        self.step_days
+    }
+
+    pub fn date_for(&self, step: usize) -> Date {
+        self.start.add_days(step as i64 * self.step_days as i64)
+    }
+
+    /// The step whose span contains `date`, or `None` if it precedes the start.
+    pub fn step_for(&self, date: Date) -> Option<usize> {
+        let offset = date.to_days() - self.start.to_days();
+        if offset < 0 {
+            return None;
+        }
+        Some((offset / self.step_days as i64) as usize)
+    }
+
+    pub fn weekday(&self, step: usize) -> Weekday {
+        self.date_for(step).weekday()
+    }
+
+    /// Last day of the week containing `step`, for weeks beginning on `week_start`.
+    pub fn week_ending(&self, step: usize, week_start: Weekday) -> Date {
+        let date = self.date_for(step);
+        let into_week =
+            (date.weekday().days_from_monday() - week_start.days_from_monday()).rem_euclid(7);
+        date.add_days(6 - into_week)
+    }
+
+    pub fn mmwr_week(&self, step: usize) -> MmwrWeek {
+        self.date_for(step).mmwr_week()
+    }
+
+    /// One flag per step, set when the step's date falls within any of `ranges`.
+    pub fn mask_for_dates(&self, ranges: &[DateRange], n_steps: usize) -> Vec<bool> {
+        (0..n_steps)
+            .map(|step| {
+                let date = self.date_for(step);
+                ranges.iter().any(|r| r.contains(date))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn d(s: &str) -> Date {
+        Date::parse(s).unwrap()
+    }
+
+    #[test]
+    fn test_parse_and_display() {
+        assert_eq!(d("2024-02-29").to_string(), "2024-02-29");
+        assert!(Date::parse("2023-02-29").is_err());
+        assert!(Date::parse("1900-02-29").is_err());
+        assert!(Date::parse("2000-02-29").is_ok());
+        assert!(Date::parse("2024-13-01").is_err());
+        assert!(Date::parse("2024-1-01").is_err());
+        assert!(Date::parse("not a date").is_err());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(Days::parse("28 days").unwrap(), Days(28));
+        assert_eq!(Days::parse("1 day").unwrap(), Days(1));
+        assert_eq!(Days::parse(" 4 weeks ").unwrap(), Days(28));
+        assert_eq!(Days::parse("7").unwrap(), Days(7));
+        for bad in ["", "days", "-1 days", "2 months", "1.5 weeks", "3 days ago"] {
+            let err = Days::parse(bad).unwrap_err().to_string();
+            assert!(err.contains(&format!("{bad:?}")), "{err}");
+        }
+        let days: Vec<Days> = serde_json::from_str(r#"[14, "2 weeks"]"#).unwrap();
+        assert_eq!(days, vec![Days(14), Days(14)]);
+        assert!(serde_json::from_str::<Days>("-3").is_err());
+        assert_eq!(serde_json::to_string(&Days(14)).unwrap(), "14");
+    }
+
+    #[test]
+    fn test_days_round_trip() {
+        assert_eq!(d("1970-01-01").to_days(), 0);
+        assert_eq!(d("2000-03-01").to_days(), 11017);
+        for days in -800_000..800_000 {
+            if days % 997 == 0 {
+                assert_eq!(Date::from_days(days).to_days(), days);
+            }
+        }
+    }
+
+    #[test]
+    fn test_leap_year_steps() {
+        let cal = Calendar::new(d("2024-02-27"), 1);
+        assert_eq!(cal.date_for(2), d("2024-02-29"));
+        assert_eq!(cal.date_for(3), d("2024-03-01"));
+        let cal = Calendar::new(d("2023-02-27"), 1);
+        assert_eq!(cal.date_for(2), d("2023-03-01"));
+        let cal = Calendar::new(d("2023-12-31"), 1);
+        assert_eq!(cal.date_for(366), d("2024-12-31"));
+    }
+
+    #[test]
+    fn test_date_step_round_trip() {
+        let cal = Calendar::new(d("2023-12-25"), 1);
+        for step in 0..1000 {
+            assert_eq!(cal.step_for(cal.date_for(step)), Some(step));
+        }
+        assert_eq!(cal.step_for(d("2023-12-24")), None);
+
+        let weekly = Calendar::new(d("2024-01-07"), 7);
+        assert!(Calendar::try_new(d("2024-01-07"), 0).is_err());
+        assert_eq!(weekly.date_for(1), d("2024-01-14"));
+        assert_eq!(weekly.step_for(d("2024-01-13")), Some(0));
+        assert_eq!(weekly.step_for(d("2024-01-14")), Some(1));
+    }
+
+    #[test]
+    fn test_weekday() {
+        assert_eq!(d("1970-01-01").weekday(), Weekday::Thursday);
+        assert_eq!(d("2024-01-01").weekday(), Weekday::Monday);
+        assert_eq!(d("2024-02-29").weekday(), Weekday::Thursday);
+        let cal = Calendar::new(d("2024-01-06"), 1);
+        assert_eq!(cal.weekday(0), Weekday::Saturday);
+        assert_eq!(cal.weekday(1), Weekday::Sunday);
+    }
+
+    #[test]
+    fn test_week_ending() {
+        // 2024-01-03 is a Wednesday
+        let cal = Calendar::new(d("2024-01-03"), 1);
+        assert_eq!(cal.week_ending(0, Weekday::Sunday), d("2024-01-06"));
+        assert_eq!(cal.week_ending(0, Weekday::Monday), d("2024-01-07"));
+        assert_eq!(cal.week_ending(0, Weekday::Wednesday), d("2024-01-09"));
+        // Week boundaries: Saturday ends a Sunday-start week, Sunday starts the next
+        assert_eq!(cal.week_ending(3, Weekday::Sunday), d("2024-01-06"));
+        assert_eq!(cal.week_ending(4, Weekday::Sunday), d("2024-01-13"));
+        // Weeks spanning a year boundary
+        let cal = Calendar::new(d("2024-12-30"), 1);
+        assert_eq!(cal.week_ending(0, Weekday::Sunday), d("2025-01-04"));
+    }
+
+    #[test]
+    fn test_mmwr_week() {
+        let w = |s: &str| {
+            let w = d(s).mmwr_week();
+            (w.year, w.week)
+        };
+        // Ordinary year starts
+        assert_eq!(w("2023-01-01"), (2023, 1));
+        assert_eq!(w("2023-12-30"), (2023, 52));
+        assert_eq!(w("2023-12-31"), (2024, 1));
+        // 2014 and 2020 have 53 weeks
+        assert_eq!(w("2015-01-03"), (2014, 53));
+        assert_eq!(w("2015-01-04"), (2015, 1));
+        assert_eq!(w("2019-12-29"), (2020, 1));
+        assert_eq!(w("2021-01-01"), (2020, 53));
+        assert_eq!(w("2021-01-02"), (2020, 53));
+        assert_eq!(w("2021-01-03"), (2021, 1));
+        // A year starting on Saturday leaves January 1 in the previous year
+        assert_eq!(w("2022-01-01"), (2021, 52));
+        assert_eq!(w("2024-12-28"), (2024, 52));
+        assert_eq!(w("2024-12-29"), (2025, 1));
+        assert_eq!(w("2025-01-04"), (2025, 1));
+    }
+
+    #[test]
+    fn test_rfc3339_utc() {
+        use std::time::Duration;
+        assert_eq!(rfc3339_utc(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        let t = UNIX_EPOCH + Duration::from_millis(1_709_164_800_123);
+        assert_eq!(rfc3339_utc(t), "2024-02-29T00:00:00.123Z");
+        let t = UNIX_EPOCH - Duration::from_millis(1);
+        assert_eq!(rfc3339_utc(t), "1969-12-31T23:59:59.999Z");
+    }
+
+    #[test]
+    fn test_mask_for_dates() {
+        let cal = Calendar::new(d("2024-12-23"), 1);
+        let holidays = [
+            DateRange::new(d("2024-12-25"), d("2024-12-25")),
+            DateRange::new(d("2024-12-31"), d("2025-01-01")),
+        ];
+        let mask = cal.mask_for_dates(&holidays, 11);
+        let expected: Vec<bool> = [0, 0, 1, 0, 0, 0, 0, 0, 1, 1, 0]
+            .iter()
+            .map(|&b| b == 1)
+            .collect();
+        assert_eq!(mask, expected);
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let range: DateRange =
+            serde_json::from_value(serde_json::json!({"start": "2024-01-01", "end": "2024-01-07"}))
+                .unwrap();
+        assert_eq!(range.start, d("2024-01-01"));
+        let bad: Result<Date, _> = serde_json::from_value(serde_json::json!("2024-02-30"));
+        assert!(bad.is_err());
+        let wd: Weekday = serde_json::from_value(serde_json::json!("sunday")).unwrap();
+        assert_eq!(wd, Weekday::Sunday);
+    }
+}
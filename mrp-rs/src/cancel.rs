@@ -0,0 +1,122 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+/// A shared request to stop a run early.
+///
+/// Clones share one state and can be sent to other threads. The first
+/// [`CancelToken::cancel`] wins; its reason is kept. A token built from a
+/// payload also trips itself once `"max_run_seconds"` have passed, or on
+/// SIGTERM with `"cancel_on_sigterm": true`.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    reason: OnceLock<String>,
+    deadline: Option<(Instant, Duration)>,
+    sigterm: bool,
+}
+
+/// Set by the SIGTERM handler; read by every token watching for it.
+static SIGTERM: AtomicBool = AtomicBool::new(false);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A token that trips after `max_run` and, with `sigterm`, on SIGTERM.
+    pub(crate) fn watching(max_run: Option<Duration>, sigterm: bool) -> Self {
+        if sigterm {
+            install_sigterm_handler();
+        }
+        CancelToken {
+            inner: Arc::new(Inner {
+                reason: OnceLock::new(),
+                deadline: max_run.map(|d| (Instant::now() + d, d)),
+                sigterm,
+            }),
+        }
+    }
+
+    /// Ask the run to stop; later reasons are ignored.
+    pub fn cancel(&self, reason: &str) {
+        let _ = self.inner.reason.set(reason.to_string());
+    }
+
+    /// Whether the run has been asked to stop. Cheap enough to call once
+    /// per step.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled_reason().is_some()
+    }
+
+    /// Why the run was asked to stop, if it was.
+    pub fn cancelled_reason(&self) -> Option<String> {
+        let inner = &self.inner;
+        if inner.reason.get().is_none() {
+            if let Some((deadline, max_run)) = inner.deadline
+                && Instant::now() >= deadline
+            {
+                self.cancel(&format!(
+                    "exceeded max_run_seconds ({})",
+                    max_run.as_secs_f64()
+                ));
+            } else if inner.sigterm && SIGTERM.load(Ordering::Relaxed) {
+                self.cancel("received SIGTERM");
+            }
+        }
+        inner.reason.get().cloned()
+    }
+
+    /// The reason, without checking the deadline or signal, so a run that
+    /// finished without noticing them still counts as complete.
+    pub(crate) fn tripped(&self) -> Option<String> {
+        self.inner.reason.get().cloned()
+    }
+}
+
+#[cfg(unix)]
+fn install_sigterm_handler() {
+    extern "C" fn handle(_: libc::c_int) {
+        SIGTERM.store(true, Ordering::Relaxed);
+    }
+    static INSTALL: std::sync::Once = std::sync::Once::new();
+    INSTALL.call_once(|| unsafe {
+        libc::signal(libc::SIGTERM, handle as *const () as libc::sighandler_t);
+    });
+}
+
+#[cfg(not(unix))]
+fn install_sigterm_handler() {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_reason_wins_across_threads() {
+        let token = CancelToken::new();
+        assert!(!token.is_cancelled());
+        let remote = token.clone();
+        std::thread::spawn(move || remote.cancel("client went away"))
+            .join()
+            .unwrap();
+        token.cancel("later");
+        assert!(token.is_cancelled());
+        assert_eq!(token.cancelled_reason().unwrap(), "client went away");
+    }
+
+    #[test]
+    fn test_deadline_trips() {
+        let token = CancelToken::watching(Some(Duration::ZERO), false);
+        assert_eq!(
+            token.cancelled_reason().unwrap(),
+            "exceeded max_run_seconds (0)"
+        );
+        let token = CancelToken::watching(Some(Duration::from_secs(3600)), false);
+        assert!(!token.is_cancelled());
+    }
+}
@@ -0,0 +1,153 @@
+use serde::Serialize;
+
+use crate::MrpError;
+
+/// A stable error code, as returned by [`MrpError::code`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ErrorCode {
+    pub code: &'static str,
+    /// The matching [`MrpError::kind`].
+    pub kind: &'static str,
+    pub description: &'static str,
+    /// Whether running again unchanged may succeed.
+    pub retryable: bool,
+}
+
+/// Every error code, one per [`MrpError`] variant. Codes are never reused or
+/// renumbered; a retired variant keeps its entry.
+pub const CATALOG: [ErrorCode; 8] = [
+    ErrorCode {
+        code: "MRP-CONFIG-001",
+        kind: "config",
+        description: "a config file, profile or output spec is invalid",
+        retryable: false,
+    },
+    ErrorCode {
+        code: "MRP-IO-001",
+        kind: "file_not_found",
+        description: "a config or input file does not exist",
+        retryable: false,
+    },
+    ErrorCode {
+        code: "MRP-INPUT-001",
+        kind: "input",
+        description: "the payload or model input is invalid",
+        retryable: false,
+    },
+    ErrorCode {
+        code: "MRP-IO-002",
+        kind: "staging",
+        description: "input files could not be staged",
+        retryable: true,
+    },
+    ErrorCode {
+        code: "MRP-RUNTIME-001",
+        kind: "runtime",
+        description: "the model process could not be run or failed",
+        retryable: false,
+    },
+    ErrorCode {
+        code: "MRP-IO-003",
+        kind: "output",
+        description: "an output could not be written or uploaded",
+        retryable: true,
+    },
+    ErrorCode {
+        code: "MRP-SER-001",
+        kind: "serialization",
+        description: "a value could not be serialized",
+        retryable: false,
+    },
+    ErrorCode {
+        code: "MRP-IO-004",
+        kind: "stalled",
+        description: "the consumer of streamed output stopped reading",
+        retryable: true,
+    },
+];
+
+/// All error codes with descriptions and retryability, so tooling can check
+/// its handling is exhaustive.
+pub fn error_catalog() -> &'static [ErrorCode] {
+    &CATALOG
+}
+
+impl MrpError {
+    /// Stable public code of the error, e.g. `MRP-INPUT-001`.
+    pub fn code(&self) -> &'static str {
+        self.entry().code
+    }
+
+    /// Whether running again unchanged may succeed.
+    pub fn retryable(&self) -> bool {
+        self.entry().retryable
+    }
+
+    fn entry(&self) -> &'static ErrorCode {
+        let kind = self.kind();
+        CATALOG
+            .iter()
+            .find(|e| e.kind == kind)
+            .unwrap_or_else(|| panic!("no error catalog entry for kind '{kind}'"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    /// One error of every variant; the match fails to compile when a variant
+    /// is added, so it must be listed here too.
+    fn every_variant() -> Vec<MrpError> {
+        let all = vec![
+            MrpError::Config(String::new()),
+            MrpError::FileNotFound(String::new()),
+            MrpError::Input(String::new()),
+            MrpError::Staging(String::new()),
+            MrpError::Runtime(String::new()),
+            MrpError::Output(String::new()),
+            MrpError::Serialization(String::new()),
+            MrpError::Stalled(String::new()),
+        ];
+        for e in &all {
+            match e {
+                MrpError::Config(_)
+                | MrpError::FileNotFound(_)
+                | MrpError::Input(_)
+                | MrpError::Staging(_)
+                | MrpError::Runtime(_)
+                | MrpError::Output(_)
+                | MrpError::Serialization(_)
+                | MrpError::Stalled(_) => {}
+            }
+        }
+        all
+    }
+
+    #[test]
+    fn test_catalog_is_complete() {
+        let variants = every_variant();
+        let kinds: BTreeSet<&str> = variants.iter().map(|e| e.kind()).collect();
+        let codes: BTreeSet<&str> = variants.iter().map(|e| e.code()).collect();
+        assert_eq!(kinds.len(), variants.len());
+        assert_eq!(codes.len(), variants.len());
+        assert_eq!(error_catalog().len(), variants.len());
+        for entry in error_catalog() {
+            assert!(kinds.contains(entry.kind), "{}", entry.code);
+        }
+    }
+
+    #[test]
+    fn test_codes_are_stable() {
+        let invalid = crate::Environment::try_from_json(serde_json::json!({
+            "output": { "spec": "filesystem", "on_write_failure": "maybe" }
+        }));
+        assert_eq!(invalid.err().unwrap().code(), "MRP-CONFIG-001");
+        let input = MrpError::Input("bad r0".to_string());
+        assert_eq!(input.code(), "MRP-INPUT-001");
+        assert!(!input.retryable());
+        assert_eq!(MrpError::Stalled(String::new()).code(), "MRP-IO-004");
+    }
+}
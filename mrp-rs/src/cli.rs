@@ -0,0 +1,66 @@
+use crate::MrpError;
+
+/// `--set key=value` pairs from the process's arguments, for
+/// [`crate::Environment::apply_overrides`]. Other arguments are left to the
+/// model.
+pub fn parse_set_args() -> Result<Vec<(String, String)>, MrpError> {
+    parse_set_args_from(std::env::args().skip(1))
+}
+
+/// `--set key=value` or `--set=key=value` pairs from `args`, in order.
+pub fn parse_set_args_from<S: Into<String>>(
+    args: impl IntoIterator<Item = S>,
+) -> Result<Vec<(String, String)>, MrpError> {
+    let mut args = args.into_iter().map(Into::into);
+    let mut pairs = Vec::new();
+    while let Some(arg) = args.next() {
+        let assignment = match arg.strip_prefix("--set") {
+            Some("") => args
+                .next()
+                .ok_or_else(|| MrpError::Config("--set requires key=value".to_string()))?,
+            Some(rest) => match rest.strip_prefix('=') {
+                Some(assignment) => assignment.to_string(),
+                None => continue,
+            },
+            None => continue,
+        };
+        let (key, value) = assignment
+            .split_once('=')
+            .filter(|(key, _)| !key.is_empty())
+            .ok_or_else(|| {
+                MrpError::Config(format!("--set expects key=value, got '{assignment}'"))
+            })?;
+        pairs.push((key.to_string(), value.to_string()));
+    }
+    Ok(pairs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_set_args() {
+        let pairs = parse_set_args_from([
+            "--set",
+            "r0=1.5",
+            "--verbose",
+            "--set=observation.delay=[1,2]",
+            "--set",
+            "label=a=b",
+            "--settings",
+        ])
+        .unwrap();
+        assert_eq!(
+            pairs,
+            vec![
+                ("r0".to_string(), "1.5".to_string()),
+                ("observation.delay".to_string(), "[1,2]".to_string()),
+                ("label".to_string(), "a=b".to_string()),
+            ]
+        );
+        assert!(parse_set_args_from(["--set"]).is_err());
+        assert!(parse_set_args_from(["--set", "r0"]).is_err());
+        assert!(parse_set_args_from(["--set", "=1"]).is_err());
+    }
+}
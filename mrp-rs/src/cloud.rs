@@ -0,0 +1,242 @@
+//! An [`ObjectStore`] over the `object_store` crate, for the `"s3"`, `"gs"`
+//! and `"azure"` output specs.
+//!
+//! Credentials come from each provider's standard chain, never the payload.
+//! For S3 that is `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY` (with
+//! `AWS_SESSION_TOKEN`), then the `AWS_PROFILE` (or `default`) profile of the
+//! shared credentials file, then web identity (`AWS_WEB_IDENTITY_TOKEN_FILE`
+//! and `AWS_ROLE_ARN`, as on EKS), ECS container credentials and finally the
+//! EC2 instance metadata service. Temporary credentials are refreshed as they
+//! expire. For GCS it is `GOOGLE_SERVICE_ACCOUNT` or application default
+//! credentials; for Azure, `AZURE_STORAGE_*` keys or a managed identity.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use object_store::aws::AmazonS3Builder;
+use object_store::azure::MicrosoftAzureBuilder;
+use object_store::gcp::GoogleCloudStorageBuilder;
+use object_store::path::Path;
+use tokio::runtime::Runtime;
+
+use crate::MrpError;
+use crate::object_store::ObjectStore;
+
+/// A bucket (or Azure container) of a cloud object store.
+///
+/// Uploads block on a runtime of the store's own, so a store must not be
+/// used from inside another async runtime.
+pub struct CloudStore {
+    store: Arc<dyn object_store::ObjectStore>,
+    /// `s3`, `gs` or `azure`, for messages.
+    scheme: &'static str,
+    bucket: String,
+    runtime: Runtime,
+}
+
+impl std::fmt::Debug for CloudStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CloudStore")
+            .field("uri", &self.uri(""))
+            .finish_non_exhaustive()
+    }
+}
+
+impl CloudStore {
+    /// The store of output spec `spec` (`"s3"`, `"gs"` or `"azure"`).
+    pub fn for_spec(spec: &str, bucket: &str) -> Result<Self, MrpError> {
+        match spec {
+            "s3" => Self::s3(bucket),
+            "gs" => Self::gcs(bucket),
+            "azure" => Self::azure(bucket),
+            _ => Err(MrpError::Config(format!(
+                "'{spec}' is not an object store output spec"
+            ))),
+        }
+    }
+
+    /// `bucket` in `AWS_REGION` (else `AWS_DEFAULT_REGION`, else
+    /// `us-east-1`), at `AWS_ENDPOINT_URL` or else AWS's regional endpoint,
+    /// addressed path-style so that MinIO, localstack and dotted bucket
+    /// names work.
+    pub fn s3(bucket: &str) -> Result<Self, MrpError> {
+        let mut builder = AmazonS3Builder::from_env().with_bucket_name(bucket);
+        let var = |name| std::env::var(name).ok().filter(|v: &String| !v.is_empty());
+        if let Some(endpoint) = var("AWS_ENDPOINT_URL") {
+            builder = builder
+                .with_allow_http(endpoint.starts_with("http://"))
+                .with_endpoint(endpoint);
+        }
+        if var("AWS_ACCESS_KEY_ID").is_none()
+            && let Some(profile) = SharedProfile::from_env()
+        {
+            builder = builder
+                .with_access_key_id(profile.access_key_id)
+                .with_secret_access_key(profile.secret_access_key);
+            if let Some(token) = profile.session_token {
+                builder = builder.with_token(token);
+            }
+        }
+        Self::new("s3", bucket, builder.build())
+    }
+
+    /// `bucket` on Google Cloud Storage.
+    pub fn gcs(bucket: &str) -> Result<Self, MrpError> {
+        let builder = GoogleCloudStorageBuilder::from_env().with_bucket_name(bucket);
+        Self::new("gs", bucket, builder.build())
+    }
+
+    /// Container `container` of the storage account in
+    /// `AZURE_STORAGE_ACCOUNT_NAME`.
+    pub fn azure(container: &str) -> Result<Self, MrpError> {
+        let builder = MicrosoftAzureBuilder::from_env().with_container_name(container);
+        Self::new("azure", container, builder.build())
+    }
+
+    fn new<S: object_store::ObjectStore>(
+        scheme: &'static str,
+        bucket: &str,
+        store: object_store::Result<S>,
+    ) -> Result<Self, MrpError> {
+        let store =
+            store.map_err(|e| MrpError::Config(format!("cannot open {scheme}://{bucket}: {e}")))?;
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| MrpError::Runtime(format!("failed to start upload runtime: {e}")))?;
+        Ok(CloudStore {
+            store: Arc::new(store),
+            scheme,
+            bucket: bucket.to_string(),
+            runtime,
+        })
+    }
+
+    /// The URI of `key`, e.g. `s3://bucket/key`, for messages.
+    pub fn uri(&self, key: &str) -> String {
+        format!("{}://{}/{key}", self.scheme, self.bucket)
+    }
+}
+
+impl ObjectStore for CloudStore {
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        let location = Path::parse(key)
+            .map_err(|e| io::Error::other(format!("PUT {}: {e}", self.uri(key))))?;
+        self.runtime
+            .block_on(self.store.put(&location, data.to_vec().into()))
+            .map(|_| ())
+            .map_err(|e| io::Error::other(format!("PUT {}: {e}", self.uri(key))))
+    }
+}
+
+/// Keys from a profile of the shared AWS credentials file.
+struct SharedProfile {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+impl SharedProfile {
+    /// The `AWS_PROFILE` (or `default`) profile of
+    /// `AWS_SHARED_CREDENTIALS_FILE` or `~/.aws/credentials`.
+    fn from_env() -> Option<Self> {
+        let var = |name| std::env::var(name).ok().filter(|v: &String| !v.is_empty());
+        let profile = var("AWS_PROFILE").unwrap_or_else(|| "default".to_string());
+        let path = var("AWS_SHARED_CREDENTIALS_FILE")
+            .map(PathBuf::from)
+            .or_else(|| var("HOME").map(|home| PathBuf::from(home).join(".aws/credentials")))?;
+        Self::parse(&fs::read_to_string(path).ok()?, &profile)
+    }
+
+    /// `profile` from the text of a shared credentials file.
+    fn parse(text: &str, profile: &str) -> Option<Self> {
+        let mut section = None;
+        let mut values = std::collections::BTreeMap::new();
+        for line in text.lines().map(str::trim) {
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = Some(name.trim().to_string());
+            } else if section.as_deref() == Some(profile)
+                && let Some((key, value)) = line.split_once('=')
+            {
+                values.insert(key.trim().to_string(), value.trim().to_string());
+            }
+        }
+        Some(SharedProfile {
+            access_key_id: values.remove("aws_access_key_id")?,
+            secret_access_key: values.remove("aws_secret_access_key")?,
+            session_token: values.remove("aws_session_token"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_credentials_file_profile() {
+        let text = "[default]\naws_access_key_id = A\naws_secret_access_key = B\n\n\
+                    [cluster]\naws_access_key_id=C\naws_secret_access_key=D\naws_session_token=E\n";
+        let cluster = SharedProfile::parse(text, "cluster").unwrap();
+        assert_eq!(cluster.access_key_id, "C");
+        assert_eq!(cluster.session_token.as_deref(), Some("E"));
+        assert_eq!(
+            SharedProfile::parse(text, "default")
+                .unwrap()
+                .secret_access_key,
+            "B"
+        );
+        assert!(SharedProfile::parse(text, "missing").is_none());
+    }
+
+    #[test]
+    fn test_unreachable_store_names_object() {
+        let store = CloudStore::new(
+            "s3",
+            "bucket",
+            AmazonS3Builder::new()
+                .with_bucket_name("bucket")
+                .with_region("us-east-1")
+                .with_access_key_id("A")
+                .with_secret_access_key("B")
+                .with_endpoint("http://127.0.0.1:9")
+                .with_allow_http(true)
+                .with_retry(object_store::RetryConfig {
+                    max_retries: 0,
+                    ..Default::default()
+                })
+                .build(),
+        )
+        .unwrap();
+        let err = store.put("runs/1/out.csv", b"a\n").unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("PUT s3://bucket/runs/1/out.csv: "),
+            "{err}"
+        );
+        assert!(CloudStore::for_spec("ftp", "bucket").is_err());
+    }
+
+    /// Uploads to the endpoint in `MRP_TEST_S3_ENDPOINT` (e.g. a local MinIO)
+    /// and bucket `MRP_TEST_S3_BUCKET`, with credentials from the usual
+    /// chain; skipped when those are not set.
+    #[test]
+    fn test_put_to_live_endpoint() {
+        let (Ok(endpoint), Ok(bucket)) = (
+            std::env::var("MRP_TEST_S3_ENDPOINT"),
+            std::env::var("MRP_TEST_S3_BUCKET"),
+        ) else {
+            return;
+        };
+        let builder = AmazonS3Builder::from_env()
+            .with_bucket_name(&bucket)
+            .with_allow_http(endpoint.starts_with("http://"))
+            .with_endpoint(endpoint);
+        let store = CloudStore::new("s3", &bucket, builder.build()).unwrap();
+        store
+            .put("mrp-test/hello.csv", b"a,b\n1,2\n")
+            .unwrap_or_else(|e| panic!("{e}"));
+    }
+}
@@ -0,0 +1,415 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::MrpError;
+use crate::provenance::sha256_hex;
+
+/// How closely two runs' outputs must agree.
+#[derive(Debug, Clone, Default)]
+pub struct CompareOptions {
+    /// Absolute tolerance for numeric values without a column entry.
+    pub tolerance: f64,
+    /// Absolute tolerances by CSV column name or JSON key.
+    pub column_tolerances: BTreeMap<String, f64>,
+}
+
+impl CompareOptions {
+    fn tolerance_for(&self, column: &str) -> f64 {
+        self.column_tolerances
+            .get(column)
+            .copied()
+            .unwrap_or(self.tolerance)
+    }
+}
+
+/// One value that differs between the two runs.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CellDiff {
+    /// Data row (0-based, excluding the header) for CSVs; `None` for JSON.
+    pub row: Option<usize>,
+    /// CSV column name, or JSON path such as `$.a[2].b`.
+    pub column: String,
+    pub a: String,
+    pub b: String,
+    /// `|a - b|` when both values are numeric.
+    pub abs_diff: Option<f64>,
+}
+
+/// Differences found in a file present in both runs.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FileDiff {
+    pub file: String,
+    /// Structural problems (headers, row counts) or a hash mismatch.
+    pub notes: Vec<String>,
+    pub cells: Vec<CellDiff>,
+    pub max_abs_diff: Option<f64>,
+    pub mean_abs_diff: Option<f64>,
+}
+
+/// Result of [`compare_runs`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CompareReport {
+    /// Files only in the candidate (`b`).
+    pub added: Vec<String>,
+    /// Files only in the baseline (`a`).
+    pub removed: Vec<String>,
+    pub changed: Vec<FileDiff>,
+    pub unchanged: Vec<String>,
+}
+
+impl CompareReport {
+    pub fn is_match(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    /// A human-readable summary of the differences.
+    pub fn summary(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "{} unchanged, {} changed, {} added, {} removed",
+            self.unchanged.len(),
+            self.changed.len(),
+            self.added.len(),
+            self.removed.len()
+        );
+        for file in &self.added {
+            let _ = writeln!(out, "  added:   {file}");
+        }
+        for file in &self.removed {
+            let _ = writeln!(out, "  removed: {file}");
+        }
+        for diff in &self.changed {
+            let _ = write!(out, "  changed: {} ({} cells", diff.file, diff.cells.len());
+            if let Some(max) = diff.max_abs_diff {
+                let _ = write!(out, ", max |diff| {max}");
+            }
+            let _ = writeln!(out, ")");
+            for note in &diff.notes {
+                let _ = writeln!(out, "    {note}");
+            }
+            for cell in diff.cells.iter().take(10) {
+                let at = match cell.row {
+                    Some(row) => format!("row {row}, {}", cell.column),
+                    None => cell.column.clone(),
+                };
+                let _ = writeln!(out, "    {at}: {} -> {}", cell.a, cell.b);
+            }
+            if diff.cells.len() > 10 {
+                let _ = writeln!(out, "    ... {} more", diff.cells.len() - 10);
+            }
+        }
+        out
+    }
+}
+
+/// Compare a baseline output directory `a` against a candidate `b`.
+///
+/// Files are paired by relative path. CSVs are compared cell by cell and JSON
+/// structurally, both with numeric tolerance; other files by hash.
+pub fn compare_runs(
+    a: &Path,
+    b: &Path,
+    options: &CompareOptions,
+) -> Result<CompareReport, MrpError> {
+    let files_a = list_files(a)?;
+    let files_b = list_files(b)?;
+    let mut report = CompareReport {
+        added: files_b.difference(&files_a).cloned().collect(),
+        removed: files_a.difference(&files_b).cloned().collect(),
+        changed: Vec::new(),
+        unchanged: Vec::new(),
+    };
+    for file in files_a.intersection(&files_b) {
+        let read = |dir: &Path| {
+            fs::read(dir.join(file))
+                .map_err(|e| MrpError::Output(format!("failed to read {file}: {e}")))
+        };
+        let (data_a, data_b) = (read(a)?, read(b)?);
+        let mut diff = FileDiff {
+            file: file.clone(),
+            notes: Vec::new(),
+            cells: Vec::new(),
+            max_abs_diff: None,
+            mean_abs_diff: None,
+        };
+        if data_a != data_b {
+            if file.ends_with(".csv") {
+                compare_csv(&data_a, &data_b, options, &mut diff);
+            } else if file.ends_with(".json") {
+                match (
+                    serde_json::from_slice::<Value>(&data_a),
+                    serde_json::from_slice::<Value>(&data_b),
+                ) {
+                    (Ok(va), Ok(vb)) => {
+                        compare_json("$", &va, &vb, options, options.tolerance, &mut diff)
+                    }
+                    _ => diff
+                        .notes
+                        .push("not valid JSON; contents differ".to_string()),
+                }
+            } else {
+                diff.notes.push(format!(
+                    "sha256 {} -> {}",
+                    sha256_hex(&data_a),
+                    sha256_hex(&data_b)
+                ));
+            }
+        }
+        let numeric: Vec<f64> = diff.cells.iter().filter_map(|c| c.abs_diff).collect();
+        if !numeric.is_empty() {
+            diff.max_abs_diff = numeric.iter().copied().reduce(f64::max);
+            diff.mean_abs_diff = Some(numeric.iter().sum::<f64>() / numeric.len() as f64);
+        }
+        if diff.notes.is_empty() && diff.cells.is_empty() {
+            report.unchanged.push(file.clone());
+        } else {
+            report.changed.push(diff);
+        }
+    }
+    Ok(report)
+}
+
+/// Panic with a summary if two output directories differ.
+pub fn assert_runs_match(a: &Path, b: &Path, options: &CompareOptions) {
+    let report = compare_runs(a, b, options).unwrap_or_else(|e| panic!("{e}"));
+    if !report.is_match() {
+        panic!("runs differ:\n{}", report.summary());
+    }
+}
+
+/// Files under `root`, as `/`-separated relative paths.
+pub(crate) fn list_files(root: &Path) -> Result<BTreeSet<String>, MrpError> {
+    let mut files = BTreeSet::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries = fs::read_dir(&dir)
+            .map_err(|e| MrpError::Output(format!("failed to list {}: {e}", dir.display())))?;
+        for entry in entries {
+            let path = entry
+                .map_err(|e| MrpError::Output(format!("failed to list {}: {e}", dir.display())))?
+                .path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if let Ok(rel) = path.strip_prefix(root) {
+                files.insert(rel.to_string_lossy().replace('\\', "/"));
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Compare two cells, numerically when both parse as numbers. Returns `None`
+/// if they agree, else the absolute difference when numeric.
+fn compare_cell(a: &str, b: &str, tolerance: f64) -> Option<Option<f64>> {
+    if a == b {
+        return None;
+    }
+    match (a.parse::<f64>(), b.parse::<f64>()) {
+        (Ok(x), Ok(y)) if x.is_nan() && y.is_nan() => None,
+        (Ok(x), Ok(y)) => {
+            let diff = (x - y).abs();
+            (diff.is_nan() || diff > tolerance).then_some(Some(diff))
+        }
+        _ => Some(None),
+    }
+}
+
+fn compare_csv(a: &[u8], b: &[u8], options: &CompareOptions, diff: &mut FileDiff) {
+    let parse = |data: &[u8]| -> Result<(Vec<String>, Vec<Vec<String>>), csv::Error> {
+        let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(data);
+        let headers = reader.headers()?.iter().map(String::from).collect();
+        let rows = reader
+            .records()
+            .map(|r| r.map(|r| r.iter().map(String::from).collect()))
+            .collect::<Result<_, _>>()?;
+        Ok((headers, rows))
+    };
+    let ((headers_a, rows_a), (headers_b, rows_b)) = match (parse(a), parse(b)) {
+        (Ok(a), Ok(b)) => (a, b),
+        _ => {
+            diff.notes
+                .push("not valid CSV; contents differ".to_string());
+            return;
+        }
+    };
+    if headers_a != headers_b {
+        diff.notes.push(format!(
+            "headers differ: {} -> {}",
+            headers_a.join(","),
+            headers_b.join(",")
+        ));
+        return;
+    }
+    if rows_a.len() != rows_b.len() {
+        diff.notes
+            .push(format!("row count {} -> {}", rows_a.len(), rows_b.len()));
+    }
+    for (row, (ra, rb)) in rows_a.iter().zip(&rows_b).enumerate() {
+        for (i, column) in headers_a.iter().enumerate() {
+            let (ca, cb) = (
+                ra.get(i).map_or("", String::as_str),
+                rb.get(i).map_or("", String::as_str),
+            );
+            if let Some(abs_diff) = compare_cell(ca, cb, options.tolerance_for(column)) {
+                diff.cells.push(CellDiff {
+                    row: Some(row),
+                    column: column.clone(),
+                    a: ca.to_string(),
+                    b: cb.to_string(),
+                    abs_diff,
+                });
+            }
+        }
+    }
+}
+
+/// Compare JSON values; numbers use the tolerance of the nearest key.
+fn compare_json(
+    path: &str,
+    a: &Value,
+    b: &Value,
+    options: &CompareOptions,
+    tolerance: f64,
+    diff: &mut FileDiff,
+) {
+    let cell = |column: String, a: String, b: String, abs_diff| CellDiff {
+        row: None,
+        column,
+        a,
+        b,
+        abs_diff,
+    };
+    match (a, b) {
+        (Value::Object(ma), Value::Object(mb)) => {
+            let keys: BTreeSet<&String> = ma.keys().chain(mb.keys()).collect();
+            for key in keys {
+                let child = format!("{path}.{key}");
+                match (ma.get(key), mb.get(key)) {
+                    (Some(va), Some(vb)) => {
+                        let tolerance = options
+                            .column_tolerances
+                            .get(key)
+                            .copied()
+                            .unwrap_or(tolerance);
+                        compare_json(&child, va, vb, options, tolerance, diff)
+                    }
+                    (va, vb) => diff.cells.push(cell(
+                        child,
+                        va.map_or("(missing)".to_string(), Value::to_string),
+                        vb.map_or("(missing)".to_string(), Value::to_string),
+                        None,
+                    )),
+                }
+            }
+        }
+        (Value::Array(va), Value::Array(vb)) if va.len() == vb.len() => {
+            for (i, (x, y)) in va.iter().zip(vb).enumerate() {
+                compare_json(&format!("{path}[{i}]"), x, y, options, tolerance, diff);
+            }
+        }
+        (Value::Number(x), Value::Number(y)) => {
+            if let Some(abs_diff) = compare_cell(&x.to_string(), &y.to_string(), tolerance) {
+                diff.cells.push(cell(
+                    path.to_string(),
+                    a.to_string(),
+                    b.to_string(),
+                    abs_diff,
+                ));
+            }
+        }
+        _ if a != b => diff
+            .cells
+            .push(cell(path.to_string(), a.to_string(), b.to_string(), None)),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(dir: &Path, csv: &str, json: &str) {
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("out.csv"), csv).unwrap();
+        fs::write(dir.join("metrics.json"), json).unwrap();
+        fs::write(dir.join("sub/blob.bin"), [1, 2, 3]).unwrap();
+    }
+
+    const CSV: &str = "step,value,label\n0,1.5,a\n1,2.25,b\n";
+    const JSON: &str = r#"{"peak": 120.0, "days": [1, 2]}"#;
+
+    #[test]
+    fn test_identical_runs() {
+        let (a, b) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        run(a.path(), CSV, JSON);
+        run(b.path(), CSV, JSON);
+        let report = compare_runs(a.path(), b.path(), &CompareOptions::default()).unwrap();
+        assert!(report.is_match());
+        assert_eq!(
+            report.unchanged,
+            vec!["metrics.json", "out.csv", "sub/blob.bin"]
+        );
+        assert_runs_match(a.path(), b.path(), &CompareOptions::default());
+    }
+
+    #[test]
+    fn test_numeric_drift() {
+        let (a, b) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        run(a.path(), CSV, JSON);
+        run(
+            b.path(),
+            "step,value,label\n0,1.5,a\n1,2.2500001,b\n",
+            r#"{"peak": 120.00001, "days": [1, 2]}"#,
+        );
+
+        let loose = CompareOptions {
+            tolerance: 1e-3,
+            ..Default::default()
+        };
+        assert!(compare_runs(a.path(), b.path(), &loose).unwrap().is_match());
+
+        let strict = CompareOptions {
+            column_tolerances: BTreeMap::from([("peak".to_string(), 1e-3)]),
+            ..Default::default()
+        };
+        let report = compare_runs(a.path(), b.path(), &strict).unwrap();
+        assert_eq!(report.changed.len(), 1);
+        let diff = &report.changed[0];
+        assert_eq!(diff.file, "out.csv");
+        assert_eq!(diff.cells.len(), 1);
+        assert_eq!(diff.cells[0].row, Some(1));
+        assert_eq!(diff.cells[0].column, "value");
+        assert!((diff.max_abs_diff.unwrap() - 1e-7).abs() < 1e-9);
+        assert!(report.summary().contains("row 1, value: 2.25 -> 2.2500001"));
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["changed"][0]["cells"][0]["column"], "value");
+    }
+
+    #[test]
+    fn test_missing_and_changed_files() {
+        let (a, b) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        run(a.path(), CSV, JSON);
+        run(b.path(), CSV, r#"{"peak": 120.0, "days": [1, 3]}"#);
+        fs::remove_file(b.path().join("out.csv")).unwrap();
+        fs::write(b.path().join("sub/blob.bin"), [9]).unwrap();
+        fs::write(b.path().join("new.txt"), "x").unwrap();
+
+        let report = compare_runs(a.path(), b.path(), &CompareOptions::default()).unwrap();
+        assert_eq!(report.removed, vec!["out.csv"]);
+        assert_eq!(report.added, vec!["new.txt"]);
+        let files: Vec<&str> = report.changed.iter().map(|d| d.file.as_str()).collect();
+        assert_eq!(files, vec!["metrics.json", "sub/blob.bin"]);
+        assert_eq!(report.changed[0].cells[0].column, "$.days[1]");
+        assert!(report.changed[1].notes[0].starts_with("sha256"));
+        let result = std::panic::catch_unwind(|| {
+            assert_runs_match(a.path(), b.path(), &CompareOptions::default())
+        });
+        assert!(result.is_err());
+    }
+}
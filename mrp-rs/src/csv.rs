@@ -1,29 +1,537 @@
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
 use std::io::Write;
+use std::rc::Rc;
 
-use csv::Writer;
+use csv::{Writer, WriterBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::MrpError;
+use crate::environment::Warning;
+use crate::schema::OutputSchema;
+use crate::stream;
+
+/// How string fields with control characters or invalid UTF-8 are written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StringPolicy {
+    /// Write fields as given.
+    #[default]
+    Passthrough,
+    /// Fail the row, naming the row and column.
+    Reject,
+    /// Map control characters (`\r`, `\n`, `\t`, NUL, ...) to spaces and
+    /// replace invalid UTF-8 with U+FFFD.
+    ReplaceControlChars,
+}
+
+/// Options for CSV output.
+#[derive(Debug, Clone, Default)]
+pub struct CsvOptions {
+    strings: StringPolicy,
+    run_columns: bool,
+    atomic: bool,
+}
+
+impl CsvOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check or clean string fields before they are written.
+    pub fn sanitize(mut self, policy: StringPolicy) -> Self {
+        self.strings = policy;
+        self
+    }
+
+    /// Start every file with `seed` and `replicate` columns, filled in on
+    /// each row from the run, so outputs can be joined back to it.
+    pub fn with_run_columns(mut self, enabled: bool) -> Self {
+        self.run_columns = enabled;
+        self
+    }
+
+    pub(crate) fn run_columns(&self) -> bool {
+        self.run_columns
+    }
+
+    /// Write files to `<filename>.tmp-<pid>` and rename them into place
+    /// when closed, as the output spec's `"atomic": true` does for every
+    /// output.
+    pub fn with_atomic(mut self, enabled: bool) -> Self {
+        self.atomic = enabled;
+        self
+    }
+
+    pub(crate) fn atomic(&self) -> bool {
+        self.atomic
+    }
+}
+
+/// The columns [`CsvOptions::with_run_columns`] adds, in order.
+pub const RUN_COLUMNS: [&str; 2] = ["seed", "replicate"];
+
+/// A deployment's trimming of one CSV output, from the output spec's
+/// `"csv_filters"` section, keyed by filename.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct CsvFilter {
+    /// Columns to keep, in the writer's order.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    columns: Option<Vec<String>>,
+    /// Keep the first of every `n` rows.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    every_nth_row: Option<u64>,
+    /// Keep rows whose `step` column is in this inclusive range; applied
+    /// before thinning.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    step_range: Option<[i64; 2]>,
+}
+
+impl CsvFilter {
+    pub(crate) fn from_spec(spec: &Value) -> Result<BTreeMap<String, Self>, MrpError> {
+        let Some(filters) = spec.get("csv_filters") else {
+            return Ok(BTreeMap::new());
+        };
+        let filters: BTreeMap<String, Self> = serde_json::from_value(filters.clone())
+            .map_err(|e| MrpError::Config(format!("invalid csv_filters: {e}")))?;
+        if let Some(name) = filters
+            .iter()
+            .find(|(_, f)| f.every_nth_row == Some(0))
+            .map(|(name, _)| name)
+        {
+            return Err(MrpError::Config(format!(
+                "csv_filters: every_nth_row for '{name}' must be positive"
+            )));
+        }
+        Ok(filters)
+    }
+
+    /// Resolve the filter against a writer's headers.
+    pub(crate) fn bind(&self, filename: &str, headers: &[&str]) -> Result<RowFilter, MrpError> {
+        let position = |name: &str| {
+            headers.iter().position(|h| *h == name).ok_or_else(|| {
+                MrpError::Config(format!(
+                    "csv_filters for '{filename}' names unknown column '{name}' (columns: {})",
+                    headers.join(", ")
+                ))
+            })
+        };
+        let keep = match &self.columns {
+            Some(columns) => columns
+                .iter()
+                .map(|c| position(c))
+                .collect::<Result<_, _>>()?,
+            None => (0..headers.len()).collect(),
+        };
+        let step = self.step_range.map(|_| position("step")).transpose()?;
+        Ok(RowFilter {
+            keep,
+            step: step.zip(self.step_range),
+            every: self.every_nth_row.unwrap_or(1),
+            admitted: 0,
+        })
+    }
+}
+
+/// A [`CsvFilter`] bound to one writer's columns.
+pub(crate) struct RowFilter {
+    keep: Vec<usize>,
+    step: Option<(usize, [i64; 2])>,
+    every: u64,
+    admitted: u64,
+}
+
+impl RowFilter {
+    /// Whether to write `row`.
+    fn admit(&mut self, row: &[Cow<[u8]>]) -> bool {
+        if let Some((column, [from, to])) = self.step {
+            let step = row
+                .get(column)
+                .and_then(|f| std::str::from_utf8(f).ok())
+                .and_then(|f| f.trim().parse::<i64>().ok());
+            if !step.is_some_and(|s| (from..=to).contains(&s)) {
+                return false;
+            }
+        }
+        self.admitted += 1;
+        (self.admitted - 1).is_multiple_of(self.every)
+    }
+}
 
 pub struct CsvWriter {
     writer: Writer<Box<dyn Write>>,
+    filename: Option<String>,
+    headers: Vec<String>,
+    schema: Option<OutputSchema>,
+    options: CsvOptions,
+    rows: Option<Rc<Cell<u64>>>,
+    next_row: u64,
+    replaced: Replaced,
+    warnings: Option<Rc<RefCell<Vec<Warning>>>>,
+    filter: Option<RowFilter>,
+    run: Option<[String; 2]>,
+}
+
+/// Fields changed under [`StringPolicy::ReplaceControlChars`].
+#[derive(Debug, Default)]
+struct Replaced {
+    control_chars: u64,
+    invalid_utf8: u64,
 }
 
 impl CsvWriter {
     pub fn new(dest: Box<dyn Write>, headers: &[&str]) -> Self {
-        let mut writer = Writer::from_writer(dest);
-        writer.write_record(headers).expect("failed to write CSV headers");
-        CsvWriter { writer }
+        let mut writer = Self::continuing(dest, headers);
+        writer
+            .writer
+            .write_record(headers)
+            .expect("failed to write CSV headers");
+        writer
+    }
+
+    /// A writer that continues an existing CSV, so writes no header row.
+    pub(crate) fn continuing(dest: Box<dyn Write>, headers: &[&str]) -> Self {
+        CsvWriter {
+            writer: WriterBuilder::new().has_headers(false).from_writer(dest),
+            filename: None,
+            headers: headers.iter().map(|h| h.to_string()).collect(),
+            schema: None,
+            options: CsvOptions::default(),
+            rows: None,
+            next_row: 0,
+            replaced: Replaced::default(),
+            warnings: None,
+            filter: None,
+            run: None,
+        }
+    }
+
+    /// Write the filtered header row and trim every subsequent row.
+    pub(crate) fn filtered(mut self, filter: RowFilter) -> Self {
+        let headers: Vec<&str> = filter
+            .keep
+            .iter()
+            .map(|&i| self.headers[i].as_str())
+            .collect();
+        self.writer
+            .write_record(&headers)
+            .expect("failed to write CSV headers");
+        self.filter = Some(filter);
+        self
     }
 
+    /// Validate every subsequent row against `schema`.
+    pub fn with_schema(mut self, filename: &str, schema: OutputSchema) -> Self {
+        self.filename = Some(filename.to_string());
+        self.schema = Some(schema);
+        self
+    }
+
+    pub fn with_options(mut self, options: CsvOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Put `seed` and `replicate` in front of every row, under the
+    /// [`RUN_COLUMNS`] headers the writer was created with. A schema
+    /// checks only the fields after them.
+    pub(crate) fn with_run_values(mut self, seed: u64, replicate: u64) -> Self {
+        self.run = Some([seed.to_string(), replicate.to_string()]);
+        self
+    }
+
+    pub(crate) fn named(mut self, filename: &str) -> Self {
+        self.filename = Some(filename.to_string());
+        self
+    }
+
+    /// Add every subsequent row to `rows`.
+    pub(crate) fn counted(mut self, rows: Rc<Cell<u64>>) -> Self {
+        self.rows = Some(rows);
+        self
+    }
+
+    /// Record the sanitization warning in `warnings` as well as on stderr.
+    pub(crate) fn warn_to(mut self, warnings: Rc<RefCell<Vec<Warning>>>) -> Self {
+        self.warnings = Some(warnings);
+        self
+    }
+
+    pub(crate) fn filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
+    /// Write a row, panicking if it is rejected. A stalled stdout consumer
+    /// does not panic; the stall is returned by the `try_` variants and by
+    /// finalize instead.
     pub fn write_row(&mut self, row: &[&str]) {
-        self.writer.write_record(row).expect("failed to write CSV row");
+        stream::panic_unless_stalled(self.try_write_row(row));
+    }
+
+    pub fn try_write_row(&mut self, row: &[&str]) -> Result<(), MrpError> {
+        let row: Vec<&[u8]> = row.iter().map(|field| field.as_bytes()).collect();
+        self.try_write_row_bytes(&row)
+    }
+
+    /// Write a row of raw fields, e.g. text copied from an upstream file
+    /// that may not be valid UTF-8.
+    pub fn write_row_bytes(&mut self, row: &[&[u8]]) {
+        stream::panic_unless_stalled(self.try_write_row_bytes(row));
+    }
+
+    pub fn try_write_row_bytes(&mut self, row: &[&[u8]]) -> Result<(), MrpError> {
+        let run = self.run.clone();
+        let prefixed: Vec<&[u8]>;
+        let row = match &run {
+            Some(run) => {
+                prefixed = run
+                    .iter()
+                    .map(|v| v.as_bytes())
+                    .chain(row.iter().copied())
+                    .collect();
+                &prefixed
+            }
+            None => row,
+        };
+        let mut fields: Vec<Cow<[u8]>> = Vec::with_capacity(row.len());
+        for (column, field) in row.iter().enumerate() {
+            fields.push(self.sanitize(column, field)?);
+        }
+        if let Some(schema) = &self.schema {
+            let text: Vec<Cow<str>> = fields.iter().map(|f| String::from_utf8_lossy(f)).collect();
+            let refs: Vec<&str> = text.iter().map(|f| f.as_ref()).collect();
+            let own = if run.is_some() { RUN_COLUMNS.len() } else { 0 };
+            schema.check_row(self.filename.as_deref().unwrap_or_default(), &refs[own..])?;
+        }
+        if let Some(filter) = &mut self.filter {
+            if !filter.admit(&fields) {
+                self.next_row += 1;
+                return Ok(());
+            }
+            fields = filter
+                .keep
+                .iter()
+                .map(|&i| fields.get(i).cloned().unwrap_or_default())
+                .collect();
+        }
+        self.writer.write_record(&fields).map_err(|e| {
+            match e.kind() {
+                csv::ErrorKind::Io(io) => stream::stall_error(io),
+                _ => None,
+            }
+            .unwrap_or_else(|| MrpError::Output(format!("failed to write CSV row: {e}")))
+        })?;
+        self.next_row += 1;
+        if let Some(rows) = &self.rows {
+            rows.set(rows.get() + 1);
+        }
+        Ok(())
+    }
+
+    /// Write one serde-serializable record (a flat struct or tuple) as a row,
+    /// under the same string policy as [`CsvWriter::write_row`].
+    pub fn write_record<T: Serialize>(&mut self, record: &T) {
+        stream::panic_unless_stalled(self.try_write_record(record));
+    }
+
+    pub fn try_write_record<T: Serialize>(&mut self, record: &T) -> Result<(), MrpError> {
+        let err = |e: &dyn std::fmt::Display| MrpError::Serialization(format!("CSV record: {e}"));
+        let mut buffer = WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(Vec::new());
+        buffer.serialize(record).map_err(|e| err(&e))?;
+        let bytes = buffer.into_inner().map_err(|e| err(&e))?;
+        let mut record = csv::ByteRecord::new();
+        csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_reader(bytes.as_slice())
+            .read_byte_record(&mut record)
+            .map_err(|e| err(&e))?;
+        let row: Vec<&[u8]> = record.iter().collect();
+        self.try_write_row_bytes(&row)
+    }
+
+    fn sanitize<'a>(&mut self, column: usize, field: &'a [u8]) -> Result<Cow<'a, [u8]>, MrpError> {
+        let policy = self.options.strings;
+        if policy == StringPolicy::Passthrough {
+            return Ok(Cow::Borrowed(field));
+        }
+        let text = String::from_utf8_lossy(field);
+        let invalid = matches!(text, Cow::Owned(_));
+        let control = text.chars().any(char::is_control);
+        if !invalid && !control {
+            return Ok(Cow::Borrowed(field));
+        }
+        if policy == StringPolicy::Reject {
+            let column = self
+                .headers
+                .get(column)
+                .map(|h| format!("'{h}'"))
+                .unwrap_or_else(|| column.to_string());
+            return Err(MrpError::Output(format!(
+                "{}: row {}, column {column} has {}",
+                self.filename.as_deref().unwrap_or("CSV"),
+                self.next_row,
+                if invalid {
+                    "invalid UTF-8"
+                } else {
+                    "a control character"
+                }
+            )));
+        }
+        self.replaced.invalid_utf8 += invalid as u64;
+        self.replaced.control_chars += control as u64;
+        let cleaned: String = text
+            .chars()
+            .map(|c| if c.is_control() { ' ' } else { c })
+            .collect();
+        Ok(Cow::Owned(cleaned.into_bytes()))
     }
 
     pub fn flush(&mut self) {
-        self.writer.flush().expect("failed to flush CSV writer");
+        if let Err(e) = self.try_flush()
+            && stream::stall_error(&e).is_none()
+        {
+            panic!("failed to flush CSV writer: {e}");
+        }
+    }
+
+    /// Flush, returning rather than panicking on failure.
+    pub(crate) fn try_flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
     }
 }
 
 impl Drop for CsvWriter {
     fn drop(&mut self) {
         let _ = self.writer.flush();
+        let Replaced {
+            control_chars,
+            invalid_utf8,
+        } = self.replaced;
+        if control_chars + invalid_utf8 == 0 {
+            return;
+        }
+        let message = format!(
+            "{}: replaced control characters in {control_chars} fields and invalid UTF-8 in \
+             {invalid_utf8} fields",
+            self.filename.as_deref().unwrap_or("CSV")
+        );
+        eprintln!("warning [csv_sanitized]: {message}");
+        if let Some(warnings) = &self.warnings {
+            warnings.borrow_mut().push(Warning {
+                code: "csv_sanitized".to_string(),
+                message,
+                error_code: None,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A destination whose bytes stay readable after the writer is dropped.
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    const DIRTY: [&[u8]; 4] = [b"line\nbreak", b"cr\rtab\t", b"nul\0", b"bad \xff byte"];
+
+    fn writer(policy: StringPolicy) -> (CsvWriter, Shared, Rc<RefCell<Vec<Warning>>>) {
+        let dest = Shared::default();
+        let warnings = Rc::new(RefCell::new(Vec::new()));
+        let writer = CsvWriter::new(Box::new(dest.clone()), &["id", "name"])
+            .named("places.csv")
+            .with_options(CsvOptions::new().sanitize(policy))
+            .warn_to(warnings.clone());
+        (writer, dest, warnings)
+    }
+
+    #[test]
+    fn test_passthrough_writes_fields_as_given() {
+        let (mut w, dest, warnings) = writer(StringPolicy::Passthrough);
+        for field in DIRTY {
+            w.write_row_bytes(&[b"1", field]);
+        }
+        drop(w);
+        let out = dest.0.borrow();
+        assert!(out.windows(4).any(|b| b == b"nul\0"));
+        assert!(out.windows(2).any(|b| b == b"\xff "));
+        assert!(warnings.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_reject_names_row_and_column() {
+        let (mut w, _, _) = writer(StringPolicy::Reject);
+        w.write_row(&["0", "clean"]);
+        for field in DIRTY {
+            let err = w.try_write_row_bytes(&[b"1", field]).unwrap_err();
+            assert!(
+                err.to_string().contains("places.csv: row 1, column 'name'"),
+                "{err}"
+            );
+        }
+        let err = w.try_write_row(&["x\ny", "ok"]).unwrap_err();
+        assert!(err.to_string().contains("column 'id' has a control"));
+    }
+
+    #[test]
+    fn test_replace_control_chars() {
+        let (mut w, dest, warnings) = writer(StringPolicy::ReplaceControlChars);
+        for field in DIRTY {
+            w.write_row_bytes(&[b"1", field]);
+        }
+        w.write_row(&["2", "clean"]);
+        drop(w);
+        let out = String::from_utf8(dest.0.borrow().clone()).unwrap();
+        assert_eq!(
+            out,
+            "id,name\n1,line break\n1,cr tab \n1,nul \n1,bad \u{FFFD} byte\n2,clean\n"
+        );
+        let warnings = warnings.borrow();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].code, "csv_sanitized");
+        assert!(
+            warnings[0]
+                .message
+                .contains("control characters in 3 fields")
+        );
+        assert!(warnings[0].message.contains("invalid UTF-8 in 1 fields"));
+    }
+
+    #[test]
+    fn test_typed_records_follow_policy() {
+        #[derive(Serialize)]
+        struct Place {
+            id: u32,
+            name: String,
+        }
+        let place = Place {
+            id: 7,
+            name: "two\r\nlines".to_string(),
+        };
+        let (mut w, _, _) = writer(StringPolicy::Reject);
+        assert!(w.try_write_record(&place).is_err());
+
+        let (mut w, dest, _) = writer(StringPolicy::ReplaceControlChars);
+        w.write_record(&place);
+        w.write_record(&(8, "plain"));
+        drop(w);
+        let out = String::from_utf8(dest.0.borrow().clone()).unwrap();
+        assert_eq!(out, "id,name\n7,two  lines\n8,plain\n");
     }
 }
@@ -0,0 +1,113 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::environment::Warning;
+
+/// Default number of times one warning is emitted before repeats are
+/// suppressed.
+pub(crate) const DEFAULT_WARNING_LIMIT: u64 = 10;
+
+/// Counts warnings by code and message template, suppressing repeats
+/// beyond a limit.
+#[derive(Debug)]
+pub(crate) struct Dedup {
+    limit: u64,
+    seen: HashMap<(String, String), u64>,
+    counts: BTreeMap<String, u64>,
+    suppressed: BTreeMap<String, u64>,
+}
+
+impl Dedup {
+    pub(crate) fn new(limit: u64) -> Self {
+        Dedup {
+            limit,
+            seen: HashMap::new(),
+            counts: BTreeMap::new(),
+            suppressed: BTreeMap::new(),
+        }
+    }
+
+    /// Count a warning, returning whether it should still be emitted.
+    pub(crate) fn admit(&mut self, code: &str, message: &str) -> bool {
+        *self.counts.entry(code.to_string()).or_insert(0) += 1;
+        let seen = self
+            .seen
+            .entry((code.to_string(), template(message)))
+            .or_insert(0);
+        *seen += 1;
+        if *seen <= self.limit {
+            return true;
+        }
+        *self.suppressed.entry(code.to_string()).or_insert(0) += 1;
+        false
+    }
+
+    /// Warnings counted per code, including suppressed ones.
+    pub(crate) fn counts(&self) -> &BTreeMap<String, u64> {
+        &self.counts
+    }
+
+    /// A warning summarizing what was suppressed, if anything was.
+    pub(crate) fn summary(&self) -> Option<Warning> {
+        if self.suppressed.is_empty() {
+            return None;
+        }
+        let total: u64 = self.suppressed.values().sum();
+        let by_code: Vec<String> = self
+            .suppressed
+            .iter()
+            .map(|(code, n)| format!("{code}: {n}"))
+            .collect();
+        Some(Warning {
+            code: "warnings_suppressed".to_string(),
+            message: format!(
+                "suppressed {total} duplicates beyond {} per warning ({})",
+                self.limit,
+                by_code.join(", ")
+            ),
+            error_code: None,
+        })
+    }
+}
+
+/// `message` with numbers replaced by `#` and quoted text by `'…'`, so
+/// messages that differ only in their values share a template.
+fn template(message: &str) -> String {
+    let mut out = String::with_capacity(message.len());
+    let mut chars = message.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_ascii_digit() {
+            while chars
+                .peek()
+                .is_some_and(|c| c.is_ascii_digit() || *c == '.')
+            {
+                chars.next();
+            }
+            out.push('#');
+        } else if c == '\'' || c == '"' {
+            if chars.by_ref().any(|next| next == c) {
+                out.extend([c, '…', c]);
+            } else {
+                out.push(c);
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_template_ignores_values() {
+        assert_eq!(
+            template("step 12: rate -0.25 below 'floor'"),
+            template("step 7: rate -3 below 'ceiling'")
+        );
+        assert_eq!(template("step 12: x = 1.5e3"), "step #: x = #e#");
+        assert_ne!(template("rate too low"), template("rate too high"));
+        assert_eq!(template("unclosed 'quote"), "unclosed '");
+    }
+}
@@ -0,0 +1,56 @@
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::environment::Warning;
+
+pub(crate) type Cleanup = Box<dyn FnOnce() + Send + 'static>;
+
+/// Cleanup actions registered with `defer`, run newest first.
+///
+/// Whatever has not run by the time this is dropped (a model that returned
+/// early or panicked) runs then, so teardown happens on every path.
+#[derive(Default)]
+pub(crate) struct Deferred {
+    actions: Vec<Cleanup>,
+}
+
+impl Deferred {
+    pub(crate) fn push(&mut self, action: Cleanup) {
+        self.actions.push(action);
+    }
+
+    /// Run and clear every action, newest first.
+    ///
+    /// A panicking action is reported as a `deferred_failed` warning and the
+    /// rest still run.
+    pub(crate) fn run(&mut self) -> Vec<Warning> {
+        run_all(std::mem::take(&mut self.actions))
+    }
+}
+
+impl Drop for Deferred {
+    fn drop(&mut self) {
+        self.run();
+    }
+}
+
+/// Run `actions` newest first, catching panics.
+pub(crate) fn run_all(actions: Vec<Cleanup>) -> Vec<Warning> {
+    let mut warnings = Vec::new();
+    for (i, action) in actions.into_iter().enumerate().rev() {
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(action)) {
+            let reason = payload
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            let message = format!("deferred cleanup {i} panicked: {reason}");
+            eprintln!("warning [deferred_failed]: {message}");
+            warnings.push(Warning {
+                code: "deferred_failed".to_string(),
+                message,
+                error_code: None,
+            });
+        }
+    }
+    warnings
+}
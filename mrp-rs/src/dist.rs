@@ -0,0 +1,383 @@
+//! Delay distributions given by their parameters rather than as a PMF,
+//! e.g. `{"distribution": "gamma", "mean": 4.2, "sd": 1.7}`.
+
+use serde::{Deserialize, Serialize};
+
+use crate::params::PmfOptions;
+
+/// A non-negative delay distribution, in days. Means and standard
+/// deviations are on the natural scale, including for `lognormal`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(tag = "distribution", rename_all = "snake_case", deny_unknown_fields)]
+pub enum DistributionSpec {
+    Gamma {
+        mean: f64,
+        sd: f64,
+    },
+    #[serde(rename = "lognormal", alias = "log_normal")]
+    LogNormal {
+        mean: f64,
+        sd: f64,
+    },
+    Exponential {
+        mean: f64,
+    },
+    /// Always `value` days.
+    Fixed {
+        value: f64,
+    },
+    /// Day `i` has probability `pmf[i]`.
+    Empirical {
+        pmf: Vec<f64>,
+    },
+}
+
+impl DistributionSpec {
+    /// What is wrong with the parameters, if anything: means and standard
+    /// deviations must be positive, and an empirical PMF a distribution.
+    pub fn validate(&self) -> Result<(), String> {
+        let positive = |name: &str, x: f64| {
+            if x.is_finite() && x > 0.0 {
+                Ok(())
+            } else {
+                Err(format!("{name} must be positive, got {x}"))
+            }
+        };
+        match self {
+            DistributionSpec::Gamma { mean, sd } | DistributionSpec::LogNormal { mean, sd } => {
+                positive("mean", *mean)?;
+                positive("sd", *sd)
+            }
+            DistributionSpec::Exponential { mean } => positive("mean", *mean),
+            DistributionSpec::Fixed { value } if value.is_finite() && *value >= 0.0 => Ok(()),
+            DistributionSpec::Fixed { value } => {
+                Err(format!("value must be non-negative, got {value}"))
+            }
+            DistributionSpec::Empirical { pmf } => PmfOptions::default().check(pmf).map(|_| ()),
+        }
+    }
+
+    pub fn mean(&self) -> f64 {
+        match self {
+            DistributionSpec::Gamma { mean, .. }
+            | DistributionSpec::LogNormal { mean, .. }
+            | DistributionSpec::Exponential { mean } => *mean,
+            DistributionSpec::Fixed { value } => *value,
+            DistributionSpec::Empirical { pmf } => {
+                pmf.iter().enumerate().map(|(i, p)| i as f64 * p).sum()
+            }
+        }
+    }
+
+    /// Probabilities for days `0..n_days`, where day `i` holds the mass in
+    /// `[i, i + 1)`. Mass beyond the last day is dropped and the rest
+    /// renormalized; if none is left, every entry is 0.
+    pub fn discretize(&self, n_days: usize) -> Vec<f64> {
+        let mut pmf: Vec<f64> = match self {
+            DistributionSpec::Fixed { value } => {
+                let mut pmf = vec![0.0; n_days];
+                if let Some(p) = pmf.get_mut(value.floor() as usize) {
+                    *p = 1.0;
+                }
+                pmf
+            }
+            DistributionSpec::Empirical { pmf } => (0..n_days)
+                .map(|i| pmf.get(i).copied().unwrap_or(0.0))
+                .collect(),
+            _ => (0..n_days)
+                .map(|i| self.cdf(i as f64 + 1.0) - self.cdf(i as f64))
+                .collect(),
+        };
+        let total: f64 = pmf.iter().sum();
+        if total > 0.0 {
+            pmf.iter_mut().for_each(|p| *p /= total);
+        }
+        pmf
+    }
+
+    /// A draw from the distribution; `empirical` draws whole days.
+    #[cfg(feature = "rand")]
+    pub fn sample<R: rand::Rng + ?Sized>(&self, rng: &mut R) -> f64 {
+        use rand::distr::weighted::WeightedIndex;
+        use rand_distr::{Distribution, Exp, Gamma, LogNormal};
+        match self {
+            DistributionSpec::Gamma { mean, sd } => {
+                let (shape, scale) = gamma_shape_scale(*mean, *sd);
+                Gamma::new(shape, scale)
+                    .expect("validated gamma parameters")
+                    .sample(rng)
+            }
+            DistributionSpec::LogNormal { mean, sd } => {
+                let (mu, sigma) = lognormal_mu_sigma(*mean, *sd);
+                LogNormal::new(mu, sigma)
+                    .expect("validated lognormal parameters")
+                    .sample(rng)
+            }
+            DistributionSpec::Exponential { mean } => Exp::new(1.0 / mean)
+                .expect("validated exponential mean")
+                .sample(rng),
+            DistributionSpec::Fixed { value } => *value,
+            DistributionSpec::Empirical { pmf } => WeightedIndex::new(pmf)
+                .expect("validated empirical PMF")
+                .sample(rng) as f64,
+        }
+    }
+
+    /// P(X < x) for the continuous distributions.
+    fn cdf(&self, x: f64) -> f64 {
+        if x <= 0.0 {
+            return 0.0;
+        }
+        match self {
+            DistributionSpec::Gamma { mean, sd } => {
+                let (shape, scale) = gamma_shape_scale(*mean, *sd);
+                regularized_gamma(shape, x / scale)
+            }
+            DistributionSpec::LogNormal { mean, sd } => {
+                let (mu, sigma) = lognormal_mu_sigma(*mean, *sd);
+                0.5 * erfc(-(x.ln() - mu) / (sigma * std::f64::consts::SQRT_2))
+            }
+            DistributionSpec::Exponential { mean } => 1.0 - (-x / mean).exp(),
+            DistributionSpec::Fixed { .. } | DistributionSpec::Empirical { .. } => {
+                unreachable!("discretized directly")
+            }
+        }
+    }
+}
+
+fn gamma_shape_scale(mean: f64, sd: f64) -> (f64, f64) {
+    ((mean / sd).powi(2), sd * sd / mean)
+}
+
+fn lognormal_mu_sigma(mean: f64, sd: f64) -> (f64, f64) {
+    let sigma2 = (1.0 + (sd / mean).powi(2)).ln();
+    (mean.ln() - sigma2 / 2.0, sigma2.sqrt())
+}
+
+/// The regularized lower incomplete gamma function P(a, x), by its series
+/// below `a + 1` and its continued fraction above.
+fn regularized_gamma(a: f64, x: f64) -> f64 {
+    const EPS: f64 = 1e-14;
+    const MAX_ITER: usize = 1000;
+    if x <= 0.0 {
+        return 0.0;
+    }
+    let log_prefix = a * x.ln() - x - ln_gamma(a);
+    if x < a + 1.0 {
+        let (mut term, mut sum, mut n) = (1.0 / a, 1.0 / a, a);
+        for _ in 0..MAX_ITER {
+            n += 1.0;
+            term *= x / n;
+            sum += term;
+            if term.abs() < sum.abs() * EPS {
+                break;
+            }
+        }
+        (sum * log_prefix.exp()).min(1.0)
+    } else {
+        // Lentz's method for the continued fraction of Q(a, x)
+        let tiny = 1e-300;
+        let mut b = x + 1.0 - a;
+        let mut c = 1.0 / tiny;
+        let mut d = 1.0 / b;
+        let mut h = d;
+        for i in 1..MAX_ITER {
+            let an = -(i as f64) * (i as f64 - a);
+            b += 2.0;
+            d = an * d + b;
+            if d.abs() < tiny {
+                d = tiny;
+            }
+            c = b + an / c;
+            if c.abs() < tiny {
+                c = tiny;
+            }
+            d = 1.0 / d;
+            let delta = d * c;
+            h *= delta;
+            if (delta - 1.0).abs() < EPS {
+                break;
+            }
+        }
+        (1.0 - log_prefix.exp() * h).max(0.0)
+    }
+}
+
+/// ln Γ(x) for x > 0, by the Lanczos approximation.
+fn ln_gamma(x: f64) -> f64 {
+    const G: f64 = 7.0;
+    const COEFFICIENTS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+    if x < 0.5 {
+        // Reflection keeps the approximation in its accurate range
+        let pi = std::f64::consts::PI;
+        return (pi / (pi * x).sin()).ln() - ln_gamma(1.0 - x);
+    }
+    let x = x - 1.0;
+    let mut sum = COEFFICIENTS[0];
+    for (i, c) in COEFFICIENTS.iter().enumerate().skip(1) {
+        sum += c / (x + i as f64);
+    }
+    let t = x + G + 0.5;
+    0.5 * (2.0 * std::f64::consts::PI).ln() + (x + 0.5) * t.ln() - t + sum.ln()
+}
+
+/// The complementary error function, to about 1.2e-7 relative error.
+fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1.0 / (1.0 + 0.5 * z);
+    let poly = -z * z - 1.265_512_23
+        + t * (1.000_023_68
+            + t * (0.374_091_96
+                + t * (0.096_784_18
+                    + t * (-0.186_288_06
+                        + t * (0.278_868_07
+                            + t * (-1.135_203_98
+                                + t * (1.488_515_87 + t * (-0.822_152_23 + t * 0.170_872_77))))))));
+    let value = t * poly.exp();
+    if x >= 0.0 { value } else { 2.0 - value }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn close(actual: &[f64], expected: &[f64], tolerance: f64) {
+        for (i, (a, e)) in actual.iter().zip(expected).enumerate() {
+            assert!((a - e).abs() < tolerance, "day {i}: {a} vs {e}");
+        }
+    }
+
+    #[test]
+    fn test_discretize_matches_closed_forms() {
+        // Exponential(1): P(i <= X < i + 1) = e^-i - e^-(i+1)
+        let exponential = DistributionSpec::Exponential { mean: 1.0 };
+        close(
+            &exponential.discretize(60),
+            &[0.632_120_558_8, 0.232_544_157_9],
+            1e-9,
+        );
+        // Gamma(shape 2, scale 1): F(x) = 1 - e^-x (1 + x)
+        let gamma = DistributionSpec::Gamma {
+            mean: 2.0,
+            sd: 2f64.sqrt(),
+        };
+        close(
+            &gamma.discretize(60),
+            &[0.264_241_117_7, 0.329_753_032_6],
+            1e-9,
+        );
+        // Lognormal(mu 0, sigma 1): F(x) = Phi(ln x)
+        let lognormal = DistributionSpec::LogNormal {
+            mean: 0.5f64.exp(),
+            sd: ((1f64.exp() - 1.0) * 1f64.exp()).sqrt(),
+        };
+        let pmf = lognormal.discretize(2000);
+        close(&pmf, &[0.5, 0.255_891_404_2, 0.108_139_988_1], 1e-6);
+        assert!((pmf.iter().sum::<f64>() - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_truncation_renormalizes() {
+        let pmf = DistributionSpec::Exponential { mean: 1.0 }.discretize(2);
+        let kept = 1.0 - (-2f64).exp();
+        close(
+            &pmf,
+            &[
+                (1.0 - (-1f64).exp()) / kept,
+                ((-1f64).exp() - (-2f64).exp()) / kept,
+            ],
+            1e-12,
+        );
+        assert_eq!(
+            DistributionSpec::Fixed { value: 2.5 }.discretize(4),
+            vec![0.0, 0.0, 1.0, 0.0]
+        );
+        assert_eq!(
+            DistributionSpec::Empirical {
+                pmf: vec![0.5, 0.25, 0.25]
+            }
+            .discretize(2),
+            vec![2.0 / 3.0, 1.0 / 3.0]
+        );
+        assert_eq!(
+            DistributionSpec::Fixed { value: 9.0 }.discretize(3),
+            vec![0.0; 3]
+        );
+    }
+
+    #[test]
+    fn test_parse_and_validate() {
+        let spec: DistributionSpec =
+            serde_json::from_value(json!({ "distribution": "gamma", "mean": 4.2, "sd": 1.7 }))
+                .unwrap();
+        assert_eq!(spec, DistributionSpec::Gamma { mean: 4.2, sd: 1.7 });
+        assert_eq!(spec.mean(), 4.2);
+        let spec: DistributionSpec =
+            serde_json::from_value(json!({ "distribution": "log_normal", "mean": 3, "sd": 1 }))
+                .unwrap();
+        assert!(matches!(spec, DistributionSpec::LogNormal { .. }));
+        let empirical = DistributionSpec::Empirical {
+            pmf: vec![0.0, 0.5, 0.5],
+        };
+        assert_eq!(empirical.mean(), 1.5);
+        assert!(
+            serde_json::from_value::<DistributionSpec>(json!({ "distribution": "weibull" }))
+                .is_err()
+        );
+        assert!(
+            serde_json::from_value::<DistributionSpec>(
+                json!({ "distribution": "gamma", "mean": 4.2, "sdev": 1.7 })
+            )
+            .is_err()
+        );
+
+        let invalid = DistributionSpec::Gamma { mean: 4.2, sd: 0.0 };
+        assert_eq!(
+            invalid.validate().unwrap_err(),
+            "sd must be positive, got 0"
+        );
+        assert!(DistributionSpec::Fixed { value: -1.0 }.validate().is_err());
+        assert!(
+            DistributionSpec::Empirical { pmf: vec![0.5] }
+                .validate()
+                .is_err()
+        );
+        empirical.validate().unwrap();
+    }
+
+    #[cfg(feature = "rand")]
+    #[test]
+    fn test_sample_means() {
+        use rand::SeedableRng;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        for spec in [
+            DistributionSpec::Gamma { mean: 4.2, sd: 1.7 },
+            DistributionSpec::LogNormal { mean: 3.0, sd: 1.0 },
+            DistributionSpec::Exponential { mean: 2.0 },
+            DistributionSpec::Empirical {
+                pmf: vec![0.0, 0.5, 0.5],
+            },
+        ] {
+            let n = 20_000;
+            let mean = (0..n).map(|_| spec.sample(&mut rng)).sum::<f64>() / n as f64;
+            assert!(
+                (mean - spec.mean()).abs() < 0.05 * spec.mean(),
+                "{spec:?}: {mean}"
+            );
+        }
+        assert_eq!(DistributionSpec::Fixed { value: 3.0 }.sample(&mut rng), 3.0);
+    }
+}
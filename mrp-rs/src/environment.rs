@@ -1,12 +1,69 @@
-use std::collections::HashMap;
+use std::cell::{Cell, OnceCell, Ref, RefCell};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs;
-use std::io::{self, Read};
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
+use std::rc::{Rc, Weak};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
+use serde::Serialize;
 use serde::de::DeserializeOwned;
 use serde_json::Value;
 
-use crate::csv::CsvWriter;
+use crate::MrpError;
+use crate::archive::ArchiveOutput;
+use crate::atomic::{self, AtomicFile, OpenFiles};
+use crate::builder::EnvironmentBuilder;
+use crate::calendar;
+use crate::cancel::CancelToken;
+use crate::csv::{CsvFilter, CsvOptions, CsvWriter, RUN_COLUMNS};
+use crate::dedup::{DEFAULT_WARNING_LIMIT, Dedup};
+use crate::defer::{self, Deferred};
+use crate::dist::DistributionSpec;
+use crate::fallback::{self, FallbackLog, FallbackWriter, SplitOutput, WriteFailurePolicy};
+use crate::file_refs;
+use crate::files_error::{self, FilesError};
+use crate::formats::{ErrorRecord, Metrics};
+use crate::framing::{self, Completion, CompletionStatus, FramedWriter};
+use crate::if_exists::{self, IfExists};
+use crate::input_error::{self, InputError};
+use crate::input_hash;
+use crate::input_sets::{self, RunSpec};
+use crate::interpolate;
+use crate::io_pool::{IoHandle, IoPool, IoPoolStats};
+use crate::jsonl::JsonlWriter;
+use crate::manifest::MRP_VERSION;
+use crate::matrix::Matrix;
+use crate::memory::{MemoryOutputs, MemoryWriter};
+use crate::object_store::ObjectOutput;
+use crate::params::{self, ParamCollector, ParamError, PmfOptions};
+use crate::provenance::{ProvenanceLog, SpanWriter};
+use crate::replicate::ReplicateContext;
+use crate::report::{self, REPORT, Report, ReportSection};
+#[cfg(feature = "rand")]
+use crate::rng::ModelRng;
+use crate::rng::RngKind;
+use crate::schema::OutputSchema;
+use crate::scratch::Scratch;
+use crate::seed::{RngStreams, derive_seed, entropy_seed};
+use crate::snapshot::{self, SnapshotIndex, SnapshotOptions};
+use crate::stream::{self, StreamStats, StreamWriter};
+use crate::tee::{SinkKind, Tee, TeeWriter};
+use crate::tempdir::{OutputTempDir, TempDirWriter};
+use crate::template::{self, TemplateError};
+use crate::throttle::{DEFAULT_PROGRESS_INTERVAL, Throttle};
+use crate::validate::{self, Validate};
+use crate::worker::{WorkerDest, WorkerEnv, WorkerOutputs, WorkerRecord};
+
+mod finalize;
+mod negative_control;
+mod objects;
+mod provenance;
+mod schema;
+mod scratch;
+
+pub use finalize::{FINALIZE_ORDER, FinalizeStage};
 
 pub struct Environment<I = ()> {
     pub input: Option<I>,
@@ -14,63 +71,592 @@ pub struct Environment<I = ()> {
     pub files: HashMap<String, PathBuf>,
     input_json: Value,
     output: Value,
-    csv_writers: HashMap<String, CsvWriter>,
+    /// The `output.profile` entry in use; `None` for a flat `output`.
+    output_profile: Option<String>,
+    output_dir: Option<PathBuf>,
+    /// `output_dir`, once [`Environment::ensure_output_dir`] has created it.
+    output_dir_ready: OnceCell<PathBuf>,
+    /// Other directories writes have created, such as subdirectories.
+    created_dirs: RefCell<HashSet<PathBuf>>,
+    /// The directory created for the `"tempdir"` spec, shared with the
+    /// writers opened in it.
+    output_tempdir: Option<Rc<OutputTempDir>>,
+    /// Whether files are written under a temp name and renamed into place,
+    /// from the spec's `"atomic"`.
+    atomic: bool,
+    /// What to do about files already in the output directory.
+    if_exists: IfExists,
+    /// Whether outputs go to `memory`, under the `"memory"` spec.
+    in_memory: bool,
+    memory: MemoryOutputs,
+    /// Where outputs go under an object-store spec: `"s3"`, `"gs"` or
+    /// `"azure"`.
+    objects: Option<ObjectOutput>,
+    archive: Option<Rc<ArchiveOutput>>,
+    /// The other sinks of a `"multi"` spec, written alongside the first.
+    tee: Option<Tee>,
+    csv_writers: CsvWriters,
+    /// Atomic files not yet renamed into place, for [`Environment::try_close_csv`].
+    atomic_files: OpenFiles,
+    csv_options: CsvOptions,
+    csv_filters: BTreeMap<String, CsvFilter>,
+    output_schemas: BTreeMap<String, OutputSchema>,
+    strict_outputs: bool,
+    produced: RefCell<BTreeSet<String>>,
+    rows_written: Rc<Cell<u64>>,
+    // Before `scratch`, so cleanups run before it is removed on drop
+    deferred: RefCell<Deferred>,
+    scratch: Scratch,
+    provenance: Rc<RefCell<ProvenanceLog>>,
+    metrics: RefCell<BTreeMap<String, f64>>,
+    metrics_throttle: Throttle,
+    progress: Throttle,
+    stdout: Rc<StreamStats>,
+    max_stall: Option<Duration>,
+    /// Whether stdout carries the framed protocol of [`crate::framing`],
+    /// from the stdout spec's `"framed"`.
+    framed: bool,
+    io_pool: Arc<IoPool>,
+    warnings: Rc<RefCell<Vec<Warning>>>,
+    warning_dedup: RefCell<Dedup>,
+    write_failure: WriteFailurePolicy,
+    split_outputs: Rc<RefCell<Vec<SplitOutput>>>,
+    workers: Vec<Arc<Mutex<WorkerRecord>>>,
+    tags: BTreeMap<String, String>,
+    input_overrides: Vec<InputOverride>,
+    rng_streams: RngStreams,
+    rng_labels: RefCell<BTreeSet<String>>,
+    rng_kind: RngKind,
+    replicate_count: Option<u64>,
+    seed_table: Option<Vec<u64>>,
+    seed_generated: bool,
+    /// Set while the `missing_seed` warning is owed: the payload had no
+    /// seed, and defaults may yet supply one.
+    missing_seed: Cell<bool>,
+    report: bool,
+    manifest: bool,
+    /// When the environment was built, for the manifest's duration.
+    started: Instant,
+    keep_resolved_input: bool,
+    lenient: bool,
+    report_sections: RefCell<Vec<Box<dyn ReportSection>>>,
+    redact: Vec<String>,
+    cancel: CancelToken,
+    payload: Value,
+}
+
+/// Writers made by [`Environment::create_csv`], by ID. Shared so a panic
+/// hook can flush them.
+type CsvWriters = Rc<RefCell<HashMap<String, CsvWriter>>>;
+
+/// Options for [`Environment::from_json_strict`] and
+/// [`Environment::from_stdin_strict`].
+#[derive(Debug, Clone, Default)]
+pub struct StrictOptions {
+    /// Top-level keys to accept besides those MRP reads, such as a runner's
+    /// own bookkeeping.
+    pub allow_keys: Vec<String>,
+}
+
+/// How a payload's text is written, for [`Environment::from_stdin_any`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadFormat {
+    Json,
+    /// Parsed as an MRP config file is: datetimes become strings.
+    Toml,
+    /// The document must be a mapping; keys become strings.
+    Yaml,
+}
+
+/// What the model binary was asked to do, from `MRP_MODE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Read the payload and run; `MRP_MODE` unset or `run`.
+    Run,
+    /// Print the input's JSON Schema and exit, without reading stdin; see
+    /// `exit_with_schema_if_requested` under the `schema` feature.
+    Schema,
+}
+
+/// A change applied to the payload's input before the model saw it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct InputOverride {
+    /// Dotted path into the input, e.g. `observation.delay`.
+    pub path: String,
+    /// The payload's value, if the key was present.
+    pub previous: Option<Value>,
+    pub value: Value,
+    /// What applied the override (e.g. `negative_control`).
+    pub source: String,
+}
+
+/// A structured warning recorded during a run.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Warning {
+    pub code: String,
+    pub message: String,
+    /// [`MrpError::code`] of the failure behind the warning, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<String>,
+}
+
+impl Warning {
+    /// A warning for a failed write or upload the run recovered from.
+    pub(crate) fn output_failure(code: &str, message: String) -> Self {
+        Warning {
+            code: code.to_string(),
+            message,
+            error_code: Some(MrpError::Output(String::new()).code().to_string()),
+        }
+    }
 }
 
 impl Environment<()> {
     /// Create an empty environment.
     pub fn new() -> Self {
-        Environment {
-            input: None,
-            replicate: 0,
-            files: HashMap::new(),
-            input_json: Value::Null,
-            output: Value::Object(Default::default()),
-            csv_writers: HashMap::new(),
-        }
+        Self::build(Value::Null)
+    }
+
+    /// Start building an environment from typed settings rather than a
+    /// payload, e.g. `Environment::builder().seed(42).param("r0", 2.0)`.
+    pub fn builder() -> EnvironmentBuilder {
+        EnvironmentBuilder::default()
+    }
+
+    /// The mode `MRP_MODE` asks for. Check it before reading stdin, since a
+    /// runner asking for the schema sends no payload.
+    pub fn mode() -> Result<Mode, MrpError> {
+        parse_mode(std::env::var(MODE_VAR).ok().as_deref())
     }
 
     /// Create from a parsed JSON value.
+    ///
+    /// Exactly when [`Environment::try_from_json`] returns an error, prints
+    /// it to stderr as `error: {e}` and exits with [`PAYLOAD_EXIT_CODE`].
+    /// The other infallible constructors keep the same contract with their
+    /// `try_` forms.
     pub fn from_json(data: Value) -> Self {
         Self::build(data)
     }
 
-    /// Read JSON from stdin.
+    /// Read a payload from stdin: JSON, else YAML. `MRP_INPUT_FORMAT` set
+    /// to `json`, `yaml` or `toml` reads only that format.
     pub fn from_stdin() -> Self {
-        let data = read_stdin();
-        Self::build(data)
+        exit_on_error(Self::try_from_stdin())
     }
 
-    /// Read JSON or TOML from a file.
-    pub fn from_file(path: &Path) -> Self {
-        let data = read_file(path);
-        Self::build(data)
+    /// Read JSON, or TOML or YAML by extension, from a file, such as a
+    /// payload saved from a runner's logs. The payload is then read exactly
+    /// as [`Environment::from_stdin`] reads one.
+    pub fn from_file(path: impl AsRef<Path>) -> Self {
+        exit_on_error(Self::try_from_file(path))
+    }
+
+    /// Like [`Environment::from_stdin`], but returns read, parse and payload
+    /// errors instead of exiting.
+    pub fn try_from_stdin() -> Result<Self, MrpError> {
+        Self::try_build(read_stdin()?)
+    }
+
+    /// Read a JSON or YAML payload from any reader, such as an in-memory
+    /// pipe, as [`Environment::from_stdin`] reads stdin. Unlike stdin, a
+    /// blank reader is an error rather than an empty payload.
+    pub fn from_reader<R: Read>(reader: R) -> Self {
+        exit_on_error(Self::try_from_reader(reader))
+    }
+
+    pub fn try_from_reader<R: Read>(reader: R) -> Result<Self, MrpError> {
+        Self::try_build(read_payload(reader, "payload reader")?)
+    }
+
+    /// Like [`Environment::from_file`], but returns read, parse and payload
+    /// errors instead of exiting.
+    pub fn try_from_file(path: impl AsRef<Path>) -> Result<Self, MrpError> {
+        let path = path.as_ref();
+        let payload_dir = std::path::absolute(path)
+            .ok()
+            .and_then(|path| path.parent().map(Path::to_path_buf));
+        Self::try_build_in(read_file(path)?, payload_dir.as_deref())
+    }
+
+    /// Load a snapshot written by [`Environment::snapshot`] for local replay.
+    ///
+    /// Copied input files resolve inside the snapshot, and filesystem output
+    /// goes to its `output` directory rather than the original run's.
+    pub fn from_snapshot(dir: &Path) -> Self {
+        exit_on_error(Self::try_from_snapshot(dir))
+    }
+
+    pub fn try_from_snapshot(dir: &Path) -> Result<Self, MrpError> {
+        Self::try_build(snapshot::read(dir)?)
+    }
+
+    /// Like [`Environment::from_json`], but returns payload errors instead of
+    /// panicking.
+    pub fn try_from_json(data: Value) -> Result<Self, MrpError> {
+        Self::try_build(data)
+    }
+
+    /// Create from a TOML document, such as a model's `.mrp.toml` while
+    /// debugging. Tables become objects and datetimes become strings; the
+    /// payload is then read as a JSON one is.
+    pub fn from_toml_str(text: &str) -> Self {
+        exit_on_error(Self::try_from_toml_str(text))
+    }
+
+    pub fn try_from_toml_str(text: &str) -> Result<Self, MrpError> {
+        Self::try_build(parse_toml(text, "TOML payload")?)
+    }
+
+    /// Create from a YAML document, such as a model config authored in
+    /// YAML. The payload is then read as a JSON one is.
+    pub fn from_yaml_str(text: &str) -> Self {
+        exit_on_error(Self::try_from_yaml_str(text))
+    }
+
+    pub fn try_from_yaml_str(text: &str) -> Result<Self, MrpError> {
+        Self::try_build(parse_yaml(text, "YAML payload")?)
+    }
+
+    /// Read a payload in `format` from stdin.
+    pub fn from_stdin_any(format: PayloadFormat) -> Self {
+        exit_on_error(Self::try_from_stdin_any(format))
+    }
+
+    pub fn try_from_stdin_any(format: PayloadFormat) -> Result<Self, MrpError> {
+        let mut text = String::new();
+        io::stdin()
+            .lock()
+            .read_to_string(&mut text)
+            .map_err(|e| MrpError::Input(format!("failed to read payload from stdin: {e}")))?;
+        Self::try_build(parse_as(&text, format, "stdin")?)
+    }
+
+    /// Like [`Environment::from_json`], but checking the payload's keys as
+    /// `options` say.
+    pub fn from_json_strict(data: Value, options: &StrictOptions) -> Self {
+        exit_on_error(Self::try_from_json_strict(data, options))
+    }
+
+    pub fn try_from_json_strict(data: Value, options: &StrictOptions) -> Result<Self, MrpError> {
+        Self::try_build_strict(data, options)
+    }
+
+    /// Like [`Environment::from_stdin`], but checking the payload's keys as
+    /// `options` say.
+    pub fn from_stdin_strict(options: &StrictOptions) -> Self {
+        exit_on_error(Self::try_from_stdin_strict(options))
+    }
+
+    pub fn try_from_stdin_strict(options: &StrictOptions) -> Result<Self, MrpError> {
+        Self::try_build_strict(read_stdin()?, options)
     }
 
     fn build(data: Value) -> Self {
-        let (replicate, files, input_json, output) = extract_common(&data);
-        Environment {
+        exit_on_error(Self::try_build(data))
+    }
+
+    /// An unknown top-level key is an error; an unknown key in an output
+    /// spec is a warning, as deployments add their own.
+    fn try_build_strict(data: Value, options: &StrictOptions) -> Result<Self, MrpError> {
+        let unknown: Vec<String> = data
+            .as_object()
+            .into_iter()
+            .flat_map(|m| m.keys())
+            .filter(|k| !PAYLOAD_KEYS.contains(&k.as_str()) && !options.allow_keys.contains(k))
+            .map(|k| format!("{k:?}"))
+            .collect();
+        if !unknown.is_empty() {
+            return Err(MrpError::Input(format!(
+                "unknown payload keys {} (expected one of {})",
+                unknown.join(", "),
+                PAYLOAD_KEYS.join(", ")
+            )));
+        }
+        let env = Self::try_build(data)?;
+        for (path, key) in unknown_output_keys(&env.output) {
+            env.warn(
+                "unknown_output_key",
+                &format!("unknown key {key:?} in {path} was ignored"),
+            );
+        }
+        Ok(env)
+    }
+
+    fn try_build(data: Value) -> Result<Self, MrpError> {
+        Self::try_build_in(data, None)
+    }
+
+    /// Build from `data`, read from a file in `payload_dir` if it was.
+    fn try_build_in(mut data: Value, payload_dir: Option<&Path>) -> Result<Self, MrpError> {
+        if json_depth(&data) > MAX_PAYLOAD_DEPTH {
+            drop_iteratively(data);
+            return Err(MrpError::Input(format!(
+                "payload nests deeper than {MAX_PAYLOAD_DEPTH} levels"
+            )));
+        }
+        interpolate::apply(&mut data)?;
+        if file_refs::resolve(&mut data)? && json_depth(&data) > MAX_PAYLOAD_DEPTH {
+            return Err(MrpError::Input(format!(
+                "payload nests deeper than {MAX_PAYLOAD_DEPTH} levels once $file values are read"
+            )));
+        }
+        let started = SystemTime::now();
+        let (replicate, files, mut input_json, mut output, warnings) = extract_common(&data)?;
+        resolve_output_dirs(&mut output, &DirBases::from_env(payload_dir, None))?;
+        let seed = resolve_seed(&mut input_json, replicate)?;
+        let mut tags = BTreeMap::new();
+        if seed.generated {
+            tags.insert("generated_seed".to_string(), input_json["seed"].to_string());
+        }
+        let missing_seed = seed.missing
+            && !data.is_null()
+            && data.get("allow_missing_seed").and_then(|v| v.as_bool()) != Some(true);
+        let mut metrics = BTreeMap::new();
+        let mut input_overrides = Vec::new();
+        let output_profile = select_profile(&output, std::env::var(OUTPUT_PROFILE_VAR).ok())?;
+        let spec = output_spec(
+            &output,
+            output_profile.as_ref().map(|(name, _)| name.as_str()),
+        );
+        let first = spec.map(first_sink).transpose()?;
+        let output_tempdir = first.map(create_output_tempdir).transpose()?.flatten();
+        let output_dir = match &output_tempdir {
+            Some(tempdir) => {
+                eprintln!("{}", tempdir.announcement());
+                Some(tempdir.path().to_path_buf())
+            }
+            None => first.map(resolve_output_dir).transpose()?.flatten(),
+        };
+        let output_dir = match (first, output_dir) {
+            (Some(spec), Some(dir)) => Some(scope_output_dir(spec, dir, replicate)?),
+            (_, dir) => dir,
+        };
+        let in_memory = first.and_then(|spec| spec.get("spec")) == Some(&Value::from("memory"));
+        let atomic = match first.and_then(|spec| spec.get("atomic")) {
+            None => false,
+            Some(Value::Bool(atomic)) => *atomic,
+            Some(other) => {
+                return Err(MrpError::Config(format!(
+                    "output \"atomic\" must be true or false, got {other}"
+                )));
+            }
+        };
+        let framed = match first.and_then(|spec| spec.get("framed")) {
+            None => false,
+            Some(Value::Bool(framed)) => *framed,
+            Some(other) => {
+                return Err(MrpError::Config(format!(
+                    "output \"framed\" must be true or false, got {other}"
+                )));
+            }
+        };
+        if framed
+            && first
+                .and_then(|spec| spec.get("spec"))
+                .and_then(|v| v.as_str())
+                != Some("stdout")
+        {
+            return Err(MrpError::Config(
+                "output \"framed\" applies only to the stdout spec".to_string(),
+            ));
+        }
+        if let Some((name, source)) = &output_profile {
+            let dest = match &output_dir {
+                Some(dir) => dir.display().to_string(),
+                None => "no output directory".to_string(),
+            };
+            eprintln!("output profile '{name}' ({source}): {dest}");
+        }
+        let output_profile = output_profile.map(|(name, _)| name);
+        let if_exists = first
+            .map(IfExists::from_spec)
+            .transpose()?
+            .unwrap_or_default();
+        let write_failure = spec
+            .map(WriteFailurePolicy::from_spec)
+            .transpose()?
+            .unwrap_or_default();
+        let csv_filters = spec
+            .map(CsvFilter::from_spec)
+            .transpose()?
+            .unwrap_or_default();
+        let progress_interval = data
+            .get("progress_interval_ms")
+            .and_then(|v| v.as_u64())
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_PROGRESS_INTERVAL);
+        let max_stall = data
+            .get("max_stall_seconds")
+            .and_then(|v| v.as_f64())
+            .filter(|s| s.is_finite() && *s >= 0.0)
+            .map(Duration::from_secs_f64);
+        let io_pool = IoPool::configured(data.get("io_threads").and_then(|v| v.as_u64()))?;
+        let max_run = data
+            .get("max_run_seconds")
+            .and_then(|v| v.as_f64())
+            .filter(|s| s.is_finite() && *s >= 0.0)
+            .map(Duration::from_secs_f64);
+        let cancel_on_sigterm = data
+            .get("cancel_on_sigterm")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        let warning_limit = data
+            .get("warning_limit")
+            .and_then(|v| v.as_u64())
+            .unwrap_or(DEFAULT_WARNING_LIMIT);
+        let rng_streams = data
+            .get("rng_streams")
+            .map(RngStreams::from_payload)
+            .transpose()?
+            .unwrap_or_default();
+        let rng_kind = data
+            .get("rng")
+            .map(RngKind::from_payload)
+            .transpose()?
+            .unwrap_or_default();
+        let replicate_count = data.get("replicates").map(parse_replicates).transpose()?;
+        let report = data
+            .get("report")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        let manifest = data
+            .get("manifest")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        // A generated seed is always recorded, so the run can be repeated
+        let keep_resolved_input = seed.generated
+            || data
+                .get("resolved_input")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false);
+        let redact = data
+            .get("redact")
+            .and_then(|v| v.as_array())
+            .map(|paths| {
+                paths
+                    .iter()
+                    .filter_map(|p| p.as_str().map(String::from))
+                    .collect()
+            })
+            .unwrap_or_default();
+        if let Some(applied) = negative_control::apply(&data, &mut input_json)? {
+            input_overrides.push(applied);
+            tags.insert("negative_control".to_string(), "true".to_string());
+            metrics.insert("negative_control".to_string(), 1.0);
+        }
+        let mut env = Environment {
             input: None,
             replicate,
             files,
             input_json,
             output,
-            csv_writers: HashMap::new(),
+            output_profile,
+            output_dir,
+            output_dir_ready: OnceCell::new(),
+            created_dirs: RefCell::default(),
+            output_tempdir,
+            atomic,
+            if_exists,
+            in_memory,
+            memory: MemoryOutputs::default(),
+            objects: None,
+            archive: None,
+            tee: None,
+            csv_writers: CsvWriters::default(),
+            atomic_files: OpenFiles::default(),
+            csv_options: CsvOptions::default(),
+            csv_filters,
+            output_schemas: BTreeMap::new(),
+            strict_outputs: false,
+            produced: RefCell::new(BTreeSet::new()),
+            rows_written: Rc::new(Cell::new(0)),
+            scratch: Scratch::new(
+                data.get("scratch_dir")
+                    .and_then(|v| v.as_str())
+                    .map(PathBuf::from),
+                data.get("keep_scratch")
+                    .and_then(|v| v.as_bool())
+                    .unwrap_or(false),
+            ),
+            deferred: RefCell::new(Deferred::default()),
+            provenance: Rc::default(),
+            metrics: RefCell::new(metrics),
+            metrics_throttle: Throttle::new(progress_interval),
+            progress: Throttle::new(progress_interval),
+            stdout: Rc::new(StreamStats::new()),
+            max_stall,
+            framed,
+            io_pool: Arc::new(io_pool),
+            warnings: Rc::new(RefCell::new(Vec::new())),
+            warning_dedup: RefCell::new(Dedup::new(warning_limit)),
+            write_failure,
+            split_outputs: Rc::new(RefCell::new(Vec::new())),
+            workers: Vec::new(),
+            tags,
+            input_overrides,
+            rng_streams,
+            rng_labels: RefCell::new(BTreeSet::new()),
+            rng_kind,
+            replicate_count,
+            seed_table: seed.table,
+            seed_generated: seed.generated,
+            missing_seed: Cell::new(missing_seed),
+            report,
+            manifest,
+            started: Instant::now(),
+            keep_resolved_input,
+            lenient: false,
+            report_sections: RefCell::new(Vec::new()),
+            redact,
+            cancel: CancelToken::watching(max_run, cancel_on_sigterm),
+            payload: Value::Null,
+        };
+        for warning in warnings {
+            env.warn(&warning.code, &warning.message);
+        }
+        let templated = env.output_dir.as_ref();
+        if templated.is_some_and(|dir| dir.to_string_lossy().contains(['{', '}'])) {
+            let profile = env.output_profile.as_deref().unwrap_or("default");
+            env.output_dir = env.output_dir_for(profile)?;
+        }
+        if let Some(dir) = env.output_dir.as_deref().filter(|_| env.atomic) {
+            for path in atomic::remove_stale(dir, started) {
+                eprintln!("removed stale temp file {}", path.display());
+            }
         }
+        env.objects = env.object_output()?;
+        env.archive = env.archive_output()?;
+        env.tee = env.tee()?;
+        env.payload = data;
+        Ok(env)
     }
 }
 
 impl<I: DeserializeOwned> Environment<I> {
     /// Read JSON from stdin and deserialize input into a typed struct.
     pub fn from_stdin_typed() -> Self {
-        let data = read_stdin();
-        Self::build_typed(data)
+        exit_on_error(read_stdin().and_then(Self::try_build_typed))
     }
 
     /// Read JSON or TOML from a file and deserialize input.
-    pub fn from_file_typed(path: &Path) -> Self {
-        let data = read_file(path);
-        Self::build_typed(data)
+    pub fn from_file_typed(path: impl AsRef<Path>) -> Self {
+        exit_on_error(Self::load_from_file(path))
+    }
+
+    /// Like [`Environment::from_file_typed`], but returns read, parse,
+    /// payload and input errors instead of exiting.
+    pub fn load_from_file(path: impl AsRef<Path>) -> Result<Self, MrpError> {
+        Ok(Environment::try_from_file(path)?.try_with_input_type()?)
+    }
+
+    /// Like [`Environment::load_from_file`], then run the input's
+    /// [`Validate`] checks.
+    pub fn load_validated(path: impl AsRef<Path>) -> Result<Self, MrpError>
+    where
+        I: Validate,
+    {
+        Environment::try_from_file(path)?.try_with_input_type_validated()
     }
 
     /// Create from parsed JSON and deserialize input.
@@ -79,82 +665,597 @@ impl<I: DeserializeOwned> Environment<I> {
     }
 
     fn build_typed(data: Value) -> Self {
-        let (replicate, files, input_json, output) = extract_common(&data);
-        let input = if input_json.is_null() || input_json.as_object().is_some_and(|m| m.is_empty())
-        {
-            None
-        } else {
-            Some(serde_json::from_value::<I>(input_json.clone()).expect("failed to parse input"))
+        exit_on_error(Self::try_build_typed(data))
+    }
+
+    fn try_build_typed(data: Value) -> Result<Self, MrpError> {
+        Ok(Environment::try_build(data)?.try_with_input_type()?)
+    }
+}
+
+impl<I: Serialize> Environment<I> {
+    /// Dotted paths of input keys the typed input did not read, such as a
+    /// misspelled `generation_interval` that left a defaulted
+    /// `generation_interval_pmf`. Found by serializing the input back and
+    /// comparing keys, so a renamed or skipped field counts as unused.
+    pub fn unused_input_keys(&self) -> Vec<String> {
+        let Some(input) = &self.input else {
+            return Vec::new();
         };
-        Environment {
-            input,
-            replicate,
-            files,
-            input_json,
-            output,
-            csv_writers: HashMap::new(),
+        let used = serde_json::to_value(input).unwrap_or(Value::Null);
+        params::unused_keys(&self.input_json, &used)
+    }
+
+    /// Record an `unused_input_key` warning for each of
+    /// [`Environment::unused_input_keys`].
+    pub fn warn_unused_input(&self) {
+        for key in self.unused_input_keys() {
+            self.warn(
+                "unused_input_key",
+                &format!("input key '{key}' is not read by the model"),
+            );
+        }
+    }
+
+    /// Fail if the typed input left any input key unread.
+    pub fn strict_input(self) -> Result<Self, MrpError> {
+        let unused = self.unused_input_keys();
+        if unused.is_empty() {
+            return Ok(self);
         }
+        Err(MrpError::Input(format!(
+            "input keys not read by the model: {}",
+            unused.join(", ")
+        )))
     }
 }
 
 impl Environment<()> {
+    /// Set input values, e.g. from [`crate::cli::parse_set_args`], before
+    /// [`Environment::with_input_type`] reads them. Keys may be dotted paths
+    /// into nested objects, which are created as needed. Each value is
+    /// parsed as JSON, or taken as a string if it is not JSON, so `1.5`,
+    /// `true` and `[1, 2]` keep their types and `fast` needs no quotes.
+    /// Overrides are listed in [`Environment::input_overrides`].
+    pub fn apply_overrides(&mut self, overrides: &[(String, String)]) -> Result<(), MrpError> {
+        for (key, raw) in overrides {
+            if key.split('.').any(str::is_empty) || key.split('.').count() > MAX_PAYLOAD_DEPTH {
+                return Err(MrpError::Input(format!("invalid override key '{key}'")));
+            }
+            let mut value =
+                serde_json::from_str(raw).unwrap_or_else(|_| Value::String(raw.clone()));
+            match key.as_str() {
+                "replicate" => {
+                    let replicate = parse_index("replicate", &value)?;
+                    self.input_overrides.push(InputOverride {
+                        path: key.clone(),
+                        previous: Some(self.replicate.into()),
+                        value: replicate.into(),
+                        source: "cli".to_string(),
+                    });
+                    self.replicate = replicate;
+                    self.reselect_table_seed()?;
+                    continue;
+                }
+                "seed" => {
+                    let (seed, table) = parse_seed(&value, self.replicate)?;
+                    value = seed.into();
+                    self.seed_table = table;
+                }
+                _ => {}
+            }
+            let applied = set_input_path(&mut self.input_json, key, value, "cli")?;
+            self.input_overrides.push(applied);
+        }
+        Ok(())
+    }
+
+    /// Fill keys the input does not set from `defaults`, recursing into
+    /// nested objects. Values from the payload always win, and a payload
+    /// array replaces a default one. A default `seed` or `replicate` is
+    /// used when the payload has none, parsed as the payload's would be.
+    ///
+    /// Invalid defaults are reported and exit with [`PAYLOAD_EXIT_CODE`],
+    /// as a bad payload is.
+    pub fn with_defaults(self, defaults: Value) -> Self {
+        exit_on_error(self.try_with_defaults(defaults))
+    }
+
+    pub fn try_with_defaults(mut self, defaults: Value) -> Result<Self, MrpError> {
+        self.merge_input_defaults(&defaults)?;
+        Ok(self)
+    }
+
+    /// Like [`Environment::with_defaults`], reading the defaults from a JSON
+    /// or TOML file such as a model's `defaults.json`.
+    pub fn with_defaults_file(self, path: impl AsRef<Path>) -> Self {
+        exit_on_error(self.try_with_defaults_file(path))
+    }
+
+    pub fn try_with_defaults_file(self, path: impl AsRef<Path>) -> Result<Self, MrpError> {
+        let defaults = read_file(path.as_ref())?;
+        self.try_with_defaults(defaults)
+    }
+
+    /// After the replicate changes, take its seed from the seed table.
+    fn reselect_table_seed(&mut self) -> Result<(), MrpError> {
+        if let Some(table) = &self.seed_table {
+            self.input_json["seed"] = table_seed(table, self.replicate)?.into();
+        }
+        Ok(())
+    }
+
+    fn merge_input_defaults(&mut self, defaults: &Value) -> Result<(), MrpError> {
+        let Some(defaults) = defaults.as_object() else {
+            return Err(MrpError::Input(format!(
+                "input defaults must be an object, got {defaults}"
+            )));
+        };
+        let mut defaults = defaults.clone();
+        if let Some(replicate) = defaults.remove("replicate") {
+            let given = self
+                .payload
+                .get("input")
+                .is_some_and(|input| input.get("replicate").is_some())
+                || self.input_overrides.iter().any(|o| o.path == "replicate");
+            if !given {
+                self.replicate = parse_index("replicate", &replicate)?;
+                self.reselect_table_seed()?;
+            }
+        }
+        if let Some(seed) = defaults.get_mut("seed") {
+            let (value, table) = parse_seed(seed, self.replicate)?;
+            *seed = value.into();
+            if self.input_json.get("seed").is_none() {
+                self.seed_table = table;
+            }
+        }
+        if self.input_json.is_null() {
+            self.input_json = Value::Object(Default::default());
+        }
+        merge_defaults(&mut self.input_json, &Value::Object(defaults));
+        Ok(())
+    }
+
+    /// Have [`Environment::with_input_type`] read numbers written as strings,
+    /// such as `"r0": "2.0"` from R or a spreadsheet, where the input type
+    /// wants a number. Strings the type accepts are left alone. Each
+    /// coerced value is recorded as a `coerced_input` warning. Off by
+    /// default, so a mistyped value fails the run.
+    pub fn lenient(mut self) -> Self {
+        self.lenient = true;
+        self
+    }
+
+    fn coerce_input<I: DeserializeOwned>(&mut self) {
+        // Coerce with `replicate` for types that declare it, then without
+        // for those that reject it
+        let mut resolved = self.resolved_input();
+        let mut coerced = input_error::coerce_numbers::<I>(&mut resolved);
+        for path in &coerced {
+            if let (Some(value), Some(target)) = (
+                input_error::lookup(&resolved, path),
+                input_error::lookup_mut(&mut self.input_json, path),
+            ) {
+                *target = value.clone();
+            }
+        }
+        coerced.extend(input_error::coerce_numbers::<I>(&mut self.input_json));
+        for path in coerced {
+            self.warn(
+                "coerced_input",
+                &format!("input.{path}: numeric string read as a number"),
+            );
+        }
+    }
+
     /// Convert an untyped environment into a typed one by deserializing input.
     pub fn with_input_type<I: DeserializeOwned>(self) -> Environment<I> {
+        self.try_with_input_type().unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Like [`Environment::with_input_type`], then run the input's
+    /// [`Validate`] checks, panicking with all of their failures.
+    pub fn with_input_type_validated<I: DeserializeOwned + Validate>(self) -> Environment<I> {
+        self.try_with_input_type_validated()
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Like [`Environment::with_input_type_validated`], but returns
+    /// deserialization and validation errors instead of panicking.
+    pub fn try_with_input_type_validated<I: DeserializeOwned + Validate>(
+        self,
+    ) -> Result<Environment<I>, MrpError> {
+        let env = self.try_with_input_type::<I>()?;
+        validate::check(env.input.as_ref())?;
+        Ok(env)
+    }
+
+    /// Like [`Environment::with_input_type`], but returns an error if the
+    /// input does not deserialize.
+    pub fn try_with_input_type<I: DeserializeOwned>(
+        mut self,
+    ) -> Result<Environment<I>, InputError> {
+        if self.lenient {
+            self.coerce_input::<I>();
+        }
         let input = if self.input_json.is_null()
             || self.input_json.as_object().is_some_and(|m| m.is_empty())
         {
             None
         } else {
-            Some(
-                serde_json::from_value::<I>(self.input_json.clone())
-                    .expect("failed to parse input"),
-            )
+            // `replicate` is taken out of the input; put it back for types
+            // that declare it, but not for those that reject unknown fields
+            match input_error::deserialize(&self.resolved_input()) {
+                Ok(input) => Some(input),
+                Err(injected) => Some(
+                    input_error::deserialize(&self.input_json)
+                        .map_err(|e| e.or_injected(injected))?,
+                ),
+            }
         };
+        Ok(self.with_input(input))
+    }
+}
+
+impl<I> Environment<I> {
+    /// This environment with `input` as its typed input.
+    fn with_input<J>(self, input: Option<J>) -> Environment<J> {
         Environment {
             input,
             replicate: self.replicate,
             files: self.files,
             input_json: self.input_json,
             output: self.output,
+            output_profile: self.output_profile,
+            output_dir: self.output_dir,
+            output_dir_ready: self.output_dir_ready,
+            created_dirs: self.created_dirs,
+            output_tempdir: self.output_tempdir,
+            atomic: self.atomic,
+            if_exists: self.if_exists,
+            in_memory: self.in_memory,
+            memory: self.memory,
+            objects: self.objects,
+            archive: self.archive,
+            tee: self.tee,
             csv_writers: self.csv_writers,
+            atomic_files: self.atomic_files,
+            csv_options: self.csv_options,
+            csv_filters: self.csv_filters,
+            output_schemas: self.output_schemas,
+            strict_outputs: self.strict_outputs,
+            produced: self.produced,
+            rows_written: self.rows_written,
+            scratch: self.scratch,
+            deferred: self.deferred,
+            provenance: self.provenance,
+            metrics: self.metrics,
+            metrics_throttle: self.metrics_throttle,
+            progress: self.progress,
+            stdout: self.stdout,
+            max_stall: self.max_stall,
+            framed: self.framed,
+            io_pool: self.io_pool,
+            warnings: self.warnings,
+            warning_dedup: self.warning_dedup,
+            write_failure: self.write_failure,
+            split_outputs: self.split_outputs,
+            workers: self.workers,
+            tags: self.tags,
+            input_overrides: self.input_overrides,
+            rng_streams: self.rng_streams,
+            rng_labels: self.rng_labels,
+            rng_kind: self.rng_kind,
+            replicate_count: self.replicate_count,
+            seed_table: self.seed_table,
+            seed_generated: self.seed_generated,
+            missing_seed: self.missing_seed,
+            report: self.report,
+            manifest: self.manifest,
+            started: self.started,
+            keep_resolved_input: self.keep_resolved_input,
+            lenient: self.lenient,
+            report_sections: self.report_sections,
+            redact: self.redact,
+            cancel: self.cancel,
+            payload: self.payload,
         }
     }
-}
 
-impl<I> Environment<I> {
-    /// Get the output directory, if configured as filesystem output.
+    /// The `[input]` table as JSON, after any overrides and without
+    /// `replicate`.
+    pub fn input_json(&self) -> &Value {
+        &self.input_json
+    }
+
+    /// The parameter sets of a batch payload, whose `"input_sets"` array
+    /// runs several sets in one process. Each set is laid over `input`, its
+    /// keys winning, and gets its own seed, replicate (else the payload's)
+    /// and typed input. A payload without `"input_sets"` gives its one
+    /// input. Write each set's outputs under [`RunSpec::output_name`].
+    pub fn input_sets<T: DeserializeOwned>(&self) -> Result<Vec<RunSpec<T>>, MrpError> {
+        input_sets::parse(
+            &self.input_json,
+            self.payload.get("input_sets"),
+            self.replicate,
+        )
+    }
+
+    /// Hex SHA-256 of the input, including `seed` and `replicate`. Keys are
+    /// sorted and integral floats written as integers first, so key order
+    /// and `2` versus `2.0` do not change it.
+    pub fn input_hash(&self) -> String {
+        input_hash::hash(&self.resolved_input())
+    }
+
+    /// Like [`Environment::input_hash`], but without `seed` and
+    /// `replicate`, so replicates of one parameter set share it.
+    pub fn parameter_hash(&self) -> String {
+        let mut input = self.resolved_input();
+        if let Some(input) = input.as_object_mut() {
+            input.remove("seed");
+            input.remove("replicate");
+        }
+        input_hash::hash(&input)
+    }
+
+    /// Whether the output directory already has `pattern` with `{hash}`
+    /// replaced by [`Environment::input_hash`], e.g. a replicate's
+    /// `"output_{hash}.csv"` from an earlier run, so it can be skipped.
+    /// Always false without filesystem output.
+    pub fn output_exists_for_hash(&self, pattern: &str) -> bool {
+        let Some(dir) = &self.output_dir else {
+            return false;
+        };
+        dir.join(pattern.replace("{hash}", &self.input_hash()))
+            .is_file()
+    }
+
+    /// Get the output directory, if configured as filesystem output. With
+    /// profiles, this is the one selected by `output.selected`, else
+    /// `MRP_OUTPUT_PROFILE`, else `default`.
+    ///
+    /// A symlinked directory is returned resolved to its target.
     pub fn output_dir(&self) -> Option<PathBuf> {
-        let output = &self.output;
-        // Flat output
-        if output.get("spec").and_then(|v| v.as_str()) == Some("filesystem") {
-            return output
-                .get("dir")
-                .and_then(|v| v.as_str())
-                .map(PathBuf::from);
-        }
-        // Profiled output
-        if let Some(profiles) = output.get("profile").and_then(|v| v.as_object()) {
-            let selected = profiles.get("default").or_else(|| profiles.values().next());
-            if let Some(prof) = selected {
-                if prof.get("spec").and_then(|v| v.as_str()) == Some("filesystem") {
-                    return prof.get("dir").and_then(|v| v.as_str()).map(PathBuf::from);
-                }
+        self.output_dir.clone()
+    }
+
+    /// Resolve relative output directories against `base` rather than the
+    /// working directory or the payload's directory, for embedders that
+    /// keep payloads apart from where their output belongs.
+    pub fn set_output_base(&mut self, base: impl AsRef<Path>) {
+        self.try_set_output_base(base)
+            .unwrap_or_else(|e| panic!("{e}"));
+    }
+
+    pub fn try_set_output_base(&mut self, base: impl AsRef<Path>) -> Result<(), MrpError> {
+        let base = std::path::absolute(base.as_ref()).map_err(|e| {
+            MrpError::Config(format!(
+                "output base '{}' cannot be resolved: {e}",
+                base.as_ref().display()
+            ))
+        })?;
+        let Some(mut output) = self
+            .payload
+            .get("output")
+            .filter(|v| v.is_object())
+            .cloned()
+        else {
+            return Ok(());
+        };
+        resolve_output_dirs(&mut output, &DirBases::from_env(None, Some(&base)))?;
+        self.output = output;
+        if self.output_tempdir.is_none() {
+            let profile = self.output_profile.as_deref().unwrap_or("default");
+            self.output_dir = self.output_dir_for(profile)?;
+        }
+        self.output_dir_ready = OnceCell::new();
+        self.created_dirs.borrow_mut().clear();
+        if self.tee.is_some() {
+            self.tee = self.tee()?;
+        }
+        if let Some(archive) = self.archive.take() {
+            archive.abandon();
+            self.archive = self.archive_output()?;
+        }
+        Ok(())
+    }
+
+    /// Create the output directory if it is not there yet, once per
+    /// Environment, and return it. Writers go through this rather than
+    /// creating directories on every write, so a directory removed
+    /// mid-run makes later writes fail instead of being made again.
+    pub fn ensure_output_dir(&self) -> io::Result<&Path> {
+        if let Some(dir) = self.output_dir_ready.get() {
+            return Ok(dir);
+        }
+        let dir = self.output_dir.as_ref().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "no output directory is configured")
+        })?;
+        fs::create_dir_all(dir)?;
+        Ok(self.output_dir_ready.get_or_init(|| dir.clone()))
+    }
+
+    /// Create `dir` unless a write has already.
+    fn ensure_dir(&self, dir: &Path) -> io::Result<()> {
+        if Some(dir) == self.output_dir.as_deref() {
+            return self.ensure_output_dir().map(|_| ());
+        }
+        if self.created_dirs.borrow().contains(dir) {
+            return Ok(());
+        }
+        fs::create_dir_all(dir)?;
+        self.created_dirs.borrow_mut().insert(dir.to_path_buf());
+        Ok(())
+    }
+
+    /// Files written under the `"memory"` output spec, by filename; empty
+    /// for other specs. A CSV or JSON-lines writer's rows are here once it
+    /// is flushed or closed.
+    pub fn memory_outputs(&self) -> Ref<'_, HashMap<String, Vec<u8>>> {
+        self.memory.borrow()
+    }
+
+    /// A file of [`Environment::memory_outputs`] as text, with invalid
+    /// UTF-8 replaced.
+    pub fn memory_output_string(&self, filename: &str) -> Option<String> {
+        self.memory
+            .borrow()
+            .get(filename)
+            .map(|data| String::from_utf8_lossy(data).into_owned())
+    }
+
+    /// The payload's `output` table as given, profiles and all.
+    pub fn output(&self) -> &Value {
+        &self.output
+    }
+
+    /// Names of the payload's output profiles, sorted. A flat `output`
+    /// table is the one profile `default`.
+    pub fn output_profiles(&self) -> Vec<String> {
+        match self.output.get("profile").and_then(|v| v.as_object()) {
+            Some(profiles) if self.output.get("spec").is_none() => {
+                profiles.keys().cloned().collect()
             }
+            _ => vec!["default".to_string()],
+        }
+    }
+
+    /// The output directory of `profile`, resolved as
+    /// [`Environment::output_dir`] is; `None` if it is not filesystem
+    /// output. An unknown profile is an error naming those there are.
+    pub fn output_dir_for(&self, profile: &str) -> Result<Option<PathBuf>, MrpError> {
+        let spec = match self.output.get("profile").and_then(|v| v.as_object()) {
+            Some(profiles) if self.output.get("spec").is_none() => profiles.get(profile),
+            _ => Some(&self.output).filter(|_| profile == "default"),
+        };
+        let Some(spec) = spec else {
+            return Err(MrpError::Config(format!(
+                "unknown output profile '{profile}' (profiles: {})",
+                self.output_profiles().join(", ")
+            )));
+        };
+        let spec = first_sink(spec)?;
+        if spec.get("spec").and_then(|v| v.as_str()) == Some("tempdir") {
+            // Only the profile in use has its directory created
+            let in_use = self.output_profile.as_deref().unwrap_or("default") == profile;
+            return Ok(self.output_dir.clone().filter(|_| in_use));
+        }
+        resolve_output_dir(&self.expand_dir(spec, profile)?)?
+            .map(|dir| scope_output_dir(spec, dir, self.replicate))
+            .transpose()
+    }
+
+    /// Files whose contents were split between the output directory and the
+    /// `on_write_failure` fallback because a write failed mid-run.
+    pub fn split_outputs(&self) -> Vec<SplitOutput> {
+        self.split_outputs.borrow().clone()
+    }
+
+    fn fallback_log(&self) -> FallbackLog {
+        FallbackLog {
+            splits: self.split_outputs.clone(),
+            warnings: self.warnings.clone(),
         }
-        None
     }
 
     /// Write bytes to a file in the output directory, or to stdout.
     pub fn write(&self, filename: &str, data: &[u8]) {
-        if let Some(dir) = self.output_dir() {
-            fs::create_dir_all(&dir).expect("failed to create output dir");
-            fs::write(dir.join(filename), data).expect("failed to write file");
+        stream::panic_unless_stalled(self.try_write(filename, data));
+    }
+
+    /// Like [`Environment::write`], but returns IO errors, so a failed write
+    /// can be retried.
+    pub fn try_write(&self, filename: &str, data: &[u8]) -> Result<(), MrpError> {
+        self.try_write_as(&self.expand_template(filename)?, filename, data)
+    }
+
+    /// [`Environment::try_write`] to `filename`, checked against the
+    /// declared schema of `spec_name`.
+    pub(crate) fn try_write_as(
+        &self,
+        filename: &str,
+        spec_name: &str,
+        data: &[u8],
+    ) -> Result<(), MrpError> {
+        self.try_write_in(self.output_dir.as_deref(), filename, spec_name, data)
+    }
+
+    /// [`Environment::try_write_as`] into `dir`, or to stdout if `None`.
+    fn try_write_in(
+        &self,
+        dir: Option<&Path>,
+        filename: &str,
+        spec_name: &str,
+        data: &[u8],
+    ) -> Result<(), MrpError> {
+        self.try_record_output_as(filename, spec_name)?;
+        let first = self.try_write_sink(dir, filename, data);
+        self.try_write_tee(dir, filename, data, first)?;
+        self.finish_output(filename);
+        Ok(())
+    }
+
+    /// Write `filename` to `dir`, else the spec's destination.
+    fn try_write_sink(
+        &self,
+        dir: Option<&Path>,
+        filename: &str,
+        data: &[u8],
+    ) -> Result<(), MrpError> {
+        if let Some(dir) = dir {
+            let path = dir.join(filename);
+            if self.if_exists != IfExists::Overwrite && path.exists() {
+                return match self.if_exists {
+                    IfExists::Skip => {
+                        self.skip_output(filename, &path);
+                        Ok(())
+                    }
+                    policy => Err(if_exists::exists_error(filename, &path, policy)),
+                };
+            }
+            let parent = path.parent().unwrap_or(dir);
+            let result = self
+                .ensure_dir(parent)
+                .and_then(|_| self.write_file(&path, data));
+            match result {
+                Err(e)
+                    if fallback::is_degradable(&e)
+                        && self.write_failure != WriteFailurePolicy::Fail =>
+                {
+                    fallback::write_to_fallback(
+                        filename,
+                        &path,
+                        data,
+                        &self.write_failure,
+                        &self.fallback_log(),
+                        &e,
+                    )
+                    .map_err(|e| write_error(&format!("'{filename}' to fallback"), e))?;
+                }
+                result => result.map_err(|e| write_error(&format!("'{filename}'"), e))?,
+            }
+        } else if let Some(archive) = &self.archive {
+            archive.put(filename, data)?;
+        } else if let Some(objects) = &self.objects {
+            let result = objects.put(filename, data);
+            self.take_upload_warnings();
+            result?;
+        } else if self.in_memory {
+            self.memory
+                .borrow_mut()
+                .insert(filename.to_string(), data.to_vec());
         } else {
-            use std::io::Write;
-            io::stdout()
-                .write_all(data)
-                .expect("failed to write to stdout");
+            let mut stdout = self.stdout_writer();
+            match self.framed {
+                true => framing::write_file(&mut stdout, filename, data),
+                false => stdout.write_all(data),
+            }
+            .and_then(|_| io::stdout().flush())
+            .map_err(|e| write_error("to stdout", e))?;
         }
+        Ok(())
     }
 
     /// Write a string to a file in the output directory, or to stdout.
@@ -162,230 +1263,4786 @@ impl<I> Environment<I> {
         self.write(filename, data.as_bytes());
     }
 
+    pub fn try_write_str(&self, filename: &str, data: &str) -> Result<(), MrpError> {
+        self.try_write(filename, data.as_bytes())
+    }
+
+    /// [`Environment::write`] to output profile `profile`, e.g. small
+    /// summaries to one profile while bulk CSVs go to the default.
+    pub fn write_to(&self, profile: &str, filename: &str, data: &[u8]) {
+        self.try_write_to(profile, filename, data)
+            .unwrap_or_else(|e| panic!("{e}"));
+    }
+
+    pub fn try_write_to(&self, profile: &str, filename: &str, data: &[u8]) -> Result<(), MrpError> {
+        let dir = self.output_dir_for(profile)?;
+        let run = (self.seed(), self.replicate);
+        let name = self.expand_template_for(filename, run, Some(profile))?;
+        self.try_write_in(dir.as_deref(), &name, filename, data)
+    }
+
+    /// Options for CSV writers created from now on. With
+    /// [`CsvOptions::with_run_columns`], files start with `seed` and
+    /// `replicate` columns holding [`Environment::seed`] and the replicate;
+    /// rows, records and schemas then give only the model's own columns.
+    pub fn set_csv_options(&mut self, options: CsvOptions) {
+        self.csv_options = options;
+    }
+
     /// Create a managed CSV writer with an ID for later row writes.
     pub fn create_csv(&mut self, id: &str, filename: &str, headers: &[&str]) {
-        let writer = self.csv_writer(filename, headers);
-        self.csv_writers.insert(id.to_string(), writer);
+        self.try_create_csv(id, filename, headers)
+            .unwrap_or_else(|e| panic!("{e}"));
+    }
+
+    pub fn try_create_csv(
+        &mut self,
+        id: &str,
+        filename: &str,
+        headers: &[&str],
+    ) -> Result<(), MrpError> {
+        let writer = self.try_csv_writer(filename, headers)?;
+        self.csv_writers.borrow_mut().insert(id.to_string(), writer);
+        Ok(())
+    }
+
+    /// [`Environment::create_csv`] in output profile `profile`.
+    pub fn create_csv_to(&mut self, profile: &str, id: &str, filename: &str, headers: &[&str]) {
+        self.try_create_csv_to(profile, id, filename, headers)
+            .unwrap_or_else(|e| panic!("{e}"));
+    }
+
+    pub fn try_create_csv_to(
+        &mut self,
+        profile: &str,
+        id: &str,
+        filename: &str,
+        headers: &[&str],
+    ) -> Result<(), MrpError> {
+        let writer = self.try_csv_writer_to(profile, filename, headers)?;
+        self.csv_writers.borrow_mut().insert(id.to_string(), writer);
+        Ok(())
     }
 
     /// Write a row to a managed CSV writer by ID.
+    ///
+    /// A stdout consumer that stops reading for `"max_stall_seconds"` does
+    /// not panic here: the stall is recorded, later `try_` writes to stdout
+    /// return it at once, and finalize fails with it.
     pub fn write_csv_row(&mut self, id: &str, row: &[&str]) {
+        stream::panic_unless_stalled(self.try_write_csv_row(id, row));
+    }
+
+    /// Like [`Environment::write_csv_row`], but returns an error for an
+    /// unknown ID or a row the writer rejects.
+    pub fn try_write_csv_row(&mut self, id: &str, row: &[&str]) -> Result<(), MrpError> {
         self.csv_writers
+            .borrow_mut()
             .get_mut(id)
-            .expect("no CSV writer with that id")
-            .write_row(row);
+            .ok_or_else(|| MrpError::Output(format!("no CSV writer with id '{id}'")))?
+            .try_write_row(row)
     }
 
     /// Close and remove a managed CSV writer by ID.
     pub fn close_csv(&mut self, id: &str) {
-        if let Some(mut w) = self.csv_writers.remove(id) {
-            w.flush();
+        stream::panic_unless_stalled(self.try_close_csv(id));
+    }
+
+    /// Like [`Environment::close_csv`], but returns a failed flush, or a
+    /// failed rename into place of an atomic output. The writer is removed
+    /// either way.
+    pub fn try_close_csv(&mut self, id: &str) -> Result<(), MrpError> {
+        let writer = self.csv_writers.borrow_mut().remove(id);
+        if let Some(mut w) = writer {
+            w.try_flush()
+                .map_err(|e| write_error(&format!("CSV '{id}'"), e))?;
+            if let Some(filename) = w.filename() {
+                self.atomic_files.commit(filename).map_err(|e| {
+                    MrpError::Output(format!("failed to move '{filename}' into place: {e}"))
+                })?;
+                self.try_each_objects(filename, |objects| objects.upload(filename))?;
+            }
         }
+        Ok(())
     }
 
     /// Close all managed CSV writers.
     pub fn close_all_csv(&mut self) {
-        for (_, mut w) in self.csv_writers.drain() {
-            w.flush();
+        stream::panic_unless_stalled(self.try_close_all_csv());
+    }
+
+    /// Like [`Environment::close_all_csv`], but returns the first failed
+    /// close. Every writer is closed and removed either way.
+    pub fn try_close_all_csv(&mut self) -> Result<(), MrpError> {
+        let mut ids: Vec<String> = self.csv_writers.borrow().keys().cloned().collect();
+        ids.sort();
+        let mut result = Ok(());
+        for id in ids {
+            let closed = self.try_close_csv(&id);
+            if result.is_ok() {
+                result = closed;
+            }
         }
+        result
     }
 
     /// Create a standalone CSV writer for the given filename and headers.
+    ///
+    /// A `"csv_filters"` entry for `filename` in the output spec trims the
+    /// file's columns and rows as it is written; declared schemas still
+    /// describe the unfiltered rows.
     pub fn csv_writer(&self, filename: &str, headers: &[&str]) -> CsvWriter {
-        let dest: Box<dyn std::io::Write> = if let Some(dir) = self.output_dir() {
-            fs::create_dir_all(&dir).expect("failed to create output dir");
-            Box::new(fs::File::create(dir.join(filename)).expect("failed to create CSV file"))
-        } else {
-            Box::new(io::stdout())
-        };
-        CsvWriter::new(dest, headers)
+        self.try_csv_writer(filename, headers)
+            .unwrap_or_else(|e| panic!("{e}"))
     }
 
-    /// Write all rows to a CSV file at once.
-    pub fn write_csv(&self, filename: &str, headers: &[&str], rows: &[Vec<String>]) {
-        let mut writer = self.csv_writer(filename, headers);
-        for row in rows {
-            let refs: Vec<&str> = row.iter().map(|s| s.as_str()).collect();
-            writer.write_row(&refs);
-        }
-        writer.flush();
+    pub fn try_csv_writer(&self, filename: &str, headers: &[&str]) -> Result<CsvWriter, MrpError> {
+        self.try_csv_writer_as(
+            &self.expand_template(filename)?,
+            filename,
+            headers,
+            (self.seed(), self.replicate),
+        )
     }
-}
 
-impl Default for Environment<()> {
-    fn default() -> Self {
-        Self::new()
+    /// [`Environment::try_csv_writer`] for `filename`, with the schema and
+    /// filter declared for `spec_name` and `run` as the seed and replicate
+    /// of any run columns.
+    pub(crate) fn try_csv_writer_as(
+        &self,
+        filename: &str,
+        spec_name: &str,
+        headers: &[&str],
+        run: (u64, u64),
+    ) -> Result<CsvWriter, MrpError> {
+        self.try_csv_writer_in(
+            self.output_dir.as_deref(),
+            filename,
+            spec_name,
+            headers,
+            run,
+        )
     }
-}
 
-fn extract_common(data: &Value) -> (u64, HashMap<String, PathBuf>, Value, Value) {
-    let input_section = data
-        .get("input")
-        .cloned()
-        .unwrap_or(Value::Object(Default::default()));
+    /// [`Environment::try_csv_writer_as`] into `dir`, or to stdout if `None`.
+    fn try_csv_writer_in(
+        &self,
+        dir: Option<&Path>,
+        filename: &str,
+        spec_name: &str,
+        headers: &[&str],
+        run: (u64, u64),
+    ) -> Result<CsvWriter, MrpError> {
+        let schema = self.output_schemas.get(spec_name);
+        if let Some(schema) = schema {
+            schema.check_headers(filename, headers)?;
+        }
+        let headers = &self.csv_headers(headers);
+        let filter = self
+            .csv_filters
+            .get(spec_name)
+            .map(|f| f.bind(filename, headers))
+            .transpose()?;
+        let existing = dir
+            .map(|dir| dir.join(filename))
+            .filter(|path| self.if_exists != IfExists::Overwrite && path.exists());
+        let appending = existing.is_some() && self.if_exists == IfExists::Append;
+        if appending && filter.is_some() {
+            return Err(MrpError::Config(format!(
+                "csv_filters cannot apply to '{filename}', which is appended to"
+            )));
+        }
+        self.try_record_output_as(filename, spec_name)?;
+        let dest: Box<dyn io::Write> = match &existing {
+            None => self.try_open_output_with(dir, filename, self.csv_atomic())?,
+            Some(path) => match self.if_exists {
+                IfExists::Skip => {
+                    self.skip_output(filename, path);
+                    Box::new(io::sink())
+                }
+                IfExists::Append => Box::new(if_exists::open_for_append(path, headers)?),
+                policy => return Err(if_exists::exists_error(filename, path, policy)),
+            },
+        };
+        let dest = Box::new(SpanWriter::new(dest, self.provenance.clone(), filename));
+        let writer = match filter {
+            Some(filter) => CsvWriter::continuing(dest, headers).filtered(filter),
+            None if appending => CsvWriter::continuing(dest, headers),
+            None => CsvWriter::new(dest, headers),
+        };
+        let writer = self
+            .run_values(writer, run)
+            .with_options(self.csv_options.clone())
+            .counted(self.rows_written.clone())
+            .warn_to(self.warnings.clone());
+        Ok(match schema {
+            Some(schema) => writer.with_schema(filename, schema.clone()),
+            None => writer.named(filename),
+        })
+    }
+
+    /// Note that `filename` was left as it is under `"if_exists": "skip"`.
+    fn skip_output(&self, filename: &str, path: &Path) {
+        let message = format!(
+            "'{filename}' already exists at {}, so was not written (if_exists is \"skip\")",
+            path.display()
+        );
+        eprintln!("warning [output_skipped]: {message}");
+        self.warnings.borrow_mut().push(Warning {
+            code: "output_skipped".to_string(),
+            message,
+            error_code: None,
+        });
+    }
+
+    fn csv_atomic(&self) -> bool {
+        self.atomic || self.csv_options.atomic()
+    }
+
+    /// Write `data` to `path`, through a temp file if the spec is atomic.
+    fn write_file(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        if self.atomic {
+            atomic::write(path, data)
+        } else {
+            fs::write(path, data)
+        }
+    }
+
+    /// `headers` after the run columns, if the CSV options ask for them.
+    fn csv_headers<'h>(&self, headers: &[&'h str]) -> Vec<&'h str> {
+        let run: &[&str] = if self.csv_options.run_columns() {
+            &RUN_COLUMNS
+        } else {
+            &[]
+        };
+        run.iter().chain(headers).copied().collect()
+    }
+
+    fn run_values(&self, writer: CsvWriter, (seed, replicate): (u64, u64)) -> CsvWriter {
+        if self.csv_options.run_columns() {
+            writer.with_run_values(seed, replicate)
+        } else {
+            writer
+        }
+    }
+
+    /// [`Environment::csv_writer`] in output profile `profile`.
+    pub fn csv_writer_to(&self, profile: &str, filename: &str, headers: &[&str]) -> CsvWriter {
+        self.try_csv_writer_to(profile, filename, headers)
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    pub fn try_csv_writer_to(
+        &self,
+        profile: &str,
+        filename: &str,
+        headers: &[&str],
+    ) -> Result<CsvWriter, MrpError> {
+        let dir = self.output_dir_for(profile)?;
+        let run = (self.seed(), self.replicate);
+        let name = self.expand_template_for(filename, run, Some(profile))?;
+        self.try_csv_writer_in(dir.as_deref(), &name, filename, headers, run)
+    }
+
+    /// Create a JSON-lines writer for the given filename.
+    pub fn jsonl_writer(&self, filename: &str) -> JsonlWriter {
+        let name = self
+            .expand_template(filename)
+            .unwrap_or_else(|e| panic!("{e}"));
+        self.jsonl_writer_as(&name, filename)
+    }
+
+    pub(crate) fn jsonl_writer_as(&self, filename: &str, spec_name: &str) -> JsonlWriter {
+        self.record_output_as(filename, spec_name);
+        let dest = SpanWriter::new(
+            self.open_output(filename),
+            self.provenance.clone(),
+            filename,
+        );
+        JsonlWriter::new(Box::new(dest)).named(filename)
+    }
+
+    /// Write all rows to a CSV file at once.
+    pub fn write_csv(&self, filename: &str, headers: &[&str], rows: &[Vec<String>]) {
+        stream::panic_unless_stalled(self.try_write_csv(filename, headers, rows));
+    }
+
+    pub fn try_write_csv(
+        &self,
+        filename: &str,
+        headers: &[&str],
+        rows: &[Vec<String>],
+    ) -> Result<(), MrpError> {
+        self.try_write_csv_as(
+            &self.expand_template(filename)?,
+            filename,
+            headers,
+            rows,
+            (self.seed(), self.replicate),
+        )
+    }
+
+    pub(crate) fn try_write_csv_as(
+        &self,
+        filename: &str,
+        spec_name: &str,
+        headers: &[&str],
+        rows: &[Vec<String>],
+        run: (u64, u64),
+    ) -> Result<(), MrpError> {
+        let mut writer = self.try_csv_writer_as(filename, spec_name, headers, run)?;
+        for row in rows {
+            let refs: Vec<&str> = row.iter().map(|s| s.as_str()).collect();
+            writer.try_write_row(&refs)?;
+        }
+        writer
+            .try_flush()
+            .map_err(|e| write_error(&format!("'{filename}'"), e))?;
+        self.finish_output(filename);
+        Ok(())
+    }
+
+    /// Start `filename` as a copy of an existing CSV and return a writer that
+    /// appends rows after it.
+    ///
+    /// `existing` must start with exactly `headers`, after any run columns;
+    /// no header row is written.
+    pub fn append_csv(
+        &self,
+        filename: &str,
+        headers: &[&str],
+        existing: &[u8],
+    ) -> Result<CsvWriter, MrpError> {
+        let own_headers = headers;
+        let headers = &self.csv_headers(headers);
+        let first_line = existing.split(|&b| b == b'\n').next().unwrap_or_default();
+        let found = String::from_utf8_lossy(first_line);
+        if found.trim_end_matches('\r') != headers.join(",") {
+            return Err(MrpError::Output(format!(
+                "cannot append to '{filename}': existing header '{}' does not match '{}'",
+                found.trim_end(),
+                headers.join(",")
+            )));
+        }
+        if self.csv_filters.contains_key(filename) {
+            return Err(MrpError::Config(format!(
+                "csv_filters cannot apply to '{filename}', which continues an existing CSV"
+            )));
+        }
+        let schema = self.output_schemas.get(filename);
+        if let Some(schema) = schema {
+            schema.check_headers(filename, own_headers)?;
+        }
+        let name = &self.expand_template(filename)?;
+        self.try_record_output_as(name, filename)?;
+        let dir = self.output_dir.as_deref();
+        let mut dest = self.try_open_output_with(dir, name, self.csv_atomic())?;
+        dest.write_all(existing)
+            .and_then(|_| match existing.last() {
+                Some(b'\n') | None => Ok(()),
+                Some(_) => dest.write_all(b"\n"),
+            })
+            .map_err(|e| MrpError::Output(format!("failed to copy '{name}': {e}")))?;
+        let writer = self
+            .run_values(
+                CsvWriter::continuing(dest, headers),
+                (self.seed(), self.replicate),
+            )
+            .with_options(self.csv_options.clone())
+            .counted(self.rows_written.clone())
+            .warn_to(self.warnings.clone());
+        Ok(match schema {
+            Some(schema) => writer.with_schema(name, schema.clone()),
+            None => writer.named(name),
+        })
+    }
+
+    /// `MRP_RUN_ID`, else the input hash.
+    fn run_id(&self) -> String {
+        std::env::var("MRP_RUN_ID").unwrap_or_else(|_| self.input_hash())
+    }
+
+    /// `template` with `{seed}`, `{replicate}`, `{run_id}` (`MRP_RUN_ID`,
+    /// else the input hash) and `{profile}` (the output profile in use)
+    /// filled in; `{{` and `}}` are literal braces, and any other
+    /// placeholder is an error. Filenames given to the writers and the
+    /// output spec's `"dir"` are expanded this way.
+    pub fn expand_template(&self, template: &str) -> Result<String, TemplateError> {
+        self.expand_template_for(template, (self.seed(), self.replicate), None)
+    }
+
+    /// [`Environment::expand_template`] with `run` as the seed and
+    /// replicate, and `profile` if given.
+    pub(crate) fn expand_template_for(
+        &self,
+        template: &str,
+        (seed, replicate): (u64, u64),
+        profile: Option<&str>,
+    ) -> Result<String, TemplateError> {
+        template::expand(template, |name| match name {
+            "seed" => Some(seed.to_string()),
+            "replicate" => Some(replicate.to_string()),
+            "run_id" => Some(self.run_id()),
+            "profile" => Some(
+                profile
+                    .or(self.output_profile.as_deref())
+                    .unwrap_or("default")
+                    .to_string(),
+            ),
+            _ => None,
+        })
+    }
+
+    /// `spec` with its `"dir"` expanded for `profile`.
+    fn expand_dir(&self, spec: &Value, profile: &str) -> Result<Value, MrpError> {
+        let mut spec = spec.clone();
+        if let Some(dir) = spec.get("dir").and_then(|v| v.as_str()) {
+            let run = (self.seed(), self.replicate);
+            spec["dir"] = self.expand_template_for(dir, run, Some(profile))?.into();
+        }
+        Ok(spec)
+    }
+
+    fn open_output(&self, filename: &str) -> Box<dyn io::Write> {
+        self.try_open_output(filename)
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    fn try_open_output(&self, filename: &str) -> Result<Box<dyn io::Write>, MrpError> {
+        self.try_open_output_in(self.output_dir.as_deref(), filename)
+    }
+
+    fn try_open_output_in(
+        &self,
+        dir: Option<&Path>,
+        filename: &str,
+    ) -> Result<Box<dyn io::Write>, MrpError> {
+        self.try_open_output_with(dir, filename, self.atomic)
+    }
+
+    /// [`Environment::try_open_output_in`], writing files under a temp
+    /// name and renaming them into place if `atomic`.
+    fn try_open_output_with(
+        &self,
+        dir: Option<&Path>,
+        filename: &str,
+        atomic: bool,
+    ) -> Result<Box<dyn io::Write>, MrpError> {
+        let Some(tee) = self.tee_for(dir) else {
+            return self.try_open_sink(dir, filename, atomic);
+        };
+        let first = self.try_open_sink(dir, filename, atomic);
+        let mut opened = vec![(tee.first.as_str(), first)];
+        for sink in &tee.sinks {
+            let writer: Result<Box<dyn io::Write>, MrpError> = match &sink.kind {
+                SinkKind::Dir(dir, tempdir) => {
+                    self.try_open_sink(Some(dir), filename, atomic)
+                        .map(|writer| match tempdir {
+                            Some(tempdir) => Box::new(TempDirWriter::new(writer, tempdir.clone())),
+                            None => writer,
+                        })
+                }
+                SinkKind::Objects(objects) => Ok(Box::new(objects.writer(filename))),
+                SinkKind::Memory => Ok(Box::new(MemoryWriter::create(
+                    self.memory.clone(),
+                    filename,
+                ))),
+            };
+            opened.push((sink.name.as_str(), writer));
+        }
+        let mut writers = Vec::new();
+        let mut results = Vec::new();
+        for (name, writer) in opened {
+            results.push((
+                name,
+                writer.map(|writer| writers.push((name.to_string(), writer))),
+            ));
+        }
+        tee.settle(filename, results, &self.warnings)?;
+        Ok(Box::new(TeeWriter::new(
+            filename,
+            writers,
+            tee.require_all,
+            self.warnings.clone(),
+        )))
+    }
+
+    /// Open `filename` in `dir`, else the spec's destination.
+    fn try_open_sink(
+        &self,
+        dir: Option<&Path>,
+        filename: &str,
+        atomic: bool,
+    ) -> Result<Box<dyn io::Write>, MrpError> {
+        let create_error = |e| MrpError::Output(format!("failed to create '{filename}': {e}"));
+        if let Some(dir) = dir {
+            let path = dir.join(filename);
+            let parent = path.parent().unwrap_or(dir);
+            let file = self
+                .ensure_dir(parent)
+                .and_then(|_| -> io::Result<Box<dyn io::Write>> {
+                    if atomic {
+                        let file = AtomicFile::create(&path, self.warnings.clone())?;
+                        self.atomic_files.register(filename, &file);
+                        Ok(Box::new(file))
+                    } else {
+                        Ok(Box::new(fs::File::create(&path)?))
+                    }
+                });
+            let writer: Box<dyn io::Write> = match (&self.write_failure, file) {
+                (WriteFailurePolicy::Fail, file) => file.map_err(create_error)?,
+                (policy, Ok(file)) => Box::new(FallbackWriter::new(
+                    filename,
+                    &path,
+                    file,
+                    policy.clone(),
+                    self.fallback_log(),
+                )),
+                (policy, Err(e)) if fallback::is_degradable(&e) => Box::new(
+                    FallbackWriter::switched(
+                        filename,
+                        &path,
+                        policy.clone(),
+                        self.fallback_log(),
+                        &e,
+                    )
+                    .map_err(|e| {
+                        MrpError::Output(format!("failed to open fallback for '{filename}': {e}"))
+                    })?,
+                ),
+                (_, Err(e)) => return Err(create_error(e)),
+            };
+            Ok(match &self.output_tempdir {
+                Some(tempdir) if dir.starts_with(tempdir.path()) => {
+                    Box::new(TempDirWriter::new(writer, tempdir.clone()))
+                }
+                _ => writer,
+            })
+        } else if let Some(archive) = &self.archive {
+            Ok(Box::new(archive.writer(filename)?))
+        } else if let Some(objects) = &self.objects {
+            Ok(Box::new(objects.writer(filename)))
+        } else if self.in_memory {
+            Ok(Box::new(MemoryWriter::create(
+                self.memory.clone(),
+                filename,
+            )))
+        } else if self.framed {
+            let writer = FramedWriter::new(self.stdout_writer(), filename);
+            Ok(Box::new(writer.map_err(|e| write_error("to stdout", e))?))
+        } else {
+            Ok(Box::new(self.stdout_writer()))
+        }
+    }
+
+    /// A writer to stdout that accounts for time spent waiting on the
+    /// consumer and fails after `"max_stall_seconds"` without progress.
+    fn stdout_writer(&self) -> StreamWriter<io::Stdout> {
+        StreamWriter::new(
+            io::stdout(),
+            self.max_stall,
+            self.stdout.clone(),
+            &self.io_pool,
+        )
+    }
+
+    /// Run blocking IO, such as an upload or download, on the run's
+    /// background IO pool. The pool is sized by the payload's
+    /// `"io_threads"` or `MRP_IO_THREADS` and is drained by finalize.
+    pub fn submit_io<T, F>(&self, task: F) -> IoHandle<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        self.io_pool.submit(task)
+    }
+
+    pub fn io_pool_stats(&self) -> IoPoolStats {
+        self.io_pool.stats()
+    }
+
+    /// Write what is needed to replay this run to the directory `dest`: the
+    /// payload with `"redact"` input paths masked and file paths pointing at
+    /// copies of the input files, plus `snapshot.json` with build info and
+    /// the hash and source of every input file.
+    pub fn snapshot(&self, dest: &Path) -> Result<SnapshotIndex, MrpError> {
+        self.snapshot_with(dest, &SnapshotOptions::default())
+    }
+
+    /// Like [`Environment::snapshot`], with a limit on the size of copied
+    /// files.
+    pub fn snapshot_with(
+        &self,
+        dest: &Path,
+        options: &SnapshotOptions,
+    ) -> Result<SnapshotIndex, MrpError> {
+        snapshot::write(dest, &self.payload, &self.redact, options)
+    }
+
+    /// Time spent so far waiting for the consumer of stdout output to read.
+    pub fn stdout_blocked(&self) -> Duration {
+        self.stdout.blocked()
+    }
+
+    /// Set a run-level metric, written to `metrics.json` by finalize.
+    pub fn record_metric(&self, name: &str, value: f64) {
+        assert!(
+            name != "format_version",
+            "metric name 'format_version' is reserved"
+        );
+        self.metrics.borrow_mut().insert(name.to_string(), value);
+    }
+
+    /// Update a metric while the run is in progress.
+    ///
+    /// Like [`Environment::record_metric`], but also publishes the current
+    /// metrics at most once per `"progress_interval_ms"` (default 1000): to
+    /// `metrics.partial.json`, replaced atomically, with filesystem output, or
+    /// as a `metrics {json}` line on stderr otherwise. Finalize writes the
+    /// last values to `metrics.json` and removes the partial file.
+    pub fn update_metric(&self, name: &str, value: f64) {
+        self.record_metric(name, value);
+        if self.metrics_throttle.ready()
+            && let Err(e) = self.publish_partial_metrics()
+        {
+            self.warn("partial_metrics", &e.to_string());
+        }
+    }
+
+    fn publish_partial_metrics(&self) -> Result<(), MrpError> {
+        let json = serde_json::to_vec(&Metrics::new(self.metrics.borrow().clone()))
+            .map_err(|e| MrpError::Serialization(e.to_string()))?;
+        let Some(dir) = self.output_dir() else {
+            eprintln!("metrics {}", String::from_utf8_lossy(&json));
+            return Ok(());
+        };
+        let tmp = dir.join(format!("{PARTIAL_METRICS}.tmp"));
+        self.ensure_dir(&dir)
+            .and_then(|_| fs::write(&tmp, json))
+            .and_then(|_| fs::rename(&tmp, dir.join(PARTIAL_METRICS)))
+            .map_err(|e| MrpError::Output(format!("failed to write {PARTIAL_METRICS}: {e}")))
+    }
+
+    /// CSV data rows written through this environment so far.
+    pub fn rows_written(&self) -> u64 {
+        self.rows_written.get()
+    }
+
+    /// Report that `done` of `total` units of work are complete, as a
+    /// `progress {done}/{total}` line on stderr.
+    ///
+    /// Throttled like [`Environment::update_metric`]; completion is always
+    /// reported. Once output streamed to stdout has had to wait for its
+    /// consumer, the line also gives the share of wall time spent waiting.
+    pub fn progress(&self, done: u64, total: u64) {
+        if self.progress.ready() || done >= total {
+            let waiting = self.stdout.blocked_fraction();
+            if waiting >= 0.005 {
+                eprintln!(
+                    "progress {done}/{total} ({:.0}% of wall time spent waiting on consumer)",
+                    waiting * 100.0
+                );
+            } else {
+                eprintln!("progress {done}/{total}");
+            }
+        }
+    }
+
+    pub fn metrics(&self) -> BTreeMap<String, f64> {
+        self.metrics.borrow().clone()
+    }
+
+    /// Register a cleanup action to run whether the model succeeds, fails or
+    /// panics.
+    ///
+    /// Actions run newest first during [`Environment::finalize`], or when the
+    /// environment is dropped without being finalized. A panicking action is
+    /// recorded as a `deferred_failed` warning and never stops the others or
+    /// replaces the run's own error.
+    pub fn defer(&self, cleanup: impl FnOnce() + Send + 'static) {
+        self.deferred.borrow_mut().push(Box::new(cleanup));
+    }
+
+    /// Record a structured warning and echo it to stderr.
+    ///
+    /// Warnings with the same code and message template (the message with
+    /// numbers and quoted values masked) are emitted at most
+    /// `"warning_limit"` times (default 10); the rest are only counted, and
+    /// [`Environment::finalize`] records one `warnings_suppressed` warning.
+    pub fn warn(&self, code: &str, message: &str) {
+        if !self.warning_dedup.borrow_mut().admit(code, message) {
+            return;
+        }
+        eprintln!("warning [{code}]: {message}");
+        self.warnings.borrow_mut().push(Warning {
+            code: code.to_string(),
+            message: message.to_string(),
+            error_code: None,
+        });
+    }
+
+    pub fn warnings(&self) -> Vec<Warning> {
+        self.warnings.borrow().clone()
+    }
+
+    /// Warnings raised per code through [`Environment::warn`] and split
+    /// workers, including suppressed duplicates.
+    pub fn warning_counts(&self) -> BTreeMap<String, u64> {
+        self.warning_dedup.borrow().counts().clone()
+    }
+
+    /// Attach a key/value label to the run.
+    pub fn set_tag(&mut self, key: &str, value: &str) {
+        self.tags.insert(key.to_string(), value.to_string());
+    }
+
+    pub fn tags(&self) -> &BTreeMap<String, String> {
+        &self.tags
+    }
+
+    /// Add a section to the run report, after the metrics.
+    pub fn add_report_section(&self, section: impl ReportSection + 'static) {
+        self.report_sections.borrow_mut().push(Box::new(section));
+    }
+
+    /// Write `report.md`: build info, tags, input overrides, metrics, any
+    /// sections the model added, warnings and the outputs written so far.
+    ///
+    /// Values of the input paths listed in the payload's `"redact"` are
+    /// masked wherever they appear. [`Environment::finalize`] calls this
+    /// when the payload sets `"report": true`.
+    pub fn write_report(&self) {
+        let mut report = Report::default();
+        report.push(
+            "Build",
+            report::table(
+                &["item", "value"],
+                [
+                    vec!["cfa-mrp".to_string(), env!("CARGO_PKG_VERSION").to_string()],
+                    vec!["protocol".to_string(), MRP_VERSION.to_string()],
+                    vec!["replicate".to_string(), self.replicate.to_string()],
+                    vec!["seed".to_string(), self.seed_value().to_string()],
+                ],
+            ),
+        );
+        report.push(
+            "Tags",
+            report::table(
+                &["tag", "value"],
+                self.tags.iter().map(|(k, v)| vec![k.clone(), v.clone()]),
+            ),
+        );
+        report.push(
+            "Input overrides",
+            report::table(
+                &["path", "previous", "value", "source"],
+                self.input_overrides.iter().map(|o| {
+                    vec![
+                        o.path.clone(),
+                        o.previous
+                            .as_ref()
+                            .map(|v| v.to_string())
+                            .unwrap_or_default(),
+                        o.value.to_string(),
+                        o.source.clone(),
+                    ]
+                }),
+            ),
+        );
+        report.push(
+            "Metrics",
+            report::table(
+                &["metric", "value"],
+                self.metrics()
+                    .into_iter()
+                    .map(|(k, v)| vec![k, v.to_string()]),
+            ),
+        );
+        for section in self.report_sections.borrow().iter() {
+            report.push(&section.title(), section.body());
+        }
+        report.push(
+            "Warnings",
+            report::table(
+                &["code", "message"],
+                self.warnings().into_iter().map(|w| vec![w.code, w.message]),
+            ),
+        );
+        let dir = self.output_dir();
+        report.push(
+            "Outputs",
+            report::table(
+                &["file", "bytes"],
+                self.produced
+                    .borrow()
+                    .iter()
+                    .filter(|name| *name != REPORT)
+                    .map(|name| {
+                        let size = dir
+                            .as_ref()
+                            .and_then(|d| fs::metadata(d.join(name)).ok())
+                            .map(|m| m.len().to_string())
+                            .unwrap_or_else(|| "-".to_string());
+                        vec![name.clone(), size]
+                    }),
+            ),
+        );
+        let redacted: Vec<String> = self
+            .redact
+            .iter()
+            .filter_map(|path| input_path(&self.input_json, path))
+            .map(|value| match value {
+                Value::String(s) => s.clone(),
+                other => other.to_string(),
+            })
+            .collect();
+        self.write_str(REPORT, &report.render(&redacted));
+    }
+
+    /// Overrides applied to the payload input, in the order they were applied.
+    pub fn input_overrides(&self) -> &[InputOverride] {
+        &self.input_overrides
+    }
+
+    /// Seed for the named random stream `name`.
+    ///
+    /// Streams are independent of each other and, by default, of other
+    /// replicates. The payload's `"rng_streams"` table can give a stream a
+    /// `seed_offset`, or list it under `"freeze"` so its seed depends only on
+    /// the base seed and every replicate repeats the same draws.
+    pub fn stream_seed(&self, name: &str) -> u64 {
+        self.stream_seed_for(name, self.seed_value(), self.replicate)
+    }
+
+    pub(crate) fn stream_seed_for(&self, name: &str, seed: u64, replicate: u64) -> u64 {
+        self.rng_streams.seed_for(name, seed, replicate)
+    }
+
+    /// The payload's `"replicates": {"count": n}`, asking for `n`
+    /// replicates to be run in this process.
+    pub fn replicate_count(&self) -> Option<u64> {
+        self.replicate_count
+    }
+
+    /// Run replicates `r..r + n`, where `r` is this run's replicate, one
+    /// after another in this process. Each gets the seeds a standalone run
+    /// with that replicate would, so its draws and outputs are the same as
+    /// that run's; see [`ReplicateContext`]. Needs the typed input.
+    pub fn run_replicates<F>(&self, n: u64, mut f: F) -> Result<(), MrpError>
+    where
+        F: FnMut(ReplicateContext<'_, I>),
+    {
+        let input = self
+            .input
+            .as_ref()
+            .ok_or_else(|| MrpError::Input("run_replicates needs the typed input".to_string()))?;
+        for replicate in self.replicate..self.replicate.saturating_add(n) {
+            let seed = match &self.seed_table {
+                Some(table) => table_seed(table, replicate)?,
+                None => self.seed_value(),
+            };
+            f(ReplicateContext::new(self, input, replicate, seed));
+        }
+        Ok(())
+    }
+
+    /// [`Environment::run_replicates`] for the payload's
+    /// [`Environment::replicate_count`], or just this replicate without one.
+    pub fn run_payload_replicates<F>(&self, f: F) -> Result<(), MrpError>
+    where
+        F: FnMut(ReplicateContext<'_, I>),
+    {
+        self.run_replicates(self.replicate_count.unwrap_or(1), f)
+    }
+
+    /// The input's `seed`, or 0 if it has none. With a seed table, this
+    /// replicate's entry.
+    pub fn seed(&self) -> u64 {
+        self.warn_missing_seed();
+        self.seed_value()
+    }
+
+    /// Whether the payload asked for a random seed with `"seed": null` or
+    /// `"seed": "random"`. The seed drawn is then the input's `seed`, is
+    /// tagged `generated_seed`, and is written to `resolved_input.json`
+    /// at finalize when there is an output directory.
+    pub fn seed_was_generated(&self) -> bool {
+        self.seed_generated
+    }
+
+    /// The payload's per-replicate seeds, from `"seed": [101, 102, ...]`,
+    /// for a model running several replicates in one process. `None` for a
+    /// single seed.
+    pub fn seed_table(&self) -> Option<&[u64]> {
+        self.seed_table.as_deref()
+    }
+
+    /// The seed for this replicate: the input's `seed` (0 if none) mixed
+    /// with `replicate` as [`derive_seed`] does,
+    /// `splitmix64(splitmix64(seed) ^ replicate)`, so nearby seeds and
+    /// replicates do not share draws as `seed + replicate` would.
+    pub fn effective_seed(&self) -> u64 {
+        self.warn_missing_seed();
+        derive_seed(self.seed_value(), self.replicate)
+    }
+
+    /// The algorithm of the generators below, from the payload's `"rng"`
+    /// key: `chacha8`, `chacha20` (the default) or `pcg64`.
+    pub fn rng_kind(&self) -> RngKind {
+        self.rng_kind
+    }
+
+    /// The model's generator, of [`Environment::rng_kind`] and seeded from
+    /// [`Environment::effective_seed`], so a payload always gives the same
+    /// draws, whatever the `rand` version. Get randomness from here or from
+    /// [`Environment::rng_stream`] rather than seeding a generator from the
+    /// input yourself.
+    #[cfg(feature = "rand")]
+    pub fn rng(&self) -> ModelRng {
+        ModelRng::new(self.rng_kind, self.effective_seed())
+    }
+
+    /// Like [`Environment::rng`], with `extra` mixed into the seed for an
+    /// ad-hoc substream: `derive_seed(effective_seed, extra)`.
+    #[cfg(feature = "rand")]
+    pub fn rng_seeded_with(&self, extra: u64) -> ModelRng {
+        ModelRng::new(self.rng_kind, derive_seed(self.effective_seed(), extra))
+    }
+
+    /// A generator for the named stream; see [`Environment::stream_seed`].
+    #[cfg(feature = "rand")]
+    pub fn rng_stream(&self, name: &str) -> ModelRng {
+        ModelRng::new(self.rng_kind, self.stream_seed(name))
+    }
+
+    /// A generator for the model component `label`, such as
+    /// `"transmission"` or `"importation"`, so changing one component's
+    /// draws leaves the others' alone. Seeded as [`Environment::rng_stream`]
+    /// is, from the seed, replicate and label (including any payload
+    /// `"rng_streams"` settings for it).
+    ///
+    /// Asking for a label again returns a fresh generator with the same
+    /// seed, which repeats the first one's draws; keep the generator to
+    /// continue a stream. Labels used so far are in
+    /// [`Environment::rng_labels`].
+    #[cfg(feature = "rand")]
+    pub fn rng_for(&self, label: &str) -> ModelRng {
+        self.rng_labels.borrow_mut().insert(label.to_string());
+        self.rng_stream(label)
+    }
+
+    /// Labels passed to [`Environment::rng_for`], sorted.
+    pub fn rng_labels(&self) -> Vec<String> {
+        self.rng_labels.borrow().iter().cloned().collect()
+    }
+
+    /// Declare the streams this model uses; streams configured in the
+    /// payload's `"rng_streams"` but not declared raise a warning.
+    pub fn declare_rng_streams(&self, names: &[&str]) {
+        for name in self.rng_streams.names() {
+            if !names.contains(&name) {
+                self.warn(
+                    "unknown_rng_stream",
+                    &format!("rng_streams configures '{name}', which this model does not use"),
+                );
+            }
+        }
+    }
+
+    /// Split into `n` worker environments for parallel work inside one run.
+    ///
+    /// See [`WorkerEnv`] for the reproducibility contract. Worker results are
+    /// merged back by [`Environment::merge_workers`] or finalize.
+    pub fn split(&mut self, n: usize) -> Vec<WorkerEnv> {
+        self.split_with_prefix(n, false)
+    }
+
+    /// Like [`Environment::split`], but each worker writes under `worker_{i}/`.
+    pub fn split_scoped(&mut self, n: usize) -> Vec<WorkerEnv> {
+        self.split_with_prefix(n, true)
+    }
+
+    fn split_with_prefix(&mut self, n: usize, scoped: bool) -> Vec<WorkerEnv> {
+        let run_seed = self.effective_seed();
+        let outputs = Arc::new(WorkerOutputs::new(
+            self.output_schemas.clone(),
+            self.strict_outputs,
+            self.worker_dest(),
+            self.provenance.borrow().clock(),
+        ));
+        (0..n)
+            .map(|i| {
+                let record = Arc::new(Mutex::new(WorkerRecord::default()));
+                self.workers.push(record.clone());
+                let prefix = if scoped {
+                    format!("worker_{i}/")
+                } else {
+                    String::new()
+                };
+                WorkerEnv::new(
+                    i,
+                    derive_seed(run_seed, i as u64),
+                    prefix,
+                    record,
+                    outputs.clone(),
+                )
+            })
+            .collect()
+    }
+
+    /// Where split workers write: only a plain output directory is shared
+    /// with them, as the other sinks live on this thread.
+    fn worker_dest(&self) -> WorkerDest {
+        let spec = if self.tee.is_some() {
+            "the \"multi\" output spec"
+        } else if self.write_failure != WriteFailurePolicy::Fail {
+            "a \"write_failure\" fallback"
+        } else if let Some(dir) = &self.output_dir {
+            return WorkerDest::Dir {
+                dir: dir.clone(),
+                atomic: self.atomic,
+                atomic_csv: self.csv_atomic(),
+                if_exists: self.if_exists,
+            };
+        } else if self.archive.is_some() {
+            "the \"archive\" output spec"
+        } else if self.objects.is_some() {
+            "an object store output spec"
+        } else if self.in_memory {
+            "the \"memory\" output spec"
+        } else {
+            "stdout output"
+        };
+        WorkerDest::Unsupported(spec)
+    }
+
+    /// Fold metrics, warnings and outputs from split workers into this
+    /// Environment, in worker-index order. Each output keeps the span its
+    /// worker wrote it in, so its provenance lists only earlier reads.
+    pub fn merge_workers(&mut self) {
+        for record in self.workers.drain(..) {
+            let mut record = record.lock().unwrap();
+            let failures = defer::run_all(std::mem::take(&mut record.deferred));
+            record.apply_metrics(&mut self.metrics.borrow_mut());
+            let mut dedup = self.warning_dedup.borrow_mut();
+            self.warnings.borrow_mut().extend(
+                record
+                    .warnings
+                    .iter()
+                    .filter(|w| dedup.admit(&w.code, &w.message))
+                    .cloned()
+                    .chain(failures),
+            );
+            for (name, span) in std::mem::take(&mut record.outputs) {
+                self.produced.borrow_mut().insert(name.clone());
+                self.provenance.borrow_mut().replay_output(&name, span);
+            }
+        }
+    }
+
+    /// The run's cancellation token. Long-running models should check it
+    /// periodically and, once tripped, stop and return normally so that
+    /// finalize records the partial run as cancelled.
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    /// Write `error.json` recording why the run failed, with the seed,
+    /// replicate and time, without ending the run. It goes to the output
+    /// directory, which is created if needed, or to stdout if there is none.
+    pub fn record_error(&self, code: &str, message: &str) -> Result<(), MrpError> {
+        let record = ErrorRecord::new(
+            code,
+            message,
+            self.input_json.get("seed").and_then(|v| v.as_u64()),
+            self.replicate,
+            calendar::rfc3339_utc(SystemTime::now()),
+        );
+        let mut json = serde_json::to_vec_pretty(&record)
+            .map_err(|e| MrpError::Serialization(e.to_string()))?;
+        let err = |e: io::Error| MrpError::Output(format!("failed to write {ERROR}: {e}"));
+        match self.output_dir() {
+            Some(dir) => {
+                let first = self
+                    .ensure_dir(&dir)
+                    .and_then(|_| self.write_file(&dir.join(ERROR), &json))
+                    .map_err(err);
+                self.try_write_tee(Some(&dir), ERROR, &json, first)?;
+                self.produced.borrow_mut().insert(ERROR.to_string());
+            }
+            None if self.in_memory => {
+                self.memory.borrow_mut().insert(ERROR.to_string(), json);
+            }
+            None => self.write_record_to_stdout(&mut json, ERROR).map_err(err)?,
+        }
+        Ok(())
+    }
+
+    /// Write the input the model saw, after defaults and overrides and with
+    /// `seed` and `replicate`, to `resolved_input.json` in the output
+    /// directory, or to stdout if there is none. `"redact"` paths are
+    /// masked. With `"resolved_input": true` in the payload, `finalize`
+    /// writes it when there is an output directory.
+    pub fn write_resolved_input(&self) -> Result<(), MrpError> {
+        let mut input = self.resolved_input();
+        snapshot::redact_input(&mut input, &self.redact);
+        let mut json = serde_json::to_vec_pretty(&input)
+            .map_err(|e| MrpError::Serialization(e.to_string()))?;
+        let err = |e: io::Error| MrpError::Output(format!("failed to write {RESOLVED_INPUT}: {e}"));
+        match self.output_dir() {
+            Some(dir) => {
+                let first = self
+                    .ensure_dir(&dir)
+                    .and_then(|_| self.write_file(&dir.join(RESOLVED_INPUT), &json))
+                    .map_err(err);
+                self.try_write_tee(Some(&dir), RESOLVED_INPUT, &json, first)?;
+                self.produced
+                    .borrow_mut()
+                    .insert(RESOLVED_INPUT.to_string());
+            }
+            None if self.in_memory => {
+                self.memory
+                    .borrow_mut()
+                    .insert(RESOLVED_INPUT.to_string(), json);
+            }
+            None => self
+                .write_record_to_stdout(&mut json, RESOLVED_INPUT)
+                .map_err(err)?,
+        }
+        Ok(())
+    }
+
+    /// Write a record such as `error.json` to stdout on a line of its own,
+    /// or as one framed file under `"framed"`.
+    fn write_record_to_stdout(&self, json: &mut Vec<u8>, filename: &str) -> io::Result<()> {
+        let mut stdout = io::stdout().lock();
+        if self.framed {
+            framing::write_file(&mut stdout, filename, json)?;
+        } else {
+            json.push(b'\n');
+            stdout.write_all(json)?;
+        }
+        stdout.flush()
+    }
+
+    /// End a framed stdout stream with a `completion` frame carrying the
+    /// run's metrics; nothing without `"framed"`.
+    fn write_completion(
+        &self,
+        status: CompletionStatus,
+        code: Option<&str>,
+        message: Option<String>,
+    ) -> io::Result<()> {
+        if !self.framed {
+            return Ok(());
+        }
+        let completion = Completion {
+            status,
+            code: code.map(String::from),
+            message,
+            metrics: self.metrics.borrow().clone(),
+        };
+        let mut stdout = io::stdout().lock();
+        framing::write_completion(&mut stdout, completion).and_then(|_| stdout.flush())
+    }
+
+    /// Record the error with [`record_error`](Self::record_error), print it
+    /// to stderr and exit with [`FAILURE_EXIT_CODE`]. Framed stdout ends
+    /// with a `failed` completion.
+    pub fn fail(&self, code: &str, message: &str) -> ! {
+        eprintln!("{code}: {message}");
+        if let Err(e) = self.record_error(code, message) {
+            eprintln!("{e}");
+        }
+        let failed = self.write_completion(
+            CompletionStatus::Failed,
+            Some(code),
+            Some(message.to_string()),
+        );
+        if let Err(e) = failed {
+            eprintln!("failed to write the completion frame: {e}");
+        }
+        std::process::exit(FAILURE_EXIT_CODE)
+    }
+
+    /// The input parameter at `key`, which may be a dotted path such as
+    /// `observation.delay_pmf`. Missing or of the wrong type is an error
+    /// naming the key, the type asked for and the value found.
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Result<T, ParamError> {
+        params::get(&self.input_json, key)
+    }
+
+    /// The input parameter at `key`, or `default` if it is missing. A value
+    /// of the wrong type, or a key differing only in case (`R0` for `r0`),
+    /// is still an error.
+    pub fn get_or<T: DeserializeOwned>(&self, key: &str, default: T) -> Result<T, ParamError> {
+        Ok(params::get_opt(&self.input_json, key)?.unwrap_or(default))
+    }
+
+    /// The probability mass function at `key`: non-empty, with
+    /// non-negative entries summing to 1, as `options` loosen or tighten.
+    pub fn get_pmf(&self, key: &str, options: &PmfOptions) -> Result<Vec<f64>, ParamError> {
+        params::pmf(&self.input_json, key, options)
+    }
+
+    /// The matrix at `key`, given as nested rows or as an object with
+    /// `rows`, `cols` and row-major `data`. Ragged rows and entries that are
+    /// not finite numbers are errors naming the entry.
+    pub fn get_matrix(&self, key: &str) -> Result<Matrix, ParamError> {
+        params::matrix(&self.input_json, key)
+    }
+
+    /// The delay distribution at `key`, such as
+    /// `{"distribution": "gamma", "mean": 4.2, "sd": 1.7}`, with its
+    /// parameters checked.
+    pub fn get_distribution(&self, key: &str) -> Result<DistributionSpec, ParamError> {
+        params::distribution(&self.input_json, key)
+    }
+
+    /// The ISO-8601 date at `key`, such as `"start_date": "2024-01-01"`.
+    pub fn get_date(&self, key: &str) -> Result<calendar::Date, ParamError> {
+        self.get(key)
+    }
+
+    /// The duration at `key`, given as days or as a string like
+    /// `"28 days"` or `"4 weeks"`.
+    pub fn get_duration(&self, key: &str) -> Result<calendar::Days, ParamError> {
+        self.get(key)
+    }
+
+    /// The input object at dotted path `path`, e.g. `observation.delay`.
+    /// The error names the first segment that is missing or not an object.
+    pub fn section(&self, path: &str) -> Result<serde_json::Map<String, Value>, ParamError> {
+        params::section(&self.input_json, path).cloned()
+    }
+
+    /// The input object at `path` as `T`, so a model component can own its
+    /// part of the input. A failure inside it names the full path.
+    pub fn section_as<T: DeserializeOwned>(&self, path: &str) -> Result<T, ParamError> {
+        params::section_as(&self.input_json, path)
+    }
+
+    /// Read a block of parameters with [`ParamCollector::require`] and
+    /// [`ParamCollector::get_or`], then report every failure at once from
+    /// [`ParamCollector::finish`].
+    pub fn params(&self) -> ParamCollector<'_> {
+        ParamCollector::new(&self.input_json)
+    }
+
+    /// Check that every `model.files` entry exists and can be read, so a
+    /// bad path fails up front with every problem listed rather than
+    /// halfway through a run.
+    pub fn validate_files(&self) -> Result<(), FilesError> {
+        files_error::check(&self.files, &[])
+    }
+
+    /// [`Environment::validate_files`], also reporting any of `required`
+    /// that are not in `model.files`.
+    pub fn validate_required_files(&self, required: &[&str]) -> Result<(), FilesError> {
+        files_error::check(&self.files, required)
+    }
+
+    /// Warn, once, that the run has no seed, if neither the payload nor
+    /// the defaults applied since gave it one. Called when the seed is
+    /// first used, so that defaults are in by then.
+    fn warn_missing_seed(&self) {
+        if self.missing_seed.replace(false) && self.input_json.get("seed").is_none() {
+            eprintln!(
+                "warning [missing_seed]: the input has no seed, so this run uses seed 0 and \
+                 repeats every other unseeded run; set \"seed\": null for a random seed"
+            );
+        }
+    }
+
+    fn seed_value(&self) -> u64 {
+        self.input_json
+            .get("seed")
+            .and_then(|v| v.as_u64())
+            .unwrap_or(0)
+    }
+
+    /// The input as seen by the model, with `replicate` re-included.
+    fn resolved_input(&self) -> Value {
+        let mut input = match &self.input_json {
+            Value::Object(m) => m.clone(),
+            _ => Default::default(),
+        };
+        input.insert("replicate".to_string(), Value::from(self.replicate));
+        Value::Object(input)
+    }
+
+    /// The managed CSV writers, for flushing from a panic hook.
+    pub(crate) fn csv_writers(&self) -> Weak<RefCell<HashMap<String, CsvWriter>>> {
+        Rc::downgrade(&self.csv_writers)
+    }
+
+    fn record_output_as(&self, filename: &str, spec_name: &str) {
+        self.try_record_output_as(filename, spec_name)
+            .unwrap_or_else(|e| panic!("{e}"));
+    }
+
+    fn try_record_output_as(&self, filename: &str, spec_name: &str) -> Result<(), MrpError> {
+        check_output_filename(filename)?;
+        if self.strict_outputs && !self.output_schemas.contains_key(spec_name) {
+            return Err(MrpError::Output(format!(
+                "output '{filename}' has no declared schema (strict outputs enabled)"
+            )));
+        }
+        self.produced.borrow_mut().insert(filename.to_string());
+        self.provenance.borrow_mut().start_output(filename);
+        Ok(())
+    }
+
+    fn finish_output(&self, filename: &str) {
+        self.provenance.borrow_mut().finish_output(filename);
+    }
+}
+
+impl Default for Environment<()> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Check that `filename` names a file inside the output directory: it is
+/// relative, and no `..` takes it above where it starts. Subdirectories
+/// are fine.
+pub(crate) fn check_output_filename(filename: &str) -> Result<(), MrpError> {
+    use std::path::Component;
+
+    let error = |why: &str| {
+        MrpError::Output(format!(
+            "output filename '{filename}' {why}; give a path relative to the output directory"
+        ))
+    };
+    let mut depth = 0usize;
+    for component in Path::new(filename).components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir => {
+                depth = depth
+                    .checked_sub(1)
+                    .ok_or_else(|| error("escapes the output directory"))?;
+            }
+            Component::RootDir | Component::Prefix(_) => return Err(error("is absolute")),
+        }
+    }
+    if depth == 0 {
+        return Err(error("names no file"));
+    }
+    Ok(())
+}
+
+/// Names the output profile to use when the payload does not.
+const OUTPUT_PROFILE_VAR: &str = "MRP_OUTPUT_PROFILE";
+
+/// The output spec in use: the flat `output` table, or the `profile` entry
+/// from [`select_profile`].
+fn output_spec<'a>(output: &'a Value, profile: Option<&str>) -> Option<&'a Value> {
+    if output.get("spec").is_some() {
+        return Some(output);
+    }
+    output.get("profile")?.get(profile?)
+}
+
+/// Which entry of `output.profile` is used, and why: `output.selected`,
+/// else `from_env` (`MRP_OUTPUT_PROFILE`), else `default`, else the only
+/// profile. `None` for a flat `output` table. Naming a profile that does not
+/// exist is an error, as is leaving the choice open among several: the
+/// order of a JSON object's keys is not kept, so "the first" would depend
+/// on how serde_json was built.
+fn select_profile(
+    output: &Value,
+    from_env: Option<String>,
+) -> Result<Option<(String, &'static str)>, MrpError> {
+    let Some(profiles) = output
+        .get("profile")
+        .and_then(|v| v.as_object())
+        .filter(|_| output.get("spec").is_none())
+    else {
+        return Ok(None);
+    };
+    let named = match output.get("selected") {
+        Some(Value::String(name)) => Some((name.clone(), "output.selected")),
+        Some(other) => {
+            return Err(MrpError::Config(format!(
+                "output.selected must be a profile name, got {other}"
+            )));
+        }
+        None => from_env.map(|name| (name, OUTPUT_PROFILE_VAR)),
+    };
+    if let Some((name, source)) = named {
+        if !profiles.contains_key(&name) {
+            return Err(MrpError::Config(format!(
+                "{source} names unknown output profile '{name}' (profiles: {})",
+                profiles.keys().cloned().collect::<Vec<_>>().join(", ")
+            )));
+        }
+        return Ok(Some((name, source)));
+    }
+    if profiles.contains_key("default") {
+        return Ok(Some(("default".to_string(), "default")));
+    }
+    if profiles.len() > 1 {
+        return Err(MrpError::Config(format!(
+            "output has profiles {} but none is named \"default\"; set output.selected or \
+             {OUTPUT_PROFILE_VAR} to choose one",
+            profiles.keys().cloned().collect::<Vec<_>>().join(", ")
+        )));
+    }
+    Ok(profiles
+        .keys()
+        .next()
+        .map(|name| (name.clone(), "only profile")))
+}
+
+/// The spec whose destination the Environment writes to: the first of a
+/// `"multi"` spec's `"sinks"`, else `spec` itself.
+fn first_sink(spec: &Value) -> Result<&Value, MrpError> {
+    if spec.get("spec").and_then(|v| v.as_str()) != Some("multi") {
+        return Ok(spec);
+    }
+    let sinks = spec.get("sinks").and_then(|v| v.as_array());
+    let Some(first) = sinks.and_then(|sinks| sinks.first()) else {
+        return Err(MrpError::Config(
+            "the multi output spec needs a non-empty \"sinks\" array".to_string(),
+        ));
+    };
+    if let Some(i) = sinks
+        .into_iter()
+        .flatten()
+        .position(|sink| !sink.is_object() || sink.get("spec") == Some(&Value::from("multi")))
+    {
+        return Err(MrpError::Config(format!(
+            "output sink {i} must be an output spec other than multi"
+        )));
+    }
+    Ok(first)
+}
+
+/// Names sink `i` of a `"multi"` spec in warnings and errors.
+fn sink_name(i: usize, spec: &Value) -> String {
+    let kind = spec
+        .get("spec")
+        .and_then(|v| v.as_str())
+        .unwrap_or("stdout");
+    let dest = match kind {
+        "filesystem" => spec.get("dir").and_then(|v| v.as_str()),
+        "s3" | "gs" | "azure" => spec.get("bucket").and_then(|v| v.as_str()),
+        _ => None,
+    };
+    match dest {
+        Some(dest) => format!("output sink {i} ({kind} {dest})"),
+        None => format!("output sink {i} ({kind})"),
+    }
+}
+
+/// The directory of a `"tempdir"` spec, created now. It is kept unless the
+/// spec sets `"keep": false`.
+fn create_output_tempdir(spec: &Value) -> Result<Option<Rc<OutputTempDir>>, MrpError> {
+    if spec.get("spec").and_then(|v| v.as_str()) != Some("tempdir") {
+        return Ok(None);
+    }
+    let keep = match spec.get("keep") {
+        None => true,
+        Some(Value::Bool(keep)) => *keep,
+        Some(other) => {
+            return Err(MrpError::Config(format!(
+                "output \"keep\" must be true or false, got {other}"
+            )));
+        }
+    };
+    OutputTempDir::create(keep).map(Some)
+}
+
+/// `dir` under the spec's `"layout"`: itself for `"flat"` (the default),
+/// or its `replicate_{replicate}` subdirectory for `"per_replicate"`, so
+/// replicates sharing a directory do not overwrite each other's files.
+fn scope_output_dir(spec: &Value, dir: PathBuf, replicate: u64) -> Result<PathBuf, MrpError> {
+    match spec.get("layout") {
+        None => Ok(dir),
+        Some(Value::String(layout)) if layout == "flat" => Ok(dir),
+        Some(Value::String(layout)) if layout == "per_replicate" => {
+            Ok(dir.join(format!("replicate_{replicate}")))
+        }
+        Some(other) => Err(MrpError::Config(format!(
+            "output layout must be \"flat\" or \"per_replicate\", got {other}"
+        ))),
+    }
+}
+
+/// The filesystem output directory of `spec`, checked against what is on disk.
+///
+/// An existing non-directory is an error. A symlink to a directory resolves
+/// to its target, or is an error if the spec sets `"follow_symlinks": false`.
+fn resolve_output_dir(spec: &Value) -> Result<Option<PathBuf>, MrpError> {
+    if spec.get("spec").and_then(|v| v.as_str()) != Some("filesystem") {
+        return Ok(None);
+    }
+    let Some(dir) = spec.get("dir").and_then(|v| v.as_str()).map(PathBuf::from) else {
+        return Ok(None);
+    };
+    let Ok(meta) = fs::symlink_metadata(&dir) else {
+        // Created on first write
+        return Ok(Some(dir));
+    };
+    if meta.file_type().is_symlink() {
+        if spec.get("follow_symlinks").and_then(|v| v.as_bool()) == Some(false) {
+            return Err(MrpError::Output(format!(
+                "output dir '{}' is a symlink and follow_symlinks is false",
+                dir.display()
+            )));
+        }
+        let target = fs::canonicalize(&dir).map_err(|e| {
+            MrpError::Output(format!(
+                "output dir '{}' is a symlink that cannot be resolved: {e}",
+                dir.display()
+            ))
+        })?;
+        if !target.is_dir() {
+            return Err(MrpError::Output(format!(
+                "output dir '{}' links to '{}', which is not a directory",
+                dir.display(),
+                target.display()
+            )));
+        }
+        return Ok(Some(target));
+    }
+    if !meta.is_dir() {
+        return Err(MrpError::Output(format!(
+            "output dir '{}' exists and is not a directory",
+            dir.display()
+        )));
+    }
+    Ok(Some(dir))
+}
+
+/// What `~` and relative output directories resolve against.
+struct DirBases<'a> {
+    home: Option<PathBuf>,
+    cwd: Option<PathBuf>,
+    payload_dir: Option<&'a Path>,
+    /// Set by [`Environment::set_output_base`], replacing `"base"`.
+    base: Option<&'a Path>,
+}
+
+impl<'a> DirBases<'a> {
+    fn from_env(payload_dir: Option<&'a Path>, base: Option<&'a Path>) -> Self {
+        DirBases {
+            home: std::env::var_os("HOME")
+                .or_else(|| std::env::var_os("USERPROFILE"))
+                .filter(|home| !home.is_empty())
+                .map(PathBuf::from),
+            cwd: std::env::current_dir().ok(),
+            payload_dir,
+            base,
+        }
+    }
+}
+
+/// Make the `"dir"` (or an archive's `"path"`) of the output spec, its
+/// profiles and its sinks absolute, so [`Environment::output_dir`] does not depend on where the
+/// model runs from. A leading `~` is the home directory; a relative path
+/// is under the spec's `"base"`: `"cwd"` (the default) or `"payload_dir"`,
+/// the directory of a payload read by [`Environment::from_file`]. A spec
+/// without `"base"` takes that of the spec it is in.
+fn resolve_output_dirs(spec: &mut Value, bases: &DirBases) -> Result<(), MrpError> {
+    fn resolve(spec: &mut Value, inherited: &str, bases: &DirBases) -> Result<(), MrpError> {
+        let base = match spec.get("base") {
+            None => inherited.to_string(),
+            Some(Value::String(base)) if base == "cwd" || base == "payload_dir" => base.clone(),
+            Some(other) => {
+                return Err(MrpError::Config(format!(
+                    "output \"base\" must be \"cwd\" or \"payload_dir\", got {other}"
+                )));
+            }
+        };
+        for key in ["dir", "path"] {
+            if let Some(dir) = spec.get(key).and_then(|v| v.as_str()) {
+                spec[key] = Value::from(resolve_dir(key, dir, &base, bases)?.to_string_lossy());
+            }
+        }
+        if let Some(profiles) = spec.get_mut("profile").and_then(|v| v.as_object_mut()) {
+            for profile in profiles.values_mut() {
+                resolve(profile, &base, bases)?;
+            }
+        }
+        if let Some(sinks) = spec.get_mut("sinks").and_then(|v| v.as_array_mut()) {
+            for sink in sinks {
+                resolve(sink, &base, bases)?;
+            }
+        }
+        Ok(())
+    }
+    resolve(spec, "cwd", bases)
+}
+
+/// `dir`, the spec's `key`, with `~` expanded and, if relative, joined to
+/// `base`.
+fn resolve_dir(key: &str, dir: &str, base: &str, bases: &DirBases) -> Result<PathBuf, MrpError> {
+    let dir = match dir.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with(['/', '\\']) => {
+            let home = bases.home.as_ref().ok_or_else(|| {
+                MrpError::Config(format!(
+                    "output {key} '{dir}' starts with ~ but HOME is not set"
+                ))
+            })?;
+            match rest.trim_start_matches(['/', '\\']) {
+                "" => home.clone(),
+                rest => home.join(rest),
+            }
+        }
+        _ => PathBuf::from(dir),
+    };
+    if dir.is_absolute() {
+        return Ok(dir);
+    }
+    let base = match (bases.base, base) {
+        (Some(base), _) => base,
+        (None, "payload_dir") => bases.payload_dir.ok_or_else(|| {
+            MrpError::Config(format!(
+                "output {key} '{}' is relative to the payload's directory, but the payload \
+                 was not read from a file",
+                dir.display()
+            ))
+        })?,
+        _ => bases.cwd.as_deref().ok_or_else(|| {
+            MrpError::Config(format!(
+                "output {key} '{}' is relative, and the working directory is unknown",
+                dir.display()
+            ))
+        })?,
+    };
+    Ok(base.join(dir))
+}
+
+/// Top-level payload keys read by MRP, for strict parsing.
+const PAYLOAD_KEYS: &[&str] = &[
+    "mrp",
+    "model",
+    "runtime",
+    "input",
+    "output",
+    "cache",
+    "cancel_on_sigterm",
+    "interpolate_env",
+    "io_threads",
+    "keep_scratch",
+    "manifest",
+    "max_run_seconds",
+    "max_stall_seconds",
+    "negative_control",
+    "progress_interval_ms",
+    "redact",
+    "input_sets",
+    "allow_missing_seed",
+    "replicates",
+    "report",
+    "resolved_input",
+    "rng",
+    "rng_streams",
+    "scratch_dir",
+    "warning_limit",
+];
+
+/// Keys read from an output spec.
+const OUTPUT_SPEC_KEYS: &[&str] = &[
+    "spec",
+    "dir",
+    "format",
+    "follow_symlinks",
+    "on_write_failure",
+    "fallback_dir",
+    "csv_filters",
+    "bucket",
+    "prefix",
+    "retry",
+    "spool_dir",
+    "keep",
+    "sinks",
+    "require_all",
+    "layout",
+    "atomic",
+    "if_exists",
+    "base",
+    "path",
+];
+
+/// Keys of the `output` table, and of each of its profiles, that are not
+/// read, as `(where, key)`.
+fn unknown_output_keys(output: &Value) -> Vec<(String, String)> {
+    let mut unknown = Vec::new();
+    let mut check = |path: String, spec: &Value, extra: &[&str]| {
+        for key in spec.as_object().into_iter().flat_map(|m| m.keys()) {
+            if !OUTPUT_SPEC_KEYS.contains(&key.as_str()) && !extra.contains(&key.as_str()) {
+                unknown.push((path.clone(), key.clone()));
+            }
+        }
+    };
+    check("\"output\"".to_string(), output, &["profile", "selected"]);
+    if let Some(profiles) = output.get("profile").and_then(|v| v.as_object()) {
+        for (name, spec) in profiles {
+            check(format!("output profile {name:?}"), spec, &[]);
+        }
+    }
+    let specs = std::iter::once(("\"output\"".to_string(), output)).chain(
+        output
+            .get("profile")
+            .and_then(|v| v.as_object())
+            .into_iter()
+            .flatten()
+            .map(|(name, spec)| (format!("output profile {name:?}"), spec)),
+    );
+    for (path, spec) in specs {
+        let sinks = spec.get("sinks").and_then(|v| v.as_array());
+        for (i, sink) in sinks.into_iter().flatten().enumerate() {
+            check(format!("{path} sink {i}"), sink, &[]);
+        }
+    }
+    unknown
+}
+
+/// Metrics published by [`Environment::update_metric`] before finalize.
+const PARTIAL_METRICS: &str = "metrics.partial.json";
+const METRICS: &str = "metrics.json";
+const MANIFEST: &str = "manifest.json";
+const COMPLETE: &str = "complete.json";
+const ERROR: &str = "error.json";
+const RESOLVED_INPUT: &str = "resolved_input.json";
+
+/// Exit status of [`Environment::fail`], so a runner can tell a failure the
+/// model recorded in `error.json` from a crash (101 for a panic) or a
+/// generic error exit (1).
+pub const FAILURE_EXIT_CODE: i32 = 3;
+
+/// Exit status of the infallible constructors, such as
+/// [`Environment::from_stdin`], when the payload cannot be read or built.
+pub const PAYLOAD_EXIT_CODE: i32 = 2;
+
+/// The value of `result`, or print its error to stderr and exit with
+/// [`PAYLOAD_EXIT_CODE`]: a bad payload is the runner's mistake, not a
+/// crash, so it gets neither a panic message nor a backtrace.
+fn exit_on_error<T>(result: Result<T, MrpError>) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("error: {e}");
+        std::process::exit(PAYLOAD_EXIT_CODE)
+    })
+}
+
+/// Payloads nested deeper than this are rejected; serde_json's parser stops
+/// at the same depth.
+const MAX_PAYLOAD_DEPTH: usize = 128;
+
+fn json_depth(value: &Value) -> usize {
+    let mut max = 0;
+    let mut stack = vec![(value, 1)];
+    while let Some((v, depth)) = stack.pop() {
+        max = max.max(depth);
+        match v {
+            Value::Array(items) => stack.extend(items.iter().map(|i| (i, depth + 1))),
+            Value::Object(map) => stack.extend(map.values().map(|i| (i, depth + 1))),
+            _ => {}
+        }
+    }
+    max
+}
+
+/// Drop a value without recursing, so arbitrarily deep input cannot overflow
+/// the stack.
+fn drop_iteratively(value: Value) {
+    let mut stack = vec![value];
+    while let Some(v) = stack.pop() {
+        match v {
+            Value::Array(items) => stack.extend(items),
+            Value::Object(map) => stack.extend(map.into_iter().map(|(_, v)| v)),
+            _ => {}
+        }
+    }
+}
+
+fn ignored(code: &str, message: String) -> Warning {
+    Warning {
+        code: code.to_string(),
+        message,
+        error_code: None,
+    }
+}
+
+/// The payload's replicate, files, input and output, with a warning for
+/// each element that is present but ignored.
+type Common = (u64, HashMap<String, PathBuf>, Value, Value, Vec<Warning>);
+
+/// Split the payload into its common parts. An `input`, `model.files` or
+/// `output` that is present but not an object (or null) is an error rather
+/// than being read as empty, which would run the model on its defaults.
+fn extract_common(data: &Value) -> Result<Common, MrpError> {
+    let mut warnings = Vec::new();
+    let mut input_map = match data.get("input") {
+        None | Some(Value::Null) => Default::default(),
+        Some(Value::Object(m)) => m.clone(),
+        Some(other) => return Err(not_an_object("input", other)),
+    };
+
+    let replicate = match input_map.remove("replicate") {
+        None | Some(Value::Null) => 0,
+        Some(v) => parse_index("replicate", &v)?,
+    };
+
+    let input_json = Value::Object(input_map);
+
+    let mut files = HashMap::new();
+    match data.get("model").and_then(|m| m.get("files")) {
+        None | Some(Value::Null) => {}
+        Some(Value::Object(m)) => {
+            for (k, v) in m {
+                match v.as_str() {
+                    Some(s) => {
+                        files.insert(k.clone(), PathBuf::from(s));
+                    }
+                    None => warnings.push(ignored(
+                        "ignored_file",
+                        format!("file '{k}' is not a path string and was ignored: {v}"),
+                    )),
+                }
+            }
+        }
+        Some(other) => return Err(not_an_object("model.files", other)),
+    }
+
+    let output = match data.get("output") {
+        None | Some(Value::Null) => Value::Object(Default::default()),
+        Some(output @ Value::Object(_)) => output.clone(),
+        Some(other) => return Err(not_an_object("output", other)),
+    };
+
+    Ok((replicate, files, input_json, output, warnings))
+}
+
+/// How the input's seed was given.
+#[derive(Debug, Default)]
+struct SeedSource {
+    table: Option<Vec<u64>>,
+    generated: bool,
+    missing: bool,
+}
+
+/// Replace the input's `seed` with the number the model sees: parsed, taken
+/// from a seed table, or drawn from OS entropy for `null` or `"random"`.
+fn resolve_seed(input: &mut Value, replicate: u64) -> Result<SeedSource, MrpError> {
+    let Some(seed) = input.get_mut("seed") else {
+        return Ok(SeedSource {
+            missing: true,
+            ..Default::default()
+        });
+    };
+    if seed.is_null() || seed.as_str() == Some("random") {
+        *seed = entropy_seed().into();
+        return Ok(SeedSource {
+            generated: true,
+            ..Default::default()
+        });
+    }
+    // Stored back as a number, so the model and derived seeds agree
+    let (value, table) = parse_seed(seed, replicate)?;
+    *seed = value.into();
+    Ok(SeedSource {
+        table,
+        ..Default::default()
+    })
+}
+
+/// The count of a payload's `"replicates": {"count": n}`.
+fn parse_replicates(value: &Value) -> Result<u64, MrpError> {
+    match value.get("count").and_then(|v| v.as_u64()) {
+        Some(count) if count > 0 && value.as_object().is_some_and(|m| m.len() == 1) => Ok(count),
+        _ => Err(MrpError::Input(format!(
+            "\"replicates\" must be {{\"count\": n}} with n a positive integer, got {value}"
+        ))),
+    }
+}
+
+/// A payload `seed`: one seed, or a table of one per replicate from which
+/// `replicate`'s is taken, returned with the table.
+fn parse_seed(value: &Value, replicate: u64) -> Result<(u64, Option<Vec<u64>>), MrpError> {
+    let Value::Array(entries) = value else {
+        return Ok((parse_index("seed", value)?, None));
+    };
+    let table = entries
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| parse_index(&format!("seed[{i}]"), entry))
+        .collect::<Result<Vec<_>, _>>()?;
+    let seed = table_seed(&table, replicate)?;
+    Ok((seed, Some(table)))
+}
+
+fn table_seed(table: &[u64], replicate: u64) -> Result<u64, MrpError> {
+    usize::try_from(replicate)
+        .ok()
+        .and_then(|r| table.get(r))
+        .copied()
+        .ok_or_else(|| {
+            MrpError::Input(format!(
+                "replicate {replicate} is out of range for the seed table of {} seeds",
+                table.len()
+            ))
+        })
+}
+
+/// A seed or replicate: a non-negative integer, as a number or a string
+/// such as `"42"`. Anything else is an error rather than being read as 0,
+/// which would give different payloads the same draws.
+pub(crate) fn parse_index(key: &str, value: &Value) -> Result<u64, MrpError> {
+    let parsed = match value {
+        Value::Number(n) => n.as_u64().or_else(|| {
+            n.as_f64()
+                .filter(|f| f.fract() == 0.0 && *f >= 0.0 && *f < u64::MAX as f64)
+                .map(|f| f as u64)
+        }),
+        Value::String(s) => s.trim().parse::<u64>().ok(),
+        _ => None,
+    };
+    parsed.ok_or_else(|| {
+        MrpError::Input(format!(
+            "\"{key}\" must be a non-negative integer or a string of one, found {value}"
+        ))
+    })
+}
+
+/// A failed write of `what`, or the stall that caused it.
+fn write_error(what: &str, e: io::Error) -> MrpError {
+    stream::stall_error(&e)
+        .unwrap_or_else(|| MrpError::Output(format!("failed to write {what}: {e}")))
+}
+
+pub(crate) fn not_an_object(key: &str, value: &Value) -> MrpError {
+    let mut found = value.to_string();
+    if found.len() > 80 {
+        found.truncate(found.floor_char_boundary(77));
+        found.push_str("...");
+    }
+    MrpError::Input(format!("\"{key}\" must be an object, found {found}"))
+}
+
+/// The value at a dotted `path` inside the input, if present.
+fn input_path<'a>(input: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(input, |value, key| value.get(key))
+}
+
+pub(crate) fn merge_defaults(target: &mut Value, defaults: &Value) {
+    if let (Value::Object(target), Value::Object(defaults)) = (target, defaults) {
+        for (key, default) in defaults {
+            match target.get_mut(key) {
+                Some(value) => merge_defaults(value, default),
+                None => {
+                    target.insert(key.clone(), default.clone());
+                }
+            }
+        }
+    }
+}
+
+/// Set a dotted `path` inside the input object, creating missing
+/// intermediate objects. An intermediate that holds anything other than
+/// an object is an error rather than being replaced.
+fn set_input_path(
+    input: &mut Value,
+    path: &str,
+    value: Value,
+    source: &str,
+) -> Result<InputOverride, MrpError> {
+    let parts: Vec<&str> = path.split('.').collect();
+    let mut target = input;
+    for (depth, part) in parts.iter().enumerate() {
+        let Some(object) = target.as_object_mut() else {
+            let parent = if depth == 0 {
+                "input".to_string()
+            } else {
+                format!("'{}'", parts[..depth].join("."))
+            };
+            return Err(MrpError::Input(format!(
+                "cannot set '{path}' from {source}: {parent} is not an object"
+            )));
+        };
+        if depth == parts.len() - 1 {
+            let previous = object.insert(part.to_string(), value.clone());
+            return Ok(InputOverride {
+                path: path.to_string(),
+                previous,
+                value,
+                source: source.to_string(),
+            });
+        }
+        target = object
+            .entry(part.to_string())
+            .or_insert_with(|| Value::Object(Default::default()));
+    }
+    unreachable!("split always yields at least one part")
+}
+
+/// Names the [`Mode`] the model binary runs in.
+const MODE_VAR: &str = "MRP_MODE";
+
+fn parse_mode(mode: Option<&str>) -> Result<Mode, MrpError> {
+    match mode {
+        None | Some("" | "run") => Ok(Mode::Run),
+        Some("schema") => Ok(Mode::Schema),
+        Some(other) => Err(MrpError::Config(format!(
+            "{MODE_VAR} must be run or schema, got '{other}'"
+        ))),
+    }
+}
+
+/// Names the one format to read a stdin payload as, instead of trying
+/// JSON and then YAML.
+const INPUT_FORMAT_VAR: &str = "MRP_INPUT_FORMAT";
+
+fn read_stdin() -> Result<Value, MrpError> {
+    let mut buf = String::new();
+    io::stdin()
+        .lock()
+        .read_to_string(&mut buf)
+        .map_err(|e| MrpError::Input(format!("failed to read payload from stdin: {e}")))?;
+    parse_stdin(&buf, std::env::var(INPUT_FORMAT_VAR).ok().as_deref())
+}
+
+/// A stdin payload in the format `hint` names, else sniffed.
+fn parse_stdin(text: &str, hint: Option<&str>) -> Result<Value, MrpError> {
+    let format = match hint.map(str::to_ascii_lowercase).as_deref() {
+        None | Some("") => return parse_json_or_yaml(text, "stdin"),
+        Some("json") => PayloadFormat::Json,
+        Some("yaml" | "yml") => PayloadFormat::Yaml,
+        Some("toml") => PayloadFormat::Toml,
+        Some(other) => {
+            return Err(MrpError::Config(format!(
+                "{INPUT_FORMAT_VAR} must be json, yaml or toml, got '{other}'"
+            )));
+        }
+    };
+    parse_as(text, format, "stdin")
+}
+
+fn read_payload<R: Read>(mut reader: R, source: &str) -> Result<Value, MrpError> {
+    let mut buf = String::new();
+    reader
+        .read_to_string(&mut buf)
+        .map_err(|e| MrpError::Input(format!("failed to read payload from {source}: {e}")))?;
+    if buf.trim().is_empty() {
+        return Err(MrpError::Input(format!("{source} is empty")));
+    }
+    parse_json_or_yaml(&buf, source)
+}
+
+fn parse_as(text: &str, format: PayloadFormat, source: &str) -> Result<Value, MrpError> {
+    match format {
+        PayloadFormat::Json => parse_payload(text, source),
+        PayloadFormat::Toml => parse_toml(text, source),
+        PayloadFormat::Yaml => parse_yaml(text, source),
+    }
+}
+
+/// Parse a JSON payload, else a YAML one, naming both failures if neither
+/// parses.
+fn parse_json_or_yaml(text: &str, source: &str) -> Result<Value, MrpError> {
+    let json = match parse_payload(text, source) {
+        Ok(data) => return Ok(data),
+        Err(MrpError::Input(e)) => e,
+        Err(e) => return Err(e),
+    };
+    parse_yaml(text, source).map_err(|yaml| {
+        let yaml = match yaml {
+            MrpError::Input(e) => e,
+            e => e.to_string(),
+        };
+        MrpError::Input(format!("{json}; read as YAML instead, {yaml}"))
+    })
+}
+
+fn read_file(path: &Path) -> Result<Value, MrpError> {
+    let contents = fs::read_to_string(path).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => {
+            MrpError::FileNotFound(format!("payload file '{}'", path.display()))
+        }
+        _ => MrpError::Input(format!(
+            "failed to read payload file '{}': {e}",
+            path.display()
+        )),
+    })?;
+    let source = format!("payload file '{}'", path.display());
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("toml") => parse_toml(&contents, &source),
+        Some("yaml" | "yml") => parse_yaml(&contents, &source),
+        _ => parse_payload(&contents, &source),
+    }
+}
+
+/// Parse a JSON payload; blank text is an empty payload.
+fn parse_payload(text: &str, source: &str) -> Result<Value, MrpError> {
+    if text.trim().is_empty() {
+        return Ok(Value::Object(Default::default()));
+    }
+    serde_json::from_str(text)
+        .map_err(|e| MrpError::Input(format!("{source} is not valid JSON: {e}")))
+}
+
+fn parse_toml(text: &str, source: &str) -> Result<Value, MrpError> {
+    let table: toml::Value = text
+        .parse()
+        .map_err(|e| MrpError::Input(format!("{source} is not valid TOML: {e}")))?;
+    Ok(toml_to_json(table))
+}
+
+/// Parse a YAML payload; blank text is an empty payload, and anything but
+/// a mapping is an error.
+fn parse_yaml(text: &str, source: &str) -> Result<Value, MrpError> {
+    if text.trim().is_empty() {
+        return Ok(Value::Object(Default::default()));
+    }
+    let data: Value = serde_yaml::from_str(text)
+        .map_err(|e| MrpError::Input(format!("{source} is not valid YAML: {e}")))?;
+    if !data.is_object() {
+        return Err(MrpError::Input(format!(
+            "{source} is not a YAML mapping: found {}",
+            json_kind(&data)
+        )));
+    }
+    Ok(data)
+}
+
+fn json_kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "a sequence",
+        Value::Object(_) => "a mapping",
+    }
+}
+
+pub fn toml_to_json(val: toml::Value) -> Value {
+    match val {
+        toml::Value::String(s) => Value::String(s),
+        toml::Value::Integer(i) => Value::Number(serde_json::Number::from(i)),
+        toml::Value::Float(f) => serde_json::Number::from_f64(f).map_or(Value::Null, Value::Number),
+        toml::Value::Boolean(b) => Value::Bool(b),
+        toml::Value::Datetime(d) => Value::String(d.to_string()),
+        toml::Value::Array(arr) => Value::Array(arr.into_iter().map(toml_to_json).collect()),
+        toml::Value::Table(table) => {
+            let map = table
+                .into_iter()
+                .map(|(k, v)| (k, toml_to_json(v)))
+                .collect();
+            Value::Object(map)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compare::list_files;
+    use crate::csv::StringPolicy;
+    use crate::formats::Manifest;
+    use crate::schema::ColumnType;
+    use crate::validate::ValidationError;
+
+    #[test]
+    fn test_from_json_empty() {
+        let env = Environment::from_json(Value::Object(Default::default()));
+        assert_eq!(env.replicate, 0);
+        assert!(env.files.is_empty());
+        assert!(env.output_dir().is_none());
+    }
+
+    #[test]
+    fn test_from_json_with_input() {
+        let data = serde_json::json!({
+            "input": { "seed": 42, "replicate": 3, "r0": 2.0 },
+            "output": { "spec": "filesystem", "dir": "/tmp/out" },
+            "model": { "files": { "data": "/tmp/data.csv" } }
+        });
+        let env = Environment::from_json(data);
+        assert_eq!(env.replicate, 3);
+        assert_eq!(
+            env.files.get("data").unwrap(),
+            &PathBuf::from("/tmp/data.csv")
+        );
+        assert_eq!(env.output_dir(), Some(PathBuf::from("/tmp/out")));
+    }
+
+    #[test]
+    fn test_typed_input() {
+        #[derive(serde::Deserialize, Debug)]
+        struct MyInput {
+            r0: f64,
+            seed: u64,
+        }
+        let data = serde_json::json!({
+            "input": { "seed": 1, "r0": 2.5 }
+        });
+        let env = Environment::<MyInput>::from_json_typed(data);
+        let input = env.input.unwrap();
+        assert_eq!(input.seed, 1);
+        assert!((input.r0 - 2.5).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_with_input_type() {
+        #[derive(serde::Deserialize, Debug)]
+        struct MyInput {
+            r0: f64,
+            seed: u64,
+        }
+        let data = serde_json::json!({
+            "input": { "seed": 5, "r0": 1.5 }
+        });
+        let env = Environment::from_json(data);
+        let typed: Environment<MyInput> = env.with_input_type();
+        assert_eq!(typed.input.as_ref().unwrap().seed, 5);
+        assert!((typed.input.unwrap().r0 - 1.5).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_validated_input_reports_every_failure() {
+        #[derive(serde::Deserialize, Debug)]
+        struct MyInput {
+            r0: f64,
+            pmf: Vec<f64>,
+        }
+        impl Validate for MyInput {
+            fn validate(&self) -> Result<(), Vec<ValidationError>> {
+                let mut errors = Vec::new();
+                if self.r0 < 0.0 {
+                    errors.push(ValidationError::new("r0", "must be non-negative"));
+                }
+                if (self.pmf.iter().sum::<f64>() - 1.0).abs() > 1e-9 {
+                    errors.push(ValidationError::new("pmf", "must sum to 1"));
+                }
+                if errors.is_empty() {
+                    Ok(())
+                } else {
+                    Err(errors)
+                }
+            }
+        }
+        let valid = Environment::from_json(serde_json::json!({
+            "input": { "r0": 1.5, "pmf": [0.5, 0.5] }
+        }))
+        .with_input_type_validated::<MyInput>();
+        assert_eq!(valid.input.unwrap().r0, 1.5);
+
+        let err = Environment::from_json(serde_json::json!({
+            "input": { "r0": -1.0, "pmf": [1.0, 2.0] }
+        }))
+        .try_with_input_type_validated::<MyInput>()
+        .err()
+        .unwrap();
+        assert_eq!(err.kind(), "input");
+        assert_eq!(
+            err.to_string(),
+            "input error: 2 invalid input values:\n  r0: must be non-negative\n  pmf: must sum to 1"
+        );
+
+        let err = Environment::from_json(serde_json::json!({ "input": { "r0": "high" } }))
+            .try_with_input_type_validated::<MyInput>()
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("r0"), "{err}");
+    }
+
+    #[test]
+    fn test_input_hash_ignores_key_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let env = |input: &str| {
+            let input: Value = serde_json::from_str(input).unwrap();
+            Environment::from_json(serde_json::json!({
+                "input": input,
+                "output": { "spec": "filesystem", "dir": dir.path().to_str().unwrap() },
+            }))
+        };
+        let a = env(r#"{ "seed": 1, "replicate": 2, "r0": 2.0, "delay": { "mean": 3, "sd": 1 } }"#);
+        let b = env(r#"{ "delay": { "sd": 1, "mean": 3.0 }, "r0": 2, "replicate": 2, "seed": 1 }"#);
+        assert_eq!(a.input_hash(), b.input_hash());
+        assert_eq!(a.input_hash().len(), 64);
+
+        let other_draw =
+            env(r#"{ "seed": 1, "replicate": 3, "r0": 2, "delay": { "mean": 3, "sd": 1 } }"#);
+        assert_ne!(a.input_hash(), other_draw.input_hash());
+        assert_eq!(a.parameter_hash(), other_draw.parameter_hash());
+        let other_params =
+            env(r#"{ "seed": 1, "replicate": 2, "r0": 2.5, "delay": { "mean": 3, "sd": 1 } }"#);
+        assert_ne!(a.parameter_hash(), other_params.parameter_hash());
+
+        assert!(!a.output_exists_for_hash("output_{hash}.csv"));
+        b.write_str(&format!("output_{}.csv", b.input_hash()), "step\n");
+        assert!(a.output_exists_for_hash("output_{hash}.csv"));
+        assert!(!other_draw.output_exists_for_hash("output_{hash}.csv"));
+    }
+
+    #[test]
+    fn test_resolved_input_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut env = Environment::from_json(serde_json::json!({
+            "input": { "seed": "7", "replicate": 2, "r0": 2.0, "token": "secret" },
+            "output": { "spec": "filesystem", "dir": dir.path().to_str().unwrap() },
+            "redact": ["token"],
+            "resolved_input": true,
+        }))
+        .with_defaults(serde_json::json!({ "delay": 3 }));
+        env.apply_overrides(&[("r0".to_string(), "2.5".to_string())])
+            .unwrap();
+        env.finalize().unwrap();
+        let written: Value =
+            serde_json::from_slice(&fs::read(dir.path().join(RESOLVED_INPUT)).unwrap()).unwrap();
+        assert_eq!(
+            written,
+            serde_json::json!({
+                "seed": 7, "replicate": 2, "r0": 2.5, "delay": 3, "token": "[redacted]"
+            })
+        );
+
+        let other = tempfile::tempdir().unwrap();
+        let mut env = Environment::from_json(serde_json::json!({
+            "input": { "r0": 2.0 },
+            "output": { "spec": "filesystem", "dir": other.path().to_str().unwrap() },
+        }));
+        env.finalize().unwrap();
+        assert!(!other.path().join(RESOLVED_INPUT).exists());
+    }
+
+    #[test]
+    fn test_input_sets_write_distinct_outputs() {
+        #[derive(serde::Deserialize)]
+        struct Growth {
+            rate: f64,
+            steps: usize,
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let env = Environment::from_json(serde_json::json!({
+            "input": { "steps": 3, "seed": 1 },
+            "input_sets": [{ "rate": 2.0 }, { "rate": 3.0, "seed": 2 }],
+            "output": { "spec": "filesystem", "dir": dir.path().to_str().unwrap() },
+        }));
+        for spec in env.input_sets::<Growth>().unwrap() {
+            let mut size = 1.0;
+            let rows: Vec<Vec<String>> = (0..spec.input.steps)
+                .map(|step| {
+                    size *= spec.input.rate;
+                    vec![step.to_string(), size.to_string()]
+                })
+                .collect();
+            env.write_csv(&spec.output_name("growth.csv"), &["step", "size"], &rows);
+        }
+        assert_eq!(
+            fs::read_to_string(dir.path().join("growth_0.csv")).unwrap(),
+            "step,size\n0,2\n1,4\n2,8\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.path().join("growth_1.csv")).unwrap(),
+            "step,size\n0,3\n1,9\n2,27\n"
+        );
+        let seeds: Vec<_> = env
+            .input_sets::<Growth>()
+            .unwrap()
+            .iter()
+            .map(|s| s.seed)
+            .collect();
+        assert_eq!(seeds, vec![Some(1), Some(2)]);
+
+        let single = Environment::from_json(serde_json::json!({
+            "input": { "rate": 1.5, "steps": 1 }
+        }));
+        let specs = single.input_sets::<Growth>().unwrap();
+        assert_eq!((specs.len(), specs[0].index), (1, 0));
+        assert_eq!(specs[0].input.rate, 1.5);
+    }
+
+    #[test]
+    fn test_date_and_duration_getters() {
+        let env = Environment::from_json(serde_json::json!({
+            "input": {
+                "start_date": "2024-01-01",
+                "forecast_horizon": "4 weeks",
+                "lag": 3,
+                "end_date": "2024-02-30",
+                "window": "3 fortnights",
+            }
+        }));
+        let start = env.get_date("start_date").unwrap();
+        let horizon = env.get_duration("forecast_horizon").unwrap();
+        assert_eq!(start.add_days(horizon.0.into()).to_string(), "2024-01-29");
+        assert_eq!(env.get_duration("lag").unwrap(), calendar::Days(3));
+        let err = env.get_date("end_date").unwrap_err().to_string();
+        assert!(err.contains("2024-02-30"), "{err}");
+        let err = env.get_duration("window").unwrap_err().to_string();
+        assert!(err.contains("3 fortnights"), "{err}");
+        assert!(env.get_date("missing").is_err());
+    }
+
+    #[test]
+    fn test_lenient_input_reads_numeric_strings() {
+        #[derive(serde::Deserialize, Debug)]
+        struct MyInput {
+            r0: f64,
+            seed: u64,
+            label: String,
+            pmf: Vec<f64>,
+        }
+        let payload = serde_json::json!({
+            "input": { "r0": "2.0", "seed": "3", "label": "7", "pmf": ["0.25", 0.75] }
+        });
+        assert!(
+            Environment::from_json(payload.clone())
+                .try_with_input_type::<MyInput>()
+                .is_err()
+        );
+        let env = Environment::from_json(payload)
+            .lenient()
+            .with_input_type::<MyInput>();
+        let input = env.input.as_ref().unwrap();
+        assert_eq!((input.r0, input.seed), (2.0, 3));
+        assert_eq!(input.label, "7");
+        assert_eq!(input.pmf, vec![0.25, 0.75]);
+        assert_eq!(env.input_json()["pmf"], serde_json::json!([0.25, 0.75]));
+        let warnings: Vec<_> = env.warnings().into_iter().map(|w| w.message).collect();
+        assert_eq!(
+            warnings,
+            vec![
+                "input.pmf[0]: numeric string read as a number",
+                "input.r0: numeric string read as a number"
+            ]
+        );
+
+        let err = Environment::from_json(serde_json::json!({
+            "input": { "r0": "high", "seed": 1, "label": "a", "pmf": [] }
+        }))
+        .lenient()
+        .try_with_input_type::<MyInput>()
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("high"), "{err}");
+    }
+
+    #[test]
+    fn test_input_value_read_from_file() {
+        #[derive(serde::Deserialize)]
+        struct MyInput {
+            r0: f64,
+            generation_interval_pmf: Vec<f64>,
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let pmf = dir.path().join("generation_interval.json");
+        fs::write(&pmf, "[0.1, 0.2, 0.4, 0.2, 0.1]").unwrap();
+        let env = Environment::<MyInput>::from_json_typed(serde_json::json!({
+            "input": { "r0": 2.0, "generation_interval_pmf": { "$file": "gi" } },
+            "model": { "files": { "gi": pmf.to_str().unwrap() } },
+        }));
+        let input = env.input.unwrap();
+        assert_eq!(input.r0, 2.0);
+        assert_eq!(input.generation_interval_pmf, vec![0.1, 0.2, 0.4, 0.2, 0.1]);
+        assert_eq!(
+            env.input_json["generation_interval_pmf"],
+            serde_json::json!([0.1, 0.2, 0.4, 0.2, 0.1])
+        );
+
+        let err = Environment::try_from_json(serde_json::json!({
+            "input": { "generation_interval_pmf": { "$file": "gi" } },
+        }))
+        .err()
+        .unwrap();
+        assert!(
+            err.to_string()
+                .contains("input.generation_interval_pmf: $file 'gi' (gi) not found"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_builder_matches_parsed_payload() {
+        #[derive(serde::Deserialize, Debug, PartialEq)]
+        struct MyInput {
+            r0: f64,
+            seed: u64,
+            generation_interval_pmf: Vec<f64>,
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let data = dir.path().join("data.csv");
+        let out = dir.path().join("out");
+        let built = Environment::builder()
+            .seed(42)
+            .replicate(3)
+            .param("r0", 2.0)
+            .param("generation_interval_pmf", [0.25, 0.5, 0.25])
+            .file("data", &data)
+            .output_dir(&out)
+            .build_typed::<MyInput>();
+        let parsed = Environment::<MyInput>::from_json_typed(serde_json::json!({
+            "input": {
+                "seed": 42,
+                "replicate": 3,
+                "r0": 2.0,
+                "generation_interval_pmf": [0.25, 0.5, 0.25],
+            },
+            "model": { "files": { "data": data.to_str().unwrap() } },
+            "output": { "spec": "filesystem", "dir": out.to_str().unwrap() },
+        }));
+        assert_eq!(built.payload, parsed.payload);
+        assert_eq!(built.input, parsed.input);
+        assert_eq!(built.input_json, parsed.input_json);
+        assert_eq!(built.replicate, 3);
+        assert_eq!(built.files, parsed.files);
+        assert_eq!(built.output, parsed.output);
+        assert_eq!(built.output_dir, Some(out));
+
+        let env = Environment::builder().output_stdout().build();
+        assert_eq!(env.output, serde_json::json!({ "spec": "stdout" }));
+        assert_eq!(env.output_dir, None);
+        assert!(env.input.is_none());
+    }
+
+    #[test]
+    fn test_profiled_output_dir() {
+        let data = serde_json::json!({
+            "output": {
+                "profile": {
+                    "default": { "spec": "filesystem", "dir": "/tmp/profiled" }
+                }
+            }
+        });
+        let env = Environment::from_json(data);
+        assert_eq!(env.output_dir(), Some(PathBuf::from("/tmp/profiled")));
+    }
+
+    #[test]
+    fn test_profile_selection_precedence() {
+        let output = |selected: Option<&str>| {
+            let mut output = serde_json::json!({
+                "profile": {
+                    "default": { "spec": "filesystem", "dir": "/tmp/default" },
+                    "scratch": { "spec": "filesystem", "dir": "/tmp/scratch" },
+                    "shared": { "spec": "filesystem", "dir": "/tmp/shared" }
+                }
+            });
+            if let Some(name) = selected {
+                output["selected"] = name.into();
+            }
+            output
+        };
+        let select = |output: &Value, from_env: Option<&str>| {
+            select_profile(output, from_env.map(String::from)).map(|p| p.map(|(name, _)| name))
+        };
+        assert_eq!(
+            select(&output(None), None).unwrap().as_deref(),
+            Some("default")
+        );
+        assert_eq!(
+            select(&output(None), Some("scratch")).unwrap().as_deref(),
+            Some("scratch")
+        );
+        assert_eq!(
+            select(&output(Some("shared")), Some("scratch"))
+                .unwrap()
+                .as_deref(),
+            Some("shared")
+        );
+        let err = select(&output(None), Some("nfs")).unwrap_err().to_string();
+        assert!(
+            err.contains("MRP_OUTPUT_PROFILE names unknown output profile 'nfs' (profiles: default, scratch, shared)"),
+            "{err}"
+        );
+        assert!(select(&output(Some("nfs")), None).is_err());
+        assert_eq!(
+            select(&serde_json::json!({ "spec": "stdout" }), Some("scratch")).unwrap(),
+            None
+        );
+
+        // Without a default, two profiles need an explicit choice
+        let mut undecided = output(None);
+        undecided["profile"]
+            .as_object_mut()
+            .unwrap()
+            .remove("default");
+        let err = select(&undecided, None).unwrap_err().to_string();
+        assert!(
+            err.contains("profiles scratch, shared but none is named \"default\""),
+            "{err}"
+        );
+        assert!(
+            Environment::try_from_json(serde_json::json!({ "output": undecided.clone() })).is_err()
+        );
+        assert_eq!(
+            select(&undecided, Some("shared")).unwrap().as_deref(),
+            Some("shared")
+        );
+        undecided["profile"]
+            .as_object_mut()
+            .unwrap()
+            .remove("shared");
+        assert_eq!(
+            select(&undecided, None).unwrap().as_deref(),
+            Some("scratch")
+        );
+
+        let env = Environment::from_json(serde_json::json!({ "output": output(Some("shared")) }));
+        assert_eq!(env.output_dir(), Some(PathBuf::from("/tmp/shared")));
+        assert_eq!(env.output(), &output(Some("shared")));
+        assert_eq!(
+            env.output_dir_for("default").unwrap(),
+            Some(PathBuf::from("/tmp/default"))
+        );
+    }
+
+    #[test]
+    fn test_tempdir_output() {
+        let env = Environment::from_json(serde_json::json!({ "output": { "spec": "tempdir" } }));
+        let dir = env.output_dir().unwrap();
+        assert!(dir.is_dir());
+        assert!(dir.starts_with(std::env::temp_dir()));
+        assert_eq!(env.output_dir_for("default").unwrap(), Some(dir.clone()));
+        assert_eq!(
+            env.output_tempdir.as_ref().unwrap().announcement(),
+            format!("output tempdir: {} (kept)", dir.display())
+        );
+        env.write_str("summary.json", "{}");
+        drop(env);
+        assert!(dir.join("summary.json").is_file());
+        fs::remove_dir_all(&dir).unwrap();
+
+        let other = Environment::from_json(serde_json::json!({ "output": { "spec": "tempdir" } }));
+        assert_ne!(other.output_dir(), Some(dir));
+
+        // Removed once the Environment and its open writers are gone
+        let mut env = Environment::from_json(serde_json::json!({
+            "output": { "spec": "tempdir", "keep": false }
+        }));
+        let dir = env.output_dir().unwrap();
+        assert_eq!(
+            env.output_tempdir.as_ref().unwrap().announcement(),
+            format!(
+                "output tempdir: {} (removed when the run ends)",
+                dir.display()
+            )
+        );
+        env.create_csv("cases", "cases.csv", &["step"]);
+        let mut writer = env.csv_writer("rows.csv", &["step"]);
+        drop(env);
+        assert!(dir.join("cases.csv").is_file());
+        writer.write_row(&["0"]);
+        writer.flush();
+        assert_eq!(
+            fs::read_to_string(dir.join("rows.csv")).unwrap(),
+            "step\n0\n"
+        );
+        drop(writer);
+        assert!(!dir.exists());
+
+        let err = Environment::try_from_json(serde_json::json!({
+            "output": { "spec": "tempdir", "keep": "no" }
+        }))
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("\"keep\" must be true or false"));
+    }
+
+    #[test]
+    fn test_framed_applies_only_to_stdout() {
+        let env = Environment::from_json(serde_json::json!({
+            "output": { "spec": "stdout", "framed": true }
+        }));
+        assert!(env.framed);
+        for (output, message) in [
+            (
+                serde_json::json!({ "spec": "memory", "framed": true }),
+                "applies only to the stdout spec",
+            ),
+            (
+                serde_json::json!({ "spec": "stdout", "framed": "yes" }),
+                "\"framed\" must be true or false",
+            ),
+        ] {
+            let err = Environment::try_from_json(serde_json::json!({ "output": output }))
+                .err()
+                .unwrap();
+            assert!(err.to_string().contains(message), "{err}");
+        }
+    }
+
+    #[test]
+    fn test_filename_templates() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().to_str().unwrap();
+        let mut env = Environment::from_json(serde_json::json!({
+            "input": { "seed": 7, "replicate": 2 },
+            "output": { "spec": "filesystem", "dir": format!("{base}/run_{{seed}}") }
+        }));
+        let out = dir.path().join("run_7");
+        assert_eq!(env.output_dir(), Some(out.clone()));
+        assert_eq!(
+            env.expand_template("{seed}-{replicate}-{profile}").unwrap(),
+            "7-2-default"
+        );
+        assert_eq!(env.expand_template("{run_id}").unwrap(), env.input_hash());
+        assert_eq!(env.expand_template("{{seed}}").unwrap(), "{seed}");
+
+        env.write_str("summary_{replicate}.json", "{}");
+        env.write_csv("all_{seed}.csv", &["step"], &[vec!["0".to_string()]]);
+        env.create_csv("cases", "cases_{replicate}.csv", &["step"]);
+        env.close_csv("cases");
+        let mut writer = env.csv_writer("rows_{replicate}.csv", &["step"]);
+        writer.flush();
+        for name in ["summary_2.json", "all_7.csv", "cases_2.csv", "rows_2.csv"] {
+            assert!(out.join(name).is_file(), "{name}");
+        }
+
+        let err = env.try_write_str("out_{sed}.csv", "").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "config error: unknown placeholder {sed} in 'out_{sed}.csv' \
+             (known: seed, replicate, run_id, profile)"
+        );
+        assert!(env.try_csv_writer("out_{seed.csv", &["step"]).is_err());
+        assert!(matches!(
+            env.expand_template("a}b"),
+            Err(TemplateError::UnmatchedBrace { .. })
+        ));
+        let err = Environment::try_from_json(serde_json::json!({
+            "output": { "spec": "filesystem", "dir": format!("{base}/{{nope}}") }
+        }))
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("unknown placeholder {nope}"));
+    }
+
+    #[test]
+    fn test_per_replicate_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let run = |replicate: u64| {
+            let mut env = Environment::from_json(serde_json::json!({
+                "input": { "seed": 3, "replicate": replicate },
+                "output": {
+                    "spec": "filesystem",
+                    "dir": dir.path().to_str().unwrap(),
+                    "layout": "per_replicate"
+                }
+            }));
+            let scoped = dir.path().join(format!("replicate_{replicate}"));
+            assert_eq!(env.output_dir(), Some(scoped.clone()));
+            assert_eq!(env.output_dir_for("default").unwrap(), Some(scoped));
+            env.write_str("summary.json", &replicate.to_string());
+            env.write_csv("all.csv", &["replicate"], &[vec![replicate.to_string()]]);
+            env.create_csv("cases", "cases.csv", &["replicate"]);
+            env.write_csv_row("cases", &[&replicate.to_string()]);
+            env.close_csv("cases");
+        };
+        run(0);
+        run(1);
+        for replicate in ["0", "1"] {
+            let scoped = dir.path().join(format!("replicate_{replicate}"));
+            assert_eq!(
+                fs::read_to_string(scoped.join("summary.json")).unwrap(),
+                replicate
+            );
+            for name in ["all.csv", "cases.csv"] {
+                assert_eq!(
+                    fs::read_to_string(scoped.join(name)).unwrap(),
+                    format!("replicate\n{replicate}\n")
+                );
+            }
+        }
+        assert!(!dir.path().join("summary.json").exists());
+
+        let err = Environment::try_from_json(serde_json::json!({
+            "output": { "spec": "filesystem", "dir": "/tmp/out", "layout": "nested" }
+        }))
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("\"flat\" or \"per_replicate\""));
+    }
+
+    #[test]
+    fn test_atomic_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid = std::process::id();
+        // Left by a run that is gone, and by this one
+        let stale = dir.path().join("old.csv.tmp-4294967294");
+        let ours = dir.path().join(format!("live.csv.tmp-{pid}"));
+        for path in [&stale, &ours] {
+            let file = fs::File::create(path).unwrap();
+            file.set_modified(SystemTime::UNIX_EPOCH).unwrap();
+        }
+        let mut env = Environment::from_json(serde_json::json!({
+            "output": { "spec": "filesystem", "dir": dir.path().to_str().unwrap(), "atomic": true }
+        }));
+        assert!(!stale.exists());
+        assert!(ours.exists());
+        let out = |name: &str| dir.path().join(name);
+        let tmp = |name: &str| dir.path().join(format!("{name}.tmp-{pid}"));
+
+        // A rename that fails on close is returned, not left to a warning
+        fs::create_dir(out("blocked.csv")).unwrap();
+        env.create_csv("blocked", "blocked.csv", &["step"]);
+        let err = env.try_close_csv("blocked").unwrap_err();
+        assert_eq!(err.kind(), "output");
+        assert!(err.to_string().contains("'blocked.csv' into place"), "{err}");
+        assert!(tmp("blocked.csv").exists());
+        assert!(env.warnings().is_empty());
+        fs::remove_dir(out("blocked.csv")).unwrap();
+        fs::remove_file(tmp("blocked.csv")).unwrap();
+
+        env.write_str("summary.json", "{}");
+        assert_eq!(fs::read_to_string(out("summary.json")).unwrap(), "{}");
+        assert!(!tmp("summary.json").exists());
+
+        env.create_csv("cases", "cases.csv", &["step"]);
+        env.write_csv_row("cases", &["0"]);
+        assert!(!out("cases.csv").exists());
+        assert!(tmp("cases.csv").exists());
+        env.close_csv("cases");
+        assert_eq!(fs::read_to_string(out("cases.csv")).unwrap(), "step\n0\n");
+        assert!(!tmp("cases.csv").exists());
+
+        let mut writer = env.csv_writer("rows.csv", &["step"]);
+        writer.write_row(&["1"]);
+        drop(writer);
+        assert_eq!(fs::read_to_string(out("rows.csv")).unwrap(), "step\n1\n");
+
+        // A writer dropped by a panic leaves its temp file unpublished
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let mut writer = env.csv_writer("partial.csv", &["step"]);
+            writer.write_row(&["2"]);
+            writer.flush();
+            panic!("preempted");
+        }));
+        assert!(result.is_err());
+        assert!(!out("partial.csv").exists());
+        assert_eq!(fs::read_to_string(tmp("partial.csv")).unwrap(), "step\n2\n");
+        assert!(env.warnings().is_empty());
+
+        // CSV writers alone, through CsvOptions
+        let plain = tempfile::tempdir().unwrap();
+        let mut env = Environment::builder().output_dir(plain.path()).build();
+        env.set_csv_options(CsvOptions::new().with_atomic(true));
+        env.create_csv("cases", "cases.csv", &["step"]);
+        assert!(!plain.path().join("cases.csv").exists());
+        env.close_csv("cases");
+        assert!(plain.path().join("cases.csv").is_file());
+    }
+
+    #[test]
+    fn test_if_exists_policies() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_with = |policy: &str| {
+            Environment::from_json(serde_json::json!({
+                "output": {
+                    "spec": "filesystem",
+                    "dir": dir.path().to_str().unwrap(),
+                    "if_exists": policy
+                }
+            }))
+        };
+        let read = |name: &str| fs::read_to_string(dir.path().join(name)).unwrap();
+        let rows = |values: &[&str]| -> Vec<Vec<String>> {
+            values.iter().map(|v| vec![v.to_string()]).collect()
+        };
+        let mut env = env_with("overwrite");
+        env.write_str("summary.json", "1");
+        env.write_str("summary.json", "2");
+        env.write_csv("all.csv", &["step"], &rows(&["0"]));
+        env.create_csv("cases", "cases.csv", &["step"]);
+        env.write_csv_row("cases", &["0"]);
+        env.close_csv("cases");
+        assert_eq!(read("summary.json"), "2");
+
+        // Existing files stop the write, named with the key to change
+        let mut env = env_with("error");
+        let err = env.try_write_str("summary.json", "3").unwrap_err();
+        assert!(err.to_string().contains("'summary.json' already exists at"));
+        assert!(err.to_string().contains("\"if_exists\""));
+        assert!(
+            env.try_write_csv("all.csv", &["step"], &rows(&["9"]))
+                .is_err()
+        );
+        assert!(env.try_create_csv("cases", "cases.csv", &["step"]).is_err());
+        env.write_str("new.json", "{}");
+        assert_eq!(read("summary.json"), "2");
+        assert_eq!(read("new.json"), "{}");
+
+        let mut env = env_with("skip");
+        env.write_str("summary.json", "4");
+        env.write_csv("all.csv", &["step"], &rows(&["9"]));
+        env.create_csv("cases", "cases.csv", &["step"]);
+        env.write_csv_row("cases", &["9"]);
+        env.close_csv("cases");
+        assert_eq!(read("summary.json"), "2");
+        assert_eq!(read("all.csv"), "step\n0\n");
+        assert_eq!(read("cases.csv"), "step\n0\n");
+        let warnings = env.warnings();
+        assert_eq!(warnings.len(), 3);
+        assert!(warnings.iter().all(|w| w.code == "output_skipped"));
+
+        // Rows go after the existing ones; a header is written only for new files
+        fs::write(dir.path().join("unterminated.csv"), "step\n0").unwrap();
+        let mut env = env_with("append");
+        env.write_csv("all.csv", &["step"], &rows(&["1"]));
+        env.create_csv("cases", "cases.csv", &["step"]);
+        env.write_csv_row("cases", &["1"]);
+        env.close_csv("cases");
+        env.write_csv("unterminated.csv", &["step"], &rows(&["1"]));
+        env.write_csv("fresh.csv", &["step"], &rows(&["1"]));
+        assert_eq!(read("all.csv"), "step\n0\n1\n");
+        assert_eq!(read("cases.csv"), "step\n0\n1\n");
+        assert_eq!(read("unterminated.csv"), "step\n0\n1\n");
+        assert_eq!(read("fresh.csv"), "step\n1\n");
+        let err = env
+            .try_write_csv("all.csv", &["day"], &rows(&["1"]))
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("existing header 'step' does not match 'day'")
+        );
+        let err = env.try_write_str("summary.json", "5").unwrap_err();
+        assert!(err.to_string().contains("applies only to CSV files"));
+        env.write_str("other.json", "{}");
+        assert_eq!(read("other.json"), "{}");
+
+        let err = Environment::try_from_json(serde_json::json!({
+            "output": { "spec": "filesystem", "dir": "/tmp/out", "if_exists": "keep" }
+        }))
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("unknown if_exists policy"));
+    }
+
+    #[test]
+    fn test_output_filenames_stay_in_output_dir() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("out");
+        let mut env = Environment::builder().output_dir(&dir).build();
+        let rows = vec![vec!["0".to_string()]];
+        for filename in [
+            "../../etc/passwd",
+            "a/../../b.csv",
+            "/etc/passwd",
+            "",
+            "plots/..",
+        ] {
+            let err = env.try_write_str(filename, "x").unwrap_err();
+            assert!(
+                err.to_string()
+                    .contains(&format!("output filename '{filename}'")),
+                "{err}"
+            );
+            assert!(env.try_write_csv(filename, &["step"], &rows).is_err());
+            assert!(env.try_csv_writer(filename, &["step"]).is_err());
+            assert!(env.try_create_csv("id", filename, &["step"]).is_err());
+        }
+        assert!(
+            env.try_write_str("../x.json", "x")
+                .unwrap_err()
+                .to_string()
+                .contains("escapes the output directory")
+        );
+        assert!(
+            env.try_write_str("/tmp/x.json", "x")
+                .unwrap_err()
+                .to_string()
+                .contains("is absolute")
+        );
+        assert!(!root.path().join("x.json").exists());
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            env.write_str("../x.json", "x")
+        }));
+        assert!(result.is_err());
+
+        env.write_str("plots/fig1.json", "{}");
+        env.write_csv("plots/fig1.csv", &["step"], &rows);
+        env.create_csv("cases", "by_day/./cases.csv", &["step"]);
+        env.close_csv("cases");
+        env.write_str("a/../b.json", "{}");
+        for name in [
+            "plots/fig1.json",
+            "plots/fig1.csv",
+            "by_day/cases.csv",
+            "b.json",
+        ] {
+            assert!(dir.join(name).is_file(), "{name}");
+        }
+    }
+
+    #[test]
+    fn test_output_dir_created_once() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("out");
+        let env = Environment::builder().output_dir(&dir).build();
+        assert!(!dir.exists());
+        assert_eq!(env.ensure_output_dir().unwrap(), dir);
+        assert!(dir.is_dir());
+        env.write_str("a.json", "{}");
+        env.write_str("plots/a.json", "{}");
+
+        // Neither is made again once made
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(env.ensure_output_dir().unwrap(), dir);
+        assert!(!dir.exists());
+        assert!(env.try_write_str("b.json", "{}").is_err());
+        fs::create_dir(&dir).unwrap();
+        assert!(env.try_write_str("plots/b.json", "{}").is_err());
+        env.write_str("b.json", "{}");
+        env.write_str("tables/b.json", "{}");
+        assert!(dir.join("tables/b.json").is_file());
+
+        let env = Environment::builder().output_memory().build();
+        let err = env.ensure_output_dir().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_output_dir_tilde_and_base() {
+        let home = tempfile::tempdir().unwrap();
+        let payload_dir = tempfile::tempdir().unwrap();
+        let bases = DirBases {
+            home: Some(home.path().to_path_buf()),
+            cwd: Some(PathBuf::from("/work")),
+            payload_dir: Some(payload_dir.path()),
+            base: None,
+        };
+        let mut output = serde_json::json!({
+            "profile": {
+                "home": { "spec": "filesystem", "dir": "~/runs" },
+                "bare": { "spec": "filesystem", "dir": "~" },
+                "cwd": { "spec": "filesystem", "dir": "out" },
+                "payload": { "spec": "filesystem", "dir": "out", "base": "payload_dir" },
+                "absolute": { "spec": "filesystem", "dir": "/data/out" },
+            }
+        });
+        resolve_output_dirs(&mut output, &bases).unwrap();
+        let dir =
+            |profile: &str| PathBuf::from(output["profile"][profile]["dir"].as_str().unwrap());
+        assert_eq!(dir("home"), home.path().join("runs"));
+        assert_eq!(dir("bare"), home.path());
+        assert_eq!(dir("cwd"), Path::new("/work/out"));
+        assert_eq!(dir("payload"), payload_dir.path().join("out"));
+        assert_eq!(dir("absolute"), Path::new("/data/out"));
+
+        let mut output = serde_json::json!({ "spec": "filesystem", "dir": "~/runs" });
+        let bases = DirBases {
+            home: None,
+            ..bases
+        };
+        let err = resolve_output_dirs(&mut output, &bases).unwrap_err();
+        assert!(err.to_string().contains("HOME is not set"));
+        let mut output = serde_json::json!({ "spec": "filesystem", "dir": "out", "base": "home" });
+        let err = resolve_output_dirs(&mut output, &bases).unwrap_err();
+        assert!(err.to_string().contains("\"cwd\" or \"payload_dir\""));
+    }
+
+    #[test]
+    fn test_output_dir_relative_to_payload() {
+        let root = tempfile::tempdir().unwrap();
+        let path = root.path().join("payload.json");
+        let payload = serde_json::json!({
+            "input": { "seed": 1 },
+            "output": { "spec": "filesystem", "dir": "out", "base": "payload_dir" }
+        });
+        fs::write(&path, payload.to_string()).unwrap();
+        let env = Environment::from_file(&path);
+        assert_eq!(env.output_dir(), Some(root.path().join("out")));
+
+        // Only a payload read from a file has a directory
+        let err = Environment::try_from_json(payload).err().unwrap();
+        assert!(err.to_string().contains("not read from a file"));
+
+        let payload = serde_json::json!({
+            "input": { "seed": 1 },
+            "output": { "spec": "filesystem", "dir": "out" }
+        });
+        let mut env = Environment::from_json(payload);
+        let cwd = std::env::current_dir().unwrap();
+        assert_eq!(env.output_dir(), Some(cwd.join("out")));
+        env.set_output_base(root.path());
+        assert_eq!(env.output_dir(), Some(root.path().join("out")));
+        env.write_str("a.json", "{}");
+        assert!(root.path().join("out/a.json").is_file());
+    }
+
+    #[test]
+    fn test_memory_output() {
+        let mut env = Environment::builder().seed(1).output_memory().build();
+        env.write_str("summary.json", "{\"peak\": 4}");
+        env.write_csv("all.csv", &["step"], &[vec!["0".to_string()]]);
+        env.create_csv("cases", "cases.csv", &["step", "cases"]);
+        env.write_csv_row("cases", &["0", "5"]);
+        env.write_csv_row("cases", &["1", "8"]);
+        env.close_csv("cases");
+        let mut writer = env.jsonl_writer("events.jsonl");
+        writer.write_record(&serde_json::json!({ "step": 1 }));
+        writer.flush();
+        assert_eq!(
+            env.memory_output_string("summary.json").as_deref(),
+            Some("{\"peak\": 4}")
+        );
+        assert_eq!(
+            env.memory_output_string("cases.csv").as_deref(),
+            Some("step,cases\n0,5\n1,8\n")
+        );
+        assert_eq!(env.memory_outputs()["all.csv"], b"step\n0\n");
+        assert_eq!(
+            env.memory_output_string("events.jsonl").as_deref(),
+            Some("{\"step\":1}\n")
+        );
+        assert_eq!(env.memory_output_string("missing.csv"), None);
+
+        let dir = tempfile::tempdir().unwrap();
+        let env = Environment::builder().output_dir(dir.path()).build();
+        env.write_str("summary.json", "{}");
+        assert!(env.memory_outputs().is_empty());
+    }
+
+    #[test]
+    fn test_output_profiles() {
+        let bulk = tempfile::tempdir().unwrap();
+        let plots = tempfile::tempdir().unwrap();
+        let mut env = Environment::from_json(serde_json::json!({
+            "output": {
+                "profile": {
+                    "default": { "spec": "filesystem", "dir": bulk.path().to_str().unwrap() },
+                    "plots": { "spec": "filesystem", "dir": plots.path().to_str().unwrap() }
+                }
+            }
+        }));
+        assert_eq!(env.output_profiles(), vec!["default", "plots"]);
+        assert_eq!(
+            env.output_dir_for("plots").unwrap().as_deref(),
+            Some(plots.path())
+        );
+        env.write_to("plots", "summary.json", b"{}");
+        let mut writer = env.csv_writer_to("plots", "peaks.csv", &["step"]);
+        writer.write_row(&["4"]);
+        writer.flush();
+        env.create_csv_to("default", "cases", "cases.csv", &["step"]);
+        env.write_csv_row("cases", &["0"]);
+        env.close_csv("cases");
+        assert!(plots.path().join("summary.json").is_file());
+        assert!(plots.path().join("peaks.csv").is_file());
+        assert!(bulk.path().join("cases.csv").is_file());
+        assert!(!bulk.path().join("summary.json").exists());
+
+        let err = env.try_write_to("figures", "a.png", b"").unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("unknown output profile 'figures' (profiles: default, plots)"),
+            "{err}"
+        );
+        let flat = Environment::from_json(serde_json::json!({ "output": { "spec": "stdout" } }));
+        assert_eq!(flat.output_profiles(), vec!["default"]);
+        assert_eq!(flat.output_dir_for("default").unwrap(), None);
+        assert!(flat.output_dir_for("plots").is_err());
+    }
+
+    #[test]
+    fn test_write_csv() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = serde_json::json!({
+            "output": { "spec": "filesystem", "dir": dir.path().to_str().unwrap() }
+        });
+        let env = Environment::from_json(data);
+        env.write_csv(
+            "test.csv",
+            &["a", "b"],
+            &[
+                vec!["1".to_string(), "2".to_string()],
+                vec!["3".to_string(), "4".to_string()],
+            ],
+        );
+        let content = fs::read_to_string(dir.path().join("test.csv")).unwrap();
+        assert!(content.contains("a,b"));
+        assert!(content.contains("1,2"));
+        assert!(content.contains("3,4"));
+    }
+
+    pub(super) fn fs_env(dir: &Path) -> Environment {
+        Environment::from_json(serde_json::json!({
+            "output": { "spec": "filesystem", "dir": dir.to_str().unwrap() }
+        }))
+    }
+
+    fn run_split(dir: &Path) -> (BTreeMap<String, f64>, Vec<Warning>, Manifest) {
+        let mut env = Environment::from_json(serde_json::json!({
+            "input": { "seed": 11 },
+            "output": { "spec": "filesystem", "dir": dir.to_str().unwrap() },
+            "manifest": true,
+        }));
+        let workers = env.split_scoped(8);
+        use rayon::prelude::*;
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+        pool.install(|| {
+            workers.into_par_iter().rev().for_each(|worker| {
+                let i = worker.index();
+                // Uneven work so completion order varies
+                std::thread::sleep(std::time::Duration::from_millis((i as u64 * 7) % 5));
+                let mut w = worker.csv_writer("rows.csv", &["worker", "seed"]);
+                w.write_row(&[&i.to_string(), &worker.seed().to_string()]);
+                drop(w);
+                worker.write("summary.txt", format!("worker {i}").as_bytes());
+                worker.add_metric("total", (worker.seed() % 1000) as f64 / 7.0);
+                worker.record_metric("last", i as f64);
+                if i % 3 == 0 {
+                    worker.warn("W001", &format!("worker {i}"));
+                }
+            });
+        });
+        env.finalize().unwrap();
+        let mut manifest = crate::formats::read_manifest(&dir.join(MANIFEST)).unwrap();
+        // Timings differ from run to run
+        manifest.duration_seconds = None;
+        for file in &mut manifest.files {
+            if let Some(provenance) = &mut file.provenance {
+                assert!(provenance.finished_at.is_some(), "{}", file.name);
+                provenance.started_at.clear();
+                provenance.finished_at = None;
+            }
+        }
+        (env.metrics(), env.warnings(), manifest)
+    }
+
+    #[test]
+    fn test_split_merges_deterministically() {
+        let a = tempfile::tempdir().unwrap();
+        let b = tempfile::tempdir().unwrap();
+        let first = run_split(a.path());
+        let second = run_split(b.path());
+        assert_eq!(first, second);
+        assert_eq!(first.0["last"], 7.0);
+        let messages: Vec<&str> = first.1.iter().map(|w| w.message.as_str()).collect();
+        assert_eq!(messages, vec!["worker 0", "worker 3", "worker 6"]);
+        let names: Vec<&str> = first.2.files.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names.len(), 17);
+        assert!(names.contains(&"worker_7/rows.csv"));
+        assert!(names.contains(&"metrics.json"));
+        let rows = first.2.files.iter().find(|f| f.name == "worker_3/rows.csv");
+        assert_eq!(rows.unwrap().rows, Some(1));
+    }
+
+    #[test]
+    fn test_split_output_provenance_spans_its_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("late.csv");
+        fs::write(&input, "x\n1\n").unwrap();
+        let mut env = Environment::from_json(serde_json::json!({
+            "output": { "spec": "filesystem", "dir": dir.path().join("out").to_str().unwrap() },
+            "model": { "files": { "late": input.to_str().unwrap() } }
+        }));
+        let workers = env.split(2);
+        workers[0].write("early.txt", b"done");
+        let open = workers[1].csv_writer("open.csv", &["v"]);
+        // Read after one worker output is closed, while the other is open
+        env.read_file("late").unwrap();
+        drop(open);
+        drop(workers);
+        env.merge_workers();
+
+        let early = env.provenance_for("early.txt").unwrap();
+        assert!(early.files_read.is_empty());
+        assert!(early.finished_at.is_some());
+        let open = env.provenance_for("open.csv").unwrap();
+        assert_eq!(open.files_read.len(), 1);
+        assert_eq!(open.files_read[0].key, "late");
+    }
+
+    #[test]
+    fn test_split_workers_follow_parent_output_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("kept.txt"), "old").unwrap();
+        fs::write(dir.path().join("rows.csv"), "v\n1\n").unwrap();
+        let mut env = Environment::from_json(serde_json::json!({
+            "output": {
+                "spec": "filesystem",
+                "dir": dir.path().to_str().unwrap(),
+                "if_exists": "append",
+                "atomic": true,
+            }
+        }));
+        let workers = env.split(1);
+        let err = workers[0].try_write("kept.txt", b"new").unwrap_err();
+        assert!(err.to_string().contains("kept.txt"));
+        let mut rows = workers[0].csv_writer("rows.csv", &["v"]);
+        rows.write_row(&["2"]);
+        drop(rows);
+        workers[0].write("fresh.txt", b"fresh");
+        let read = |name: &str| fs::read_to_string(dir.path().join(name)).unwrap();
+        assert_eq!(read("rows.csv"), "v\n1\n2\n");
+        assert_eq!(read("kept.txt"), "old");
+        assert_eq!(read("fresh.txt"), "fresh");
+        let files = list_files(dir.path()).unwrap();
+        assert!(files.iter().all(|f| !f.contains(".tmp-")));
+
+        let mut skip = Environment::from_json(serde_json::json!({
+            "output": { "spec": "filesystem", "dir": dir.path().to_str().unwrap(), "if_exists": "skip" }
+        }));
+        skip.split(1)[0].write("kept.txt", b"new");
+        skip.merge_workers();
+        assert_eq!(read("kept.txt"), "old");
+        assert_eq!(skip.warnings()[0].code, "output_skipped");
+    }
+
+    #[test]
+    fn test_split_workers_reject_unshared_outputs() {
+        let dir = tempfile::tempdir().unwrap();
+        for output in [
+            serde_json::json!({ "spec": "memory" }),
+            serde_json::json!({ "spec": "stdout" }),
+            serde_json::json!({
+                "spec": "multi",
+                "sinks": [
+                    { "spec": "filesystem", "dir": dir.path().join("a").to_str().unwrap() },
+                    { "spec": "filesystem", "dir": dir.path().join("b").to_str().unwrap() },
+                ],
+            }),
+        ] {
+            let mut env = Environment::from_json(serde_json::json!({ "output": output }));
+            let workers = env.split(1);
+            let err = workers[0].try_write("out.txt", b"x").unwrap_err();
+            assert!(matches!(err, MrpError::Config(_)), "{err}");
+            assert!(workers[0].try_csv_writer("rows.csv", &["v"]).is_err());
+        }
+        assert!(list_files(dir.path()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_split_seeds_distinct() {
+        let mut env = Environment::from_json(serde_json::json!({"input": {"seed": 1}}));
+        let seeds: BTreeSet<u64> = env.split(8).iter().map(|w| w.seed()).collect();
+        assert_eq!(seeds.len(), 8);
+        let mut other = Environment::from_json(serde_json::json!({
+            "input": {"seed": 1, "replicate": 1}
+        }));
+        assert_ne!(other.split(1)[0].seed(), env.split(1)[0].seed());
+    }
+
+    #[test]
+    fn test_split_workers_checked_like_parent() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut env = fs_env(dir.path());
+        env.declare_output_schema(
+            "rows.csv",
+            &[("step", ColumnType::Integer), ("value", ColumnType::Float)],
+        );
+        let workers = env.split(2);
+        workers[0].write("summary.txt", b"first");
+        let err = workers[1].try_write("summary.txt", b"second").unwrap_err();
+        assert!(err.to_string().contains("worker 0 has written"));
+        workers[0].write("summary.txt", b"again");
+        assert_eq!(fs::read(dir.path().join("summary.txt")).unwrap(), b"again");
+        assert!(workers[0].try_write("../escape.txt", b"").is_err());
+        let err = workers[0]
+            .try_csv_writer("rows.csv", &["value", "step"])
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("do not match declared columns"));
+
+        env.set_strict_outputs(true);
+        let scoped = env.split_scoped(2);
+        assert!(scoped[1].try_write("notes.txt", b"").is_err());
+        let mut rows = scoped[1].csv_writer("rows.csv", &["step", "value"]);
+        rows.write_row(&["0", "1.5"]);
+        drop(rows);
+        drop(scoped);
+        env.merge_workers();
+        assert!(dir.path().join("worker_1/rows.csv").is_file());
+    }
+
+    #[test]
+    fn test_append_csv() {
+        let dir = tempfile::tempdir().unwrap();
+        let env = fs_env(dir.path());
+        let mut w = env
+            .append_csv("out.csv", &["step", "value"], b"step,value\n0,1.5\n1,2")
+            .unwrap();
+        w.write_row(&["2", "3"]);
+        w.flush();
+        assert_eq!(
+            fs::read_to_string(dir.path().join("out.csv")).unwrap(),
+            "step,value\n0,1.5\n1,2\n2,3\n"
+        );
+        let err = env
+            .append_csv("other.csv", &["step", "x"], b"step,value\n")
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("does not match"));
+    }
+
+    #[test]
+    fn test_deep_payload_rejected() {
+        // Built by hand: json! would serialize (and recurse into) `deep`
+        let mut deep = Value::from(1);
+        for _ in 0..100_000 {
+            deep = Value::Array(vec![deep]);
+        }
+        let mut payload = serde_json::Map::new();
+        payload.insert("input".to_string(), deep);
+        let err = Environment::try_from_json(Value::Object(payload))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("deeper than 128"));
+
+        let control = serde_json::json!({
+            "negative_control": { "parameter": "a.".repeat(200) + "b", "value": 0 }
+        });
+        assert!(Environment::try_from_json(control).is_err());
+    }
+
+    #[test]
+    fn test_ignored_payload_elements_warn() {
+        let env = Environment::try_from_json(serde_json::json!({
+            "input": { "replicate": "1", "seed": 4.0 },
+            "model": { "files": { "ok": "/data/a.csv", "bad": { "path": "/data/b.csv" } } }
+        }))
+        .unwrap();
+        let codes: Vec<String> = env.warnings().into_iter().map(|w| w.code).collect();
+        assert_eq!(codes, vec!["ignored_file"]);
+        assert_eq!(env.replicate, 1);
+        assert_eq!(env.input_json()["seed"], 4);
+        assert_eq!(env.files.len(), 1);
+    }
+
+    #[test]
+    fn test_non_object_sections_rejected() {
+        let cases = [
+            (serde_json::json!({ "input": [1, 2, 3] }), "\"input\""),
+            (serde_json::json!({ "input": "oops" }), "\"input\""),
+            (serde_json::json!({ "input": 4 }), "\"input\""),
+            (
+                serde_json::json!({ "model": { "files": "oops" } }),
+                "\"model.files\"",
+            ),
+            (
+                serde_json::json!({ "model": { "files": ["a.csv"] } }),
+                "\"model.files\"",
+            ),
+            (serde_json::json!({ "output": 7 }), "\"output\""),
+            (serde_json::json!({ "output": "filesystem" }), "\"output\""),
+        ];
+        for (payload, key) in cases {
+            let err = Environment::try_from_json(payload.clone()).err().unwrap();
+            assert_eq!(err.kind(), "input");
+            assert!(err.to_string().contains(key), "{payload}: {err}");
+            assert!(err.to_string().contains("must be an object"), "{err}");
+        }
+
+        let env = Environment::try_from_json(serde_json::json!({
+            "input": null,
+            "model": { "files": null },
+            "output": null
+        }))
+        .unwrap();
+        assert!(env.warnings().is_empty());
+        assert_eq!(env.output_dir(), None);
+    }
+
+    #[test]
+    fn test_malformed_payloads_do_not_panic() {
+        #[derive(serde::Deserialize)]
+        #[allow(dead_code)]
+        struct Known {
+            r0: f64,
+            steps: u32,
+        }
+        let payloads = [
+            serde_json::json!(null),
+            serde_json::json!("string"),
+            serde_json::json!({ "input": { "r0": "two", "steps": -1 } }),
+            serde_json::json!({ "input": { "r0": f64::MAX, "steps": 1e12 } }),
+            serde_json::json!({ "output": 7 }),
+            serde_json::json!({ "output": { "profile": { "a": [] } } }),
+            serde_json::json!({ "output": { "spec": "filesystem", "on_write_failure": 3 } }),
+            serde_json::json!({ "negative_control": "r0" }),
+            serde_json::json!({ "negative_control": { "parameter": "..", "value": 0 } }),
+        ];
+        for payload in payloads {
+            if Environment::try_from_json(payload.clone()).is_ok() {
+                let env = Environment::from_json(payload);
+                let _ = env.output_dir();
+                let _ = env.try_with_input_type::<Known>();
+            }
+        }
+    }
+
+    #[test]
+    fn test_frozen_stream_repeats_across_replicates() {
+        let env_for = |replicate: u64| {
+            Environment::from_json(serde_json::json!({
+                "input": { "seed": 9, "replicate": replicate },
+                "rng_streams": { "observation": { "seed_offset": 17 }, "freeze": ["transmission"] }
+            }))
+        };
+        let (a, b) = (env_for(0), env_for(1));
+        assert_eq!(a.stream_seed("transmission"), b.stream_seed("transmission"));
+        assert_ne!(a.stream_seed("observation"), b.stream_seed("observation"));
+        assert_ne!(a.stream_seed("transmission"), a.stream_seed("observation"));
+
+        a.declare_rng_streams(&["transmission", "observation"]);
+        assert!(a.warnings().is_empty());
+        a.declare_rng_streams(&["transmission"]);
+        assert_eq!(a.warnings()[0].code, "unknown_rng_stream");
+    }
+
+    #[cfg(feature = "rand")]
+    #[test]
+    fn test_rng_stream_reproducible() {
+        use rand::Rng;
+        let payload = serde_json::json!({ "input": { "seed": 3 } });
+        let draws = |env: Environment| -> Vec<u64> {
+            let mut rng = env.rng_stream("transmission");
+            (0..4).map(|_| rng.random()).collect()
+        };
+        assert_eq!(
+            draws(Environment::from_json(payload.clone())),
+            draws(Environment::from_json(payload))
+        );
+    }
+
+    #[cfg(feature = "rand")]
+    #[test]
+    fn test_rng_seeded_from_payload() {
+        use rand::Rng;
+        let draws = |mut rng: ModelRng| -> Vec<u64> { (0..4).map(|_| rng.random()).collect() };
+        let payload = serde_json::json!({ "input": { "seed": "42", "r0": 2.0 } });
+        let a = Environment::from_json(payload.clone());
+        let b = Environment::from_json(payload);
+        assert_eq!(draws(a.rng()), draws(b.rng()));
+        assert_eq!(draws(a.rng_seeded_with(7)), draws(b.rng_seeded_with(7)));
+        assert_ne!(draws(a.rng()), draws(a.rng_seeded_with(7)));
+        assert_ne!(draws(a.rng_seeded_with(7)), draws(a.rng_seeded_with(8)));
+        let other = Environment::builder().seed(43).build();
+        assert_ne!(draws(a.rng()), draws(other.rng()));
+    }
+
+    #[test]
+    fn test_seed_table_indexed_by_replicate() {
+        let env = |input: Value| Environment::try_from_json(serde_json::json!({ "input": input }));
+        let table = env(serde_json::json!({ "seed": [101, "102", 103], "replicate": 1 })).unwrap();
+        assert_eq!(table.seed(), 102);
+        assert_eq!(table.seed_table(), Some(&[101, 102, 103][..]));
+        assert_eq!(table.input_json["seed"], 102);
+        assert_eq!(table.effective_seed(), derive_seed(102, 1));
+
+        let first = env(serde_json::json!({ "seed": [101, 102, 103] })).unwrap();
+        assert_eq!(first.seed(), 101);
+        let mut overridden = first;
+        overridden
+            .apply_overrides(&[("replicate".to_string(), "2".to_string())])
+            .unwrap();
+        assert_eq!(overridden.seed(), 103);
+
+        let err = env(serde_json::json!({ "seed": [101, 102], "replicate": 2 }))
+            .err()
+            .unwrap();
+        assert!(
+            err.to_string()
+                .contains("replicate 2 is out of range for the seed table of 2 seeds"),
+            "{err}"
+        );
+        let err = env(serde_json::json!({ "seed": [101, -1] })).err().unwrap();
+        assert!(err.to_string().contains("seed[1]"), "{err}");
+
+        let scalar = env(serde_json::json!({ "seed": 7, "replicate": 3 })).unwrap();
+        assert_eq!(scalar.seed(), 7);
+        assert_eq!(scalar.seed_table(), None);
+    }
+
+    #[test]
+    fn test_random_seed_generated_and_recorded() {
+        let dir = tempfile::tempdir().unwrap();
+        let payload = |seed: Value| {
+            serde_json::json!({
+                "input": { "seed": seed, "r0": 2.0 },
+                "output": { "spec": "filesystem", "dir": dir.path().to_str().unwrap() },
+            })
+        };
+        let mut env = Environment::from_json(payload(Value::Null));
+        assert!(env.seed_was_generated());
+        let seed = env.seed();
+        assert_eq!(env.input_json["seed"], seed);
+        assert_eq!(env.tags()["generated_seed"], seed.to_string());
+        env.finalize().unwrap();
+        let resolved: Value =
+            serde_json::from_str(&fs::read_to_string(dir.path().join(RESOLVED_INPUT)).unwrap())
+                .unwrap();
+        assert_eq!(resolved["seed"], seed);
+
+        let other = Environment::from_json(payload("random".into()));
+        assert!(other.seed_was_generated());
+        assert_ne!(other.seed(), seed);
+
+        let given = Environment::from_json(payload(7.into()));
+        assert!(!given.seed_was_generated());
+        assert!(!given.tags().contains_key("generated_seed"));
+        let missing = Environment::from_json(serde_json::json!({
+            "input": { "r0": 2.0 },
+            "allow_missing_seed": true,
+        }));
+        assert!(!missing.seed_was_generated());
+        assert_eq!(missing.seed(), 0);
+    }
+
+    #[test]
+    fn test_effective_seed_mixes_replicate() {
+        let env = |seed, replicate| {
+            Environment::builder()
+                .seed(seed)
+                .replicate(replicate)
+                .build()
+        };
+        let (a, b) = (env(5, 1), env(4, 2));
+        assert_ne!(a.effective_seed(), b.effective_seed());
+        assert_eq!(a.effective_seed(), env(5, 1).effective_seed());
+        assert_eq!(
+            a.effective_seed(),
+            crate::seed::splitmix64(crate::seed::splitmix64(5) ^ 1)
+        );
+        #[cfg(feature = "rand")]
+        {
+            use rand::Rng;
+            let draws = |mut rng: ModelRng| -> Vec<u64> { (0..4).map(|_| rng.random()).collect() };
+            assert_ne!(draws(a.rng()), draws(b.rng()));
+        }
+    }
+
+    #[cfg(feature = "rand")]
+    #[test]
+    fn test_rng_for_labels_independent_and_reproducible() {
+        use rand::Rng;
+        let draws = |mut rng: ModelRng| -> Vec<u64> { (0..8).map(|_| rng.random()).collect() };
+        let build = || Environment::builder().seed(5).replicate(2).build();
+        let env = build();
+        let transmission = draws(env.rng_for("transmission"));
+        let observation = draws(env.rng_for("observation"));
+        assert_ne!(transmission, observation);
+        assert!(transmission.iter().all(|x| !observation.contains(x)));
+        assert_eq!(draws(env.rng_for("transmission")), transmission);
+        assert_eq!(env.rng_labels(), vec!["observation", "transmission"]);
+
+        let rebuilt = build();
+        assert_eq!(draws(rebuilt.rng_for("observation")), observation);
+        assert_eq!(draws(rebuilt.rng_for("transmission")), transmission);
+        let other_replicate = Environment::builder().seed(5).replicate(3).build();
+        assert_ne!(draws(other_replicate.rng_for("transmission")), transmission);
+
+        // Unit-interval draws of two labels are uncorrelated
+        let (mut a, mut b) = (env.rng_for("a"), env.rng_for("b"));
+        let n = 20_000;
+        let pairs: Vec<(f64, f64)> = (0..n).map(|_| (a.random(), b.random())).collect();
+        let covariance = pairs
+            .iter()
+            .map(|(x, y)| (x - 0.5) * (y - 0.5))
+            .sum::<f64>()
+            / n as f64;
+        // Var(U) = 1/12, so the correlation is 12 * covariance
+        assert!((12.0 * covariance).abs() < 0.05, "{covariance}");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_write_failure_falls_back_to_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let alt = dir.path().join("alt");
+        fs::create_dir_all(&out).unwrap();
+        // Writes through this link fail with ENOSPC
+        std::os::unix::fs::symlink("/dev/full", out.join("big.csv")).unwrap();
+        let env = Environment::from_json(serde_json::json!({
+            "output": {
+                "spec": "filesystem",
+                "dir": out.to_str().unwrap(),
+                "on_write_failure": "fallback_dir",
+                "fallback_dir": alt.to_str().unwrap(),
+            }
+        }));
+        env.write_csv("big.csv", &["x"], &[vec!["1".to_string()]]);
+        env.write_str("ok.txt", "fine");
+
+        assert_eq!(fs::read_to_string(alt.join("big.csv")).unwrap(), "x\n1\n");
+        assert!(alt.join("big.csv.fallback.json").exists());
+        assert_eq!(fs::read_to_string(out.join("ok.txt")).unwrap(), "fine");
+        let splits = env.split_outputs();
+        assert_eq!(splits.len(), 1);
+        assert_eq!(splits[0].filename, "big.csv");
+        assert_eq!(splits[0].primary_bytes, 0);
+        assert_eq!(env.warnings()[0].code, "write_fallback");
+    }
+
+    #[test]
+    fn test_update_metric_partial_and_promotion() {
+        let dir = tempfile::tempdir().unwrap();
+        let payload = |interval: u64| {
+            serde_json::json!({
+                "output": { "spec": "filesystem", "dir": dir.path().to_str().unwrap() },
+                "progress_interval_ms": interval,
+            })
+        };
+        let partial = dir.path().join("metrics.partial.json");
+        let read = |path: &Path| -> BTreeMap<String, f64> {
+            serde_json::from_slice(&fs::read(path).unwrap()).unwrap()
+        };
+
+        let mut env = Environment::from_json(payload(0));
+        for i in 0..1000 {
+            env.update_metric("cumulative", i as f64);
+            assert_eq!(read(&partial)["cumulative"], i as f64);
+        }
+        env.finalize().unwrap();
+        assert!(!partial.exists());
+        assert_eq!(read(&dir.path().join("metrics.json"))["cumulative"], 999.0);
+
+        // Throttled: intermediate values are skipped, but not the last
+        let mut env = Environment::from_json(payload(3_600_000));
+        for i in 0..1000 {
+            env.update_metric("peak", i as f64);
+        }
+        assert_eq!(read(&partial)["peak"], 0.0);
+        env.finalize().unwrap();
+        assert!(!partial.exists());
+        assert_eq!(read(&dir.path().join("metrics.json"))["peak"], 999.0);
+    }
+
+    #[test]
+    fn test_output_dir_file_in_the_way() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("out");
+        fs::write(&file, "not a dir").unwrap();
+        let err = Environment::try_from_json(serde_json::json!({
+            "output": { "spec": "filesystem", "dir": file.to_str().unwrap() }
+        }))
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("exists and is not a directory"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_output_dir_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("target");
+        let link = dir.path().join("link");
+        fs::create_dir(&target).unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        let env = Environment::from_json(serde_json::json!({
+            "output": { "spec": "filesystem", "dir": link.to_str().unwrap() }
+        }));
+        let resolved = fs::canonicalize(&target).unwrap();
+        assert_eq!(env.output_dir(), Some(resolved.clone()));
+        env.write_str("a.txt", "hi");
+        assert_eq!(fs::read_to_string(resolved.join("a.txt")).unwrap(), "hi");
+
+        let err = Environment::try_from_json(serde_json::json!({
+            "output": {
+                "spec": "filesystem",
+                "dir": link.to_str().unwrap(),
+                "follow_symlinks": false,
+            }
+        }))
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("follow_symlinks is false"));
+    }
+
+    #[test]
+    fn test_input_json_untyped() {
+        let env = Environment::from_json(serde_json::json!({
+            "input": { "seed": 4, "replicate": 1, "r0": 2.0 }
+        }));
+        assert_eq!(
+            env.input_json(),
+            &serde_json::json!({ "seed": 4, "r0": 2.0 })
+        );
+        // Typed input sees `replicate` again
+        let typed = env.with_input_type::<Value>();
+        assert_eq!(
+            typed.input,
+            Some(serde_json::json!({ "seed": 4, "replicate": 1, "r0": 2.0 }))
+        );
+    }
+
+    #[test]
+    fn test_csv_options_apply_to_new_writers() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut env = Environment::from_json(serde_json::json!({
+            "output": { "spec": "filesystem", "dir": dir.path().to_str().unwrap() }
+        }));
+        env.set_csv_options(CsvOptions::new().sanitize(StringPolicy::ReplaceControlChars));
+        env.write_csv(
+            "places.csv",
+            &["name"],
+            &[vec!["a\r\nb".to_string()], vec!["c\td".to_string()]],
+        );
+        assert_eq!(
+            fs::read_to_string(dir.path().join("places.csv")).unwrap(),
+            "name\na  b\nc d\n"
+        );
+        let warnings = env.warnings();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].message.contains("in 2 fields"));
+    }
+
+    #[test]
+    fn test_csv_run_columns() {
+        #[derive(Serialize)]
+        struct Row {
+            step: u64,
+            cases: u64,
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut env = Environment::builder()
+            .seed(7)
+            .replicate(2)
+            .output_dir(dir.path())
+            .build();
+        env.set_csv_options(CsvOptions::new().with_run_columns(true));
+        env.create_csv("cases", "cases.csv", &["step", "cases"]);
+        env.write_csv_row("cases", &["0", "5"]);
+        env.close_csv("cases");
+        let mut writer = env.csv_writer("records.csv", &["step", "cases"]);
+        writer.write_record(&Row { step: 1, cases: 8 });
+        writer.flush();
+        for file in ["cases.csv", "records.csv"] {
+            let text = fs::read_to_string(dir.path().join(file)).unwrap();
+            assert!(
+                text.starts_with("seed,replicate,step,cases\n7,2,"),
+                "{text}"
+            );
+        }
+
+        env.set_csv_options(CsvOptions::new().with_run_columns(false));
+        env.write_csv(
+            "plain.csv",
+            &["step", "cases"],
+            &[vec!["0".into(), "5".into()]],
+        );
+        assert_eq!(
+            fs::read_to_string(dir.path().join("plain.csv")).unwrap(),
+            "step,cases\n0,5\n"
+        );
+    }
+
+    type CleanupLog = Arc<Mutex<Vec<&'static str>>>;
+
+    fn cleanup_log() -> (
+        CleanupLog,
+        impl Fn(&'static str) -> Box<dyn FnOnce() + Send>,
+    ) {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let shared = log.clone();
+        let action = move |name: &'static str| -> Box<dyn FnOnce() + Send> {
+            let log = shared.clone();
+            Box::new(move || {
+                if name == "throws" {
+                    panic!("cleanup failed");
+                }
+                log.lock().unwrap().push(name);
+            })
+        };
+        (log, action)
+    }
+
+    #[test]
+    fn test_deferred_run_lifo_on_success() {
+        let (log, action) = cleanup_log();
+        let mut env = Environment::new();
+        env.defer(action("first"));
+        env.defer(action("throws"));
+        env.defer(action("last"));
+        env.finalize().unwrap();
+        assert_eq!(*log.lock().unwrap(), vec!["last", "first"]);
+        let warnings = env.warnings();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].code, "deferred_failed");
+        // Already run; finalizing again or dropping does not repeat them
+        env.finalize().unwrap();
+        drop(env);
+        assert_eq!(log.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_deferred_run_on_error_and_panic() {
+        let (log, action) = cleanup_log();
+        let mut env = Environment::new();
+        env.declare_output_schema("never.csv", &[("x", ColumnType::Integer)]);
+        env.defer(action("error"));
+        env.defer(action("throws"));
+        let err = env.finalize().unwrap_err();
+        assert!(err.to_string().contains("never.csv"));
+        assert_eq!(*log.lock().unwrap(), vec!["error"]);
+
+        // A model returning early without finalizing
+        let env = Environment::new();
+        env.defer(action("returned"));
+        drop(env);
+
+        let result = std::panic::catch_unwind(|| {
+            let env = Environment::new();
+            env.defer(action("panicked"));
+            env.defer(action("throws"));
+            panic!("model failed");
+        });
+        assert_eq!(
+            *result.unwrap_err().downcast_ref::<&str>().unwrap(),
+            "model failed"
+        );
+        assert_eq!(*log.lock().unwrap(), vec!["error", "returned", "panicked"]);
+    }
 
-    let mut input_map = match input_section {
-        Value::Object(m) => m,
-        _ => Default::default(),
-    };
+    #[test]
+    fn test_worker_deferred_run_on_merge() {
+        let (log, action) = cleanup_log();
+        let mut env = Environment::new();
+        env.defer(action("parent"));
+        let workers = env.split(2);
+        std::thread::scope(|s| {
+            for worker in workers {
+                let action = &action;
+                s.spawn(move || {
+                    worker.defer(action(["w0", "w1"][worker.index()]));
+                    worker.defer(action("throws"));
+                });
+            }
+        });
+        env.merge_workers();
+        assert_eq!(*log.lock().unwrap(), vec!["w0", "w1"]);
+        assert_eq!(env.warnings().len(), 2);
+        env.finalize().unwrap();
+        assert_eq!(*log.lock().unwrap(), vec!["w0", "w1", "parent"]);
+    }
 
-    let replicate = input_map
-        .remove("replicate")
-        .and_then(|v| v.as_u64())
-        .unwrap_or(0);
+    #[test]
+    fn test_input_defaults_fill_missing_keys() {
+        let env = Environment::from_json(serde_json::json!({
+            "input": { "r0": 3.0, "delay": { "mean": 4 } }
+        }))
+        .with_defaults(serde_json::json!({
+            "r0": 2.0,
+            "seed": 1,
+            "delay": { "mean": 2, "sd": 1 }
+        }));
+        assert_eq!(
+            env.input_json(),
+            &serde_json::json!({ "r0": 3.0, "seed": 1, "delay": { "mean": 4, "sd": 1 } })
+        );
+        let empty = Environment::new().with_defaults(serde_json::json!({ "seed": 2 }));
+        assert_eq!(empty.input_json(), &serde_json::json!({ "seed": 2 }));
+    }
 
-    let input_json = Value::Object(input_map);
+    #[test]
+    fn test_missing_seed_warning_waits_for_defaults() {
+        let unseeded = serde_json::json!({ "input": { "r0": 2.0 } });
+        let env = Environment::from_json(unseeded.clone());
+        assert!(env.missing_seed.get());
+        let env = env.with_defaults(serde_json::json!({ "seed": 5 }));
+        assert_eq!(env.seed(), 5);
+        assert!(!env.missing_seed.get());
 
-    let model = data.get("model").unwrap_or(&Value::Null);
-    let files = model
-        .get("files")
-        .and_then(|f| f.as_object())
-        .map(|m| {
-            m.iter()
-                .filter_map(|(k, v)| v.as_str().map(|s| (k.clone(), PathBuf::from(s))))
-                .collect()
-        })
-        .unwrap_or_default();
+        let env = Environment::from_json(unseeded);
+        env.effective_seed();
+        assert!(!env.missing_seed.get());
+        let allowed = Environment::from_json(serde_json::json!({
+            "input": {}, "allow_missing_seed": true
+        }));
+        assert!(!allowed.missing_seed.get());
+    }
 
-    let output = data
-        .get("output")
-        .cloned()
-        .unwrap_or(Value::Object(Default::default()));
+    #[test]
+    fn test_defaults_merged_under_payload() {
+        let env = Environment::from_json(serde_json::json!({
+            "input": {
+                "r0": 3.0,
+                "delays": [1],
+                "observation": { "rate": 0.5, "lag": { "mean": 4 } },
+            }
+        }))
+        .with_defaults(serde_json::json!({
+            "r0": 2.0,
+            "seed": "7",
+            "replicate": 4,
+            "delays": [1, 2, 3],
+            "observation": { "rate": 0.1, "lag": { "mean": 2, "sd": 1 }, "noise": "poisson" },
+        }));
+        assert_eq!(
+            env.input_json(),
+            &serde_json::json!({
+                "r0": 3.0,
+                "seed": 7,
+                "delays": [1],
+                "observation": { "rate": 0.5, "lag": { "mean": 4, "sd": 1 }, "noise": "poisson" },
+            })
+        );
+        assert_eq!(env.replicate, 4);
 
-    (replicate, files, input_json, output)
-}
+        let env = Environment::from_json(serde_json::json!({
+            "input": { "seed": 1, "replicate": 0 }
+        }))
+        .with_defaults(serde_json::json!({ "seed": 9, "replicate": 4 }));
+        assert_eq!(env.input_json()["seed"], 1);
+        assert_eq!(env.replicate, 0);
 
-fn read_stdin() -> Value {
-    let mut buf = String::new();
-    io::stdin()
-        .read_to_string(&mut buf)
-        .expect("failed to read stdin");
-    if buf.trim().is_empty() {
-        return Value::Object(Default::default());
+        let err = Environment::new()
+            .try_with_defaults(serde_json::json!({ "seed": -1 }))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("seed"), "{err}");
+        assert!(
+            Environment::new()
+                .try_with_defaults(serde_json::json!([1]))
+                .is_err()
+        );
     }
-    serde_json::from_str(&buf).expect("failed to parse JSON from stdin")
-}
 
-fn read_file(path: &Path) -> Value {
-    let contents = fs::read_to_string(path).expect("failed to read file");
-    match path.extension().and_then(|e| e.to_str()) {
-        Some("toml") => {
-            let table: toml::Value = contents.parse().expect("failed to parse TOML");
-            toml_to_json(table)
+    #[test]
+    fn test_defaults_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("defaults.json");
+        fs::write(&path, r#"{ "r0": 2.0, "seed": 11 }"#).unwrap();
+        let env = Environment::from_json(serde_json::json!({ "input": { "r0": 1.5 } }))
+            .with_defaults_file(&path);
+        assert_eq!(
+            env.input_json(),
+            &serde_json::json!({ "r0": 1.5, "seed": 11 })
+        );
+        let err = Environment::new()
+            .try_with_defaults_file(dir.path().join("missing.json"))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), "file_not_found");
+    }
+
+    #[test]
+    fn test_duplicate_warnings_suppressed() {
+        let mut env = Environment::from_json(serde_json::json!({ "warning_limit": 5 }));
+        for step in 0..10_000 {
+            env.warn(
+                "negative_rate",
+                &format!("step {step}: rate {} below zero", -(step as f64) / 7.0),
+            );
         }
-        _ => serde_json::from_str(&contents).expect("failed to parse JSON file"),
+        env.warn("negative_rate", "rate missing");
+        env.warn("clamped", "p clamped to 1 in 'onsets'");
+        env.warn("clamped", "p clamped to 1 in 'reports'");
+        env.warn("slow_step", "step took too long");
+        assert_eq!(env.warnings().len(), 5 + 4);
+        assert_eq!(
+            env.warning_counts(),
+            BTreeMap::from([
+                ("clamped".to_string(), 2),
+                ("negative_rate".to_string(), 10_001),
+                ("slow_step".to_string(), 1),
+            ])
+        );
+
+        env.finalize().unwrap();
+        let warnings = env.warnings();
+        assert_eq!(warnings.len(), 5 + 4 + 1);
+        let last = warnings.last().unwrap();
+        assert_eq!(last.code, "warnings_suppressed");
+        assert_eq!(
+            last.message,
+            "suppressed 9995 duplicates beyond 5 per warning (negative_rate: 9995)"
+        );
     }
-}
 
-pub fn toml_to_json(val: toml::Value) -> Value {
-    match val {
-        toml::Value::String(s) => Value::String(s),
-        toml::Value::Integer(i) => Value::Number(serde_json::Number::from(i)),
-        toml::Value::Float(f) => serde_json::Number::from_f64(f).map_or(Value::Null, Value::Number),
-        toml::Value::Boolean(b) => Value::Bool(b),
-        toml::Value::Datetime(d) => Value::String(d.to_string()),
-        toml::Value::Array(arr) => Value::Array(arr.into_iter().map(toml_to_json).collect()),
-        toml::Value::Table(table) => {
-            let map = table
-                .into_iter()
-                .map(|(k, v)| (k, toml_to_json(v)))
-                .collect();
-            Value::Object(map)
+    #[test]
+    fn test_report_sections_and_redaction() {
+        struct Peak(u64);
+        impl ReportSection for Peak {
+            fn title(&self) -> String {
+                "Peak".to_string()
+            }
+            fn body(&self) -> String {
+                format!("Peak incidence: {}", self.0)
+            }
         }
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut env = Environment::from_json(serde_json::json!({
+            "input": { "r0": 2.0, "store": { "token": "s3cr3t-t0ken" } },
+            "output": { "spec": "filesystem", "dir": dir.path().to_str().unwrap() },
+            "negative_control": { "parameter": "r0", "value": 0.0 },
+            "redact": ["store.token"],
+            "report": true,
+        }));
+        env.set_tag("scenario", "baseline");
+        env.set_tag("bucket", "s3cr3t-t0ken");
+        env.record_metric("final_size", 812.0);
+        env.warn("auth", "retrying with token s3cr3t-t0ken");
+        env.add_report_section(Peak(97));
+        env.write_csv("out.csv", &["step"], &[vec!["0".to_string()]]);
+        env.finalize().unwrap();
+
+        let report = fs::read_to_string(dir.path().join("report.md")).unwrap();
+        let headings: Vec<&str> = report.lines().filter(|l| l.starts_with('#')).collect();
+        assert_eq!(
+            headings,
+            [
+                "# Run report",
+                "## Build",
+                "## Tags",
+                "## Input overrides",
+                "## Metrics",
+                "## Peak",
+                "## Warnings",
+                "## Outputs",
+            ]
+        );
+        assert!(report.contains("| scenario | baseline |"));
+        assert!(report.contains("| r0 | 2.0 | 0.0 | negative_control |"));
+        assert!(report.contains("| final_size | 812 |"));
+        assert!(report.contains("Peak incidence: 97"));
+        assert!(report.contains("| out.csv | 7 |"));
+        assert!(!report.contains("s3cr3t-t0ken"));
+        assert!(report.contains("| bucket | [redacted] |"));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_csv_filters() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut env = Environment::from_json(serde_json::json!({
+            "output": {
+                "spec": "filesystem",
+                "dir": dir.path().to_str().unwrap(),
+                "csv_filters": {
+                    "daily.csv": { "columns": ["reported", "step"], "every_nth_row": 7 },
+                    "window.csv": { "step_range": [3, 5] },
+                },
+            },
+            "manifest": true,
+        }));
+        let headers = ["step", "infections", "reported"];
+        let rows: Vec<Vec<String>> = (0..20)
+            .map(|i| vec![i.to_string(), (i * 10).to_string(), i.to_string()])
+            .collect();
+        // Batch and streaming writers both apply the filter
+        env.write_csv("daily.csv", &headers, &rows);
+        env.create_csv("window", "window.csv", &headers);
+        for row in &rows {
+            let refs: Vec<&str> = row.iter().map(|s| s.as_str()).collect();
+            env.write_csv_row("window", &refs);
+        }
+        env.write_csv("full.csv", &headers, &rows[..2]);
+        env.finalize().unwrap();
+
+        let read = |name: &str| fs::read_to_string(dir.path().join(name)).unwrap();
+        assert_eq!(read("daily.csv"), "reported,step\n0,0\n7,7\n14,14\n");
+        assert_eq!(
+            read("window.csv"),
+            "step,infections,reported\n3,30,3\n4,40,4\n5,50,5\n"
+        );
+        assert_eq!(
+            read("full.csv"),
+            "step,infections,reported\n0,0,0\n1,10,1\n"
+        );
+        assert_eq!(env.rows_written(), 3 + 3 + 2);
+
+        let manifest: Value = serde_json::from_str(&read(MANIFEST)).unwrap();
+        let filter = |name: &str| {
+            manifest["files"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|f| f["name"] == name)
+                .unwrap()
+                .get("filter")
+                .cloned()
+        };
+        assert_eq!(
+            filter("daily.csv").unwrap(),
+            serde_json::json!({ "columns": ["reported", "step"], "every_nth_row": 7 })
+        );
+        assert_eq!(filter("full.csv"), None);
+    }
 
     #[test]
-    fn test_from_json_empty() {
-        let env = Environment::from_json(Value::Object(Default::default()));
-        assert_eq!(env.replicate, 0);
-        assert!(env.files.is_empty());
-        assert!(env.output_dir().is_none());
+    #[should_panic(expected = "names unknown column 'cases'")]
+    fn test_csv_filter_unknown_column() {
+        let dir = tempfile::tempdir().unwrap();
+        let env = Environment::from_json(serde_json::json!({
+            "output": {
+                "spec": "filesystem",
+                "dir": dir.path().to_str().unwrap(),
+                "csv_filters": { "out.csv": { "columns": ["step", "cases"] } },
+            },
+        }));
+        env.csv_writer("out.csv", &["step", "infections"]);
     }
 
     #[test]
-    fn test_from_json_with_input() {
-        let data = serde_json::json!({
-            "input": { "seed": 42, "replicate": 3, "r0": 2.0 },
-            "output": { "spec": "filesystem", "dir": "/tmp/out" },
-            "model": { "files": { "data": "/tmp/data.csv" } }
-        });
-        let env = Environment::from_json(data);
-        assert_eq!(env.replicate, 3);
-        assert_eq!(
-            env.files.get("data").unwrap(),
-            &PathBuf::from("/tmp/data.csv")
+    fn test_from_reader() {
+        let payload = br#"{ "input": { "r0": 2.5, "replicate": 2 } }"#.to_vec();
+        let env = Environment::try_from_reader(io::Cursor::new(payload)).unwrap();
+        assert_eq!(env.replicate, 2);
+        assert_eq!(env.input_json()["r0"], 2.5);
+
+        let err = Environment::try_from_reader(io::Cursor::new(b"  \n".to_vec()))
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "input error: payload reader is empty");
+        let err = Environment::try_from_reader(io::Cursor::new(b"{ \"input\": ".to_vec()))
+            .err()
+            .unwrap();
+        assert!(
+            err.to_string().contains("payload reader is not valid JSON"),
+            "{err}"
         );
-        assert_eq!(env.output_dir(), Some(PathBuf::from("/tmp/out")));
     }
 
     #[test]
-    fn test_typed_input() {
+    fn test_load_from_file_matches_stdin_parsing() {
         #[derive(serde::Deserialize, Debug)]
         struct MyInput {
             r0: f64,
-            seed: u64,
         }
-        let data = serde_json::json!({
-            "input": { "seed": 1, "r0": 2.5 }
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("payload.json");
+        let payload = serde_json::json!({ "input": { "r0": 1.5, "seed": 9, "replicate": 3 } });
+        fs::write(&path, payload.to_string()).unwrap();
+        let env = Environment::<MyInput>::load_from_file(&path).unwrap();
+        assert_eq!(env.replicate, 3);
+        assert_eq!(env.input.as_ref().unwrap().r0, 1.5);
+        assert_eq!(
+            env.stream_seed("transmission"),
+            Environment::from_json(payload).stream_seed("transmission")
+        );
+
+        fs::write(&path, r#"{ "input": { "r0": "high" } }"#).unwrap();
+        let err = Environment::<MyInput>::load_from_file(&path).err().unwrap();
+        assert!(err.to_string().contains("r0: invalid type"), "{err}");
+    }
+
+    #[test]
+    fn test_try_from_file_errors_name_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("payload.json");
+        let err = Environment::try_from_file(&missing).err().unwrap();
+        assert_eq!(err.kind(), "file_not_found");
+        assert!(err.to_string().contains("payload.json"), "{err}");
+
+        fs::write(&missing, "{\"input\": {\"r0\": 2.0,}}").unwrap();
+        let err = Environment::try_from_file(&missing).err().unwrap();
+        assert_eq!(err.kind(), "input");
+        assert!(err.to_string().contains("is not valid JSON"), "{err}");
+        assert!(err.to_string().contains("line 1"), "{err}");
+
+        fs::write(&missing, "{\"input\": {\"r0\": 2.0}}").unwrap();
+        let env = Environment::try_from_file(&missing).unwrap();
+        assert_eq!(env.input_json()["r0"], 2.0);
+    }
+
+    #[test]
+    fn test_toml_payload_matches_json() {
+        let env = Environment::try_from_toml_str(
+            r#"
+            [input]
+            seed = "42"
+            replicate = 2
+            r0 = 2.5
+            delays = [1, 2, 3]
+            start = 2024-01-01
+
+            [model.files]
+            cases = "/data/cases.csv"
+
+            [output.profile.default]
+            spec = "filesystem"
+            dir = "/tmp/toml-out"
+            "#,
+        )
+        .unwrap();
+        let json = Environment::from_json(serde_json::json!({
+            "input": {
+                "seed": 42, "replicate": 2, "r0": 2.5,
+                "delays": [1, 2, 3], "start": "2024-01-01",
+            },
+            "model": { "files": { "cases": "/data/cases.csv" } },
+            "output": {
+                "profile": { "default": { "spec": "filesystem", "dir": "/tmp/toml-out" } }
+            },
+        }));
+        assert_eq!(env.input_json(), json.input_json());
+        assert_eq!(env.replicate, 2);
+        assert_eq!(env.files, json.files);
+        assert_eq!(env.output_dir(), Some(PathBuf::from("/tmp/toml-out")));
+
+        let err = Environment::try_from_toml_str("[input\nr0 = 1")
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), "input");
+        assert!(err.to_string().contains("is not valid TOML"), "{err}");
+    }
+
+    #[test]
+    fn test_yaml_payload_matches_json() {
+        let yaml = r#"
+input:
+  seed: 42
+  replicate: 2
+  r0: 2.5
+  delays: [1, 2, 3]
+  start: "2024-01-01"
+model:
+  files:
+    cases: /data/cases.csv
+output:
+  spec: filesystem
+  dir: /tmp/yaml-out
+"#;
+        let json = serde_json::json!({
+            "input": {
+                "seed": 42, "replicate": 2, "r0": 2.5,
+                "delays": [1, 2, 3], "start": "2024-01-01",
+            },
+            "model": { "files": { "cases": "/data/cases.csv" } },
+            "output": { "spec": "filesystem", "dir": "/tmp/yaml-out" },
         });
-        let env = Environment::<MyInput>::from_json_typed(data);
-        let input = env.input.unwrap();
-        assert_eq!(input.seed, 1);
-        assert!((input.r0 - 2.5).abs() < f64::EPSILON);
+        let expected = Environment::from_json(json.clone());
+        let from_str = Environment::try_from_yaml_str(yaml).unwrap();
+        // As stdin is read: not JSON, so sniffed as YAML
+        let sniffed = Environment::try_from_reader(io::Cursor::new(yaml)).unwrap();
+        for env in [&from_str, &sniffed] {
+            assert_eq!(env.input_json(), expected.input_json());
+            assert_eq!(env.replicate, expected.replicate);
+            assert_eq!(env.files, expected.files);
+            assert_eq!(env.output_dir(), expected.output_dir());
+            assert_eq!(env.input_hash(), expected.input_hash());
+        }
+
+        assert_eq!(parse_stdin(yaml, Some("yaml")).unwrap(), json.clone());
+        assert_eq!(parse_stdin(&json.to_string(), Some("JSON")).unwrap(), json);
+        let err = parse_stdin(yaml, Some("json")).unwrap_err();
+        assert!(err.to_string().contains("stdin is not valid JSON"), "{err}");
+        let err = parse_stdin(yaml, Some("xml")).unwrap_err();
+        assert_eq!(err.kind(), "config");
     }
 
     #[test]
-    fn test_with_input_type() {
-        #[derive(serde::Deserialize, Debug)]
-        struct MyInput {
-            r0: f64,
-            seed: u64,
+    fn test_mode_parsed_from_env_value() {
+        assert_eq!(parse_mode(None).unwrap(), Mode::Run);
+        assert_eq!(parse_mode(Some("run")).unwrap(), Mode::Run);
+        assert_eq!(parse_mode(Some("schema")).unwrap(), Mode::Schema);
+        let err = parse_mode(Some("schemas")).unwrap_err();
+        assert_eq!(err.kind(), "config");
+        assert!(err.to_string().contains("MRP_MODE"), "{err}");
+    }
+
+    #[test]
+    fn test_yaml_payload_errors() {
+        // Neither JSON nor YAML: both failures are given
+        let err = Environment::try_from_reader(io::Cursor::new("input: [1, 2\n"))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), "input");
+        let message = err.to_string();
+        assert!(message.contains("payload reader is not valid JSON"), "{message}");
+        assert!(message.contains("is not valid YAML"), "{message}");
+
+        let err = Environment::try_from_yaml_str("- 1\n- 2\n").err().unwrap();
+        assert!(err.to_string().contains("not a YAML mapping: found a sequence"), "{err}");
+        let err = Environment::try_from_reader(io::Cursor::new("just text"))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("found a string"), "{err}");
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("payload.yml");
+        fs::write(&path, "input:\n  r0: 1.5\n").unwrap();
+        assert_eq!(Environment::try_from_file(&path).unwrap().input_json()["r0"], 1.5);
+    }
+
+    #[test]
+    fn test_record_error_creates_output_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("not/yet/created");
+        let env = Environment::try_from_json(serde_json::json!({
+            "input": { "seed": 42, "replicate": 3 },
+            "output": { "spec": "filesystem", "dir": out.to_str().unwrap() },
+        }))
+        .unwrap();
+        env.record_error("parameters_out_of_bounds", "r0 must be positive")
+            .unwrap();
+        let record = crate::formats::read_error(&out.join(ERROR)).unwrap();
+        assert_eq!(record.code, "parameters_out_of_bounds");
+        assert_eq!(record.message, "r0 must be positive");
+        assert_eq!(record.seed, Some(42));
+        assert_eq!(record.replicate, 3);
+        assert!(record.timestamp.ends_with('Z'), "{}", record.timestamp);
+    }
+
+    #[test]
+    fn test_seed_and_replicate_parsed_strictly() {
+        let env = |seed: Value, replicate: Value| {
+            Environment::try_from_json(serde_json::json!({
+                "input": { "seed": seed, "replicate": replicate }
+            }))
+        };
+        let parsed = env("42".into(), " 3 ".into()).unwrap();
+        assert_eq!(parsed.input_json()["seed"], 42);
+        assert_eq!(parsed.replicate, 3);
+        let numeric = env(42.into(), 3.into()).unwrap();
+        assert_eq!(
+            parsed.stream_seed("transmission"),
+            numeric.stream_seed("transmission")
+        );
+        assert_eq!(env(u64::MAX.into(), 2.0.into()).unwrap().replicate, 2);
+
+        let invalid = [
+            ("seed", Value::from(-1), Value::from(0)),
+            ("seed", Value::from(1.5), Value::from(0)),
+            ("seed", Value::from("forty-two"), Value::from(0)),
+            ("seed", Value::from("-1"), Value::from(0)),
+            ("seed", Value::from(1e30), Value::from(0)),
+            ("seed", serde_json::json!({ "seed": 42 }), Value::from(0)),
+            ("replicate", Value::from(1), Value::from(-1)),
+            ("replicate", Value::from(1), Value::from(0.5)),
+            ("replicate", Value::from(1), Value::from("first")),
+        ];
+        for (key, seed, replicate) in invalid {
+            let err = env(seed.clone(), replicate.clone()).err().unwrap();
+            assert_eq!(err.kind(), "input");
+            assert!(
+                err.to_string().contains(&format!("\"{key}\" must be")),
+                "{seed} {replicate}: {err}"
+            );
         }
-        let data = serde_json::json!({
-            "input": { "seed": 5, "r0": 1.5 }
+    }
+
+    #[test]
+    fn test_strict_rejects_unknown_top_level_keys() {
+        let payload = serde_json::json!({
+            "input": { "r0": 2.0 },
+            "outputs": { "spec": "filesystem", "dir": "/tmp/out" },
         });
-        let env = Environment::from_json(data);
-        let typed: Environment<MyInput> = env.with_input_type();
-        assert_eq!(typed.input.as_ref().unwrap().seed, 5);
-        assert!((typed.input.unwrap().r0 - 1.5).abs() < f64::EPSILON);
+        // Lenient parsing still ignores the key
+        assert_eq!(
+            Environment::try_from_json(payload.clone())
+                .unwrap()
+                .output_dir(),
+            None
+        );
+
+        let err = Environment::try_from_json_strict(payload.clone(), &StrictOptions::default())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), "input");
+        assert!(
+            err.to_string().contains("unknown payload keys \"outputs\""),
+            "{err}"
+        );
+
+        let options = StrictOptions {
+            allow_keys: vec!["outputs".to_string()],
+        };
+        assert!(Environment::try_from_json_strict(payload, &options).is_ok());
+
+        let known = serde_json::json!({
+            "mrp": { "version": "0.0.1" },
+            "model": { "files": {} },
+            "runtime": { "spec": "process", "command": "model" },
+            "input": {},
+            "report": true,
+            "warning_limit": 5,
+        });
+        let env = Environment::try_from_json_strict(known, &StrictOptions::default()).unwrap();
+        assert!(env.warnings().is_empty());
     }
 
     #[test]
-    fn test_profiled_output_dir() {
-        let data = serde_json::json!({
-            "output": {
-                "profile": {
-                    "default": { "spec": "filesystem", "dir": "/tmp/profiled" }
-                }
+    fn test_strict_warns_on_unknown_output_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let env = Environment::try_from_json_strict(
+            serde_json::json!({
+                "output": {
+                    "spec": "filesystem",
+                    "dir": dir.path().to_str().unwrap(),
+                    "directory": "/elsewhere",
+                },
+            }),
+            &StrictOptions::default(),
+        )
+        .unwrap();
+        let warnings = env.warnings();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].code, "unknown_output_key");
+        assert!(warnings[0].message.contains("\"directory\""));
+
+        let env = Environment::try_from_json_strict(
+            serde_json::json!({
+                "output": {
+                    "profile": {
+                        "default": { "spec": "filesystem", "dir": "/out", "on_write_fail": "fail" },
+                        "stdout": { "spec": "stdout" },
+                    },
+                },
+            }),
+            &StrictOptions::default(),
+        )
+        .unwrap();
+        let messages: Vec<String> = env.warnings().into_iter().map(|w| w.message).collect();
+        assert_eq!(
+            messages,
+            vec!["unknown key \"on_write_fail\" in output profile \"default\" was ignored"]
+        );
+    }
+
+    #[test]
+    fn test_failed_writes_return_errors_and_can_be_retried() {
+        let dir = tempfile::tempdir().unwrap();
+        let blocker = dir.path().join("out");
+        fs::write(&blocker, "not a directory").unwrap();
+        let mut env = Environment::try_from_json(serde_json::json!({
+            "output": { "spec": "filesystem", "dir": blocker.join("run").to_str().unwrap() },
+        }))
+        .unwrap();
+
+        let err = env.try_write_str("notes.txt", "hello").unwrap_err();
+        assert_eq!(err.kind(), "output");
+        assert!(err.to_string().contains("'notes.txt'"), "{err}");
+        let err = env
+            .try_write_csv("rows.csv", &["a"], &[vec!["1".to_string()]])
+            .unwrap_err();
+        assert!(err.to_string().contains("'rows.csv'"), "{err}");
+        assert!(env.try_create_csv("cases", "cases.csv", &["a"]).is_err());
+
+        // The filesystem recovers; the same calls now succeed
+        fs::remove_file(&blocker).unwrap();
+        env.try_write_str("notes.txt", "hello").unwrap();
+        env.try_create_csv("cases", "cases.csv", &["a"]).unwrap();
+        env.try_write_csv_row("cases", &["1"]).unwrap();
+        env.try_close_csv("cases").unwrap();
+        let out = blocker.join("run");
+        assert_eq!(fs::read_to_string(out.join("notes.txt")).unwrap(), "hello");
+        assert_eq!(fs::read_to_string(out.join("cases.csv")).unwrap(), "a\n1\n");
+    }
+
+    #[test]
+    fn test_unknown_csv_id_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut env = Environment::try_from_json(serde_json::json!({
+            "output": { "spec": "filesystem", "dir": dir.path().to_str().unwrap() },
+        }))
+        .unwrap();
+        let err = env.try_write_csv_row("missing", &["1"]).unwrap_err();
+        assert_eq!(err.kind(), "output");
+        assert!(err.to_string().contains("no CSV writer with id 'missing'"));
+        env.try_create_csv("cases", "cases.csv", &["a", "b"])
+            .unwrap();
+        assert!(env.try_write_csv_row("cases", &["1"]).is_err());
+        assert!(env.try_close_csv("missing").is_ok());
+    }
+
+    #[test]
+    fn test_typed_input_receives_seed_and_replicate() {
+        #[derive(Debug, serde::Deserialize)]
+        struct Params {
+            seed: u64,
+            replicate: u64,
+            r0: f64,
+        }
+        #[derive(Debug, serde::Deserialize)]
+        #[serde(deny_unknown_fields)]
+        #[allow(dead_code)]
+        struct Strict {
+            seed: Option<u64>,
+            r0: f64,
+        }
+        let payload = serde_json::json!({ "input": { "seed": "42", "replicate": 3, "r0": 2.0 } });
+
+        let env = Environment::from_json(payload.clone()).with_input_type::<Params>();
+        let params = env.input.as_ref().unwrap();
+        assert_eq!((params.seed, params.replicate, params.r0), (42, 3, 2.0));
+        assert_eq!(env.replicate, 3);
+        assert!(env.input_json().get("replicate").is_none());
+
+        let env = Environment::from_json(payload).with_input_type::<Strict>();
+        assert_eq!(env.input.as_ref().unwrap().seed, Some(42));
+        assert_eq!(env.replicate, 3);
+
+        // A strict type's rejection of `replicate` is not reported
+        let err = Environment::from_json(serde_json::json!({ "input": { "r0": "two" } }))
+            .try_with_input_type::<Strict>()
+            .err()
+            .unwrap();
+        assert_eq!(err.path, "r0");
+        assert!(!err.message.contains("replicate"), "{err}");
+
+        // Both failures are reported when they differ
+        #[derive(Debug, serde::Deserialize)]
+        #[allow(dead_code)]
+        struct Labelled {
+            replicate: String,
+            r0: f64,
+        }
+        let err = Environment::from_json(serde_json::json!({ "input": { "r0": 2.0 } }))
+            .try_with_input_type::<Labelled>()
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("missing field `replicate`"), "{err}");
+        assert!(
+            err.contains("with `replicate` set, also replicate: invalid type: integer"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_unused_input_keys() {
+        #[derive(Debug, Serialize, serde::Deserialize)]
+        struct Params {
+            #[serde(default)]
+            r0: f64,
+            observation: Observation,
+        }
+        #[derive(Debug, Serialize, serde::Deserialize)]
+        struct Observation {
+            #[serde(default)]
+            delay_pmf: Vec<f64>,
+        }
+        let payload = serde_json::json!({
+            "input": {
+                "r_zero": 2.0,
+                "seed": 1,
+                "observation": { "delay": [1.0] },
             }
         });
-        let env = Environment::from_json(data);
-        assert_eq!(env.output_dir(), Some(PathBuf::from("/tmp/profiled")));
+        let env = Environment::from_json(payload.clone()).with_input_type::<Params>();
+        assert_eq!(
+            env.unused_input_keys(),
+            vec!["observation.delay", "r_zero", "seed"]
+        );
+        env.warn_unused_input();
+        let warnings = env.warnings();
+        assert_eq!(warnings.len(), 3);
+        assert_eq!(warnings[1].code, "unused_input_key");
+        assert!(warnings[1].message.contains("'r_zero'"));
+
+        let err = Environment::from_json(payload)
+            .with_input_type::<Params>()
+            .strict_input()
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "input error: input keys not read by the model: observation.delay, r_zero, seed"
+        );
+
+        let env = Environment::from_json(serde_json::json!({
+            "input": { "r0": 2.0, "replicate": 1, "observation": { "delay_pmf": [1.0] } }
+        }))
+        .with_input_type::<Params>();
+        assert!(env.unused_input_keys().is_empty());
+        assert!(env.strict_input().is_ok());
     }
 
     #[test]
-    fn test_write_csv() {
+    fn test_apply_overrides() {
+        let mut env = Environment::from_json(serde_json::json!({
+            "input": {
+                "r0": 2.0,
+                "seed": 1,
+                "fit": false,
+                "observation": { "delay_pmf": [0.5, 0.5], "reporting": 0.4 },
+            }
+        }));
+        let set = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        env.apply_overrides(&set(&[
+            ("r0", "1.5"),
+            ("sim_length", "50"),
+            ("fit", "true"),
+            ("label", "baseline run"),
+            ("quoted", "\"7\""),
+            ("observation.delay_pmf", "[1.0]"),
+            ("observation.prior.mean", "0.3"),
+            ("seed", "\"9\""),
+            ("replicate", "4"),
+        ]))
+        .unwrap();
+        assert_eq!(
+            env.input_json(),
+            &serde_json::json!({
+                "r0": 1.5,
+                "seed": 9,
+                "sim_length": 50,
+                "fit": true,
+                "label": "baseline run",
+                "quoted": "7",
+                "observation": {
+                    "delay_pmf": [1.0],
+                    "reporting": 0.4,
+                    "prior": { "mean": 0.3 },
+                },
+            })
+        );
+        assert_eq!(env.replicate, 4);
+        let overrides = env.input_overrides();
+        assert_eq!(overrides.len(), 9);
+        assert_eq!(overrides[0].previous, Some(serde_json::json!(2.0)));
+        assert_eq!(overrides[1].previous, None);
+        assert_eq!(overrides[0].source, "cli");
+
+        #[derive(serde::Deserialize)]
+        struct Params {
+            r0: f64,
+            sim_length: usize,
+        }
+        let typed = env.with_input_type::<Params>();
+        let params = typed.input.as_ref().unwrap();
+        assert_eq!((params.r0, params.sim_length), (1.5, 50));
+
+        let mut env = Environment::new();
+        assert!(env.apply_overrides(&set(&[("a..b", "1")])).is_err());
+        assert!(env.apply_overrides(&set(&[("seed", "-1")])).is_err());
+
+        let mut env = Environment::from_json(serde_json::json!({
+            "input": { "r0": 2.0, "delays": [1, 2] }
+        }));
+        let err = env
+            .apply_overrides(&set(&[("r0.mean", "1.5")]))
+            .unwrap_err();
+        assert!(err.to_string().contains("'r0' is not an object"));
+        assert!(env.apply_overrides(&set(&[("delays.0", "3")])).is_err());
+        assert_eq!(env.input_json()["r0"], 2.0);
+        assert_eq!(env.input_json()["delays"], serde_json::json!([1, 2]));
+    }
+
+    #[test]
+    fn test_env_interpolated_at_load() {
         let dir = tempfile::tempdir().unwrap();
-        let data = serde_json::json!({
-            "output": { "spec": "filesystem", "dir": dir.path().to_str().unwrap() }
+        fs::write(dir.path().join("cases.csv"), "step,cases\n").unwrap();
+        let payload = serde_json::json!({
+            "interpolate_env": true,
+            "model": { "files": { "cases": "${MRP_TEST_INTERPOLATE_DIR}/cases.csv" } },
+            "output": { "spec": "filesystem", "dir": "${MRP_TEST_INTERPOLATE_DIR}/out" },
         });
-        let env = Environment::from_json(data);
-        env.write_csv(
-            "test.csv",
-            &["a", "b"],
-            &[
-                vec!["1".to_string(), "2".to_string()],
-                vec!["3".to_string(), "4".to_string()],
-            ],
+        // SAFETY: no other test reads or writes this variable
+        unsafe { std::env::set_var("MRP_TEST_INTERPOLATE_DIR", dir.path()) };
+        let env = Environment::try_from_json(payload.clone()).unwrap();
+        assert_eq!(env.files["cases"], dir.path().join("cases.csv"));
+        assert_eq!(env.output_dir(), Some(dir.path().join("out")));
+        assert!(env.validate_files().is_ok());
+
+        unsafe { std::env::remove_var("MRP_TEST_INTERPOLATE_DIR") };
+        let err = Environment::try_from_json(payload).err().unwrap();
+        assert_eq!(err.kind(), "config");
+        assert!(
+            err.to_string()
+                .contains("MRP_TEST_INTERPOLATE_DIR is not set"),
+            "{err}"
         );
-        let content = fs::read_to_string(dir.path().join("test.csv")).unwrap();
-        assert!(content.contains("a,b"));
-        assert!(content.contains("1,2"));
-        assert!(content.contains("3,4"));
     }
 }
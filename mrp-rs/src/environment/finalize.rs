@@ -0,0 +1,446 @@
+use std::fs;
+use std::io;
+
+use crate::MrpError;
+use crate::formats::{Complete, FORMAT_VERSION, Manifest, ManifestFile, Metrics, RunStatus};
+use crate::framing::CompletionStatus;
+use crate::object_store::ObjectOutput;
+use crate::provenance::sha256_hex;
+
+use super::{COMPLETE, Environment, MANIFEST, METRICS, PARTIAL_METRICS};
+
+impl<I> Environment<I> {
+    /// Finish the run, in the stages of [`FINALIZE_ORDER`]: merge workers,
+    /// close all managed CSV writers, run deferred cleanups, record
+    /// suppressed duplicate warnings, remove the scratch directory and, if
+    /// requested, write `resolved_input.json`; write `metrics.json` and, if
+    /// requested, the report; check that every required declared output was
+    /// produced; then, with `"manifest": true`, write `manifest.json` and
+    /// finally `complete.json`.
+    pub fn finalize(&mut self) -> Result<(), MrpError> {
+        self.finalize_with(|_| {})
+    }
+
+    /// [`Environment::finalize`], calling `after` as each stage completes.
+    pub(crate) fn finalize_with(
+        &mut self,
+        mut after: impl FnMut(FinalizeStage),
+    ) -> Result<(), MrpError> {
+        for stage in FINALIZE_ORDER {
+            self.finalize_stage(stage)?;
+            after(stage);
+        }
+        Ok(())
+    }
+
+    fn finalize_stage(&mut self, stage: FinalizeStage) -> Result<(), MrpError> {
+        match stage {
+            FinalizeStage::Outputs => {
+                self.merge_workers();
+                self.try_close_all_csv()?;
+                let uploaded = self.try_each_objects("outputs", ObjectOutput::finish);
+                if uploaded.is_err() {
+                    // The default spool is in scratch; keep it for resume_uploads
+                    self.scratch.keep();
+                }
+                uploaded?;
+                if let Some(archive) = &self.archive {
+                    archive.finish()?;
+                }
+                self.io_pool.shutdown();
+                let failures = self.deferred.borrow_mut().run();
+                self.warnings.borrow_mut().extend(failures);
+                let waiting = self.stdout.blocked_fraction();
+                if waiting >= 0.005 {
+                    eprintln!(
+                        "stdout: {:.0}% of wall time ({:.1}s) spent waiting on consumer",
+                        waiting * 100.0,
+                        self.stdout.blocked().as_secs_f64()
+                    );
+                }
+                if let Some(reason) = self.cancel.tripped() {
+                    self.warn("cancelled", &reason);
+                }
+                if let Some(summary) = self.warning_dedup.borrow().summary() {
+                    eprintln!("warning [{}]: {}", summary.code, summary.message);
+                    self.warnings.borrow_mut().push(summary);
+                }
+                self.scratch.cleanup();
+                if let Some(stalled) = self.stdout.stall() {
+                    return Err(stalled);
+                }
+                if self.keep_resolved_input && self.output_dir.is_some() {
+                    self.write_resolved_input()?;
+                }
+            }
+            FinalizeStage::Metrics => {
+                if !self.metrics.borrow().is_empty()
+                    && let Some(dir) = self.output_dir()
+                {
+                    let json =
+                        serde_json::to_vec_pretty(&Metrics::new(self.metrics.borrow().clone()))
+                            .map_err(|e| MrpError::Serialization(e.to_string()))?;
+                    self.ensure_dir(&dir)
+                        .and_then(|_| self.write_file(&dir.join(METRICS), &json))
+                        .map_err(|e| MrpError::Output(format!("failed to write {METRICS}: {e}")))?;
+                }
+                if let Some(dir) = self.output_dir() {
+                    match fs::remove_file(dir.join(PARTIAL_METRICS)) {
+                        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                            return Err(MrpError::Output(format!(
+                                "failed to remove {PARTIAL_METRICS}: {e}"
+                            )));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            FinalizeStage::Report => {
+                if self.report {
+                    self.write_report();
+                }
+            }
+            FinalizeStage::Check => {
+                let produced = self.produced.borrow();
+                let missing: Vec<&str> = self
+                    .output_schemas
+                    .iter()
+                    .filter(|(name, schema)| !schema.optional && !produced.contains(*name))
+                    .map(|(name, _)| name.as_str())
+                    .collect();
+                if !missing.is_empty() {
+                    return Err(MrpError::Output(format!(
+                        "declared outputs were never written: {}",
+                        missing.join(", ")
+                    )));
+                }
+            }
+            FinalizeStage::Manifest => {
+                if let Some(dir) = self.output_dir().filter(|_| self.manifest) {
+                    let mut names = self.produced.borrow().clone();
+                    names.insert(METRICS.to_string());
+                    let files = names
+                        .iter()
+                        .filter(|name| dir.join(name).is_file())
+                        .map(|name| {
+                            let data = fs::read(dir.join(name)).map_err(|e| {
+                                MrpError::Output(format!("failed to read '{name}': {e}"))
+                            })?;
+                            Ok(ManifestFile {
+                                name: name.clone(),
+                                bytes: data.len() as u64,
+                                sha256: sha256_hex(&data),
+                                rows: name.ends_with(".csv").then(|| csv_rows(&data)).flatten(),
+                                filter: self
+                                    .csv_filters
+                                    .get(name)
+                                    .map(serde_json::to_value)
+                                    .transpose()
+                                    .map_err(|e| MrpError::Serialization(e.to_string()))?,
+                                provenance: self.provenance_for(name),
+                            })
+                        })
+                        .collect::<Result<Vec<_>, MrpError>>()?;
+                    let manifest = Manifest {
+                        seed: Some(self.seed()),
+                        replicate: Some(self.replicate),
+                        duration_seconds: Some(self.started.elapsed().as_secs_f64()),
+                        schemas: Some(self.output_schemas.clone())
+                            .filter(|schemas| !schemas.is_empty()),
+                        negative_control: self.is_negative_control().then_some(true),
+                        ..Manifest::new(files)
+                    };
+                    let json = serde_json::to_vec_pretty(&manifest)
+                        .map_err(|e| MrpError::Serialization(e.to_string()))?;
+                    self.write_file(&dir.join(MANIFEST), &json).map_err(|e| {
+                        MrpError::Output(format!("failed to write {MANIFEST}: {e}"))
+                    })?;
+                }
+            }
+            FinalizeStage::Complete => {
+                let reason = self.cancel.tripped();
+                let status = match reason {
+                    Some(_) => CompletionStatus::Cancelled,
+                    None => CompletionStatus::Complete,
+                };
+                self.write_completion(status, None, reason.clone())
+                    .map_err(|e| {
+                        MrpError::Output(format!("failed to write the completion frame: {e}"))
+                    })?;
+                if let Some(dir) = self.output_dir().filter(|_| self.manifest) {
+                    let manifest = fs::read(dir.join(MANIFEST))
+                        .map_err(|e| MrpError::Output(format!("failed to read {MANIFEST}: {e}")))?;
+                    let status = Complete {
+                        format_version: FORMAT_VERSION,
+                        status: match reason {
+                            Some(_) => RunStatus::Cancelled,
+                            None => RunStatus::Complete,
+                        },
+                        manifest_sha256: sha256_hex(&manifest),
+                        reason,
+                        cache_hit: None,
+                        negative_control: self.is_negative_control().then_some(true),
+                    };
+                    let json = serde_json::to_vec_pretty(&status)
+                        .map_err(|e| MrpError::Serialization(e.to_string()))?;
+                    self.write_file(&dir.join(COMPLETE), &json).map_err(|e| {
+                        MrpError::Output(format!("failed to write {COMPLETE}: {e}"))
+                    })?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A step of [`Environment::finalize`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FinalizeStage {
+    /// Flush and close data outputs, run deferred cleanups and record
+    /// suppressed warnings.
+    Outputs,
+    /// `metrics.json`.
+    Metrics,
+    /// `report.md`, if requested.
+    Report,
+    /// Fail if a required declared output was never written.
+    Check,
+    /// `manifest.json`, listing every file written before it.
+    Manifest,
+    /// `complete.json`, holding the manifest's hash and whether the run
+    /// completed or was cancelled.
+    Complete,
+}
+
+/// The order [`Environment::finalize`] runs its stages in. Each stage only
+/// writes files after every earlier stage's, so a consumer that sees
+/// `manifest.json` can rely on everything it lists, and `complete.json`
+/// appears last of all.
+pub const FINALIZE_ORDER: [FinalizeStage; 6] = [
+    FinalizeStage::Outputs,
+    FinalizeStage::Metrics,
+    FinalizeStage::Report,
+    FinalizeStage::Check,
+    FinalizeStage::Manifest,
+    FinalizeStage::Complete,
+];
+
+/// The number of records after the header row of a CSV file, or `None`
+/// if it does not parse.
+fn csv_rows(data: &[u8]) -> Option<u64> {
+    let mut rows = 0;
+    for record in ::csv::Reader::from_reader(data).records() {
+        record.ok()?;
+        rows += 1;
+    }
+    Some(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use serde_json::Value;
+
+    use super::*;
+    use crate::environment::tests::fs_env;
+    use crate::schema::{ColumnType, OutputSchema};
+
+    #[test]
+    fn test_finalize_writes_manifest_last() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut env = fs_env(dir.path());
+        env.manifest = true;
+        env.report = true;
+        env.create_csv("cases", "cases.csv", &["day", "count"]);
+        env.write_csv_row("cases", &["0", "4"]);
+        env.write("run_info.json", b"{}");
+        env.record_metric("final_size", 4.0);
+
+        // Which stage each file first appeared after
+        let mut appeared: BTreeMap<String, FinalizeStage> = BTreeMap::new();
+        env.finalize_with(|stage| {
+            for entry in fs::read_dir(dir.path()).unwrap() {
+                let name = entry.unwrap().file_name().to_string_lossy().into_owned();
+                appeared.entry(name).or_insert(stage);
+            }
+        })
+        .unwrap();
+        assert_eq!(appeared[MANIFEST], FinalizeStage::Manifest);
+        assert_eq!(appeared[COMPLETE], FinalizeStage::Complete);
+        let position = |stage| FINALIZE_ORDER.iter().position(|s| *s == stage).unwrap();
+        for (name, stage) in &appeared {
+            if name != MANIFEST && name != COMPLETE {
+                assert!(
+                    position(*stage) < position(FinalizeStage::Manifest),
+                    "{name}"
+                );
+            }
+        }
+
+        let manifest = crate::formats::read_manifest(&dir.path().join(MANIFEST)).unwrap();
+        assert_eq!(manifest.format_version, FORMAT_VERSION);
+        let listed: Vec<&str> = manifest.files.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(
+            listed,
+            ["cases.csv", "metrics.json", "report.md", "run_info.json"]
+        );
+        let others: Vec<&String> = appeared
+            .keys()
+            .filter(|n| *n != MANIFEST && *n != COMPLETE)
+            .collect();
+        assert_eq!(others, listed);
+        let complete = crate::formats::read_complete(&dir.path().join(COMPLETE)).unwrap();
+        assert_eq!(complete.status, RunStatus::Complete);
+        assert_eq!(
+            complete.manifest_sha256,
+            sha256_hex(&fs::read(dir.path().join(MANIFEST)).unwrap())
+        );
+    }
+
+    #[test]
+    fn test_manifest_matches_files_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut env = fs_env(dir.path());
+        env.manifest = true;
+        env.create_csv("cases", "cases.csv", &["day", "count"]);
+        for day in 0..5 {
+            env.write_csv_row("cases", &[&day.to_string(), "1"]);
+        }
+        env.write_csv("tables/peaks.csv", &["peak"], &[vec!["8".to_string()]]);
+        env.write_str("summary.json", "{}");
+        fs::write(
+            dir.path().join("untracked.txt"),
+            "not through the environment",
+        )
+        .unwrap();
+        env.finalize().unwrap();
+
+        let manifest = crate::formats::read_manifest(&dir.path().join(MANIFEST)).unwrap();
+        assert_eq!(manifest.seed, Some(env.seed()));
+        assert_eq!(manifest.replicate, Some(0));
+        assert!(manifest.duration_seconds.is_some_and(|s| s >= 0.0));
+        let listed: Vec<&str> = manifest.files.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(listed, ["cases.csv", "summary.json", "tables/peaks.csv"]);
+        for file in &manifest.files {
+            let data = fs::read(dir.path().join(&file.name)).unwrap();
+            assert_eq!(file.bytes, data.len() as u64, "{}", file.name);
+            assert_eq!(file.sha256, sha256_hex(&data), "{}", file.name);
+        }
+        let rows: Vec<Option<u64>> = manifest.files.iter().map(|f| f.rows).collect();
+        assert_eq!(rows, [Some(5), None, Some(1)]);
+        for file in &manifest.files {
+            let provenance = file.provenance.as_ref().unwrap();
+            assert_eq!(Some(provenance), env.provenance_for(&file.name).as_ref());
+            assert_eq!(provenance.replicate, 0);
+            assert!(provenance.finished_at.is_some(), "{}", file.name);
+        }
+    }
+
+    #[test]
+    fn test_manifest_lists_declared_schemas() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut env = fs_env(dir.path());
+        env.manifest = true;
+        env.declare_output_schema(
+            "cases.csv",
+            &[("day", ColumnType::Integer), ("count", ColumnType::Float)],
+        );
+        env.declare_output("peaks.csv", OutputSchema::new(&[]).optional());
+        env.write_csv(
+            "cases.csv",
+            &["day", "count"],
+            &[vec!["0".into(), "1.5".into()]],
+        );
+        env.finalize().unwrap();
+
+        let manifest = crate::formats::read_manifest(&dir.path().join(MANIFEST)).unwrap();
+        let schemas = manifest.schemas.unwrap();
+        assert_eq!(&schemas, env.output_schemas());
+        let cases = &schemas["cases.csv"].columns;
+        assert_eq!(cases[1].name, "count");
+        assert_eq!(cases[1].ty, ColumnType::Float);
+        assert!(schemas["peaks.csv"].optional);
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut env = fs_env(dir.path());
+        env.manifest = true;
+        env.finalize().unwrap();
+        let manifest = crate::formats::read_manifest(&dir.path().join(MANIFEST)).unwrap();
+        assert!(manifest.schemas.is_none());
+    }
+
+    #[test]
+    fn test_cancelled_run_finalizes_as_cancelled() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut env = fs_env(dir.path());
+        env.manifest = true;
+        env.create_csv("cases", "cases.csv", &["day", "count"]);
+        let token = env.cancel_token();
+        let mut day = 0;
+        while !token.is_cancelled() {
+            env.write_csv_row("cases", &[&day.to_string(), "1"]);
+            day += 1;
+            if day == 3 {
+                let remote = env.cancel_token();
+                std::thread::spawn(move || remote.cancel("shutting down"))
+                    .join()
+                    .unwrap();
+            }
+        }
+        env.finalize().unwrap();
+
+        let csv = fs::read_to_string(dir.path().join("cases.csv")).unwrap();
+        assert_eq!(csv.lines().count(), 4);
+        let complete: Value =
+            serde_json::from_slice(&fs::read(dir.path().join(COMPLETE)).unwrap()).unwrap();
+        assert_eq!(complete["status"], "cancelled");
+        assert_eq!(complete["reason"], "shutting down");
+        assert_eq!(env.warnings()[0].code, "cancelled");
+
+        let timed = Environment::from_json(serde_json::json!({ "max_run_seconds": 0 }));
+        assert!(timed.cancel_token().is_cancelled());
+        assert!(!Environment::new().cancel_token().is_cancelled());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_finalize_returns_failed_csv_close() {
+        let dir = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink("/dev/full", dir.path().join("full.csv")).unwrap();
+        let mut env = fs_env(dir.path());
+        env.create_csv("cases", "cases.csv", &["x"]);
+        env.create_csv("full", "full.csv", &["x"]);
+        env.write_csv_row("cases", &["1"]);
+        env.write_csv_row("full", &["1"]);
+        let err = env.finalize().unwrap_err();
+        assert_eq!(err.kind(), "output");
+        assert!(err.to_string().contains("'full'"), "{err}");
+        assert_eq!(
+            fs::read_to_string(dir.path().join("cases.csv")).unwrap(),
+            "x\n1\n"
+        );
+    }
+
+    #[test]
+    fn test_io_pool_drained_at_finalize() {
+        let mut env = Environment::from_json(serde_json::json!({ "io_threads": 3 }));
+        assert_eq!(env.io_pool_stats().threads, 3);
+        let done = Arc::new(Mutex::new(Vec::new()));
+        let first = env.submit_io(|| 1 + 1);
+        for i in 0..5 {
+            let done = done.clone();
+            env.submit_io(move || {
+                std::thread::sleep(Duration::from_millis(10));
+                done.lock().unwrap().push(i);
+            });
+        }
+        assert_eq!(first.wait().unwrap(), 2);
+        env.finalize().unwrap();
+        assert_eq!(done.lock().unwrap().len(), 5);
+        assert_eq!(env.io_pool_stats().tasks_run, 6);
+        assert!(Environment::try_from_json(serde_json::json!({ "io_threads": 0 })).is_err());
+    }
+}
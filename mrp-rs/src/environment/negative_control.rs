@@ -0,0 +1,130 @@
+use serde_json::Value;
+
+use crate::MrpError;
+
+use super::{Environment, InputOverride, MAX_PAYLOAD_DEPTH, set_input_path};
+
+impl<I> Environment<I> {
+    /// Whether the payload requested a negative-control run.
+    ///
+    /// A payload `"negative_control": {"parameter": "r0", "value": 0.0}`
+    /// overrides the (dotted) input parameter before the model sees it, tags
+    /// the run `negative_control=true`, and sets a `negative_control` metric so
+    /// its outputs cannot be mistaken for a real run. `manifest.json` and
+    /// `complete.json` also carry `"negative_control": true`.
+    pub fn is_negative_control(&self) -> bool {
+        self.tags.get("negative_control").map(String::as_str) == Some("true")
+    }
+}
+
+/// Apply the payload's `"negative_control"`, if it has one, to `input`,
+/// returning the override it made.
+pub(super) fn apply(data: &Value, input: &mut Value) -> Result<Option<InputOverride>, MrpError> {
+    let Some(control) = data.get("negative_control") else {
+        return Ok(None);
+    };
+    let (path, value) = parse_negative_control(control)?;
+    set_input_path(input, &path, value, "negative_control").map(Some)
+}
+
+fn parse_negative_control(control: &Value) -> Result<(String, Value), MrpError> {
+    let parameter = control
+        .get("parameter")
+        .and_then(|v| v.as_str())
+        .filter(|p| !p.is_empty())
+        .ok_or_else(|| {
+            MrpError::Input(format!(
+                "negative_control requires a \"parameter\" string, got {control}"
+            ))
+        })?;
+    if parameter.split('.').any(str::is_empty) {
+        return Err(MrpError::Config(format!(
+            "negative_control parameter '{parameter}' has an empty path segment"
+        )));
+    }
+    if parameter.split('.').count() > MAX_PAYLOAD_DEPTH {
+        return Err(MrpError::Input(format!(
+            "negative_control parameter nests deeper than {MAX_PAYLOAD_DEPTH} levels"
+        )));
+    }
+    let value = control.get("value").cloned().ok_or_else(|| {
+        MrpError::Input(format!(
+            "negative_control requires a \"value\", got {control}"
+        ))
+    })?;
+    Ok((parameter.to_string(), value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environment::tests::fs_env;
+    use crate::environment::{COMPLETE, MANIFEST};
+
+    #[test]
+    fn test_negative_control_nested() {
+        let env = Environment::from_json(serde_json::json!({
+            "input": { "r0": 2.0, "observation": { "reporting_prob": 0.3 } },
+            "negative_control": { "parameter": "observation.reporting_prob", "value": 0.0 }
+        }));
+        assert!(env.is_negative_control());
+        assert_eq!(env.input_json["observation"]["reporting_prob"], 0.0);
+        assert_eq!(env.input_json["r0"], 2.0);
+        let overrides = env.input_overrides();
+        assert_eq!(overrides.len(), 1);
+        assert_eq!(overrides[0].path, "observation.reporting_prob");
+        assert_eq!(overrides[0].previous, Some(serde_json::json!(0.3)));
+        assert_eq!(overrides[0].source, "negative_control");
+        assert_eq!(env.metrics()["negative_control"], 1.0);
+    }
+
+    #[test]
+    fn test_negative_control_malformed() {
+        let err = Environment::try_from_json(serde_json::json!({"negative_control": {"value": 0}}))
+            .err()
+            .unwrap();
+        assert!(
+            err.to_string()
+                .contains("negative_control requires a \"parameter\"")
+        );
+    }
+
+    #[test]
+    fn test_negative_control_rejects_empty_segments() {
+        for parameter in ["a..b", ".r0", "r0."] {
+            let err = Environment::try_from_json(serde_json::json!({
+                "input": { "r0": 2.0 },
+                "negative_control": { "parameter": parameter, "value": 0.0 }
+            }))
+            .err()
+            .unwrap();
+            assert_eq!(err.kind(), "config", "{parameter}");
+            assert!(err.to_string().contains("empty path segment"), "{err}");
+        }
+    }
+
+    #[test]
+    fn test_negative_control_recorded_at_finalize() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut env = Environment::from_json(serde_json::json!({
+            "input": { "r0": 2.0 },
+            "output": { "spec": "filesystem", "dir": dir.path().to_str().unwrap() },
+            "negative_control": { "parameter": "r0", "value": 0.0 },
+        }));
+        env.manifest = true;
+        env.finalize().unwrap();
+        let manifest = crate::formats::read_manifest(&dir.path().join(MANIFEST)).unwrap();
+        assert_eq!(manifest.negative_control, Some(true));
+        let complete = crate::formats::read_complete(&dir.path().join(COMPLETE)).unwrap();
+        assert_eq!(complete.negative_control, Some(true));
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut env = fs_env(dir.path());
+        env.manifest = true;
+        env.finalize().unwrap();
+        let manifest = crate::formats::read_manifest(&dir.path().join(MANIFEST)).unwrap();
+        assert_eq!(manifest.negative_control, None);
+        let complete = crate::formats::read_complete(&dir.path().join(COMPLETE)).unwrap();
+        assert_eq!(complete.negative_control, None);
+    }
+}
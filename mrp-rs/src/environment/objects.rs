@@ -0,0 +1,495 @@
+use std::path::Path;
+use std::rc::Rc;
+
+use serde_json::Value;
+
+use crate::MrpError;
+use crate::archive::ArchiveOutput;
+use crate::object_store::{ObjectOutput, ObjectStore};
+use crate::tee::{Sink, SinkKind, Tee};
+
+use super::{
+    Environment, create_output_tempdir, first_sink, output_spec, resolve_output_dir,
+    scope_output_dir, sink_name,
+};
+
+impl<I> Environment<I> {
+    /// The store of an `"s3"`, `"gs"` or `"azure"` output spec, under its
+    /// `"prefix"` with `{run_id}`, `{seed}`, `{replicate}` and `{hash}`
+    /// filled in.
+    pub(super) fn object_output(&self) -> Result<Option<ObjectOutput>, MrpError> {
+        let Some(spec) = output_spec(&self.output, self.output_profile.as_deref())
+            .map(first_sink)
+            .transpose()?
+            .filter(|spec| is_object_spec(spec))
+        else {
+            return Ok(None);
+        };
+        self.object_output_for(spec).map(Some)
+    }
+
+    pub(super) fn object_output_for(&self, spec: &Value) -> Result<ObjectOutput, MrpError> {
+        let kind = spec.get("spec").and_then(|v| v.as_str()).unwrap_or("s3");
+        let bucket = spec.get("bucket").and_then(|v| v.as_str()).ok_or_else(|| {
+            MrpError::Config(format!("the {kind} output spec needs a \"bucket\""))
+        })?;
+        let prefix = self.object_prefix(spec.get("prefix").and_then(|v| v.as_str()).unwrap_or(""));
+        ObjectOutput::new(cloud_store(kind, bucket)?, spec, prefix, &self.scratch)
+    }
+
+    /// The archive of an `"archive"` output spec, at its `"path"` with
+    /// placeholders expanded.
+    pub(super) fn archive_output(&self) -> Result<Option<Rc<ArchiveOutput>>, MrpError> {
+        let Some(spec) = output_spec(&self.output, self.output_profile.as_deref())
+            .map(first_sink)
+            .transpose()?
+            .filter(|spec| spec.get("spec").and_then(|v| v.as_str()) == Some("archive"))
+        else {
+            return Ok(None);
+        };
+        let path = spec.get("path").and_then(|v| v.as_str()).ok_or_else(|| {
+            MrpError::Config("the archive output spec needs a \"path\"".to_string())
+        })?;
+        let path = self.expand_template_for(
+            path,
+            (self.seed_value(), self.replicate),
+            self.output_profile.as_deref(),
+        )?;
+        ArchiveOutput::create(Path::new(&path), &self.scratch, self.warnings.clone()).map(Some)
+    }
+
+    /// The sinks of a `"multi"` spec after the first.
+    pub(super) fn tee(&self) -> Result<Option<Tee>, MrpError> {
+        let Some(spec) = output_spec(&self.output, self.output_profile.as_deref())
+            .filter(|spec| spec.get("spec").and_then(|v| v.as_str()) == Some("multi"))
+        else {
+            return Ok(None);
+        };
+        let profile = self.output_profile.as_deref().unwrap_or("default");
+        let specs = spec["sinks"]
+            .as_array()
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let mut sinks = Vec::new();
+        for (i, spec) in specs.iter().enumerate().skip(1) {
+            let kind = match spec.get("spec").and_then(|v| v.as_str()) {
+                Some("filesystem") => {
+                    let Some(dir) = resolve_output_dir(&self.expand_dir(spec, profile)?)? else {
+                        return Err(MrpError::Config(format!(
+                            "output sink {i} is filesystem output with no \"dir\""
+                        )));
+                    };
+                    SinkKind::Dir(scope_output_dir(spec, dir, self.replicate)?, None)
+                }
+                Some("tempdir") => {
+                    let tempdir = create_output_tempdir(spec)?.expect("a tempdir spec");
+                    eprintln!("{}", tempdir.announcement());
+                    let dir = tempdir.path().to_path_buf();
+                    let dir = scope_output_dir(spec, dir, self.replicate)?;
+                    SinkKind::Dir(dir, Some(tempdir))
+                }
+                Some(_) if is_object_spec(spec) => SinkKind::Objects(self.object_output_for(spec)?),
+                Some("memory") => SinkKind::Memory,
+                _ => {
+                    return Err(MrpError::Config(format!(
+                        "output sink {i} must be filesystem, tempdir, s3, gs, azure or memory; \
+                         only the first sink can be stdout"
+                    )));
+                }
+            };
+            sinks.push(Sink {
+                name: sink_name(i, spec),
+                kind,
+            });
+        }
+        Ok(Some(Tee {
+            first: sink_name(0, &specs[0]),
+            sinks,
+            require_all: spec.get("require_all").and_then(|v| v.as_bool()) == Some(true),
+        }))
+    }
+
+    /// The tee of a `"multi"` spec, if writes to `dir` go through it: those
+    /// to the first sink's destination.
+    pub(super) fn tee_for(&self, dir: Option<&Path>) -> Option<&Tee> {
+        self.tee
+            .as_ref()
+            .filter(|_| dir == self.output_dir.as_deref())
+    }
+
+    /// Write `filename` to the other sinks of a `"multi"` spec, given
+    /// `first`, the result of writing it to the first. Without a tee this
+    /// is just `first`.
+    pub(super) fn try_write_tee(
+        &self,
+        dir: Option<&Path>,
+        filename: &str,
+        data: &[u8],
+        first: Result<(), MrpError>,
+    ) -> Result<(), MrpError> {
+        let Some(tee) = self.tee_for(dir) else {
+            return first;
+        };
+        let mut results = vec![(tee.first.as_str(), first)];
+        for sink in &tee.sinks {
+            let result = match &sink.kind {
+                SinkKind::Dir(dir, _) => self.try_write_sink(Some(dir), filename, data),
+                SinkKind::Objects(objects) => {
+                    let result = objects.put(filename, data);
+                    self.take_upload_warnings();
+                    result
+                }
+                SinkKind::Memory => {
+                    self.memory
+                        .borrow_mut()
+                        .insert(filename.to_string(), data.to_vec());
+                    Ok(())
+                }
+            };
+            results.push((sink.name.as_str(), result));
+        }
+        tee.settle(filename, results, &self.warnings)
+    }
+
+    /// Run `f` on the object store outputs: that of the spec, and those of
+    /// a `"multi"` spec's other sinks, settling failures as writes are.
+    pub(super) fn try_each_objects<F>(&self, filename: &str, f: F) -> Result<(), MrpError>
+    where
+        F: Fn(&ObjectOutput) -> Result<(), MrpError>,
+    {
+        let first = self.objects.as_ref().map_or(Ok(()), &f);
+        self.take_upload_warnings();
+        let Some(tee) = &self.tee else {
+            return first;
+        };
+        let mut results = vec![(tee.first.as_str(), first)];
+        for sink in &tee.sinks {
+            if let SinkKind::Objects(objects) = &sink.kind {
+                let result = f(objects);
+                self.take_upload_warnings();
+                results.push((sink.name.as_str(), result));
+            }
+        }
+        tee.settle(filename, results, &self.warnings)
+    }
+
+    /// `template` with its placeholders filled in, ending in `/` unless
+    /// empty. `{run_id}` is `MRP_RUN_ID`, else the input hash.
+    pub(super) fn object_prefix(&self, template: &str) -> String {
+        let hash = self.input_hash();
+        let mut prefix = template
+            .replace("{run_id}", &self.run_id())
+            .replace("{seed}", &self.seed_value().to_string())
+            .replace("{replicate}", &self.replicate.to_string())
+            .replace("{hash}", &hash);
+        if !prefix.is_empty() && !prefix.ends_with('/') {
+            prefix.push('/');
+        }
+        prefix
+    }
+
+    /// Record the object store's retry and deferral warnings.
+    pub(super) fn take_upload_warnings(&self) {
+        let tee = self.tee.iter().flat_map(|tee| &tee.sinks);
+        let tee = tee.filter_map(|sink| match &sink.kind {
+            SinkKind::Objects(objects) => Some(objects),
+            _ => None,
+        });
+        for objects in self.objects.iter().chain(tee) {
+            for warning in objects.take_warnings() {
+                eprintln!("warning [{}]: {}", warning.code, warning.message);
+                self.warnings.borrow_mut().push(warning);
+            }
+        }
+    }
+}
+
+/// Whether `spec` uploads to an object store: `"s3"`, `"gs"` or `"azure"`.
+fn is_object_spec(spec: &Value) -> bool {
+    matches!(
+        spec.get("spec").and_then(|v| v.as_str()),
+        Some("s3" | "gs" | "azure")
+    )
+}
+
+#[cfg(feature = "object-store")]
+fn cloud_store(kind: &str, bucket: &str) -> Result<Box<dyn ObjectStore>, MrpError> {
+    Ok(Box::new(crate::cloud::CloudStore::for_spec(kind, bucket)?))
+}
+
+#[cfg(not(feature = "object-store"))]
+fn cloud_store(kind: &str, _bucket: &str) -> Result<Box<dyn ObjectStore>, MrpError> {
+    Err(MrpError::Config(format!(
+        "the {kind} output spec needs cfa-mrp's object-store feature"
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+    use std::{fs, io};
+
+    use super::*;
+    use crate::compare::list_files;
+    use crate::environment::tests::fs_env;
+
+    type Objects = Rc<RefCell<BTreeMap<String, Vec<u8>>>>;
+
+    /// Stores objects in memory, or fails every put.
+    struct TestStore {
+        objects: Objects,
+        fail: bool,
+    }
+
+    impl ObjectStore for TestStore {
+        fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+            if self.fail {
+                return Err(io::Error::other(format!("PUT s3://bucket/{key}: 503")));
+            }
+            self.objects
+                .borrow_mut()
+                .insert(key.to_string(), data.to_vec());
+            Ok(())
+        }
+    }
+
+    fn object_env(fail: bool, spool: Option<&Path>) -> (Environment, Objects) {
+        let objects = Rc::new(RefCell::new(BTreeMap::new()));
+        let store = TestStore {
+            objects: objects.clone(),
+            fail,
+        };
+        let mut spec = serde_json::json!({ "retry": { "attempts": 1, "backoff_ms": 0 } });
+        if let Some(spool) = spool {
+            spec["spool_dir"] = spool.to_str().unwrap().into();
+        }
+        let mut env = Environment::builder().seed(4).replicate(1).build();
+        let prefix = env.object_prefix("runs/{seed}-{replicate}");
+        let objects_out = ObjectOutput::new(Box::new(store), &spec, prefix, &env.scratch).unwrap();
+        env.objects = Some(objects_out);
+        (env, objects)
+    }
+
+    #[test]
+    fn test_object_store_output() {
+        let spool = tempfile::tempdir().unwrap();
+        let (mut env, objects) = object_env(false, Some(spool.path()));
+        env.write_str("summary.json", "{}");
+        assert_eq!(objects.borrow()["runs/4-1/summary.json"], b"{}");
+
+        env.create_csv("cases", "cases.csv", &["step"]);
+        env.write_csv_row("cases", &["0"]);
+        assert!(!objects.borrow().contains_key("runs/4-1/cases.csv"));
+        env.close_csv("cases");
+        assert_eq!(objects.borrow()["runs/4-1/cases.csv"], b"step\n0\n");
+
+        let mut writer = env.csv_writer("later.csv", &["step"]);
+        writer.write_row(&["1"]);
+        writer.flush();
+        env.finalize().unwrap();
+        assert_eq!(objects.borrow()["runs/4-1/later.csv"], b"step\n1\n");
+        assert_eq!(env.output_dir(), None);
+    }
+
+    #[test]
+    fn test_object_store_failure_names_object() {
+        let spool = tempfile::tempdir().unwrap();
+        let (mut env, _) = object_env(true, Some(spool.path()));
+        env.try_write_str("summary.json", "{}").unwrap();
+        let warnings = env.warnings();
+        assert_eq!(warnings[0].code, "upload_deferred");
+        assert!(
+            warnings[0]
+                .message
+                .contains("s3://bucket/runs/4-1/summary.json")
+        );
+        let err = env.finalize().unwrap_err().to_string();
+        assert!(err.contains("runs/4-1/summary.json"), "{err}");
+        assert!(spool.path().join("runs/4-1/summary.json").is_file());
+
+        let err = Environment::try_from_json(serde_json::json!({ "output": { "spec": "s3" } }))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("needs a \"bucket\""), "{err}");
+    }
+
+    #[test]
+    fn test_failed_upload_keeps_scratch_spool() {
+        let (mut env, _) = object_env(true, None);
+        env.try_write_str("summary.json", "{}").unwrap();
+        let scratch = env.scratch_dir().unwrap().to_path_buf();
+        assert!(env.finalize().is_err());
+        let spooled: Vec<String> = list_files(&scratch).unwrap().into_iter().collect();
+        assert_eq!(spooled.len(), 1);
+        assert!(
+            spooled[0].ends_with("_spool/runs/4-1/summary.json"),
+            "{spooled:?}"
+        );
+        fs::remove_dir_all(scratch).unwrap();
+    }
+
+    #[test]
+    fn test_multi_output() {
+        let a = tempfile::tempdir().unwrap();
+        let b = tempfile::tempdir().unwrap();
+        let mut env = Environment::from_json(serde_json::json!({
+            "output": {
+                "spec": "multi",
+                "sinks": [
+                    { "spec": "filesystem", "dir": a.path().to_str().unwrap() },
+                    { "spec": "filesystem", "dir": b.path().to_str().unwrap() },
+                    { "spec": "memory" }
+                ]
+            }
+        }));
+        assert_eq!(env.output_dir().as_deref(), Some(a.path()));
+        env.write_str("summary.json", "{\"peak\": 4}");
+        env.create_csv("cases", "cases.csv", &["step", "cases"]);
+        env.write_csv_row("cases", &["0", "5"]);
+        env.write_csv_row("cases", &["1", "8"]);
+        env.close_csv("cases");
+        let mut writer = env.jsonl_writer("events.jsonl");
+        writer.write_record(&serde_json::json!({ "step": 1 }));
+        writer.flush();
+        for name in ["summary.json", "cases.csv", "events.jsonl"] {
+            let written = fs::read(a.path().join(name)).unwrap();
+            assert_eq!(fs::read(b.path().join(name)).unwrap(), written);
+            assert_eq!(env.memory_outputs()[name], written);
+        }
+        assert_eq!(
+            fs::read_to_string(b.path().join("cases.csv")).unwrap(),
+            "step,cases\n0,5\n1,8\n"
+        );
+        assert!(env.warnings().is_empty());
+    }
+
+    #[test]
+    fn test_multi_output_failed_sink() {
+        let a = tempfile::tempdir().unwrap();
+        let blocker = a.path().join("blocker");
+        fs::write(&blocker, "").unwrap();
+        let broken = blocker.join("out");
+        let payload = |require_all: bool| {
+            serde_json::json!({
+                "output": {
+                    "spec": "multi",
+                    "require_all": require_all,
+                    "sinks": [
+                        { "spec": "filesystem", "dir": a.path().join("out").to_str().unwrap() },
+                        { "spec": "filesystem", "dir": broken.to_str().unwrap() }
+                    ]
+                }
+            })
+        };
+
+        // The healthy sink keeps getting every write
+        let mut env = Environment::from_json(payload(false));
+        env.try_write_str("summary.json", "{}").unwrap();
+        env.try_create_csv("cases", "cases.csv", &["step"]).unwrap();
+        env.write_csv_row("cases", &["0"]);
+        env.close_csv("cases");
+        assert_eq!(
+            fs::read_to_string(a.path().join("out/cases.csv")).unwrap(),
+            "step\n0\n"
+        );
+        assert!(a.path().join("out/summary.json").is_file());
+        let warnings = env.warnings();
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings.iter().all(|w| w.code == "sink_failed"));
+        assert!(warnings[1].message.contains("output sink 1 (filesystem"));
+        assert!(warnings[1].message.contains("'cases.csv'"));
+
+        let mut env = Environment::from_json(payload(true));
+        let err = env.try_write_str("summary.json", "{}").unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("output sinks failed for 'summary.json'")
+        );
+        assert!(err.to_string().contains("output sink 1"));
+        assert!(env.try_create_csv("cases", "cases.csv", &["step"]).is_err());
+
+        for (output, message) in [
+            (
+                serde_json::json!({ "spec": "multi", "sinks": [] }),
+                "non-empty \"sinks\"",
+            ),
+            (
+                serde_json::json!({ "spec": "multi", "sinks": [{}, { "spec": "stdout" }] }),
+                "only the first sink can be stdout",
+            ),
+        ] {
+            let err = Environment::try_from_json(serde_json::json!({ "output": output }))
+                .err()
+                .unwrap();
+            assert!(err.to_string().contains(message), "{err}");
+        }
+    }
+
+    #[test]
+    fn test_archive_output() {
+        fn run(env: &mut Environment) {
+            env.write_str("summary.json", "{\"peak\": 4}");
+            env.write_csv("tables/peaks.csv", &["peak"], &[vec!["4".to_string()]]);
+            env.create_csv("cases", "cases.csv", &["step", "cases"]);
+            env.write_csv_row("cases", &["0", "5"]);
+            env.close_csv("cases");
+            // Left for finalize to close
+            env.create_csv("deaths", "deaths.csv", &["step"]);
+            env.write_csv_row("deaths", &["0"]);
+            let mut writer = env.jsonl_writer("events.jsonl");
+            writer.write_record(&serde_json::json!({ "step": 1 }));
+        }
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("fs");
+        let mut env = fs_env(&dir);
+        run(&mut env);
+        env.finalize().unwrap();
+        let expected: BTreeMap<String, Vec<u8>> = crate::compare::list_files(&dir)
+            .unwrap()
+            .into_iter()
+            .map(|name| (name.clone(), fs::read(dir.join(name)).unwrap()))
+            .collect();
+        assert_eq!(expected.len(), 5);
+
+        for name in ["run_{seed}.tar.gz", "run_{seed}.zip"] {
+            let mut env = Environment::from_json(serde_json::json!({
+                "input": { "seed": 3 },
+                "output": { "spec": "archive", "path": root.path().join(name) }
+            }));
+            let path = root.path().join(name.replace("{seed}", "3"));
+            run(&mut env);
+            assert!(!path.exists());
+            assert!(env.try_write_str("summary.json", "{}").is_err());
+            env.finalize().unwrap();
+            assert_eq!(crate::archive::read_entries(&path), expected, "{name}");
+            assert!(env.warnings().is_empty());
+        }
+
+        // A writer closed after finalize is left out, and fails from then on
+        let path = root.path().join("late.tar.gz");
+        let mut env = Environment::from_json(serde_json::json!({
+            "output": { "spec": "archive", "path": path }
+        }));
+        env.write_str("early.json", "{}");
+        let mut writer = env.csv_writer("late.csv", &["step"]);
+        writer.write_row(&["0"]);
+        env.finalize().unwrap();
+        let warnings = env.warnings();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].code, "archive_entry_open");
+        assert!(writer.try_flush().is_err());
+        drop(writer);
+        let entries = crate::archive::read_entries(&path);
+        assert_eq!(entries.keys().collect::<Vec<_>>(), ["early.json"]);
+        let err = env.try_write_str("later.json", "{}").unwrap_err();
+        assert!(err.to_string().contains("finalize has closed the archive"));
+
+        let err = Environment::try_from_json(serde_json::json!({
+            "output": { "spec": "archive", "path": root.path().join("run.rar") }
+        }))
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains(".tar.gz, .tgz or .zip"));
+    }
+}
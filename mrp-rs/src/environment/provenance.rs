@@ -0,0 +1,118 @@
+use std::fs;
+
+use crate::MrpError;
+use crate::provenance::{Provenance, sha256_hex};
+
+use super::Environment;
+
+impl<I> Environment<I> {
+    /// Read an input file from `model.files` by key, recording its hash for
+    /// the provenance of outputs finalized afterwards.
+    pub fn read_file(&self, key: &str) -> Result<Vec<u8>, MrpError> {
+        let path = self
+            .files
+            .get(key)
+            .ok_or_else(|| MrpError::FileNotFound(format!("no file with key '{key}'")))?;
+        let data = fs::read(path)
+            .map_err(|e| MrpError::FileNotFound(format!("'{key}' ({}): {e}", path.display())))?;
+        self.provenance
+            .borrow_mut()
+            .record_read(key, &path.to_string_lossy(), &data);
+        Ok(data)
+    }
+
+    pub fn read_file_to_string(&self, key: &str) -> Result<String, MrpError> {
+        String::from_utf8(self.read_file(key)?)
+            .map_err(|e| MrpError::Input(format!("'{key}' is not valid UTF-8: {e}")))
+    }
+
+    /// The inputs, files read, and write span behind an output file.
+    ///
+    /// Only files read before the output was closed are listed.
+    pub fn provenance_for(&self, filename: &str) -> Option<Provenance> {
+        let input = self.resolved_input();
+        let input_sha256 =
+            sha256_hex(&serde_json::to_vec(&input).expect("failed to serialize input"));
+        self.provenance.borrow().provenance_for(
+            filename,
+            input_sha256,
+            input.get("seed").and_then(|v| v.as_u64()),
+            self.replicate,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_provenance_lists_files_read_before_finalize() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a.csv");
+        let b = dir.path().join("b.csv");
+        fs::write(&a, "x\n1\n").unwrap();
+        fs::write(&b, "y\n2\n").unwrap();
+        let mut env = Environment::from_json(serde_json::json!({
+            "input": { "seed": 7, "replicate": 2, "r0": 1.5 },
+            "output": { "spec": "filesystem", "dir": dir.path().join("out").to_str().unwrap() },
+            "model": { "files": { "a": a.to_str().unwrap(), "b": b.to_str().unwrap() } }
+        }));
+
+        env.read_file("a").unwrap();
+        env.create_csv("first", "first.csv", &["v"]);
+        env.write_csv_row("first", &["1"]);
+        env.close_csv("first");
+        env.create_csv("second", "second.csv", &["v"]);
+        env.read_file_to_string("b").unwrap();
+        env.write_csv_row("second", &["2"]);
+        env.close_csv("second");
+        env.read_file("a").unwrap();
+
+        let first = env.provenance_for("first.csv").unwrap();
+        let keys: Vec<&str> = first.files_read.iter().map(|f| f.key.as_str()).collect();
+        assert_eq!(keys, vec!["a"]);
+        assert_eq!(first.seed, Some(7));
+        assert_eq!(first.replicate, 2);
+        assert_eq!(first.files_read[0].sha256, sha256_hex(b"x\n1\n"));
+        assert!(first.finished_at.is_some());
+
+        let second = env.provenance_for("second.csv").unwrap();
+        let keys: Vec<&str> = second.files_read.iter().map(|f| f.key.as_str()).collect();
+        assert_eq!(keys, vec!["a", "b"]);
+        assert_eq!(first.input_sha256, second.input_sha256);
+
+        assert!(env.provenance_for("missing.csv").is_none());
+
+        // Standalone writers end their span when dropped
+        let mut writer = env.csv_writer("third.csv", &["v"]);
+        writer.write_row(&["3"]);
+        assert!(
+            env.provenance_for("third.csv")
+                .unwrap()
+                .finished_at
+                .is_none()
+        );
+        drop(writer);
+        assert!(
+            env.provenance_for("third.csv")
+                .unwrap()
+                .finished_at
+                .is_some()
+        );
+        let writer = env.jsonl_writer("events.jsonl");
+        assert!(
+            env.provenance_for("events.jsonl")
+                .unwrap()
+                .finished_at
+                .is_none()
+        );
+        drop(writer);
+        assert!(
+            env.provenance_for("events.jsonl")
+                .unwrap()
+                .finished_at
+                .is_some()
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::BTreeMap;
+
+use crate::MrpError;
+use crate::schema::{ColumnType, OutputContract, OutputSchema};
+
+use super::Environment;
+
+impl<I> Environment<I> {
+    /// Declare the columns a CSV output must have.
+    ///
+    /// Writers created for `filename` check headers on creation and cell types
+    /// on every row, and [`Environment::finalize`] fails if it was never written.
+    pub fn declare_output_schema(&mut self, filename: &str, columns: &[(&str, ColumnType)]) {
+        self.declare_output(filename, OutputSchema::new(columns));
+    }
+
+    pub fn declare_output(&mut self, filename: &str, schema: OutputSchema) {
+        self.output_schemas.insert(filename.to_string(), schema);
+    }
+
+    /// Declare every output in an [`OutputContract`] read from `model.files[key]`.
+    pub fn load_output_contract(&mut self, key: &str) -> Result<(), MrpError> {
+        let path = self
+            .files
+            .get(key)
+            .ok_or_else(|| MrpError::FileNotFound(format!("no file with key '{key}'")))?;
+        let contract = OutputContract::from_file(path)?;
+        self.strict_outputs |= contract.strict;
+        self.output_schemas.extend(contract.outputs);
+        Ok(())
+    }
+
+    /// Reject writes to outputs without a declared schema.
+    pub fn set_strict_outputs(&mut self, strict: bool) {
+        self.strict_outputs = strict;
+    }
+
+    pub fn output_schemas(&self) -> &BTreeMap<String, OutputSchema> {
+        &self.output_schemas
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::environment::tests::fs_env;
+
+    #[test]
+    fn test_output_schema_matches() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut env = fs_env(dir.path());
+        env.declare_output_schema(
+            "out.csv",
+            &[("step", ColumnType::Integer), ("value", ColumnType::Float)],
+        );
+        env.write_csv(
+            "out.csv",
+            &["step", "value"],
+            &[vec!["0".to_string(), "1.5".to_string()]],
+        );
+        assert!(env.finalize().is_ok());
+    }
+
+    #[test]
+    #[should_panic(expected = "do not match declared columns")]
+    fn test_output_schema_wrong_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut env = fs_env(dir.path());
+        env.declare_output_schema(
+            "out.csv",
+            &[("step", ColumnType::Integer), ("value", ColumnType::Float)],
+        );
+        env.create_csv("out", "out.csv", &["value", "step"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "column 'value' expected Float")]
+    fn test_output_schema_row_type() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut env = fs_env(dir.path());
+        env.declare_output_schema(
+            "out.csv",
+            &[("step", ColumnType::Integer), ("value", ColumnType::Float)],
+        );
+        env.create_csv("out", "out.csv", &["step", "value"]);
+        env.write_csv_row("out", &["0", "n/a"]);
+    }
+
+    #[test]
+    fn test_output_schema_missing_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut env = fs_env(dir.path());
+        env.declare_output_schema("out.csv", &[("step", ColumnType::Integer)]);
+        env.declare_output(
+            "extra.csv",
+            OutputSchema::new(&[("step", ColumnType::Integer)]).optional(),
+        );
+        let err = env.finalize().unwrap_err();
+        assert!(err.to_string().contains("out.csv"));
+        assert!(!err.to_string().contains("extra.csv"));
+    }
+
+    #[test]
+    #[should_panic(expected = "no declared schema")]
+    fn test_output_schema_strict_undeclared() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut env = fs_env(dir.path());
+        env.declare_output_schema("out.csv", &[("step", ColumnType::Integer)]);
+        env.set_strict_outputs(true);
+        env.write_str("notes.txt", "hello");
+    }
+
+    #[test]
+    fn test_load_output_contract() {
+        let dir = tempfile::tempdir().unwrap();
+        let contract = dir.path().join("contract.json");
+        fs::write(
+            &contract,
+            r#"{"strict": true, "outputs": {"out.csv": {"columns": [{"name": "step", "type": "integer"}]}}}"#,
+        )
+        .unwrap();
+        let mut env = Environment::from_json(serde_json::json!({
+            "output": { "spec": "filesystem", "dir": dir.path().join("out").to_str().unwrap() },
+            "model": { "files": { "contract": contract.to_str().unwrap() } }
+        }));
+        env.load_output_contract("contract").unwrap();
+        assert!(env.output_schemas().contains_key("out.csv"));
+        assert!(env.finalize().is_err());
+    }
+}
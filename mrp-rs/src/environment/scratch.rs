@@ -0,0 +1,242 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use crate::MrpError;
+use crate::compare::list_files;
+use crate::provenance::sha256_hex;
+
+use super::{Environment, output_spec};
+
+impl<I> Environment<I> {
+    /// Directory for intermediate files that should not land in the outputs.
+    ///
+    /// Uses the payload's `"scratch_dir"` if given, otherwise a temporary
+    /// directory created on first call and removed by [`Environment::finalize`]
+    /// (or drop) unless the payload sets `"keep_scratch": true`.
+    pub fn scratch_dir(&self) -> Result<&Path, MrpError> {
+        self.scratch.dir()
+    }
+
+    /// A unique path inside the scratch directory ending in `name`.
+    pub fn scratch_file(&self, name: &str) -> Result<PathBuf, MrpError> {
+        self.scratch.file(name)
+    }
+}
+
+impl<I: Clone> Environment<I> {
+    /// Run the model `f` against this environment, first checking it as
+    /// [`Environment::verify_reproducibility`] does if the payload sets
+    /// `"mrp": {"verify_reproducibility": true}`.
+    pub fn run_model<F>(&self, f: F) -> Result<(), MrpError>
+    where
+        F: Fn(&Environment<I>) -> Result<(), MrpError>,
+    {
+        let verify = self
+            .payload
+            .pointer("/mrp/verify_reproducibility")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        if verify {
+            self.verify_reproducibility(f)
+        } else {
+            f(self)
+        }
+    }
+
+    /// Run `f` twice against copies of this environment that write to
+    /// scratch directories, and fail naming each file whose SHA-256
+    /// differs between the two. If none do, run `f` against this
+    /// environment. A model that reads the clock, iterates a `HashMap` or
+    /// seeds a generator outside [`Environment::rng`] is caught here.
+    pub fn verify_reproducibility<F>(&self, f: F) -> Result<(), MrpError>
+    where
+        F: Fn(&Environment<I>) -> Result<(), MrpError>,
+    {
+        let first = self.trial_hashes(&f)?;
+        let second = self.trial_hashes(&f)?;
+        let names: BTreeSet<&String> = first.keys().chain(second.keys()).collect();
+        let differing: Vec<String> = names
+            .into_iter()
+            .filter(|name| first.get(*name) != second.get(*name))
+            .map(|name| {
+                let hash = |hashes: &BTreeMap<String, String>| {
+                    hashes
+                        .get(name)
+                        .cloned()
+                        .unwrap_or_else(|| "missing".to_string())
+                };
+                format!("{name} ({} vs {})", hash(&first), hash(&second))
+            })
+            .collect();
+        if !differing.is_empty() {
+            return Err(MrpError::Runtime(format!(
+                "two runs of the same payload wrote different outputs: {}",
+                differing.join(", ")
+            )));
+        }
+        f(self)
+    }
+
+    /// Run `f` against a copy of this environment writing to a fresh
+    /// scratch directory, and hash each file it wrote, by relative path.
+    fn trial_hashes<F>(&self, f: &F) -> Result<BTreeMap<String, String>, MrpError>
+    where
+        F: Fn(&Environment<I>) -> Result<(), MrpError>,
+    {
+        let trial_dir = self.scratch.trial_dir("reproducibility")?;
+        let dir = trial_dir.path();
+        let mut output = output_spec(&self.output, self.output_profile.as_deref())
+            .filter(|spec| spec.is_object())
+            .cloned()
+            .unwrap_or_else(|| Value::Object(Default::default()));
+        output["spec"] = "filesystem".into();
+        output["dir"] = dir.to_string_lossy().into_owned().into();
+        // The resolved input, so a generated seed is not drawn again
+        let mut payload = self.payload.clone();
+        payload["input"] = self.resolved_input();
+        payload["output"] = output;
+        if let Some(mrp) = payload.get_mut("mrp").and_then(|v| v.as_object_mut()) {
+            mrp.remove("verify_reproducibility");
+        }
+        let mut trial = Environment::try_build(payload)?.with_input(self.input.clone());
+        trial.csv_options = self.csv_options.clone();
+        trial.output_schemas = self.output_schemas.clone();
+        trial.strict_outputs = self.strict_outputs;
+        f(&trial)?;
+        trial.try_close_all_csv()?;
+        let mut hashes = BTreeMap::new();
+        for name in list_files(dir)? {
+            let data = fs::read(dir.join(&name))
+                .map_err(|e| MrpError::Output(format!("failed to read '{name}': {e}")))?;
+            hashes.insert(name, sha256_hex(&data));
+        }
+        Ok(hashes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::environment::tests::fs_env;
+
+    #[test]
+    fn test_scratch_dir_removed_on_finalize() {
+        let out = tempfile::tempdir().unwrap();
+        let mut env = fs_env(out.path());
+        let scratch = env.scratch_dir().unwrap().to_path_buf();
+        assert!(scratch.is_dir());
+        assert!(!scratch.starts_with(out.path()));
+        let a = env.scratch_file("chunk.csv").unwrap();
+        let b = env.scratch_file("chunk.csv").unwrap();
+        assert_ne!(a, b);
+        fs::write(&a, "x").unwrap();
+        env.finalize().unwrap();
+        assert!(!scratch.exists());
+        assert!(env.produced.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_scratch_dir_keep() {
+        let mut env = Environment::from_json(serde_json::json!({"keep_scratch": true}));
+        let scratch = env.scratch_dir().unwrap().to_path_buf();
+        env.finalize().unwrap();
+        drop(env);
+        assert!(scratch.is_dir());
+        fs::remove_dir_all(scratch).unwrap();
+    }
+
+    #[test]
+    fn test_scratch_dir_from_payload() {
+        let dir = tempfile::tempdir().unwrap();
+        let configured = dir.path().join("scratch");
+        let mut env = Environment::from_json(serde_json::json!({
+            "scratch_dir": configured.to_str().unwrap()
+        }));
+        assert_eq!(env.scratch_dir().unwrap(), configured);
+        env.finalize().unwrap();
+        assert!(configured.is_dir());
+    }
+
+    #[test]
+    fn test_scratch_files_unique_across_runs_sharing_a_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let payload = serde_json::json!({ "scratch_dir": dir.path().to_str().unwrap() });
+        let a = Environment::from_json(payload.clone());
+        let b = Environment::from_json(payload);
+        let (a, b) = (
+            a.scratch_file("chunk.csv").unwrap(),
+            b.scratch_file("chunk.csv").unwrap(),
+        );
+        assert_ne!(a, b);
+        let name = a.file_name().unwrap().to_str().unwrap();
+        assert!(
+            name.starts_with(&format!("{}_", std::process::id())),
+            "{name}"
+        );
+        assert!(name.ends_with("_0_chunk.csv"), "{name}");
+    }
+
+    #[test]
+    fn test_verify_reproducibility() {
+        let dir = tempfile::tempdir().unwrap();
+        let payload = |verify: bool| {
+            serde_json::json!({
+                "input": { "seed": 3 },
+                "output": { "spec": "filesystem", "dir": dir.path().to_str().unwrap() },
+                "mrp": { "verify_reproducibility": verify },
+            })
+        };
+        let runs = Cell::new(0);
+        let model = |env: &Environment| {
+            runs.set(runs.get() + 1);
+            env.try_write_str("draws.txt", &env.effective_seed().to_string())
+        };
+        Environment::from_json(payload(true))
+            .run_model(model)
+            .unwrap();
+        assert_eq!(runs.get(), 3);
+        let files: Vec<_> = fs::read_dir(dir.path()).unwrap().collect();
+        assert_eq!(files.len(), 1);
+
+        runs.set(0);
+        Environment::from_json(payload(false))
+            .run_model(model)
+            .unwrap();
+        assert_eq!(runs.get(), 1);
+
+        fs::remove_file(dir.path().join("draws.txt")).unwrap();
+        let clock = |env: &Environment| {
+            runs.set(runs.get() + 1);
+            env.try_write_str("draws.txt", &runs.get().to_string())
+        };
+        let err = Environment::from_json(payload(false))
+            .verify_reproducibility(clock)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains(&format!(
+                "draws.txt ({} vs {})",
+                sha256_hex(b"2"),
+                sha256_hex(b"3")
+            )),
+            "{err}"
+        );
+        assert!(!dir.path().join("draws.txt").exists());
+
+        // A failed trial leaves nothing in the scratch directory
+        let scratch = tempfile::tempdir().unwrap();
+        let failing = |_: &Environment| Err(MrpError::Runtime("model failed".to_string()));
+        let mut payload = payload(false);
+        payload["scratch_dir"] = scratch.path().to_str().unwrap().into();
+        let err = Environment::from_json(payload)
+            .verify_reproducibility(failing)
+            .unwrap_err();
+        assert_eq!(err.kind(), "runtime");
+        assert_eq!(fs::read_dir(scratch.path()).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,384 @@
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::MrpError;
+use crate::environment::Warning;
+
+/// What to do when writing to the output directory fails with a full or
+/// failing disk, set by the output spec's `"on_write_failure"` key.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum WriteFailurePolicy {
+    /// Propagate the error (the default).
+    #[default]
+    Fail,
+    /// Send each affected file to stdout.
+    FallbackStdout,
+    /// Send each affected file to the same name under another directory,
+    /// given by the spec's `"fallback_dir"`.
+    FallbackDir(PathBuf),
+}
+
+impl WriteFailurePolicy {
+    pub(crate) fn from_spec(spec: &Value) -> Result<Self, MrpError> {
+        match spec.get("on_write_failure").and_then(|v| v.as_str()) {
+            None | Some("fail") => Ok(WriteFailurePolicy::Fail),
+            Some("fallback_stdout") => Ok(WriteFailurePolicy::FallbackStdout),
+            Some("fallback_dir") => spec
+                .get("fallback_dir")
+                .and_then(|v| v.as_str())
+                .map(|d| WriteFailurePolicy::FallbackDir(PathBuf::from(d)))
+                .ok_or_else(|| {
+                    MrpError::Config(
+                        "on_write_failure = \"fallback_dir\" requires \"fallback_dir\"".to_string(),
+                    )
+                }),
+            Some(other) => Err(MrpError::Config(format!(
+                "unknown on_write_failure policy: {other:?} (expected fail, fallback_stdout or fallback_dir)"
+            ))),
+        }
+    }
+}
+
+/// An output file moved to the fallback partway through being written.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SplitOutput {
+    pub filename: String,
+    pub primary: String,
+    /// Bytes successfully written to `primary` before the switch. They are
+    /// copied to the fallback where they can be read back, and the partial
+    /// primary file is removed.
+    pub primary_bytes: u64,
+    /// `"stdout"` or the fallback file path.
+    pub fallback: String,
+    pub error: String,
+}
+
+/// Shared record of fallbacks taken by any writer in an Environment.
+#[derive(Clone, Default)]
+pub(crate) struct FallbackLog {
+    pub(crate) splits: Rc<RefCell<Vec<SplitOutput>>>,
+    pub(crate) warnings: Rc<RefCell<Vec<Warning>>>,
+}
+
+/// Whether a write error means the destination is out of space or failing.
+pub(crate) fn is_degradable(e: &io::Error) -> bool {
+    if e.kind() == io::ErrorKind::StorageFull {
+        return true;
+    }
+    #[cfg(unix)]
+    if matches!(e.raw_os_error(), Some(libc::EIO | libc::ENOSPC)) {
+        return true;
+    }
+    false
+}
+
+const RETRIES: u32 = 2;
+/// The wait before the first retry, doubling for each one after.
+const RETRY_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Run `op`, retrying degradable errors after a backoff.
+fn with_retries<T>(mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Err(e) if is_degradable(&e) && attempt < RETRIES => {
+                thread::sleep(RETRY_BACKOFF * 2u32.pow(attempt));
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Writes to a primary destination, switching to the policy's fallback the
+/// first time the primary fails with a degradable error (after retries).
+pub(crate) struct FallbackWriter {
+    filename: String,
+    primary_path: String,
+    primary: Option<Box<dyn Write>>,
+    fallback: Option<Box<dyn Write>>,
+    policy: WriteFailurePolicy,
+    written: u64,
+    log: FallbackLog,
+}
+
+impl FallbackWriter {
+    pub(crate) fn new(
+        filename: &str,
+        primary_path: &Path,
+        primary: Box<dyn Write>,
+        policy: WriteFailurePolicy,
+        log: FallbackLog,
+    ) -> Self {
+        FallbackWriter {
+            filename: filename.to_string(),
+            primary_path: primary_path.to_string_lossy().to_string(),
+            primary: Some(primary),
+            fallback: None,
+            policy,
+            written: 0,
+            log,
+        }
+    }
+
+    /// A writer that starts out on the fallback because the primary could not
+    /// even be opened.
+    pub(crate) fn switched(
+        filename: &str,
+        primary_path: &Path,
+        policy: WriteFailurePolicy,
+        log: FallbackLog,
+        error: &io::Error,
+    ) -> io::Result<Self> {
+        let mut w = FallbackWriter {
+            filename: filename.to_string(),
+            primary_path: primary_path.to_string_lossy().to_string(),
+            primary: None,
+            fallback: None,
+            policy,
+            written: 0,
+            log,
+        };
+        w.switch(error)?;
+        Ok(w)
+    }
+
+    fn switch(&mut self, error: &io::Error) -> io::Result<()> {
+        // Close the primary so a partial atomic write is in place to read back
+        self.primary = None;
+        let head = read_head(Path::new(&self.primary_path), self.written);
+        let _ = fs::remove_file(&self.primary_path);
+        let split = SplitOutput {
+            filename: self.filename.clone(),
+            primary: self.primary_path.clone(),
+            primary_bytes: self.written,
+            fallback: String::new(),
+            error: error.to_string(),
+        };
+        let (mut dest, split) = open_fallback(&self.policy, split)?;
+        let lost = match &head {
+            Some(head) => {
+                dest.write_all(head)?;
+                String::new()
+            }
+            None if self.written > 0 => {
+                format!("; the first {} bytes could not be recovered", self.written)
+            }
+            None => String::new(),
+        };
+        self.log.warnings.borrow_mut().push(Warning::output_failure(
+            "write_fallback",
+            format!(
+                "writing {} failed after {} bytes ({}); continuing in {}{lost}",
+                split.filename, split.primary_bytes, split.error, split.fallback
+            ),
+        ));
+        eprintln!(
+            "warning [write_fallback]: {} continues in {} after {} bytes: {}{lost}",
+            split.filename, split.fallback, split.primary_bytes, split.error
+        );
+        self.log.splits.borrow_mut().push(split);
+        self.fallback = Some(dest);
+        Ok(())
+    }
+}
+
+/// The first `len` bytes of the partial primary at `path`, if they can all
+/// be read back.
+fn read_head(path: &Path, len: u64) -> Option<Vec<u8>> {
+    if len == 0 {
+        return Some(Vec::new());
+    }
+    let mut head = Vec::new();
+    fs::File::open(path)
+        .ok()?
+        .take(len)
+        .read_to_end(&mut head)
+        .ok()?;
+    (head.len() as u64 == len).then_some(head)
+}
+
+/// Open the fallback destination and write the marker explaining the switch.
+fn open_fallback(
+    policy: &WriteFailurePolicy,
+    mut split: SplitOutput,
+) -> io::Result<(Box<dyn Write>, SplitOutput)> {
+    match policy {
+        WriteFailurePolicy::Fail => Err(io::Error::other("no fallback configured")),
+        WriteFailurePolicy::FallbackStdout => {
+            split.fallback = "stdout".to_string();
+            // stdout carries the data itself, so the marker goes to stderr
+            eprintln!(
+                "{}",
+                serde_json::to_string(&serde_json::json!({"mrp_fallback": &split}))
+                    .unwrap_or_default()
+            );
+            Ok((Box::new(io::stdout()), split))
+        }
+        WriteFailurePolicy::FallbackDir(dir) => {
+            let path = dir.join(&split.filename);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            split.fallback = path.to_string_lossy().to_string();
+            let marker = dir.join(format!("{}.fallback.json", split.filename));
+            fs::write(
+                marker,
+                serde_json::to_vec_pretty(&split).map_err(io::Error::other)?,
+            )?;
+            Ok((Box::new(fs::File::create(path)?), split))
+        }
+    }
+}
+
+/// Write a whole file to the fallback after the primary write failed.
+pub(crate) fn write_to_fallback(
+    filename: &str,
+    primary_path: &Path,
+    data: &[u8],
+    policy: &WriteFailurePolicy,
+    log: &FallbackLog,
+    error: &io::Error,
+) -> io::Result<()> {
+    let mut w =
+        FallbackWriter::switched(filename, primary_path, policy.clone(), log.clone(), error)?;
+    w.write_all(data)?;
+    w.flush()
+}
+
+impl Write for FallbackWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(primary) = self.primary.as_mut() {
+            match with_retries(|| primary.write(buf)) {
+                Ok(n) => {
+                    self.written += n as u64;
+                    return Ok(n);
+                }
+                Err(e) if is_degradable(&e) && self.policy != WriteFailurePolicy::Fail => {
+                    self.switch(&e)?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        self.fallback.as_mut().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(primary) = self.primary.as_mut() {
+            match with_retries(|| primary.flush()) {
+                Ok(()) => return Ok(()),
+                Err(e) if is_degradable(&e) && self.policy != WriteFailurePolicy::Fail => {
+                    self.switch(&e)?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        self.fallback.as_mut().unwrap().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Accepts `capacity` bytes, then fails every write with ENOSPC.
+    struct FillingWriter {
+        file: Option<fs::File>,
+        written: usize,
+        capacity: usize,
+    }
+
+    impl Write for FillingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let room = self.capacity - self.written;
+            if room == 0 {
+                return Err(io::Error::from(io::ErrorKind::StorageFull));
+            }
+            let n = room.min(buf.len());
+            if let Some(file) = &mut self.file {
+                file.write_all(&buf[..n])?;
+            }
+            self.written += n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_tail_lands_in_fallback_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let fallback = dir.path().join("fallback");
+        let primary = dir.path().join("out.csv");
+        let log = FallbackLog::default();
+        let mut w = FallbackWriter::new(
+            "out.csv",
+            &primary,
+            Box::new(FillingWriter {
+                file: Some(fs::File::create(&primary).unwrap()),
+                written: 0,
+                capacity: 10,
+            }),
+            WriteFailurePolicy::FallbackDir(fallback.clone()),
+            log.clone(),
+        );
+        let payload: Vec<u8> = (0..40u8).map(|i| b'a' + i % 26).collect();
+        w.write_all(&payload).unwrap();
+        w.flush().unwrap();
+
+        assert_eq!(fs::read(fallback.join("out.csv")).unwrap(), payload);
+        assert!(!primary.exists());
+        let splits = log.splits.borrow();
+        assert_eq!(splits.len(), 1);
+        assert_eq!(splits[0].primary_bytes, 10);
+        assert_eq!(log.warnings.borrow()[0].code, "write_fallback");
+        let marker = fs::read_to_string(fallback.join("out.csv.fallback.json")).unwrap();
+        assert!(marker.contains("\"primary_bytes\": 10"));
+    }
+
+    #[test]
+    fn test_fail_policy_propagates() {
+        let log = FallbackLog::default();
+        let mut w = FallbackWriter::new(
+            "out.csv",
+            Path::new("/full/out.csv"),
+            Box::new(FillingWriter {
+                file: None,
+                written: 0,
+                capacity: 4,
+            }),
+            WriteFailurePolicy::Fail,
+            log.clone(),
+        );
+        let err = w.write_all(b"more than four").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        assert!(log.splits.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_policy_from_spec() {
+        let p = |v| WriteFailurePolicy::from_spec(&v);
+        assert_eq!(p(serde_json::json!({})).unwrap(), WriteFailurePolicy::Fail);
+        assert_eq!(
+            p(serde_json::json!({"on_write_failure": "fallback_stdout"})).unwrap(),
+            WriteFailurePolicy::FallbackStdout
+        );
+        assert_eq!(
+            p(serde_json::json!({"on_write_failure": "fallback_dir", "fallback_dir": "/alt"}))
+                .unwrap(),
+            WriteFailurePolicy::FallbackDir(PathBuf::from("/alt"))
+        );
+        assert!(p(serde_json::json!({"on_write_failure": "fallback_dir"})).is_err());
+        assert!(p(serde_json::json!({"on_write_failure": "retry"})).is_err());
+    }
+}
@@ -0,0 +1,177 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use crate::MrpError;
+
+/// The key marking a value to read from a file.
+const FILE_REF: &str = "$file";
+
+/// How many files deep a `$file` may lead, so a file that refers back to
+/// itself fails rather than looping.
+const MAX_FILE_REF_DEPTH: usize = 8;
+
+/// Replace each `{"$file": "key_or_path"}` in the payload's input with the
+/// JSON in that file, found as a `model.files` key or else as a path.
+/// Files may hold further references. Returns whether any were found.
+pub(crate) fn resolve(data: &mut Value) -> Result<bool, MrpError> {
+    let files: Vec<(String, PathBuf)> = data
+        .pointer("/model/files")
+        .and_then(|f| f.as_object())
+        .into_iter()
+        .flatten()
+        .filter_map(|(key, path)| Some((key.clone(), PathBuf::from(path.as_str()?))))
+        .collect();
+    let Some(input) = data.get_mut("input") else {
+        return Ok(false);
+    };
+    let mut found = false;
+    walk(input, "input", &files, 0, &mut found)?;
+    Ok(found)
+}
+
+fn walk(
+    value: &mut Value,
+    at: &str,
+    files: &[(String, PathBuf)],
+    depth: usize,
+    found: &mut bool,
+) -> Result<(), MrpError> {
+    if let Some(target) = file_ref(value) {
+        if depth == MAX_FILE_REF_DEPTH {
+            return Err(MrpError::Input(format!(
+                "{at}: $file references nest deeper than {MAX_FILE_REF_DEPTH} files \
+                 (is '{target}' referring to itself?)"
+            )));
+        }
+        let path = files
+            .iter()
+            .find(|(key, _)| key == target)
+            .map(|(_, path)| path.clone())
+            .unwrap_or_else(|| PathBuf::from(target));
+        let mut loaded = read(&path).map_err(|e| match e {
+            Error::Io(e) if e.kind() == io::ErrorKind::NotFound => MrpError::FileNotFound(format!(
+                "{at}: $file '{target}' ({}) not found",
+                path.display()
+            )),
+            Error::Io(e) => MrpError::Input(format!(
+                "{at}: $file '{target}' ({}) could not be read: {e}",
+                path.display()
+            )),
+            Error::Json(e) => MrpError::Input(format!(
+                "{at}: $file '{target}' ({}) is not valid JSON: {e}",
+                path.display()
+            )),
+        })?;
+        walk(&mut loaded, at, files, depth + 1, found)?;
+        *value = loaded;
+        *found = true;
+        return Ok(());
+    }
+    match value {
+        Value::Object(map) => {
+            for (key, item) in map.iter_mut() {
+                walk(item, &format!("{at}.{key}"), files, depth, found)?;
+            }
+        }
+        Value::Array(items) => {
+            for (i, item) in items.iter_mut().enumerate() {
+                walk(item, &format!("{at}[{i}]"), files, depth, found)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// The target of a `{"$file": "..."}` object, which must have no other keys.
+fn file_ref(value: &Value) -> Option<&str> {
+    let map = value.as_object()?;
+    if map.len() != 1 {
+        return None;
+    }
+    map.get(FILE_REF)?.as_str()
+}
+
+enum Error {
+    Io(io::Error),
+    Json(serde_json::Error),
+}
+
+fn read(path: &Path) -> Result<Value, Error> {
+    let text = fs::read_to_string(path).map_err(Error::Io)?;
+    serde_json::from_str(&text).map_err(Error::Json)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_refs_resolved_by_key_then_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let pmf = dir.path().join("gi.json");
+        fs::write(&pmf, "[0.25, 0.5, 0.25]").unwrap();
+        let rt = dir.path().join("rt.json");
+        let nested = dir.path().join("nested.json");
+        fs::write(&rt, "[1.2, 1.1]").unwrap();
+        fs::write(
+            &nested,
+            json!({ "rt": { "$file": rt.to_str().unwrap() } }).to_string(),
+        )
+        .unwrap();
+        let mut data = json!({
+            "input": {
+                "generation_interval_pmf": { "$file": "gi" },
+                "series": [{ "$file": nested.to_str().unwrap() }],
+                "literal": { "$file": "gi", "note": "not a reference" },
+            },
+            "model": { "files": { "gi": pmf.to_str().unwrap() } },
+        });
+        assert!(resolve(&mut data).unwrap());
+        assert_eq!(
+            data["input"]["generation_interval_pmf"],
+            json!([0.25, 0.5, 0.25])
+        );
+        assert_eq!(data["input"]["series"][0], json!({ "rt": [1.2, 1.1] }));
+        assert_eq!(data["input"]["literal"]["$file"], "gi");
+
+        let mut plain = json!({ "input": { "r0": 2.0 } });
+        assert!(!resolve(&mut plain).unwrap());
+    }
+
+    #[test]
+    fn test_errors_name_key_and_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing.json");
+        let mut data = json!({
+            "input": { "observation": { "delay": { "$file": "delay" } } },
+            "model": { "files": { "delay": missing.to_str().unwrap() } },
+        });
+        let e = resolve(&mut data).unwrap_err();
+        assert_eq!(e.kind(), "file_not_found");
+        let message = e.to_string();
+        assert!(message.contains("input.observation.delay"), "{message}");
+        assert!(message.contains("missing.json"), "{message}");
+
+        let bad = dir.path().join("bad.json");
+        fs::write(&bad, "[0.5,").unwrap();
+        let mut data = json!({ "input": { "pmf": { "$file": bad.to_str().unwrap() } } });
+        let message = resolve(&mut data).unwrap_err().to_string();
+        assert!(message.contains("is not valid JSON"), "{message}");
+
+        let looped = dir.path().join("loop.json");
+        fs::write(
+            &looped,
+            json!({ "$file": looped.to_str().unwrap() }).to_string(),
+        )
+        .unwrap();
+        let mut data = json!({ "input": { "x": { "$file": looped.to_str().unwrap() } } });
+        let message = resolve(&mut data).unwrap_err().to_string();
+        assert!(message.contains("nest deeper than"), "{message}");
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::PathBuf;
+
+use crate::MrpError;
+
+/// Entries of `model.files` that a model cannot read.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilesError {
+    /// Every problem found, ordered by key.
+    pub problems: Vec<FileProblem>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileProblem {
+    pub key: String,
+    /// The path given for `key`, or `None` if a required key is missing.
+    pub path: Option<PathBuf>,
+    pub reason: String,
+}
+
+impl std::fmt::Display for FilesError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} unusable model.files entries:", self.problems.len())?;
+        for problem in &self.problems {
+            match &problem.path {
+                Some(path) => write!(
+                    f,
+                    "\n  '{}' ({}): {}",
+                    problem.key,
+                    path.display(),
+                    problem.reason
+                )?,
+                None => write!(f, "\n  '{}': {}", problem.key, problem.reason)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for FilesError {}
+
+impl From<FilesError> for MrpError {
+    fn from(e: FilesError) -> Self {
+        MrpError::FileNotFound(e.to_string())
+    }
+}
+
+/// Check that every file exists and opens for reading, and that every
+/// `required` key is present.
+pub(crate) fn check(files: &HashMap<String, PathBuf>, required: &[&str]) -> Result<(), FilesError> {
+    let mut problems: Vec<FileProblem> = required
+        .iter()
+        .filter(|key| !files.contains_key(**key))
+        .map(|key| FileProblem {
+            key: key.to_string(),
+            path: None,
+            reason: "required but not in model.files".to_string(),
+        })
+        .collect();
+    for (key, path) in files {
+        let reason = match File::open(path).and_then(|f| f.metadata()) {
+            Ok(meta) if meta.is_dir() => "is a directory".to_string(),
+            Ok(_) => continue,
+            Err(e) => e.to_string(),
+        };
+        problems.push(FileProblem {
+            key: key.clone(),
+            path: Some(path.clone()),
+            reason,
+        });
+    }
+    if problems.is_empty() {
+        return Ok(());
+    }
+    problems.sort_by(|a, b| a.key.cmp(&b.key));
+    Err(FilesError { problems })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_all_problems_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let present = dir.path().join("cases.csv");
+        std::fs::write(&present, "step,cases\n").unwrap();
+        let files = HashMap::from([
+            ("observed_cases".to_string(), present),
+            ("delays".to_string(), dir.path().join("missing.csv")),
+            ("pmf".to_string(), dir.path().to_path_buf()),
+        ]);
+        let err = check(&files, &["observed_cases", "population"]).unwrap_err();
+        let keys: Vec<&str> = err.problems.iter().map(|p| p.key.as_str()).collect();
+        assert_eq!(keys, vec!["delays", "pmf", "population"]);
+        assert_eq!(err.problems[1].reason, "is a directory");
+        assert_eq!(err.problems[2].path, None);
+        let message = err.to_string();
+        assert!(
+            message.starts_with("3 unusable model.files entries:"),
+            "{message}"
+        );
+        assert!(message.contains("missing.csv"), "{message}");
+        assert!(message.contains("'population': required"), "{message}");
+    }
+
+    #[test]
+    fn test_readable_files_pass() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cases.csv");
+        std::fs::write(&path, "step,cases\n").unwrap();
+        let files = HashMap::from([("observed_cases".to_string(), path)]);
+        assert_eq!(check(&files, &["observed_cases"]), Ok(()));
+        assert_eq!(check(&HashMap::new(), &[]), Ok(()));
+    }
+}
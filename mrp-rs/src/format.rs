@@ -0,0 +1,193 @@
+//! Locale-independent text formatting for values written to outputs.
+//!
+//! Rust's formatting machinery never consults the system locale, but its
+//! `Display` output for floats spells out every digit of very large or very
+//! small values. Models format CSV cells and other text they write with
+//! these functions (or [`Cell`]) so output bytes are pinned regardless of
+//! platform or locale. mrp's own JSON outputs are serialized by
+//! `serde_json`, and the CSV writers write cells as they are given.
+//!
+//! The formats are:
+//!
+//! - floats use the shortest representation that round-trips, with `.` as
+//!   the decimal separator and no grouping; magnitudes outside
+//!   `[1e-5, 1e16)` switch to exponent form (`1.5e-7`, `1e300`);
+//! - negative zero is written as `-0`, and non-finite values as `NaN`,
+//!   `inf` and `-inf`;
+//! - integers are plain decimal digits with a leading `-` when negative;
+//! - timestamps are RFC 3339 in UTC with millisecond precision.
+
+use std::time::SystemTime;
+
+use crate::calendar::{Date, rfc3339_utc};
+
+pub fn float(x: f64) -> String {
+    if x.is_nan() {
+        return "NaN".to_string();
+    }
+    if x.is_infinite() {
+        return if x > 0.0 { "inf" } else { "-inf" }.to_string();
+    }
+    let abs = x.abs();
+    if abs != 0.0 && !(1e-5..1e16).contains(&abs) {
+        format!("{x:e}")
+    } else {
+        format!("{x}")
+    }
+}
+
+pub fn timestamp(t: SystemTime) -> String {
+    rfc3339_utc(t)
+}
+
+/// A value that can be written as a CSV cell.
+pub trait Cell {
+    fn to_cell(&self) -> String;
+}
+
+impl Cell for f64 {
+    fn to_cell(&self) -> String {
+        float(*self)
+    }
+}
+
+impl Cell for f32 {
+    fn to_cell(&self) -> String {
+        // Format via the shortest f32 representation, not the widened f64
+        if self.is_finite() && *self != 0.0 && !(1e-5..1e16).contains(&self.abs()) {
+            format!("{self:e}")
+        } else {
+            float(self.to_string().parse().unwrap_or(f64::NAN))
+        }
+    }
+}
+
+macro_rules! int_cell {
+    ($($t:ty),*) => {
+        $(impl Cell for $t {
+            fn to_cell(&self) -> String {
+                self.to_string()
+            }
+        })*
+    };
+}
+
+int_cell!(
+    u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize
+);
+
+impl Cell for bool {
+    fn to_cell(&self) -> String {
+        if *self { "true" } else { "false" }.to_string()
+    }
+}
+
+impl Cell for str {
+    fn to_cell(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl Cell for String {
+    fn to_cell(&self) -> String {
+        self.clone()
+    }
+}
+
+impl Cell for Date {
+    fn to_cell(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl<T: Cell + ?Sized> Cell for &T {
+    fn to_cell(&self) -> String {
+        (**self).to_cell()
+    }
+}
+
+/// Format a row of heterogeneous values as CSV cells.
+pub fn row(values: &[&dyn Cell]) -> Vec<String> {
+    values.iter().map(|v| v.to_cell()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+
+    #[test]
+    fn test_float_bytes() {
+        let cases: &[(f64, &str)] = &[
+            (0.0, "0"),
+            (-0.0, "-0"),
+            (1.0, "1"),
+            (0.1, "0.1"),
+            (-2.5, "-2.5"),
+            (1.0 / 3.0, "0.3333333333333333"),
+            (0.00001, "0.00001"),
+            (0.0000015, "1.5e-6"),
+            (123456.789, "123456.789"),
+            (9999999999999998.0, "9999999999999998"),
+            (1e16, "1e16"),
+            (123456789012345680.0, "1.2345678901234568e17"),
+            (1e300, "1e300"),
+            (-1e-300, "-1e-300"),
+            (f64::MIN_POSITIVE, "2.2250738585072014e-308"),
+            (f64::NAN, "NaN"),
+            (f64::INFINITY, "inf"),
+            (f64::NEG_INFINITY, "-inf"),
+        ];
+        for (x, expected) in cases {
+            assert_eq!(float(*x), *expected, "formatting {x:?}");
+            if x.is_finite() {
+                assert_eq!(float(*x).parse::<f64>().unwrap().to_bits(), x.to_bits());
+            }
+        }
+    }
+
+    #[test]
+    fn test_f32_bytes() {
+        assert_eq!(0.1f32.to_cell(), "0.1");
+        assert_eq!(1e20f32.to_cell(), "1e20");
+        assert_eq!((-0.0f32).to_cell(), "-0");
+    }
+
+    #[test]
+    fn test_integer_bytes() {
+        assert_eq!(u64::MAX.to_cell(), "18446744073709551615");
+        assert_eq!(i64::MIN.to_cell(), "-9223372036854775808");
+        assert_eq!(1_000_000u32.to_cell(), "1000000");
+        assert_eq!(
+            u128::MAX.to_cell(),
+            "340282366920938463463374607431768211455"
+        );
+    }
+
+    #[test]
+    fn test_timestamp_bytes() {
+        let t = UNIX_EPOCH + Duration::from_millis(1_700_000_000_042);
+        assert_eq!(timestamp(t), "2023-11-14T22:13:20.042Z");
+    }
+
+    #[test]
+    fn test_row() {
+        let date = Date::parse("2024-03-01").unwrap();
+        assert_eq!(
+            row(&[&7u64, &0.5f64, &true, &"a,b", &date]),
+            vec!["7", "0.5", "true", "a,b", "2024-03-01"]
+        );
+    }
+
+    #[test]
+    fn test_json_bytes() {
+        // serde_json is locale-free as well; pin what it emits for artifacts
+        let v = serde_json::json!({"a": 0.1, "b": 1e300, "c": -0.0, "d": u64::MAX, "e": 1.0});
+        assert_eq!(
+            serde_json::to_string(&v).unwrap(),
+            r#"{"a":0.1,"b":1e+300,"c":-0.0,"d":18446744073709551615,"e":1.0}"#
+        );
+        assert_eq!(serde_json::to_string(&f64::NAN).unwrap(), "null");
+    }
+}
@@ -0,0 +1,329 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::MrpError;
+use crate::provenance::Provenance;
+use crate::schema::OutputSchema;
+
+/// Version of `manifest.json`, `complete.json`, `metrics.json`,
+/// `run_info.json`, `error.json` and `panic.json` written by this build.
+///
+/// Adding an optional field keeps the version; removing, renaming or
+/// retyping one bumps it and adds a fixture under `src/formats/`.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// `manifest.json`: every file a run finalized, with its size and hash.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub format_version: u32,
+    pub files: Vec<ManifestFile>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replicate: Option<u64>,
+    /// Wall-clock seconds from building the environment to the manifest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_seconds: Option<f64>,
+    /// The declared schema of each output, by file name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schemas: Option<BTreeMap<String, OutputSchema>>,
+    /// Set when the run was a negative control.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub negative_control: Option<bool>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestFile {
+    pub name: String,
+    pub bytes: u64,
+    pub sha256: String,
+    /// Records after the header row, for a CSV file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rows: Option<u64>,
+    /// The `csv_filters` entry the file was written under.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<Value>,
+    /// The inputs, files read and write span behind the file, for files
+    /// written through the environment before the manifest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<Provenance>,
+}
+
+/// `complete.json`, written last by finalize.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Complete {
+    pub format_version: u32,
+    pub status: RunStatus,
+    pub manifest_sha256: String,
+    /// Why a cancelled run stopped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Set when the outputs were copied from the run cache.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_hit: Option<bool>,
+    /// Set when the run was a negative control.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub negative_control: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunStatus {
+    Complete,
+    Cancelled,
+}
+
+/// `metrics.json` and `metrics.partial.json`. `format_version` is
+/// reserved, so no metric can take its name.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Metrics {
+    pub format_version: u32,
+    #[serde(flatten)]
+    pub values: BTreeMap<String, f64>,
+}
+
+/// `error.json`: why a model gave up on a run.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ErrorRecord {
+    pub format_version: u32,
+    /// The model's own code for the failure, e.g. `parameters_out_of_bounds`.
+    pub code: String,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    pub replicate: u64,
+    /// When the error was recorded, as RFC 3339 UTC.
+    pub timestamp: String,
+}
+
+/// `panic.json`: a panic on a thread watched by
+/// [`install_panic_hook`](crate::install_panic_hook).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PanicRecord {
+    pub format_version: u32,
+    pub message: String,
+    /// `file:line:column` of the panic.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    pub replicate: u64,
+}
+
+/// `run_info.json`: a model's own record of the run, `X`, under the
+/// shared version field.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RunInfo<X = Value> {
+    pub format_version: u32,
+    #[serde(flatten)]
+    pub info: X,
+}
+
+impl Manifest {
+    pub fn new(files: Vec<ManifestFile>) -> Self {
+        Manifest {
+            format_version: FORMAT_VERSION,
+            files,
+            seed: None,
+            replicate: None,
+            duration_seconds: None,
+            schemas: None,
+            negative_control: None,
+        }
+    }
+}
+
+impl Metrics {
+    pub fn new(values: BTreeMap<String, f64>) -> Self {
+        Metrics {
+            format_version: FORMAT_VERSION,
+            values,
+        }
+    }
+}
+
+impl ErrorRecord {
+    pub fn new(
+        code: &str,
+        message: &str,
+        seed: Option<u64>,
+        replicate: u64,
+        timestamp: String,
+    ) -> Self {
+        ErrorRecord {
+            format_version: FORMAT_VERSION,
+            code: code.to_string(),
+            message: message.to_string(),
+            seed,
+            replicate,
+            timestamp,
+        }
+    }
+}
+
+impl PanicRecord {
+    pub fn new(
+        message: String,
+        location: Option<String>,
+        seed: Option<u64>,
+        replicate: u64,
+    ) -> Self {
+        PanicRecord {
+            format_version: FORMAT_VERSION,
+            message,
+            location,
+            seed,
+            replicate,
+        }
+    }
+}
+
+impl<X> RunInfo<X> {
+    pub fn new(info: X) -> Self {
+        RunInfo {
+            format_version: FORMAT_VERSION,
+            info,
+        }
+    }
+}
+
+pub fn read_manifest(path: &Path) -> Result<Manifest, MrpError> {
+    parse("manifest", &read(path)?)
+}
+
+pub fn read_complete(path: &Path) -> Result<Complete, MrpError> {
+    parse("complete", &read(path)?)
+}
+
+pub fn read_metrics(path: &Path) -> Result<Metrics, MrpError> {
+    parse("metrics", &read(path)?)
+}
+
+pub fn read_error(path: &Path) -> Result<ErrorRecord, MrpError> {
+    parse("error", &read(path)?)
+}
+
+pub fn read_panic(path: &Path) -> Result<PanicRecord, MrpError> {
+    parse("panic", &read(path)?)
+}
+
+pub fn read_run_info<X: DeserializeOwned>(path: &Path) -> Result<RunInfo<X>, MrpError> {
+    parse_run_info(&read(path)?)
+}
+
+/// Parse run info already read, e.g. through `Environment::read_file`.
+pub fn parse_run_info<X: DeserializeOwned>(data: &[u8]) -> Result<RunInfo<X>, MrpError> {
+    parse("run_info", data)
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, MrpError> {
+    fs::read(path).map_err(|e| MrpError::FileNotFound(format!("{}: {e}", path.display())))
+}
+
+/// Check the version before the shape, so a newer file fails with a
+/// version error rather than a missing field. Files from before versioning
+/// have no `format_version` and read as version 1.
+fn parse<T: DeserializeOwned>(what: &str, data: &[u8]) -> Result<T, MrpError> {
+    let mut value: Value = serde_json::from_slice(data)
+        .map_err(|e| MrpError::Serialization(format!("invalid {what}: {e}")))?;
+    let Some(object) = value.as_object_mut() else {
+        return Err(MrpError::Serialization(format!(
+            "invalid {what}: expected an object"
+        )));
+    };
+    let version = match object.get("format_version") {
+        None => 1,
+        Some(v) => v.as_u64().ok_or_else(|| {
+            MrpError::Serialization(format!("invalid {what}: format_version is not an integer"))
+        })?,
+    };
+    if version > FORMAT_VERSION as u64 {
+        return Err(MrpError::Serialization(format!(
+            "{what} has format_version {version}, but this build reads up to \
+             {FORMAT_VERSION}; upgrade cfa-mrp"
+        )));
+    }
+    object.insert("format_version".to_string(), version.into());
+    serde_json::from_value(value)
+        .map_err(|e| MrpError::Serialization(format!("invalid {what}: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Golden files per version. A struct change that drops or renames a
+    /// field fails here until the version is bumped and new fixtures added.
+    const FIXTURES: [(u32, &str, &str); 6] = [
+        (1, "manifest", include_str!("formats/v1/manifest.json")),
+        (1, "complete", include_str!("formats/v1/complete.json")),
+        (1, "metrics", include_str!("formats/v1/metrics.json")),
+        (1, "run_info", include_str!("formats/v1/run_info.json")),
+        (1, "error", include_str!("formats/v1/error.json")),
+        (1, "panic", include_str!("formats/v1/panic.json")),
+    ];
+
+    fn round_trip(what: &str, data: &[u8]) -> Value {
+        match what {
+            "manifest" => serde_json::to_value(parse::<Manifest>(what, data).unwrap()),
+            "complete" => serde_json::to_value(parse::<Complete>(what, data).unwrap()),
+            "metrics" => serde_json::to_value(parse::<Metrics>(what, data).unwrap()),
+            "run_info" => serde_json::to_value(parse::<RunInfo>(what, data).unwrap()),
+            "error" => serde_json::to_value(parse::<ErrorRecord>(what, data).unwrap()),
+            "panic" => serde_json::to_value(parse::<PanicRecord>(what, data).unwrap()),
+            _ => unreachable!(),
+        }
+        .unwrap()
+    }
+
+    #[test]
+    fn test_fixtures_round_trip() {
+        for (version, what, fixture) in FIXTURES {
+            assert!(version <= FORMAT_VERSION);
+            let written = round_trip(what, fixture.as_bytes());
+            if version == FORMAT_VERSION {
+                let golden: Value = serde_json::from_str(fixture).unwrap();
+                assert_eq!(written, golden, "{what} v{version}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_newer_version_rejected() {
+        let err = parse::<Manifest>("manifest", br#"{"format_version": 2, "paths": []}"#)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("format_version 2"), "{err}");
+        assert!(err.contains("upgrade cfa-mrp"), "{err}");
+    }
+
+    #[test]
+    fn test_unknown_fields_and_unversioned_files() {
+        let complete: Complete = parse(
+            "complete",
+            br#"{"status": "complete", "manifest_sha256": "ab", "retries": 3}"#,
+        )
+        .unwrap();
+        assert_eq!(complete.format_version, 1);
+        assert_eq!(complete.status, RunStatus::Complete);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run_info.json");
+        fs::write(
+            &path,
+            br#"{"format_version": 1, "seed": 4, "new_field": true}"#,
+        )
+        .unwrap();
+        #[derive(Deserialize)]
+        struct Info {
+            seed: u64,
+        }
+        assert_eq!(read_run_info::<Info>(&path).unwrap().info.seed, 4);
+        assert!(read_manifest(&dir.path().join("missing.json")).is_err());
+    }
+}
@@ -0,0 +1,238 @@
+//! Reading and writing polars [`DataFrame`]s, for models and analysis tools
+//! that already hold their results as frames.
+//!
+//! Frames are encoded in memory and written with [`Environment::try_write`],
+//! so they get the same output handling as any other file: the output
+//! spec's destination, atomic rename, `if_exists`, the manifest and
+//! provenance. Columns must be integers, floats, booleans or strings; any
+//! other dtype is an error naming the column.
+
+use std::io::Cursor;
+use std::path::Path;
+
+use polars::prelude::{
+    CsvReadOptions, CsvWriter, DataFrame, DataType, IpcReader, IpcWriter, ParquetReader,
+    ParquetWriter, PolarsError, SerReader, SerWriter,
+};
+
+use crate::MrpError;
+use crate::environment::Environment;
+
+/// How a frame is encoded on disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameFormat {
+    Csv,
+    Parquet,
+    /// Arrow IPC, also known as Feather.
+    Ipc,
+}
+
+impl FrameFormat {
+    /// The format named by `path`'s extension: `.csv`, `.parquet`, or
+    /// `.arrow`, `.ipc` or `.feather`.
+    pub fn from_path(path: &Path) -> Result<Self, MrpError> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("csv") => Ok(FrameFormat::Csv),
+            Some("parquet") => Ok(FrameFormat::Parquet),
+            Some("arrow" | "ipc" | "feather") => Ok(FrameFormat::Ipc),
+            _ => Err(MrpError::Config(format!(
+                "cannot tell the frame format of '{}'; use .csv, .parquet, .arrow, .ipc \
+                 or .feather, or name the format",
+                path.display()
+            ))),
+        }
+    }
+}
+
+impl<I> Environment<I> {
+    /// Write `frame` to `filename` in `format`, else the format its
+    /// extension names, as [`Environment::try_write`] writes bytes.
+    pub fn write_dataframe(
+        &self,
+        filename: &str,
+        frame: &DataFrame,
+        format: Option<FrameFormat>,
+    ) -> Result<(), MrpError> {
+        let format = match format {
+            Some(format) => format,
+            None => FrameFormat::from_path(Path::new(filename))?,
+        };
+        let data = encode(frame, format).map_err(|e| frame_error(filename, e))?;
+        self.try_write(filename, &data)
+    }
+
+    /// Read the `model.files` entry `key` as a frame, in the format its
+    /// extension names. The read is recorded for provenance as
+    /// [`Environment::read_file`]'s are.
+    pub fn read_dataframe_file(&self, key: &str) -> Result<DataFrame, MrpError> {
+        let path = self
+            .files
+            .get(key)
+            .ok_or_else(|| MrpError::FileNotFound(format!("no file with key '{key}'")))?;
+        let format = FrameFormat::from_path(path)?;
+        let data = self.read_file(key)?;
+        let frame = decode(data, format).map_err(|e| {
+            MrpError::Input(format!(
+                "'{key}' ({}) is not a valid frame: {e}",
+                path.display()
+            ))
+        })?;
+        check_dtypes(&frame).map_err(|e| match e {
+            FrameError::Dtype(msg) => MrpError::Input(format!("'{key}': {msg}")),
+            FrameError::Polars(e) => MrpError::Input(format!("'{key}': {e}")),
+        })?;
+        Ok(frame)
+    }
+}
+
+enum FrameError {
+    Dtype(String),
+    Polars(PolarsError),
+}
+
+impl From<PolarsError> for FrameError {
+    fn from(e: PolarsError) -> Self {
+        FrameError::Polars(e)
+    }
+}
+
+fn frame_error(filename: &str, e: FrameError) -> MrpError {
+    match e {
+        FrameError::Dtype(msg) => MrpError::Output(format!("cannot write '{filename}': {msg}")),
+        FrameError::Polars(e) => {
+            MrpError::Serialization(format!("failed to encode '{filename}': {e}"))
+        }
+    }
+}
+
+/// Fail on the first column whose dtype is not supported.
+fn check_dtypes(frame: &DataFrame) -> Result<(), FrameError> {
+    for column in frame.get_columns() {
+        let supported = matches!(
+            column.dtype(),
+            DataType::Boolean
+                | DataType::UInt8
+                | DataType::UInt16
+                | DataType::UInt32
+                | DataType::UInt64
+                | DataType::Int8
+                | DataType::Int16
+                | DataType::Int32
+                | DataType::Int64
+                | DataType::Float32
+                | DataType::Float64
+                | DataType::String
+        );
+        if !supported {
+            return Err(FrameError::Dtype(format!(
+                "column '{}' has unsupported dtype {}; use integers, floats, booleans or strings",
+                column.name(),
+                column.dtype()
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn encode(frame: &DataFrame, format: FrameFormat) -> Result<Vec<u8>, FrameError> {
+    check_dtypes(frame)?;
+    let mut frame = frame.clone();
+    let mut data = Vec::new();
+    match format {
+        FrameFormat::Csv => CsvWriter::new(&mut data).finish(&mut frame)?,
+        FrameFormat::Parquet => {
+            ParquetWriter::new(&mut data).finish(&mut frame)?;
+        }
+        FrameFormat::Ipc => IpcWriter::new(&mut data).finish(&mut frame)?,
+    }
+    Ok(data)
+}
+
+fn decode(data: Vec<u8>, format: FrameFormat) -> Result<DataFrame, PolarsError> {
+    let data = Cursor::new(data);
+    match format {
+        FrameFormat::Csv => CsvReadOptions::default()
+            .with_has_header(true)
+            .into_reader_with_file_handle(data)
+            .finish(),
+        FrameFormat::Parquet => ParquetReader::new(data).finish(),
+        FrameFormat::Ipc => IpcReader::new(data).finish(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use polars::prelude::{Column, NamedFrom, Series};
+
+    use super::*;
+
+    fn frame() -> DataFrame {
+        DataFrame::new(vec![
+            Column::new("step".into(), &[0u64, 1, 2]),
+            Column::new("count".into(), &[-3i64, 0, 7]),
+            Column::new("rate".into(), &[0.5f64, 1.25, -2.0]),
+            Column::new("label".into(), &["a", "b", "c"]),
+        ])
+        .unwrap()
+    }
+
+    fn env(dir: &Path) -> Environment {
+        Environment::from_json(serde_json::json!({
+            "output": {"spec": "filesystem", "dir": dir.to_string_lossy()},
+            "model": {"files": {
+                "csv": dir.join("frame.csv").to_string_lossy(),
+                "parquet": dir.join("frame.parquet").to_string_lossy(),
+                "ipc": dir.join("frame.arrow").to_string_lossy(),
+            }},
+        }))
+    }
+
+    #[test]
+    fn test_round_trip_each_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let env = env(dir.path());
+        let frame = frame();
+        for (key, name) in [
+            ("csv", "frame.csv"),
+            ("parquet", "frame.parquet"),
+            ("ipc", "frame.arrow"),
+        ] {
+            env.write_dataframe(name, &frame, None).unwrap();
+            let read = env.read_dataframe_file(key).unwrap();
+            if key == "csv" {
+                // CSV keeps values but infers its own integer widths
+                assert_eq!(read.get_column_names(), frame.get_column_names());
+                assert_eq!(read.column("step").unwrap().dtype(), &DataType::Int64);
+                assert_eq!(read.column("rate").unwrap().f64().unwrap().get(1), Some(1.25));
+                assert_eq!(read.column("label").unwrap().str().unwrap().get(2), Some("c"));
+            } else {
+                assert_eq!(read.schema(), frame.schema(), "{name}");
+                assert!(read.equals(&frame), "{name}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_format_argument_overrides_extension() {
+        let dir = tempfile::tempdir().unwrap();
+        let env = env(dir.path());
+        env.write_dataframe("frame.csv", &frame(), Some(FrameFormat::Parquet))
+            .unwrap();
+        let data = std::fs::read(dir.path().join("frame.csv")).unwrap();
+        assert_eq!(&data[..4], b"PAR1");
+        let err = env.write_dataframe("frame.txt", &frame(), None).unwrap_err();
+        assert!(err.to_string().contains("frame.txt"));
+    }
+
+    #[test]
+    fn test_unsupported_dtype_names_column() {
+        let dir = tempfile::tempdir().unwrap();
+        let env = env(dir.path());
+        let bytes = Series::new("blob".into(), &[b"x".as_slice(), b"y".as_slice()]);
+        let frame = DataFrame::new(vec![bytes.into()]).unwrap();
+        let err = env.write_dataframe("blob.parquet", &frame, None).unwrap_err();
+        assert_eq!(err.kind(), "output");
+        assert!(err.to_string().contains("column 'blob'"), "{err}");
+        assert!(!dir.path().join("blob.parquet").exists());
+    }
+}
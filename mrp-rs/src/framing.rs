@@ -0,0 +1,455 @@
+//! The framed stdout protocol, for a parent model that reads a child's
+//! outputs as they are written.
+//!
+//! With `"output": {"spec": "stdout", "framed": true}`, every output goes
+//! to stdout between a `file_start` and a `file_end` frame, its bytes in
+//! `data` frames tagged with the file, so files written at the same time
+//! can share the stream. `finalize` ends it with a `completion` frame, as
+//! does [`Environment::fail`](crate::Environment::fail). Each frame is a
+//! JSON line; a `data` line is followed by exactly `len` raw bytes:
+//!
+//! ```text
+//! {"frame":"file_start","file":"out.csv"}
+//! {"frame":"data","file":"out.csv","len":10}
+//! step,cases
+//! {"frame":"file_end","file":"out.csv"}
+//! {"frame":"completion","status":"complete","metrics":{}}
+//! ```
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+use serde::{Deserialize, Serialize};
+
+/// How a framed run ended.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Completion {
+    pub status: CompletionStatus,
+    /// The error code of a failed run.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// The run's metrics, as `metrics.json` would hold them.
+    #[serde(default)]
+    pub metrics: BTreeMap<String, f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CompletionStatus {
+    Complete,
+    Cancelled,
+    Failed,
+}
+
+/// One line of the framed stream.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "frame", rename_all = "snake_case")]
+enum Header {
+    FileStart { file: String },
+    Data { file: String, len: usize },
+    FileEnd { file: String },
+    Completion(Completion),
+}
+
+fn write_header(out: &mut impl Write, header: &Header) -> io::Result<()> {
+    let mut line = serde_json::to_vec(header).map_err(io::Error::other)?;
+    line.push(b'\n');
+    out.write_all(&line)
+}
+
+fn write_data(out: &mut impl Write, file: &str, data: &[u8]) -> io::Result<()> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    let file = file.to_string();
+    write_header(
+        out,
+        &Header::Data {
+            file,
+            len: data.len(),
+        },
+    )?;
+    out.write_all(data)
+}
+
+/// Write all of `filename` as one framed file.
+pub(crate) fn write_file(out: &mut impl Write, filename: &str, data: &[u8]) -> io::Result<()> {
+    let file = filename.to_string();
+    write_header(out, &Header::FileStart { file: file.clone() })?;
+    write_data(out, filename, data)?;
+    write_header(out, &Header::FileEnd { file })
+}
+
+pub(crate) fn write_completion(out: &mut impl Write, completion: Completion) -> io::Result<()> {
+    write_header(out, &Header::Completion(completion))
+}
+
+/// Frames each write to a streamed output as `data` for its file, between
+/// the `file_start` written on creation and the `file_end` written when it
+/// is dropped.
+pub(crate) struct FramedWriter<W: Write> {
+    inner: W,
+    file: String,
+}
+
+impl<W: Write> FramedWriter<W> {
+    pub(crate) fn new(mut inner: W, filename: &str) -> io::Result<Self> {
+        let file = filename.to_string();
+        write_header(&mut inner, &Header::FileStart { file: file.clone() })?;
+        Ok(FramedWriter { inner, file })
+    }
+}
+
+impl<W: Write> Write for FramedWriter<W> {
+    /// Writes all of `buf`, so a frame is never left short of its `len`.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        write_data(&mut self.inner, &self.file, buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for FramedWriter<W> {
+    fn drop(&mut self) {
+        let file = std::mem::take(&mut self.file);
+        let _ = write_header(&mut self.inner, &Header::FileEnd { file })
+            .and_then(|_| self.inner.flush());
+    }
+}
+
+/// An event of a framed stream, as read by [`FrameReader`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    FileStart {
+        file: String,
+    },
+    /// A record of a `.csv` file; the first of each file is its header.
+    CsvRow {
+        file: String,
+        record: Vec<String>,
+    },
+    /// Bytes of any other file, as written.
+    Data {
+        file: String,
+        data: Vec<u8>,
+    },
+    FileEnd {
+        file: String,
+    },
+    Completion(Completion),
+}
+
+/// A malformed framed stream, at `offset` bytes into it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameError {
+    pub offset: u64,
+    pub message: String,
+}
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "malformed frame at byte {}: {}",
+            self.offset, self.message
+        )
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// The bytes of a `.csv` file not yet ending a record.
+#[derive(Default)]
+struct PendingCsv {
+    buf: Vec<u8>,
+    /// Whether the end of `buf` is inside a quoted field, so a newline
+    /// there does not end a record.
+    in_quotes: bool,
+}
+
+impl PendingCsv {
+    /// Append `data`, returning the complete records it ends.
+    fn push(&mut self, data: &[u8]) -> Vec<u8> {
+        let scanned = self.buf.len();
+        self.buf.extend_from_slice(data);
+        let mut end = None;
+        for (i, byte) in self.buf.iter().enumerate().skip(scanned) {
+            match byte {
+                b'"' => self.in_quotes = !self.in_quotes,
+                b'\n' if !self.in_quotes => end = Some(i + 1),
+                _ => {}
+            }
+        }
+        match end {
+            Some(end) => self.buf.drain(..end).collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Reads [`Frame`]s from a framed stream as they arrive, holding no more
+/// than one frame and a partial CSV record per open file. After an error
+/// it yields nothing more.
+pub struct FrameReader<R> {
+    inner: BufReader<R>,
+    offset: u64,
+    open: BTreeMap<String, Option<PendingCsv>>,
+    ready: VecDeque<Frame>,
+    done: bool,
+}
+
+impl<R: Read> FrameReader<R> {
+    pub fn new(inner: R) -> Self {
+        FrameReader {
+            inner: BufReader::new(inner),
+            offset: 0,
+            open: BTreeMap::new(),
+            ready: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    /// Append what is left of the stream, unread, to `out`.
+    pub(crate) fn read_rest(&mut self, out: &mut Vec<u8>) -> io::Result<usize> {
+        self.done = true;
+        self.inner.read_to_end(out)
+    }
+
+    fn error(&mut self, offset: u64, message: String) -> FrameError {
+        self.done = true;
+        FrameError { offset, message }
+    }
+
+    /// Read the next frame, queueing its events; `false` at the end.
+    fn read_frame(&mut self) -> Result<bool, FrameError> {
+        let at = self.offset;
+        let mut line = Vec::new();
+        let read = self
+            .inner
+            .read_until(b'\n', &mut line)
+            .map_err(|e| self.error(at, e.to_string()))?;
+        self.offset += read as u64;
+        if read == 0 {
+            return match self.open.keys().next() {
+                Some(file) => {
+                    let message = format!("stream ended inside '{file}'");
+                    Err(self.error(at, message))
+                }
+                None => Ok(false),
+            };
+        }
+        let header: Header = serde_json::from_slice(&line)
+            .map_err(|e| self.error(at, format!("invalid frame header: {e}")))?;
+        match header {
+            Header::FileStart { file } => {
+                if self.open.contains_key(&file) {
+                    return Err(self.error(at, format!("'{file}' started twice")));
+                }
+                let csv = file.ends_with(".csv").then(PendingCsv::default);
+                self.open.insert(file.clone(), csv);
+                self.ready.push_back(Frame::FileStart { file });
+            }
+            Header::Data { file, len } => {
+                let mut data = vec![0; len];
+                let body = self.offset;
+                self.inner.read_exact(&mut data).map_err(|e| {
+                    self.error(body, format!("{len} bytes of '{file}' expected: {e}"))
+                })?;
+                self.offset += len as u64;
+                match self.open.get_mut(&file) {
+                    None => return Err(self.error(at, format!("data for unopened '{file}'"))),
+                    Some(None) => self.ready.push_back(Frame::Data { file, data }),
+                    Some(Some(pending)) => {
+                        let records = pending.push(&data);
+                        self.queue_records(&file, &records, body)?;
+                    }
+                }
+            }
+            Header::FileEnd { file } => match self.open.remove(&file) {
+                None => return Err(self.error(at, format!("'{file}' ended but never started"))),
+                Some(pending) => {
+                    if let Some(pending) = pending {
+                        self.queue_records(&file, &pending.buf, at)?;
+                    }
+                    self.ready.push_back(Frame::FileEnd { file });
+                }
+            },
+            Header::Completion(completion) => {
+                if let Some(file) = self.open.keys().next() {
+                    let message = format!("completion while '{file}' is open");
+                    return Err(self.error(at, message));
+                }
+                self.ready.push_back(Frame::Completion(completion));
+                self.done = true;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Queue the records of `bytes`, read from `file` at `offset`.
+    fn queue_records(&mut self, file: &str, bytes: &[u8], offset: u64) -> Result<(), FrameError> {
+        let mut reader = ::csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(bytes);
+        for record in reader.records() {
+            let record = record.map_err(|e| self.error(offset, format!("'{file}': {e}")))?;
+            self.ready.push_back(Frame::CsvRow {
+                file: file.to_string(),
+                record: record.iter().map(String::from).collect(),
+            });
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Iterator for FrameReader<R> {
+    type Item = Result<Frame, FrameError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(frame) = self.ready.pop_front() {
+                return Some(Ok(frame));
+            }
+            if self.done {
+                return None;
+            }
+            match self.read_frame() {
+                Ok(true) => {}
+                Ok(false) => {
+                    self.done = true;
+                    return None;
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(stream: &[u8]) -> Vec<Result<Frame, FrameError>> {
+        FrameReader::new(stream).collect()
+    }
+
+    fn start(file: &str) -> Frame {
+        Frame::FileStart {
+            file: file.to_string(),
+        }
+    }
+
+    fn row(file: &str, record: &[&str]) -> Frame {
+        Frame::CsvRow {
+            file: file.to_string(),
+            record: record.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    fn end(file: &str) -> Frame {
+        Frame::FileEnd {
+            file: file.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_interleaved_files_round_trip() {
+        let mut stream = Vec::new();
+        {
+            let mut rows = FramedWriter::new(&mut stream, "rows.csv").unwrap();
+            // A quoted newline, and a record split across writes
+            rows.write_all(b"id,note\n1,\"two\nlines\"\n2,sp").unwrap();
+            rows.write_all(b"lit\n").unwrap();
+        }
+        write_file(&mut stream, "summary.json", b"{\"total\": 3}").unwrap();
+        write_completion(
+            &mut stream,
+            Completion {
+                status: CompletionStatus::Complete,
+                code: None,
+                message: None,
+                metrics: BTreeMap::from([("total".to_string(), 3.0)]),
+            },
+        )
+        .unwrap();
+
+        let frames: Vec<Frame> = frames(&stream).into_iter().map(Result::unwrap).collect();
+        assert_eq!(
+            frames[..5],
+            [
+                start("rows.csv"),
+                row("rows.csv", &["id", "note"]),
+                row("rows.csv", &["1", "two\nlines"]),
+                row("rows.csv", &["2", "split"]),
+                end("rows.csv"),
+            ]
+        );
+        assert_eq!(
+            frames[6],
+            Frame::Data {
+                file: "summary.json".to_string(),
+                data: b"{\"total\": 3}".to_vec(),
+            }
+        );
+        let Frame::Completion(completion) = &frames[8] else {
+            panic!("{frames:?}");
+        };
+        assert_eq!(completion.metrics["total"], 3.0);
+        assert_eq!(frames.len(), 9);
+    }
+
+    #[test]
+    fn test_last_record_without_newline() {
+        let mut stream = Vec::new();
+        write_file(&mut stream, "a.csv", b"x\n1").unwrap();
+        let frames: Vec<Frame> = frames(&stream).into_iter().map(Result::unwrap).collect();
+        assert_eq!(frames[2], row("a.csv", &["1"]));
+    }
+
+    #[test]
+    fn test_malformed_frame_reports_offset() {
+        let mut stream = Vec::new();
+        write_file(&mut stream, "a.csv", b"x\n1\n").unwrap();
+        let at = stream.len() as u64;
+        stream.extend_from_slice(b"not a frame\n");
+        write_file(&mut stream, "b.csv", b"y\n").unwrap();
+
+        let frames = frames(&stream);
+        assert_eq!(frames.len(), 5);
+        let err = frames[4].clone().unwrap_err();
+        assert_eq!(err.offset, at);
+        assert!(
+            err.to_string()
+                .starts_with(&format!("malformed frame at byte {at}: "))
+        );
+    }
+
+    #[test]
+    fn test_truncated_stream_is_an_error() {
+        let mut stream = Vec::new();
+        write_file(&mut stream, "a.txt", b"hello").unwrap();
+        stream.truncate(stream.len() - "{\"frame\":\"file_end\",\"file\":\"a.txt\"}\n".len() - 2);
+        let err = frames(&stream).pop().unwrap().unwrap_err();
+        assert!(err.message.contains("5 bytes of 'a.txt' expected"), "{err}");
+
+        let mut stream = Vec::new();
+        drop(FramedWriter::new(&mut stream, "a.csv").unwrap());
+        stream.truncate(stream.len() / 2);
+        assert!(frames(&stream).last().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_unopened_file_is_an_error() {
+        let err = frames(b"{\"frame\":\"file_end\",\"file\":\"a.csv\"}\n")[0]
+            .clone()
+            .unwrap_err();
+        assert_eq!(err.offset, 0);
+        assert!(err.message.contains("never started"), "{err}");
+    }
+}
@@ -0,0 +1,90 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use serde_json::Value;
+
+use crate::MrpError;
+
+/// What to do when an output file is already in the output directory, set
+/// by the output spec's `"if_exists"` key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IfExists {
+    /// Fail the write, naming the file.
+    Error,
+    /// Replace the file (the default).
+    #[default]
+    Overwrite,
+    /// Leave the file as it is and discard what would have been written.
+    Skip,
+    /// Add CSV rows after those in the file, without another header row.
+    /// Other files are an error, as under [`IfExists::Error`].
+    Append,
+}
+
+impl IfExists {
+    pub(crate) fn from_spec(spec: &Value) -> Result<Self, MrpError> {
+        match spec.get("if_exists").and_then(|v| v.as_str()) {
+            None | Some("overwrite") => Ok(IfExists::Overwrite),
+            Some("error") => Ok(IfExists::Error),
+            Some("skip") => Ok(IfExists::Skip),
+            Some("append") => Ok(IfExists::Append),
+            Some(other) => Err(MrpError::Config(format!(
+                "unknown if_exists policy: {other:?} (expected error, overwrite, skip or append)"
+            ))),
+        }
+    }
+}
+
+/// The error for writing `filename` over `path` under [`IfExists::Error`],
+/// or a file other than a CSV under [`IfExists::Append`].
+pub(crate) fn exists_error(filename: &str, path: &Path, policy: IfExists) -> MrpError {
+    let advice = match policy {
+        IfExists::Append => {
+            "\"if_exists\": \"append\" applies only to CSV files; set it to \"overwrite\" \
+             or \"skip\" in the output spec"
+        }
+        _ => {
+            "set \"if_exists\" in the output spec to \"overwrite\", \"skip\" or \"append\" \
+             (CSV only) to write anyway"
+        }
+    };
+    MrpError::Output(format!(
+        "'{filename}' already exists at {}; {advice}",
+        path.display()
+    ))
+}
+
+/// Open the CSV at `path` to add rows after its own, checking that its
+/// header row is `headers`. A missing final newline is added first.
+pub(crate) fn open_for_append(path: &Path, headers: &[&str]) -> Result<File, MrpError> {
+    let error = |e: std::io::Error| {
+        MrpError::Output(format!("failed to append to {}: {e}", path.display()))
+    };
+    let mut found = String::new();
+    BufReader::new(File::open(path).map_err(error)?)
+        .read_line(&mut found)
+        .map_err(error)?;
+    let found = found.trim_end_matches(['\r', '\n']);
+    if found != headers.join(",") {
+        return Err(MrpError::Output(format!(
+            "cannot append to {}: existing header '{found}' does not match '{}'",
+            path.display(),
+            headers.join(",")
+        )));
+    }
+    let mut file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .open(path)
+        .map_err(error)?;
+    let mut last = [0u8];
+    if fs::metadata(path).map_err(error)?.len() > 0 {
+        file.seek(SeekFrom::End(-1)).map_err(error)?;
+        file.read_exact(&mut last).map_err(error)?;
+        if last[0] != b'\n' {
+            file.write_all(b"\n").map_err(error)?;
+        }
+    }
+    Ok(file)
+}
@@ -0,0 +1,314 @@
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::MrpError;
+
+/// The input could not be deserialized into the model's type.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InputError {
+    /// Where in the input the problem is, e.g. `observation.delay_pmf[3]`;
+    /// empty for the input as a whole.
+    pub path: String,
+    /// The JSON found at `path`.
+    pub fragment: Value,
+    /// serde's message, without its line and column.
+    pub message: String,
+}
+
+impl std::fmt::Display for InputError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "failed to parse input: {}", self.message)
+        } else {
+            write!(
+                f,
+                "failed to parse input: {}: {} (found {})",
+                self.path, self.message, self.fragment
+            )
+        }
+    }
+}
+
+impl std::error::Error for InputError {}
+
+impl InputError {
+    /// This error from deserializing the input as given, noting `injected`,
+    /// the error with `replicate` put back, when it is a different problem
+    /// rather than the type rejecting the `replicate` key.
+    pub(crate) fn or_injected(mut self, injected: InputError) -> Self {
+        let same = injected.path == self.path && injected.message == self.message;
+        let rejects_key = injected.message.starts_with("unknown field `replicate`");
+        if !same && !rejects_key {
+            let location = match injected.path.as_str() {
+                "" => String::new(),
+                path => format!("{path}: "),
+            };
+            self.message = format!(
+                "{}; with `replicate` set, also {location}{}",
+                self.message, injected.message
+            );
+        }
+        self
+    }
+}
+
+impl From<InputError> for MrpError {
+    fn from(e: InputError) -> Self {
+        MrpError::Input(e.to_string())
+    }
+}
+
+/// Deserialize `input`, locating any failure within it.
+///
+/// serde_json only reports where an error occurred as a line and column, so
+/// on failure the input is parsed again from its pretty-printed text, where
+/// every value starts on a line of its own, and the line is mapped back to
+/// a path.
+pub(crate) fn deserialize<I: DeserializeOwned>(input: &Value) -> Result<I, InputError> {
+    let first = match serde_json::from_value(input.clone()) {
+        Ok(value) => return Ok(value),
+        Err(e) => e,
+    };
+    let text = serde_json::to_string_pretty(input).expect("a JSON value always serializes");
+    let e = serde_json::from_str::<I>(&text).err().unwrap_or(first);
+    let mut lines = vec![String::new()];
+    layout(input, "", &mut lines);
+    let path = e
+        .line()
+        .checked_sub(1)
+        .and_then(|line| lines.get(line))
+        .cloned()
+        .unwrap_or_default();
+    let message = e.to_string();
+    let message = match message.rfind(" at line ") {
+        Some(at) => message[..at].to_string(),
+        None => message,
+    };
+    Err(InputError {
+        fragment: lookup(input, &path).cloned().unwrap_or(Value::Null),
+        path,
+        message,
+    })
+}
+
+/// Push the path of each line `value` takes in pretty-printed JSON after
+/// its first, which the caller has pushed. Closing brackets belong to the
+/// value they close, as serde reports missing fields there.
+fn layout(value: &Value, path: &str, lines: &mut Vec<String>) {
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            for (key, item) in map {
+                let item_path = match path {
+                    "" => key.clone(),
+                    _ => format!("{path}.{key}"),
+                };
+                lines.push(item_path.clone());
+                layout(item, &item_path, lines);
+            }
+            lines.push(path.to_string());
+        }
+        Value::Array(items) if !items.is_empty() => {
+            for (i, item) in items.iter().enumerate() {
+                let item_path = format!("{path}[{i}]");
+                lines.push(item_path.clone());
+                layout(item, &item_path, lines);
+            }
+            lines.push(path.to_string());
+        }
+        _ => {}
+    }
+}
+
+/// The value at a path produced by [`layout`].
+pub(crate) fn lookup<'a>(input: &'a Value, path: &str) -> Option<&'a Value> {
+    let mut value = input;
+    for part in path.split('.').filter(|p| !p.is_empty()) {
+        let (key, indices) = part.split_once('[').unwrap_or((part, ""));
+        if !key.is_empty() {
+            value = value.get(key)?;
+        }
+        for index in indices.split('[') {
+            if let Some(index) = index.strip_suffix(']') {
+                value = value.get(index.parse::<usize>().ok()?)?;
+            }
+        }
+    }
+    Some(value)
+}
+
+pub(crate) fn lookup_mut<'a>(input: &'a mut Value, path: &str) -> Option<&'a mut Value> {
+    let mut value = input;
+    for part in path.split('.').filter(|p| !p.is_empty()) {
+        let (key, indices) = part.split_once('[').unwrap_or((part, ""));
+        if !key.is_empty() {
+            value = value.get_mut(key)?;
+        }
+        for index in indices.split('[') {
+            if let Some(index) = index.strip_suffix(']') {
+                value = value.get_mut(index.parse::<usize>().ok()?)?;
+            }
+        }
+    }
+    Some(value)
+}
+
+/// Turn numeric strings such as `"2.0"` into numbers where `I` fails on
+/// them, returning their paths. Only values deserialization rejects are
+/// changed, so a string field holding `"42"` stays a string. Stops at the
+/// first failure that is not a numeric string.
+pub(crate) fn coerce_numbers<I: DeserializeOwned>(input: &mut Value) -> Vec<String> {
+    let mut coerced: Vec<String> = Vec::new();
+    while let Err(e) = deserialize::<I>(input) {
+        let Some(value) = lookup_mut(input, &e.path) else {
+            break;
+        };
+        let number = match value {
+            Value::String(s) if !coerced.contains(&e.path) => number(s),
+            // `"2.0"` became a float, but an integer is wanted
+            Value::Number(n) if coerced.last() == Some(&e.path) => n
+                .as_f64()
+                .filter(|f| f.fract() == 0.0 && f.abs() < MAX_EXACT_INTEGER)
+                .map(|f| Value::from(f as i64)),
+            _ => None,
+        };
+        let Some(number) = number else {
+            break;
+        };
+        *value = number;
+        if coerced.last() != Some(&e.path) {
+            coerced.push(e.path);
+        }
+    }
+    coerced
+}
+
+const MAX_EXACT_INTEGER: f64 = 9_007_199_254_740_992.0;
+
+fn number(s: &str) -> Option<Value> {
+    let s = s.trim();
+    if let Ok(n) = s.parse::<u64>() {
+        return Some(n.into());
+    }
+    if let Ok(n) = s.parse::<i64>() {
+        return Some(n.into());
+    }
+    s.parse::<f64>()
+        .ok()
+        .filter(|f| f.is_finite())
+        .and_then(serde_json::Number::from_f64)
+        .map(Value::Number)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    #[allow(dead_code)]
+    struct Observation {
+        delay_pmf: Vec<f64>,
+        reporting: Reporting,
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    #[allow(dead_code)]
+    struct Reporting {
+        ascertainment: f64,
+        weekly: bool,
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[allow(dead_code)]
+    struct Input {
+        r0: f64,
+        observation: Observation,
+    }
+
+    fn error(input: Value) -> InputError {
+        deserialize::<Input>(&input).unwrap_err()
+    }
+
+    #[test]
+    fn test_nested_type_error_has_path() {
+        let e = error(serde_json::json!({
+            "r0": 2.0,
+            "observation": {
+                "delay_pmf": [0.1, 0.3, 0.4, "0.2"],
+                "reporting": { "ascertainment": 0.5, "weekly": false }
+            }
+        }));
+        assert_eq!(e.path, "observation.delay_pmf[3]");
+        assert_eq!(e.fragment, "0.2");
+        assert_eq!(
+            e.to_string(),
+            "failed to parse input: observation.delay_pmf[3]: invalid type: string \"0.2\", \
+             expected f64 (found \"0.2\")"
+        );
+    }
+
+    #[test]
+    fn test_numeric_strings_coerced_where_rejected() {
+        #[derive(Debug, Deserialize)]
+        #[allow(dead_code)]
+        struct Labelled {
+            r0: f64,
+            steps: u64,
+            labels: Vec<String>,
+            observation: Observation,
+        }
+        let mut input = serde_json::json!({
+            "r0": "2.5",
+            "steps": "10.0",
+            "labels": ["1", "2"],
+            "observation": {
+                "delay_pmf": [0.5, " 0.5 "],
+                "reporting": { "ascertainment": "1", "weekly": true }
+            }
+        });
+        let coerced = coerce_numbers::<Labelled>(&mut input);
+        assert_eq!(
+            coerced,
+            vec![
+                "observation.delay_pmf[1]",
+                "observation.reporting.ascertainment",
+                "r0",
+                "steps"
+            ]
+        );
+        assert_eq!(input["r0"], 2.5);
+        assert_eq!(input["steps"], 10);
+        assert_eq!(input["labels"], serde_json::json!(["1", "2"]));
+        assert_eq!(input["observation"]["delay_pmf"][1], 0.5);
+        assert!(deserialize::<Labelled>(&input).is_ok());
+
+        let mut input = serde_json::json!({ "r0": "high", "observation": {} });
+        assert!(coerce_numbers::<Input>(&mut input).is_empty());
+        assert_eq!(input["r0"], "high");
+    }
+
+    #[test]
+    fn test_missing_and_unknown_fields_have_paths() {
+        let e = error(serde_json::json!({
+            "r0": 2.0,
+            "observation": {
+                "delay_pmf": [],
+                "reporting": { "ascertainment": 0.5 }
+            }
+        }));
+        assert_eq!(e.path, "observation.reporting");
+        assert_eq!(e.message, "missing field `weekly`");
+
+        let e = error(serde_json::json!({
+            "r0": 2.0,
+            "observation": {
+                "delay_pmf": [1.0],
+                "reporting": { "ascertainment": 0.5, "weekly": true, "lag": 3 }
+            }
+        }));
+        assert_eq!(e.path, "observation.reporting.lag");
+        assert_eq!(e.fragment, 3);
+    }
+}
@@ -0,0 +1,78 @@
+use serde_json::Value;
+
+use crate::provenance::sha256_hex;
+
+/// Integral floats up to this size are written as integers, so `2.0` and
+/// `2` hash alike.
+const MAX_EXACT_INTEGER: f64 = 9_007_199_254_740_992.0;
+
+/// Hex SHA-256 of `value`'s canonical JSON.
+pub(crate) fn hash(value: &Value) -> String {
+    let mut out = String::new();
+    canonical(value, &mut out);
+    sha256_hex(out.as_bytes())
+}
+
+/// Compact JSON with object keys sorted and numbers normalized, so the
+/// text does not depend on key order or on how a number was written.
+fn canonical(value: &Value, out: &mut String) {
+    match value {
+        Value::Object(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            out.push('{');
+            for (i, (key, item)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&Value::String(key.clone()).to_string());
+                out.push(':');
+                canonical(item, out);
+            }
+            out.push('}');
+        }
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                canonical(item, out);
+            }
+            out.push(']');
+        }
+        Value::Number(n) => match n.as_f64() {
+            Some(f) if n.is_f64() && f.fract() == 0.0 && f.abs() <= MAX_EXACT_INTEGER => {
+                out.push_str(&(f as i64).to_string());
+            }
+            _ => out.push_str(&n.to_string()),
+        },
+        other => out.push_str(&other.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn text(value: &Value) -> String {
+        let mut out = String::new();
+        canonical(value, &mut out);
+        out
+    }
+
+    #[test]
+    fn test_canonical_json() {
+        assert_eq!(
+            text(&json!({ "b": [2.0, -0.0, 1.5], "a": { "y": "x\"", "x": null } })),
+            r#"{"a":{"x":null,"y":"x\""},"b":[2,0,1.5]}"#
+        );
+        assert_eq!(
+            hash(&json!({ "r0": 2, "seed": 1 })),
+            hash(&json!({ "seed": 1, "r0": 2.0 }))
+        );
+        assert_ne!(hash(&json!({ "r0": 2 })), hash(&json!({ "r0": "2" })));
+    }
+}
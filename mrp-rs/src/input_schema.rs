@@ -0,0 +1,129 @@
+//! JSON Schema for a model's input type, so frontends can render parameter
+//! forms and check configs before launching runs.
+//!
+//! A model opts in by deriving [`schemars::JsonSchema`] on its input type
+//! and calling [`exit_with_schema_if_requested`] before reading stdin; run
+//! with `MRP_MODE=schema`, it then prints [`emit_input_schema`] and exits.
+
+use std::borrow::Cow;
+
+use schemars::{JsonSchema, Schema, SchemaGenerator, json_schema};
+use serde_json::Value;
+
+use crate::MrpError;
+use crate::calendar::{Date, Days};
+use crate::environment::{Environment, Mode};
+
+/// Input keys the runner sets itself, whatever the input type declares.
+pub const RESERVED_INPUT_KEYS: [&str; 2] = ["seed", "replicate"];
+
+const RESERVED_NOTE: &str = "Reserved: injected by the runner, which sets it for each run.";
+
+/// The JSON Schema of `I`, noting that `seed` and `replicate` are reserved
+/// keys the runner injects: listed under `x-mrp-reserved`, and described
+/// as such where `I` declares them.
+pub fn emit_input_schema<I: JsonSchema>() -> Value {
+    let mut schema = schemars::schema_for!(I).to_value();
+    if let Some(properties) = schema.get_mut("properties").and_then(|v| v.as_object_mut()) {
+        for key in RESERVED_INPUT_KEYS {
+            if let Some(property) = properties.get_mut(key).and_then(|v| v.as_object_mut()) {
+                property.insert("description".to_string(), RESERVED_NOTE.into());
+            }
+        }
+    }
+    if let Some(root) = schema.as_object_mut() {
+        root.insert("x-mrp-reserved".to_string(), RESERVED_INPUT_KEYS.into());
+    }
+    schema
+}
+
+/// With `MRP_MODE=schema`, print [`emit_input_schema`] for `I` to stdout
+/// and exit; otherwise return so the model reads its payload. Call before
+/// [`Environment::from_stdin`].
+pub fn exit_with_schema_if_requested<I: JsonSchema>() -> Result<(), MrpError> {
+    if Environment::mode()? != Mode::Schema {
+        return Ok(());
+    }
+    let json = serde_json::to_string_pretty(&emit_input_schema::<I>())
+        .map_err(|e| MrpError::Serialization(e.to_string()))?;
+    println!("{json}");
+    std::process::exit(0)
+}
+
+impl JsonSchema for Date {
+    fn schema_name() -> Cow<'static, str> {
+        "Date".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "type": "string",
+            "format": "date",
+            "description": "An ISO-8601 calendar date, e.g. 2024-01-01."
+        })
+    }
+}
+
+impl JsonSchema for Days {
+    fn schema_name() -> Cow<'static, str> {
+        "Days".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "description": "A number of days, or a string like \"28 days\" or \"4 weeks\".",
+            "oneOf": [
+                {"type": "integer", "minimum": 0},
+                {"type": "string", "pattern": "^\\s*[0-9]+(\\s+(days?|weeks?))?\\s*$"}
+            ]
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::DistributionSpec;
+
+    #[allow(dead_code)]
+    #[derive(Deserialize, JsonSchema)]
+    struct Input {
+        /// Basic reproduction number.
+        r0: f64,
+        seed: u64,
+        start_date: Option<Date>,
+        horizon: Days,
+        delay: DistributionSpec,
+    }
+
+    #[test]
+    fn test_schema_describes_input() {
+        let schema = emit_input_schema::<Input>();
+        let properties = &schema["properties"];
+        assert_eq!(properties["r0"]["type"], "number");
+        assert_eq!(properties["r0"]["description"], "Basic reproduction number.");
+        let required: Vec<&str> = schema["required"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v.as_str().unwrap())
+            .collect();
+        assert!(required.contains(&"r0"));
+        assert!(!required.contains(&"start_date"));
+        assert_eq!(schema["$defs"]["Date"]["format"], "date");
+        assert!(schema["$defs"]["Days"]["oneOf"].is_array());
+        let delay = schema["$defs"]["DistributionSpec"].to_string();
+        assert!(delay.contains("\"gamma\"") && delay.contains("\"lognormal\""), "{delay}");
+    }
+
+    #[test]
+    fn test_schema_notes_reserved_keys() {
+        let schema = emit_input_schema::<Input>();
+        assert_eq!(schema["x-mrp-reserved"], serde_json::json!(["seed", "replicate"]));
+        let seed = schema["properties"]["seed"]["description"].as_str().unwrap();
+        assert!(seed.starts_with("Reserved: injected by the runner"), "{seed}");
+        assert!(schema["properties"].get("replicate").is_none());
+    }
+}
@@ -0,0 +1,172 @@
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::MrpError;
+use crate::environment::{merge_defaults, not_an_object, parse_index};
+use crate::input_error;
+
+/// One parameter set of a batch payload, from
+/// [`crate::Environment::input_sets`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunSpec<I> {
+    /// Position in `"input_sets"`, or 0 for a single-input payload.
+    pub index: usize,
+    pub seed: Option<u64>,
+    pub replicate: u64,
+    pub input: I,
+}
+
+impl<I> RunSpec<I> {
+    /// `filename` for this set, so sets do not overwrite each other's
+    /// outputs: `{set}` is replaced by the index, or without one the index
+    /// is added before the extension, e.g. `cases_1.csv`.
+    pub fn output_name(&self, filename: &str) -> String {
+        if filename.contains("{set}") {
+            return filename.replace("{set}", &self.index.to_string());
+        }
+        match filename.rsplit_once('.') {
+            Some((stem, ext)) if !stem.is_empty() => format!("{stem}_{}.{ext}", self.index),
+            _ => format!("{filename}_{}", self.index),
+        }
+    }
+}
+
+/// Each of `sets` over the `base` input, with the set's keys winning, or
+/// just `base` if there are no sets.
+pub(crate) fn parse<I: DeserializeOwned>(
+    base: &Value,
+    sets: Option<&Value>,
+    replicate: u64,
+) -> Result<Vec<RunSpec<I>>, MrpError> {
+    let Some(sets) = sets else {
+        return Ok(vec![spec(0, base.clone(), replicate)?]);
+    };
+    let Value::Array(sets) = sets else {
+        return Err(MrpError::Input(format!(
+            "\"input_sets\" must be an array of objects, found {sets}"
+        )));
+    };
+    if sets.is_empty() {
+        return Err(MrpError::Input("\"input_sets\" is empty".to_string()));
+    }
+    sets.iter()
+        .enumerate()
+        .map(|(index, set)| {
+            if !set.is_object() {
+                return Err(not_an_object(&format!("input_sets[{index}]"), set));
+            }
+            let mut input = set.clone();
+            merge_defaults(&mut input, base);
+            spec(index, input, replicate).map_err(|e| match e {
+                MrpError::Input(message) => {
+                    MrpError::Input(format!("input_sets[{index}]: {message}"))
+                }
+                other => other,
+            })
+        })
+        .collect()
+}
+
+fn spec<I: DeserializeOwned>(
+    index: usize,
+    mut input: Value,
+    replicate: u64,
+) -> Result<RunSpec<I>, MrpError> {
+    if input.is_null() {
+        input = Value::Object(Default::default());
+    }
+    let map = input.as_object_mut().expect("input is an object");
+    let replicate = match map.remove("replicate") {
+        Some(value) => parse_index("replicate", &value)?,
+        None => replicate,
+    };
+    let seed = match map.get_mut("seed") {
+        Some(value) => {
+            let seed = parse_index("seed", value)?;
+            *value = seed.into();
+            Some(seed)
+        }
+        None => None,
+    };
+    // As for the environment's typed input, `replicate` is offered to types
+    // that declare it
+    let mut with_replicate = input.clone();
+    with_replicate["replicate"] = replicate.into();
+    let input = match input_error::deserialize(&with_replicate) {
+        Ok(input) => input,
+        Err(_) => input_error::deserialize(&input)?,
+    };
+    Ok(RunSpec {
+        index,
+        seed,
+        replicate,
+        input,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn spec(index: usize) -> RunSpec<()> {
+        RunSpec {
+            index,
+            seed: None,
+            replicate: 0,
+            input: (),
+        }
+    }
+
+    #[test]
+    fn test_output_name() {
+        assert_eq!(spec(1).output_name("cases.csv"), "cases_1.csv");
+        assert_eq!(
+            spec(2).output_name("set-{set}/cases.csv"),
+            "set-2/cases.csv"
+        );
+        assert_eq!(spec(0).output_name("summary"), "summary_0");
+    }
+
+    #[test]
+    fn test_sets_merged_over_base() {
+        #[derive(serde::Deserialize, Debug, PartialEq)]
+        struct Input {
+            r0: f64,
+            sim_length: u64,
+        }
+        let base = json!({ "r0": 2.0, "sim_length": 10, "seed": 1 });
+        let sets = json!([{ "r0": 1.5 }, { "r0": 3.0, "seed": "9", "replicate": 4 }]);
+        let specs = parse::<Input>(&base, Some(&sets), 2).unwrap();
+        assert_eq!(specs.len(), 2);
+        assert_eq!(
+            specs[0],
+            RunSpec {
+                index: 0,
+                seed: Some(1),
+                replicate: 2,
+                input: Input {
+                    r0: 1.5,
+                    sim_length: 10
+                },
+            }
+        );
+        assert_eq!((specs[1].seed, specs[1].replicate), (Some(9), 4));
+        assert_eq!(specs[1].input.r0, 3.0);
+
+        let single = parse::<Input>(&base, None, 0).unwrap();
+        assert_eq!(single.len(), 1);
+        assert_eq!(single[0].input.r0, 2.0);
+
+        let err = parse::<Input>(&base, Some(&json!([{ "r0": "high" }])), 0).unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("input error: input_sets[0]: failed to parse input: r0"),
+            "{err}"
+        );
+        assert!(parse::<Input>(&base, Some(&json!([1])), 0).is_err());
+        assert!(parse::<Input>(&base, Some(&json!({})), 0).is_err());
+        assert!(parse::<Input>(&base, Some(&json!([])), 0).is_err());
+    }
+}
@@ -0,0 +1,220 @@
+use serde_json::Value;
+
+use crate::MrpError;
+
+/// Resolve `${VAR}` and `${VAR:-default}` in the payload's path strings,
+/// as selected by its `"interpolate_env"`:
+///
+/// - `true`: `model.files` values and the output `dir` and `fallback_dir`,
+///   including those of output profiles;
+/// - `"all"`: those and every string in `input`.
+///
+/// Absent or `false` leaves the payload untouched, so literal `${` in
+/// older payloads keeps working.
+pub(crate) fn apply(data: &mut Value) -> Result<(), MrpError> {
+    apply_with(data, |name| std::env::var(name).ok())
+}
+
+fn apply_with(data: &mut Value, var: impl Fn(&str) -> Option<String>) -> Result<(), MrpError> {
+    let all = match data.get("interpolate_env") {
+        None | Some(Value::Null) | Some(Value::Bool(false)) => return Ok(()),
+        Some(Value::Bool(true)) => false,
+        Some(Value::String(s)) if s == "all" => true,
+        Some(other) => {
+            return Err(MrpError::Config(format!(
+                "interpolate_env must be true, false or \"all\", got {other}"
+            )));
+        }
+    };
+    let mut targets: Vec<(String, &mut Value)> = Vec::new();
+    let Some(data) = data.as_object_mut() else {
+        return Ok(());
+    };
+    for (key, section) in data.iter_mut() {
+        match key.as_str() {
+            "model" => {
+                if let Some(files) = section.get_mut("files").and_then(|f| f.as_object_mut()) {
+                    for (name, path) in files {
+                        targets.push((format!("model.files.{name}"), path));
+                    }
+                }
+            }
+            "output" => {
+                let Some(output) = section.as_object_mut() else {
+                    continue;
+                };
+                for (key, value) in output.iter_mut() {
+                    match key.as_str() {
+                        "dir" | "fallback_dir" => targets.push((format!("output.{key}"), value)),
+                        "profile" => {
+                            let profiles = value.as_object_mut().into_iter().flatten();
+                            for (name, spec) in profiles {
+                                let spec = spec.as_object_mut().into_iter().flatten();
+                                for (key, value) in spec {
+                                    if key == "dir" || key == "fallback_dir" {
+                                        let at = format!("output.profile.{name}.{key}");
+                                        targets.push((at, value));
+                                    }
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            "input" if all => strings(section, "input", &mut targets),
+            _ => {}
+        }
+    }
+    for (at, value) in targets {
+        if let Value::String(s) = value {
+            *s = interpolate(s, &var).map_err(|e| MrpError::Config(format!("{at}: {e}")))?;
+        }
+    }
+    Ok(())
+}
+
+/// Every string within `value`, with its dotted path.
+fn strings<'v>(value: &'v mut Value, path: &str, out: &mut Vec<(String, &'v mut Value)>) {
+    match value {
+        Value::String(_) => out.push((path.to_string(), value)),
+        Value::Array(items) => {
+            for (i, item) in items.iter_mut().enumerate() {
+                strings(item, &format!("{path}[{i}]"), out);
+            }
+        }
+        Value::Object(map) => {
+            for (key, item) in map.iter_mut() {
+                strings(item, &format!("{path}.{key}"), out);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Replace each `${VAR}` or `${VAR:-default}` in `s`. `$$` is a literal `$`.
+fn interpolate(s: &str, var: impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(at) = rest.find('$') {
+        out.push_str(&rest[..at]);
+        rest = &rest[at..];
+        if let Some(after) = rest.strip_prefix("$$") {
+            out.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after
+                .find('}')
+                .ok_or_else(|| format!("unclosed '${{' in '{s}'"))?;
+            let (name, default) = match after[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&after[..end], None),
+            };
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(format!("invalid variable name '{name}' in '{s}'"));
+            }
+            match (var(name), default) {
+                (Some(value), _) => out.push_str(&value),
+                (None, Some(default)) => out.push_str(default),
+                (None, None) => {
+                    return Err(format!(
+                        "environment variable {name} is not set and has no default"
+                    ));
+                }
+            }
+            rest = &after[end + 1..];
+        } else {
+            out.push('$');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn var(name: &str) -> Option<String> {
+        match name {
+            "DATA" => Some("/cluster/data".to_string()),
+            "RUN_ID" => Some("42".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_interpolate() {
+        assert_eq!(
+            interpolate("${DATA}/cases.csv", var).unwrap(),
+            "/cluster/data/cases.csv"
+        );
+        assert_eq!(
+            interpolate("${SCRATCH:-/tmp}/run-${RUN_ID}", var).unwrap(),
+            "/tmp/run-42"
+        );
+        assert_eq!(
+            interpolate("${DATA:-/laptop}", var).unwrap(),
+            "/cluster/data"
+        );
+        assert_eq!(
+            interpolate("cost $5, $${DATA}", var).unwrap(),
+            "cost $5, ${DATA}"
+        );
+        assert_eq!(
+            interpolate("${SCRATCH}/out", var).unwrap_err(),
+            "environment variable SCRATCH is not set and has no default"
+        );
+        assert!(interpolate("${DATA", var).is_err());
+        assert!(interpolate("${DA TA}", var).is_err());
+    }
+
+    #[test]
+    fn test_only_opted_in_payloads_change() {
+        let payload = json!({
+            "input": { "label": "${RUN_ID}", "paths": ["${DATA}/a"] },
+            "model": { "files": { "cases": "${DATA}/cases.csv" } },
+            "output": {
+                "spec": "filesystem",
+                "dir": "${SCRATCH:-/tmp}/out",
+                "profile": { "cluster": { "spec": "filesystem", "dir": "${DATA}/out" } },
+            },
+        });
+        let mut untouched = payload.clone();
+        apply_with(&mut untouched, var).unwrap();
+        assert_eq!(untouched, payload);
+
+        let mut paths = payload.clone();
+        paths["interpolate_env"] = json!(true);
+        apply_with(&mut paths, var).unwrap();
+        assert_eq!(paths["model"]["files"]["cases"], "/cluster/data/cases.csv");
+        assert_eq!(paths["output"]["dir"], "/tmp/out");
+        assert_eq!(
+            paths["output"]["profile"]["cluster"]["dir"],
+            "/cluster/data/out"
+        );
+        assert_eq!(paths["input"]["label"], "${RUN_ID}");
+
+        let mut all = payload;
+        all["interpolate_env"] = json!("all");
+        apply_with(&mut all, var).unwrap();
+        assert_eq!(
+            all["input"],
+            json!({ "label": "42", "paths": ["/cluster/data/a"] })
+        );
+    }
+
+    #[test]
+    fn test_unset_variable_names_variable_and_key() {
+        let mut payload = json!({
+            "interpolate_env": true,
+            "model": { "files": { "cases": "${CASES_DIR}/cases.csv" } },
+        });
+        let err = apply_with(&mut payload, var).unwrap_err().to_string();
+        assert!(err.contains("CASES_DIR"), "{err}");
+        assert!(err.contains("model.files.cases"), "{err}");
+    }
+}
@@ -0,0 +1,252 @@
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::MrpError;
+
+/// Threads used when neither the payload's `"io_threads"` nor
+/// `MRP_IO_THREADS` sets a size.
+pub(crate) const DEFAULT_IO_THREADS: usize = 2;
+
+/// Environment variable read when the payload does not set `"io_threads"`.
+pub(crate) const IO_THREADS_VAR: &str = "MRP_IO_THREADS";
+
+type Task = Box<dyn FnOnce() + Send>;
+
+/// Counters of an Environment's IO pool.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IoPoolStats {
+    pub threads: usize,
+    pub tasks_run: u64,
+    /// Most tasks ever waiting for a thread at once.
+    pub queue_high_water: usize,
+}
+
+/// The one pool of threads background IO runs on, so writers and uploads
+/// never start threads of their own.
+///
+/// Threads start with the first task and stop at [`IoPool::shutdown`], after
+/// finishing every queued task; a later task starts them again.
+pub(crate) struct IoPool {
+    size: usize,
+    shared: Arc<Shared>,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    ready: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    queue: VecDeque<Task>,
+    /// Bumped by each shutdown; threads started before it exit once the
+    /// queue is empty.
+    generation: u64,
+    stats: IoPoolStats,
+}
+
+/// The result of a task submitted to an [`IoPool`].
+pub struct IoHandle<T> {
+    result: Receiver<T>,
+}
+
+impl<T> IoHandle<T> {
+    /// Block until the task finishes.
+    pub fn wait(self) -> Result<T, MrpError> {
+        self.result
+            .recv()
+            .map_err(|_| MrpError::Runtime("IO task panicked".to_string()))
+    }
+
+    /// Wait up to `timeout`; `None` if the task is still running.
+    pub fn wait_timeout(&self, timeout: Duration) -> Option<Result<T, MrpError>> {
+        match self.result.recv_timeout(timeout) {
+            Ok(value) => Some(Ok(value)),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => {
+                Some(Err(MrpError::Runtime("IO task panicked".to_string())))
+            }
+        }
+    }
+}
+
+impl IoPool {
+    pub(crate) fn new(size: usize) -> Self {
+        IoPool {
+            size: size.max(1),
+            shared: Arc::new(Shared {
+                state: Mutex::new(State::default()),
+                ready: Condvar::new(),
+            }),
+            threads: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Pool size from the payload's `"io_threads"`, else `MRP_IO_THREADS`.
+    pub(crate) fn configured(payload: Option<u64>) -> Result<Self, MrpError> {
+        let size = match payload {
+            Some(n) => n as usize,
+            None => match std::env::var(IO_THREADS_VAR) {
+                Ok(value) => value.trim().parse().map_err(|_| {
+                    MrpError::Config(format!(
+                        "{IO_THREADS_VAR} must be a positive integer, got {value:?}"
+                    ))
+                })?,
+                Err(_) => DEFAULT_IO_THREADS,
+            },
+        };
+        if size == 0 {
+            return Err(MrpError::Config("io_threads must be positive".to_string()));
+        }
+        Ok(Self::new(size))
+    }
+
+    /// Run `task` on the pool.
+    pub(crate) fn submit<T, F>(&self, task: F) -> IoHandle<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        self.start();
+        let (tx, result) = mpsc::channel();
+        let mut state = self.shared.state.lock().unwrap();
+        state.queue.push_back(Box::new(move || {
+            let _ = tx.send(task());
+        }));
+        state.stats.queue_high_water = state.stats.queue_high_water.max(state.queue.len());
+        drop(state);
+        self.shared.ready.notify_one();
+        IoHandle { result }
+    }
+
+    pub(crate) fn stats(&self) -> IoPoolStats {
+        IoPoolStats {
+            threads: self.size,
+            ..self.shared.state.lock().unwrap().stats
+        }
+    }
+
+    /// Finish every queued task, then stop the threads.
+    pub(crate) fn shutdown(&self) {
+        let threads: Vec<_> = {
+            let mut threads = self.threads.lock().unwrap();
+            self.shared.state.lock().unwrap().generation += 1;
+            threads.drain(..).collect()
+        };
+        self.shared.ready.notify_all();
+        for thread in threads {
+            let _ = thread.join();
+        }
+    }
+
+    fn start(&self) {
+        let mut threads = self.threads.lock().unwrap();
+        if !threads.is_empty() {
+            return;
+        }
+        let generation = self.shared.state.lock().unwrap().generation;
+        for i in 0..self.size {
+            let shared = self.shared.clone();
+            let thread = thread::Builder::new()
+                .name(format!("mrp-io-{i}"))
+                .spawn(move || shared.work(generation))
+                .expect("failed to start IO thread");
+            threads.push(thread);
+        }
+    }
+}
+
+impl Shared {
+    fn work(&self, generation: u64) {
+        loop {
+            let mut state = self.state.lock().unwrap();
+            let task = loop {
+                if let Some(task) = state.queue.pop_front() {
+                    break task;
+                }
+                if state.generation > generation {
+                    return;
+                }
+                state = self.ready.wait(state).unwrap();
+            };
+            drop(state);
+            // A panicking task drops its sender, failing only its handle
+            let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(task));
+            self.state.lock().unwrap().stats.tasks_run += 1;
+        }
+    }
+}
+
+impl Drop for IoPool {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn test_concurrent_submissions() {
+        let pool = Arc::new(IoPool::new(3));
+        let submitters: Vec<_> = (0..4)
+            .map(|t| {
+                let pool = pool.clone();
+                thread::spawn(move || {
+                    let handles: Vec<_> =
+                        (0..25).map(|i| pool.submit(move || t * 100 + i)).collect();
+                    handles
+                        .into_iter()
+                        .map(|h| h.wait().unwrap())
+                        .sum::<usize>()
+                })
+            })
+            .collect();
+        let total: usize = submitters.into_iter().map(|s| s.join().unwrap()).sum();
+        assert_eq!(total, (0..4).map(|t| t * 2500 + 300).sum::<usize>());
+        pool.shutdown();
+        let stats = pool.stats();
+        assert_eq!(stats.tasks_run, 100);
+        assert_eq!(stats.threads, 3);
+        assert!(stats.queue_high_water >= 1);
+    }
+
+    #[test]
+    fn test_shutdown_finishes_pending_tasks() {
+        let pool = IoPool::new(1);
+        let done = Arc::new(AtomicUsize::new(0));
+        for _ in 0..10 {
+            let done = done.clone();
+            pool.submit(move || {
+                thread::sleep(Duration::from_millis(2));
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        pool.shutdown();
+        assert_eq!(done.load(Ordering::SeqCst), 10);
+        assert!(pool.threads.lock().unwrap().is_empty());
+
+        // The pool starts again for later work, and a panic fails one task
+        assert!(pool.submit(|| panic!("boom")).wait().is_err());
+        assert_eq!(pool.submit(|| 7).wait().unwrap(), 7);
+        let slow = pool.submit(|| thread::sleep(Duration::from_millis(200)));
+        assert!(slow.wait_timeout(Duration::from_millis(1)).is_none());
+        pool.shutdown();
+        assert_eq!(pool.stats().tasks_run, 13);
+    }
+
+    #[test]
+    fn test_configured_size() {
+        assert_eq!(IoPool::configured(Some(4)).unwrap().stats().threads, 4);
+        assert!(IoPool::configured(Some(0)).is_err());
+    }
+}
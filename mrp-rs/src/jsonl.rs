@@ -0,0 +1,107 @@
+use std::io::{BufWriter, Write};
+
+use serde::Serialize;
+
+use crate::MrpError;
+
+/// Writes one JSON value per line, for record streams too large or too
+/// long-running to collect into a single JSON document.
+pub struct JsonlWriter {
+    writer: BufWriter<Box<dyn Write>>,
+    filename: Option<String>,
+    records: u64,
+}
+
+impl JsonlWriter {
+    pub fn new(dest: Box<dyn Write>) -> Self {
+        JsonlWriter {
+            writer: BufWriter::new(dest),
+            filename: None,
+            records: 0,
+        }
+    }
+
+    pub(crate) fn named(mut self, filename: &str) -> Self {
+        self.filename = Some(filename.to_string());
+        self
+    }
+
+    /// Records written so far.
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    pub fn write_record<T: Serialize>(&mut self, record: &T) {
+        self.try_write_record(record)
+            .unwrap_or_else(|e| panic!("{e}"));
+    }
+
+    pub fn try_write_record<T: Serialize>(&mut self, record: &T) -> Result<(), MrpError> {
+        let filename = self.filename.as_deref().unwrap_or("JSON lines");
+        serde_json::to_writer(&mut self.writer, record)
+            .map_err(|e| MrpError::Serialization(format!("{filename}: {e}")))?;
+        self.writer
+            .write_all(b"\n")
+            .map_err(|e| MrpError::Output(format!("failed to write {filename}: {e}")))?;
+        self.records += 1;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) {
+        self.writer
+            .flush()
+            .expect("failed to flush JSON lines writer");
+    }
+}
+
+impl Drop for JsonlWriter {
+    fn drop(&mut self) {
+        let _ = self.writer.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use serde_json::{Value, json};
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_one_record_per_line() {
+        let dest = Shared::default();
+        let mut writer = JsonlWriter::new(Box::new(dest.clone()));
+        writer.write_record(&json!({ "step": 1, "note": "two\nlines" }));
+        writer.write_record(&(2, "pair"));
+        assert_eq!(writer.records(), 2);
+        drop(writer);
+        let out = String::from_utf8(dest.0.borrow().clone()).unwrap();
+        let lines: Vec<Value> = out
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                json!({ "step": 1, "note": "two\nlines" }),
+                json!([2, "pair"])
+            ]
+        );
+    }
+}
@@ -1,26 +1,118 @@
 pub mod api;
+mod archive;
+mod atomic;
+mod builder;
+pub mod cache;
+pub mod calendar;
+pub mod cancel;
+pub mod catalog;
+pub mod cli;
+#[cfg(feature = "object-store")]
+pub mod cloud;
+pub mod compare;
 pub mod config;
 pub mod csv;
+mod dedup;
+mod defer;
+pub mod dist;
 pub mod environment;
+pub mod fallback;
+mod file_refs;
+mod files_error;
+pub mod format;
+pub mod formats;
+#[cfg(feature = "polars")]
+pub mod frame;
+pub mod framing;
+pub mod if_exists;
+mod input_error;
+mod input_hash;
+#[cfg(feature = "schema")]
+pub mod input_schema;
+mod input_sets;
+mod interpolate;
+mod io_pool;
+pub mod jsonl;
 pub mod manifest;
+pub mod matrix;
+mod memory;
+pub mod object_store;
 pub mod orchestrator;
+mod panic_hook;
+mod params;
+pub mod provenance;
+mod replicate;
+pub mod report;
+pub mod rng;
 pub mod runtime;
+pub mod schema;
+mod scratch;
+pub mod seed;
+pub mod snapshot;
+pub mod serve;
 pub mod stager;
+mod stream;
+mod tee;
+mod tempdir;
+pub mod template;
+mod throttle;
+pub mod types;
+mod validate;
+pub mod worker;
 
 pub use api::{run, run_with_options};
-pub use csv::CsvWriter;
-pub use environment::Environment;
+pub use builder::EnvironmentBuilder;
+pub use cache::RunCache;
+pub use calendar::Calendar;
+pub use cancel::CancelToken;
+pub use catalog::{ErrorCode, error_catalog};
+pub use csv::{CsvOptions, CsvWriter, StringPolicy};
+pub use dist::DistributionSpec;
+pub use environment::{
+    Environment, FINALIZE_ORDER, FinalizeStage, InputOverride, Mode, PayloadFormat, StrictOptions,
+    Warning,
+};
+pub use fallback::{SplitOutput, WriteFailurePolicy};
+#[cfg(feature = "polars")]
+pub use frame::FrameFormat;
+pub use framing::{Frame, FrameError, FrameReader};
+pub use files_error::{FileProblem, FilesError};
+pub use input_error::InputError;
+#[cfg(feature = "schema")]
+pub use input_schema::{emit_input_schema, exit_with_schema_if_requested};
+pub use template::TemplateError;
+pub use input_sets::RunSpec;
+pub use io_pool::{IoHandle, IoPoolStats};
+pub use jsonl::JsonlWriter;
+pub use matrix::Matrix;
 pub use orchestrator::{ConfigSource, DefaultOrchestrator, Orchestrator};
+pub use panic_hook::install_panic_hook;
+pub use params::{ParamCollector, ParamError, ParamErrors, PmfOptions};
+pub use object_store::{ObjectStore, ObjectStoreSink, RetryPolicy, resume_uploads};
 pub use manifest::{ModelSection, MrpMeta, MrpOutput, RunManifest, RuntimeSpec};
-pub use runtime::{RunResult, Runtime, SubprocessRuntime};
+pub use replicate::ReplicateContext;
+pub use report::ReportSection;
+pub use rng::RngKind;
+#[cfg(feature = "rand")]
+pub use rng::ModelRng;
+pub use runtime::{ChildRun, RunResult, Runtime, SubprocessRuntime};
+pub use schema::{ColumnType, OutputContract, OutputSchema};
+pub use serve::{ServeOptions, SessionSummary, serve};
+pub use snapshot::{SnapshotIndex, SnapshotOptions};
+pub use validate::{Validate, ValidationError, ValidationErrors};
+pub use worker::WorkerEnv;
 
 #[derive(Debug)]
 pub enum MrpError {
     Config(String),
     FileNotFound(String),
+    Input(String),
     Staging(String),
     Runtime(String),
+    Output(String),
     Serialization(String),
+    /// The consumer of a streamed output stopped reading.
+    Stalled(String),
 }
 
 impl std::fmt::Display for MrpError {
@@ -28,9 +120,28 @@ impl std::fmt::Display for MrpError {
         match self {
             MrpError::Config(msg) => write!(f, "config error: {msg}"),
             MrpError::FileNotFound(msg) => write!(f, "file not found: {msg}"),
+            MrpError::Input(msg) => write!(f, "input error: {msg}"),
             MrpError::Staging(msg) => write!(f, "staging error: {msg}"),
             MrpError::Runtime(msg) => write!(f, "runtime error: {msg}"),
+            MrpError::Output(msg) => write!(f, "output error: {msg}"),
             MrpError::Serialization(msg) => write!(f, "serialization error: {msg}"),
+            MrpError::Stalled(msg) => write!(f, "output stalled: {msg}"),
+        }
+    }
+}
+
+impl MrpError {
+    /// Short snake_case name of the error variant.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            MrpError::Config(_) => "config",
+            MrpError::FileNotFound(_) => "file_not_found",
+            MrpError::Input(_) => "input",
+            MrpError::Staging(_) => "staging",
+            MrpError::Runtime(_) => "runtime",
+            MrpError::Output(_) => "output",
+            MrpError::Serialization(_) => "serialization",
+            MrpError::Stalled(_) => "stalled",
         }
     }
 }
@@ -0,0 +1,248 @@
+use std::ops::Index;
+
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+
+use crate::MrpError;
+
+/// A dense row-major matrix of finite numbers, such as a contact or
+/// mobility matrix.
+///
+/// Read from the input as nested rows, `[[1, 2], [3, 4]]`, or as
+/// `{"rows": 2, "cols": 2, "data": [1, 2, 3, 4]}`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Matrix {
+    nrows: usize,
+    ncols: usize,
+    data: Vec<f64>,
+}
+
+/// Why a value is not a [`Matrix`]: where in it, what was found there and
+/// what is wrong.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Invalid {
+    /// Suffix of the value's path, e.g. `[1][2]` or `.data`.
+    pub(crate) at: String,
+    pub(crate) found: Value,
+    pub(crate) message: String,
+}
+
+impl Matrix {
+    pub fn new(nrows: usize, ncols: usize, data: Vec<f64>) -> Result<Self, MrpError> {
+        if nrows == 0 || ncols == 0 {
+            return Err(MrpError::Input(format!(
+                "matrix must not be empty, got {nrows}x{ncols}"
+            )));
+        }
+        if nrows.checked_mul(ncols) != Some(data.len()) {
+            return Err(MrpError::Input(format!(
+                "{nrows}x{ncols} matrix needs {} entries, got {}",
+                nrows.saturating_mul(ncols),
+                data.len()
+            )));
+        }
+        if let Some(i) = data.iter().position(|x| !x.is_finite()) {
+            return Err(MrpError::Input(format!("matrix entry {i} is {}", data[i])));
+        }
+        Ok(Matrix { nrows, ncols, data })
+    }
+
+    pub fn nrows(&self) -> usize {
+        self.nrows
+    }
+
+    pub fn ncols(&self) -> usize {
+        self.ncols
+    }
+
+    /// The entries, row by row.
+    pub fn data(&self) -> &[f64] {
+        &self.data
+    }
+
+    pub fn get(&self, i: usize, j: usize) -> Option<f64> {
+        (i < self.nrows && j < self.ncols).then(|| self.data[i * self.ncols + j])
+    }
+
+    /// The entries of row `i`. Panics if `i` is out of range.
+    pub fn row(&self, i: usize) -> impl ExactSizeIterator<Item = f64> + '_ {
+        assert!(i < self.nrows, "row {i} of a {}-row matrix", self.nrows);
+        self.data[i * self.ncols..(i + 1) * self.ncols]
+            .iter()
+            .copied()
+    }
+
+    /// The entries of column `j`. Panics if `j` is out of range.
+    pub fn col(&self, j: usize) -> impl ExactSizeIterator<Item = f64> + '_ {
+        assert!(
+            j < self.ncols,
+            "column {j} of a {}-column matrix",
+            self.ncols
+        );
+        self.data[j..].iter().step_by(self.ncols).copied()
+    }
+
+    pub(crate) fn from_value(value: &Value) -> Result<Self, Invalid> {
+        let invalid = |at: String, found: &Value, message: &str| Invalid {
+            at,
+            found: found.clone(),
+            message: message.to_string(),
+        };
+        match value {
+            Value::Array(rows) => {
+                let ncols = match rows.first() {
+                    Some(Value::Array(first)) => first.len(),
+                    Some(_) => 0,
+                    None => return Err(invalid(String::new(), value, "has no rows")),
+                };
+                let mut data = Vec::with_capacity(rows.len() * ncols);
+                for (i, row) in rows.iter().enumerate() {
+                    let Some(row) = row.as_array() else {
+                        return Err(invalid(format!("[{i}]"), row, "is not an array of numbers"));
+                    };
+                    if row.is_empty() {
+                        return Err(invalid(format!("[{i}]"), &Value::Null, "is an empty row"));
+                    }
+                    if row.len() != ncols {
+                        return Err(Invalid {
+                            at: format!("[{i}]"),
+                            found: Value::from(row.clone()),
+                            message: format!("has {} entries, but row 0 has {ncols}", row.len()),
+                        });
+                    }
+                    for (j, x) in row.iter().enumerate() {
+                        data.push(number(x).ok_or_else(|| {
+                            invalid(format!("[{i}][{j}]"), x, "is not a finite number")
+                        })?);
+                    }
+                }
+                Ok(Matrix {
+                    nrows: rows.len(),
+                    ncols,
+                    data,
+                })
+            }
+            Value::Object(map) => {
+                let size = |key: &str| {
+                    let found = map.get(key).unwrap_or(&Value::Null);
+                    found
+                        .as_u64()
+                        .filter(|n| *n > 0)
+                        .map(|n| n as usize)
+                        .ok_or_else(|| {
+                            invalid(format!(".{key}"), found, "must be a positive integer")
+                        })
+                };
+                let (nrows, ncols) = (size("rows")?, size("cols")?);
+                let found = map.get("data").unwrap_or(&Value::Null);
+                let Some(entries) = found.as_array() else {
+                    return Err(invalid(
+                        ".data".to_string(),
+                        found,
+                        "must be an array of numbers",
+                    ));
+                };
+                if nrows.checked_mul(ncols) != Some(entries.len()) {
+                    return Err(Invalid {
+                        at: ".data".to_string(),
+                        found: found.clone(),
+                        message: format!(
+                            "has {} entries, but a {nrows}x{ncols} matrix needs {}",
+                            entries.len(),
+                            nrows.saturating_mul(ncols)
+                        ),
+                    });
+                }
+                let data = entries
+                    .iter()
+                    .enumerate()
+                    .map(|(k, x)| {
+                        number(x).ok_or_else(|| {
+                            invalid(format!(".data[{k}]"), x, "is not a finite number")
+                        })
+                    })
+                    .collect::<Result<_, _>>()?;
+                Ok(Matrix { nrows, ncols, data })
+            }
+            _ => Err(invalid(
+                String::new(),
+                value,
+                "must be an array of rows or an object with rows, cols and data",
+            )),
+        }
+    }
+}
+
+fn number(value: &Value) -> Option<f64> {
+    value.as_f64().filter(|x| x.is_finite())
+}
+
+impl Index<(usize, usize)> for Matrix {
+    type Output = f64;
+
+    fn index(&self, (i, j): (usize, usize)) -> &f64 {
+        assert!(
+            i < self.nrows && j < self.ncols,
+            "index ({i}, {j}) of a {}x{} matrix",
+            self.nrows,
+            self.ncols
+        );
+        &self.data[i * self.ncols + j]
+    }
+}
+
+impl<'de> Deserialize<'de> for Matrix {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        Matrix::from_value(&value).map_err(|e| {
+            serde::de::Error::custom(format!("matrix{}: {} (found {})", e.at, e.message, e.found))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_nested_and_object_forms_agree() {
+        let nested = Matrix::from_value(&json!([[1, 2, 3], [4, 5, 6]])).unwrap();
+        let flat = Matrix::from_value(&json!({ "rows": 2, "cols": 3, "data": [1, 2, 3, 4, 5, 6] }))
+            .unwrap();
+        assert_eq!(nested, flat);
+        assert_eq!((nested.nrows(), nested.ncols()), (2, 3));
+        assert_eq!(nested[(1, 0)], 4.0);
+        assert_eq!(nested.get(2, 0), None);
+        assert_eq!(nested.row(1).collect::<Vec<_>>(), vec![4.0, 5.0, 6.0]);
+        assert_eq!(nested.col(2).collect::<Vec<_>>(), vec![3.0, 6.0]);
+        let typed: Vec<Matrix> = serde_json::from_value(json!([[[1.5]]])).unwrap();
+        assert_eq!(typed[0].data(), &[1.5]);
+    }
+
+    #[test]
+    fn test_invalid_matrices() {
+        let e = Matrix::from_value(&json!([[1, 2], [3]])).unwrap_err();
+        assert_eq!(e.at, "[1]");
+        assert_eq!(e.message, "has 1 entries, but row 0 has 2");
+        let e = Matrix::from_value(&json!([[1, 2], [3, "x"]])).unwrap_err();
+        assert_eq!((e.at.as_str(), e.found), ("[1][1]", json!("x")));
+        let e = Matrix::from_value(&json!([])).unwrap_err();
+        assert_eq!(e.message, "has no rows");
+        let e = Matrix::from_value(&json!([[]])).unwrap_err();
+        assert_eq!(e.message, "is an empty row");
+        let e =
+            Matrix::from_value(&json!({ "rows": 2, "cols": 2, "data": [1, 2, 3] })).unwrap_err();
+        assert_eq!(e.at, ".data");
+        let e = Matrix::from_value(&json!({ "rows": 0, "cols": 2, "data": [] })).unwrap_err();
+        assert_eq!(e.at, ".rows");
+        assert!(Matrix::from_value(&json!(3)).is_err());
+        let e = serde_json::from_value::<Matrix>(json!([[1], [null]])).unwrap_err();
+        assert!(e.to_string().contains("matrix[1][0]"), "{e}");
+
+        assert!(Matrix::new(2, 2, vec![1.0; 3]).is_err());
+        assert!(Matrix::new(1, 1, vec![f64::NAN]).is_err());
+        assert!(Matrix::new(0, 0, vec![]).is_err());
+    }
+}
@@ -0,0 +1,40 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+/// Files written under the `"memory"` output spec, by filename.
+pub(crate) type MemoryOutputs = Rc<RefCell<HashMap<String, Vec<u8>>>>;
+
+/// Writes one file of a [`MemoryOutputs`], replacing any earlier contents.
+pub(crate) struct MemoryWriter {
+    outputs: MemoryOutputs,
+    filename: String,
+}
+
+impl MemoryWriter {
+    pub(crate) fn create(outputs: MemoryOutputs, filename: &str) -> Self {
+        outputs
+            .borrow_mut()
+            .insert(filename.to_string(), Vec::new());
+        MemoryWriter {
+            outputs,
+            filename: filename.to_string(),
+        }
+    }
+}
+
+impl Write for MemoryWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outputs
+            .borrow_mut()
+            .entry(self.filename.clone())
+            .or_default()
+            .extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
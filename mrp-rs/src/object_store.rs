@@ -0,0 +1,499 @@
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::MrpError;
+use crate::environment::Warning;
+use crate::memory::{MemoryOutputs, MemoryWriter};
+use crate::scratch::Scratch;
+
+/// Minimal interface to an object store bucket/prefix.
+pub trait ObjectStore {
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()>;
+}
+
+/// Retry policy for object-store uploads.
+///
+/// Deserializes from the output spec's `"retry"` table, e.g.
+/// `{"attempts": 5, "backoff_ms": 200, "max_backoff_ms": 10000, "deadline_ms": 60000}`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Total attempts per upload, including the first.
+    pub attempts: u32,
+    /// Delay before the first retry; doubles on each subsequent retry.
+    pub backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// Give up retrying an upload once this much time has passed.
+    pub deadline_ms: Option<u64>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 5,
+            backoff_ms: 200,
+            max_backoff_ms: 10_000,
+            deadline_ms: Some(120_000),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Upload `data` to `key`, retrying with exponential backoff.
+    ///
+    /// `on_retry` is called with a warning for every failed attempt that will
+    /// be retried.
+    pub fn put(
+        &self,
+        store: &dyn ObjectStore,
+        key: &str,
+        data: &[u8],
+        on_retry: &mut dyn FnMut(Warning),
+    ) -> io::Result<()> {
+        let start = Instant::now();
+        let deadline = self.deadline_ms.map(Duration::from_millis);
+        let mut backoff = Duration::from_millis(self.backoff_ms);
+        let mut attempt = 1;
+        loop {
+            let err = match store.put(key, data) {
+                Ok(()) => return Ok(()),
+                Err(e) => e,
+            };
+            let out_of_time = deadline.is_some_and(|d| start.elapsed() + backoff > d);
+            if attempt >= self.attempts.max(1) || out_of_time {
+                return Err(err);
+            }
+            on_retry(Warning::output_failure(
+                "upload_retry",
+                format!(
+                    "upload of '{key}' failed (attempt {attempt}/{}): {err}; retrying in {}ms",
+                    self.attempts,
+                    backoff.as_millis()
+                ),
+            ));
+            std::thread::sleep(backoff);
+            backoff = (backoff * 2).min(Duration::from_millis(self.max_backoff_ms));
+            attempt += 1;
+        }
+    }
+}
+
+/// Uploads files to an object store, keeping a local copy of each file in a
+/// spool directory until its upload succeeds.
+///
+/// A file whose upload exhausts its retries stays in the spool and is retried
+/// once more by [`ObjectStoreSink::finish`]; anything still pending after that
+/// can be uploaded later with [`resume_uploads`]. Only files this sink
+/// spooled are uploaded; anything else in the spool directory is left alone.
+pub struct ObjectStoreSink {
+    store: Box<dyn ObjectStore>,
+    policy: RetryPolicy,
+    spool_dir: PathBuf,
+    /// Keys spooled here and not yet uploaded.
+    spooled: BTreeSet<String>,
+    warnings: Vec<Warning>,
+}
+
+impl ObjectStoreSink {
+    pub fn new(store: Box<dyn ObjectStore>, policy: RetryPolicy, spool_dir: &Path) -> Self {
+        ObjectStoreSink {
+            store,
+            policy,
+            spool_dir: spool_dir.to_path_buf(),
+            spooled: BTreeSet::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Spool `data` under `key` and try to upload it.
+    ///
+    /// Upload failures are recorded as warnings, not errors: the file stays
+    /// spooled for [`ObjectStoreSink::finish`]. Only a failure to spool is an
+    /// error.
+    pub fn put(&mut self, key: &str, data: &[u8]) -> Result<(), MrpError> {
+        let spooled = spool_path(&self.spool_dir, key)?;
+        fs::create_dir_all(spooled.parent().unwrap())
+            .and_then(|_| fs::write(&spooled, data))
+            .map_err(|e| MrpError::Output(format!("failed to spool '{key}': {e}")))?;
+        self.spooled.insert(key.to_string());
+        let warnings = &mut self.warnings;
+        match self
+            .policy
+            .put(self.store.as_ref(), key, data, &mut |w| warnings.push(w))
+        {
+            Ok(()) => {
+                let _ = fs::remove_file(&spooled);
+                self.spooled.remove(key);
+            }
+            Err(e) => self.warnings.push(Warning::output_failure(
+                "upload_deferred",
+                format!("upload of '{key}' deferred to finalize: {e}"),
+            )),
+        }
+        Ok(())
+    }
+
+    /// Retry every upload this sink deferred.
+    ///
+    /// Fails only if some upload exhausts this final round of retries; those
+    /// files remain in the spool directory.
+    pub fn finish(&mut self) -> Result<(), MrpError> {
+        let keys = self.spooled.iter().cloned().collect();
+        let warnings = &mut self.warnings;
+        let report = upload_spooled(
+            &self.spool_dir,
+            keys,
+            self.store.as_ref(),
+            &self.policy,
+            |w| warnings.push(w),
+        )?;
+        for key in &report.uploaded {
+            self.spooled.remove(key);
+        }
+        report.into_result(&self.spool_dir)
+    }
+
+    /// Keys still waiting to be uploaded.
+    pub fn pending(&self) -> Result<Vec<String>, MrpError> {
+        Ok(self.spooled.iter().cloned().collect())
+    }
+
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    /// Remove and return the warnings recorded so far.
+    pub(crate) fn take_warnings(&mut self) -> Vec<Warning> {
+        std::mem::take(&mut self.warnings)
+    }
+}
+
+/// Outputs of an object-store output spec, under the spec's `"prefix"`.
+///
+/// Whole files are uploaded as they are written. Streamed files (CSV and
+/// JSON-lines writers) are buffered in memory and uploaded when closed, or
+/// at finalize.
+pub(crate) struct ObjectOutput {
+    sink: RefCell<ObjectStoreSink>,
+    prefix: String,
+    buffers: MemoryOutputs,
+}
+
+impl ObjectOutput {
+    /// Upload to `store` under `prefix`, with the spec's `"retry"` policy
+    /// and `"spool_dir"`, else a fresh directory in `scratch`. Finalize
+    /// keeps the scratch directory if an upload never succeeds, for
+    /// [`resume_uploads`].
+    pub(crate) fn new(
+        store: Box<dyn ObjectStore>,
+        spec: &Value,
+        prefix: String,
+        scratch: &Scratch,
+    ) -> Result<Self, MrpError> {
+        let policy = spec
+            .get("retry")
+            .map(|retry| serde_json::from_value(retry.clone()))
+            .transpose()
+            .map_err(|e| MrpError::Config(format!("invalid output retry policy: {e}")))?
+            .unwrap_or_default();
+        let spool_dir = match spec.get("spool_dir").and_then(|v| v.as_str()) {
+            Some(dir) => PathBuf::from(dir),
+            None => scratch.file("spool")?,
+        };
+        Ok(ObjectOutput {
+            sink: RefCell::new(ObjectStoreSink::new(store, policy, &spool_dir)),
+            prefix,
+            buffers: MemoryOutputs::default(),
+        })
+    }
+
+    fn key(&self, filename: &str) -> String {
+        format!("{}{filename}", self.prefix)
+    }
+
+    pub(crate) fn put(&self, filename: &str, data: &[u8]) -> Result<(), MrpError> {
+        self.sink.borrow_mut().put(&self.key(filename), data)
+    }
+
+    /// A writer buffering `filename` until [`ObjectOutput::upload`].
+    pub(crate) fn writer(&self, filename: &str) -> MemoryWriter {
+        MemoryWriter::create(self.buffers.clone(), filename)
+    }
+
+    /// Upload the buffered `filename`, if there is one.
+    pub(crate) fn upload(&self, filename: &str) -> Result<(), MrpError> {
+        let data = self.buffers.borrow_mut().remove(filename);
+        match data {
+            Some(data) => self.put(filename, &data),
+            None => Ok(()),
+        }
+    }
+
+    /// Upload every buffered file, then retry any deferred uploads.
+    pub(crate) fn finish(&self) -> Result<(), MrpError> {
+        let mut filenames: Vec<String> = self.buffers.borrow().keys().cloned().collect();
+        filenames.sort();
+        for filename in filenames {
+            self.upload(&filename)?;
+        }
+        self.sink.borrow_mut().finish()
+    }
+
+    pub(crate) fn take_warnings(&self) -> Vec<Warning> {
+        self.sink.borrow_mut().take_warnings()
+    }
+}
+
+/// Outcome of uploading a spool directory.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ResumeReport {
+    pub uploaded: Vec<String>,
+    pub failed: Vec<String>,
+}
+
+impl ResumeReport {
+    fn into_result(self, spool_dir: &Path) -> Result<(), MrpError> {
+        if self.failed.is_empty() {
+            return Ok(());
+        }
+        Err(MrpError::Output(format!(
+            "uploads failed after retries: {} (spooled in {})",
+            self.failed.join(", "),
+            spool_dir.display()
+        )))
+    }
+}
+
+/// Upload whatever a previous run left in `spool_dir`.
+pub fn resume_uploads(
+    spool_dir: &Path,
+    store: &dyn ObjectStore,
+    policy: &RetryPolicy,
+) -> Result<ResumeReport, MrpError> {
+    upload_spooled(spool_dir, spooled_keys(spool_dir)?, store, policy, |w| {
+        eprintln!("warning [{}]: {}", w.code, w.message)
+    })
+}
+
+/// Upload `keys` from `spool_dir`, removing each file once uploaded.
+fn upload_spooled(
+    spool_dir: &Path,
+    keys: Vec<String>,
+    store: &dyn ObjectStore,
+    policy: &RetryPolicy,
+    mut on_warning: impl FnMut(Warning),
+) -> Result<ResumeReport, MrpError> {
+    let mut report = ResumeReport::default();
+    for key in keys {
+        let path = spool_dir.join(&key);
+        let data = fs::read(&path)
+            .map_err(|e| MrpError::Output(format!("failed to read spooled '{key}': {e}")))?;
+        match policy.put(store, &key, &data, &mut on_warning) {
+            Ok(()) => {
+                let _ = fs::remove_file(&path);
+                report.uploaded.push(key);
+            }
+            Err(e) => {
+                on_warning(Warning::output_failure(
+                    "upload_failed",
+                    format!("upload of '{key}' failed: {e}"),
+                ));
+                report.failed.push(key);
+            }
+        }
+    }
+    Ok(report)
+}
+
+fn spool_path(spool_dir: &Path, key: &str) -> Result<PathBuf, MrpError> {
+    let relative = Path::new(key);
+    if key.is_empty()
+        || relative.is_absolute()
+        || relative
+            .components()
+            .any(|c| !matches!(c, std::path::Component::Normal(_)))
+    {
+        return Err(MrpError::Input(format!("invalid object key: {key:?}")));
+    }
+    Ok(spool_dir.join(relative))
+}
+
+/// Spooled keys in sorted order, using `/` separators.
+fn spooled_keys(spool_dir: &Path) -> Result<Vec<String>, MrpError> {
+    fn walk(dir: &Path, prefix: &str, keys: &mut Vec<String>) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let key = format!("{prefix}{name}");
+            if entry.file_type()?.is_dir() {
+                walk(&entry.path(), &format!("{key}/"), keys)?;
+            } else {
+                keys.push(key);
+            }
+        }
+        Ok(())
+    }
+    let mut keys = Vec::new();
+    if spool_dir.exists() {
+        walk(spool_dir, "", &mut keys)
+            .map_err(|e| MrpError::Output(format!("failed to read spool dir: {e}")))?;
+    }
+    keys.sort();
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+    use std::rc::Rc;
+
+    use super::*;
+
+    type Objects = Rc<RefCell<BTreeMap<String, Vec<u8>>>>;
+
+    /// Fails the first `failures` puts, then stores objects in memory.
+    struct FlakyStore {
+        failures: RefCell<u32>,
+        objects: Objects,
+    }
+
+    impl FlakyStore {
+        fn new(failures: u32) -> (Self, Objects) {
+            let objects = Rc::new(RefCell::new(BTreeMap::new()));
+            let store = FlakyStore {
+                failures: RefCell::new(failures),
+                objects: objects.clone(),
+            };
+            (store, objects)
+        }
+    }
+
+    impl ObjectStore for FlakyStore {
+        fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+            let mut failures = self.failures.borrow_mut();
+            if *failures > 0 {
+                *failures -= 1;
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "connection reset"));
+            }
+            self.objects
+                .borrow_mut()
+                .insert(key.to_string(), data.to_vec());
+            Ok(())
+        }
+    }
+
+    fn fast_policy(attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            attempts,
+            backoff_ms: 0,
+            max_backoff_ms: 0,
+            deadline_ms: None,
+        }
+    }
+
+    #[test]
+    fn test_retry_then_succeed() {
+        let spool = tempfile::tempdir().unwrap();
+        let (store, objects) = FlakyStore::new(2);
+        let mut sink = ObjectStoreSink::new(Box::new(store), fast_policy(3), spool.path());
+        sink.put("runs/a.csv", b"a,b\n").unwrap();
+        assert_eq!(objects.borrow()["runs/a.csv"], b"a,b\n");
+        assert!(sink.pending().unwrap().is_empty());
+        let codes: Vec<&str> = sink.warnings().iter().map(|w| w.code.as_str()).collect();
+        assert_eq!(codes, vec!["upload_retry", "upload_retry"]);
+        sink.finish().unwrap();
+    }
+
+    #[test]
+    fn test_deferred_upload_succeeds_at_finish() {
+        let spool = tempfile::tempdir().unwrap();
+        let (store, objects) = FlakyStore::new(2);
+        let mut sink = ObjectStoreSink::new(Box::new(store), fast_policy(2), spool.path());
+        sink.put("out.csv", b"x").unwrap();
+        assert_eq!(sink.pending().unwrap(), vec!["out.csv"]);
+        assert_eq!(fs::read(spool.path().join("out.csv")).unwrap(), b"x");
+        sink.finish().unwrap();
+        assert_eq!(objects.borrow()["out.csv"], b"x");
+        assert!(sink.pending().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_finish_uploads_only_own_keys() {
+        let spool = tempfile::tempdir().unwrap();
+        fs::write(spool.path().join("stale.csv"), b"left by another run").unwrap();
+        let (store, objects) = FlakyStore::new(1);
+        let mut sink = ObjectStoreSink::new(Box::new(store), fast_policy(1), spool.path());
+        sink.put("out.csv", b"x").unwrap();
+        assert_eq!(sink.pending().unwrap(), vec!["out.csv"]);
+        sink.finish().unwrap();
+        assert_eq!(objects.borrow().keys().collect::<Vec<_>>(), vec!["out.csv"]);
+        assert!(spool.path().join("stale.csv").is_file());
+    }
+
+    #[test]
+    fn test_always_failing_store() {
+        let spool = tempfile::tempdir().unwrap();
+        let (store, _) = FlakyStore::new(u32::MAX);
+        let mut sink = ObjectStoreSink::new(Box::new(store), fast_policy(3), spool.path());
+        sink.put("nested/out.csv", b"x").unwrap();
+        let err = sink.finish().unwrap_err();
+        assert!(err.to_string().contains("nested/out.csv"));
+        // Exhausted retries may succeed later; see resume_uploads
+        assert_eq!(err.code(), "MRP-IO-003");
+        assert!(err.retryable());
+        assert!(
+            sink.warnings()
+                .iter()
+                .all(|w| w.error_code.as_deref() == Some("MRP-IO-003"))
+        );
+        assert_eq!(sink.pending().unwrap(), vec!["nested/out.csv"]);
+
+        // A later resume against a healthy store drains the spool
+        let (healthy, objects) = FlakyStore::new(0);
+        let report = resume_uploads(spool.path(), &healthy, &fast_policy(1)).unwrap();
+        assert_eq!(report.uploaded, vec!["nested/out.csv"]);
+        assert!(report.failed.is_empty());
+        assert_eq!(objects.borrow()["nested/out.csv"], b"x");
+        assert!(spooled_keys(spool.path()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_deadline_stops_retries() {
+        let (store, _) = FlakyStore::new(u32::MAX);
+        let policy = RetryPolicy {
+            attempts: 100,
+            backoff_ms: 50,
+            max_backoff_ms: 50,
+            deadline_ms: Some(120),
+        };
+        let mut retries = 0;
+        assert!(policy.put(&store, "k", b"", &mut |_| retries += 1).is_err());
+        assert!(retries <= 3);
+    }
+
+    #[test]
+    fn test_invalid_key_rejected() {
+        let spool = tempfile::tempdir().unwrap();
+        let (store, _) = FlakyStore::new(0);
+        let mut sink = ObjectStoreSink::new(Box::new(store), fast_policy(1), spool.path());
+        let err = sink.put("../escape", b"x").unwrap_err();
+        assert_eq!(err.code(), "MRP-INPUT-001");
+        assert!(!err.retryable());
+    }
+
+    #[test]
+    fn test_policy_from_json() {
+        let policy: RetryPolicy =
+            serde_json::from_value(serde_json::json!({"attempts": 2})).unwrap();
+        assert_eq!(policy.attempts, 2);
+        assert_eq!(policy.backoff_ms, RetryPolicy::default().backoff_ms);
+    }
+}
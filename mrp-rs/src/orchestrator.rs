@@ -2,6 +2,7 @@ use std::path::Path;
 
 use serde_json::Value;
 
+use crate::cache::RunCache;
 use crate::config::{apply_overrides, build_run_json_with_options, load_toml, resolve_input};
 use crate::runtime::{resolve_runtime as rt_resolve, resolve_runtime_with_profile, RunResult, Runtime};
 use crate::stager::{cleanup, stage_files};
@@ -194,7 +195,11 @@ impl Orchestrator for DefaultOrchestrator {
     }
 
     fn run(&self, run_json: &Value, runtime: &dyn Runtime) -> Result<RunResult, MrpError> {
-        let result = runtime.run(run_json);
+        let result = match RunCache::from_run(run_json) {
+            Ok(Some(cache)) => cache.run(run_json, runtime),
+            Ok(None) => runtime.run(run_json),
+            Err(e) => Err(e),
+        };
         cleanup();
         result
     }
@@ -0,0 +1,159 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::panic::{self, PanicHookInfo};
+use std::path::PathBuf;
+use std::rc::Weak;
+use std::sync::Once;
+
+use crate::Environment;
+use crate::csv::CsvWriter;
+use crate::formats::PanicRecord;
+
+const PANIC: &str = "panic.json";
+
+/// The run a thread's panics are reported against.
+struct Watched {
+    output_dir: Option<PathBuf>,
+    seed: Option<u64>,
+    replicate: u64,
+    csv_writers: Weak<RefCell<HashMap<String, CsvWriter>>>,
+}
+
+thread_local! {
+    static WATCHED: RefCell<Option<Watched>> = const { RefCell::new(None) };
+}
+
+static INSTALL: Once = Once::new();
+
+/// Report panics on the calling thread as JSON.
+///
+/// The writers from [`Environment::create_csv`] are flushed first, so their
+/// files end on a whole row. A [`PanicRecord`] with the message, location,
+/// seed and replicate is then written as one line to stderr and, if `env`
+/// has an output directory, to `panic.json` in it. The previous hook still
+/// runs and the panic unwinds as usual, so the process exits with status
+/// 101.
+///
+/// Other threads' panics are left to the previous hook. Calling again
+/// replaces `env`.
+pub fn install_panic_hook<I>(env: &Environment<I>) {
+    let watched = Watched {
+        output_dir: env.output_dir(),
+        seed: env.input_json().get("seed").and_then(|v| v.as_u64()),
+        replicate: env.replicate,
+        csv_writers: env.csv_writers(),
+    };
+    WATCHED.with(|w| *w.borrow_mut() = Some(watched));
+    INSTALL.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            report(info);
+            previous(info);
+        }));
+    });
+}
+
+/// Nothing here may panic, as a panic inside the hook aborts.
+fn report(info: &PanicHookInfo) {
+    let _ = WATCHED.try_with(|watched| {
+        let Ok(watched) = watched.try_borrow() else {
+            return;
+        };
+        let Some(watched) = watched.as_ref() else {
+            return;
+        };
+        if let Some(writers) = watched.csv_writers.upgrade() {
+            // Borrowed if a managed writer panicked; its rows are then
+            // flushed when it drops during unwinding
+            if let Ok(mut writers) = writers.try_borrow_mut() {
+                for writer in writers.values_mut() {
+                    let _ = writer.try_flush();
+                }
+            }
+        }
+        let record = PanicRecord::new(
+            message(info),
+            info.location().map(|l| l.to_string()),
+            watched.seed,
+            watched.replicate,
+        );
+        if let Ok(line) = serde_json::to_string(&record) {
+            let _ = writeln!(io::stderr(), "{line}");
+        }
+        if let (Some(dir), Ok(json)) = (&watched.output_dir, serde_json::to_vec_pretty(&record)) {
+            let _ = fs::create_dir_all(dir).and_then(|_| fs::write(dir.join(PANIC), json));
+        }
+    });
+}
+
+fn message(info: &PanicHookInfo) -> String {
+    let payload = info.payload();
+    payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "non-string panic payload".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::AssertUnwindSafe;
+
+    use super::*;
+    use crate::formats;
+
+    #[test]
+    fn test_panic_recorded_and_csv_flushed() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let mut env = Environment::try_from_json(serde_json::json!({
+            "input": { "seed": 7, "replicate": 2 },
+            "output": { "spec": "filesystem", "dir": out.to_str().unwrap() },
+        }))
+        .unwrap();
+        install_panic_hook(&env);
+        env.create_csv("cases", "cases.csv", &["step", "cases"]);
+        env.write_csv_row("cases", &["0", "10"]);
+        env.write_csv_row("cases", &["1", "12"]);
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            // Read while the writer is still open and unflushed
+            let written = fs::read_to_string(out.join("cases.csv")).unwrap_or_default();
+            panic!("diverged at step 2 ({} bytes written)", written.len());
+        }));
+        assert!(result.is_err());
+
+        let record = formats::read_panic(&out.join(PANIC)).unwrap();
+        assert_eq!(record.message, "diverged at step 2 (0 bytes written)");
+        assert!(
+            record
+                .location
+                .as_deref()
+                .unwrap()
+                .contains("panic_hook.rs"),
+            "{record:?}"
+        );
+        assert_eq!(record.seed, Some(7));
+        assert_eq!(record.replicate, 2);
+        assert_eq!(
+            fs::read_to_string(out.join("cases.csv")).unwrap(),
+            "step,cases\n0,10\n1,12\n"
+        );
+    }
+
+    #[test]
+    fn test_other_threads_not_watched() {
+        let dir = tempfile::tempdir().unwrap();
+        let env = Environment::try_from_json(serde_json::json!({
+            "input": {},
+            "output": { "spec": "filesystem", "dir": dir.path().to_str().unwrap() },
+        }))
+        .unwrap();
+        install_panic_hook(&env);
+        let result = std::thread::spawn(|| panic!("elsewhere")).join();
+        assert!(result.is_err());
+        assert!(!dir.path().join(PANIC).exists());
+    }
+}
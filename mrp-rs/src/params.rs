@@ -0,0 +1,557 @@
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+
+use crate::MrpError;
+use crate::dist::DistributionSpec;
+use crate::input_error;
+use crate::matrix::Matrix;
+
+/// A parameter that is missing or does not have the type asked for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParamError {
+    /// Dotted path of the parameter, or of the part of it that failed,
+    /// e.g. `observation` when `observation.delay` was asked for but
+    /// `observation` is not an object.
+    pub key: String,
+    /// The type asked for, e.g. `f64` or `Vec<f64>`.
+    pub expected: String,
+    /// The JSON found at `key`; `None` if it is missing.
+    pub found: Option<Value>,
+    /// What is wrong, without the key.
+    pub message: String,
+}
+
+impl std::fmt::Display for ParamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.found {
+            None => write!(f, "parameter '{}': {}", self.key, self.message),
+            Some(found) => write!(
+                f,
+                "parameter '{}': {} (found {found})",
+                self.key, self.message
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ParamError {}
+
+impl From<ParamError> for MrpError {
+    fn from(e: ParamError) -> Self {
+        MrpError::Input(e.to_string())
+    }
+}
+
+/// Every failure collected by a [`ParamCollector`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParamErrors {
+    pub errors: Vec<ParamError>,
+}
+
+impl std::fmt::Display for ParamErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} invalid parameters:", self.errors.len())?;
+        for e in &self.errors {
+            write!(f, "\n  {e}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ParamErrors {}
+
+impl From<ParamErrors> for MrpError {
+    fn from(e: ParamErrors) -> Self {
+        MrpError::Input(e.to_string())
+    }
+}
+
+/// Reads a block of parameters, keeping going past failures so they can
+/// all be reported at once by [`ParamCollector::finish`].
+///
+/// Failed reads return `None` or the default; their values must not be
+/// used unless `finish` succeeds.
+pub struct ParamCollector<'a> {
+    input: &'a Value,
+    errors: Vec<ParamError>,
+}
+
+impl<'a> ParamCollector<'a> {
+    pub(crate) fn new(input: &'a Value) -> Self {
+        ParamCollector {
+            input,
+            errors: Vec::new(),
+        }
+    }
+
+    /// The parameter at `key`, recording an error if it is missing or has
+    /// the wrong type.
+    pub fn require<T: DeserializeOwned>(&mut self, key: &str) -> Option<T> {
+        get(self.input, key).map_err(|e| self.errors.push(e)).ok()
+    }
+
+    /// The parameter at `key`, or `default` if it is missing, recording an
+    /// error if it has the wrong type.
+    pub fn get_or<T: DeserializeOwned>(&mut self, key: &str, default: T) -> T {
+        match get_opt(self.input, key) {
+            Ok(value) => value.unwrap_or(default),
+            Err(e) => {
+                self.errors.push(e);
+                default
+            }
+        }
+    }
+
+    pub fn finish(self) -> Result<(), ParamErrors> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ParamErrors {
+                errors: self.errors,
+            })
+        }
+    }
+}
+
+/// The parameter at dotted path `key` in `input`.
+pub(crate) fn get<T: DeserializeOwned>(input: &Value, key: &str) -> Result<T, ParamError> {
+    get_opt(input, key)?.ok_or_else(|| missing::<T>(key))
+}
+
+/// The parameter at `key`, or `None` if it is missing. A key that differs
+/// only in case from one in the input is an error, not missing, so a typo
+/// like `R0` does not silently fall back to a default.
+pub(crate) fn get_opt<T: DeserializeOwned>(
+    input: &Value,
+    key: &str,
+) -> Result<Option<T>, ParamError> {
+    let Some(value) = lookup(input, key)? else {
+        return match near_miss(input, key) {
+            Some(similar) => Err(ParamError {
+                message: format!("not found, but the input has '{similar}'"),
+                ..missing::<T>(key)
+            }),
+            None => Ok(None),
+        };
+    };
+    T::deserialize(value).map(Some).map_err(|e| ParamError {
+        key: key.to_string(),
+        expected: type_name::<T>(),
+        found: Some(value.clone()),
+        message: e.to_string(),
+    })
+}
+
+/// The value at `key`, or `None` if its last segment is missing. An
+/// earlier segment that is missing or not an object is an error naming
+/// that segment.
+pub(crate) fn lookup<'v>(input: &'v Value, key: &str) -> Result<Option<&'v Value>, ParamError> {
+    let mut value = input;
+    let segments: Vec<&str> = key.split('.').collect();
+    for (i, segment) in segments.iter().enumerate() {
+        let Some(map) = value.as_object() else {
+            let parent = segments[..i].join(".");
+            return Err(ParamError {
+                key: parent,
+                expected: "object".to_string(),
+                found: Some(value.clone()),
+                message: format!("is not an object, so has no '{segment}'"),
+            });
+        };
+        match map.get(*segment) {
+            Some(next) => value = next,
+            None if i + 1 == segments.len() => return Ok(None),
+            None => {
+                return Err(ParamError {
+                    key: segments[..=i].join("."),
+                    expected: "object".to_string(),
+                    found: None,
+                    message: format!("missing, so '{key}' cannot be read"),
+                });
+            }
+        }
+    }
+    Ok(Some(value))
+}
+
+/// The object at dotted path `path`.
+pub(crate) fn section<'v>(
+    input: &'v Value,
+    path: &str,
+) -> Result<&'v Map<String, Value>, ParamError> {
+    let not_a_section = |found: Option<&Value>, message: &str| ParamError {
+        key: path.to_string(),
+        expected: "object".to_string(),
+        found: found.cloned(),
+        message: message.to_string(),
+    };
+    let value = lookup(input, path)?.ok_or_else(|| not_a_section(None, "missing section"))?;
+    value
+        .as_object()
+        .ok_or_else(|| not_a_section(Some(value), "is not an object"))
+}
+
+/// The section at `path` as `T`, with any failure located within it.
+pub(crate) fn section_as<T: DeserializeOwned>(input: &Value, path: &str) -> Result<T, ParamError> {
+    let value = section(input, path)?;
+    input_error::deserialize(&Value::Object(value.clone())).map_err(|e| ParamError {
+        key: match e.path.as_str() {
+            "" => path.to_string(),
+            inner => format!("{path}.{inner}"),
+        },
+        expected: type_name::<T>(),
+        found: Some(e.fragment),
+        message: e.message,
+    })
+}
+
+/// Checks for a probability mass function, for
+/// [`crate::Environment::get_pmf`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct PmfOptions {
+    /// How far the sum may be from 1.
+    pub tolerance: f64,
+    /// Divide by the sum when it is outside `tolerance`, rather than fail.
+    pub renormalize: bool,
+    /// Fewest entries allowed; a PMF is never empty.
+    pub min_len: usize,
+}
+
+impl Default for PmfOptions {
+    fn default() -> Self {
+        PmfOptions {
+            tolerance: 1e-6,
+            renormalize: false,
+            min_len: 1,
+        }
+    }
+}
+
+impl PmfOptions {
+    /// `pmf`, renormalized if allowed, or what is wrong with it.
+    pub fn check(&self, pmf: &[f64]) -> Result<Vec<f64>, String> {
+        if pmf.len() < self.min_len.max(1) {
+            return Err(format!(
+                "has {} entries, needs at least {}",
+                pmf.len(),
+                self.min_len.max(1)
+            ));
+        }
+        if let Some(i) = pmf.iter().position(|p| !(p.is_finite() && *p >= 0.0)) {
+            return Err(format!("entry {i} is {}, must be non-negative", pmf[i]));
+        }
+        let total: f64 = pmf.iter().sum();
+        if (total - 1.0).abs() <= self.tolerance {
+            return Ok(pmf.to_vec());
+        }
+        if self.renormalize && total > 0.0 {
+            return Ok(pmf.iter().map(|p| p / total).collect());
+        }
+        Err(format!(
+            "sums to {total}, not 1 (tolerance {})",
+            self.tolerance
+        ))
+    }
+}
+
+/// The PMF at `key`, checked as `options` say.
+pub(crate) fn pmf(input: &Value, key: &str, options: &PmfOptions) -> Result<Vec<f64>, ParamError> {
+    let values: Vec<f64> = get(input, key)?;
+    options.check(&values).map_err(|message| ParamError {
+        key: key.to_string(),
+        expected: "PMF".to_string(),
+        found: lookup(input, key).ok().flatten().cloned(),
+        message,
+    })
+}
+
+/// The distribution at `key`, with its parameters checked.
+pub(crate) fn distribution(input: &Value, key: &str) -> Result<DistributionSpec, ParamError> {
+    let spec: DistributionSpec = get(input, key)?;
+    spec.validate().map_err(|message| ParamError {
+        key: key.to_string(),
+        expected: "DistributionSpec".to_string(),
+        found: lookup(input, key).ok().flatten().cloned(),
+        message,
+    })?;
+    Ok(spec)
+}
+
+/// The matrix at `key`, with any bad entry located within it.
+pub(crate) fn matrix(input: &Value, key: &str) -> Result<Matrix, ParamError> {
+    let value = lookup(input, key)?.ok_or_else(|| missing::<Matrix>(key))?;
+    Matrix::from_value(value).map_err(|e| ParamError {
+        key: format!("{key}{}", e.at),
+        expected: "Matrix".to_string(),
+        found: Some(e.found),
+        message: e.message,
+    })
+}
+
+/// Dotted paths of keys in `given` with no counterpart in `used`,
+/// recursing into objects present in both.
+pub(crate) fn unused_keys(given: &Value, used: &Value) -> Vec<String> {
+    fn walk(given: &Value, used: &Value, path: &str, unused: &mut Vec<String>) {
+        let (Some(given), Some(used)) = (given.as_object(), used.as_object()) else {
+            return;
+        };
+        for (key, value) in given {
+            let key_path = match path {
+                "" => key.clone(),
+                _ => format!("{path}.{key}"),
+            };
+            match used.get(key) {
+                Some(used) => walk(value, used, &key_path, unused),
+                None => unused.push(key_path),
+            }
+        }
+    }
+    let mut unused = Vec::new();
+    walk(given, used, "", &mut unused);
+    unused
+}
+
+fn missing<T>(key: &str) -> ParamError {
+    ParamError {
+        key: key.to_string(),
+        expected: type_name::<T>(),
+        found: None,
+        message: format!("missing (expected {})", type_name::<T>()),
+    }
+}
+
+/// A key beside the missing one at `key` that differs only in case.
+fn near_miss(input: &Value, key: &str) -> Option<String> {
+    let (prefix, last) = match key.rsplit_once('.') {
+        Some((parent, last)) => (format!("{parent}."), last),
+        None => (String::new(), key),
+    };
+    let parent = match prefix.strip_suffix('.') {
+        Some(parent) => lookup(input, parent).ok()??,
+        None => input,
+    };
+    let found = parent
+        .as_object()?
+        .keys()
+        .find(|k| k.eq_ignore_ascii_case(last))?;
+    Some(format!("{prefix}{found}"))
+}
+
+/// `T`'s name without module paths, e.g. `Vec<f64>`.
+fn type_name<T>() -> String {
+    let full = std::any::type_name::<T>();
+    let mut name = String::with_capacity(full.len());
+    // Where in `name` the current path began
+    let mut start = 0;
+    let mut chars = full.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == ':' && chars.peek() == Some(&':') {
+            chars.next();
+            name.truncate(start);
+        } else {
+            name.push(c);
+            if !(c.is_alphanumeric() || c == '_') {
+                start = name.len();
+            }
+        }
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn input() -> Value {
+        json!({
+            "r0": 2.0,
+            "steps": "100",
+            "observation": { "delay_pmf": [0.2, 0.8], "reporting": 0.4 },
+        })
+    }
+
+    #[test]
+    fn test_wrong_type_names_key_type_and_value() {
+        let e = get::<u32>(&input(), "steps").unwrap_err();
+        assert_eq!(e.key, "steps");
+        assert_eq!(e.expected, "u32");
+        assert_eq!(e.found, Some(json!("100")));
+        assert_eq!(
+            e.to_string(),
+            "parameter 'steps': invalid type: string \"100\", expected u32 (found \"100\")"
+        );
+        let e = get::<Vec<u64>>(&input(), "observation.delay_pmf").unwrap_err();
+        assert_eq!(e.expected, "Vec<u64>");
+    }
+
+    #[test]
+    fn test_missing_key() {
+        let e = get::<f64>(&input(), "gamma").unwrap_err();
+        assert_eq!(e.found, None);
+        assert_eq!(e.to_string(), "parameter 'gamma': missing (expected f64)");
+        assert_eq!(get_opt::<f64>(&input(), "gamma"), Ok(None));
+
+        // A case-only difference is a typo, not a missing key
+        let e = get_opt::<f64>(&json!({ "R0": 2.0 }), "r0").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "parameter 'r0': not found, but the input has 'R0'"
+        );
+    }
+
+    #[test]
+    fn test_nested_keys() {
+        let pmf: Vec<f64> = get(&input(), "observation.delay_pmf").unwrap();
+        assert_eq!(pmf, vec![0.2, 0.8]);
+        assert_eq!(get_opt::<f64>(&input(), "observation.delay"), Ok(None));
+
+        let e = get::<f64>(&input(), "r0.mean").unwrap_err();
+        assert_eq!(e.key, "r0");
+        assert_eq!(e.message, "is not an object, so has no 'mean'");
+        let e = get::<f64>(&input(), "transmission.r0").unwrap_err();
+        assert_eq!(e.key, "transmission");
+        assert_eq!(e.found, None);
+        let e = get_opt::<f64>(&input(), "observation.Reporting").unwrap_err();
+        assert!(e.to_string().contains("'observation.reporting'"), "{e}");
+    }
+
+    #[test]
+    fn test_collector_reports_every_failure() {
+        let input = input();
+        let mut params = ParamCollector::new(&input);
+        let r0: Option<f64> = params.require("r0");
+        let gamma: Option<f64> = params.require("gamma");
+        let steps: u32 = params.get_or("steps", 50);
+        let days: u32 = params.get_or("days", 7);
+        assert_eq!((r0, gamma, steps, days), (Some(2.0), None, 50, 7));
+        let errors = params.finish().unwrap_err();
+        let keys: Vec<&str> = errors.errors.iter().map(|e| e.key.as_str()).collect();
+        assert_eq!(keys, vec!["gamma", "steps"]);
+        assert!(errors.to_string().starts_with("2 invalid parameters:"));
+
+        let mut params = ParamCollector::new(&input);
+        let _: Option<f64> = params.require("observation.reporting");
+        assert!(params.finish().is_ok());
+    }
+
+    #[test]
+    fn test_pmf_checks() {
+        let pmfs = json!({
+            "negative": [0.5, -0.1, 0.6],
+            "short": [0.98],
+            "empty": [],
+            "zero": [0.0, 0.0],
+        });
+        let strict = PmfOptions::default();
+        let e = pmf(&pmfs, "negative", &strict).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "parameter 'negative': entry 1 is -0.1, must be non-negative \
+             (found [0.5,-0.1,0.6])"
+        );
+        let e = pmf(&pmfs, "short", &strict).unwrap_err();
+        assert!(e.message.starts_with("sums to 0.98"), "{e}");
+        let renormalize = PmfOptions {
+            renormalize: true,
+            ..PmfOptions::default()
+        };
+        assert_eq!(pmf(&pmfs, "short", &renormalize).unwrap(), vec![1.0]);
+        let e = pmf(&pmfs, "empty", &renormalize).unwrap_err();
+        assert_eq!(e.message, "has 0 entries, needs at least 1");
+        assert!(pmf(&pmfs, "zero", &renormalize).is_err());
+        let long = PmfOptions {
+            min_len: 3,
+            ..PmfOptions::default()
+        };
+        let e = pmf(&input(), "observation.delay_pmf", &long).unwrap_err();
+        assert_eq!(e.message, "has 2 entries, needs at least 3");
+        assert_eq!(
+            pmf(&input(), "observation.delay_pmf", &strict).unwrap(),
+            vec![0.2, 0.8]
+        );
+        assert!(pmf(&input(), "missing", &strict).is_err());
+    }
+
+    #[test]
+    fn test_matrix_errors_name_entry() {
+        let input = json!({
+            "contacts": { "school": [[2.0, 0.5], [0.5, "1"]] },
+            "mobility": { "rows": 1, "cols": 2, "data": [0.9, 0.1] },
+        });
+        let e = matrix(&input, "contacts.school").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "parameter 'contacts.school[1][1]': is not a finite number (found \"1\")"
+        );
+        assert_eq!(matrix(&input, "mobility").unwrap().ncols(), 2);
+        assert_eq!(
+            matrix(&input, "contacts.home").unwrap_err().to_string(),
+            "parameter 'contacts.home': missing (expected Matrix)"
+        );
+    }
+
+    #[test]
+    fn test_distribution_checked() {
+        let input = json!({
+            "incubation": { "distribution": "lognormal", "mean": 5.2, "sd": 3.9 },
+            "delay": { "distribution": "gamma", "mean": 4.2, "sd": -1 },
+        });
+        assert_eq!(
+            distribution(&input, "incubation").unwrap(),
+            DistributionSpec::LogNormal { mean: 5.2, sd: 3.9 }
+        );
+        let e = distribution(&input, "delay").unwrap_err();
+        assert_eq!(e.expected, "DistributionSpec");
+        assert_eq!(e.message, "sd must be positive, got -1");
+    }
+
+    #[test]
+    fn test_type_name() {
+        assert_eq!(type_name::<Option<Vec<String>>>(), "Option<Vec<String>>");
+        assert_eq!(
+            type_name::<std::collections::BTreeMap<String, f64>>(),
+            "BTreeMap<String, f64>"
+        );
+    }
+
+    #[test]
+    fn test_sections() {
+        let input = input();
+        let observation = section(&input, "observation").unwrap();
+        assert_eq!(observation["reporting"], 0.4);
+
+        let e = section(&input, "observation.delay_pmf").unwrap_err();
+        assert_eq!(e.key, "observation.delay_pmf");
+        assert_eq!(e.message, "is not an object");
+        let e = section(&input, "transmission").unwrap_err();
+        assert_eq!(e.to_string(), "parameter 'transmission': missing section");
+        let e = section(&input, "r0.prior").unwrap_err();
+        assert_eq!(e.key, "r0");
+        let e = section(&input, "transmission.prior").unwrap_err();
+        assert_eq!(e.key, "transmission");
+    }
+
+    #[test]
+    fn test_typed_section() {
+        #[derive(Debug, PartialEq, serde::Deserialize)]
+        struct Observation {
+            delay_pmf: Vec<f64>,
+            reporting: f64,
+        }
+        assert_eq!(
+            section_as::<Observation>(&input(), "observation").unwrap(),
+            Observation {
+                delay_pmf: vec![0.2, 0.8],
+                reporting: 0.4
+            }
+        );
+
+        let input = json!({ "observation": { "delay_pmf": [0.2, "0.8"], "reporting": 0.4 } });
+        let e = section_as::<Observation>(&input, "observation").unwrap_err();
+        assert_eq!(e.key, "observation.delay_pmf[1]");
+        assert_eq!(e.found, Some(json!("0.8")));
+        assert_eq!(e.expected, "Observation");
+    }
+}
@@ -0,0 +1,184 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::calendar::rfc3339_utc;
+
+/// An input file read through the Environment.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileRead {
+    pub key: String,
+    pub path: String,
+    pub sha256: String,
+}
+
+/// What went into producing one output file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Provenance {
+    /// SHA-256 of the resolved input.
+    pub input_sha256: String,
+    pub mrp_version: String,
+    pub seed: Option<u64>,
+    pub replicate: u64,
+    /// Input files read before this output was finalized.
+    pub files_read: Vec<FileRead>,
+    pub started_at: String,
+    pub finished_at: Option<String>,
+}
+
+/// A logical clock, shared with split workers so that their writes are
+/// ordered against the parent's reads.
+pub(crate) type Clock = Arc<AtomicU64>;
+
+fn tick(clock: &Clock) -> u64 {
+    clock.fetch_add(1, Ordering::SeqCst) + 1
+}
+
+/// When one output was opened and closed.
+pub(crate) struct OutputSpan {
+    started_at: SystemTime,
+    finished: Option<(u64, SystemTime)>,
+}
+
+impl OutputSpan {
+    pub(crate) fn start(clock: &Clock) -> Self {
+        tick(clock);
+        OutputSpan {
+            started_at: SystemTime::now(),
+            finished: None,
+        }
+    }
+
+    pub(crate) fn finish(&mut self, clock: &Clock) {
+        self.finished = Some((tick(clock), SystemTime::now()));
+    }
+
+    pub(crate) fn is_finished(&self) -> bool {
+        self.finished.is_some()
+    }
+}
+
+/// Orders file reads and output lifetimes on a logical clock so provenance
+/// does not depend on timestamp resolution.
+#[derive(Default)]
+pub(crate) struct ProvenanceLog {
+    clock: Clock,
+    reads: Vec<(u64, FileRead)>,
+    outputs: BTreeMap<String, OutputSpan>,
+}
+
+impl ProvenanceLog {
+    pub(crate) fn clock(&self) -> Clock {
+        self.clock.clone()
+    }
+
+    pub(crate) fn record_read(&mut self, key: &str, path: &str, data: &[u8]) {
+        let seq = tick(&self.clock);
+        self.reads.push((
+            seq,
+            FileRead {
+                key: key.to_string(),
+                path: path.to_string(),
+                sha256: sha256_hex(data),
+            },
+        ));
+    }
+
+    pub(crate) fn start_output(&mut self, filename: &str) {
+        let span = OutputSpan::start(&self.clock);
+        self.outputs.insert(filename.to_string(), span);
+    }
+
+    pub(crate) fn finish_output(&mut self, filename: &str) {
+        if let Some(span) = self.outputs.get_mut(filename) {
+            span.finish(&self.clock);
+        }
+    }
+
+    /// Record a span a split worker timed on [`ProvenanceLog::clock`].
+    pub(crate) fn replay_output(&mut self, filename: &str, span: OutputSpan) {
+        self.outputs.insert(filename.to_string(), span);
+    }
+
+    pub(crate) fn provenance_for(
+        &self,
+        filename: &str,
+        input_sha256: String,
+        seed: Option<u64>,
+        replicate: u64,
+    ) -> Option<Provenance> {
+        let span = self.outputs.get(filename)?;
+        let cutoff = span.finished.map_or(u64::MAX, |(seq, _)| seq);
+        let mut files_read: Vec<FileRead> = Vec::new();
+        for (seq, read) in &self.reads {
+            if *seq < cutoff && !files_read.contains(read) {
+                files_read.push(read.clone());
+            }
+        }
+        Some(Provenance {
+            input_sha256,
+            mrp_version: env!("CARGO_PKG_VERSION").to_string(),
+            seed,
+            replicate,
+            files_read,
+            started_at: rfc3339_utc(span.started_at),
+            finished_at: span.finished.map(|(_, t)| rfc3339_utc(t)),
+        })
+    }
+}
+
+/// The destination of a streamed output, ending the output's span in the
+/// [`ProvenanceLog`] once it is dropped with its writer.
+pub(crate) struct SpanWriter {
+    inner: Option<Box<dyn Write>>,
+    log: Rc<RefCell<ProvenanceLog>>,
+    filename: String,
+}
+
+impl SpanWriter {
+    pub(crate) fn new(
+        inner: Box<dyn Write>,
+        log: Rc<RefCell<ProvenanceLog>>,
+        filename: &str,
+    ) -> Self {
+        SpanWriter {
+            inner: Some(inner),
+            log,
+            filename: filename.to_string(),
+        }
+    }
+
+    fn inner(&mut self) -> &mut Box<dyn Write> {
+        self.inner.as_mut().expect("writer is open until drop")
+    }
+}
+
+impl Write for SpanWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner().flush()
+    }
+}
+
+impl Drop for SpanWriter {
+    fn drop(&mut self) {
+        // Close the destination first, so the span covers its last write
+        drop(self.inner.take());
+        self.log.borrow_mut().finish_output(&self.filename);
+    }
+}
+
+/// Lowercase hex SHA-256 of `data`.
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
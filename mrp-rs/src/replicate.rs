@@ -0,0 +1,154 @@
+use crate::MrpError;
+use crate::csv::CsvWriter;
+use crate::environment::Environment;
+use crate::jsonl::JsonlWriter;
+#[cfg(feature = "rand")]
+use crate::rng::ModelRng;
+use crate::seed::derive_seed;
+use crate::template;
+
+/// One replicate of an in-process run, from
+/// [`Environment::run_replicates`].
+///
+/// Seeds match those of a standalone run with `"replicate": k`, so the
+/// replicate draws the same numbers either way. Outputs go under
+/// `replicate_{k}/`, or wherever `{replicate}` in a filename says, so
+/// replicates do not overwrite each other.
+pub struct ReplicateContext<'a, I> {
+    pub input: &'a I,
+    pub replicate: u64,
+    /// [`Environment::effective_seed`] of the standalone run.
+    pub seed: u64,
+    base_seed: u64,
+    env: &'a Environment<I>,
+}
+
+impl<'a, I> ReplicateContext<'a, I> {
+    pub(crate) fn new(
+        env: &'a Environment<I>,
+        input: &'a I,
+        replicate: u64,
+        base_seed: u64,
+    ) -> Self {
+        ReplicateContext {
+            input,
+            replicate,
+            seed: derive_seed(base_seed, replicate),
+            base_seed,
+            env,
+        }
+    }
+
+    /// The environment, for anything not scoped to the replicate.
+    pub fn env(&self) -> &'a Environment<I> {
+        self.env
+    }
+
+    /// Where `filename` is written for this replicate: expanded as
+    /// [`Environment::expand_template`] would in the standalone run, and
+    /// under `replicate_{k}/` unless it uses `{replicate}`.
+    pub fn output_name(&self, filename: &str) -> String {
+        self.try_output_name(filename)
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    pub fn try_output_name(&self, filename: &str) -> Result<String, MrpError> {
+        let name = self.env.expand_template_for(filename, self.run(), None)?;
+        if template::uses(filename, "replicate") {
+            Ok(name)
+        } else {
+            Ok(format!("replicate_{}/{name}", self.replicate))
+        }
+    }
+
+    /// Seed of the named stream, as [`Environment::stream_seed`] gives in
+    /// the standalone run.
+    pub fn stream_seed(&self, name: &str) -> u64 {
+        self.env
+            .stream_seed_for(name, self.base_seed, self.replicate)
+    }
+
+    #[cfg(feature = "rand")]
+    pub fn rng(&self) -> ModelRng {
+        ModelRng::new(self.env.rng_kind(), self.seed)
+    }
+
+    #[cfg(feature = "rand")]
+    pub fn rng_stream(&self, name: &str) -> ModelRng {
+        ModelRng::new(self.env.rng_kind(), self.stream_seed(name))
+    }
+
+    /// Output schemas and CSV filters declared for `filename` apply to each
+    /// replicate's copy of it.
+    pub fn write(&self, filename: &str, data: &[u8]) {
+        self.try_write(filename, data)
+            .unwrap_or_else(|e| panic!("{e}"));
+    }
+
+    pub fn try_write(&self, filename: &str, data: &[u8]) -> Result<(), MrpError> {
+        self.env
+            .try_write_as(&self.try_output_name(filename)?, filename, data)
+    }
+
+    pub fn write_str(&self, filename: &str, data: &str) {
+        self.write(filename, data.as_bytes());
+    }
+
+    pub fn csv_writer(&self, filename: &str, headers: &[&str]) -> CsvWriter {
+        self.try_csv_writer(filename, headers)
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    pub fn try_csv_writer(&self, filename: &str, headers: &[&str]) -> Result<CsvWriter, MrpError> {
+        self.env.try_csv_writer_as(
+            &self.try_output_name(filename)?,
+            filename,
+            headers,
+            self.run(),
+        )
+    }
+
+    pub fn write_csv(&self, filename: &str, headers: &[&str], rows: &[Vec<String>]) {
+        self.try_write_csv(filename, headers, rows)
+            .unwrap_or_else(|e| panic!("{e}"));
+    }
+
+    pub fn try_write_csv(
+        &self,
+        filename: &str,
+        headers: &[&str],
+        rows: &[Vec<String>],
+    ) -> Result<(), MrpError> {
+        self.env.try_write_csv_as(
+            &self.try_output_name(filename)?,
+            filename,
+            headers,
+            rows,
+            self.run(),
+        )
+    }
+
+    /// The seed and replicate of run columns, as in the standalone run.
+    fn run(&self) -> (u64, u64) {
+        (self.base_seed, self.replicate)
+    }
+
+    pub fn jsonl_writer(&self, filename: &str) -> JsonlWriter {
+        self.env
+            .jsonl_writer_as(&self.output_name(filename), filename)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_name() {
+        let env = Environment::builder().seed(1).build();
+        let context = ReplicateContext::new(&env, &(), 3, 1);
+        assert_eq!(context.output_name("cases.csv"), "replicate_3/cases.csv");
+        assert_eq!(context.output_name("cases_{replicate}.csv"), "cases_3.csv");
+        assert_eq!(context.seed, derive_seed(1, 3));
+    }
+}
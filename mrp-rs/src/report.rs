@@ -0,0 +1,77 @@
+/// A section a model adds to the run report.
+pub trait ReportSection {
+    fn title(&self) -> String;
+    /// The section body, as Markdown.
+    fn body(&self) -> String;
+}
+
+/// Filename of the report written by `Environment::write_report`.
+pub(crate) const REPORT: &str = "report.md";
+
+const REDACTED: &str = "[redacted]";
+
+/// A Markdown report assembled section by section.
+#[derive(Debug, Default)]
+pub(crate) struct Report {
+    sections: Vec<(String, String)>,
+}
+
+impl Report {
+    pub(crate) fn push(&mut self, title: &str, body: String) {
+        let body = if body.trim().is_empty() {
+            "_None._".to_string()
+        } else {
+            body
+        };
+        self.sections.push((title.to_string(), body));
+    }
+
+    /// Render the report with every occurrence of a `redacted` value masked.
+    pub(crate) fn render(&self, redacted: &[String]) -> String {
+        let mut out = String::from("# Run report\n");
+        for (title, body) in &self.sections {
+            out.push_str(&format!("\n## {title}\n\n{}\n", body.trim_end()));
+        }
+        for value in redacted.iter().filter(|v| !v.is_empty()) {
+            out = out.replace(value.as_str(), REDACTED);
+        }
+        out
+    }
+}
+
+/// A Markdown table, or nothing if there are no rows.
+pub fn table(headers: &[&str], rows: impl IntoIterator<Item = Vec<String>>) -> String {
+    let rows: Vec<Vec<String>> = rows.into_iter().collect();
+    if rows.is_empty() {
+        return String::new();
+    }
+    let line = |cells: &[String]| {
+        let cells: Vec<String> = cells
+            .iter()
+            .map(|c| c.replace('|', "\\|").replace(['\r', '\n'], " "))
+            .collect();
+        format!("| {} |\n", cells.join(" | "))
+    };
+    let headers: Vec<String> = headers.iter().map(|h| h.to_string()).collect();
+    let rule: Vec<String> = headers.iter().map(|_| "---".to_string()).collect();
+    let mut out = line(&headers) + &line(&rule);
+    for row in &rows {
+        out.push_str(&line(row));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table_escapes_cells() {
+        let out = table(
+            &["a", "b"],
+            [vec!["x|y".to_string(), "two\nlines".to_string()]],
+        );
+        assert_eq!(out, "| a | b |\n| --- | --- |\n| x\\|y | two lines |\n");
+        assert_eq!(table(&["a"], []), "");
+    }
+}
@@ -0,0 +1,252 @@
+//! Pinned random number generators, so a `rand` upgrade does not change a
+//! model's draws as it may change `StdRng`'s.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::MrpError;
+use crate::seed::splitmix64;
+
+/// The algorithm behind [`crate::Environment::rng`], chosen by the
+/// payload's `"rng"` key.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RngKind {
+    /// ChaCha with 8 rounds: faster, still statistically strong.
+    ChaCha8,
+    #[default]
+    ChaCha20,
+    /// PCG XSL RR 128/64 (O'Neill's `pcg64`).
+    Pcg64,
+}
+
+impl RngKind {
+    pub const ALL: [RngKind; 3] = [RngKind::ChaCha8, RngKind::ChaCha20, RngKind::Pcg64];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            RngKind::ChaCha8 => "chacha8",
+            RngKind::ChaCha20 => "chacha20",
+            RngKind::Pcg64 => "pcg64",
+        }
+    }
+
+    pub(crate) fn from_payload(value: &Value) -> Result<Self, MrpError> {
+        value
+            .as_str()
+            .and_then(|name| Self::ALL.into_iter().find(|kind| kind.name() == name))
+            .ok_or_else(|| {
+                MrpError::Input(format!(
+                    "\"rng\" must be one of chacha8, chacha20, pcg64, got {value}"
+                ))
+            })
+    }
+}
+
+/// The 32 seed bytes for `seed`: the first four outputs of a splitmix64
+/// generator started at `seed`, little-endian. Fixed here rather than left
+/// to `SeedableRng::seed_from_u64`, so seeding cannot change either.
+pub fn seed_bytes(seed: u64) -> [u8; 32] {
+    const GAMMA: u64 = 0x9E3779B97F4A7C15;
+    let mut bytes = [0; 32];
+    for (i, chunk) in bytes.chunks_exact_mut(8).enumerate() {
+        let x = splitmix64(seed.wrapping_add(GAMMA.wrapping_mul(i as u64)));
+        chunk.copy_from_slice(&x.to_le_bytes());
+    }
+    bytes
+}
+
+#[cfg(feature = "rand")]
+pub use generators::{ModelRng, Pcg64};
+
+#[cfg(feature = "rand")]
+mod generators {
+    use rand::rand_core::impls;
+    use rand::{RngCore, SeedableRng};
+    use rand_chacha::{ChaCha8Rng, ChaCha20Rng};
+
+    use super::{RngKind, seed_bytes};
+
+    /// A generator of the [`RngKind`] the payload chose.
+    #[derive(Debug, Clone)]
+    pub enum ModelRng {
+        ChaCha8(ChaCha8Rng),
+        ChaCha20(ChaCha20Rng),
+        Pcg64(Pcg64),
+    }
+
+    impl ModelRng {
+        /// A `kind` generator seeded from [`seed_bytes`]`(seed)`.
+        pub fn new(kind: RngKind, seed: u64) -> Self {
+            let bytes = seed_bytes(seed);
+            match kind {
+                RngKind::ChaCha8 => ModelRng::ChaCha8(ChaCha8Rng::from_seed(bytes)),
+                RngKind::ChaCha20 => ModelRng::ChaCha20(ChaCha20Rng::from_seed(bytes)),
+                RngKind::Pcg64 => ModelRng::Pcg64(Pcg64::from_seed(bytes)),
+            }
+        }
+
+        pub fn kind(&self) -> RngKind {
+            match self {
+                ModelRng::ChaCha8(_) => RngKind::ChaCha8,
+                ModelRng::ChaCha20(_) => RngKind::ChaCha20,
+                ModelRng::Pcg64(_) => RngKind::Pcg64,
+            }
+        }
+    }
+
+    impl RngCore for ModelRng {
+        fn next_u32(&mut self) -> u32 {
+            match self {
+                ModelRng::ChaCha8(rng) => rng.next_u32(),
+                ModelRng::ChaCha20(rng) => rng.next_u32(),
+                ModelRng::Pcg64(rng) => rng.next_u32(),
+            }
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            match self {
+                ModelRng::ChaCha8(rng) => rng.next_u64(),
+                ModelRng::ChaCha20(rng) => rng.next_u64(),
+                ModelRng::Pcg64(rng) => rng.next_u64(),
+            }
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            match self {
+                ModelRng::ChaCha8(rng) => rng.fill_bytes(dest),
+                ModelRng::ChaCha20(rng) => rng.fill_bytes(dest),
+                ModelRng::Pcg64(rng) => rng.fill_bytes(dest),
+            }
+        }
+    }
+
+    /// PCG XSL RR 128/64: a 128-bit LCG whose high and low halves are
+    /// xored and rotated into each output. Implemented here so its output
+    /// is fixed by this crate.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Pcg64 {
+        state: u128,
+        increment: u128,
+    }
+
+    const MULTIPLIER: u128 = 0x2360_ED05_1FC6_5DA4_4385_DF64_9FCC_F645;
+
+    impl Pcg64 {
+        /// The generator with initial `state` on stream `stream`.
+        pub fn new(state: u128, stream: u128) -> Self {
+            let mut pcg = Pcg64 {
+                state,
+                increment: (stream << 1) | 1,
+            };
+            pcg.state = pcg.state.wrapping_add(pcg.increment);
+            pcg.step();
+            pcg
+        }
+
+        fn step(&mut self) {
+            self.state = self
+                .state
+                .wrapping_mul(MULTIPLIER)
+                .wrapping_add(self.increment);
+        }
+    }
+
+    impl SeedableRng for Pcg64 {
+        type Seed = [u8; 32];
+
+        /// The first 16 bytes are the state and the rest the stream, both
+        /// little-endian.
+        fn from_seed(seed: [u8; 32]) -> Self {
+            let state = u128::from_le_bytes(seed[..16].try_into().unwrap());
+            let stream = u128::from_le_bytes(seed[16..].try_into().unwrap());
+            Pcg64::new(state, stream)
+        }
+    }
+
+    impl RngCore for Pcg64 {
+        fn next_u32(&mut self) -> u32 {
+            self.next_u64() as u32
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            self.step();
+            let state = self.state;
+            let rotate = (state >> 122) as u32;
+            (((state >> 64) as u64) ^ (state as u64)).rotate_right(rotate)
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            impls::fill_bytes_via_next(self, dest)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_kind_from_payload() {
+        assert_eq!(
+            RngKind::from_payload(&json!("chacha8")).unwrap(),
+            RngKind::ChaCha8
+        );
+        assert_eq!(
+            RngKind::from_payload(&json!("pcg64")).unwrap(),
+            RngKind::Pcg64
+        );
+        assert!(RngKind::from_payload(&json!("std")).is_err());
+        assert!(RngKind::from_payload(&json!(20)).is_err());
+        assert_eq!(RngKind::default(), RngKind::ChaCha20);
+        assert_eq!(
+            serde_json::from_value::<RngKind>(json!("chacha20")).unwrap(),
+            RngKind::ChaCha20
+        );
+    }
+
+    /// These draws must not change: a model's trajectories depend on them.
+    /// A failure here after a dependency upgrade means the upgrade changes
+    /// every simulation and needs a new `RngKind` instead.
+    #[cfg(feature = "rand")]
+    #[test]
+    fn test_pinned_draws() {
+        use rand::RngCore;
+
+        let draws = |kind| {
+            let mut rng = ModelRng::new(kind, 42);
+            assert_eq!(rng.kind(), kind);
+            [rng.next_u64(), rng.next_u64(), rng.next_u64()]
+        };
+        assert_eq!(
+            draws(RngKind::ChaCha8),
+            [
+                3536907876931541756,
+                1681417456739323905,
+                17856965759995586207
+            ]
+        );
+        assert_eq!(
+            draws(RngKind::ChaCha20),
+            [
+                693385945204756564,
+                16436763086163553629,
+                3187728548114239752
+            ]
+        );
+        assert_eq!(
+            draws(RngKind::Pcg64),
+            [
+                16021905521437134002,
+                11119440567189958216,
+                10932489310456944801
+            ]
+        );
+        assert_eq!(
+            seed_bytes(42)[..8],
+            crate::seed::splitmix64(42).to_le_bytes()
+        );
+    }
+}
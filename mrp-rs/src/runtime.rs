@@ -1,10 +1,12 @@
-use std::io::Write;
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::thread::JoinHandle;
 
 use serde_json::Value;
 
 use crate::MrpError;
+use crate::framing::FrameReader;
 
 #[derive(Debug, Clone)]
 pub struct RunResult {
@@ -21,6 +23,12 @@ impl RunResult {
 
 pub trait Runtime {
     fn run(&self, run_json: &Value) -> Result<RunResult, MrpError>;
+
+    /// Identifies the model build, so cached runs of another build are not
+    /// reused.
+    fn build_info(&self) -> Value {
+        Value::Null
+    }
 }
 
 pub struct SubprocessRuntime {
@@ -37,10 +45,10 @@ impl SubprocessRuntime {
             timeout: None,
         }
     }
-}
 
-impl Runtime for SubprocessRuntime {
-    fn run(&self, run_json: &Value) -> Result<RunResult, MrpError> {
+    /// Start the command with `run_json` on its stdin and its stdout, stderr
+    /// and stdin piped.
+    fn start(&self, run_json: &Value) -> Result<Child, MrpError> {
         prepare_output(run_json);
 
         let input_bytes =
@@ -52,9 +60,9 @@ impl Runtime for SubprocessRuntime {
             .ok_or_else(|| MrpError::Config("empty command".to_string()))?;
 
         let mut cmd = Command::new(program);
-        cmd.args(args).stdin(std::process::Stdio::piped());
-        cmd.stdout(std::process::Stdio::piped());
-        cmd.stderr(std::process::Stdio::piped());
+        cmd.args(args).stdin(Stdio::piped());
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
 
         if let Some(ref cwd) = self.cwd {
             cmd.current_dir(cwd);
@@ -67,6 +75,71 @@ impl Runtime for SubprocessRuntime {
                 .write_all(&input_bytes)
                 .map_err(|e| MrpError::Runtime(e.to_string()))?;
         }
+        Ok(child)
+    }
+
+    /// Start the command on `run_json` and read its stdout as it runs.
+    /// The payload should ask for `"output": {"spec": "stdout", "framed":
+    /// true}`, so that [`ChildRun::stream`] can tell its files apart.
+    pub fn spawn(&self, run_json: &Value) -> Result<ChildRun, MrpError> {
+        let mut child = self.start(run_json)?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let mut stderr = child.stderr.take().expect("stderr is piped");
+        // Drained alongside, so a chatty child cannot block on stderr
+        let stderr = std::thread::spawn(move || {
+            let mut data = Vec::new();
+            let _ = stderr.read_to_end(&mut data);
+            data
+        });
+        Ok(ChildRun {
+            child,
+            frames: FrameReader::new(stdout),
+            stderr,
+        })
+    }
+}
+
+/// A child model started by [`SubprocessRuntime::spawn`].
+pub struct ChildRun {
+    child: Child,
+    frames: FrameReader<ChildStdout>,
+    stderr: JoinHandle<Vec<u8>>,
+}
+
+impl ChildRun {
+    /// The child's framed stdout, as frames parsed while it is read. The
+    /// child waits while the parent does not read, so a fold over its rows
+    /// holds no more than a frame at a time.
+    pub fn stream(&mut self) -> &mut FrameReader<ChildStdout> {
+        &mut self.frames
+    }
+
+    /// Wait for the child to exit. `stdout` holds whatever of its stdout
+    /// [`ChildRun::stream`] had not read.
+    pub fn wait(mut self) -> Result<RunResult, MrpError> {
+        let mut stdout = Vec::new();
+        self.frames
+            .read_rest(&mut stdout)
+            .map_err(|e| MrpError::Runtime(e.to_string()))?;
+        let status = self
+            .child
+            .wait()
+            .map_err(|e| MrpError::Runtime(e.to_string()))?;
+        let stderr = self
+            .stderr
+            .join()
+            .map_err(|_| MrpError::Runtime("stderr reader panicked".to_string()))?;
+        Ok(RunResult {
+            exit_code: status.code().unwrap_or(-1),
+            stdout,
+            stderr,
+        })
+    }
+}
+
+impl Runtime for SubprocessRuntime {
+    fn run(&self, run_json: &Value) -> Result<RunResult, MrpError> {
+        let child = self.start(run_json)?;
 
         let output = child
             .wait_with_output()
@@ -78,6 +151,18 @@ impl Runtime for SubprocessRuntime {
             stderr: output.stderr,
         })
     }
+
+    /// The command, plus the hash of the executable when it names a file.
+    fn build_info(&self) -> Value {
+        let executable = self.command.first().map(|program| match &self.cwd {
+            Some(cwd) => cwd.join(program),
+            None => PathBuf::from(program),
+        });
+        let sha256 = executable
+            .and_then(|path| std::fs::read(path).ok())
+            .map(|data| crate::provenance::sha256_hex(&data));
+        serde_json::json!({ "command": self.command, "executable_sha256": sha256 })
+    }
 }
 
 fn select_profile<'a>(section: &'a Value, profile_name: Option<&str>) -> &'a Value {
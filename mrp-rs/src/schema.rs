@@ -0,0 +1,165 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::MrpError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColumnType {
+    Integer,
+    Float,
+    Bool,
+    String,
+}
+
+impl ColumnType {
+    /// Whether a serialized CSV cell is a valid value of this type.
+    pub fn accepts(&self, cell: &str) -> bool {
+        match self {
+            ColumnType::Integer => cell.parse::<i64>().is_ok() || cell.parse::<u64>().is_ok(),
+            ColumnType::Float => cell.parse::<f64>().is_ok(),
+            ColumnType::Bool => cell == "true" || cell == "false",
+            ColumnType::String => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Column {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub ty: ColumnType,
+}
+
+/// The declared shape of one output file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutputSchema {
+    pub columns: Vec<Column>,
+    /// Optional outputs may be absent at finalize.
+    #[serde(default)]
+    pub optional: bool,
+}
+
+impl OutputSchema {
+    pub fn new(columns: &[(&str, ColumnType)]) -> Self {
+        OutputSchema {
+            columns: columns
+                .iter()
+                .map(|(name, ty)| Column {
+                    name: name.to_string(),
+                    ty: *ty,
+                })
+                .collect(),
+            optional: false,
+        }
+    }
+
+    pub fn optional(mut self) -> Self {
+        self.optional = true;
+        self
+    }
+
+    /// Check that `headers` match the declared columns exactly, in order.
+    pub fn check_headers(&self, filename: &str, headers: &[&str]) -> Result<(), MrpError> {
+        let declared: Vec<&str> = self.columns.iter().map(|c| c.name.as_str()).collect();
+        if declared != headers {
+            return Err(MrpError::Output(format!(
+                "{filename}: headers {headers:?} do not match declared columns {declared:?}"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Check each cell of `row` against its declared column type.
+    pub fn check_row(&self, filename: &str, row: &[&str]) -> Result<(), MrpError> {
+        if row.len() != self.columns.len() {
+            return Err(MrpError::Output(format!(
+                "{filename}: row has {} fields, expected {}",
+                row.len(),
+                self.columns.len()
+            )));
+        }
+        for (cell, col) in row.iter().zip(&self.columns) {
+            if !col.ty.accepts(cell) {
+                return Err(MrpError::Output(format!(
+                    "{filename}: column '{}' expected {:?}, got {cell:?}",
+                    col.name, col.ty
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A whole-run output contract, typically loaded from a JSON file.
+///
+/// ```json
+/// {
+///   "strict": true,
+///   "outputs": {
+///     "results.csv": {"columns": [{"name": "step", "type": "integer"}]}
+///   }
+/// }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutputContract {
+    /// Reject outputs that are not declared.
+    #[serde(default)]
+    pub strict: bool,
+    #[serde(default)]
+    pub outputs: BTreeMap<String, OutputSchema>,
+}
+
+impl OutputContract {
+    pub fn from_file(path: &Path) -> Result<Self, MrpError> {
+        let contents = fs::read_to_string(path).map_err(|e| {
+            MrpError::FileNotFound(format!("output contract {}: {e}", path.display()))
+        })?;
+        serde_json::from_str(&contents).map_err(|e| {
+            MrpError::Serialization(format!("output contract {}: {e}", path.display()))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_column_types() {
+        assert!(ColumnType::Integer.accepts("-3"));
+        assert!(ColumnType::Integer.accepts("18446744073709551615"));
+        assert!(!ColumnType::Integer.accepts("1.5"));
+        assert!(ColumnType::Float.accepts("1.5"));
+        assert!(ColumnType::Float.accepts("2"));
+        assert!(!ColumnType::Float.accepts("abc"));
+        assert!(ColumnType::Bool.accepts("true"));
+        assert!(!ColumnType::Bool.accepts("1"));
+        assert!(ColumnType::String.accepts(""));
+    }
+
+    #[test]
+    fn test_check_headers_order() {
+        let schema = OutputSchema::new(&[("a", ColumnType::Integer), ("b", ColumnType::Float)]);
+        assert!(schema.check_headers("x.csv", &["a", "b"]).is_ok());
+        assert!(schema.check_headers("x.csv", &["b", "a"]).is_err());
+    }
+
+    #[test]
+    fn test_contract_from_json() {
+        let contract: OutputContract = serde_json::from_value(serde_json::json!({
+            "strict": true,
+            "outputs": {
+                "out.csv": {"columns": [{"name": "step", "type": "integer"}], "optional": true}
+            }
+        }))
+        .unwrap();
+        assert!(contract.strict);
+        let schema = &contract.outputs["out.csv"];
+        assert!(schema.optional);
+        assert_eq!(schema.columns[0].ty, ColumnType::Integer);
+    }
+}
@@ -0,0 +1,117 @@
+use std::cell::{Cell, OnceCell};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::MrpError;
+
+static SCRATCH_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A scratch directory for intermediate files, created on first use.
+///
+/// Directories created here (as opposed to one supplied by the payload) are
+/// removed on [`Scratch::cleanup`] or drop unless `keep` is set.
+///
+/// A supplied directory may be shared by other runs, at once or one after
+/// another, so file names start with this process's ID and a run ID unique
+/// within it.
+pub(crate) struct Scratch {
+    configured: Option<PathBuf>,
+    keep: Cell<bool>,
+    dir: OnceCell<PathBuf>,
+    /// `<pid>_<run>`, prefixed to every file name.
+    run: String,
+    next_file: Cell<u64>,
+}
+
+impl Scratch {
+    pub(crate) fn new(configured: Option<PathBuf>, keep: bool) -> Self {
+        Scratch {
+            configured,
+            keep: Cell::new(keep),
+            dir: OnceCell::new(),
+            run: format!(
+                "{}_{}",
+                std::process::id(),
+                SCRATCH_COUNTER.fetch_add(1, Ordering::Relaxed)
+            ),
+            next_file: Cell::new(0),
+        }
+    }
+
+    pub(crate) fn dir(&self) -> Result<&Path, MrpError> {
+        if let Some(dir) = self.dir.get() {
+            return Ok(dir);
+        }
+        let dir = match &self.configured {
+            Some(dir) => fs::create_dir_all(dir).map(|_| dir.clone()).map_err(|e| {
+                MrpError::Output(format!(
+                    "failed to create scratch dir {}: {e}",
+                    dir.display()
+                ))
+            })?,
+            // A fresh, randomly named directory, so nothing already in the
+            // shared temp directory can stand in for it
+            None => tempfile::Builder::new()
+                .prefix("mrp_scratch_")
+                .tempdir()
+                .map(|dir| dir.keep())
+                .map_err(|e| MrpError::Output(format!("failed to create scratch dir: {e}")))?,
+        };
+        Ok(self.dir.get_or_init(|| dir))
+    }
+
+    pub(crate) fn file(&self, name: &str) -> Result<PathBuf, MrpError> {
+        let n = self.next_file.get();
+        self.next_file.set(n + 1);
+        Ok(self.dir()?.join(format!("{}_{n}_{name}", self.run)))
+    }
+
+    /// An empty directory at a fresh [`Scratch::file`] path, removed with
+    /// its contents when the guard is dropped, however its user returns.
+    pub(crate) fn trial_dir(&self, name: &str) -> Result<TrialDir, MrpError> {
+        let path = self.file(name)?;
+        // Anything already there was left by another run
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path)
+            .map_err(|e| MrpError::Output(format!("failed to create {}: {e}", path.display())))?;
+        Ok(TrialDir(path))
+    }
+
+    /// Leave an auto-created scratch directory in place on cleanup, such
+    /// as for spooled uploads that never succeeded.
+    pub(crate) fn keep(&self) {
+        self.keep.set(true);
+    }
+
+    /// Remove an auto-created scratch directory.
+    pub(crate) fn cleanup(&mut self) {
+        if self.keep.get() || self.configured.is_some() {
+            return;
+        }
+        if let Some(dir) = self.dir.take() {
+            let _ = fs::remove_dir_all(dir);
+        }
+    }
+}
+
+/// A directory from [`Scratch::trial_dir`].
+pub(crate) struct TrialDir(PathBuf);
+
+impl TrialDir {
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TrialDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        self.cleanup();
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::MrpError;
+
+/// One step of the splitmix64 generator: a bijective avalanche mix of `x`.
+///
+/// ```text
+/// z = x + 0x9E3779B97F4A7C15
+/// z = (z ^ (z >> 30)) * 0xBF58476D1CE4E5B9
+/// z = (z ^ (z >> 27)) * 0x94D049BB133111EB
+/// z ^ (z >> 31)
+/// ```
+///
+/// All arithmetic wraps modulo 2^64.
+pub fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9E3779B97F4A7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+/// Derive an independent seed for substream `stream` of `seed`.
+pub fn derive_seed(seed: u64, stream: u64) -> u64 {
+    splitmix64(splitmix64(seed) ^ stream)
+}
+
+/// Stream number for a named stream: the first 8 bytes of SHA-256 of the
+/// name, little-endian.
+pub fn stream_id(name: &str) -> u64 {
+    let digest = Sha256::digest(name.as_bytes());
+    u64::from_le_bytes(digest[..8].try_into().unwrap())
+}
+
+/// Seed of the named stream `name` for a run with `seed` and `replicate`:
+/// `derive_seed(derive_seed(seed, replicate), stream_id(name))`.
+pub fn named_stream_seed(seed: u64, replicate: u64, name: &str) -> u64 {
+    derive_seed(derive_seed(seed, replicate), stream_id(name))
+}
+
+/// A fresh seed from OS entropy, for a payload asking for a random one.
+///
+/// Drawn through the standard library's randomly keyed hasher, so no
+/// random number crate is needed; each call gives a different seed.
+pub fn entropy_seed() -> u64 {
+    use std::hash::{BuildHasher, Hasher};
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos());
+    hasher.write_u128(nanos);
+    hasher.write_u32(std::process::id());
+    splitmix64(hasher.finish())
+}
+
+/// Payload settings for one named stream.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StreamConfig {
+    /// Added to the stream number, giving the stream different draws.
+    #[serde(default)]
+    pub seed_offset: u64,
+}
+
+/// The payload's `"rng_streams"` table.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RngStreams {
+    streams: BTreeMap<String, StreamConfig>,
+    freeze: BTreeSet<String>,
+}
+
+impl RngStreams {
+    pub(crate) fn from_payload(value: &Value) -> Result<Self, MrpError> {
+        let map = value.as_object().ok_or_else(|| {
+            MrpError::Input(format!("rng_streams must be an object, got {value}"))
+        })?;
+        let mut streams = RngStreams::default();
+        for (name, config) in map {
+            if name == "freeze" {
+                streams.freeze = serde_json::from_value(config.clone()).map_err(|e| {
+                    MrpError::Input(format!("rng_streams.freeze must list stream names: {e}"))
+                })?;
+            } else {
+                let config = serde_json::from_value(config.clone())
+                    .map_err(|e| MrpError::Input(format!("rng_streams.{name}: {e}")))?;
+                streams.streams.insert(name.clone(), config);
+            }
+        }
+        Ok(streams)
+    }
+
+    /// Seed for `name`. Frozen streams ignore the replicate, so every
+    /// replicate sees the same draws.
+    pub(crate) fn seed_for(&self, name: &str, seed: u64, replicate: u64) -> u64 {
+        let offset = self.streams.get(name).map_or(0, |c| c.seed_offset);
+        let base = if self.freeze.contains(name) {
+            seed
+        } else {
+            derive_seed(seed, replicate)
+        };
+        derive_seed(base, stream_id(name).wrapping_add(offset))
+    }
+
+    /// Every stream name the payload mentions.
+    pub(crate) fn names(&self) -> BTreeSet<&str> {
+        self.streams
+            .keys()
+            .chain(&self.freeze)
+            .map(String::as_str)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_splitmix64_reference_values() {
+        // Reference outputs of the splitmix64 sequence seeded with 0
+        assert_eq!(splitmix64(0), 0xE220A8397B1DCDAF);
+        assert_eq!(splitmix64(0x9E3779B97F4A7C15), 0x6E789E6AA1B965F4);
+    }
+
+    #[test]
+    fn test_derive_seed_distinct() {
+        let seeds: std::collections::BTreeSet<u64> = (0..100).map(|i| derive_seed(42, i)).collect();
+        assert_eq!(seeds.len(), 100);
+        assert_eq!(derive_seed(42, 3), derive_seed(42, 3));
+    }
+
+    #[test]
+    fn test_frozen_streams_ignore_replicate() {
+        let streams = RngStreams::from_payload(&serde_json::json!({
+            "transmission": { "seed_offset": 0 },
+            "observation": { "seed_offset": 17 },
+            "freeze": ["transmission"]
+        }))
+        .unwrap();
+        assert_eq!(
+            streams.seed_for("transmission", 5, 0),
+            streams.seed_for("transmission", 5, 1)
+        );
+        assert_ne!(
+            streams.seed_for("observation", 5, 0),
+            streams.seed_for("observation", 5, 1)
+        );
+        // Unconfigured streams match the plain named stream seed
+        assert_eq!(
+            streams.seed_for("other", 5, 2),
+            named_stream_seed(5, 2, "other")
+        );
+        assert_ne!(
+            streams.seed_for("observation", 5, 2),
+            named_stream_seed(5, 2, "observation")
+        );
+        assert_eq!(
+            streams.names().into_iter().collect::<Vec<_>>(),
+            vec!["observation", "transmission"]
+        );
+    }
+
+    #[test]
+    fn test_rng_streams_shape_errors() {
+        assert!(RngStreams::from_payload(&serde_json::json!([1])).is_err());
+        assert!(RngStreams::from_payload(&serde_json::json!({ "freeze": "x" })).is_err());
+        assert!(RngStreams::from_payload(&serde_json::json!({ "x": { "offset": 1 } })).is_err());
+    }
+}
@@ -0,0 +1,382 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{BufRead, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+
+use crate::MrpError;
+use crate::environment::Environment;
+
+/// Options for [`serve`].
+#[derive(Debug, Clone, Default)]
+pub struct ServeOptions {
+    /// Write the session summary here when input ends, instead of as the last
+    /// line of output.
+    pub summary_path: Option<PathBuf>,
+    /// Print a rollup line to stderr after every this many requests.
+    pub rollup_every: Option<u64>,
+}
+
+/// Rollup of a [`serve`] session.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct SessionSummary {
+    pub requests: u64,
+    pub succeeded: u64,
+    /// Succeeded requests that stopped early on their cancel token.
+    pub cancelled: u64,
+    /// Failed requests by [`MrpError::kind`], or `panic`.
+    pub failures: BTreeMap<String, u64>,
+    /// CSV rows written by requests whose handler returned.
+    pub rows_written: u64,
+    pub wall_time_secs: f64,
+    pub latency_p50_ms: f64,
+    pub latency_p95_ms: f64,
+}
+
+/// Run `handler` on each newline-delimited JSON payload read from `input`.
+///
+/// Every payload gets its own [`Environment`], finalized after the handler
+/// returns, so requests share nothing but the session accounting. One status
+/// line is written to `output` per request, and an error or panic fails only
+/// that request. A handler that returns normally after its
+/// [`Environment::cancel_token`] trips, e.g. on the payload's own
+/// `"max_run_seconds"`, is reported as `cancelled`. When input ends the [`SessionSummary`] goes to
+/// `summary_path`, or else `output` as a final `{"session_summary": ...}` line.
+///
+/// Payloads should use filesystem output so model output does not interleave
+/// with status lines.
+pub fn serve<I, R, W, F>(
+    input: R,
+    mut output: W,
+    options: &ServeOptions,
+    mut handler: F,
+) -> Result<SessionSummary, MrpError>
+where
+    I: DeserializeOwned,
+    R: BufRead,
+    W: Write,
+    F: FnMut(&mut Environment<I>) -> Result<(), MrpError>,
+{
+    let mut session = Session::new();
+    for line in input.lines() {
+        let line = line.map_err(|e| MrpError::Input(format!("failed to read request: {e}")))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let start = Instant::now();
+        let (result, rows) = handle(&line, &mut handler);
+        let request = session.requests;
+        let status = match &result {
+            Ok(None) => json!({ "request": request, "status": "ok", "rows": rows }),
+            Ok(Some(reason)) => json!({
+                "request": request,
+                "status": "cancelled",
+                "reason": reason,
+                "rows": rows,
+            }),
+            Err((kind, code, message)) => json!({
+                "request": request,
+                "status": "error",
+                "kind": kind,
+                "code": code,
+                "message": message,
+            }),
+        };
+        if matches!(result, Ok(Some(_))) {
+            session.cancelled += 1;
+        }
+        session.record(start.elapsed(), rows, result.err().map(|(kind, _, _)| kind));
+        writeln!(output, "{status}")
+            .and_then(|_| output.flush())
+            .map_err(|e| MrpError::Output(format!("failed to write status: {e}")))?;
+        if let Some(every) = options.rollup_every
+            && every > 0
+            && session.requests.is_multiple_of(every)
+        {
+            eprintln!("{}", session.rollup());
+        }
+    }
+    let summary = session.summary();
+    let json =
+        serde_json::to_string(&summary).map_err(|e| MrpError::Serialization(e.to_string()))?;
+    match &options.summary_path {
+        Some(path) => fs::write(path, &json).map_err(|e| {
+            MrpError::Output(format!(
+                "failed to write session summary to {}: {e}",
+                path.display()
+            ))
+        })?,
+        None => writeln!(output, "{{\"session_summary\":{json}}}")
+            .map_err(|e| MrpError::Output(format!("failed to write session summary: {e}")))?,
+    }
+    Ok(summary)
+}
+
+/// A failed request's kind, [`MrpError::code`] (none for a panic) and message.
+type Failure = (String, Option<&'static str>, String);
+
+/// Run one request, returning the rows it wrote and its outcome: the cancel
+/// reason if it stopped early, or the failure.
+fn handle<I, F>(line: &str, handler: &mut F) -> (Result<Option<String>, Failure>, u64)
+where
+    I: DeserializeOwned,
+    F: FnMut(&mut Environment<I>) -> Result<(), MrpError>,
+{
+    let mut rows = 0;
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let data: Value = serde_json::from_str(line)
+            .map_err(|e| MrpError::Input(format!("invalid JSON payload: {e}")))?;
+        let mut env = Environment::try_from_json(data)?.try_with_input_type::<I>()?;
+        let result = handler(&mut env).and_then(|_| env.finalize());
+        rows = env.rows_written();
+        result.map(|_| env.cancel_token().tripped())
+    }));
+    let result = match result {
+        Ok(Ok(cancelled)) => Ok(cancelled),
+        Ok(Err(e)) => Err((e.kind().to_string(), Some(e.code()), e.to_string())),
+        Err(payload) => {
+            let message = payload
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            Err(("panic".to_string(), None, message))
+        }
+    };
+    (result, rows)
+}
+
+/// Latency buckets per doubling; bucket `k > 0` holds latencies in
+/// `(2^((k-1)/8), 2^(k/8)]` µs, so percentiles are within about 9%.
+const BUCKETS_PER_DOUBLING: f64 = 8.0;
+
+struct Session {
+    started: Instant,
+    requests: u64,
+    succeeded: u64,
+    cancelled: u64,
+    failures: BTreeMap<String, u64>,
+    rows_written: u64,
+    latencies: Vec<u64>,
+}
+
+impl Session {
+    fn new() -> Self {
+        Session {
+            started: Instant::now(),
+            requests: 0,
+            succeeded: 0,
+            cancelled: 0,
+            failures: BTreeMap::new(),
+            rows_written: 0,
+            latencies: Vec::new(),
+        }
+    }
+
+    fn record(&mut self, latency: Duration, rows: u64, failure: Option<String>) {
+        self.requests += 1;
+        self.rows_written += rows;
+        match failure {
+            None => self.succeeded += 1,
+            Some(kind) => *self.failures.entry(kind).or_insert(0) += 1,
+        }
+        let micros = latency.as_secs_f64() * 1e6;
+        let bucket = if micros <= 1.0 {
+            0
+        } else {
+            (micros.log2() * BUCKETS_PER_DOUBLING).ceil() as usize
+        };
+        if self.latencies.len() <= bucket {
+            self.latencies.resize(bucket + 1, 0);
+        }
+        self.latencies[bucket] += 1;
+    }
+
+    /// Upper bound of the bucket holding the `p` quantile, in milliseconds.
+    fn percentile_ms(&self, p: f64) -> f64 {
+        let rank = (p * self.requests as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.latencies.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return 2f64.powf(bucket as f64 / BUCKETS_PER_DOUBLING) / 1000.0;
+            }
+        }
+        0.0
+    }
+
+    fn rollup(&self) -> String {
+        format!(
+            "session: {} requests, {} failed, {} rows, p50 {:.3} ms, p95 {:.3} ms",
+            self.requests,
+            self.requests - self.succeeded,
+            self.rows_written,
+            self.percentile_ms(0.5),
+            self.percentile_ms(0.95)
+        )
+    }
+
+    fn summary(&self) -> SessionSummary {
+        SessionSummary {
+            requests: self.requests,
+            succeeded: self.succeeded,
+            cancelled: self.cancelled,
+            failures: self.failures.clone(),
+            rows_written: self.rows_written,
+            wall_time_secs: self.started.elapsed().as_secs_f64(),
+            latency_p50_ms: self.percentile_ms(0.5),
+            latency_p95_ms: self.percentile_ms(0.95),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(serde::Deserialize)]
+    struct Request {
+        rows: usize,
+        #[serde(default)]
+        fail: Option<String>,
+    }
+
+    #[test]
+    fn test_scripted_session() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().to_str().unwrap();
+        let payload = |input: Value| {
+            json!({ "input": input, "output": { "spec": "filesystem", "dir": out } }).to_string()
+        };
+        let script = [
+            payload(json!({ "rows": 3 })),
+            payload(json!({ "rows": 2, "fail": "input" })),
+            "{ not json".to_string(),
+            String::new(),
+            payload(json!({ "rows": 1, "fail": "panic" })),
+            payload(json!({ "rows": "three" })),
+            payload(json!({ "rows": 4 })),
+        ]
+        .join("\n");
+
+        let mut status = Vec::new();
+        let summary_path = dir.path().join("session_summary.json");
+        let options = ServeOptions {
+            summary_path: Some(summary_path.clone()),
+            rollup_every: Some(2),
+        };
+        let summary = serve(
+            script.as_bytes(),
+            &mut status,
+            &options,
+            |env: &mut Environment<Request>| {
+                // Nothing carries over from earlier requests
+                assert!(env.metrics().is_empty());
+                assert!(env.warnings().is_empty());
+                assert_eq!(env.rows_written(), 0);
+                env.record_metric("seen", 1.0);
+                env.warn("request", "started");
+                let request = env.input.as_ref().unwrap();
+                let rows: Vec<Vec<String>> =
+                    (0..request.rows).map(|i| vec![i.to_string()]).collect();
+                env.write_csv("rows.csv", &["i"], &rows);
+                match request.fail.as_deref() {
+                    Some("panic") => panic!("injected"),
+                    Some(_) => Err(MrpError::Input("injected".to_string())),
+                    None => Ok(()),
+                }
+            },
+        )
+        .unwrap();
+
+        assert_eq!(summary.requests, 6);
+        assert_eq!(summary.succeeded, 2);
+        assert_eq!(
+            summary.failures,
+            BTreeMap::from([("input".to_string(), 3), ("panic".to_string(), 1)])
+        );
+        // The failed request's rows count; the panicked one's are lost
+        assert_eq!(summary.rows_written, 3 + 2 + 4);
+        assert!(summary.latency_p50_ms <= summary.latency_p95_ms);
+        assert!(summary.latency_p95_ms > 0.0);
+
+        let lines: Vec<Value> = String::from_utf8(status)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[0]["status"], "ok");
+        assert_eq!(lines[0]["rows"], 3);
+        assert_eq!(lines[1]["code"], "MRP-INPUT-001");
+        assert_eq!(lines[3]["kind"], "panic");
+        assert!(lines[3]["code"].is_null());
+        assert_eq!(lines[3]["message"], "injected");
+        assert_eq!(lines[5]["request"], 5);
+
+        let written: Value = serde_json::from_slice(&fs::read(&summary_path).unwrap()).unwrap();
+        assert_eq!(written["requests"], 6);
+        assert_eq!(written["failures"]["panic"], 1);
+    }
+
+    #[test]
+    fn test_summary_as_last_line() {
+        let mut status = Vec::new();
+        serve(
+            "{}\n{}\n".as_bytes(),
+            &mut status,
+            &ServeOptions::default(),
+            |_: &mut Environment<Value>| Ok(()),
+        )
+        .unwrap();
+        let status = String::from_utf8(status).unwrap();
+        let last: Value = serde_json::from_str(status.lines().last().unwrap()).unwrap();
+        assert_eq!(last["session_summary"]["succeeded"], 2);
+    }
+
+    #[test]
+    fn test_cancelled_request_status() {
+        let mut status = Vec::new();
+        let summary = serve(
+            "{\"max_run_seconds\": 0}\n{}\n".as_bytes(),
+            &mut status,
+            &ServeOptions::default(),
+            |env: &mut Environment<Value>| {
+                let token = env.cancel_token();
+                if !token.is_cancelled() {
+                    token.cancel("client disconnected");
+                }
+                Ok(())
+            },
+        )
+        .unwrap();
+        let status = String::from_utf8(status).unwrap();
+        let lines: Vec<Value> = status
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines[0]["status"], "cancelled");
+        assert_eq!(lines[0]["reason"], "exceeded max_run_seconds (0)");
+        assert_eq!(lines[1]["status"], "cancelled");
+        assert_eq!(lines[1]["reason"], "client disconnected");
+        assert_eq!(summary.succeeded, 2);
+        assert_eq!(summary.cancelled, 2);
+    }
+
+    #[test]
+    fn test_latency_percentiles() {
+        let mut session = Session::new();
+        for ms in 1..=100 {
+            session.record(Duration::from_millis(ms), 0, None);
+        }
+        let p50 = session.percentile_ms(0.5);
+        let p95 = session.percentile_ms(0.95);
+        assert!((50.0..=50.0 * 1.1).contains(&p50), "{p50}");
+        assert!((95.0..=95.0 * 1.1).contains(&p95), "{p95}");
+    }
+}
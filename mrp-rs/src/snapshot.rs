@@ -0,0 +1,286 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::MrpError;
+use crate::manifest::MRP_VERSION;
+
+/// Payload file inside a snapshot directory.
+pub(crate) const PAYLOAD: &str = "payload.json";
+/// Description of a snapshot's contents.
+pub(crate) const INDEX: &str = "snapshot.json";
+
+const FILES_DIR: &str = "files";
+const REDACTED: &str = "[redacted]";
+
+/// Options for [`crate::Environment::snapshot_with`].
+#[derive(Debug, Clone)]
+pub struct SnapshotOptions {
+    /// Input files larger than this are recorded by hash and source path
+    /// instead of copied.
+    pub max_file_bytes: u64,
+}
+
+impl Default for SnapshotOptions {
+    fn default() -> Self {
+        SnapshotOptions {
+            max_file_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// `snapshot.json`: what a snapshot holds and how it was made.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotIndex {
+    pub cfa_mrp_version: String,
+    pub protocol_version: String,
+    /// Input paths whose values were replaced in the payload.
+    pub redacted: Vec<String>,
+    pub files: BTreeMap<String, SnapshotFile>,
+}
+
+/// An input file of a snapshotted run.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotFile {
+    /// The path the run read the file from.
+    pub source: String,
+    pub bytes: u64,
+    pub sha256: String,
+    /// The copy, relative to the snapshot directory. Absent for files over
+    /// the size limit, which must be fetched from `source` and checked
+    /// against `sha256` before replay.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub copy: Option<String>,
+}
+
+/// Mask the dotted `paths` present in `input`, returning those masked.
+pub(crate) fn redact_input(input: &mut Value, paths: &[String]) -> Vec<String> {
+    let mut redacted = Vec::new();
+    for path in paths {
+        if let Some(value) = path
+            .split('.')
+            .try_fold(&mut *input, |value, key| value.get_mut(key))
+        {
+            *value = Value::from(REDACTED);
+            redacted.push(path.clone());
+        }
+    }
+    redacted
+}
+
+/// Write `payload` and its input files to the directory `dest`.
+pub(crate) fn write(
+    dest: &Path,
+    payload: &Value,
+    redact: &[String],
+    options: &SnapshotOptions,
+) -> Result<SnapshotIndex, MrpError> {
+    let err = |what: &str, e: io::Error| MrpError::Output(format!("snapshot {what}: {e}"));
+    fs::create_dir_all(dest.join(FILES_DIR)).map_err(|e| err("directory", e))?;
+    let mut payload = payload.clone();
+    let redacted = match payload.get_mut("input") {
+        Some(input) => redact_input(input, redact),
+        None => Vec::new(),
+    };
+    let mut files = BTreeMap::new();
+    if let Some(Value::Object(entries)) = payload.get_mut("model").and_then(|m| m.get_mut("files"))
+    {
+        for (key, value) in entries.iter_mut() {
+            let Some(source) = value.as_str().map(String::from) else {
+                continue;
+            };
+            let (bytes, sha256) = hash_file(Path::new(&source))
+                .map_err(|e| err(&format!("input '{key}' ({source})"), e))?;
+            let copy = if bytes <= options.max_file_bytes {
+                let name = Path::new(&source)
+                    .file_name()
+                    .map(|n| n.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                let local = format!("{FILES_DIR}/{}-{name}", sanitize(key));
+                fs::copy(&source, dest.join(&local))
+                    .map_err(|e| err(&format!("copy of '{key}'"), e))?;
+                *value = Value::from(local.clone());
+                Some(local)
+            } else {
+                None
+            };
+            files.insert(
+                key.clone(),
+                SnapshotFile {
+                    source,
+                    bytes,
+                    sha256,
+                    copy,
+                },
+            );
+        }
+    }
+    let index = SnapshotIndex {
+        cfa_mrp_version: env!("CARGO_PKG_VERSION").to_string(),
+        protocol_version: MRP_VERSION.to_string(),
+        redacted,
+        files,
+    };
+    let ser = |e: serde_json::Error| MrpError::Serialization(e.to_string());
+    fs::write(
+        dest.join(PAYLOAD),
+        serde_json::to_vec_pretty(&payload).map_err(ser)?,
+    )
+    .map_err(|e| err(PAYLOAD, e))?;
+    fs::write(
+        dest.join(INDEX),
+        serde_json::to_vec_pretty(&index).map_err(ser)?,
+    )
+    .map_err(|e| err(INDEX, e))?;
+    Ok(index)
+}
+
+/// Read the payload in snapshot directory `dir`, pointing copied input
+/// files and filesystem output inside it.
+pub(crate) fn read(dir: &Path) -> Result<Value, MrpError> {
+    let path = dir.join(PAYLOAD);
+    let text = fs::read_to_string(&path)
+        .map_err(|e| MrpError::FileNotFound(format!("{}: {e}", path.display())))?;
+    let mut payload: Value = serde_json::from_str(&text)
+        .map_err(|e| MrpError::Input(format!("invalid snapshot payload: {e}")))?;
+    if let Some(Value::Object(entries)) = payload.get_mut("model").and_then(|m| m.get_mut("files"))
+    {
+        for value in entries.values_mut() {
+            if let Some(local) = value.as_str().filter(|p| Path::new(p).is_relative()) {
+                *value = Value::from(dir.join(local).to_string_lossy().into_owned());
+            }
+        }
+    }
+    if let Some(output) = payload.get_mut("output").and_then(|o| o.as_object_mut())
+        && output.contains_key("dir")
+    {
+        output.insert(
+            "dir".to_string(),
+            Value::from(dir.join("output").to_string_lossy().into_owned()),
+        );
+    }
+    Ok(payload)
+}
+
+fn hash_file(path: &Path) -> io::Result<(u64, String)> {
+    let mut hasher = Sha256::new();
+    let bytes = io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok((bytes, hex::encode(hasher.finalize())))
+}
+
+fn sanitize(key: &str) -> String {
+    key.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Environment;
+    use crate::seed::derive_seed;
+
+    /// Read both inputs and write a seeded CSV from them.
+    fn model(env: &Environment) -> String {
+        let cases = env.read_file_to_string("cases").unwrap();
+        let delays = env.read_file_to_string("delays").unwrap();
+        let seed = derive_seed(env.input_json()["seed"].as_u64().unwrap(), env.replicate);
+        let rows = vec![vec![
+            cases.lines().count().to_string(),
+            delays.trim().to_string(),
+            seed.to_string(),
+        ]];
+        env.write_csv("out.csv", &["cases", "delays", "draw"], &rows);
+        fs::read_to_string(env.output_dir().unwrap().join("out.csv")).unwrap()
+    }
+
+    fn copy_dir(from: &Path, to: &Path) {
+        fs::create_dir_all(to).unwrap();
+        for entry in fs::read_dir(from).unwrap() {
+            let entry = entry.unwrap();
+            if entry.file_type().unwrap().is_dir() {
+                copy_dir(&entry.path(), &to.join(entry.file_name()));
+            } else {
+                fs::copy(entry.path(), to.join(entry.file_name())).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_snapshot_replays_on_another_machine() {
+        let prod = tempfile::tempdir().unwrap();
+        let inputs = prod.path().join("inputs");
+        fs::create_dir_all(&inputs).unwrap();
+        fs::write(inputs.join("cases.csv"), "day,count\n0,3\n1,5\n").unwrap();
+        fs::write(inputs.join("delays.txt"), "0.2 0.5 0.3\n").unwrap();
+        let env = Environment::from_json(serde_json::json!({
+            "input": { "seed": 11, "replicate": 2, "store": { "token": "hunter2" } },
+            "model": { "files": {
+                "cases": inputs.join("cases.csv").to_str().unwrap(),
+                "delays": inputs.join("delays.txt").to_str().unwrap(),
+            } },
+            "output": { "spec": "filesystem", "dir": prod.path().join("out").to_str().unwrap() },
+            "redact": ["store.token"],
+        }));
+        let original = model(&env);
+        let snap = prod.path().join("snapshot");
+        let index = env.snapshot(&snap).unwrap();
+        assert_eq!(index.redacted, ["store.token"]);
+        assert_eq!(index.files["cases"].bytes, 18);
+        assert!(index.files.values().all(|f| f.copy.is_some()));
+        assert!(
+            !fs::read_to_string(snap.join(PAYLOAD))
+                .unwrap()
+                .contains("hunter2")
+        );
+
+        // Replay elsewhere, with the original inputs gone
+        let laptop = tempfile::tempdir().unwrap();
+        let moved = laptop.path().join("incident");
+        copy_dir(&snap, &moved);
+        drop(prod);
+        let replay = Environment::from_snapshot(&moved);
+        assert!(replay.files["cases"].starts_with(&moved));
+        assert_eq!(replay.replicate, 2);
+        assert_eq!(replay.output_dir().unwrap(), moved.join("output"));
+        assert_eq!(replay.input_json()["store"]["token"], REDACTED);
+        assert_eq!(model(&replay), original);
+    }
+
+    #[test]
+    fn test_large_files_are_referenced() {
+        let dir = tempfile::tempdir().unwrap();
+        let big = dir.path().join("big.bin");
+        fs::write(&big, vec![7u8; 4096]).unwrap();
+        let env = Environment::from_json(serde_json::json!({
+            "model": { "files": { "big": big.to_str().unwrap() } },
+        }));
+        let snap = dir.path().join("snapshot");
+        let index = env
+            .snapshot_with(
+                &snap,
+                &SnapshotOptions {
+                    max_file_bytes: 1024,
+                },
+            )
+            .unwrap();
+        let file = &index.files["big"];
+        assert_eq!(file.copy, None);
+        assert_eq!(file.bytes, 4096);
+        assert_eq!(file.sha256, crate::provenance::sha256_hex(&[7u8; 4096]));
+        assert_eq!(fs::read_dir(snap.join(FILES_DIR)).unwrap().count(), 0);
+        let replay = Environment::from_snapshot(&snap);
+        assert_eq!(replay.files["big"], big);
+    }
+}
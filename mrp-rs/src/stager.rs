@@ -4,6 +4,7 @@ use std::path::{Path, PathBuf};
 use std::sync::Mutex;
 
 use crate::MrpError;
+use crate::io_pool::IoPool;
 
 static STAGE_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
 
@@ -22,9 +23,21 @@ pub fn stage_files(files: &HashMap<String, String>) -> Result<HashMap<String, St
     if files.is_empty() {
         return Ok(HashMap::new());
     }
+    // Downloads run side by side on an IO pool sized by MRP_IO_THREADS
+    let pool = IoPool::configured(None)?;
+    let pending: Vec<_> = files
+        .iter()
+        .map(|(name, uri)| {
+            let (task_name, uri) = (name.clone(), uri.clone());
+            (
+                name.clone(),
+                pool.submit(move || stage_one(&task_name, &uri)),
+            )
+        })
+        .collect();
     let mut staged = HashMap::new();
-    for (name, uri) in files {
-        staged.insert(name.clone(), stage_one(name, uri)?);
+    for (name, handle) in pending {
+        staged.insert(name, handle.wait()??);
     }
     Ok(staged)
 }
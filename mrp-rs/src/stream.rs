@@ -0,0 +1,458 @@
+use std::cell::{Cell, RefCell};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::fd::{AsFd, AsRawFd};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::MrpError;
+use crate::io_pool::IoPool;
+
+/// Largest single write, so a slow consumer takes the stream a little at
+/// a time.
+const CHUNK: usize = 4096;
+
+/// How long to wait for the consumer before re-checking the stall limit.
+const POLL_SLICE: Duration = Duration::from_millis(50);
+
+/// Time spent waiting on the consumer of a stream, shared by its writers,
+/// and the stall that ended it, if any.
+#[derive(Debug)]
+pub(crate) struct StreamStats {
+    started: Instant,
+    blocked: Cell<Duration>,
+    stalled: RefCell<Option<String>>,
+}
+
+impl StreamStats {
+    pub(crate) fn new() -> Self {
+        StreamStats {
+            started: Instant::now(),
+            blocked: Cell::new(Duration::ZERO),
+            stalled: RefCell::new(None),
+        }
+    }
+
+    /// The stall that stopped the stream, if its consumer stopped reading.
+    pub(crate) fn stall(&self) -> Option<MrpError> {
+        self.stalled.borrow().clone().map(MrpError::Stalled)
+    }
+
+    pub(crate) fn blocked(&self) -> Duration {
+        self.blocked.get()
+    }
+
+    /// Share of wall time since the stream was created spent blocked.
+    pub(crate) fn blocked_fraction(&self) -> f64 {
+        let wall = self.started.elapsed().as_secs_f64();
+        if wall > 0.0 {
+            (self.blocked().as_secs_f64() / wall).min(1.0)
+        } else {
+            0.0
+        }
+    }
+
+    fn add_blocked(&self, waited: Duration) {
+        self.blocked.set(self.blocked.get() + waited);
+    }
+}
+
+/// The error a [`StreamWriter`] returns when the consumer stops reading.
+#[derive(Debug)]
+struct StallError(String);
+
+impl StallError {
+    fn after(waited: Duration) -> Self {
+        StallError(format!(
+            "consumer stopped reading for {:.1}s (max_stall_seconds)",
+            waited.as_secs_f64()
+        ))
+    }
+}
+
+impl std::fmt::Display for StallError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for StallError {}
+
+/// The [`MrpError::Stalled`] behind `error`, if a stream stalled.
+pub(crate) fn stall_error(error: &io::Error) -> Option<MrpError> {
+    error
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<StallError>())
+        .map(|e| MrpError::Stalled(e.to_string()))
+}
+
+/// Panic with `result`'s error unless it is a stall, which the stream has
+/// recorded; the `try_` variants and finalize return it instead.
+pub(crate) fn panic_unless_stalled(result: Result<(), MrpError>) {
+    match result {
+        Ok(()) | Err(MrpError::Stalled(_)) => {}
+        Err(e) => panic!("{e}"),
+    }
+}
+
+/// Writes to a stream in bounded chunks, accounting for the time spent
+/// waiting on a slow consumer and failing with a stall error once a single
+/// wait exceeds `max_stall`.
+pub(crate) struct StreamWriter<W> {
+    inner: Sink<W>,
+    max_stall: Option<Duration>,
+    stats: Rc<StreamStats>,
+}
+
+#[cfg(unix)]
+mod sink {
+    use std::io;
+    use std::os::fd::{AsRawFd, RawFd};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::io_pool::IoPool;
+
+    /// Polls the descriptor for room, then writes with `O_NONBLOCK` set,
+    /// so a consumer that fills up between the two gives `WouldBlock`
+    /// rather than a write that blocks past the stall limit.
+    pub(super) struct Sink<W>(pub(super) W);
+
+    impl<W: super::Target> Sink<W> {
+        pub(super) fn new(inner: W, _pool: &Arc<IoPool>) -> Self {
+            Sink(inner)
+        }
+
+        /// Wait up to `timeout` for room to write; `Ok(false)` on timeout.
+        pub(super) fn wait(&mut self, timeout: Duration) -> io::Result<bool> {
+            let mut fd = libc::pollfd {
+                fd: self.0.as_fd().as_raw_fd(),
+                events: libc::POLLOUT,
+                revents: 0,
+            };
+            let ms = timeout.as_millis().min(i32::MAX as u128) as i32;
+            // SAFETY: `fd` is a valid pollfd for the duration of the call.
+            match unsafe { libc::poll(&mut fd, 1, ms) } {
+                -1 => {
+                    let e = io::Error::last_os_error();
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        Ok(false)
+                    } else {
+                        Err(e)
+                    }
+                }
+                0 => Ok(false),
+                _ => Ok(true),
+            }
+        }
+
+        pub(super) fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write_nonblocking(buf)
+        }
+
+        pub(super) fn flush(&mut self) -> io::Result<()> {
+            self.0.flush_nonblocking()
+        }
+    }
+
+    /// Run `op` with `O_NONBLOCK` set on `fd`, restoring its flags after.
+    ///
+    /// The flag belongs to the open file description, which other writers
+    /// of the stream may share, so it is only set for the one call.
+    pub(super) fn nonblocking<T>(fd: RawFd, op: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+        // SAFETY: fcntl with F_GETFL/F_SETFL only reads and sets flags of
+        // `fd`, which the caller holds open.
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        if flags & libc::O_NONBLOCK != 0 {
+            return op();
+        }
+        if unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let result = op();
+        unsafe { libc::fcntl(fd, libc::F_SETFL, flags) };
+        result
+    }
+}
+
+#[cfg(not(unix))]
+mod sink {
+    use std::io::{self, Write};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use crate::io_pool::{Handle, IoPool};
+
+    /// Hands each write to the IO pool, so waiting on the consumer can time
+    /// out.
+    pub(super) struct Sink<W> {
+        inner: Arc<Mutex<W>>,
+        pool: Arc<IoPool>,
+        pending: Option<Handle<io::Result<usize>>>,
+    }
+
+    impl<W: super::Target> Sink<W> {
+        pub(super) fn new(inner: W, pool: &Arc<IoPool>) -> Self {
+            Sink {
+                inner: Arc::new(Mutex::new(inner)),
+                pool: pool.clone(),
+                pending: None,
+            }
+        }
+
+        /// Wait up to `timeout` for the previous write to finish.
+        pub(super) fn wait(&mut self, timeout: Duration) -> io::Result<bool> {
+            let Some(pending) = &self.pending else {
+                return Ok(true);
+            };
+            match pending.wait_timeout(timeout) {
+                None => Ok(false),
+                Some(result) => {
+                    self.pending = None;
+                    result
+                        .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e.to_string()))?
+                        .map(|_| true)
+                }
+            }
+        }
+
+        fn submit(&mut self, op: impl FnOnce(&mut W) -> io::Result<usize> + Send + 'static) {
+            let inner = self.inner.clone();
+            self.pending = Some(self.pool.submit(move || op(&mut inner.lock().unwrap())));
+        }
+
+        pub(super) fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let buf = buf.to_vec();
+            let len = buf.len();
+            self.submit(move |inner| inner.write_all(&buf).map(|_| len));
+            Ok(len)
+        }
+
+        pub(super) fn flush(&mut self) -> io::Result<()> {
+            while !self.wait(Duration::from_secs(3600))? {}
+            self.submit(|inner| inner.flush().map(|_| 0));
+            while !self.wait(Duration::from_secs(3600))? {}
+            Ok(())
+        }
+    }
+}
+
+use sink::Sink;
+
+/// Streams a [`StreamWriter`] can wrap on this platform.
+#[cfg(unix)]
+pub(crate) trait Target: Write + AsFd {
+    /// Write without blocking, or fail with `WouldBlock`.
+    fn write_nonblocking(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let fd = self.as_fd().as_raw_fd();
+        sink::nonblocking(fd, || self.write(buf))
+    }
+
+    /// Flush without blocking, or fail with `WouldBlock`.
+    fn flush_nonblocking(&mut self) -> io::Result<()> {
+        let fd = self.as_fd().as_raw_fd();
+        sink::nonblocking(fd, || self.flush())
+    }
+}
+
+#[cfg(unix)]
+impl Target for io::PipeWriter {}
+
+/// Holds the stdout lock while the descriptor is non-blocking, so other
+/// threads printing through `std` never see `WouldBlock`.
+#[cfg(unix)]
+impl Target for io::Stdout {
+    fn write_nonblocking(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut lock = self.lock();
+        let fd = lock.as_fd().as_raw_fd();
+        sink::nonblocking(fd, || lock.write(buf))
+    }
+
+    fn flush_nonblocking(&mut self) -> io::Result<()> {
+        let mut lock = self.lock();
+        let fd = lock.as_fd().as_raw_fd();
+        sink::nonblocking(fd, || lock.flush())
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) trait Target: Write + Send + 'static {}
+#[cfg(not(unix))]
+impl<T: Write + Send + 'static> Target for T {}
+
+impl<W: Target> StreamWriter<W> {
+    pub(crate) fn new(
+        inner: W,
+        max_stall: Option<Duration>,
+        stats: Rc<StreamStats>,
+        pool: &Arc<IoPool>,
+    ) -> Self {
+        StreamWriter {
+            inner: Sink::new(inner, pool),
+            max_stall,
+            stats,
+        }
+    }
+
+    /// Wait until the consumer has room, recording the time blocked. Once
+    /// the stream has stalled, every write fails straight away.
+    fn wait(&mut self) -> io::Result<()> {
+        if let Some(stalled) = self.stats.stalled.borrow().as_deref() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                StallError(stalled.to_string()),
+            ));
+        }
+        let start = Instant::now();
+        loop {
+            let slice = match self.max_stall {
+                Some(max) => POLL_SLICE.min(max.saturating_sub(start.elapsed())),
+                None => POLL_SLICE,
+            };
+            let ready = self.inner.wait(slice)?;
+            let waited = start.elapsed();
+            if ready {
+                self.stats.add_blocked(waited);
+                return Ok(());
+            }
+            if let Some(max) = self.max_stall
+                && waited >= max
+            {
+                self.stats.add_blocked(waited);
+                let stall = StallError::after(waited);
+                *self.stats.stalled.borrow_mut() = Some(stall.0.clone());
+                return Err(io::Error::new(io::ErrorKind::TimedOut, stall));
+            }
+        }
+    }
+}
+
+/// `op` once the consumer has room, waiting again whenever it turns out
+/// to have none after all.
+fn retry_blocked<W: Target, T>(
+    writer: &mut StreamWriter<W>,
+    mut op: impl FnMut(&mut Sink<W>) -> io::Result<T>,
+) -> io::Result<T> {
+    loop {
+        writer.wait()?;
+        match op(&mut writer.inner) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            result => return result,
+        }
+    }
+}
+
+impl<W: Target> Write for StreamWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let chunk = &buf[..buf.len().min(CHUNK)];
+        retry_blocked(self, |sink| sink.write(chunk))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        retry_blocked(self, |sink| sink.flush())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::io::Read;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_slow_consumer_time_is_counted() {
+        let (mut reader, pipe) = io::pipe().unwrap();
+        let consumer = thread::spawn(move || {
+            let mut total = 0;
+            let mut buf = [0; 8192];
+            loop {
+                thread::sleep(Duration::from_millis(5));
+                match reader.read(&mut buf).unwrap() {
+                    0 => return total,
+                    n => total += n,
+                }
+            }
+        });
+        let stats = Rc::new(StreamStats::new());
+        let mut writer = StreamWriter::new(pipe, None, stats.clone(), &Arc::new(IoPool::new(1)));
+        let data = vec![b'x'; 512 * 1024];
+        let start = Instant::now();
+        writer.write_all(&data).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+        let elapsed = start.elapsed();
+        assert_eq!(consumer.join().unwrap(), data.len());
+        // Beyond the pipe's buffer every write waits on the consumer
+        assert!(stats.blocked() >= Duration::from_millis(100), "{stats:?}");
+        assert!(stats.blocked() <= elapsed);
+        assert!(stats.blocked_fraction() > 0.0);
+    }
+
+    #[test]
+    fn test_stopped_consumer_aborts() {
+        let (_reader, pipe) = io::pipe().unwrap();
+        let stats = Rc::new(StreamStats::new());
+        let max = Duration::from_millis(200);
+        let mut writer =
+            StreamWriter::new(pipe, Some(max), stats.clone(), &Arc::new(IoPool::new(1)));
+        let start = Instant::now();
+        let err = writer.write_all(&vec![b'x'; 1024 * 1024]).unwrap_err();
+        assert!(start.elapsed() < max * 10);
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        let stalled = stall_error(&err).unwrap();
+        assert_eq!(stalled.kind(), "stalled");
+        assert!(stalled.to_string().contains("consumer stopped reading"));
+        assert!(stats.blocked() >= max);
+
+        // Later writes fail at once with the recorded stall
+        let start = Instant::now();
+        let again = writer.write(b"x").unwrap_err();
+        assert!(start.elapsed() < max);
+        assert_eq!(
+            stall_error(&again).unwrap().to_string(),
+            stalled.to_string()
+        );
+        assert_eq!(stats.stall().unwrap().to_string(), stalled.to_string());
+    }
+
+    #[test]
+    fn test_full_pipe_would_block_and_flags_restored() {
+        let (_reader, mut pipe) = io::pipe().unwrap();
+        let fd = pipe.as_fd().as_raw_fd();
+        let chunk = [b'x'; CHUNK];
+        // A full pipe refuses the write rather than blocking it
+        let err = loop {
+            if let Err(e) = pipe.write_nonblocking(&chunk) {
+                break e;
+            }
+        };
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        // SAFETY: `fd` is open for as long as `pipe` is.
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        assert_eq!(flags & libc::O_NONBLOCK, 0);
+    }
+
+    #[test]
+    fn test_stall_does_not_panic_infallible_csv_writes() {
+        let (_reader, pipe) = io::pipe().unwrap();
+        let stats = Rc::new(StreamStats::new());
+        let max = Duration::from_millis(100);
+        let stream = StreamWriter::new(pipe, Some(max), stats.clone(), &Arc::new(IoPool::new(1)));
+        let mut csv = crate::csv::CsvWriter::new(Box::new(stream), &["x"]);
+        let row = "x".repeat(1000);
+        while stats.stall().is_none() {
+            csv.write_row(&[&row]);
+        }
+        csv.flush();
+        let err = csv.try_write_row(&[&row]).unwrap_err();
+        assert_eq!(err.kind(), "stalled");
+    }
+}
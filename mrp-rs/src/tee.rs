@@ -0,0 +1,135 @@
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use crate::MrpError;
+use crate::environment::Warning;
+use crate::object_store::ObjectOutput;
+use crate::tempdir::OutputTempDir;
+
+/// The sinks of a `"multi"` output spec after the first, which is the
+/// Environment's usual destination.
+pub(crate) struct Tee {
+    /// The name of the first sink, for messages.
+    pub(crate) first: String,
+    pub(crate) sinks: Vec<Sink>,
+    /// Whether one failed sink fails the write, from `"require_all"`.
+    pub(crate) require_all: bool,
+}
+
+pub(crate) struct Sink {
+    pub(crate) name: String,
+    pub(crate) kind: SinkKind,
+}
+
+pub(crate) enum SinkKind {
+    Dir(PathBuf, Option<Rc<OutputTempDir>>),
+    Objects(ObjectOutput),
+    Memory,
+}
+
+impl Tee {
+    /// Turn the per-sink `results` of writing `filename` into one. A failed
+    /// sink is an error under `require_all`, or if no sink succeeded;
+    /// otherwise it is recorded as a `sink_failed` warning.
+    pub(crate) fn settle(
+        &self,
+        filename: &str,
+        results: Vec<(&str, Result<(), MrpError>)>,
+        warnings: &RefCell<Vec<Warning>>,
+    ) -> Result<(), MrpError> {
+        let any_ok = results.iter().any(|(_, result)| result.is_ok());
+        let failures: Vec<(&str, MrpError)> = results
+            .into_iter()
+            .filter_map(|(name, result)| result.err().map(|e| (name, e)))
+            .collect();
+        if failures.is_empty() {
+            return Ok(());
+        }
+        if self.require_all || !any_ok {
+            let failures: Vec<String> = failures
+                .iter()
+                .map(|(name, e)| format!("{name}: {e}"))
+                .collect();
+            return Err(MrpError::Output(format!(
+                "output sinks failed for '{filename}': {}",
+                failures.join("; ")
+            )));
+        }
+        for (name, e) in failures {
+            record_failure(warnings, name, filename, &e);
+        }
+        Ok(())
+    }
+}
+
+fn record_failure(
+    warnings: &RefCell<Vec<Warning>>,
+    name: &str,
+    filename: &str,
+    e: &dyn std::fmt::Display,
+) {
+    let warning = Warning::output_failure(
+        "sink_failed",
+        format!("{name} failed for '{filename}', still writing to the other sinks: {e}"),
+    );
+    eprintln!("warning [{}]: {}", warning.code, warning.message);
+    warnings.borrow_mut().push(warning);
+}
+
+/// Writes every byte to each of several sinks. A sink that fails is
+/// dropped with a `sink_failed` warning, unless `require_all` is set or it
+/// was the last one, which fails the write.
+pub(crate) struct TeeWriter {
+    filename: String,
+    sinks: Vec<(String, Box<dyn Write>)>,
+    require_all: bool,
+    warnings: Rc<RefCell<Vec<Warning>>>,
+}
+
+impl TeeWriter {
+    pub(crate) fn new(
+        filename: &str,
+        sinks: Vec<(String, Box<dyn Write>)>,
+        require_all: bool,
+        warnings: Rc<RefCell<Vec<Warning>>>,
+    ) -> Self {
+        TeeWriter {
+            filename: filename.to_string(),
+            sinks,
+            require_all,
+            warnings,
+        }
+    }
+
+    fn each(&mut self, mut f: impl FnMut(&mut dyn Write) -> io::Result<()>) -> io::Result<()> {
+        let mut i = 0;
+        while i < self.sinks.len() {
+            let last = self.sinks.len() == 1;
+            let (name, sink) = &mut self.sinks[i];
+            match f(sink.as_mut()) {
+                Ok(()) => i += 1,
+                Err(e) if self.require_all || last => {
+                    return Err(io::Error::new(e.kind(), format!("{name}: {e}")));
+                }
+                Err(e) => {
+                    record_failure(&self.warnings, name, &self.filename, &e);
+                    self.sinks.remove(i);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Write for TeeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.each(|sink| sink.write_all(buf))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.each(|sink| sink.flush())
+    }
+}
@@ -0,0 +1,85 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::MrpError;
+
+static TEMPDIR_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// The output directory of the `"tempdir"` output spec, created when the
+/// Environment is built.
+///
+/// It is shared by the Environment and every writer opened in it, and is
+/// removed once the last of them is dropped unless `keep` is set.
+pub(crate) struct OutputTempDir {
+    path: PathBuf,
+    keep: bool,
+}
+
+impl OutputTempDir {
+    pub(crate) fn create(keep: bool) -> Result<Rc<Self>, MrpError> {
+        let path = std::env::temp_dir().join(format!(
+            "mrp_output_{}_{}_{}",
+            std::process::id(),
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_nanos())
+                .unwrap_or(0),
+            TEMPDIR_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir(&path).map_err(|e| {
+            MrpError::Output(format!(
+                "failed to create output tempdir {}: {e}",
+                path.display()
+            ))
+        })?;
+        Ok(Rc::new(OutputTempDir { path, keep }))
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The line printed to stderr so the results can be found.
+    pub(crate) fn announcement(&self) -> String {
+        let fate = if self.keep {
+            "kept"
+        } else {
+            "removed when the run ends"
+        };
+        format!("output tempdir: {} ({fate})", self.path.display())
+    }
+}
+
+impl Drop for OutputTempDir {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+}
+
+/// A writer to a file in an [`OutputTempDir`], keeping the directory
+/// alive while it is open.
+pub(crate) struct TempDirWriter {
+    inner: Box<dyn Write>,
+    _dir: Rc<OutputTempDir>,
+}
+
+impl TempDirWriter {
+    pub(crate) fn new(inner: Box<dyn Write>, dir: Rc<OutputTempDir>) -> Self {
+        TempDirWriter { inner, _dir: dir }
+    }
+}
+
+impl Write for TempDirWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
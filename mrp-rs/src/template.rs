@@ -0,0 +1,139 @@
+use crate::MrpError;
+
+/// Placeholders known to [`crate::Environment::expand_template`].
+pub const TEMPLATE_VARS: [&str; 4] = ["seed", "replicate", "run_id", "profile"];
+
+/// A filename or path template that cannot be expanded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TemplateError {
+    /// `{name}` is not one of [`TEMPLATE_VARS`].
+    UnknownPlaceholder { template: String, name: String },
+    /// A `{` with no `}`, or a `}` closing nothing. Literal braces are
+    /// written `{{` and `}}`.
+    UnmatchedBrace { template: String },
+}
+
+impl std::fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TemplateError::UnknownPlaceholder { template, name } => write!(
+                f,
+                "unknown placeholder {{{name}}} in '{template}' (known: {})",
+                TEMPLATE_VARS.join(", ")
+            ),
+            TemplateError::UnmatchedBrace { template } => write!(
+                f,
+                "unmatched brace in '{template}'; write {{{{ or }}}} for a literal brace"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+impl From<TemplateError> for MrpError {
+    fn from(e: TemplateError) -> Self {
+        MrpError::Config(e.to_string())
+    }
+}
+
+/// `template` with each `{name}` replaced by `var(name)`, and `{{` and `}}`
+/// by single braces.
+pub(crate) fn expand(
+    template: &str,
+    var: impl Fn(&str) -> Option<String>,
+) -> Result<String, TemplateError> {
+    let unmatched = || TemplateError::UnmatchedBrace {
+        template: template.to_string(),
+    };
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(at) = rest.find(['{', '}']) {
+        out.push_str(&rest[..at]);
+        let brace = &rest[at..at + 1];
+        rest = &rest[at + 1..];
+        if let Some(after) = rest.strip_prefix(brace) {
+            out.push_str(brace);
+            rest = after;
+            continue;
+        }
+        if brace == "}" {
+            return Err(unmatched());
+        }
+        let end = rest
+            .find(['{', '}'])
+            .filter(|&end| &rest[end..end + 1] == "}");
+        let Some(end) = end else {
+            return Err(unmatched());
+        };
+        let name = &rest[..end];
+        let value = var(name).ok_or_else(|| TemplateError::UnknownPlaceholder {
+            template: template.to_string(),
+            name: name.to_string(),
+        })?;
+        out.push_str(&value);
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Whether `template` has the placeholder `{name}`.
+pub(crate) fn uses(template: &str, name: &str) -> bool {
+    let found = std::cell::Cell::new(false);
+    let _ = expand(template, |var| {
+        found.set(found.get() || var == name);
+        Some(String::new())
+    });
+    found.get()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(name: &str) -> Option<String> {
+        match name {
+            "seed" => Some("42".to_string()),
+            "replicate" => Some("3".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_expand() {
+        assert_eq!(
+            expand("out_{seed}_{replicate}.csv", vars).unwrap(),
+            "out_42_3.csv"
+        );
+        assert_eq!(expand("plain.csv", vars).unwrap(), "plain.csv");
+        assert_eq!(
+            expand("{{seed}}_{seed}.json", vars).unwrap(),
+            "{seed}_42.json"
+        );
+        assert_eq!(expand("a}}b{{", vars).unwrap(), "a}b{");
+        assert!(uses("cases_{replicate}.csv", "replicate"));
+        assert!(!uses("cases_{{replicate}}.csv", "replicate"));
+    }
+
+    #[test]
+    fn test_expand_errors() {
+        assert_eq!(
+            expand("out_{sed}.csv", vars).unwrap_err(),
+            TemplateError::UnknownPlaceholder {
+                template: "out_{sed}.csv".to_string(),
+                name: "sed".to_string()
+            }
+        );
+        for template in ["out_{seed.csv", "out_}.csv", "{a{seed}}"] {
+            assert_eq!(
+                expand(template, vars).unwrap_err(),
+                TemplateError::UnmatchedBrace {
+                    template: template.to_string()
+                }
+            );
+        }
+        let err = MrpError::from(expand("{x}", vars).unwrap_err());
+        assert!(err.to_string().contains("unknown placeholder {x} in '{x}'"));
+    }
+}
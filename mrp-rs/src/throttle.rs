@@ -0,0 +1,50 @@
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+/// Default minimum time between throttled progress emissions.
+pub(crate) const DEFAULT_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Rate limit for progress-style emissions (progress lines, partial metrics).
+///
+/// Configured by the payload's `"progress_interval_ms"`; zero emits on every
+/// call.
+pub(crate) struct Throttle {
+    interval: Duration,
+    last: Cell<Option<Instant>>,
+}
+
+impl Throttle {
+    pub(crate) fn new(interval: Duration) -> Self {
+        Throttle {
+            interval,
+            last: Cell::new(None),
+        }
+    }
+
+    /// Whether to emit now; if so, the interval restarts.
+    pub(crate) fn ready(&self) -> bool {
+        let now = Instant::now();
+        match self.last.get() {
+            Some(last) if now.duration_since(last) < self.interval => false,
+            _ => {
+                self.last.set(Some(now));
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throttle() {
+        let throttle = Throttle::new(Duration::from_secs(3600));
+        assert!(throttle.ready());
+        assert!(!throttle.ready());
+        let every = Throttle::new(Duration::ZERO);
+        assert!(every.ready());
+        assert!(every.ready());
+    }
+}
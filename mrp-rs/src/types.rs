@@ -0,0 +1,40 @@
+//! Input value types that configs commonly carry, such as
+//! `"start_date": "2024-01-01"` and `"forecast_horizon": "28 days"`, for
+//! fields of a model's typed input.
+
+pub use crate::calendar::Days;
+
+/// An ISO-8601 calendar date. The same type as [`crate::calendar::Date`],
+/// which [`Environment::get_date`](crate::Environment::get_date) returns.
+pub type IsoDate = crate::calendar::Date;
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    struct Input {
+        start_date: IsoDate,
+        forecast_horizon: Days,
+    }
+
+    #[test]
+    fn test_input_fields() {
+        let input: Input = serde_json::from_value(serde_json::json!({
+            "start_date": "2024-01-01",
+            "forecast_horizon": "4 weeks"
+        }))
+        .unwrap();
+        assert_eq!(input.start_date.to_string(), "2024-01-01");
+        assert_eq!(input.forecast_horizon, Days(28));
+
+        let err = serde_json::from_value::<Input>(serde_json::json!({
+            "start_date": "2024-13-01",
+            "forecast_horizon": 28
+        }))
+        .unwrap_err();
+        assert!(err.to_string().contains("2024-13-01"), "{err}");
+    }
+}
@@ -0,0 +1,66 @@
+use crate::MrpError;
+
+/// Checks on a model's input that deserialization cannot make, such as a
+/// rate being non-negative. Run by
+/// [`crate::Environment::with_input_type_validated`].
+pub trait Validate {
+    /// Every problem found, so they can be reported together.
+    fn validate(&self) -> Result<(), Vec<ValidationError>>;
+}
+
+/// An input value that has the right type but is not allowed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationError {
+    /// Dotted path of the value, e.g. `observation.delay_pmf`.
+    pub field: String,
+    pub message: String,
+}
+
+impl ValidationError {
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        ValidationError {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Every failure from one [`Validate::validate`] call.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationErrors {
+    pub errors: Vec<ValidationError>,
+}
+
+impl std::fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} invalid input values:", self.errors.len())?;
+        for e in &self.errors {
+            write!(f, "\n  {e}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationErrors {}
+
+impl From<ValidationErrors> for MrpError {
+    fn from(e: ValidationErrors) -> Self {
+        MrpError::Input(e.to_string())
+    }
+}
+
+/// Run `input`'s checks; no input has nothing to check.
+pub(crate) fn check<I: Validate>(input: Option<&I>) -> Result<(), ValidationErrors> {
+    match input.map(Validate::validate) {
+        Some(Err(errors)) if !errors.is_empty() => Err(ValidationErrors { errors }),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,357 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+use crate::MrpError;
+use crate::atomic::{self, AtomicFile};
+use crate::csv::CsvWriter;
+use crate::defer::Cleanup;
+use crate::environment::{Warning, check_output_filename};
+use crate::if_exists::{self, IfExists};
+use crate::provenance::{Clock, OutputSpan};
+use crate::schema::OutputSchema;
+
+pub(crate) enum MetricUpdate {
+    Set(f64),
+    Add(f64),
+}
+
+/// Everything a worker contributes back to its parent Environment.
+#[derive(Default)]
+pub(crate) struct WorkerRecord {
+    pub(crate) metrics: Vec<(String, MetricUpdate)>,
+    pub(crate) warnings: Vec<Warning>,
+    /// Each file written, with its span on the parent's provenance clock.
+    pub(crate) outputs: Vec<(String, OutputSpan)>,
+    pub(crate) deferred: Vec<Cleanup>,
+}
+
+impl WorkerRecord {
+    pub(crate) fn apply_metrics(&self, metrics: &mut BTreeMap<String, f64>) {
+        for (name, update) in &self.metrics {
+            match update {
+                MetricUpdate::Set(v) => {
+                    metrics.insert(name.clone(), *v);
+                }
+                MetricUpdate::Add(v) => *metrics.entry(name.clone()).or_insert(0.0) += v,
+            }
+        }
+    }
+}
+
+/// Where the workers of a split write.
+pub(crate) enum WorkerDest {
+    /// The parent's output directory, under its `"atomic"` and
+    /// `"if_exists"` settings.
+    Dir {
+        dir: PathBuf,
+        atomic: bool,
+        atomic_csv: bool,
+        if_exists: IfExists,
+    },
+    /// Nowhere, naming the parent's output: its sinks, e.g. a `"multi"`
+    /// tee or an archive, are not shared across threads.
+    Unsupported(&'static str),
+}
+
+/// The parent's output checks and destination, shared by the workers of
+/// one split, and which worker wrote each file.
+pub(crate) struct WorkerOutputs {
+    schemas: BTreeMap<String, OutputSchema>,
+    strict: bool,
+    dest: WorkerDest,
+    clock: Clock,
+    claimed: Mutex<BTreeMap<String, usize>>,
+}
+
+impl WorkerOutputs {
+    pub(crate) fn new(
+        schemas: BTreeMap<String, OutputSchema>,
+        strict: bool,
+        dest: WorkerDest,
+        clock: Clock,
+    ) -> Self {
+        WorkerOutputs {
+            schemas,
+            strict,
+            dest,
+            clock,
+            claimed: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+/// A per-worker view of an Environment for parallelism inside one model.
+///
+/// Created by [`crate::Environment::split`]. Each worker has its own seed,
+/// derived from the run seed, replicate and worker index, so results are
+/// reproducible as long as work is partitioned across the same number of
+/// workers in the same way. Metrics, warnings and outputs recorded here are
+/// merged into the parent in worker-index order when it is finalized,
+/// regardless of which worker finished first.
+pub struct WorkerEnv {
+    index: usize,
+    seed: u64,
+    prefix: String,
+    record: Arc<Mutex<WorkerRecord>>,
+    outputs: Arc<WorkerOutputs>,
+}
+
+impl WorkerEnv {
+    pub(crate) fn new(
+        index: usize,
+        seed: u64,
+        prefix: String,
+        record: Arc<Mutex<WorkerRecord>>,
+        outputs: Arc<WorkerOutputs>,
+    ) -> Self {
+        WorkerEnv {
+            index,
+            seed,
+            prefix,
+            record,
+            outputs,
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Seed for this worker's random stream.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Output filename as recorded by the parent, including any worker prefix.
+    pub fn output_name(&self, filename: &str) -> String {
+        format!("{}{filename}", self.prefix)
+    }
+
+    /// Check `filename` as the parent checks its own outputs, and claim
+    /// it for this worker. A file another worker of the same split has
+    /// written is an error rather than being overwritten.
+    fn claim(&self, filename: &str) -> Result<String, MrpError> {
+        let name = self.output_name(filename);
+        check_output_filename(&name)?;
+        if self.outputs.strict && !self.outputs.schemas.contains_key(filename) {
+            return Err(MrpError::Output(format!(
+                "output '{name}' has no declared schema (strict outputs enabled)"
+            )));
+        }
+        let mut claimed = self.outputs.claimed.lock().unwrap();
+        let owner = *claimed.entry(name.clone()).or_insert(self.index);
+        if owner != self.index {
+            return Err(MrpError::Output(format!(
+                "worker {} cannot write '{name}', which worker {owner} has written; \
+                 use Environment::split_scoped to give each worker its own directory",
+                self.index
+            )));
+        }
+        Ok(name)
+    }
+
+    /// The parent's output directory and settings, or an error naming the
+    /// output spec workers cannot write to.
+    fn dir(&self, name: &str) -> Result<(&Path, bool, bool, IfExists), MrpError> {
+        match &self.outputs.dest {
+            WorkerDest::Dir {
+                dir,
+                atomic,
+                atomic_csv,
+                if_exists,
+            } => Ok((dir, *atomic, *atomic_csv, *if_exists)),
+            WorkerDest::Unsupported(spec) => Err(MrpError::Config(format!(
+                "worker {} cannot write '{name}' under {spec}; \
+                 write it from the parent Environment instead",
+                self.index
+            ))),
+        }
+    }
+
+    /// Note that `name` was left as it is under `"if_exists": "skip"`.
+    fn skip_output(&self, name: &str, path: &Path) {
+        self.warn(
+            "output_skipped",
+            &format!(
+                "'{name}' already exists at {}, so was not written (if_exists is \"skip\")",
+                path.display()
+            ),
+        );
+    }
+
+    /// Write bytes to a file in the parent's output directory.
+    ///
+    /// Workers share only a filesystem output: under any other spec this
+    /// is a config error rather than a write the parent never sees.
+    pub fn write(&self, filename: &str, data: &[u8]) {
+        self.try_write(filename, data)
+            .unwrap_or_else(|e| panic!("{e}"));
+    }
+
+    pub fn try_write(&self, filename: &str, data: &[u8]) -> Result<(), MrpError> {
+        let name = self.claim(filename)?;
+        let (dir, atomic, _, if_exists) = self.dir(&name)?;
+        let path = dir.join(&name);
+        let mut span = OutputSpan::start(&self.outputs.clock);
+        if if_exists != IfExists::Overwrite && path.exists() {
+            match if_exists {
+                IfExists::Skip => self.skip_output(&name, &path),
+                policy => return Err(if_exists::exists_error(&name, &path, policy)),
+            }
+        } else {
+            fs::create_dir_all(path.parent().unwrap_or(dir))
+                .and_then(|_| {
+                    if atomic {
+                        atomic::write(&path, data)
+                    } else {
+                        fs::write(&path, data)
+                    }
+                })
+                .map_err(|e| MrpError::Output(format!("failed to write '{name}': {e}")))?;
+        }
+        span.finish(&self.outputs.clock);
+        self.record.lock().unwrap().outputs.push((name, span));
+        Ok(())
+    }
+
+    /// Create a CSV writer for a file in this worker's output namespace,
+    /// checked against the schema the parent declared for `filename`.
+    pub fn csv_writer(&self, filename: &str, headers: &[&str]) -> CsvWriter {
+        self.try_csv_writer(filename, headers)
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    pub fn try_csv_writer(&self, filename: &str, headers: &[&str]) -> Result<CsvWriter, MrpError> {
+        let schema = self.outputs.schemas.get(filename);
+        if let Some(schema) = schema {
+            schema.check_headers(filename, headers)?;
+        }
+        let name = self.claim(filename)?;
+        let (dir, _, atomic, if_exists) = self.dir(&name)?;
+        let path = dir.join(&name);
+        let exists = if_exists != IfExists::Overwrite && path.exists();
+        let warnings = Rc::default();
+        let dest: Box<dyn Write> = match (exists, if_exists) {
+            (false, _) => {
+                let create_error =
+                    |e: io::Error| MrpError::Output(format!("failed to create '{name}': {e}"));
+                fs::create_dir_all(path.parent().unwrap_or(dir)).map_err(create_error)?;
+                if atomic {
+                    Box::new(AtomicFile::create(&path, Rc::clone(&warnings)).map_err(create_error)?)
+                } else {
+                    Box::new(fs::File::create(&path).map_err(create_error)?)
+                }
+            }
+            (true, IfExists::Skip) => {
+                self.skip_output(&name, &path);
+                Box::new(io::sink())
+            }
+            (true, IfExists::Append) => Box::new(if_exists::open_for_append(&path, headers)?),
+            (true, policy) => return Err(if_exists::exists_error(&name, &path, policy)),
+        };
+        let span = OutputSpan::start(&self.outputs.clock);
+        self.record
+            .lock()
+            .unwrap()
+            .outputs
+            .push((name.clone(), span));
+        let dest = Box::new(WorkerSpanWriter {
+            inner: Some(dest),
+            warnings,
+            record: self.record.clone(),
+            clock: self.outputs.clock.clone(),
+            name: name.clone(),
+        });
+        let writer = if exists && if_exists == IfExists::Append {
+            CsvWriter::continuing(dest, headers)
+        } else {
+            CsvWriter::new(dest, headers)
+        };
+        Ok(match schema {
+            Some(schema) => writer.with_schema(&name, schema.clone()),
+            None => writer.named(&name),
+        })
+    }
+
+    /// Set a metric, overwriting values from earlier workers.
+    pub fn record_metric(&self, name: &str, value: f64) {
+        self.record
+            .lock()
+            .unwrap()
+            .metrics
+            .push((name.to_string(), MetricUpdate::Set(value)));
+    }
+
+    /// Add to a metric shared across workers.
+    pub fn add_metric(&self, name: &str, delta: f64) {
+        self.record
+            .lock()
+            .unwrap()
+            .metrics
+            .push((name.to_string(), MetricUpdate::Add(delta)));
+    }
+
+    /// Register a cleanup action, run when the parent merges this worker.
+    pub fn defer(&self, cleanup: impl FnOnce() + Send + 'static) {
+        self.record.lock().unwrap().deferred.push(Box::new(cleanup));
+    }
+
+    pub fn warn(&self, code: &str, message: &str) {
+        eprintln!("warning [{code}] worker {}: {message}", self.index);
+        self.record.lock().unwrap().warnings.push(Warning {
+            code: code.to_string(),
+            message: message.to_string(),
+            error_code: None,
+        });
+    }
+}
+
+/// A worker's streamed output, ending its span in the [`WorkerRecord`] and
+/// passing on any failure to commit it once dropped with its writer.
+struct WorkerSpanWriter {
+    inner: Option<Box<dyn Write>>,
+    warnings: Rc<RefCell<Vec<Warning>>>,
+    record: Arc<Mutex<WorkerRecord>>,
+    clock: Clock,
+    name: String,
+}
+
+impl WorkerSpanWriter {
+    fn inner(&mut self) -> &mut Box<dyn Write> {
+        self.inner.as_mut().expect("writer is open until drop")
+    }
+}
+
+impl Write for WorkerSpanWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner().flush()
+    }
+}
+
+impl Drop for WorkerSpanWriter {
+    fn drop(&mut self) {
+        // Close (and commit) the destination first, so the span covers it
+        drop(self.inner.take());
+        let Ok(mut record) = self.record.lock() else {
+            return;
+        };
+        record.warnings.append(&mut self.warnings.borrow_mut());
+        let open = record
+            .outputs
+            .iter_mut()
+            .rev()
+            .find(|(name, span)| *name == self.name && !span.is_finished());
+        if let Some((_, span)) = open {
+            span.finish(&self.clock);
+        }
+    }
+}